@@ -10,7 +10,9 @@
 use std::{env, path::Path, process::ExitCode};
 
 use clap::Command;
-use shadowsocks_rust::service::{genkey, local, manager, server};
+#[cfg(feature = "local-history")]
+use shadowsocks_rust::service::history;
+use shadowsocks_rust::service::{describe, genkey, local, manager, server, support_bundle, verify};
 
 fn main() -> ExitCode {
     let app = Command::new("shadowsocks")
@@ -29,7 +31,7 @@ fn main() -> ExitCode {
         }
     }
 
-    let matches = app
+    let app = app
         .subcommand_required(true)
         .subcommand(local::define_command_line_options(Command::new("local")).about("Shadowsocks Local service"))
         .subcommand(server::define_command_line_options(Command::new("server")).about("Shadowsocks Server service"))
@@ -40,13 +42,36 @@ fn main() -> ExitCode {
             genkey::define_command_line_options(Command::new("genkey"))
                 .about("Generate shadowsocks encryption key for method"),
         )
-        .get_matches();
+        .subcommand(
+            verify::define_command_line_options(Command::new("verify"))
+                .about("Check protocol conformance of a remote shadowsocks server"),
+        )
+        .subcommand(
+            support_bundle::define_command_line_options(Command::new("support-bundle"))
+                .about("Collect diagnostic information into an archive for bug reports"),
+        )
+        .subcommand(
+            describe::define_command_line_options(Command::new("describe"))
+                .about("Describe features, ciphers and configuration options of this build in JSON"),
+        );
+
+    #[cfg(feature = "local-history")]
+    let app = app.subcommand(
+        history::define_command_line_options(Command::new("history")).about("Query history of completed sessions"),
+    );
+
+    let matches = app.get_matches();
 
     match matches.subcommand() {
         Some(("local", matches)) => local::main(matches),
         Some(("server", matches)) => server::main(matches),
         Some(("manager", matches)) => manager::main(matches),
         Some(("genkey", matches)) => genkey::main(matches),
+        Some(("verify", matches)) => verify::main(matches),
+        Some(("support-bundle", matches)) => support_bundle::main(matches),
+        Some(("describe", matches)) => describe::main(matches),
+        #[cfg(feature = "local-history")]
+        Some(("history", matches)) => history::main(matches),
         _ => unreachable!("expecting a subcommand"),
     }
 }
@@ -9,6 +9,7 @@ use std::{
     fs::File,
     io::{self, BufRead, BufReader, Error, ErrorKind},
     net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
     path::{Path, PathBuf},
     str,
 };
@@ -22,7 +23,13 @@ use regex::bytes::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
 use shadowsocks::{context::Context, relay::socks5::Address};
 
 use self::sub_domains_tree::SubDomainsTree;
+pub use self::{
+    route::ServerRoutes,
+    sources::{RuleSet, RuleSourceKind, RuleSourceKindError, RuleSources},
+};
 
+mod route;
+mod sources;
 mod sub_domains_tree;
 
 /// Strategy mode that ACL is running
@@ -41,6 +48,7 @@ struct Rules {
     rule_regex: RegexSet,
     rule_set: HashSet<String>,
     rule_tree: SubDomainsTree,
+    ports: Vec<RangeInclusive<u16>>,
 }
 
 impl fmt::Debug for Rules {
@@ -79,7 +87,7 @@ impl fmt::Debug for Rules {
             f.write_str(", ...")?;
         }
 
-        write!(f, "], rule_tree: {:?} }}", self.rule_tree)
+        write!(f, "], rule_tree: {:?}, ports: {:?} }}", self.rule_tree, self.ports)
     }
 }
 
@@ -91,6 +99,7 @@ impl Rules {
         rule_regex: RegexSet,
         rule_set: HashSet<String>,
         rule_tree: SubDomainsTree,
+        ports: Vec<RangeInclusive<u16>>,
     ) -> Rules {
         // Optimization, merging networks
         ipv4.simplify();
@@ -102,6 +111,7 @@ impl Rules {
             rule_regex,
             rule_set,
             rule_tree,
+            ports,
         }
     }
 
@@ -145,6 +155,11 @@ impl Rules {
         self.rule_set.contains(host) || self.rule_tree.contains(host) || self.rule_regex.is_match(host.as_bytes())
     }
 
+    /// Check if the specified port matches any port rules
+    fn check_port_matched(&self, port: u16) -> bool {
+        self.ports.iter().any(|r| r.contains(&port))
+    }
+
     /// Check if there are no rules for IP addresses
     fn is_ip_empty(&self) -> bool {
         self.ipv4.is_empty() && self.ipv6.is_empty()
@@ -154,26 +169,135 @@ impl Rules {
     fn is_host_empty(&self) -> bool {
         self.rule_set.is_empty() && self.rule_tree.is_empty() && self.rule_regex.is_empty()
     }
+
+    /// Number of networks, after merging
+    fn ip_count(&self) -> usize {
+        self.ipv4.iter().count() + self.ipv6.iter().count()
+    }
+
+    /// Number of rules for domain names
+    fn host_count(&self) -> usize {
+        self.rule_set.len() + self.rule_tree.len() + self.rule_regex.len()
+    }
+
+    fn export(&self) -> RuleList<'_> {
+        RuleList {
+            hosts: self.rule_set.iter().map(String::as_str).collect(),
+            domains: self.rule_tree.domains(),
+            host_patterns: self.rule_regex.patterns(),
+            ipv4: self.ipv4.iter().collect(),
+            ipv6: self.ipv6.iter().collect(),
+        }
+    }
 }
 
 struct ParsingRules {
-    name: &'static str,
+    name: Cow<'static, str>,
     ipv4: IpRange<Ipv4Net>,
     ipv6: IpRange<Ipv6Net>,
     rules_regex: Vec<String>,
     rules_set: HashSet<String>,
     rules_tree: SubDomainsTree,
+    ports: Vec<RangeInclusive<u16>>,
+    port_rules: bool,
+    geoip_dir: Option<PathBuf>,
 }
 
 impl ParsingRules {
-    fn new(name: &'static str) -> Self {
+    fn new(name: impl Into<Cow<'static, str>>) -> Self {
         ParsingRules {
-            name,
+            name: name.into(),
             ipv4: IpRange::new(),
             ipv6: IpRange::new(),
             rules_regex: Vec::new(),
             rules_set: HashSet::new(),
             rules_tree: SubDomainsTree::new(),
+            ports: Vec::new(),
+            port_rules: false,
+            geoip_dir: None,
+        }
+    }
+
+    /// Allow `port:` rules, which only make sense for outbound addresses
+    fn with_port_rules(mut self) -> Self {
+        self.port_rules = true;
+        self
+    }
+
+    /// Directory of network lists for `geoip:` rules
+    fn with_geoip_dir(mut self, geoip_dir: Option<&Path>) -> Self {
+        self.geoip_dir = geoip_dir.map(Path::to_path_buf);
+        self
+    }
+
+    /// Add a rule line, which is either a CIDR, an IP address, a GeoIP rule, a port rule, a domain rule or a regular expression
+    fn add_rule(&mut self, line: &str) -> io::Result<()> {
+        if let Some(country) = line.strip_prefix("geoip:") {
+            return self.add_geoip_rule(country);
+        }
+
+        if let Some(ports) = line.strip_prefix("port:") {
+            return self.add_port_rule(ports);
+        }
+
+        if let Some(rule) = line.strip_prefix("||") {
+            return self.add_tree_rule(rule);
+        }
+
+        if let Some(rule) = line.strip_prefix('|') {
+            return self.add_set_rule(rule);
+        }
+
+        match line.parse::<IpNet>() {
+            Ok(IpNet::V4(v4)) => {
+                self.add_ipv4_rule(v4);
+            }
+            Ok(IpNet::V6(v6)) => {
+                self.add_ipv6_rule(v6);
+            }
+            Err(..) => {
+                // Maybe it is a pure IpAddr
+                match line.parse::<IpAddr>() {
+                    Ok(IpAddr::V4(v4)) => {
+                        self.add_ipv4_rule(v4);
+                    }
+                    Ok(IpAddr::V6(v6)) => {
+                        self.add_ipv6_rule(v6);
+                    }
+                    Err(..) => {
+                        self.add_regex_rule(line.to_owned());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_port_rule(&mut self, rule: &str) -> io::Result<()> {
+        if !self.port_rules {
+            return Err(Error::other(format!(
+                "{} parsing error: port rule `port:{}` is only supported in [outbound_block_list]",
+                self.name, rule
+            )));
+        }
+
+        let range = match rule.split_once('-') {
+            Some((start, end)) => match (start.trim().parse::<u16>(), end.trim().parse::<u16>()) {
+                (Ok(start), Ok(end)) if start <= end => Some(start..=end),
+                _ => None,
+            },
+            None => rule.trim().parse::<u16>().ok().map(|port| port..=port),
+        };
+        match range {
+            Some(range) => {
+                trace!("PORT-RULE {:?}", range);
+                self.ports.push(range);
+                Ok(())
+            }
+            None => Err(Error::other(format!(
+                "{} parsing error: invalid port rule `port:{}`, should be a port or \"start-end\"",
+                self.name, rule
+            ))),
         }
     }
 
@@ -189,6 +313,59 @@ impl ParsingRules {
         self.ipv6.add(rule);
     }
 
+    fn add_geoip_rule(&mut self, country: &str) -> io::Result<()> {
+        let geoip_dir = match self.geoip_dir {
+            Some(ref d) => d,
+            None => {
+                return Err(Error::other(format!(
+                    "{} parsing error: `geoip:{}` requires geoip_dir",
+                    self.name, country
+                )));
+            }
+        };
+
+        if country.is_empty()
+            || !country
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        {
+            return Err(Error::other(format!(
+                "{} parsing error: invalid country code `{}`",
+                self.name, country
+            )));
+        }
+
+        let path = geoip_dir.join(format!("{}.txt", country.to_ascii_lowercase()));
+        trace!("GEOIP-RULE {} from {:?}", country, path);
+
+        let fp = File::open(&path).map_err(|err| {
+            Error::new(
+                err.kind(),
+                format!("{} geoip:{} file {:?}, error: {}", self.name, country, path, err),
+            )
+        })?;
+
+        for line in BufReader::new(fp).lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.parse::<IpNet>() {
+                Ok(IpNet::V4(v4)) => self.add_ipv4_rule(v4),
+                Ok(IpNet::V6(v6)) => self.add_ipv6_rule(v6),
+                Err(..) => match line.parse::<IpAddr>() {
+                    Ok(IpAddr::V4(v4)) => self.add_ipv4_rule(v4),
+                    Ok(IpAddr::V6(v6)) => self.add_ipv6_rule(v6),
+                    Err(..) => warn!("geoip:{} network {} in {:?} is invalid, skipped", country, line, path),
+                },
+            }
+        }
+
+        Ok(())
+    }
+
     fn add_regex_rule(&mut self, mut rule: String) {
         static TREE_SET_RULE_EQUIV: Lazy<Regex> = Lazy::new(|| {
             RegexBuilder::new(
@@ -256,33 +433,265 @@ impl ParsingRules {
             // Remove the last `.` of FQDN
             Ok(str.trim_end_matches('.'))
         } else {
-            Err(Error::new(
-                ErrorKind::Other,
-                format!("{} parsing error: Unicode not allowed here `{}`", self.name, str),
-            ))
+            Err(Error::other(format!(
+                "{} parsing error: Unicode not allowed here `{}`",
+                self.name, str
+            )))
         }
     }
 
-    fn compile_regex(name: &'static str, regex_rules: Vec<String>) -> io::Result<RegexSet> {
+    fn compile_regex(name: &str, regex_rules: Vec<String>) -> io::Result<RegexSet> {
         const REGEX_SIZE_LIMIT: usize = usize::MAX;
         RegexSetBuilder::new(regex_rules)
             .size_limit(REGEX_SIZE_LIMIT)
             .unicode(false)
             .build()
-            .map_err(|err| Error::new(ErrorKind::Other, format!("{name} regex error: {err}")))
+            .map_err(|err| Error::other(format!("{name} regex error: {err}")))
     }
 
     fn into_rules(self) -> io::Result<Rules> {
         Ok(Rules::new(
             self.ipv4,
             self.ipv6,
-            Self::compile_regex(self.name, self.rules_regex)?,
+            Self::compile_regex(&self.name, self.rules_regex)?,
             self.rules_set,
             self.rules_tree,
+            self.ports,
         ))
     }
 }
 
+/// Sections of an ACL file
+struct ParsedAcl {
+    mode: Mode,
+    outbound_block: ParsingRules,
+    mirror: ParsingRules,
+    interactive: ParsingRules,
+    bulk: ParsingRules,
+    bypass: ParsingRules,
+    proxy: ParsingRules,
+    process_bypass: HashSet<String>,
+    process_proxy: HashSet<String>,
+}
+
+impl ParsedAcl {
+    fn new(geoip_dir: Option<&Path>) -> ParsedAcl {
+        ParsedAcl {
+            mode: Mode::BlackList,
+            outbound_block: ParsingRules::new("[outbound_block_list]")
+                .with_geoip_dir(geoip_dir)
+                .with_port_rules(),
+            mirror: ParsingRules::new("[mirror_list]").with_geoip_dir(geoip_dir),
+            interactive: ParsingRules::new("[interactive_list]").with_geoip_dir(geoip_dir),
+            bulk: ParsingRules::new("[bulk_list]").with_geoip_dir(geoip_dir),
+            bypass: ParsingRules::new("[black_list] or [bypass_list]").with_geoip_dir(geoip_dir),
+            proxy: ParsingRules::new("[white_list] or [proxy_list]").with_geoip_dir(geoip_dir),
+            process_bypass: HashSet::new(),
+            process_proxy: HashSet::new(),
+        }
+    }
+
+    fn load_from_file(file_path: &Path, geoip_dir: Option<&Path>) -> io::Result<ParsedAcl> {
+        let fp = File::open(file_path)?;
+        let r = BufReader::new(fp);
+
+        let mut acl = ParsedAcl::new(geoip_dir);
+        let mut curr = &mut acl.bypass;
+        // Process sections are not address rules
+        let mut curr_process: Option<&mut HashSet<String>> = None;
+
+        trace!(
+            "ACL parsing start from mode {:?} and black_list / bypass_list",
+            acl.mode
+        );
+
+        for line in r.lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+
+            // Comments
+            if line.starts_with('#') {
+                continue;
+            }
+
+            let line = line.trim();
+
+            if !line.is_ascii() {
+                warn!("ACL rule {} containing non-ASCII characters, skipped", line);
+                continue;
+            }
+
+            match line {
+                "[reject_all]" | "[bypass_all]" => {
+                    acl.mode = Mode::WhiteList;
+                    trace!("switch to mode {:?}", acl.mode);
+                }
+                "[accept_all]" | "[proxy_all]" => {
+                    acl.mode = Mode::BlackList;
+                    trace!("switch to mode {:?}", acl.mode);
+                }
+                "[outbound_block_list]" => {
+                    curr = &mut acl.outbound_block;
+                    curr_process = None;
+                    trace!("loading outbound_block_list");
+                }
+                "[mirror_list]" => {
+                    curr = &mut acl.mirror;
+                    curr_process = None;
+                    trace!("loading mirror_list");
+                }
+                "[interactive_list]" => {
+                    curr = &mut acl.interactive;
+                    curr_process = None;
+                    trace!("loading interactive_list");
+                }
+                "[bulk_list]" => {
+                    curr = &mut acl.bulk;
+                    curr_process = None;
+                    trace!("loading bulk_list");
+                }
+                "[black_list]" | "[bypass_list]" => {
+                    curr = &mut acl.bypass;
+                    curr_process = None;
+                    trace!("loading black_list / bypass_list");
+                }
+                "[white_list]" | "[proxy_list]" => {
+                    curr = &mut acl.proxy;
+                    curr_process = None;
+                    trace!("loading white_list / proxy_list");
+                }
+                "[process_bypass_list]" => {
+                    curr_process = Some(&mut acl.process_bypass);
+                    trace!("loading process_bypass_list");
+                }
+                "[process_proxy_list]" => {
+                    curr_process = Some(&mut acl.process_proxy);
+                    trace!("loading process_proxy_list");
+                }
+                _ => match curr_process {
+                    Some(ref mut process) => {
+                        process.insert(line.to_ascii_lowercase());
+                    }
+                    None => curr.add_rule(line)?,
+                },
+            }
+        }
+
+        Ok(acl)
+    }
+}
+
+/// Proxy / bypass rules of one source
+#[derive(Debug, Clone)]
+struct RuleLayer {
+    kind: RuleSourceKind,
+    name: String,
+    black_list: Rules,
+    white_list: Rules,
+}
+
+impl RuleLayer {
+    fn new(kind: RuleSourceKind, name: String, bypass: ParsingRules, proxy: ParsingRules) -> io::Result<RuleLayer> {
+        Ok(RuleLayer {
+            kind,
+            name,
+            black_list: bypass.into_rules()?,
+            white_list: proxy.into_rules()?,
+        })
+    }
+
+    /// `Some(true)` if `host` should be proxied, `Some(false)` if bypassed, `None` if no rules matched
+    fn check_host(&self, host: &str) -> Option<bool> {
+        // Addresses in proxy_list will be proxied
+        if self.white_list.check_host_matched(host) {
+            return Some(true);
+        }
+        // Addresses in bypass_list will be bypassed
+        if self.black_list.check_host_matched(host) {
+            return Some(false);
+        }
+        None
+    }
+
+    /// `Some(true)` if `ip` should be proxied, `Some(false)` if bypassed, `None` if no rules matched
+    ///
+    /// Rules against the default of `mode` are checked first
+    fn check_ip(&self, mode: Mode, ip: &IpAddr) -> Option<bool> {
+        let (first, second) = match mode {
+            Mode::BlackList => ((&self.black_list, false), (&self.white_list, true)),
+            Mode::WhiteList => ((&self.white_list, true), (&self.black_list, false)),
+        };
+        [first, second]
+            .into_iter()
+            .find(|(rules, ..)| rules.check_ip_matched(ip))
+            .map(|(.., proxied)| proxied)
+    }
+
+    /// Check if there are IP rules against the default of `mode`
+    fn is_ip_empty(&self, mode: Mode) -> bool {
+        match mode {
+            Mode::BlackList => self.black_list.is_ip_empty(),
+            Mode::WhiteList => self.white_list.is_ip_empty(),
+        }
+    }
+}
+
+/// Summary of one rule source, in precedence order of [`AccessControl::rule_layers`]
+#[derive(Debug, Clone)]
+pub struct RuleLayerSummary<'a> {
+    /// Kind of the source
+    pub kind: RuleSourceKind,
+    /// Name of the source, including its file path
+    pub name: &'a str,
+    /// Networks for connecting through proxies
+    pub proxy_ips: usize,
+    /// Domain name rules for connecting through proxies
+    pub proxy_hosts: usize,
+    /// Networks for connecting directly
+    pub bypass_ips: usize,
+    /// Domain name rules for connecting directly
+    pub bypass_hosts: usize,
+}
+
+/// Bypass / proxy rules of one source, see [`AccessControl::rule_lists`]
+#[derive(Debug, Clone)]
+pub struct RuleLayerLists<'a> {
+    /// Name of the source, including its file path
+    pub name: &'a str,
+    /// Rules for connecting through proxies
+    pub proxy: RuleList<'a>,
+    /// Rules for connecting directly
+    pub bypass: RuleList<'a>,
+}
+
+/// Rules of a list, for converting to other formats
+#[derive(Debug, Clone)]
+pub struct RuleList<'a> {
+    /// Domain names for exact matching
+    pub hosts: Vec<&'a str>,
+    /// Domain names for matching with subdomains
+    pub domains: Vec<String>,
+    /// Regular expressions for matching domain names
+    pub host_patterns: &'a [String],
+    /// IPv4 networks, merged
+    pub ipv4: Vec<Ipv4Net>,
+    /// IPv6 networks, merged
+    pub ipv6: Vec<Ipv6Net>,
+}
+
+/// Decision of proxy / bypass rules, see [`AccessControl::explain`]
+#[derive(Debug, Clone, Copy)]
+pub struct RuleDecision<'a> {
+    /// Target should be proxied
+    pub proxied: bool,
+    /// Name of the source that decided, `None` if no rules matched and the mode decided
+    pub source: Option<&'a str>,
+    /// Domain name matched no rules and may be decided by IP rules after resolution
+    pub resolve_required: bool,
+}
+
 /// ACL rules
 ///
 /// ## Sections
@@ -309,6 +718,11 @@ impl ParsingRules {
 ///     * `[proxy_all]` - ACL runs in `WhiteList` mode.
 ///     * `[bypass_list]` - Rules for connecting directly
 ///     * `[proxy_list]` - Rules for connecting through proxies
+///     * `[mirror_list]` - Rules for mirroring plaintext traffic, works only if `mirror` is configured
+///     * `[interactive_list]` - Rules for enabling `TCP_NODELAY`, for latency sensitive traffic like SSH
+///     * `[bulk_list]` - Rules for disabling `TCP_NODELAY`, for throughput sensitive traffic like downloads
+///     * `[process_bypass_list]` - Processes whose TCP connections are connected directly, only for `redir` and `tun`
+///     * `[process_proxy_list]` - Processes whose TCP connections are connected through proxies, only for `redir` and `tun`
 /// - For remote servers (`ssserver`)
 ///     * `[reject_all]` - ACL runs in `BlackList` mode.
 ///     * `[accept_all]` - ACL runs in `WhiteList` mode.
@@ -329,126 +743,207 @@ impl ParsingRules {
 ///
 /// - CIDR form network addresses, like `10.9.0.32/16`
 /// - IP addresses, like `127.0.0.1` or `::1`
+/// - GeoIP networks of a country, like `geoip:cn`, requires `geoip_dir` of [`RuleSources`]
 /// - Regular Expression for matching hosts, like `(^|\.)gmail\.com$`
 /// - Domain with preceding `|` for exact matching, like `|google.com`
 /// - Domain with preceding `||` for matching with subdomains, like `||google.com`
+/// - Destination ports, like `port:25` or `port:6881-6889`, only in `[outbound_block_list]`
+///
+/// Rules of process sections are executable names, like `firefox.exe`, or full paths of executables,
+/// like `/usr/bin/curl`, matched case-insensitively. They take precedence over rules of target addresses.
+///
+/// ## Rule Sources
+///
+/// Bypass / proxy rules of the ACL file could be merged with inline rules and rule sets, see [`RuleSources`].
 #[derive(Debug, Clone)]
 pub struct AccessControl {
     outbound_block: Rules,
-    black_list: Rules,
-    white_list: Rules,
+    mirror: Rules,
+    interactive: Rules,
+    bulk: Rules,
+    process_bypass: HashSet<String>,
+    process_proxy: HashSet<String>,
+    layers: Vec<RuleLayer>,
     mode: Mode,
-    file_path: PathBuf,
+    file_path: Option<PathBuf>,
+    sources: RuleSources,
 }
 
 impl AccessControl {
     /// Load ACL rules from a file
     pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<AccessControl> {
-        trace!("ACL loading from {:?}", p.as_ref());
-
-        let file_path_ref = p.as_ref();
-        let file_path = file_path_ref.to_path_buf();
-
-        let fp = File::open(file_path_ref)?;
-        let r = BufReader::new(fp);
-
-        let mut mode = Mode::BlackList;
-
-        let mut outbound_block = ParsingRules::new("[outbound_block_list]");
-        let mut bypass = ParsingRules::new("[black_list] or [bypass_list]");
-        let mut proxy = ParsingRules::new("[white_list] or [proxy_list]");
-        let mut curr = &mut bypass;
-
-        trace!("ACL parsing start from mode {:?} and black_list / bypass_list", mode);
+        AccessControl::load(Some(p.as_ref()), &RuleSources::default())
+    }
 
-        for line in r.lines() {
-            let line = line?;
-            if line.is_empty() {
-                continue;
-            }
+    /// Load ACL rules from an optional ACL file, merged with rule sources
+    pub fn load(file_path: Option<&Path>, sources: &RuleSources) -> io::Result<AccessControl> {
+        let geoip_dir = sources.geoip_dir.as_deref();
 
-            // Comments
-            if line.starts_with('#') {
-                continue;
+        let acl = match file_path {
+            Some(p) => {
+                trace!("ACL loading from {:?}", p);
+                ParsedAcl::load_from_file(p, geoip_dir)?
             }
+            None => ParsedAcl::new(geoip_dir),
+        };
 
-            let line = line.trim();
-
-            if !line.is_ascii() {
-                warn!("ACL rule {} containing non-ASCII characters, skipped", line);
-                continue;
-            }
+        let mut acl_bypass_proxy = file_path.map(|p| (p, acl.bypass, acl.proxy));
+        let mut layers = Vec::new();
 
-            if let Some(rule) = line.strip_prefix("||") {
-                curr.add_tree_rule(rule)?;
-                continue;
-            }
+        for kind in sources.effective_precedence() {
+            match kind {
+                RuleSourceKind::Inline => {
+                    if sources.bypass_list.is_empty() && sources.proxy_list.is_empty() {
+                        continue;
+                    }
 
-            if let Some(rule) = line.strip_prefix('|') {
-                curr.add_set_rule(rule)?;
-                continue;
-            }
+                    let mut bypass = ParsingRules::new("rules.bypass_list").with_geoip_dir(geoip_dir);
+                    for rule in &sources.bypass_list {
+                        bypass.add_rule(rule.trim())?;
+                    }
+                    let mut proxy = ParsingRules::new("rules.proxy_list").with_geoip_dir(geoip_dir);
+                    for rule in &sources.proxy_list {
+                        proxy.add_rule(rule.trim())?;
+                    }
 
-            match line {
-                "[reject_all]" | "[bypass_all]" => {
-                    mode = Mode::WhiteList;
-                    trace!("switch to mode {:?}", mode);
+                    layers.push(RuleLayer::new(kind, kind.as_str().to_owned(), bypass, proxy)?);
                 }
-                "[accept_all]" | "[proxy_all]" => {
-                    mode = Mode::BlackList;
-                    trace!("switch to mode {:?}", mode);
-                }
-                "[outbound_block_list]" => {
-                    curr = &mut outbound_block;
-                    trace!("loading outbound_block_list");
-                }
-                "[black_list]" | "[bypass_list]" => {
-                    curr = &mut bypass;
-                    trace!("loading black_list / bypass_list");
-                }
-                "[white_list]" | "[proxy_list]" => {
-                    curr = &mut proxy;
-                    trace!("loading white_list / proxy_list");
+                RuleSourceKind::Acl => {
+                    if let Some((p, bypass, proxy)) = acl_bypass_proxy.take() {
+                        layers.push(RuleLayer::new(kind, format!("acl {}", p.display()), bypass, proxy)?);
+                    }
                 }
-                _ => {
-                    match line.parse::<IpNet>() {
-                        Ok(IpNet::V4(v4)) => {
-                            curr.add_ipv4_rule(v4);
-                        }
-                        Ok(IpNet::V6(v6)) => {
-                            curr.add_ipv6_rule(v6);
-                        }
-                        Err(..) => {
-                            // Maybe it is a pure IpAddr
-                            match line.parse::<IpAddr>() {
-                                Ok(IpAddr::V4(v4)) => {
-                                    curr.add_ipv4_rule(v4);
-                                }
-                                Ok(IpAddr::V6(v6)) => {
-                                    curr.add_ipv6_rule(v6);
-                                }
-                                Err(..) => {
-                                    curr.add_regex_rule(line.to_owned());
-                                }
+                RuleSourceKind::RuleSets => {
+                    for rule_set in &sources.rule_sets {
+                        trace!("rule set loading from {:?}", rule_set.path);
+
+                        let parsed = match ParsedAcl::load_from_file(&rule_set.path, geoip_dir) {
+                            Ok(p) => p,
+                            Err(err) if err.kind() == ErrorKind::NotFound && rule_set.url.is_some() => {
+                                warn!(
+                                    "rule set {} is not downloaded from {} yet, skipped",
+                                    rule_set.path.display(),
+                                    rule_set.url.as_deref().unwrap_or_default()
+                                );
+                                continue;
                             }
-                        }
+                            Err(err) => {
+                                return Err(Error::new(
+                                    err.kind(),
+                                    format!("rule set {}, error: {}", rule_set.path.display(), err),
+                                ));
+                            }
+                        };
+
+                        layers.push(RuleLayer::new(
+                            kind,
+                            format!("rule_set {}", rule_set.path.display()),
+                            parsed.bypass,
+                            parsed.proxy,
+                        )?);
                     }
                 }
             }
         }
 
         Ok(AccessControl {
-            outbound_block: outbound_block.into_rules()?,
-            black_list: bypass.into_rules()?,
-            white_list: proxy.into_rules()?,
-            mode,
-            file_path,
+            outbound_block: acl.outbound_block.into_rules()?,
+            mirror: acl.mirror.into_rules()?,
+            interactive: acl.interactive.into_rules()?,
+            bulk: acl.bulk.into_rules()?,
+            process_bypass: acl.process_bypass,
+            process_proxy: acl.process_proxy,
+            layers,
+            mode: acl.mode,
+            file_path: file_path.map(Path::to_path_buf),
+            sources: sources.clone(),
         })
     }
 
-    /// Get ACL file path
-    pub fn file_path(&self) -> &Path {
-        &self.file_path
+    /// Load again from the same ACL file and rule sources
+    pub fn reload(&self) -> io::Result<AccessControl> {
+        AccessControl::load(self.file_path.as_deref(), &self.sources)
+    }
+
+    /// Get ACL file path, `None` if rules are only from rule sources
+    pub fn file_path(&self) -> Option<&Path> {
+        self.file_path.as_deref()
+    }
+
+    /// Get rule sources merged with the ACL file
+    pub fn rule_sources(&self) -> &RuleSources {
+        &self.sources
+    }
+
+    /// Get ACL mode
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Summary of bypass / proxy rules of each source, in precedence order
+    pub fn rule_layers(&self) -> impl Iterator<Item = RuleLayerSummary<'_>> {
+        self.layers.iter().map(|layer| RuleLayerSummary {
+            kind: layer.kind,
+            name: &layer.name,
+            proxy_ips: layer.white_list.ip_count(),
+            proxy_hosts: layer.white_list.host_count(),
+            bypass_ips: layer.black_list.ip_count(),
+            bypass_hosts: layer.black_list.host_count(),
+        })
+    }
+
+    /// Bypass / proxy rules of each source, in precedence order
+    ///
+    /// Domain names are matched against `proxy` before `bypass`,
+    /// IP addresses are matched against the list against the default of `mode` first
+    pub fn rule_lists(&self) -> impl Iterator<Item = RuleLayerLists<'_>> {
+        self.layers.iter().map(|layer| RuleLayerLists {
+            name: &layer.name,
+            proxy: layer.white_list.export(),
+            bypass: layer.black_list.export(),
+        })
+    }
+
+    /// Explain whether `target`, an IP address or a domain name, should be proxied and which source decided it
+    ///
+    /// Domain names are only matched by host rules, without DNS resolution
+    pub fn explain(&self, target: &str) -> RuleDecision<'_> {
+        if let Ok(ip) = target.parse::<IpAddr>() {
+            return match self
+                .layers
+                .iter()
+                .find_map(|layer| Some((layer, layer.check_ip(self.mode, &ip)?)))
+            {
+                Some((layer, proxied)) => RuleDecision {
+                    proxied,
+                    source: Some(&layer.name),
+                    resolve_required: false,
+                },
+                None => RuleDecision {
+                    proxied: self.is_default_in_proxy_list(),
+                    source: None,
+                    resolve_required: false,
+                },
+            };
+        }
+
+        let host = Self::convert_to_ascii(target);
+        match self
+            .layers
+            .iter()
+            .find_map(|layer| Some((layer, layer.check_host(&host)?)))
+        {
+            Some((layer, proxied)) => RuleDecision {
+                proxied,
+                source: Some(&layer.name),
+                resolve_required: false,
+            },
+            None => RuleDecision {
+                proxied: self.is_default_in_proxy_list(),
+                source: None,
+                resolve_required: !self.is_ip_empty(),
+            },
+        }
     }
 
     /// Check if domain name is in proxy_list.
@@ -471,36 +966,56 @@ impl AccessControl {
     /// - `Some(false)` if `host` is in `black_list` (should be bypassed)
     /// - `None` if `host` doesn't match any rules
     pub fn check_ascii_host_in_proxy_list(&self, host: &str) -> Option<bool> {
-        // Addresses in proxy_list will be proxied
-        if self.white_list.check_host_matched(host) {
-            return Some(true);
-        }
-        // Addresses in bypass_list will be bypassed
-        if self.black_list.check_host_matched(host) {
-            return Some(false);
-        }
-        None
+        self.layers.iter().find_map(|layer| layer.check_host(host))
     }
 
     /// If there are no IP rules
     pub fn is_ip_empty(&self) -> bool {
-        match self.mode {
-            Mode::BlackList => self.black_list.is_ip_empty(),
-            Mode::WhiteList => self.white_list.is_ip_empty(),
-        }
+        self.layers.iter().all(|layer| layer.is_ip_empty(self.mode))
     }
 
     /// If there are no domain name rules
     pub fn is_host_empty(&self) -> bool {
-        self.black_list.is_host_empty() && self.white_list.is_host_empty()
+        self.layers
+            .iter()
+            .all(|layer| layer.black_list.is_host_empty() && layer.white_list.is_host_empty())
+    }
+
+    /// If there are no process rules
+    pub fn is_process_empty(&self) -> bool {
+        self.process_bypass.is_empty() && self.process_proxy.is_empty()
+    }
+
+    /// Check if connections of the process with executable `path` should be proxied
+    ///
+    /// Return
+    /// - `Some(true)` if the process is in `[process_proxy_list]`
+    /// - `Some(false)` if the process is in `[process_bypass_list]`
+    /// - `None` if the process doesn't match any rules
+    pub fn check_process_in_proxy_list(&self, path: &Path) -> Option<bool> {
+        let full_path = path.to_string_lossy().to_lowercase();
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        let matched = |rules: &HashSet<String>| rules.contains(&full_path) || rules.contains(&file_name);
+
+        if matched(&self.process_proxy) {
+            Some(true)
+        } else if matched(&self.process_bypass) {
+            Some(false)
+        } else {
+            None
+        }
     }
 
     /// Check if `IpAddr` should be proxied
     pub fn check_ip_in_proxy_list(&self, ip: &IpAddr) -> bool {
-        match self.mode {
-            Mode::BlackList => !self.black_list.check_ip_matched(ip),
-            Mode::WhiteList => self.white_list.check_ip_matched(ip),
-        }
+        self.layers
+            .iter()
+            .find_map(|layer| layer.check_ip(self.mode, ip))
+            .unwrap_or_else(|| self.is_default_in_proxy_list())
     }
 
     /// Default mode
@@ -517,7 +1032,7 @@ impl AccessControl {
 
     /// Returns the ASCII representation a domain name,
     /// if conversion fails returns original string
-    fn convert_to_ascii(host: &str) -> Cow<str> {
+    fn convert_to_ascii(host: &str) -> Cow<'_, str> {
         idna::domain_to_ascii(host)
             .map(From::from)
             .unwrap_or_else(|_| host.into())
@@ -549,18 +1064,40 @@ impl AccessControl {
         }
     }
 
+    /// Check if traffic to target address should be mirrored (for client)
+    ///
+    /// Domain names are only matched by host rules, without DNS resolution
+    pub fn check_target_mirrored(&self, addr: &Address) -> bool {
+        match *addr {
+            Address::SocketAddress(ref saddr) => self.mirror.check_ip_matched(&saddr.ip()),
+            Address::DomainNameAddress(ref host, ..) => self.mirror.check_host_matched(&Self::convert_to_ascii(host)),
+        }
+    }
+
+    /// Get `TCP_NODELAY` of connections to target address by its traffic class (for client)
+    ///
+    /// Returns `Some(true)` for `[interactive_list]`, `Some(false)` for `[bulk_list]`, and `None` if not classified.
+    /// Domain names are only matched by host rules, without DNS resolution
+    pub fn check_target_nodelay(&self, addr: &Address) -> Option<bool> {
+        let matched = |rules: &Rules| match *addr {
+            Address::SocketAddress(ref saddr) => rules.check_ip_matched(&saddr.ip()),
+            Address::DomainNameAddress(ref host, ..) => rules.check_host_matched(&Self::convert_to_ascii(host)),
+        };
+
+        if matched(&self.interactive) {
+            Some(true)
+        } else if matched(&self.bulk) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
     /// Check if client address should be blocked (for server)
     pub fn check_client_blocked(&self, addr: &SocketAddr) -> bool {
-        match self.mode {
-            Mode::BlackList => {
-                // Only clients in black_list will be blocked
-                self.black_list.check_ip_matched(&addr.ip())
-            }
-            Mode::WhiteList => {
-                // Only clients in white_list will be proxied
-                !self.white_list.check_ip_matched(&addr.ip())
-            }
-        }
+        // BlackList mode: only clients in black_list will be blocked
+        // WhiteList mode: only clients in white_list will be accepted
+        !self.check_ip_in_proxy_list(&addr.ip())
     }
 
     /// Check if outbound address is blocked (for server)
@@ -568,6 +1105,10 @@ impl AccessControl {
     /// NOTE: `Address::DomainName` is only validated by regex rules,
     ///       resolved addresses are checked in the `lookup_outbound_then!` macro
     pub async fn check_outbound_blocked(&self, context: &Context, outbound: &Address) -> bool {
+        if self.outbound_block.check_port_matched(outbound.port()) {
+            return true;
+        }
+
         match outbound {
             Address::SocketAddress(saddr) => self.outbound_block.check_ip_matched(&saddr.ip()),
             Address::DomainNameAddress(host, port) => {
@@ -588,3 +1129,95 @@ impl AccessControl {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process};
+
+    use shadowsocks::config::ServerType;
+
+    use super::*;
+
+    fn port_rules(lines: &[&str]) -> io::Result<Rules> {
+        let mut rules = ParsingRules::new("outbound_block_list").with_port_rules();
+        for line in lines {
+            rules.add_rule(line)?;
+        }
+        rules.into_rules()
+    }
+
+    /// Load an ACL file with `content`, which is removed after loaded
+    fn load_acl(name: &str, content: &str) -> io::Result<AccessControl> {
+        let path = env::temp_dir().join(format!("shadowsocks-acl-test-{}-{}.acl", process::id(), name));
+        fs::write(&path, content)?;
+        let acl = AccessControl::load_from_file(&path);
+        let _ = fs::remove_file(&path);
+        acl
+    }
+
+    #[test]
+    fn test_port_rules() {
+        let rules = port_rules(&["port:25", "port: 6881 - 6889 ", "port:65535"]).unwrap();
+        for port in [25, 6881, 6885, 6889, 65535] {
+            assert!(rules.check_port_matched(port), "port {port}");
+        }
+        for port in [0, 24, 26, 6880, 6890, 65534] {
+            assert!(!rules.check_port_matched(port), "port {port}");
+        }
+
+        let rules = port_rules(&["port:0-65535"]).unwrap();
+        assert!(rules.check_port_matched(0) && rules.check_port_matched(65535));
+
+        let rules = port_rules(&[]).unwrap();
+        assert!(!rules.check_port_matched(25));
+    }
+
+    #[test]
+    fn test_port_rules_malformed() {
+        for rule in [
+            "port:",
+            "port:smtp",
+            "port:-25",
+            "port:25-",
+            "port:6889-6881",
+            "port:1-65536",
+            "port:65536",
+            "port:1-2-3",
+        ] {
+            assert!(port_rules(&[rule]).is_err(), "rule {rule}");
+        }
+    }
+
+    #[test]
+    fn test_port_rules_outside_outbound_block_list() {
+        let mut rules = ParsingRules::new("bypass_list");
+        assert!(rules.add_rule("port:25").is_err());
+
+        assert!(load_acl("bypass", "[bypass_list]\nport:25\n").is_err());
+        assert!(load_acl("proxy", "[bypass_all]\n[proxy_list]\nport:25\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_outbound_blocked_by_port() {
+        let acl = load_acl(
+            "outbound",
+            "[outbound_block_list]\nport:25\nport:6881-6889\n127.0.0.0/8\n",
+        )
+        .unwrap();
+        let context = Context::new(ServerType::Server);
+
+        let blocked = |addr: &str| Address::SocketAddress(addr.parse().unwrap());
+        assert!(acl.check_outbound_blocked(&context, &blocked("1.1.1.1:25")).await);
+        assert!(
+            acl.check_outbound_blocked(&context, &blocked("[2001:db8::1]:6881"))
+                .await
+        );
+        assert!(acl.check_outbound_blocked(&context, &blocked("127.0.0.1:443")).await);
+        assert!(!acl.check_outbound_blocked(&context, &blocked("1.1.1.1:443")).await);
+        assert!(!acl.check_outbound_blocked(&context, &blocked("1.1.1.1:6890")).await);
+
+        // Domain names are blocked by ports before resolving
+        let domain = Address::DomainNameAddress("mail.example.invalid".to_owned(), 25);
+        assert!(acl.check_outbound_blocked(&context, &domain).await);
+    }
+}
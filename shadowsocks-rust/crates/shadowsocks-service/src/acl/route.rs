@@ -0,0 +1,108 @@
+//! Routing rules of destinations to server groups
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use log::{trace, warn};
+
+use shadowsocks::relay::socks5::Address;
+
+use super::{AccessControl, ParsingRules, Rules};
+
+/// Server group routing rules
+///
+/// Formatted like ACL files, each section is named by a server group, followed by rules of destinations
+/// that should be sent through servers in that group.
+///
+/// ```plain
+/// [tokyo]
+/// ||jp
+/// 203.0.113.0/24
+///
+/// [us]
+/// ||netflix.com
+/// ```
+///
+/// Sections are matched in order, the first matched group is used. Rules are the same as ACL's.
+#[derive(Debug, Clone)]
+pub struct ServerRoutes {
+    routes: Vec<(String, Rules)>,
+    file_path: PathBuf,
+}
+
+impl ServerRoutes {
+    /// Load routing rules from a file
+    pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<ServerRoutes> {
+        trace!("server routes loading from {:?}", p.as_ref());
+
+        let file_path = p.as_ref().to_path_buf();
+
+        let fp = File::open(&file_path)?;
+        let r = BufReader::new(fp);
+
+        let mut groups: Vec<(String, ParsingRules)> = Vec::new();
+        let mut curr = None;
+
+        for line in r.lines() {
+            let line = line?;
+            let line = line.trim();
+
+            // Comments
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if !line.is_ascii() {
+                warn!("server route rule {} containing non-ASCII characters, skipped", line);
+                continue;
+            }
+
+            if let Some(group) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                trace!("loading server group {}", group);
+                curr = match groups.iter().position(|(name, ..)| name == group) {
+                    Some(idx) => Some(idx),
+                    None => {
+                        groups.push((group.to_owned(), ParsingRules::new(format!("[{group}]"))));
+                        Some(groups.len() - 1)
+                    }
+                };
+                continue;
+            }
+
+            match curr {
+                Some(idx) => groups[idx].1.add_rule(line)?,
+                None => warn!("server route rule {} is not in any group, skipped", line),
+            }
+        }
+
+        let mut routes = Vec::with_capacity(groups.len());
+        for (group, rules) in groups {
+            routes.push((group, rules.into_rules()?));
+        }
+
+        Ok(ServerRoutes { routes, file_path })
+    }
+
+    /// Get routing rules file path
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    /// Get the server group of target address, `None` if no group matches
+    ///
+    /// Domain names are only matched by host rules, without DNS resolution
+    pub fn check_target_group(&self, addr: &Address) -> Option<&str> {
+        self.routes
+            .iter()
+            .find(|(.., rules)| match *addr {
+                Address::SocketAddress(ref saddr) => rules.check_ip_matched(&saddr.ip()),
+                Address::DomainNameAddress(ref host, ..) => {
+                    rules.check_host_matched(&AccessControl::convert_to_ascii(host))
+                }
+            })
+            .map(|(group, ..)| group.as_str())
+    }
+}
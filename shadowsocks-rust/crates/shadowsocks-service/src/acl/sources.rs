@@ -0,0 +1,117 @@
+//! Sources of proxy / bypass rules
+//!
+//! Proxy / bypass rules could come from
+//!
+//! - `inline` - `bypass_list` and `proxy_list` in configuration
+//! - `acl` - `[bypass_list]` and `[proxy_list]` of the ACL file
+//! - `rule_sets` - `[bypass_list]` and `[proxy_list]` of rule set files, in configured order
+//!
+//! Sources are checked in precedence order, the first source that has a matching rule decides. Domain name rules of
+//! all sources are checked before IP rules. Targets that match no rules follow the mode of the ACL file.
+//!
+//! `geoip:<country code>` rules in any source are expanded to networks listed in `<geoip_dir>/<country code>.txt`.
+
+use std::{
+    fmt::{self, Display},
+    path::PathBuf,
+    str::FromStr,
+};
+
+/// Kind of rule source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleSourceKind {
+    /// `bypass_list` and `proxy_list` in configuration
+    Inline,
+    /// `[bypass_list]` and `[proxy_list]` of the ACL file
+    Acl,
+    /// `[bypass_list]` and `[proxy_list]` of rule set files
+    RuleSets,
+}
+
+impl RuleSourceKind {
+    /// Name of the kind
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            RuleSourceKind::Inline => "inline",
+            RuleSourceKind::Acl => "acl",
+            RuleSourceKind::RuleSets => "rule_sets",
+        }
+    }
+}
+
+impl Display for RuleSourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Parsing RuleSourceKind error
+#[derive(Debug, Clone, Copy)]
+pub struct RuleSourceKindError;
+
+impl Display for RuleSourceKindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid RuleSourceKind")
+    }
+}
+
+impl FromStr for RuleSourceKind {
+    type Err = RuleSourceKindError;
+
+    fn from_str(s: &str) -> Result<RuleSourceKind, Self::Err> {
+        match s {
+            "inline" => Ok(RuleSourceKind::Inline),
+            "acl" => Ok(RuleSourceKind::Acl),
+            "rule_sets" => Ok(RuleSourceKind::RuleSets),
+            _ => Err(RuleSourceKindError),
+        }
+    }
+}
+
+/// Rule set file, formatted as ACL file
+///
+/// Only `[bypass_list]` and `[proxy_list]` are used, other sections are ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleSet {
+    /// Path of the rule set file, also the cache of `url`
+    pub path: PathBuf,
+    /// Remote URL of the rule set, downloaded to `path` when `sslocal` starts
+    pub url: Option<String>,
+}
+
+/// Rule sources merged with the ACL file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RuleSources {
+    /// Order of sources, earlier sources take precedence. Kinds that are not listed follow in the default order
+    pub precedence: Vec<RuleSourceKind>,
+    /// Inline rules for connecting directly
+    pub bypass_list: Vec<String>,
+    /// Inline rules for connecting through proxies
+    pub proxy_list: Vec<String>,
+    /// Rule set files
+    pub rule_sets: Vec<RuleSet>,
+    /// Directory of `<country code>.txt` network lists for `geoip:<country code>` rules
+    pub geoip_dir: Option<PathBuf>,
+}
+
+impl RuleSources {
+    /// Default order of sources
+    pub const DEFAULT_PRECEDENCE: [RuleSourceKind; 3] =
+        [RuleSourceKind::Inline, RuleSourceKind::Acl, RuleSourceKind::RuleSets];
+
+    /// Effective order of sources
+    pub fn effective_precedence(&self) -> Vec<RuleSourceKind> {
+        let mut precedence = Vec::with_capacity(Self::DEFAULT_PRECEDENCE.len());
+        for kind in self.precedence.iter().chain(Self::DEFAULT_PRECEDENCE.iter()) {
+            if !precedence.contains(kind) {
+                precedence.push(*kind);
+            }
+        }
+        precedence
+    }
+
+    /// Check if no sources are configured other than the ACL file
+    pub fn is_empty(&self) -> bool {
+        self.bypass_list.is_empty() && self.proxy_list.is_empty() && self.rule_sets.is_empty()
+    }
+}
@@ -16,6 +16,20 @@ impl DomainPart {
             children: HashMap::new(),
         }
     }
+
+    fn len(&self) -> usize {
+        self.included as usize + self.children.values().map(DomainPart::len).sum::<usize>()
+    }
+
+    fn collect_domains(&self, suffix: &str, domains: &mut Vec<String>) {
+        if self.included {
+            domains.push(suffix.to_owned());
+            return;
+        }
+        for (part, child) in &self.children {
+            child.collect_domains(&format!("{part}.{suffix}"), domains);
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -71,4 +85,18 @@ impl SubDomainsTree {
     pub fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
+
+    /// Number of domains in the tree
+    pub fn len(&self) -> usize {
+        self.0.values().map(DomainPart::len).sum()
+    }
+
+    /// All domains in the tree
+    pub fn domains(&self) -> Vec<String> {
+        let mut domains = Vec::with_capacity(self.len());
+        for (part, child) in &self.0 {
+            child.collect_domains(part, &mut domains);
+        }
+        domains
+    }
 }
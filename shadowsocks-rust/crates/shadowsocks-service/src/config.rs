@@ -41,6 +41,9 @@
 //!
 //! These defined server will be used with a load balancing algorithm.
 
+#[cfg(any(feature = "local-tun", feature = "local-dns"))]
+use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::{
     borrow::Cow,
     convert::{From, Infallible},
@@ -50,6 +53,7 @@ use std::{
     fs::OpenOptions,
     io::Read,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    ops::RangeInclusive,
     option::Option,
     path::{Path, PathBuf},
     str::FromStr,
@@ -60,10 +64,17 @@ use std::{
 use cfg_if::cfg_if;
 #[cfg(feature = "hickory-dns")]
 use hickory_resolver::config::{NameServerConfig, Protocol, ResolverConfig};
-#[cfg(feature = "local-tun")]
+#[cfg(any(
+    feature = "local-tun",
+    feature = "local-http",
+    feature = "manager-http",
+    all(feature = "local-redir", target_os = "linux")
+))]
 use ipnet::IpNet;
 #[cfg(feature = "local-fake-dns")]
-use ipnet::{Ipv4Net, Ipv6Net};
+use ipnet::Ipv4Net;
+#[cfg(any(feature = "local-fake-dns", all(feature = "local-tun", windows)))]
+use ipnet::Ipv6Net;
 use log::warn;
 use serde::{Deserialize, Serialize};
 #[cfg(any(feature = "local-tunnel", feature = "local-dns"))]
@@ -74,12 +85,31 @@ use shadowsocks::{
         ServerWeight,
     },
     crypto::CipherKind,
+    net::AddrFamily,
     plugin::PluginConfig,
 };
 
-use crate::acl::AccessControl;
+use crate::acl::{AccessControl, RuleSet, RuleSourceKind, RuleSources, ServerRoutes};
+#[cfg(feature = "local-dns-over-https")]
+use crate::local::dns::config::DnsHttpsConfig;
+#[cfg(feature = "local-dns-over-tls")]
+use crate::local::dns::config::DnsTlsConfig;
 #[cfg(feature = "local-dns")]
-use crate::local::dns::NameServerAddr;
+use crate::local::dns::{
+    config::{
+        DnsAclStrategy, DnsBlockResponse, DnsClientSubnet, DnsQueryLogConfig, DnsRule, DnsRuleDropAnswers,
+        DnsRuleUpstream, DnsUpstreamStrategy,
+    },
+    NameServerAddr,
+};
+#[cfg(feature = "local-http")]
+use crate::local::http::HttpAuthConfig;
+#[cfg(feature = "local")]
+use crate::local::loadbalancing::score_expr::ScoreExpr;
+#[cfg(feature = "local")]
+use crate::local::net::ipc::IpcAddr;
+#[cfg(feature = "local-tls")]
+use crate::local::net::tls::TlsListenerConfig;
 #[cfg(feature = "local")]
 use crate::local::socks::config::Socks5AuthConfig;
 
@@ -111,6 +141,309 @@ struct SSBalancerConfig {
     check_interval: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     check_best_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score_tolerance: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strategy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_strategy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score_mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ewma_alpha: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failure_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rtt_weight: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    loss_weight: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verify_servers: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    check_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    check_dns: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    check_stun: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp_check_method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_check_method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_probe_packets: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    check_fail_threshold: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    check_recover_threshold: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tier_max_latency: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    switch_margin: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    switch_dwell: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warmup_checks: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warmup_bypass: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score_expr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    routes: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_file: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSMirrorConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pcap_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_sink: Option<String>,
+}
+
+#[cfg(feature = "local-history")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSHistoryConfig {
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retention_days: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_records: Option<u64>,
+}
+
+#[cfg(feature = "local-file-watch")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSFileWatchConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debounce_ms: Option<u64>,
+}
+
+#[cfg(feature = "local-tun")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSTunDscpConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preserve: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    map: Option<BTreeMap<String, u8>>,
+}
+
+#[cfg(all(feature = "local-redir", target_os = "linux"))]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSRedirFirewallConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backend: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interfaces: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bypass: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fwmark: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    route_table: Option<u32>,
+}
+
+#[cfg(feature = "local-tun")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSTunDnsHijackRule {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    action: String,
+}
+
+#[cfg(all(feature = "local-tun", windows))]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSTunIpv6Config {
+    address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    routes: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns: Option<Vec<String>>,
+}
+
+#[cfg(feature = "local-dns")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSDnsRule {
+    domains: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstream: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    drop_answers: Option<String>,
+}
+
+#[cfg(feature = "local-dns")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSDnsQueryLogConfig {
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_files: Option<usize>,
+}
+
+#[cfg(feature = "local-dns-over-https")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSDnsHttpsConfig {
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bootstrap: Option<Vec<String>>,
+}
+
+#[cfg(feature = "local-dns-over-tls")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSDnsTlsConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ca_certificates: Option<String>,
+}
+
+#[cfg(feature = "local-dns-over-tls")]
+impl From<SSDnsTlsConfig> for DnsTlsConfig {
+    fn from(c: SSDnsTlsConfig) -> DnsTlsConfig {
+        DnsTlsConfig {
+            server_name: c.server_name,
+            ca_certificates: c.ca_certificates.map(PathBuf::from),
+        }
+    }
+}
+
+#[cfg(feature = "local-dns-over-tls")]
+impl From<&DnsTlsConfig> for SSDnsTlsConfig {
+    fn from(c: &DnsTlsConfig) -> SSDnsTlsConfig {
+        SSDnsTlsConfig {
+            server_name: c.server_name.clone(),
+            ca_certificates: c
+                .ca_certificates
+                .as_ref()
+                .map(|p| p.to_str().expect("ca_certificates is not utf-8").to_owned()),
+        }
+    }
+}
+
+#[cfg(all(feature = "local-tun", any(target_os = "linux", target_os = "macos", windows)))]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSTunAutoRouteConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bypass: Option<Vec<String>>,
+    #[cfg(target_os = "linux")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fwmark: Option<u32>,
+    #[cfg(target_os = "linux")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    route_table: Option<u32>,
+    #[cfg(target_os = "linux")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bypass_uids: Option<Vec<String>>,
+    #[cfg(target_os = "linux")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bypass_cgroups: Option<Vec<String>>,
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipv6: Option<bool>,
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipv6_address_suffix: Option<String>,
+}
+
+#[cfg(feature = "local-tunnel")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSTunnelForwardConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_address: Option<String>,
+    local_port: u16,
+    forward_address: String,
+    forward_port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+}
+
+#[cfg(feature = "local-http")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSLocalControlHttpConfig {
+    listen_address: String,
+    token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allowed_ips: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls_cert: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls_key: Option<String>,
+}
+
+#[cfg(feature = "manager-http")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSManagerHttpConfig {
+    listen_address: String,
+    token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allowed_ips: Option<Vec<String>>,
+}
+
+#[cfg(feature = "local")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSHaConfig {
+    listen_address: String,
+    peer_address: String,
+    secret: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    priority: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    heartbeat_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failover_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    notify_active: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    notify_standby: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSQosConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upload_kbps: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    download_kbps: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bulk_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bulk_age: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSUplinkConfig {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interface: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bind_addr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fwmark: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    weight: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSRulesConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    precedence: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bypass_list: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proxy_list: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rule_sets: Option<Vec<SSRuleSetConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    geoip_dir: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSRuleSetConfig {
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -140,6 +473,13 @@ struct SSConfig {
     manager_address: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     manager_port: Option<u16>,
+    #[cfg(feature = "manager-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manager_http: Option<SSManagerHttpConfig>,
+
+    #[cfg(feature = "metrics")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metrics_address: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     password: Option<String>,
@@ -194,6 +534,8 @@ struct SSConfig {
     ipv6_first: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     ipv6_only: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outbound_addr_family: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     fast_open: Option<bool>,
@@ -215,15 +557,50 @@ struct SSConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     outbound_bind_interface: Option<String>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uplinks: Option<Vec<SSUplinkConfig>>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     security: Option<SSSecurityConfig>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     balancer: Option<SSBalancerConfig>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mirror: Option<SSMirrorConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    qos: Option<SSQosConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    special_target_policy: Option<String>,
+
+    #[cfg(feature = "local-history")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    history: Option<SSHistoryConfig>,
+
+    #[cfg(feature = "local-file-watch")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_watch: Option<SSFileWatchConfig>,
+
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_control_address: Option<String>,
+
+    #[cfg(feature = "local-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_control_http: Option<SSLocalControlHttpConfig>,
+
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ha: Option<SSHaConfig>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     acl: Option<String>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rules: Option<SSRulesConfig>,
+
     #[cfg(feature = "local-online-config")]
     #[serde(skip_serializing_if = "Option::is_none")]
     version: Option<u32>,
@@ -254,6 +631,14 @@ struct SSLocalExtConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     protocol: Option<String>,
 
+    /// Listening on IPC instead of `local_address`
+    #[cfg(all(feature = "local", unix))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_unix_path: Option<String>,
+    #[cfg(all(feature = "local", windows))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_pipe_name: Option<String>,
+
     /// macOS launch activate socket
     #[cfg(target_os = "macos")]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -270,6 +655,10 @@ struct SSLocalExtConfig {
     #[cfg(feature = "local-redir")]
     #[serde(skip_serializing_if = "Option::is_none")]
     udp_redir: Option<String>,
+    /// Firewall rules installed by redir
+    #[cfg(all(feature = "local-redir", target_os = "linux"))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redir_firewall: Option<SSRedirFirewallConfig>,
 
     /// Local DNS's address
     ///
@@ -280,6 +669,13 @@ struct SSLocalExtConfig {
     #[cfg(feature = "local-dns")]
     #[serde(skip_serializing_if = "Option::is_none")]
     local_dns_port: Option<u16>,
+    /// Local DNS upstreams besides `local_dns_address`, queried in plain DNS
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_dns_extra_addresses: Option<Vec<String>>,
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_upstream_strategy: Option<String>,
     /// Remote DNS's address
     ///
     /// Sending DNS query through proxy to this address
@@ -289,9 +685,69 @@ struct SSLocalExtConfig {
     #[cfg(feature = "local-dns")]
     #[serde(skip_serializing_if = "Option::is_none")]
     remote_dns_port: Option<u16>,
+    /// DNS over HTTPS endpoint for remote queries, instead of `remote_dns_address`
+    #[cfg(feature = "local-dns-over-https")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_dns_https: Option<SSDnsHttpsConfig>,
+    /// DNS over TLS of `local_dns_address` and `remote_dns_address`
+    #[cfg(feature = "local-dns-over-tls")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_dns_tls: Option<SSDnsTlsConfig>,
+    #[cfg(feature = "local-dns-over-tls")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_dns_tls: Option<SSDnsTlsConfig>,
+    /// DNS over QUIC of `local_dns_address`
+    #[cfg(feature = "local-dns-over-quic")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_dns_quic: Option<SSDnsTlsConfig>,
     #[cfg(feature = "local-dns")]
     #[serde(skip_serializing_if = "Option::is_none")]
     client_cache_size: Option<usize>,
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_quarantine_failures: Option<u32>,
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_quarantine_duration: Option<u64>,
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_cache_size: Option<usize>,
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_cache_min_ttl: Option<u64>,
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_cache_max_ttl: Option<u64>,
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_rules: Option<Vec<SSDnsRule>>,
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_client_subnet: Option<String>,
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_acl_strategy: Option<String>,
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_block_lists: Option<Vec<String>>,
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_block_response: Option<String>,
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_hosts: Option<BTreeMap<String, Vec<String>>>,
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_query_log: Option<SSDnsQueryLogConfig>,
+    #[cfg(feature = "local-dns-server-tls")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_tls_address: Option<String>,
+    #[cfg(feature = "local-dns-server-https")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_https_address: Option<String>,
+    #[cfg(feature = "local-dns-server-https")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_https_path: Option<String>,
 
     /// Tunnel
     #[cfg(feature = "local-tunnel")]
@@ -300,6 +756,15 @@ struct SSLocalExtConfig {
     #[cfg(feature = "local-tunnel")]
     #[serde(skip_serializing_if = "Option::is_none")]
     forward_port: Option<u16>,
+    #[cfg(feature = "local-tunnel")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    forward_dns_timeout: Option<u64>,
+    #[cfg(feature = "local-tunnel")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    forwards: Option<Vec<SSTunnelForwardConfig>>,
+    #[cfg(feature = "local-tunnel")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reverse_port: Option<u16>,
 
     /// Tun
     #[cfg(feature = "local-tun")]
@@ -311,29 +776,127 @@ struct SSLocalExtConfig {
     #[cfg(feature = "local-tun")]
     #[serde(skip_serializing_if = "Option::is_none")]
     tun_interface_destination: Option<String>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_interface_mtu: Option<u16>,
     #[cfg(all(feature = "local-tun", unix))]
     #[serde(skip_serializing_if = "Option::is_none")]
     tun_device_fd_from_path: Option<String>,
-
-    /// SOCKS5
-    #[cfg(feature = "local")]
+    #[cfg(all(feature = "local-tun", unix))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    socks5_auth_config_path: Option<String>,
-
-    /// Fake DNS
-    #[cfg(feature = "local-fake-dns")]
+    tun_device_fd: Option<i32>,
+    #[cfg(feature = "local-tun")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub fake_dns_record_expire_duration: Option<u64>,
-    #[cfg(feature = "local-fake-dns")]
+    tun_dscp: Option<SSTunDscpConfig>,
+    #[cfg(all(feature = "local-tun", windows))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub fake_dns_ipv4_network: Option<String>,
-    #[cfg(feature = "local-fake-dns")]
+    tun_ipv6: Option<SSTunIpv6Config>,
+    #[cfg(all(feature = "local-tun", feature = "local-fake-dns"))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub fake_dns_ipv6_network: Option<String>,
-    #[cfg(feature = "local-fake-dns")]
+    tun_fake_dns: Option<bool>,
+    #[cfg(feature = "local-tun")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub fake_dns_database_path: Option<String>,
-
+    tun_dns_hijack: Option<Vec<SSTunDnsHijackRule>>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_icmp_echo: Option<String>,
+    #[cfg(all(feature = "local-tun", any(target_os = "linux", target_os = "macos", windows)))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_auto_route: Option<SSTunAutoRouteConfig>,
+    #[cfg(all(feature = "local-tun", target_os = "linux"))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_offload: Option<bool>,
+
+    /// Limits of inbound connections
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_connections: Option<usize>,
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_connections_per_ip: Option<usize>,
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connection_rate: Option<u32>,
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connection_rate_per_ip: Option<u32>,
+
+    /// Domain names sniffed from transparently intercepted TCP connections
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sniff_timeout: Option<u64>,
+
+    /// NAT behavior of UDP associations of transparently intercepted clients
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_nat: Option<String>,
+
+    /// Point HTTP / SOCKS proxy settings of the system to this local
+    #[cfg(all(feature = "local", any(target_os = "macos", windows)))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_proxy: Option<bool>,
+
+    /// SOCKS5
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    socks5_auth_config_path: Option<String>,
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    socks5_udp_prebind: Option<bool>,
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    socks5_udp_external_addresses: Option<HashMap<String, String>>,
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    socks5_username_routing: Option<bool>,
+
+    /// HTTP
+    #[cfg(feature = "local-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    http_auth_config_path: Option<String>,
+    #[cfg(feature = "local-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    http_pac_path: Option<String>,
+    #[cfg(feature = "local-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    http_pac_acl: Option<bool>,
+
+    /// TLS of listener
+    #[cfg(feature = "local-tls")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls_cert: Option<String>,
+    #[cfg(feature = "local-tls")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls_key: Option<String>,
+    #[cfg(feature = "local-tls")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls_client_ca: Option<String>,
+
+    /// Fake DNS
+    #[cfg(feature = "local-fake-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fake_dns_record_expire_duration: Option<u64>,
+    #[cfg(feature = "local-fake-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fake_dns_ipv4_network: Option<String>,
+    #[cfg(feature = "local-fake-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fake_dns_ipv6_network: Option<String>,
+    #[cfg(feature = "local-fake-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fake_dns_database_path: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    no_delay: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp_idle_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp_half_close_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp_mss: Option<u32>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     acl: Option<String>,
 }
@@ -342,6 +905,14 @@ struct SSLocalExtConfig {
 struct SSServerUserConfig {
     name: String,
     password: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upload_kbps: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    download_kbps: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    acl: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -353,6 +924,8 @@ struct SSServerExtConfig {
     server: String,
     #[serde(alias = "port")]
     server_port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_port_range: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     password: Option<String>,
@@ -389,6 +962,43 @@ struct SSServerExtConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     udp_weight: Option<f32>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    check_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    check_dns: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    check_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    check_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    check_fail_threshold: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    check_recover_threshold: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tier: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    no_delay: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_connections: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_connections_per_ip: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_max_associations_per_ip: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upload_kbps: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    download_kbps: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota_reset_day: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp_bind: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reverse_tunnel: Option<bool>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     acl: Option<String>,
 
@@ -401,6 +1011,9 @@ struct SSServerExtConfig {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     outbound_bind_interface: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uplink: Option<String>,
 }
 
 #[cfg(feature = "local-online-config")]
@@ -492,6 +1105,15 @@ cfg_if! {
             /// Document: https://www.freebsd.org/doc/handbook/firewalls-ipfw.html
             #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "ios"))]
             IpFirewall,
+
+            /// Packet Filter (pf) `divert-to`
+            ///
+            /// Supported by OpenBSD 4.6+. Packets are delivered to the listening socket unmodified, so original
+            /// destinations are addresses of accepted TCP sockets, and of received UDP packets.
+            ///
+            /// Document: <https://man.openbsd.org/pf.conf#divert-to>
+            #[cfg(target_os = "openbsd")]
+            PacketFilterDivert,
         }
 
         impl RedirType {
@@ -568,6 +1190,30 @@ cfg_if! {
                         const AVAILABLE_TYPES: &[&str] = &[RedirType::PacketFilter.name()];
                         AVAILABLE_TYPES
                     }
+                } else if #[cfg(target_os = "openbsd")] {
+                    /// Default TCP transparent proxy solution on this platform
+                    pub fn tcp_default() -> RedirType {
+                        RedirType::PacketFilterDivert
+                    }
+
+                    /// Available TCP transparent proxy types
+                    #[doc(hidden)]
+                    pub const fn tcp_available_types() -> &'static [&'static str] {
+                        const AVAILABLE_TYPES: &[&str] = &[RedirType::PacketFilterDivert.name()];
+                        AVAILABLE_TYPES
+                    }
+
+                    /// Default UDP transparent proxy solution on this platform
+                    pub fn udp_default() -> RedirType {
+                        RedirType::PacketFilterDivert
+                    }
+
+                    /// Available UDP transparent proxy types
+                    #[doc(hidden)]
+                    pub const fn udp_available_types() -> &'static [&'static str] {
+                        const AVAILABLE_TYPES: &[&str] = &[RedirType::PacketFilterDivert.name()];
+                        AVAILABLE_TYPES
+                    }
                 } else {
                     /// Default TCP transparent proxy solution on this platform
                     pub fn tcp_default() -> RedirType {
@@ -621,6 +1267,9 @@ cfg_if! {
 
                     #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "ios"))]
                     RedirType::IpFirewall => "ipfw",
+
+                    #[cfg(target_os = "openbsd")]
+                    RedirType::PacketFilterDivert => "pf-divert",
                 }
             }
         }
@@ -666,6 +1315,9 @@ cfg_if! {
                     ))]
                     "ipfw" => Ok(RedirType::IpFirewall),
 
+                    #[cfg(target_os = "openbsd")]
+                    "pf-divert" => Ok(RedirType::PacketFilterDivert),
+
                     _ => Err(InvalidRedirType),
                 }
             }
@@ -746,6 +1398,18 @@ impl Display for ManagerServerMode {
     }
 }
 
+/// HTTP REST API of manager, authenticated by a bearer token
+#[cfg(feature = "manager-http")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManagerHttpConfig {
+    /// Listen address, must be a loopback address
+    pub listen_addr: SocketAddr,
+    /// Token in the `Authorization: Bearer` header
+    pub token: String,
+    /// Clients that are allowed to connect, all clients are allowed if empty
+    pub allowed_ips: Vec<IpNet>,
+}
+
 /// Configuration for Manager
 #[derive(Clone, Debug)]
 pub struct ManagerConfig {
@@ -771,6 +1435,9 @@ pub struct ManagerConfig {
     /// Server's working directory if running in Standalone mode
     #[cfg(unix)]
     pub server_working_directory: PathBuf,
+    /// HTTP REST API, besides the manager protocol on `addr`
+    #[cfg(feature = "manager-http")]
+    pub http: Option<ManagerHttpConfig>,
 }
 
 impl ManagerConfig {
@@ -791,6 +1458,8 @@ impl ManagerConfig {
                 Ok(d) => d,
                 Err(..) => "/tmp/shadowsocks-manager".into(),
             },
+            #[cfg(feature = "manager-http")]
+            http: None,
         }
     }
 }
@@ -802,8 +1471,14 @@ pub enum ProtocolType {
     Socks,
     #[cfg(feature = "local-http")]
     Http,
+    /// SOCKS and HTTP on the same port
+    #[cfg(feature = "local-http")]
+    Mixed,
     #[cfg(feature = "local-tunnel")]
     Tunnel,
+    /// Expose `forward_addr` on a port of the server
+    #[cfg(feature = "local-tunnel")]
+    ReverseTunnel,
     #[cfg(feature = "local-redir")]
     Redir,
     #[cfg(feature = "local-dns")]
@@ -821,8 +1496,12 @@ impl ProtocolType {
             ProtocolType::Socks => "socks",
             #[cfg(feature = "local-http")]
             ProtocolType::Http => "http",
+            #[cfg(feature = "local-http")]
+            ProtocolType::Mixed => "mixed",
             #[cfg(feature = "local-tunnel")]
             ProtocolType::Tunnel => "tunnel",
+            #[cfg(feature = "local-tunnel")]
+            ProtocolType::ReverseTunnel => "reverse-tunnel",
             #[cfg(feature = "local-redir")]
             ProtocolType::Redir => "redir",
             #[cfg(feature = "local-dns")]
@@ -840,8 +1519,12 @@ impl ProtocolType {
             "socks",
             #[cfg(feature = "local-http")]
             "http",
+            #[cfg(feature = "local-http")]
+            "mixed",
             #[cfg(feature = "local-tunnel")]
             "tunnel",
+            #[cfg(feature = "local-tunnel")]
+            "reverse-tunnel",
             #[cfg(feature = "local-redir")]
             "redir",
             #[cfg(feature = "local-dns")]
@@ -852,6 +1535,24 @@ impl ProtocolType {
             "fake-dns",
         ]
     }
+
+    /// Cargo feature required by protocol `s`, if it is a known protocol but not enabled in this build
+    pub fn missing_feature(s: &str) -> Option<&'static str> {
+        let (feature, enabled) = match s {
+            "http" | "mixed" => ("local-http", cfg!(feature = "local-http")),
+            "tunnel" | "reverse-tunnel" => ("local-tunnel", cfg!(feature = "local-tunnel")),
+            "redir" => ("local-redir", cfg!(feature = "local-redir")),
+            "dns" => ("local-dns", cfg!(feature = "local-dns")),
+            "tun" => ("local-tun", cfg!(feature = "local-tun")),
+            "fake-dns" => ("local-fake-dns", cfg!(feature = "local-fake-dns")),
+            _ => return None,
+        };
+        if enabled {
+            None
+        } else {
+            Some(feature)
+        }
+    }
 }
 
 /// Error while parsing `ProtocolType` from string
@@ -872,8 +1573,12 @@ impl FromStr for ProtocolType {
             "socks" => Ok(ProtocolType::Socks),
             #[cfg(feature = "local-http")]
             "http" => Ok(ProtocolType::Http),
+            #[cfg(feature = "local-http")]
+            "mixed" => Ok(ProtocolType::Mixed),
             #[cfg(feature = "local-tunnel")]
             "tunnel" => Ok(ProtocolType::Tunnel),
+            #[cfg(feature = "local-tunnel")]
+            "reverse-tunnel" => Ok(ProtocolType::ReverseTunnel),
             #[cfg(feature = "local-redir")]
             "redir" => Ok(ProtocolType::Redir),
             #[cfg(feature = "local-dns")]
@@ -908,6 +1613,15 @@ pub struct LocalConfig {
     /// Destination address for tunnel
     #[cfg(feature = "local-tunnel")]
     pub forward_addr: Option<Address>,
+    /// Treat UDP packets of tunnel as DNS messages, queries that are not answered in this long are answered `SERVFAIL`
+    #[cfg(feature = "local-tunnel")]
+    pub forward_dns_timeout: Option<Duration>,
+    /// More `local -> remote` mappings of tunnel, served along with `addr -> forward_addr`
+    #[cfg(feature = "local-tunnel")]
+    pub tunnel_forwards: Vec<TunnelForwardConfig>,
+    /// Port of the server that reverse tunnel listens on, any port is chosen by the server if not set
+    #[cfg(feature = "local-tunnel")]
+    pub reverse_port: Option<u16>,
 
     /// TCP Transparent Proxy type
     #[cfg(feature = "local-redir")]
@@ -915,6 +1629,9 @@ pub struct LocalConfig {
     /// UDP Transparent Proxy type
     #[cfg(feature = "local-redir")]
     pub udp_redir: RedirType,
+    /// Install firewall rules intercepting forwarded traffic when redir starts, and remove them when it stops
+    #[cfg(all(feature = "local-redir", target_os = "linux"))]
+    pub redir_firewall: Option<RedirFirewallConfig>,
 
     /// Local DNS's address
     ///
@@ -926,11 +1643,74 @@ pub struct LocalConfig {
     /// Sending DNS query through proxy to this address
     #[cfg(feature = "local-dns")]
     pub remote_dns_addr: Option<Address>,
+    /// DNS over HTTPS endpoint, sending DNS query through proxy to it instead of `remote_dns_addr`
+    #[cfg(feature = "local-dns-over-https")]
+    pub remote_dns_https: Option<DnsHttpsConfig>,
+    /// Send DNS query to `local_dns_addr` with TLS
+    #[cfg(feature = "local-dns-over-tls")]
+    pub local_dns_tls: Option<DnsTlsConfig>,
+    /// Send DNS query to `remote_dns_addr` with TLS
+    #[cfg(feature = "local-dns-over-tls")]
+    pub remote_dns_tls: Option<DnsTlsConfig>,
+    /// Send DNS query to `local_dns_addr` with QUIC
+    #[cfg(feature = "local-dns-over-quic")]
+    pub local_dns_quic: Option<DnsTlsConfig>,
     // client cache size
     // if a lot of `create connection` observed in log,
     // increase the size
     #[cfg(feature = "local-dns")]
     pub client_cache_size: Option<usize>,
+    /// Consecutive failures before quarantining a DNS upstream, 0 disables quarantine
+    #[cfg(feature = "local-dns")]
+    pub dns_quarantine_failures: Option<u32>,
+    /// How long a failing DNS upstream stays quarantined
+    #[cfg(feature = "local-dns")]
+    pub dns_quarantine_duration: Option<Duration>,
+    /// Maximum number of cached DNS responses, 0 disables cache
+    #[cfg(feature = "local-dns")]
+    pub dns_cache_size: Option<usize>,
+    /// Minimum TTL of cached DNS responses
+    #[cfg(feature = "local-dns")]
+    pub dns_cache_min_ttl: Option<Duration>,
+    /// Maximum TTL of cached DNS responses
+    #[cfg(feature = "local-dns")]
+    pub dns_cache_max_ttl: Option<Duration>,
+    /// Upstreams of DNS queries by domains, the first matched rule is used
+    #[cfg(feature = "local-dns")]
+    pub dns_rules: Vec<DnsRule>,
+    /// EDNS Client Subnet of DNS queries sent to `remote_dns_addr`
+    #[cfg(feature = "local-dns")]
+    pub dns_client_subnet: Option<DnsClientSubnet>,
+    /// How DNS queries of names that don't match domain rules of ACL are resolved
+    #[cfg(feature = "local-dns")]
+    pub dns_acl_strategy: Option<DnsAclStrategy>,
+    /// Local DNS upstreams besides `local_dns_addr`
+    #[cfg(feature = "local-dns")]
+    pub local_dns_extra_addrs: Vec<NameServerAddr>,
+    /// How DNS queries are sent to `local_dns_addr` and `local_dns_extra_addrs`
+    #[cfg(feature = "local-dns")]
+    pub dns_upstream_strategy: Option<DnsUpstreamStrategy>,
+    /// Files of names that are blocked, in hosts, adblock or domain list formats
+    #[cfg(feature = "local-dns")]
+    pub dns_block_lists: Vec<PathBuf>,
+    /// Response of DNS queries of blocked names
+    #[cfg(feature = "local-dns")]
+    pub dns_block_response: Option<DnsBlockResponse>,
+    /// Static addresses of names, which are answered without DNS upstreams
+    #[cfg(feature = "local-dns")]
+    pub dns_hosts: HashMap<String, Vec<IpAddr>>,
+    /// Log of DNS queries, in JSON lines
+    #[cfg(feature = "local-dns")]
+    pub dns_query_log: Option<DnsQueryLogConfig>,
+    /// Also serve DNS over TLS on this address, with certificate `tls`
+    #[cfg(feature = "local-dns-server-tls")]
+    pub dns_tls_addr: Option<ServerAddr>,
+    /// Also serve DNS over HTTPS on this address, with certificate `tls`
+    #[cfg(feature = "local-dns-server-https")]
+    pub dns_https_addr: Option<ServerAddr>,
+    /// Path of the DNS over HTTPS endpoint, `/dns-query` by default
+    #[cfg(feature = "local-dns-server-https")]
+    pub dns_https_path: Option<String>,
 
     /// Tun interface's name
     ///
@@ -944,12 +1724,42 @@ pub struct LocalConfig {
     /// Tun interface's destination address and netmask
     #[cfg(feature = "local-tun")]
     pub tun_interface_destination: Option<IpNet>,
+    /// Tun interface's MTU, 1500 if not set
+    #[cfg(feature = "local-tun")]
+    pub tun_interface_mtu: Option<u16>,
     /// Tun interface's file descriptor
+    ///
+    /// Like the one established by Android `VpnService.Builder`, for embedders that already opened the interface
     #[cfg(all(feature = "local-tun", unix))]
     pub tun_device_fd: Option<std::os::unix::io::RawFd>,
     /// Tun interface's file descriptor read from this Unix Domain Socket
     #[cfg(all(feature = "local-tun", unix))]
     pub tun_device_fd_from_path: Option<PathBuf>,
+    /// Reflect DSCP of intercepted packets onto outbound connections
+    #[cfg(feature = "local-tun")]
+    pub tun_dscp: Option<TunDscpConfig>,
+    /// IPv6 address, routes and DNS servers of the tun interface (wintun)
+    #[cfg(all(feature = "local-tun", windows))]
+    pub tun_ipv6: Option<TunIpv6Config>,
+    /// Answer DNS queries intercepted by tun with fake addresses, which are mapped back to domain names
+    ///
+    /// Fake addresses are allocated with `fake_dns_*` options of this local
+    #[cfg(all(feature = "local-tun", feature = "local-fake-dns"))]
+    pub tun_fake_dns: bool,
+    /// Rules of DNS queries intercepted by tun, the first rule matching the destination decides
+    ///
+    /// Queries to port 53 are hijacked if `tun_fake_dns` is enabled, or passed otherwise, if no rules match
+    #[cfg(feature = "local-tun")]
+    pub tun_dns_hijack: Vec<TunDnsHijackRule>,
+    /// Handling of ICMP echo requests (ping) intercepted by tun
+    #[cfg(feature = "local-tun")]
+    pub tun_icmp_echo: TunIcmpEchoPolicy,
+    /// Route all IPv4 traffic to tun when it is up, except servers and bypassed networks, restored when it stops
+    #[cfg(all(feature = "local-tun", any(target_os = "linux", target_os = "macos", windows)))]
+    pub tun_auto_route: Option<TunAutoRouteConfig>,
+    /// Create tun with virtio-net headers, reading and writing TCP in GSO packets of up to 64KiB
+    #[cfg(all(feature = "local-tun", target_os = "linux"))]
+    pub tun_offload: bool,
 
     /// macOS launchd socket for TCP listener
     ///
@@ -988,12 +1798,56 @@ pub struct LocalConfig {
     #[cfg(target_os = "macos")]
     pub launchd_udp_socket_name: Option<String>,
 
+    /// Listen on a Unix domain socket or Windows named pipe instead of `addr`, for `socks`, `http` and `mixed`
+    #[cfg(feature = "local")]
+    pub ipc_addr: Option<IpcAddr>,
+
+    /// Limits of inbound connections, for `socks`, `http`, `mixed` and `tunnel`
+    #[cfg(feature = "local")]
+    pub inbound_limit: InboundLimitConfig,
+
+    /// Sniff TLS SNI or HTTP `Host` of TCP clients of redir, tun and tunnel locals, waiting at most this long for them
+    ///
+    /// Sniffed domain names are matched by ACL rules before IP addresses of targets.
+    #[cfg(feature = "local")]
+    pub sniff_timeout: Option<Duration>,
+
+    /// NAT behavior of UDP associations of redir and tun locals
+    #[cfg(feature = "local")]
+    pub udp_nat: UdpNatType,
+
+    /// Set HTTP / SOCKS proxy settings of the system to this local when it starts, restored when it stops
+    #[cfg(all(feature = "local", any(target_os = "macos", windows)))]
+    pub system_proxy: bool,
+
     /// Set `IPV6_V6ONLY` for listener socket
     pub ipv6_only: bool,
 
     /// SOCKS5 Authentication configuration
     #[cfg(feature = "local")]
     pub socks5_auth: Socks5AuthConfig,
+    /// Create UDP association as soon as SOCKS5 `UDP ASSOCIATE` request arrives
+    #[cfg(feature = "local")]
+    pub socks5_udp_prebind: bool,
+    /// Addresses replied to SOCKS5 `UDP ASSOCIATE` requests, by local addresses that clients connected to
+    ///
+    /// For multi-homed hosts, clients on each interface get a reachable address of the UDP relay
+    #[cfg(feature = "local")]
+    pub socks5_udp_external_addrs: HashMap<IpAddr, IpAddr>,
+
+    /// HTTP proxy Authentication configuration
+    #[cfg(feature = "local-http")]
+    pub http_auth: HttpAuthConfig,
+    /// Path of the PAC file served by HTTP local server
+    #[cfg(feature = "local-http")]
+    pub http_pac_path: Option<String>,
+    /// Embed ACL rules in the PAC file
+    #[cfg(feature = "local-http")]
+    pub http_pac_acl: bool,
+
+    /// Accept TCP clients with TLS, for `socks`, `http` and `mixed`
+    #[cfg(feature = "local-tls")]
+    pub tls: Option<TlsListenerConfig>,
 
     /// Fake DNS record expire seconds
     #[cfg(feature = "local-fake-dns")]
@@ -1030,18 +1884,68 @@ impl LocalConfig {
 
             #[cfg(feature = "local-tunnel")]
             forward_addr: None,
+            #[cfg(feature = "local-tunnel")]
+            forward_dns_timeout: None,
+            #[cfg(feature = "local-tunnel")]
+            tunnel_forwards: Vec::new(),
+            #[cfg(feature = "local-tunnel")]
+            reverse_port: None,
 
             #[cfg(feature = "local-redir")]
             tcp_redir: RedirType::tcp_default(),
             #[cfg(feature = "local-redir")]
             udp_redir: RedirType::udp_default(),
+            #[cfg(all(feature = "local-redir", target_os = "linux"))]
+            redir_firewall: None,
 
             #[cfg(feature = "local-dns")]
             local_dns_addr: None,
             #[cfg(feature = "local-dns")]
             remote_dns_addr: None,
+            #[cfg(feature = "local-dns-over-https")]
+            remote_dns_https: None,
+            #[cfg(feature = "local-dns-over-tls")]
+            local_dns_tls: None,
+            #[cfg(feature = "local-dns-over-tls")]
+            remote_dns_tls: None,
+            #[cfg(feature = "local-dns-over-quic")]
+            local_dns_quic: None,
             #[cfg(feature = "local-dns")]
             client_cache_size: None,
+            #[cfg(feature = "local-dns")]
+            dns_quarantine_failures: None,
+            #[cfg(feature = "local-dns")]
+            dns_quarantine_duration: None,
+            #[cfg(feature = "local-dns")]
+            dns_cache_size: None,
+            #[cfg(feature = "local-dns")]
+            dns_cache_min_ttl: None,
+            #[cfg(feature = "local-dns")]
+            dns_cache_max_ttl: None,
+            #[cfg(feature = "local-dns")]
+            dns_rules: Vec::new(),
+            #[cfg(feature = "local-dns")]
+            dns_client_subnet: None,
+            #[cfg(feature = "local-dns")]
+            dns_acl_strategy: None,
+            #[cfg(feature = "local-dns")]
+            local_dns_extra_addrs: Vec::new(),
+            #[cfg(feature = "local-dns")]
+            dns_upstream_strategy: None,
+            #[cfg(feature = "local-dns")]
+            dns_block_lists: Vec::new(),
+            #[cfg(feature = "local-dns")]
+            dns_block_response: None,
+            #[cfg(feature = "local-dns")]
+            dns_hosts: HashMap::new(),
+            #[cfg(feature = "local-dns")]
+            dns_query_log: None,
+            #[cfg(feature = "local-dns-server-tls")]
+            dns_tls_addr: None,
+            #[cfg(feature = "local-dns-server-https")]
+            dns_https_addr: None,
+            #[cfg(feature = "local-dns-server-https")]
+            dns_https_path: None,
 
             #[cfg(feature = "local-tun")]
             tun_interface_name: None,
@@ -1049,20 +1953,62 @@ impl LocalConfig {
             tun_interface_address: None,
             #[cfg(feature = "local-tun")]
             tun_interface_destination: None,
+            #[cfg(feature = "local-tun")]
+            tun_interface_mtu: None,
             #[cfg(all(feature = "local-tun", unix))]
             tun_device_fd: None,
             #[cfg(all(feature = "local-tun", unix))]
             tun_device_fd_from_path: None,
+            #[cfg(feature = "local-tun")]
+            tun_dscp: None,
+            #[cfg(all(feature = "local-tun", windows))]
+            tun_ipv6: None,
+            #[cfg(all(feature = "local-tun", feature = "local-fake-dns"))]
+            tun_fake_dns: false,
+            #[cfg(feature = "local-tun")]
+            tun_dns_hijack: Vec::new(),
+            #[cfg(feature = "local-tun")]
+            tun_icmp_echo: TunIcmpEchoPolicy::Reply,
+            #[cfg(all(feature = "local-tun", any(target_os = "linux", target_os = "macos", windows)))]
+            tun_auto_route: None,
+            #[cfg(all(feature = "local-tun", target_os = "linux"))]
+            tun_offload: false,
 
             #[cfg(target_os = "macos")]
             launchd_tcp_socket_name: None,
             #[cfg(target_os = "macos")]
             launchd_udp_socket_name: None,
 
+            #[cfg(feature = "local")]
+            ipc_addr: None,
+
+            #[cfg(feature = "local")]
+            inbound_limit: InboundLimitConfig::default(),
+            #[cfg(feature = "local")]
+            sniff_timeout: None,
+            #[cfg(feature = "local")]
+            udp_nat: UdpNatType::FullCone,
+            #[cfg(all(feature = "local", any(target_os = "macos", windows)))]
+            system_proxy: false,
+
             ipv6_only: false,
 
             #[cfg(feature = "local")]
             socks5_auth: Socks5AuthConfig::default(),
+            #[cfg(feature = "local")]
+            socks5_udp_prebind: false,
+            #[cfg(feature = "local")]
+            socks5_udp_external_addrs: HashMap::new(),
+
+            #[cfg(feature = "local-http")]
+            http_auth: HttpAuthConfig::default(),
+            #[cfg(feature = "local-http")]
+            http_pac_path: None,
+            #[cfg(feature = "local-http")]
+            http_pac_acl: false,
+
+            #[cfg(feature = "local-tls")]
+            tls: None,
 
             #[cfg(feature = "local-fake-dns")]
             fake_dns_record_expire_duration: None,
@@ -1086,9 +2032,20 @@ impl LocalConfig {
         match self.protocol {
             #[cfg(feature = "local-tun")]
             ProtocolType::Tun => {}
+            // Reverse tunnel doesn't listen locally
+            #[cfg(feature = "local-tunnel")]
+            ProtocolType::ReverseTunnel => {}
+            // Tunnel could only serve mappings in `tunnel_forwards`
+            #[cfg(feature = "local-tunnel")]
+            ProtocolType::Tunnel if self.forward_addr.is_none() && !self.tunnel_forwards.is_empty() => {}
 
             _ => {
-                if self.addr.is_none() {
+                #[cfg(feature = "local")]
+                let has_ipc_addr = self.ipc_addr.is_some();
+                #[cfg(not(feature = "local"))]
+                let has_ipc_addr = false;
+
+                if self.addr.is_none() && !has_ipc_addr {
                     let err = Error::new(ErrorKind::MissingField, "missing `addr` in configuration", None);
                     return Err(err);
                 }
@@ -1097,46 +2054,85 @@ impl LocalConfig {
 
         match self.protocol {
             #[cfg(feature = "local-dns")]
-            ProtocolType::Dns => {
-                if self.local_dns_addr.is_none() || self.remote_dns_addr.is_none() {
-                    let err = Error::new(
-                        ErrorKind::MissingField,
-                        "missing `local_dns_addr` or `remote_dns_addr` in configuration",
-                        None,
-                    );
-                    return Err(err);
-                }
+            ProtocolType::Dns if (self.local_dns_addr.is_none() || self.remote_dns_addr.is_none()) => {
+                let err = Error::new(
+                    ErrorKind::MissingField,
+                    "missing `local_dns_addr` or `remote_dns_addr` in configuration",
+                    None,
+                );
+                return Err(err);
             }
             #[cfg(feature = "local-tunnel")]
-            ProtocolType::Tunnel => {
-                if self.forward_addr.is_none() {
-                    let err = Error::new(ErrorKind::MissingField, "missing `forward_addr` in configuration", None);
-                    return Err(err);
-                }
+            ProtocolType::Tunnel if self.forward_addr.is_none() && self.tunnel_forwards.is_empty() => {
+                let err = Error::new(ErrorKind::MissingField, "missing `forward_addr` in configuration", None);
+                return Err(err);
+            }
+            #[cfg(feature = "local-tunnel")]
+            ProtocolType::Tunnel
+                if self.forward_addr.is_none()
+                    && (self.addr.is_some() || self.udp_addr.is_some() || self.forward_dns_timeout.is_some()) =>
+            {
+                let err = Error::new(
+                    ErrorKind::MissingField,
+                    "missing `forward_addr` of `addr`, `udp_addr` or `forward_dns_timeout` in configuration",
+                    None,
+                );
+                return Err(err);
+            }
+            #[cfg(feature = "local-tunnel")]
+            ProtocolType::ReverseTunnel if self.forward_addr.is_none() => {
+                let err = Error::new(ErrorKind::MissingField, "missing `forward_addr` in configuration", None);
+                return Err(err);
+            }
+            #[cfg(feature = "local-tunnel")]
+            ProtocolType::ReverseTunnel if !self.mode.enable_tcp() => {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "TCP mode have to be enabled for reverse-tunnel",
+                    None,
+                );
+                return Err(err);
             }
 
             #[cfg(feature = "local-http")]
-            ProtocolType::Http => {
-                if !self.mode.enable_tcp() {
-                    let err = Error::new(ErrorKind::Invalid, "TCP mode have to be enabled for http", None);
-                    return Err(err);
-                }
+            ProtocolType::Http if !self.mode.enable_tcp() => {
+                let err = Error::new(ErrorKind::Invalid, "TCP mode have to be enabled for http", None);
+                return Err(err);
             }
 
-            _ => {}
-        }
-
-        Ok(())
-    }
-
-    // Check if it is a basic format of local
+            #[cfg(all(feature = "local-tun", unix))]
+            ProtocolType::Tun if self.tun_device_fd.is_some() && self.tun_device_fd_from_path.is_some() => {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`tun_device_fd` conflicts with `tun_device_fd_from_path`",
+                    None,
+                );
+                return Err(err);
+            }
+            #[cfg(all(feature = "local-tun", any(target_os = "linux", target_os = "macos")))]
+            ProtocolType::Tun if self.tun_device_fd.is_some() && self.tun_auto_route.is_some() => {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`tun_auto_route` conflicts with `tun_device_fd`",
+                    Some("routes of tun devices created by others are managed by their creators".to_owned()),
+                );
+                return Err(err);
+            }
+
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    // Check if it is a basic format of local
     pub fn is_basic(&self) -> bool {
         if self.protocol != ProtocolType::Socks || self.udp_addr.is_some() {
             return false;
         }
 
         #[cfg(feature = "local-tunnel")]
-        if self.forward_addr.is_some() {
+        if self.forward_addr.is_some() || !self.tunnel_forwards.is_empty() || self.reverse_port.is_some() {
             return false;
         }
 
@@ -1145,11 +2141,35 @@ impl LocalConfig {
             return false;
         }
 
+        #[cfg(all(feature = "local-redir", target_os = "linux"))]
+        if self.redir_firewall.is_some() {
+            return false;
+        }
+
         #[cfg(feature = "local-dns")]
         if self.local_dns_addr.is_some() || self.remote_dns_addr.is_some() {
             return false;
         }
 
+        #[cfg(feature = "local")]
+        if self.ipc_addr.is_some()
+            || !self.inbound_limit.is_empty()
+            || self.sniff_timeout.is_some()
+            || self.udp_nat != UdpNatType::FullCone
+        {
+            return false;
+        }
+
+        #[cfg(all(feature = "local", any(target_os = "macos", windows)))]
+        if self.system_proxy {
+            return false;
+        }
+
+        #[cfg(feature = "local-tls")]
+        if self.tls.is_some() {
+            return false;
+        }
+
         true
     }
 }
@@ -1175,605 +2195,2714 @@ pub struct SecurityReplayAttackConfig {
     pub policy: ReplayAttackPolicy,
 }
 
-/// Balancer Config
-#[derive(Clone, Debug, Default)]
-pub struct BalancerConfig {
-    /// MAX rtt of servers, which is the timeout duration of each check requests
-    pub max_server_rtt: Option<Duration>,
-    /// Interval between each checking
-    pub check_interval: Option<Duration>,
-    /// Interval for checking the best server
-    pub check_best_interval: Option<Duration>,
+/// Strategy of choosing server in balancer
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum BalancerStrategy {
+    /// Choose the server with the best statistic score
+    #[default]
+    BestScore,
+    /// Choose the available server with the least active sessions
+    LeastConnections,
+    /// Choose the available server by hashing the target address
+    ConsistentHash,
+    /// Choose the available server by hashing the client's source IP
+    SourceHash,
 }
 
-/// Address for local to report flow statistic data
-#[cfg(feature = "local-flow-stat")]
-#[derive(Debug, Clone)]
-pub enum LocalFlowStatAddress {
-    /// UNIX Domain Socket address
-    #[cfg(unix)]
-    UnixStreamPath(PathBuf),
-    /// TCP Stream Address
-    TcpStreamAddr(SocketAddr),
-}
+/// Parsing BalancerStrategy error
+#[derive(Debug, Clone, Copy)]
+pub struct BalancerStrategyError;
 
-/// Server instance config
-#[derive(Debug, Clone)]
-pub struct ServerInstanceConfig {
-    /// Server's config
-    pub config: ServerConfig,
-    /// Server's private ACL, set to `None` will use the global `AccessControl`
-    pub acl: Option<AccessControl>,
-    /// Server's outbound fwmark / address / interface to support split tunnel
-    #[cfg(any(target_os = "linux", target_os = "android"))]
-    pub outbound_fwmark: Option<u32>,
-    pub outbound_bind_addr: Option<IpAddr>,
-    pub outbound_bind_interface: Option<String>,
+impl Display for BalancerStrategyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid BalancerStrategy")
+    }
 }
 
-impl ServerInstanceConfig {
-    /// Create with `ServerConfig`
-    pub fn with_server_config(config: ServerConfig) -> ServerInstanceConfig {
-        ServerInstanceConfig {
-            config,
-            acl: None,
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            outbound_fwmark: None,
-            outbound_bind_addr: None,
-            outbound_bind_interface: None,
+impl FromStr for BalancerStrategy {
+    type Err = BalancerStrategyError;
+
+    fn from_str(s: &str) -> Result<BalancerStrategy, Self::Err> {
+        match s {
+            "best_score" => Ok(BalancerStrategy::BestScore),
+            "least_connections" => Ok(BalancerStrategy::LeastConnections),
+            "consistent_hash" => Ok(BalancerStrategy::ConsistentHash),
+            "source_hash" => Ok(BalancerStrategy::SourceHash),
+            _ => Err(BalancerStrategyError),
         }
     }
 }
 
-/// Local instance config
-#[derive(Debug, Clone)]
-pub struct LocalInstanceConfig {
-    /// Local server's config
-    pub config: LocalConfig,
-    /// Server's private ACL, set to `None` will use the global `AccessControl`
-    pub acl: Option<AccessControl>,
+impl Display for BalancerStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BalancerStrategy::BestScore => f.write_str("best_score"),
+            BalancerStrategy::LeastConnections => f.write_str("least_connections"),
+            BalancerStrategy::ConsistentHash => f.write_str("consistent_hash"),
+            BalancerStrategy::SourceHash => f.write_str("source_hash"),
+        }
+    }
 }
 
-impl LocalInstanceConfig {
-    /// Create with `LocalConfig`
-    pub fn with_local_config(config: LocalConfig) -> LocalInstanceConfig {
-        LocalInstanceConfig { config, acl: None }
-    }
+/// Scoring function of servers in balancer
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum BalancerScoreMode {
+    /// Lowest latency
+    Latency,
+    /// Lowest failure rate
+    Loss,
+    /// Combined score of latency, failure rate and latency's deviation
+    #[default]
+    Combined,
 }
 
-/// OnlineConfiguration (SIP008)
-/// https://shadowsocks.org/doc/sip008.html
-#[cfg(feature = "local-online-config")]
-#[derive(Debug, Clone)]
-pub struct OnlineConfig {
-    /// SIP008 URL
-    pub config_url: String,
-    /// Update interval, 3600s by default
-    pub update_interval: Option<Duration>,
+/// Parsing BalancerScoreMode error
+#[derive(Debug, Clone, Copy)]
+pub struct BalancerScoreModeError;
+
+impl Display for BalancerScoreModeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid BalancerScoreMode")
+    }
 }
 
-/// Configuration
-#[derive(Clone, Debug)]
-pub struct Config {
-    /// Remote ShadowSocks server configurations
-    pub server: Vec<ServerInstanceConfig>,
-    /// Local server configuration
-    pub local: Vec<LocalInstanceConfig>,
+impl FromStr for BalancerScoreMode {
+    type Err = BalancerScoreModeError;
 
-    /// DNS configuration, uses system-wide DNS configuration by default
-    ///
-    /// Value could be a `IpAddr`, uses UDP DNS protocol with port `53`. For example: `8.8.8.8`
-    ///
-    /// Also Value could be some pre-defined DNS server names:
-    ///
-    /// - `google`
-    /// - `cloudflare`, `cloudflare_tls`, `cloudflare_https`
-    /// - `quad9`, `quad9_tls`
-    pub dns: DnsConfig,
-    pub dns_cache_size: Option<usize>,
-    /// Uses IPv6 addresses first
-    ///
-    /// Set to `true` if you want to query IPv6 addresses before IPv4
-    pub ipv6_first: bool,
-    /// Set `IPV6_V6ONLY` for listener sockets
-    pub ipv6_only: bool,
+    fn from_str(s: &str) -> Result<BalancerScoreMode, Self::Err> {
+        match s {
+            "latency" => Ok(BalancerScoreMode::Latency),
+            "loss" => Ok(BalancerScoreMode::Loss),
+            "combined" => Ok(BalancerScoreMode::Combined),
+            _ => Err(BalancerScoreModeError),
+        }
+    }
+}
 
-    /// Set `TCP_NODELAY` socket option
-    pub no_delay: bool,
-    /// Set `TCP_FASTOPEN` socket option
-    pub fast_open: bool,
-    /// Set TCP Keep-Alive duration, will set both `TCP_KEEPIDLE` and `TCP_KEEPINTVL`
-    ///
-    /// <https://github.com/shadowsocks/shadowsocks-rust/issues/546>
-    ///
-    /// If this is not set, sockets will be set with a default timeout
-    pub keep_alive: Option<Duration>,
-    /// Multipath-TCP
-    pub mptcp: bool,
+impl Display for BalancerScoreMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BalancerScoreMode::Latency => f.write_str("latency"),
+            BalancerScoreMode::Loss => f.write_str("loss"),
+            BalancerScoreMode::Combined => f.write_str("combined"),
+        }
+    }
+}
 
-    /// `RLIMIT_NOFILE` option for *nix systems
-    #[cfg(all(unix, not(target_os = "android")))]
-    pub nofile: Option<u64>,
+/// HTTP URL requested by balancer for checking TCP connectivity through servers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BalancerCheckUrl {
+    addr: ServerAddr,
+    path: String,
+}
 
-    /// Set `SO_MARK` socket option for outbound sockets
-    #[cfg(any(target_os = "linux", target_os = "android"))]
-    pub outbound_fwmark: Option<u32>,
-    /// Set `SO_USER_COOKIE` socket option for outbound sockets
-    #[cfg(target_os = "freebsd")]
-    pub outbound_user_cookie: Option<u32>,
-    /// Set `SO_BINDTODEVICE` (Linux), `IP_BOUND_IF` (BSD), `IP_UNICAST_IF` (Windows) socket option for outbound sockets
-    pub outbound_bind_interface: Option<String>,
-    /// Outbound sockets will `bind` to this address
-    pub outbound_bind_addr: Option<IpAddr>,
-    /// Path to protect callback unix address, only for Android
-    #[cfg(target_os = "android")]
-    pub outbound_vpn_protect_path: Option<PathBuf>,
+impl BalancerCheckUrl {
+    /// Address of the HTTP server
+    pub fn addr(&self) -> &ServerAddr {
+        &self.addr
+    }
 
-    /// Set `SO_SNDBUF` for inbound sockets
-    pub inbound_send_buffer_size: Option<u32>,
-    /// Set `SO_RCVBUF` for inbound sockets
-    pub inbound_recv_buffer_size: Option<u32>,
-    /// Set `SO_SNDBUF` for outbound sockets
-    pub outbound_send_buffer_size: Option<u32>,
-    /// Set `SO_RCVBUF` for outbound sockets
-    pub outbound_recv_buffer_size: Option<u32>,
+    /// Path of the request, always starts with `/`
+    pub fn path(&self) -> &str {
+        &self.path
+    }
 
-    /// Manager's configuration
-    pub manager: Option<ManagerConfig>,
+    /// Value of the `Host` header
+    pub fn host(&self) -> String {
+        let host = match self.addr {
+            ServerAddr::SocketAddr(SocketAddr::V6(ref v6)) => format!("[{}]", v6.ip()),
+            ref addr => addr.host(),
+        };
 
-    /// Config is for Client or Server
-    pub config_type: ConfigType,
+        if self.addr.port() == 80 {
+            host
+        } else {
+            format!("{}:{}", host, self.addr.port())
+        }
+    }
+}
 
-    /// Timeout for UDP Associations, default is 5 minutes
-    pub udp_timeout: Option<Duration>,
-    /// Maximum number of UDP Associations, default is unconfigured
-    pub udp_max_associations: Option<usize>,
-    /// Maximum Transmission Unit (MTU) size for UDP packets
-    /// 65535 by default. Suggestion: 1500
-    /// NOTE: mtu includes IP header, UDP header, UDP payload
-    pub udp_mtu: Option<usize>,
+/// Parsing BalancerCheckUrl error
+#[derive(Debug, Clone, Copy)]
+pub struct BalancerCheckUrlError;
 
-    /// ACL configuration (Global)
-    ///
-    /// Could be overwritten by servers/locals' private `acl`
-    pub acl: Option<AccessControl>,
+impl Display for BalancerCheckUrlError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid BalancerCheckUrl, must be http://host[:port][/path]")
+    }
+}
 
-    /// Flow statistic report Unix socket path (only for Android)
-    #[cfg(feature = "local-flow-stat")]
-    pub local_stat_addr: Option<LocalFlowStatAddress>,
+impl FromStr for BalancerCheckUrl {
+    type Err = BalancerCheckUrlError;
 
-    /// Replay attack policy
-    pub security: SecurityConfig,
+    fn from_str(s: &str) -> Result<BalancerCheckUrl, Self::Err> {
+        let s = match s.strip_prefix("http://") {
+            Some(s) => s,
+            None => return Err(BalancerCheckUrlError),
+        };
 
-    /// Balancer config of local server
-    pub balancer: BalancerConfig,
+        let (authority, path) = match s.find('/') {
+            Some(pos) => (&s[..pos], &s[pos..]),
+            None => (s, "/"),
+        };
 
-    /// Configuration file path, the actual path of the configuration.
-    /// This is normally for auto-reloading if implementation supports.
-    pub config_path: Option<PathBuf>,
+        let addr = match authority.parse::<ServerAddr>() {
+            Ok(addr) => addr,
+            // Port is optional, 80 by default
+            Err(..) => match format!("{authority}:80").parse::<ServerAddr>() {
+                Ok(addr) => addr,
+                Err(..) => return Err(BalancerCheckUrlError),
+            },
+        };
 
-    /// OnlineConfiguration (SIP008)
-    /// https://shadowsocks.org/doc/sip008.html
-    #[cfg(feature = "local-online-config")]
-    pub online_config: Option<OnlineConfig>,
+        Ok(BalancerCheckUrl {
+            addr,
+            path: path.to_owned(),
+        })
+    }
 }
 
-/// Configuration parsing error kind
-#[derive(Copy, Clone, Debug)]
-pub enum ErrorKind {
-    /// Missing required fields in JSON configuration
-    MissingField,
-    /// Missing some keys that must be provided together
-    Malformed,
-    /// Invalid value of some configuration keys
-    Invalid,
-    /// Invalid JSON
-    JsonParsingError,
-    /// `std::io::Error`
-    IoError,
+impl Display for BalancerCheckUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "http://{}{}", self.addr, self.path)
+    }
 }
 
-/// Configuration parsing error
-pub struct Error {
-    pub kind: ErrorKind,
-    pub desc: &'static str,
-    pub detail: Option<String>,
+/// Method of checking servers' connectivity in balancer
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BalancerCheckMethod {
+    /// HTTP request to `check_url` through TCP relay
+    Http,
+    /// DNS query to `check_dns`, through TCP relay (length prefixed) or UDP relay
+    Dns,
+    /// STUN binding request to `check_stun` through UDP relay
+    Stun,
 }
 
-impl Error {
-    pub fn new(kind: ErrorKind, desc: &'static str, detail: Option<String>) -> Error {
-        Error { kind, desc, detail }
+impl BalancerCheckMethod {
+    /// Default method of TCP checks
+    pub const DEFAULT_TCP: BalancerCheckMethod = BalancerCheckMethod::Http;
+    /// Default method of UDP checks
+    pub const DEFAULT_UDP: BalancerCheckMethod = BalancerCheckMethod::Dns;
+
+    /// Check if this method works for TCP relay
+    pub fn support_tcp(&self) -> bool {
+        matches!(*self, BalancerCheckMethod::Http | BalancerCheckMethod::Dns)
     }
-}
 
-impl std::error::Error for Error {
-    fn description(&self) -> &str {
-        self.desc
+    /// Check if this method works for UDP relay
+    pub fn support_udp(&self) -> bool {
+        matches!(*self, BalancerCheckMethod::Dns | BalancerCheckMethod::Stun)
     }
 }
 
-macro_rules! impl_from {
-    ($error:ty, $kind:expr, $desc:expr) => {
-        impl From<$error> for Error {
-            fn from(err: $error) -> Self {
-                Error::new($kind, $desc, Some(format!("{:?}", err)))
-            }
-        }
-    };
+/// Parsing BalancerCheckMethod error
+#[derive(Debug, Clone, Copy)]
+pub struct BalancerCheckMethodError;
+
+impl Display for BalancerCheckMethodError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid BalancerCheckMethod")
+    }
 }
 
-impl_from!(::std::io::Error, ErrorKind::IoError, "error while reading file");
-impl_from!(json5::Error, ErrorKind::JsonParsingError, "json parse error");
+impl FromStr for BalancerCheckMethod {
+    type Err = BalancerCheckMethodError;
 
-impl Debug for Error {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        match self.detail {
-            None => write!(f, "{}", self.desc),
-            Some(ref det) => write!(f, "{} {}", self.desc, det),
+    fn from_str(s: &str) -> Result<BalancerCheckMethod, Self::Err> {
+        match s {
+            "http" => Ok(BalancerCheckMethod::Http),
+            "dns" => Ok(BalancerCheckMethod::Dns),
+            "stun" => Ok(BalancerCheckMethod::Stun),
+            _ => Err(BalancerCheckMethodError),
         }
     }
 }
 
-impl Display for Error {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        match self.detail {
-            None => f.write_str(self.desc),
-            Some(ref d) => write!(f, "{}, {}", self.desc, d),
+impl Display for BalancerCheckMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BalancerCheckMethod::Http => f.write_str("http"),
+            BalancerCheckMethod::Dns => f.write_str("dns"),
+            BalancerCheckMethod::Stun => f.write_str("stun"),
         }
     }
 }
 
-impl Config {
-    /// Creates an empty configuration
-    pub fn new(config_type: ConfigType) -> Config {
-        Config {
-            server: Vec::new(),
-            local: Vec::new(),
+/// Balancer Config
+#[derive(Clone, Debug, Default)]
+pub struct BalancerConfig {
+    /// MAX rtt of servers, which is the timeout duration of each check requests
+    pub max_server_rtt: Option<Duration>,
+    /// Interval between each checking
+    pub check_interval: Option<Duration>,
+    /// Interval for checking the best server
+    pub check_best_interval: Option<Duration>,
+    /// Servers with latency scores within this ratio of the best server's will be chosen by their weights
+    pub score_tolerance: Option<f64>,
+    /// Strategy of choosing server
+    pub strategy: BalancerStrategy,
+    /// Strategy of choosing server for UDP relay, same as `strategy` if not set
+    pub udp_strategy: Option<BalancerStrategy>,
+    /// Scoring function of servers
+    pub score_mode: BalancerScoreMode,
+    /// Smoothing factor of latency's EWMA. Latency median in the check window is used if not set
+    pub ewma_alpha: Option<f64>,
+    /// Latency of a failed check in EWMA, as a multiple of `max_server_rtt`
+    pub failure_penalty: Option<f64>,
+    /// Weight of latency in combined score
+    pub rtt_weight: Option<f64>,
+    /// Weight of failure rate in combined score
+    pub loss_weight: Option<f64>,
+    /// Verify credentials of static servers once on startup
+    pub verify_servers: bool,
+    /// URL for checking TCP connectivity, for servers that didn't set their own
+    pub check_url: Option<BalancerCheckUrl>,
+    /// DNS server for checking UDP connectivity, for servers that didn't set their own
+    pub check_dns: Option<ServerAddr>,
+    /// STUN server for checking UDP connectivity with `stun` method
+    pub check_stun: Option<ServerAddr>,
+    /// Method of checking TCP connectivity. Default is `http`
+    pub tcp_check_method: Option<BalancerCheckMethod>,
+    /// Method of checking UDP connectivity. Default is `dns`
+    pub udp_check_method: Option<BalancerCheckMethod>,
+    /// Packets sent in a burst in each UDP check for measuring packet loss and jitter. No bursts are sent if not set
+    pub udp_probe_packets: Option<u32>,
+    /// Consecutive failed checks before marking a server down. By default, a server is down if all checks in the check window failed
+    pub check_fail_threshold: Option<u32>,
+    /// Consecutive succeeded checks before bringing a down server back. Default is 1
+    pub check_recover_threshold: Option<u32>,
+    /// Servers in a tier whose latency exceed this are treated as unavailable when choosing the serving tier
+    pub tier_max_latency: Option<Duration>,
+    /// Another server becomes the best server only if its score is better by this ratio of the best server's score
+    pub switch_margin: Option<f64>,
+    /// Another server becomes the best server only if it has been better by `switch_margin` for this long
+    pub switch_dwell: Option<Duration>,
+    /// Succeeded checks that servers added at runtime need before being chosen. Default is 0
+    pub warmup_checks: Option<u32>,
+    /// Choose servers that are still warming up if no other server is alive
+    pub warmup_bypass: bool,
+    /// Expression that computes servers' scores from probe data for choosing the best server, instead of the built-in score
+    pub score_expr: Option<String>,
+    /// Rules of sending destinations through servers in specific groups
+    pub routes: Option<ServerRoutes>,
+    /// File that servers' probe history is saved into, and reloaded from on startup
+    pub state_file: Option<PathBuf>,
+}
 
-            dns: DnsConfig::default(),
-            dns_cache_size: None,
-            ipv6_first: false,
-            ipv6_only: false,
+/// Sink of mirrored traffic
+#[derive(Debug, Clone)]
+pub enum TrafficMirrorSink {
+    /// Write packets into a pcap file
+    Pcap(PathBuf),
+    /// Send each packet as a datagram to an UDP address
+    Udp(SocketAddr),
+}
 
-            no_delay: false,
-            fast_open: false,
-            keep_alive: None,
-            mptcp: false,
+/// Traffic classification of proxied TCP flows
+///
+/// Flows that keep transferring data are classified as bulk, and only get bandwidth left by interactive flows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QosConfig {
+    /// Upload bandwidth shared by all proxied flows, in kbit/s. Upload is not scheduled if `None`
+    pub upload_kbps: Option<u64>,
+    /// Download bandwidth shared by all proxied flows, in kbit/s. Download is not scheduled if `None`
+    pub download_kbps: Option<u64>,
+    /// Bytes a flow transferred in a burst before it is classified as bulk
+    pub bulk_bytes: u64,
+    /// Minimum age of a burst before the flow is classified as bulk
+    pub bulk_age: Duration,
+}
 
-            #[cfg(all(unix, not(target_os = "android")))]
-            nofile: None,
+impl QosConfig {
+    /// Default bytes of a bulk burst
+    pub const DEFAULT_BULK_BYTES: u64 = 1024 * 1024;
+    /// Default age of a bulk burst
+    pub const DEFAULT_BULK_AGE: Duration = Duration::from_secs(2);
+}
 
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            outbound_fwmark: None,
-            #[cfg(target_os = "freebsd")]
-            outbound_user_cookie: None,
-            outbound_bind_interface: None,
-            outbound_bind_addr: None,
-            #[cfg(target_os = "android")]
-            outbound_vpn_protect_path: None,
+/// Bandwidth limit of a server port or a user, in kbit/s
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    /// Upload from clients, not limited if `None`
+    pub upload_kbps: Option<u64>,
+    /// Download to clients, not limited if `None`
+    pub download_kbps: Option<u64>,
+}
 
-            inbound_send_buffer_size: None,
-            inbound_recv_buffer_size: None,
-            outbound_send_buffer_size: None,
-            outbound_recv_buffer_size: None,
+impl RateLimitConfig {
+    fn from_kbps(upload_kbps: Option<u64>, download_kbps: Option<u64>) -> Result<Option<RateLimitConfig>, Error> {
+        if upload_kbps == Some(0) || download_kbps == Some(0) {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "`upload_kbps` and `download_kbps` must be > 0",
+                None,
+            );
+            return Err(err);
+        }
 
-            manager: None,
+        if upload_kbps.is_none() && download_kbps.is_none() {
+            Ok(None)
+        } else {
+            Ok(Some(RateLimitConfig {
+                upload_kbps,
+                download_kbps,
+            }))
+        }
+    }
+}
 
-            config_type,
+/// Traffic quota of a server port and its users, in bytes sent and received
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QuotaConfig {
+    /// Quota shared by all clients, unlimited if `None`
+    pub quota: Option<u64>,
+    /// Quotas of users (names in `users`)
+    pub user_quotas: HashMap<String, u64>,
+    /// Day of month (1 - 28, in UTC) that used traffic is reset, never reset automatically if `None`
+    pub reset_day: Option<u8>,
+}
 
-            udp_timeout: None,
-            udp_max_associations: None,
-            udp_mtu: None,
+impl QuotaConfig {
+    /// Check if there is no quota
+    pub fn is_empty(&self) -> bool {
+        self.quota.is_none() && self.user_quotas.is_empty()
+    }
+}
 
-            acl: None,
+/// Limits of inbound connections of a local server
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InboundLimitConfig {
+    /// Concurrent connections of all clients
+    pub max_connections: Option<usize>,
+    /// Concurrent connections of each client IP
+    pub max_connections_per_ip: Option<usize>,
+    /// New connections per second of all clients
+    pub connection_rate: Option<u32>,
+    /// New connections per second of each client IP
+    pub connection_rate_per_ip: Option<u32>,
+}
 
-            #[cfg(feature = "local-flow-stat")]
-            local_stat_addr: None,
+impl InboundLimitConfig {
+    /// Check if there is no limit
+    pub fn is_empty(&self) -> bool {
+        self.max_connections.is_none()
+            && self.max_connections_per_ip.is_none()
+            && self.connection_rate.is_none()
+            && self.connection_rate_per_ip.is_none()
+    }
+}
 
-            security: SecurityConfig::default(),
+/// History of completed sessions, kept in an embedded SQLite database
+#[cfg(feature = "local-history")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryConfig {
+    /// Path of the database file
+    pub path: PathBuf,
+    /// Records older than this are deleted
+    pub retention: Option<Duration>,
+    /// Only keep the latest records
+    pub max_records: Option<u64>,
+}
 
-            balancer: BalancerConfig::default(),
+/// Watching external files referenced by the configuration, changes are applied without restarting
+#[cfg(feature = "local-file-watch")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileWatchConfig {
+    /// Changes are applied after files stay unchanged for this duration
+    pub debounce: Duration,
+}
 
-            config_path: None,
+#[cfg(feature = "local-file-watch")]
+impl FileWatchConfig {
+    /// Default debounce duration
+    pub const DEFAULT_DEBOUNCE: Duration = Duration::from_secs(1);
+}
 
-            #[cfg(feature = "local-online-config")]
-            online_config: None,
+#[cfg(feature = "local-file-watch")]
+impl Default for FileWatchConfig {
+    fn default() -> FileWatchConfig {
+        FileWatchConfig {
+            debounce: FileWatchConfig::DEFAULT_DEBOUNCE,
         }
     }
+}
 
-    fn load_from_ssconfig(config: SSConfig, config_type: ConfigType) -> Result<Config, Error> {
-        let mut nconfig = Config::new(config_type);
+/// Smallest MSS accepted by Linux
+const MIN_TCP_MSS: u32 = 88;
 
-        // Client
-        //
-        // local_address is allowed to be NULL, which means to bind to ::1 or 127.0.0.1
-        //
-        // https://shadowsocks.org/en/config/quick-guide.html
-        #[inline]
-        fn get_local_address(local_address: Option<String>, local_port: u16, ipv6_first: bool) -> ServerAddr {
-            match local_address {
-                Some(addr) => {
-                    match addr.parse::<IpAddr>() {
-                        Ok(ip) => ServerAddr::from(SocketAddr::new(ip, local_port)),
-                        Err(..) => {
-                            // treated as domain
-                            ServerAddr::from((addr, local_port))
-                        }
-                    }
-                }
-                None => {
-                    // Implementation note: This is not implemented like libev which will choose IPv6 or IPv6 LoopBack address
-                    // by checking all its remote servers if all of them supports IPv6.
-                    let ip = if ipv6_first {
-                        Ipv6Addr::LOCALHOST.into()
-                    } else {
-                        Ipv4Addr::LOCALHOST.into()
-                    };
+/// Largest MSS of IPv4 packets without jumbograms
+const MAX_TCP_MSS: u32 = 65495;
 
-                    ServerAddr::from(SocketAddr::new(ip, local_port))
-                }
-            }
-        }
+/// Largest DSCP value, which is 6 bits
+#[cfg(feature = "local-tun")]
+const MAX_DSCP: u8 = 0x3F;
 
-        // Mode
-        let mut global_mode = Mode::TcpOnly;
-        if let Some(m) = config.mode {
-            match m.parse::<Mode>() {
-                Ok(xm) => global_mode = xm,
-                Err(..) => {
-                    let e = Error::new(
-                        ErrorKind::Malformed,
-                        "malformed `mode`, must be one of `tcp_only`, `udp_only` and `tcp_and_udp`",
-                        None,
-                    );
-                    return Err(e);
-                }
-            }
+/// Reflection of DSCP of packets intercepted by tun onto outbound connections (and their packets to servers)
+#[cfg(feature = "local-tun")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TunDscpConfig {
+    /// Copy DSCP of intercepted packets if it is not remapped
+    pub preserve: bool,
+    /// Remapping of DSCP, intercepted => outbound
+    pub map: BTreeMap<u8, u8>,
+}
+
+#[cfg(feature = "local-tun")]
+impl TunDscpConfig {
+    /// DSCP of outbound connections for intercepted packets marked with `dscp`, `None` for leaving it unmarked
+    pub fn outbound_dscp(&self, dscp: u8) -> Option<u8> {
+        match self.map.get(&dscp) {
+            Some(d) => Some(*d),
+            None if self.preserve && dscp != 0 => Some(dscp),
+            None => None,
         }
+    }
+}
 
-        match config_type {
-            ConfigType::Local => {
-                // Standard config
-                if config.local_address.is_some() && config.local_port.unwrap_or(0) == 0 {
-                    let err = Error::new(ErrorKind::MissingField, "missing `local_port`", None);
-                    return Err(err);
-                }
+/// Handling of DNS traffic intercepted by tun
+#[cfg(feature = "local-tun")]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TunDnsHijackAction {
+    /// Answer UDP queries by the local fake DNS, requires `tun_fake_dns`
+    Hijack,
+    /// Relay like other traffic, to internal resolvers for example
+    Pass,
+    /// Drop the packets, of both UDP and TCP
+    Block,
+}
 
-                if let Some(local_port) = config.local_port {
-                    // local_port won't be 0, it was checked above
-                    assert_ne!(local_port, 0);
+/// Parsing TunDnsHijackAction error
+#[cfg(feature = "local-tun")]
+#[derive(Debug, Clone, Copy)]
+pub struct TunDnsHijackActionError;
 
-                    let local_addr =
-                        get_local_address(config.local_address, local_port, config.ipv6_first.unwrap_or(false));
+#[cfg(feature = "local-tun")]
+impl Display for TunDnsHijackActionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid TunDnsHijackAction")
+    }
+}
 
-                    // shadowsocks uses SOCKS5 by default
-                    let mut local_config = LocalConfig::new(ProtocolType::Socks);
-                    local_config.addr = Some(local_addr);
-                    local_config.mode = global_mode;
-                    local_config.protocol = match config.protocol {
-                        None => ProtocolType::Socks,
-                        Some(p) => match p.parse::<ProtocolType>() {
-                            Ok(p) => p,
-                            Err(..) => {
-                                let err = Error::new(
-                                    ErrorKind::Malformed,
-                                    "`protocol` invalid",
-                                    Some(format!("unrecognized protocol {p}")),
-                                );
-                                return Err(err);
-                            }
-                        },
-                    };
-                    #[cfg(target_os = "macos")]
-                    {
-                        local_config
-                            .launchd_tcp_socket_name
-                            .clone_from(&config.launchd_tcp_socket_name);
-                        local_config
-                            .launchd_udp_socket_name
-                            .clone_from(&config.launchd_udp_socket_name);
-                    }
+#[cfg(feature = "local-tun")]
+impl FromStr for TunDnsHijackAction {
+    type Err = TunDnsHijackActionError;
 
-                    let local_instance = LocalInstanceConfig {
-                        config: local_config,
-                        acl: None,
-                    };
+    fn from_str(s: &str) -> Result<TunDnsHijackAction, Self::Err> {
+        match s {
+            "hijack" => Ok(TunDnsHijackAction::Hijack),
+            "pass" => Ok(TunDnsHijackAction::Pass),
+            "block" => Ok(TunDnsHijackAction::Block),
+            _ => Err(TunDnsHijackActionError),
+        }
+    }
+}
 
-                    nconfig.local.push(local_instance);
-                }
+#[cfg(feature = "local-tun")]
+impl Display for TunDnsHijackAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TunDnsHijackAction::Hijack => f.write_str("hijack"),
+            TunDnsHijackAction::Pass => f.write_str("pass"),
+            TunDnsHijackAction::Block => f.write_str("block"),
+        }
+    }
+}
 
-                // Ext locals
-                // `locals` are only effective in local server
-                if let Some(locals) = config.locals {
-                    for local in locals {
-                        if local.disabled.unwrap_or(false) {
-                            continue;
-                        }
+/// A DNS hijack rule of tun, matching destinations of intercepted packets
+#[cfg(feature = "local-tun")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunDnsHijackRule {
+    /// Destination network, any address if `None`
+    pub destination: Option<IpNet>,
+    /// Destination port, any port if `None`
+    pub port: Option<u16>,
+    /// Action of matched packets
+    pub action: TunDnsHijackAction,
+}
 
-                        let protocol = match local.protocol {
-                            None => ProtocolType::Socks,
-                            Some(p) => match p.parse::<ProtocolType>() {
-                                Ok(p) => p,
-                                Err(..) => {
-                                    let err = Error::new(
-                                        ErrorKind::Malformed,
-                                        "`protocol` invalid",
-                                        Some(format!("unrecognized protocol {p}")),
-                                    );
-                                    return Err(err);
-                                }
-                            },
-                        };
+#[cfg(feature = "local-tun")]
+impl TunDnsHijackRule {
+    /// Check if destination `addr` matches the rule
+    pub fn matches(&self, addr: &SocketAddr) -> bool {
+        self.port.map_or(true, |port| port == addr.port())
+            && self.destination.map_or(true, |net| net.contains(&addr.ip()))
+    }
+}
 
-                        let mut local_config = LocalConfig::new(protocol);
+/// Handling of ICMP echo requests (ping) intercepted by tun
+#[cfg(feature = "local-tun")]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum TunIcmpEchoPolicy {
+    /// Reply immediately for any destinations
+    #[default]
+    Reply,
+    /// Reply for proxied destinations after the latency of the best server, or never if it is down,
+    /// bypassed destinations are replied immediately
+    Server,
+    /// Drop without any replies
+    Drop,
+}
 
-                        if let Some(local_port) = local.local_port {
-                            if local_port == 0 {
-                                let err = Error::new(ErrorKind::Malformed, "`local_port` cannot be 0", None);
-                                return Err(err);
-                            }
+/// Parsing TunIcmpEchoPolicy error
+#[cfg(feature = "local-tun")]
+#[derive(Debug, Clone, Copy)]
+pub struct TunIcmpEchoPolicyError;
 
-                            let local_addr =
-                                get_local_address(local.local_address, local_port, config.ipv6_first.unwrap_or(false));
+#[cfg(feature = "local-tun")]
+impl Display for TunIcmpEchoPolicyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid TunIcmpEchoPolicy")
+    }
+}
+
+#[cfg(feature = "local-tun")]
+impl FromStr for TunIcmpEchoPolicy {
+    type Err = TunIcmpEchoPolicyError;
+
+    fn from_str(s: &str) -> Result<TunIcmpEchoPolicy, Self::Err> {
+        match s {
+            "reply" => Ok(TunIcmpEchoPolicy::Reply),
+            "server" => Ok(TunIcmpEchoPolicy::Server),
+            "drop" => Ok(TunIcmpEchoPolicy::Drop),
+            _ => Err(TunIcmpEchoPolicyError),
+        }
+    }
+}
+
+#[cfg(feature = "local-tun")]
+impl Display for TunIcmpEchoPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TunIcmpEchoPolicy::Reply => f.write_str("reply"),
+            TunIcmpEchoPolicy::Server => f.write_str("server"),
+            TunIcmpEchoPolicy::Drop => f.write_str("drop"),
+        }
+    }
+}
+
+/// IPv6 configuration of the tun interface on Windows, where it is not configured by the tun device
+#[cfg(all(feature = "local-tun", windows))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunIpv6Config {
+    /// Address and prefix length of the interface
+    pub address: Ipv6Net,
+    /// Networks routed to the interface, `::/0` for all IPv6 traffic
+    pub routes: Vec<Ipv6Net>,
+    /// DNS servers of the interface
+    pub dns: Vec<Ipv6Addr>,
+}
+
+/// Routes installed when tun is up
+#[cfg(all(feature = "local-tun", any(target_os = "linux", target_os = "macos", windows)))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunAutoRouteConfig {
+    /// Networks routed through the previous default gateway instead of tun, besides addresses of servers
+    pub bypass: Vec<IpNet>,
+    /// Packets with this mark bypass tun, outbound sockets of this process are marked with it if `outbound_fwmark` is not set
+    #[cfg(target_os = "linux")]
+    pub fwmark: Option<u32>,
+    /// Routing table of routes to tun, with policy routing
+    #[cfg(target_os = "linux")]
+    pub route_table: u32,
+    /// Ranges of UIDs whose sockets bypass tun
+    #[cfg(target_os = "linux")]
+    pub bypass_uids: Vec<(u32, u32)>,
+    /// cgroup v2 paths (relative to the root) whose sockets bypass tun, marked with `fwmark` by nftables
+    #[cfg(target_os = "linux")]
+    pub bypass_cgroups: Vec<String>,
+    /// Route IPv6 traffic to tun, following changes of the upstream IPv6 prefixes and default gateway
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub ipv6: bool,
+    /// Interface identifier of the tun IPv6 address, which is combined with the current upstream prefix
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub ipv6_address_suffix: Option<Ipv6Addr>,
+}
+
+#[cfg(all(feature = "local-tun", any(target_os = "linux", target_os = "macos", windows)))]
+impl TunAutoRouteConfig {
+    /// Default routing table of routes to tun
+    #[cfg(target_os = "linux")]
+    pub const DEFAULT_ROUTE_TABLE: u32 = 2022;
+
+    /// Routes to tun are installed in `route_table` with policy routing, so packets could bypass tun by rules
+    #[cfg(target_os = "linux")]
+    pub fn policy_routing(&self) -> bool {
+        self.fwmark.is_some() || !self.bypass_uids.is_empty() || !self.bypass_cgroups.is_empty()
+    }
+}
+
+#[cfg(all(feature = "local-tun", any(target_os = "linux", target_os = "macos", windows)))]
+impl Default for TunAutoRouteConfig {
+    fn default() -> TunAutoRouteConfig {
+        TunAutoRouteConfig {
+            bypass: Vec::new(),
+            #[cfg(target_os = "linux")]
+            fwmark: None,
+            #[cfg(target_os = "linux")]
+            route_table: TunAutoRouteConfig::DEFAULT_ROUTE_TABLE,
+            #[cfg(target_os = "linux")]
+            bypass_uids: Vec::new(),
+            #[cfg(target_os = "linux")]
+            bypass_cgroups: Vec::new(),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            ipv6: false,
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            ipv6_address_suffix: None,
+        }
+    }
+}
+
+/// Firewall of rules installed by redir
+#[cfg(all(feature = "local-redir", target_os = "linux"))]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum RedirFirewallBackend {
+    /// nftables if `nft` is available, otherwise iptables
+    #[default]
+    Auto,
+    /// nftables, with `nft`
+    Nftables,
+    /// iptables-legacy if available, otherwise `iptables`
+    Iptables,
+}
+
+/// Parsing RedirFirewallBackend error
+#[cfg(all(feature = "local-redir", target_os = "linux"))]
+#[derive(Debug, Clone, Copy)]
+pub struct RedirFirewallBackendError;
+
+#[cfg(all(feature = "local-redir", target_os = "linux"))]
+impl Display for RedirFirewallBackendError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid RedirFirewallBackend")
+    }
+}
+
+#[cfg(all(feature = "local-redir", target_os = "linux"))]
+impl FromStr for RedirFirewallBackend {
+    type Err = RedirFirewallBackendError;
+
+    fn from_str(s: &str) -> Result<RedirFirewallBackend, Self::Err> {
+        match s {
+            "auto" => Ok(RedirFirewallBackend::Auto),
+            "nftables" => Ok(RedirFirewallBackend::Nftables),
+            "iptables" => Ok(RedirFirewallBackend::Iptables),
+            _ => Err(RedirFirewallBackendError),
+        }
+    }
+}
+
+#[cfg(all(feature = "local-redir", target_os = "linux"))]
+impl Display for RedirFirewallBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RedirFirewallBackend::Auto => f.write_str("auto"),
+            RedirFirewallBackend::Nftables => f.write_str("nftables"),
+            RedirFirewallBackend::Iptables => f.write_str("iptables"),
+        }
+    }
+}
+
+/// Firewall rules installed by redir, intercepting forwarded traffic to its ports
+///
+/// `REDIRECT` or `TPROXY` rules follow `tcp_redir` and `udp_redir`, `TPROXY` also installs policy routing of `fwmark`.
+#[cfg(all(feature = "local-redir", target_os = "linux"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedirFirewallConfig {
+    /// Firewall to install rules into
+    pub backend: RedirFirewallBackend,
+    /// Inbound interfaces to intercept, all interfaces if empty
+    pub interfaces: Vec<String>,
+    /// Destinations not intercepted, besides reserved and private networks
+    pub bypass: Vec<IpNet>,
+    /// Mark of packets of `TPROXY`
+    pub fwmark: u32,
+    /// Routing table delivering marked packets locally
+    pub route_table: u32,
+}
+
+#[cfg(all(feature = "local-redir", target_os = "linux"))]
+impl Default for RedirFirewallConfig {
+    fn default() -> RedirFirewallConfig {
+        RedirFirewallConfig {
+            backend: RedirFirewallBackend::Auto,
+            interfaces: Vec::new(),
+            bypass: Vec::new(),
+            fwmark: 1,
+            route_table: 100,
+        }
+    }
+}
+
+/// A `local -> remote` mapping of tunnel
+#[cfg(feature = "local-tunnel")]
+#[derive(Debug, Clone)]
+pub struct TunnelForwardConfig {
+    /// Listen address of TCP and UDP
+    pub addr: ServerAddr,
+    /// Destination address
+    pub forward_addr: Address,
+    /// Uses `mode` of the tunnel if not specified
+    pub mode: Option<Mode>,
+}
+
+/// Control API over HTTP(S), authenticated by a bearer token
+#[cfg(feature = "local-http")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlHttpConfig {
+    /// Listen address
+    pub listen_addr: SocketAddr,
+    /// Token in the `Authorization: Bearer` header
+    pub token: String,
+    /// Clients that are allowed to connect, all clients are allowed if empty
+    pub allowed_ips: Vec<IpNet>,
+    /// TLS certificate chain in PEM, serves plain HTTP if not set
+    pub tls_cert: Option<PathBuf>,
+    /// TLS private key in PEM
+    pub tls_key: Option<PathBuf>,
+}
+
+/// Active / standby pairing with another instance
+///
+/// Only the active instance opens local listeners. The standby takes over when heartbeats of the active are lost
+#[cfg(feature = "local")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HaConfig {
+    /// Address that heartbeats are sent from and received on
+    pub listen_addr: SocketAddr,
+    /// Heartbeat address of the peer instance
+    pub peer_addr: SocketAddr,
+    /// Shared secret of the pair, heartbeats are authenticated by HMAC-SHA256 with it
+    pub secret: String,
+    /// The instance with higher priority becomes active if both are starting, or both are active
+    pub priority: u8,
+    /// Interval between heartbeats
+    pub heartbeat_interval: Duration,
+    /// The peer is considered dead if no heartbeats are received for this long
+    pub failover_timeout: Duration,
+    /// Shell command that is run before opening listeners, e.g. for adding the virtual IP
+    pub notify_active: Option<String>,
+    /// Shell command that is run after closing listeners
+    pub notify_standby: Option<String>,
+}
+
+#[cfg(feature = "local")]
+impl HaConfig {
+    /// Default priority
+    pub const DEFAULT_PRIORITY: u8 = 100;
+    /// Default interval between heartbeats
+    pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+    /// Default timeout of heartbeats
+    pub const DEFAULT_FAILOVER_TIMEOUT: Duration = Duration::from_secs(3);
+}
+
+/// Handling of unspecified, loopback, multicast and broadcast targets requested by clients
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum SpecialTargetPolicy {
+    /// Proxy or bypass by ACL, like other targets
+    #[default]
+    Acl,
+    /// Refuse the connection, SOCKS clients get "connection not allowed" and HTTP clients get `403`
+    Reject,
+    /// Always send through servers, so targets are addresses of the server itself
+    Proxy,
+    /// Close the connection without any response
+    Drop,
+}
+
+/// Parsing SpecialTargetPolicy error
+#[derive(Debug, Clone, Copy)]
+pub struct SpecialTargetPolicyError;
+
+impl Display for SpecialTargetPolicyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid SpecialTargetPolicy")
+    }
+}
+
+impl FromStr for SpecialTargetPolicy {
+    type Err = SpecialTargetPolicyError;
+
+    fn from_str(s: &str) -> Result<SpecialTargetPolicy, Self::Err> {
+        match s {
+            "acl" => Ok(SpecialTargetPolicy::Acl),
+            "reject" => Ok(SpecialTargetPolicy::Reject),
+            "proxy" => Ok(SpecialTargetPolicy::Proxy),
+            "drop" => Ok(SpecialTargetPolicy::Drop),
+            _ => Err(SpecialTargetPolicyError),
+        }
+    }
+}
+
+impl Display for SpecialTargetPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SpecialTargetPolicy::Acl => f.write_str("acl"),
+            SpecialTargetPolicy::Reject => f.write_str("reject"),
+            SpecialTargetPolicy::Proxy => f.write_str("proxy"),
+            SpecialTargetPolicy::Drop => f.write_str("drop"),
+        }
+    }
+}
+
+/// NAT behavior of UDP associations, which decides the remote addresses that could respond to clients
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum UdpNatType {
+    /// Responses from any remote addresses, required by many games and P2P applications
+    #[default]
+    FullCone,
+    /// Responses only from remote addresses that the client has sent to
+    Symmetric,
+}
+
+/// Parsing UdpNatType error
+#[derive(Debug, Clone, Copy)]
+pub struct UdpNatTypeError;
+
+impl Display for UdpNatTypeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid UdpNatType")
+    }
+}
+
+impl FromStr for UdpNatType {
+    type Err = UdpNatTypeError;
+
+    fn from_str(s: &str) -> Result<UdpNatType, Self::Err> {
+        match s {
+            "full-cone" => Ok(UdpNatType::FullCone),
+            "symmetric" => Ok(UdpNatType::Symmetric),
+            _ => Err(UdpNatTypeError),
+        }
+    }
+}
+
+impl Display for UdpNatType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UdpNatType::FullCone => f.write_str("full-cone"),
+            UdpNatType::Symmetric => f.write_str("symmetric"),
+        }
+    }
+}
+
+/// Local uplink of multi-WAN outbound
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UplinkConfig {
+    /// Name of uplink, for servers' `uplink`
+    pub name: String,
+    /// Outbound sockets bind to this interface
+    pub bind_interface: Option<String>,
+    /// Outbound sockets bind to this address
+    pub bind_addr: Option<IpAddr>,
+    /// Outbound sockets are marked with this fwmark
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fwmark: Option<u32>,
+    /// Share of new outbound sockets, relative to other uplinks
+    pub weight: u32,
+}
+
+/// Address for local to report flow statistic data
+#[cfg(feature = "local-flow-stat")]
+#[derive(Debug, Clone)]
+pub enum LocalFlowStatAddress {
+    /// UNIX Domain Socket address
+    #[cfg(unix)]
+    UnixStreamPath(PathBuf),
+    /// TCP Stream Address
+    TcpStreamAddr(SocketAddr),
+}
+
+/// Server instance config
+#[derive(Debug, Clone)]
+pub struct ServerInstanceConfig {
+    /// Server's config
+    pub config: ServerConfig,
+    /// Server's private ACL, set to `None` will use the global `AccessControl`
+    pub acl: Option<AccessControl>,
+    /// Server's outbound fwmark / address / interface to support split tunnel
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub outbound_fwmark: Option<u32>,
+    pub outbound_bind_addr: Option<IpAddr>,
+    pub outbound_bind_interface: Option<String>,
+    /// Server's uplink (name in `uplinks`), connections to this server always go through it instead of balancing
+    pub uplink: Option<String>,
+    /// Server's private URL for balancer checking TCP connectivity, set to `None` will use the global one
+    pub check_url: Option<BalancerCheckUrl>,
+    /// Server's private DNS server for balancer checking UDP connectivity, set to `None` will use the global one
+    pub check_dns: Option<ServerAddr>,
+    /// Server's private balancer check interval, set to `None` will use the global one
+    pub check_interval: Option<Duration>,
+    /// Server's private balancer check timeout, set to `None` will use the global `max_server_rtt`
+    pub check_timeout: Option<Duration>,
+    /// Server's private consecutive failed checks before marking it down, set to `None` will use the global one
+    pub check_fail_threshold: Option<u32>,
+    /// Server's private consecutive succeeded checks before bringing it back, set to `None` will use the global one
+    pub check_recover_threshold: Option<u32>,
+    /// Server's failover tier, starts from 1 (the highest priority)
+    ///
+    /// Balancer only uses servers in a lower priority tier if all the servers in the higher tiers are unavailable
+    pub tier: u32,
+    /// Server's group, for routing destinations by balancer's `routes`
+    pub group: Option<String>,
+    /// Server's private `TCP_NODELAY` of connections to it, set to `None` will use the global `no_delay`
+    pub no_delay: Option<bool>,
+    /// Maximum concurrent sessions (TCP connections and UDP associations) through this server
+    ///
+    /// New sessions spill over to the next best server if this server is full. In servers, it limits concurrent TCP
+    /// connections, new connections are closed when it is reached
+    pub max_connections: Option<usize>,
+    /// Maximum concurrent TCP connections of each client IP in servers, new connections of it are closed when it is reached
+    pub max_connections_per_ip: Option<usize>,
+    /// Maximum UDP associations of each client IP in servers, its least recently active one is evicted for a new one
+    pub udp_max_associations_per_ip: Option<usize>,
+    /// Bandwidth limit shared by all clients of this server
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Bandwidth limits of users (names in `users`) of this server, each shared by all connections of the user
+    pub user_rate_limits: HashMap<String, RateLimitConfig>,
+    /// ACLs of users (names in `users`) of this server, their `[outbound_block_list]` are checked besides `acl`'s
+    pub user_acls: HashMap<String, AccessControl>,
+    /// Traffic quotas of this server and its users, new sessions are rejected after they are exceeded
+    pub quota: QuotaConfig,
+    /// Allow clients to BIND (SOCKS5) a listening port on this server for inbound connections
+    pub tcp_bind: bool,
+    /// Allow clients to listen on ports of this server with reverse tunnels, relaying inbound connections to them
+    pub reverse_tunnel: bool,
+    /// Ports that this server also listens on besides its own port, sharing its statistics and limits
+    pub port_range: Option<RangeInclusive<u16>>,
+}
+
+impl ServerInstanceConfig {
+    /// Create with `ServerConfig`
+    pub fn with_server_config(config: ServerConfig) -> ServerInstanceConfig {
+        ServerInstanceConfig {
+            config,
+            acl: None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            outbound_fwmark: None,
+            outbound_bind_addr: None,
+            outbound_bind_interface: None,
+            uplink: None,
+            check_url: None,
+            check_dns: None,
+            check_interval: None,
+            check_timeout: None,
+            check_fail_threshold: None,
+            check_recover_threshold: None,
+            tier: 1,
+            group: None,
+            no_delay: None,
+            max_connections: None,
+            max_connections_per_ip: None,
+            udp_max_associations_per_ip: None,
+            rate_limit: None,
+            user_rate_limits: HashMap::new(),
+            user_acls: HashMap::new(),
+            quota: QuotaConfig::default(),
+            tcp_bind: false,
+            reverse_tunnel: false,
+            port_range: None,
+        }
+    }
+}
+
+/// Local instance config
+#[derive(Debug, Clone)]
+pub struct LocalInstanceConfig {
+    /// Local server's config
+    pub config: LocalConfig,
+    /// Server's private ACL, set to `None` will use the global `AccessControl`
+    pub acl: Option<AccessControl>,
+    /// Private `TCP_NODELAY` of accepted and direct connections, set to `None` will use the global `no_delay`
+    pub no_delay: Option<bool>,
+    /// Private expiry of idle UDP associations, set to `None` will use the global `udp_timeout`
+    pub udp_timeout: Option<Duration>,
+    /// Close TCP connections without any data transferred in both directions for this duration
+    pub tcp_idle_timeout: Option<Duration>,
+    /// Close TCP connections after one direction is shut down for this duration, `0` closes them immediately
+    pub tcp_half_close_timeout: Option<Duration>,
+    /// Clamp MSS of outbound TCP connections, for overhead of encapsulation on paths failing PMTUD, mostly for
+    /// `tun` and `redir`
+    pub tcp_mss: Option<u32>,
+}
+
+impl LocalInstanceConfig {
+    /// Create with `LocalConfig`
+    pub fn with_local_config(config: LocalConfig) -> LocalInstanceConfig {
+        LocalInstanceConfig {
+            config,
+            acl: None,
+            no_delay: None,
+            udp_timeout: None,
+            tcp_idle_timeout: None,
+            tcp_half_close_timeout: None,
+            tcp_mss: None,
+        }
+    }
+}
+
+/// OnlineConfiguration (SIP008)
+/// https://shadowsocks.org/doc/sip008.html
+#[cfg(feature = "local-online-config")]
+#[derive(Debug, Clone)]
+pub struct OnlineConfig {
+    /// SIP008 URL
+    pub config_url: String,
+    /// Update interval, 3600s by default
+    pub update_interval: Option<Duration>,
+}
+
+/// Configuration
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Remote ShadowSocks server configurations
+    pub server: Vec<ServerInstanceConfig>,
+    /// Local server configuration
+    pub local: Vec<LocalInstanceConfig>,
+
+    /// DNS configuration, uses system-wide DNS configuration by default
+    ///
+    /// Value could be a `IpAddr`, uses UDP DNS protocol with port `53`. For example: `8.8.8.8`
+    ///
+    /// Also Value could be some pre-defined DNS server names:
+    ///
+    /// - `google`
+    /// - `cloudflare`, `cloudflare_tls`, `cloudflare_https`
+    /// - `quad9`, `quad9_tls`
+    pub dns: DnsConfig,
+    pub dns_cache_size: Option<usize>,
+    /// Uses IPv6 addresses first
+    ///
+    /// Set to `true` if you want to query IPv6 addresses before IPv4
+    pub ipv6_first: bool,
+    /// Set `IPV6_V6ONLY` for listener sockets
+    pub ipv6_only: bool,
+    /// Only connect addresses of this family, domain names are resolved to addresses of it
+    ///
+    /// For networks with a broken IPv4 or IPv6 stack
+    pub outbound_addr_family: Option<AddrFamily>,
+
+    /// Set `TCP_NODELAY` socket option
+    pub no_delay: bool,
+    /// Set `TCP_FASTOPEN` socket option
+    pub fast_open: bool,
+    /// Set TCP Keep-Alive duration, will set both `TCP_KEEPIDLE` and `TCP_KEEPINTVL`
+    ///
+    /// <https://github.com/shadowsocks/shadowsocks-rust/issues/546>
+    ///
+    /// If this is not set, sockets will be set with a default timeout
+    pub keep_alive: Option<Duration>,
+    /// Multipath-TCP
+    pub mptcp: bool,
+
+    /// `RLIMIT_NOFILE` option for *nix systems
+    #[cfg(all(unix, not(target_os = "android")))]
+    pub nofile: Option<u64>,
+
+    /// Set `SO_MARK` socket option for outbound sockets
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub outbound_fwmark: Option<u32>,
+    /// Set `SO_USER_COOKIE` socket option for outbound sockets
+    #[cfg(target_os = "freebsd")]
+    pub outbound_user_cookie: Option<u32>,
+    /// Set `SO_BINDTODEVICE` (Linux), `IP_BOUND_IF` (BSD), `IP_UNICAST_IF` (Windows) socket option for outbound sockets
+    pub outbound_bind_interface: Option<String>,
+    /// Uplinks that outbound sockets are balanced across, replacing global `outbound_*` options
+    pub uplinks: Vec<UplinkConfig>,
+    /// Outbound sockets will `bind` to this address
+    pub outbound_bind_addr: Option<IpAddr>,
+    /// Path to protect callback unix address, only for Android
+    #[cfg(target_os = "android")]
+    pub outbound_vpn_protect_path: Option<PathBuf>,
+    /// Protect callback of outbound sockets, for embedding in an Android `VpnService` without `outbound_vpn_protect_path`
+    #[cfg(target_os = "android")]
+    pub outbound_vpn_socket_protect: Option<std::sync::Arc<dyn shadowsocks::net::SocketProtect>>,
+
+    /// Set `SO_SNDBUF` for inbound sockets
+    pub inbound_send_buffer_size: Option<u32>,
+    /// Set `SO_RCVBUF` for inbound sockets
+    pub inbound_recv_buffer_size: Option<u32>,
+    /// Set `SO_SNDBUF` for outbound sockets
+    pub outbound_send_buffer_size: Option<u32>,
+    /// Set `SO_RCVBUF` for outbound sockets
+    pub outbound_recv_buffer_size: Option<u32>,
+
+    /// Manager's configuration
+    pub manager: Option<ManagerConfig>,
+
+    /// Address of Prometheus metrics exporter, serving `GET /metrics`
+    #[cfg(feature = "metrics")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Config is for Client or Server
+    pub config_type: ConfigType,
+
+    /// Timeout for UDP Associations, default is 5 minutes
+    pub udp_timeout: Option<Duration>,
+    /// Maximum number of UDP Associations, default is unconfigured
+    pub udp_max_associations: Option<usize>,
+    /// Maximum Transmission Unit (MTU) size for UDP packets
+    /// 65535 by default. Suggestion: 1500
+    /// NOTE: mtu includes IP header, UDP header, UDP payload
+    pub udp_mtu: Option<usize>,
+
+    /// ACL configuration (Global)
+    ///
+    /// Could be overwritten by servers/locals' private `acl`
+    pub acl: Option<AccessControl>,
+
+    /// Flow statistic report Unix socket path (only for Android)
+    #[cfg(feature = "local-flow-stat")]
+    pub local_stat_addr: Option<LocalFlowStatAddress>,
+
+    /// Replay attack policy
+    pub security: SecurityConfig,
+
+    /// Balancer config of local server
+    pub balancer: BalancerConfig,
+
+    /// Mirror plaintext traffic of targets in ACL's `[mirror_list]`, for debugging
+    pub mirror: Option<TrafficMirrorSink>,
+
+    /// Prioritize interactive flows over bulk flows in proxied TCP relays
+    pub qos: Option<QosConfig>,
+
+    /// Handling of special targets requested by clients
+    pub special_target_policy: SpecialTargetPolicy,
+
+    /// Record completed sessions into a database, for querying with `ssservice history`
+    #[cfg(feature = "local-history")]
+    pub history: Option<HistoryConfig>,
+
+    /// Watch ACL, rule sets, GeoIP lists and TLS certificates, and apply their changes
+    #[cfg(feature = "local-file-watch")]
+    pub file_watch: Option<FileWatchConfig>,
+
+    /// Address of local control socket, for managing servers at runtime
+    #[cfg(feature = "local")]
+    pub local_control_addr: Option<ManagerAddr>,
+
+    /// Serving commands of the control socket over HTTP(S), for managing remotely
+    #[cfg(feature = "local-http")]
+    pub local_control_http: Option<ControlHttpConfig>,
+
+    /// Active / standby pairing with another instance
+    #[cfg(feature = "local")]
+    pub ha: Option<HaConfig>,
+
+    /// Configuration file path, the actual path of the configuration.
+    /// This is normally for auto-reloading if implementation supports.
+    pub config_path: Option<PathBuf>,
+
+    /// OnlineConfiguration (SIP008)
+    /// https://shadowsocks.org/doc/sip008.html
+    #[cfg(feature = "local-online-config")]
+    pub online_config: Option<OnlineConfig>,
+}
+
+/// Configuration parsing error kind
+#[derive(Copy, Clone, Debug)]
+pub enum ErrorKind {
+    /// Missing required fields in JSON configuration
+    MissingField,
+    /// Missing some keys that must be provided together
+    Malformed,
+    /// Invalid value of some configuration keys
+    Invalid,
+    /// Invalid JSON
+    JsonParsingError,
+    /// `std::io::Error`
+    IoError,
+}
+
+/// Configuration parsing error
+pub struct Error {
+    pub kind: ErrorKind,
+    pub desc: &'static str,
+    pub detail: Option<String>,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, desc: &'static str, detail: Option<String>) -> Error {
+        Error { kind, desc, detail }
+    }
+}
+
+impl std::error::Error for Error {
+    fn description(&self) -> &str {
+        self.desc
+    }
+}
+
+macro_rules! impl_from {
+    ($error:ty, $kind:expr, $desc:expr) => {
+        impl From<$error> for Error {
+            fn from(err: $error) -> Self {
+                Error::new($kind, $desc, Some(format!("{:?}", err)))
+            }
+        }
+    };
+}
+
+impl_from!(::std::io::Error, ErrorKind::IoError, "error while reading file");
+impl_from!(json5::Error, ErrorKind::JsonParsingError, "json parse error");
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.detail {
+            None => write!(f, "{}", self.desc),
+            Some(ref det) => write!(f, "{} {}", self.desc, det),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.detail {
+            None => f.write_str(self.desc),
+            Some(ref d) => write!(f, "{}, {}", self.desc, d),
+        }
+    }
+}
+
+impl Config {
+    /// Creates an empty configuration
+    pub fn new(config_type: ConfigType) -> Config {
+        Config {
+            server: Vec::new(),
+            local: Vec::new(),
+
+            dns: DnsConfig::default(),
+            dns_cache_size: None,
+            ipv6_first: false,
+            ipv6_only: false,
+            outbound_addr_family: None,
+
+            no_delay: false,
+            fast_open: false,
+            keep_alive: None,
+            mptcp: false,
+
+            #[cfg(all(unix, not(target_os = "android")))]
+            nofile: None,
+
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            outbound_fwmark: None,
+            #[cfg(target_os = "freebsd")]
+            outbound_user_cookie: None,
+            outbound_bind_interface: None,
+            uplinks: Vec::new(),
+            outbound_bind_addr: None,
+            #[cfg(target_os = "android")]
+            outbound_vpn_protect_path: None,
+            #[cfg(target_os = "android")]
+            outbound_vpn_socket_protect: None,
+
+            inbound_send_buffer_size: None,
+            inbound_recv_buffer_size: None,
+            outbound_send_buffer_size: None,
+            outbound_recv_buffer_size: None,
+
+            manager: None,
+
+            #[cfg(feature = "metrics")]
+            metrics_addr: None,
+
+            config_type,
+
+            udp_timeout: None,
+            udp_max_associations: None,
+            udp_mtu: None,
+
+            acl: None,
+
+            #[cfg(feature = "local-flow-stat")]
+            local_stat_addr: None,
+
+            security: SecurityConfig::default(),
+
+            balancer: BalancerConfig::default(),
+
+            mirror: None,
+
+            qos: None,
+
+            special_target_policy: SpecialTargetPolicy::default(),
+
+            #[cfg(feature = "local-history")]
+            history: None,
+
+            #[cfg(feature = "local-file-watch")]
+            file_watch: None,
+
+            #[cfg(feature = "local")]
+            local_control_addr: None,
+
+            #[cfg(feature = "local-http")]
+            local_control_http: None,
+
+            #[cfg(feature = "local")]
+            ha: None,
+
+            config_path: None,
+
+            #[cfg(feature = "local-online-config")]
+            online_config: None,
+        }
+    }
+
+    fn load_from_ssconfig(config: SSConfig, config_type: ConfigType) -> Result<Config, Error> {
+        let mut nconfig = Config::new(config_type);
+
+        // Client
+        //
+        // local_address is allowed to be NULL, which means to bind to ::1 or 127.0.0.1
+        //
+        // https://shadowsocks.org/en/config/quick-guide.html
+        #[inline]
+        fn get_local_address(local_address: Option<String>, local_port: u16, ipv6_first: bool) -> ServerAddr {
+            match local_address {
+                Some(addr) => {
+                    match addr.parse::<IpAddr>() {
+                        Ok(ip) => ServerAddr::from(SocketAddr::new(ip, local_port)),
+                        Err(..) => {
+                            // treated as domain
+                            ServerAddr::from((addr, local_port))
+                        }
+                    }
+                }
+                None => {
+                    // Implementation note: This is not implemented like libev which will choose IPv6 or IPv6 LoopBack address
+                    // by checking all its remote servers if all of them supports IPv6.
+                    let ip = if ipv6_first {
+                        Ipv6Addr::LOCALHOST.into()
+                    } else {
+                        Ipv4Addr::LOCALHOST.into()
+                    };
+
+                    ServerAddr::from(SocketAddr::new(ip, local_port))
+                }
+            }
+        }
+
+        // Mode
+        let mut global_mode = Mode::TcpOnly;
+        if let Some(m) = config.mode {
+            match m.parse::<Mode>() {
+                Ok(xm) => global_mode = xm,
+                Err(..) => {
+                    let e = Error::new(
+                        ErrorKind::Malformed,
+                        "malformed `mode`, must be one of `tcp_only`, `udp_only` and `tcp_and_udp`",
+                        None,
+                    );
+                    return Err(e);
+                }
+            }
+        }
+
+        match config_type {
+            ConfigType::Local => {
+                // Standard config
+                if config.local_address.is_some() && config.local_port.unwrap_or(0) == 0 {
+                    let err = Error::new(ErrorKind::MissingField, "missing `local_port`", None);
+                    return Err(err);
+                }
+
+                if let Some(local_port) = config.local_port {
+                    // local_port won't be 0, it was checked above
+                    assert_ne!(local_port, 0);
+
+                    let local_addr =
+                        get_local_address(config.local_address, local_port, config.ipv6_first.unwrap_or(false));
+
+                    // shadowsocks uses SOCKS5 by default
+                    let mut local_config = LocalConfig::new(ProtocolType::Socks);
+                    local_config.addr = Some(local_addr);
+                    local_config.mode = global_mode;
+                    local_config.protocol = match config.protocol {
+                        None => ProtocolType::Socks,
+                        Some(p) => match p.parse::<ProtocolType>() {
+                            Ok(p) => p,
+                            Err(..) => return Err(invalid_protocol_error(&p)),
+                        },
+                    };
+                    #[cfg(target_os = "macos")]
+                    {
+                        local_config
+                            .launchd_tcp_socket_name
+                            .clone_from(&config.launchd_tcp_socket_name);
+                        local_config
+                            .launchd_udp_socket_name
+                            .clone_from(&config.launchd_udp_socket_name);
+                    }
+
+                    let local_instance = LocalInstanceConfig::with_local_config(local_config);
+                    nconfig.local.push(local_instance);
+                }
+
+                // Ext locals
+                // `locals` are only effective in local server
+                if let Some(locals) = config.locals {
+                    for local in locals {
+                        if local.disabled.unwrap_or(false) {
+                            continue;
+                        }
+
+                        let protocol = match local.protocol {
+                            None => ProtocolType::Socks,
+                            Some(p) => match p.parse::<ProtocolType>() {
+                                Ok(p) => p,
+                                Err(..) => return Err(invalid_protocol_error(&p)),
+                            },
+                        };
+
+                        let mut local_config = LocalConfig::new(protocol);
+
+                        // Default listen address of tunnel mappings
+                        #[cfg(feature = "local-tunnel")]
+                        let tunnel_local_address = local.local_address.clone();
+
+                        if let Some(local_port) = local.local_port {
+                            if local_port == 0 {
+                                let err = Error::new(ErrorKind::Malformed, "`local_port` cannot be 0", None);
+                                return Err(err);
+                            }
+
+                            let local_addr =
+                                get_local_address(local.local_address, local_port, config.ipv6_first.unwrap_or(false));
                             local_config.addr = Some(local_addr);
                         } else if local.local_address.is_some() {
-                            let err = Error::new(ErrorKind::Malformed, "missing `local_port`", None);
-                            return Err(err);
+                            // Mappings of tunnel listen on `local_address` by default
+                            #[cfg(feature = "local-tunnel")]
+                            let is_default_address = local.forwards.is_some();
+                            #[cfg(not(feature = "local-tunnel"))]
+                            let is_default_address = false;
+
+                            if !is_default_address {
+                                let err = Error::new(ErrorKind::Malformed, "missing `local_port`", None);
+                                return Err(err);
+                            }
+                        }
+
+                        if let Some(local_udp_port) = local.local_udp_port {
+                            if local_udp_port == 0 {
+                                let err = Error::new(ErrorKind::Malformed, "`local_udp_port` cannot be 0", None);
+                                return Err(err);
+                            }
+
+                            let local_udp_addr = get_local_address(
+                                local.local_udp_address,
+                                local_udp_port,
+                                config.ipv6_first.unwrap_or(false),
+                            );
+
+                            local_config.udp_addr = Some(local_udp_addr);
+                        }
+
+                        #[cfg(target_os = "macos")]
+                        {
+                            local_config.launchd_tcp_socket_name = local.launchd_tcp_socket_name;
+                            local_config.launchd_udp_socket_name = local.launchd_udp_socket_name;
+                        }
+
+                        #[cfg(feature = "local")]
+                        let mode_set = local.mode.is_some();
+                        match local.mode {
+                            Some(mode) => match mode.parse::<Mode>() {
+                                Ok(mode) => local_config.mode = mode,
+                                Err(..) => {
+                                    let err = Error::new(ErrorKind::Malformed, "invalid `mode`", None);
+                                    return Err(err);
+                                }
+                            },
+                            None => {
+                                // DNS server runs in `TcpAndUdp` mode by default to maintain backwards compatibility
+                                // see https://github.com/shadowsocks/shadowsocks-rust/issues/1281
+                                let mode = match protocol {
+                                    #[cfg(feature = "local-dns")]
+                                    ProtocolType::Dns => Mode::TcpAndUdp,
+                                    _ => global_mode,
+                                };
+
+                                local_config.mode = mode;
+                            }
+                        }
+
+                        #[cfg(feature = "local-tunnel")]
+                        if let Some(forward_address) = local.forward_address {
+                            let forward_port = match local.forward_port {
+                                None | Some(0) => {
+                                    let err =
+                                        Error::new(ErrorKind::Malformed, "`forward_port` cannot be missing or 0", None);
+                                    return Err(err);
+                                }
+                                Some(p) => p,
+                            };
+
+                            local_config.forward_addr = Some(match forward_address.parse::<IpAddr>() {
+                                Ok(ip) => Address::from(SocketAddr::new(ip, forward_port)),
+                                Err(..) => Address::from((forward_address, forward_port)),
+                            });
+                        }
+
+                        #[cfg(feature = "local-tunnel")]
+                        if let Some(timeout) = local.forward_dns_timeout {
+                            if timeout == 0 {
+                                let err = Error::new(ErrorKind::Invalid, "`forward_dns_timeout` must be > 0", None);
+                                return Err(err);
+                            }
+                            local_config.forward_dns_timeout = Some(Duration::from_millis(timeout));
+                        }
+
+                        #[cfg(feature = "local-tunnel")]
+                        if let Some(forwards) = local.forwards {
+                            if protocol != ProtocolType::Tunnel {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "`forwards` is only supported by tunnel locals",
+                                    None,
+                                );
+                                return Err(err);
+                            }
+
+                            for forward in forwards {
+                                if forward.local_port == 0 || forward.forward_port == 0 {
+                                    let err = Error::new(
+                                        ErrorKind::Malformed,
+                                        "`local_port` and `forward_port` of `forwards` cannot be 0",
+                                        None,
+                                    );
+                                    return Err(err);
+                                }
+
+                                let addr = get_local_address(
+                                    forward.local_address.or_else(|| tunnel_local_address.clone()),
+                                    forward.local_port,
+                                    config.ipv6_first.unwrap_or(false),
+                                );
+                                let forward_addr = match forward.forward_address.parse::<IpAddr>() {
+                                    Ok(ip) => Address::from(SocketAddr::new(ip, forward.forward_port)),
+                                    Err(..) => Address::from((forward.forward_address, forward.forward_port)),
+                                };
+                                let mode = match forward.mode {
+                                    None => None,
+                                    Some(mode) => match mode.parse::<Mode>() {
+                                        Ok(mode) => Some(mode),
+                                        Err(..) => {
+                                            let err =
+                                                Error::new(ErrorKind::Malformed, "invalid `mode` of `forwards`", None);
+                                            return Err(err);
+                                        }
+                                    },
+                                };
+
+                                local_config.tunnel_forwards.push(TunnelForwardConfig {
+                                    addr,
+                                    forward_addr,
+                                    mode,
+                                });
+                            }
+                        }
+
+                        #[cfg(feature = "local-tunnel")]
+                        if let Some(port) = local.reverse_port {
+                            if protocol != ProtocolType::ReverseTunnel {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "`reverse_port` is only supported by reverse-tunnel locals",
+                                    None,
+                                );
+                                return Err(err);
+                            }
+                            local_config.reverse_port = Some(port);
+                        }
+
+                        #[cfg(feature = "local-redir")]
+                        if let Some(tcp_redir) = local.tcp_redir {
+                            match tcp_redir.parse::<RedirType>() {
+                                Ok(r) => local_config.tcp_redir = r,
+                                Err(..) => {
+                                    let err = Error::new(ErrorKind::Malformed, "`tcp_redir` invalid", None);
+                                    return Err(err);
+                                }
+                            }
+                        }
+
+                        #[cfg(feature = "local-redir")]
+                        if let Some(udp_redir) = local.udp_redir {
+                            match udp_redir.parse::<RedirType>() {
+                                Ok(r) => local_config.udp_redir = r,
+                                Err(..) => {
+                                    let err = Error::new(ErrorKind::Malformed, "`udp_redir` invalid", None);
+                                    return Err(err);
+                                }
+                            }
+                        }
+
+                        #[cfg(all(feature = "local-redir", target_os = "linux"))]
+                        if let Some(redir_firewall) = local.redir_firewall {
+                            let mut firewall_config = RedirFirewallConfig::default();
+                            if let Some(backend) = redir_firewall.backend {
+                                match backend.parse::<RedirFirewallBackend>() {
+                                    Ok(b) => firewall_config.backend = b,
+                                    Err(..) => {
+                                        let err = Error::new(
+                                            ErrorKind::Malformed,
+                                            "`redir_firewall.backend` invalid",
+                                            Some(format!(
+                                                "{backend} is not one of \"auto\", \"nftables\" or \"iptables\""
+                                            )),
+                                        );
+                                        return Err(err);
+                                    }
+                                }
+                            }
+                            for interface in redir_firewall.interfaces.unwrap_or_default() {
+                                // Names are written into firewall rules
+                                if interface.is_empty()
+                                    || !interface
+                                        .bytes()
+                                        .all(|b| b.is_ascii_alphanumeric() || b"-_.@".contains(&b))
+                                {
+                                    let err = Error::new(
+                                        ErrorKind::Malformed,
+                                        "`redir_firewall.interfaces` invalid",
+                                        Some(format!("{interface:?} is not an interface name")),
+                                    );
+                                    return Err(err);
+                                }
+                                firewall_config.interfaces.push(interface);
+                            }
+                            for bypass in redir_firewall.bypass.unwrap_or_default() {
+                                let net = match bypass.parse::<IpNet>() {
+                                    Ok(net) => net,
+                                    Err(..) => match bypass.parse::<IpAddr>() {
+                                        Ok(addr) => IpNet::from(addr),
+                                        Err(..) => {
+                                            let err = Error::new(
+                                                ErrorKind::Malformed,
+                                                "`redir_firewall.bypass` invalid",
+                                                Some(format!("{bypass} is not an IP address or network")),
+                                            );
+                                            return Err(err);
+                                        }
+                                    },
+                                };
+                                firewall_config.bypass.push(net);
+                            }
+                            if let Some(fwmark) = redir_firewall.fwmark {
+                                firewall_config.fwmark = fwmark;
+                            }
+                            if let Some(route_table) = redir_firewall.route_table {
+                                firewall_config.route_table = route_table;
+                            }
+                            local_config.redir_firewall = Some(firewall_config);
+                        }
+
+                        #[cfg(feature = "local-dns-over-tls")]
+                        {
+                            local_config.local_dns_tls = local.local_dns_tls.map(From::from);
+                            local_config.remote_dns_tls = local.remote_dns_tls.map(From::from);
+                        }
+
+                        #[cfg(feature = "local-dns-over-quic")]
+                        if let Some(local_dns_quic) = local.local_dns_quic {
+                            if local_config.local_dns_tls.is_some() {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "`local_dns_quic` conflicts with `local_dns_tls`",
+                                    None,
+                                );
+                                return Err(err);
+                            }
+                            local_config.local_dns_quic = Some(From::from(local_dns_quic));
+                        }
+
+                        #[cfg(feature = "local-dns-over-quic")]
+                        let local_dns_quic = local_config.local_dns_quic.is_some();
+                        #[cfg(all(feature = "local-dns-over-tls", not(feature = "local-dns-over-quic")))]
+                        let local_dns_quic = false;
+
+                        // DNS over TLS and QUIC listen on port 853 by default, RFC 7858 and RFC 9250
+                        #[cfg(feature = "local-dns-over-tls")]
+                        let (local_dns_default_port, remote_dns_default_port) = (
+                            if local_config.local_dns_tls.is_some() || local_dns_quic {
+                                853
+                            } else {
+                                53
+                            },
+                            if local_config.remote_dns_tls.is_some() { 853 } else { 53 },
+                        );
+                        #[cfg(all(feature = "local-dns", not(feature = "local-dns-over-tls")))]
+                        let (local_dns_default_port, remote_dns_default_port) = (53, 53);
+
+                        #[cfg(feature = "local-dns")]
+                        if let Some(local_dns_address) = local.local_dns_address {
+                            match local_dns_address.parse::<IpAddr>() {
+                                Ok(ip) => {
+                                    local_config.local_dns_addr = Some(NameServerAddr::SocketAddr(SocketAddr::new(
+                                        ip,
+                                        local.local_dns_port.unwrap_or(local_dns_default_port),
+                                    )));
+                                }
+                                #[cfg(all(unix, feature = "local-dns-over-tls"))]
+                                Err(..) if local_config.local_dns_tls.is_some() || local_dns_quic => {
+                                    let err = Error::new(
+                                        ErrorKind::Invalid,
+                                        "`local_dns_tls` and `local_dns_quic` require an IP address of `local_dns_address`",
+                                        None,
+                                    );
+                                    return Err(err);
+                                }
+                                #[cfg(unix)]
+                                Err(..) => {
+                                    local_config.local_dns_addr =
+                                        Some(NameServerAddr::UnixSocketAddr(PathBuf::from(local_dns_address)));
+                                }
+                                #[cfg(not(unix))]
+                                Err(..) => {
+                                    let err = Error::new(ErrorKind::Malformed, "`local_dns_address` invalid", None);
+                                    return Err(err);
+                                }
+                            }
+                        }
+
+                        #[cfg(feature = "local-dns")]
+                        if let Some(client_cache_size) = local.client_cache_size {
+                            local_config.client_cache_size = Some(client_cache_size);
+                        }
+
+                        #[cfg(feature = "local-dns")]
+                        {
+                            local_config.dns_quarantine_failures = local.dns_quarantine_failures;
+                            local_config.dns_quarantine_duration =
+                                local.dns_quarantine_duration.map(Duration::from_secs);
+                        }
+
+                        #[cfg(feature = "local-dns")]
+                        {
+                            if let (Some(min_ttl), Some(max_ttl)) = (local.dns_cache_min_ttl, local.dns_cache_max_ttl) {
+                                if min_ttl > max_ttl {
+                                    let err = Error::new(
+                                        ErrorKind::Invalid,
+                                        "`dns_cache_min_ttl` is larger than `dns_cache_max_ttl`",
+                                        None,
+                                    );
+                                    return Err(err);
+                                }
+                            }
+
+                            local_config.dns_cache_size = local.dns_cache_size;
+                            local_config.dns_cache_min_ttl = local.dns_cache_min_ttl.map(Duration::from_secs);
+                            local_config.dns_cache_max_ttl = local.dns_cache_max_ttl.map(Duration::from_secs);
+                        }
+
+                        #[cfg(feature = "local-dns")]
+                        if let Some(dns_rules) = local.dns_rules {
+                            for rule in dns_rules {
+                                let upstream = match rule.upstream {
+                                    None => None,
+                                    Some(upstream) => match upstream.parse::<DnsRuleUpstream>() {
+                                        Ok(u) => Some(u),
+                                        #[allow(unreachable_patterns)]
+                                        Err(..) => {
+                                            let err = Error::new(
+                                                ErrorKind::Malformed,
+                                                "`dns_rules` upstream invalid",
+                                                Some(upstream),
+                                            );
+                                            return Err(err);
+                                        }
+                                    },
+                                };
+                                let drop_answers = match rule.drop_answers {
+                                    None => None,
+                                    Some(drop_answers) => match drop_answers.parse::<DnsRuleDropAnswers>() {
+                                        Ok(d) => Some(d),
+                                        Err(..) => {
+                                            let err = Error::new(
+                                                ErrorKind::Malformed,
+                                                "`dns_rules` drop_answers should be \"A\" or \"AAAA\"",
+                                                Some(drop_answers),
+                                            );
+                                            return Err(err);
+                                        }
+                                    },
+                                };
+                                if upstream.is_none() && drop_answers.is_none() {
+                                    let err = Error::new(
+                                        ErrorKind::Invalid,
+                                        "`dns_rules` rule requires upstream or drop_answers",
+                                        None,
+                                    );
+                                    return Err(err);
+                                }
+
+                                let mut rule = DnsRule::new(rule.domains, upstream);
+                                if rule.domains().is_empty() {
+                                    let err = Error::new(ErrorKind::Invalid, "`dns_rules` rule without domains", None);
+                                    return Err(err);
+                                }
+                                if let Some(drop_answers) = drop_answers {
+                                    rule.set_drop_answers(drop_answers);
+                                }
+                                local_config.dns_rules.push(rule);
+                            }
+                        }
+
+                        #[cfg(feature = "local-dns")]
+                        if let Some(dns_client_subnet) = local.dns_client_subnet {
+                            match dns_client_subnet.parse::<DnsClientSubnet>() {
+                                Ok(subnet) => local_config.dns_client_subnet = Some(subnet),
+                                Err(..) => {
+                                    let err = Error::new(
+                                        ErrorKind::Malformed,
+                                        "`dns_client_subnet` invalid",
+                                        Some(dns_client_subnet),
+                                    );
+                                    return Err(err);
+                                }
+                            }
+                        }
+
+                        #[cfg(feature = "local-dns")]
+                        if let Some(dns_acl_strategy) = local.dns_acl_strategy {
+                            match dns_acl_strategy.parse::<DnsAclStrategy>() {
+                                Ok(strategy) => local_config.dns_acl_strategy = Some(strategy),
+                                Err(..) => {
+                                    let err = Error::new(
+                                        ErrorKind::Malformed,
+                                        "`dns_acl_strategy` should be \"response\" or \"domain\"",
+                                        Some(dns_acl_strategy),
+                                    );
+                                    return Err(err);
+                                }
+                            }
+                        }
+
+                        #[cfg(feature = "local-dns")]
+                        if let Some(local_dns_extra_addresses) = local.local_dns_extra_addresses {
+                            for addr in local_dns_extra_addresses {
+                                match addr.parse::<NameServerAddr>() {
+                                    Ok(addr) => local_config.local_dns_extra_addrs.push(addr),
+                                    #[allow(unreachable_patterns)]
+                                    Err(..) => {
+                                        let err = Error::new(
+                                            ErrorKind::Malformed,
+                                            "`local_dns_extra_addresses` invalid",
+                                            Some(addr),
+                                        );
+                                        return Err(err);
+                                    }
+                                }
+                            }
+                        }
+
+                        #[cfg(feature = "local-dns")]
+                        if let Some(dns_upstream_strategy) = local.dns_upstream_strategy {
+                            match dns_upstream_strategy.parse::<DnsUpstreamStrategy>() {
+                                Ok(strategy) => local_config.dns_upstream_strategy = Some(strategy),
+                                Err(..) => {
+                                    let err = Error::new(
+                                        ErrorKind::Malformed,
+                                        "`dns_upstream_strategy` should be \"failover\", \"round_robin\" or \"parallel\"",
+                                        Some(dns_upstream_strategy),
+                                    );
+                                    return Err(err);
+                                }
+                            }
+                        }
+
+                        #[cfg(feature = "local-dns")]
+                        {
+                            if let Some(dns_block_lists) = local.dns_block_lists {
+                                local_config.dns_block_lists = dns_block_lists.into_iter().map(PathBuf::from).collect();
+                            }
+
+                            if let Some(dns_block_response) = local.dns_block_response {
+                                match dns_block_response.parse::<DnsBlockResponse>() {
+                                    Ok(r) => local_config.dns_block_response = Some(r),
+                                    Err(..) => {
+                                        let err = Error::new(
+                                            ErrorKind::Malformed,
+                                            "`dns_block_response` should be \"nxdomain\", \"zero\" or an IP address",
+                                            Some(dns_block_response),
+                                        );
+                                        return Err(err);
+                                    }
+                                }
+                            }
+
+                            if let Some(dns_hosts) = local.dns_hosts {
+                                for (name, addrs) in dns_hosts {
+                                    let mut ips = Vec::with_capacity(addrs.len());
+                                    for addr in addrs {
+                                        match addr.parse::<IpAddr>() {
+                                            Ok(ip) => ips.push(ip),
+                                            Err(..) => {
+                                                let err = Error::new(
+                                                    ErrorKind::Malformed,
+                                                    "`dns_hosts` address invalid",
+                                                    Some(format!("{}: {}", name, addr)),
+                                                );
+                                                return Err(err);
+                                            }
+                                        }
+                                    }
+                                    local_config.dns_hosts.insert(name, ips);
+                                }
+                            }
+
+                            if let Some(query_log) = local.dns_query_log {
+                                let mut config = DnsQueryLogConfig::new(PathBuf::from(query_log.path));
+                                if let Some(max_size) = query_log.max_size {
+                                    config.max_size = max_size;
+                                }
+                                if let Some(max_files) = query_log.max_files {
+                                    config.max_files = max_files;
+                                }
+                                local_config.dns_query_log = Some(config);
+                            }
                         }
 
-                        if let Some(local_udp_port) = local.local_udp_port {
-                            if local_udp_port == 0 {
-                                let err = Error::new(ErrorKind::Malformed, "`local_udp_port` cannot be 0", None);
+                        #[cfg(feature = "local-dns")]
+                        if let Some(remote_dns_address) = local.remote_dns_address {
+                            let remote_dns_port = local.remote_dns_port.unwrap_or(remote_dns_default_port);
+                            local_config.remote_dns_addr = Some(match remote_dns_address.parse::<IpAddr>() {
+                                Ok(ip) => Address::from(SocketAddr::new(ip, remote_dns_port)),
+                                Err(..) => Address::from((remote_dns_address, remote_dns_port)),
+                            });
+                        }
+
+                        #[cfg(feature = "local-dns-over-https")]
+                        if let Some(remote_dns_https) = local.remote_dns_https {
+                            let https_config = match remote_dns_https.url.parse::<hyper::Uri>() {
+                                Ok(url) => DnsHttpsConfig::new(url),
+                                Err(..) => None,
+                            };
+                            let mut https_config = match https_config {
+                                Some(c) => c,
+                                None => {
+                                    let err = Error::new(
+                                        ErrorKind::Malformed,
+                                        "`remote_dns_https.url` invalid",
+                                        Some(format!("{} is not an https URL", remote_dns_https.url)),
+                                    );
+                                    return Err(err);
+                                }
+                            };
+
+                            for bootstrap in remote_dns_https.bootstrap.unwrap_or_default() {
+                                match bootstrap.parse::<IpAddr>() {
+                                    Ok(ip) => https_config.bootstrap.push(ip),
+                                    Err(..) => {
+                                        let err = Error::new(
+                                            ErrorKind::Malformed,
+                                            "`remote_dns_https.bootstrap` invalid",
+                                            Some(format!("{bootstrap} is not an IP address")),
+                                        );
+                                        return Err(err);
+                                    }
+                                }
+                            }
+
+                            // Upstream of remote queries is labeled by the endpoint
+                            if local_config.remote_dns_addr.is_none() {
+                                let (host, port) = https_config.host_port();
+                                local_config.remote_dns_addr = Some(match host.parse::<IpAddr>() {
+                                    Ok(ip) => Address::from(SocketAddr::new(ip, port)),
+                                    Err(..) => Address::from((host.to_owned(), port)),
+                                });
+                            }
+                            #[cfg(feature = "local-dns-over-tls")]
+                            if local_config.remote_dns_tls.is_some() {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "`remote_dns_https` conflicts with `remote_dns_tls`",
+                                    None,
+                                );
                                 return Err(err);
                             }
+                            local_config.remote_dns_https = Some(https_config);
+                        }
 
-                            let local_udp_addr = get_local_address(
-                                local.local_udp_address,
-                                local_udp_port,
-                                config.ipv6_first.unwrap_or(false),
-                            );
+                        #[cfg(feature = "local-tun")]
+                        if let Some(tun_interface_address) = local.tun_interface_address {
+                            match tun_interface_address.parse::<IpNet>() {
+                                Ok(addr) => local_config.tun_interface_address = Some(addr),
+                                Err(..) => {
+                                    let err = Error::new(ErrorKind::Malformed, "`tun_interface_address` invalid", None);
+                                    return Err(err);
+                                }
+                            }
+                        }
 
-                            local_config.udp_addr = Some(local_udp_addr);
+                        #[cfg(feature = "local-tun")]
+                        if let Some(tun_interface_destination) = local.tun_interface_destination {
+                            match tun_interface_destination.parse::<IpNet>() {
+                                Ok(addr) => local_config.tun_interface_destination = Some(addr),
+                                Err(..) => {
+                                    let err =
+                                        Error::new(ErrorKind::Malformed, "`tun_interface_destination` invalid", None);
+                                    return Err(err);
+                                }
+                            }
                         }
 
-                        #[cfg(target_os = "macos")]
-                        {
-                            local_config.launchd_tcp_socket_name = local.launchd_tcp_socket_name;
-                            local_config.launchd_udp_socket_name = local.launchd_udp_socket_name;
+                        #[cfg(feature = "local-tun")]
+                        if let Some(tun_interface_mtu) = local.tun_interface_mtu {
+                            if tun_interface_mtu < 576 {
+                                let err =
+                                    Error::new(ErrorKind::Invalid, "`tun_interface_mtu` must be at least 576", None);
+                                return Err(err);
+                            }
+                            local_config.tun_interface_mtu = Some(tun_interface_mtu);
                         }
 
-                        match local.mode {
-                            Some(mode) => match mode.parse::<Mode>() {
-                                Ok(mode) => local_config.mode = mode,
+                        #[cfg(feature = "local-tun")]
+                        if let Some(tun_interface_name) = local.tun_interface_name {
+                            local_config.tun_interface_name = Some(tun_interface_name);
+                        }
+
+                        #[cfg(all(feature = "local-tun", unix))]
+                        if let Some(tun_device_fd_from_path) = local.tun_device_fd_from_path {
+                            local_config.tun_device_fd_from_path = Some(From::from(tun_device_fd_from_path));
+                        }
+
+                        #[cfg(all(feature = "local-tun", unix))]
+                        if let Some(tun_device_fd) = local.tun_device_fd {
+                            if tun_device_fd < 0 {
+                                let err = Error::new(
+                                    ErrorKind::Malformed,
+                                    "`tun_device_fd` invalid",
+                                    Some(format!("{tun_device_fd} is not a file descriptor")),
+                                );
+                                return Err(err);
+                            }
+                            local_config.tun_device_fd = Some(tun_device_fd);
+                        }
+
+                        #[cfg(feature = "local-tun")]
+                        if let Some(tun_dscp) = local.tun_dscp {
+                            let mut dscp_config = TunDscpConfig {
+                                preserve: tun_dscp.preserve.unwrap_or(true),
+                                map: BTreeMap::new(),
+                            };
+                            for (from, to) in tun_dscp.map.unwrap_or_default() {
+                                match from.parse::<u8>() {
+                                    Ok(from) if from <= MAX_DSCP && to <= MAX_DSCP => {
+                                        dscp_config.map.insert(from, to);
+                                    }
+                                    _ => {
+                                        let err = Error::new(
+                                            ErrorKind::Malformed,
+                                            "`tun_dscp.map` invalid",
+                                            Some(format!("DSCP must be 0 ~ {MAX_DSCP}, but found {from} => {to}")),
+                                        );
+                                        return Err(err);
+                                    }
+                                }
+                            }
+                            local_config.tun_dscp = Some(dscp_config);
+                        }
+
+                        #[cfg(all(feature = "local-tun", windows))]
+                        if let Some(tun_ipv6) = local.tun_ipv6 {
+                            let address = match tun_ipv6.address.parse::<Ipv6Net>() {
+                                Ok(a) => a,
                                 Err(..) => {
-                                    let err = Error::new(ErrorKind::Malformed, "invalid `mode`", None);
+                                    let err = Error::new(ErrorKind::Malformed, "`tun_ipv6.address` invalid", None);
                                     return Err(err);
                                 }
-                            },
-                            None => {
-                                // DNS server runs in `TcpAndUdp` mode by default to maintain backwards compatibility
-                                // see https://github.com/shadowsocks/shadowsocks-rust/issues/1281
-                                let mode = match protocol {
-                                    #[cfg(feature = "local-dns")]
-                                    ProtocolType::Dns => Mode::TcpAndUdp,
-                                    _ => global_mode,
-                                };
+                            };
 
-                                local_config.mode = mode;
+                            let mut ipv6_config = TunIpv6Config {
+                                address,
+                                routes: Vec::new(),
+                                dns: Vec::new(),
+                            };
+                            for route in tun_ipv6.routes.unwrap_or_default() {
+                                match route.parse::<Ipv6Net>() {
+                                    Ok(r) => ipv6_config.routes.push(r),
+                                    Err(..) => {
+                                        let err = Error::new(
+                                            ErrorKind::Malformed,
+                                            "`tun_ipv6.routes` invalid",
+                                            Some(format!("{route} is not an IPv6 network")),
+                                        );
+                                        return Err(err);
+                                    }
+                                }
+                            }
+                            for dns in tun_ipv6.dns.unwrap_or_default() {
+                                match dns.parse::<Ipv6Addr>() {
+                                    Ok(d) => ipv6_config.dns.push(d),
+                                    Err(..) => {
+                                        let err = Error::new(
+                                            ErrorKind::Malformed,
+                                            "`tun_ipv6.dns` invalid",
+                                            Some(format!("{dns} is not an IPv6 address")),
+                                        );
+                                        return Err(err);
+                                    }
+                                }
                             }
+                            local_config.tun_ipv6 = Some(ipv6_config);
                         }
 
-                        #[cfg(feature = "local-tunnel")]
-                        if let Some(forward_address) = local.forward_address {
-                            let forward_port = match local.forward_port {
-                                None | Some(0) => {
-                                    let err =
-                                        Error::new(ErrorKind::Malformed, "`forward_port` cannot be missing or 0", None);
+                        #[cfg(all(feature = "local-tun", feature = "local-fake-dns"))]
+                        if let Some(b) = local.tun_fake_dns {
+                            local_config.tun_fake_dns = b;
+                        }
+
+                        #[cfg(feature = "local-tun")]
+                        for rule in local.tun_dns_hijack.unwrap_or_default() {
+                            let destination = match rule.destination {
+                                None => None,
+                                Some(destination) => match destination.parse::<IpNet>() {
+                                    Ok(net) => Some(net),
+                                    Err(..) => match destination.parse::<IpAddr>() {
+                                        Ok(addr) => Some(IpNet::from(addr)),
+                                        Err(..) => {
+                                            let err = Error::new(
+                                                ErrorKind::Malformed,
+                                                "`tun_dns_hijack.destination` invalid",
+                                                Some(format!("{destination} is not an IP address or network")),
+                                            );
+                                            return Err(err);
+                                        }
+                                    },
+                                },
+                            };
+                            let action = match rule.action.parse::<TunDnsHijackAction>() {
+                                Ok(a) => a,
+                                Err(..) => {
+                                    let err = Error::new(
+                                        ErrorKind::Malformed,
+                                        "`tun_dns_hijack.action` invalid",
+                                        Some(format!(
+                                            "{} is not one of \"hijack\", \"pass\" or \"block\"",
+                                            rule.action
+                                        )),
+                                    );
                                     return Err(err);
                                 }
-                                Some(p) => p,
                             };
 
-                            local_config.forward_addr = Some(match forward_address.parse::<IpAddr>() {
-                                Ok(ip) => Address::from(SocketAddr::new(ip, forward_port)),
-                                Err(..) => Address::from((forward_address, forward_port)),
+                            #[cfg(feature = "local-fake-dns")]
+                            let hijackable = local_config.tun_fake_dns;
+                            #[cfg(not(feature = "local-fake-dns"))]
+                            let hijackable = false;
+                            if action == TunDnsHijackAction::Hijack && !hijackable {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "`tun_dns_hijack` hijacks DNS queries without `tun_fake_dns`",
+                                    None,
+                                );
+                                return Err(err);
+                            }
+
+                            local_config.tun_dns_hijack.push(TunDnsHijackRule {
+                                destination,
+                                port: rule.port,
+                                action,
                             });
                         }
 
-                        #[cfg(feature = "local-redir")]
-                        if let Some(tcp_redir) = local.tcp_redir {
-                            match tcp_redir.parse::<RedirType>() {
-                                Ok(r) => local_config.tcp_redir = r,
+                        #[cfg(feature = "local-tun")]
+                        if let Some(tun_icmp_echo) = local.tun_icmp_echo {
+                            local_config.tun_icmp_echo = match tun_icmp_echo.parse::<TunIcmpEchoPolicy>() {
+                                Ok(p) => p,
                                 Err(..) => {
-                                    let err = Error::new(ErrorKind::Malformed, "`tcp_redir` invalid", None);
+                                    let err = Error::new(
+                                        ErrorKind::Malformed,
+                                        "`tun_icmp_echo` invalid",
+                                        Some(format!(
+                                            "{tun_icmp_echo} is not one of \"reply\", \"server\" or \"drop\""
+                                        )),
+                                    );
+                                    return Err(err);
+                                }
+                            };
+                        }
+
+                        #[cfg(all(feature = "local-tun", target_os = "linux"))]
+                        if let Some(tun_offload) = local.tun_offload {
+                            if tun_offload && local_config.tun_device_fd_from_path.is_some() {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "`tun_offload` conflicts with `tun_device_fd_from_path`",
+                                    Some("tun devices created by others don't have virtio-net headers".to_owned()),
+                                );
+                                return Err(err);
+                            }
+                            local_config.tun_offload = tun_offload;
+                        }
+
+                        #[cfg(all(feature = "local-tun", any(target_os = "linux", target_os = "macos", windows)))]
+                        if let Some(tun_auto_route) = local.tun_auto_route {
+                            #[cfg(unix)]
+                            if local_config.tun_device_fd_from_path.is_some() {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "`tun_auto_route` conflicts with `tun_device_fd_from_path`",
+                                    Some(
+                                        "routes of tun devices created by others are managed by their creators"
+                                            .to_owned(),
+                                    ),
+                                );
+                                return Err(err);
+                            }
+
+                            let mut route_config = TunAutoRouteConfig::default();
+                            for bypass in tun_auto_route.bypass.unwrap_or_default() {
+                                match bypass.parse::<IpNet>() {
+                                    Ok(net) => route_config.bypass.push(net),
+                                    Err(..) => match bypass.parse::<IpAddr>() {
+                                        Ok(addr) => route_config.bypass.push(IpNet::from(addr)),
+                                        Err(..) => {
+                                            let err = Error::new(
+                                                ErrorKind::Malformed,
+                                                "`tun_auto_route.bypass` invalid",
+                                                Some(format!("{bypass} is not an IP address or network")),
+                                            );
+                                            return Err(err);
+                                        }
+                                    },
+                                }
+                            }
+
+                            #[cfg(target_os = "linux")]
+                            {
+                                route_config.fwmark = tun_auto_route.fwmark;
+                                if let Some(route_table) = tun_auto_route.route_table {
+                                    route_config.route_table = route_table;
+                                }
+
+                                for uids in tun_auto_route.bypass_uids.unwrap_or_default() {
+                                    let range = match uids.split_once('-') {
+                                        None => uids.trim().parse::<u32>().map(|uid| (uid, uid)),
+                                        Some((start, end)) => start
+                                            .trim()
+                                            .parse::<u32>()
+                                            .and_then(|start| end.trim().parse::<u32>().map(|end| (start, end))),
+                                    };
+                                    match range {
+                                        Ok((start, end)) if start <= end => route_config.bypass_uids.push((start, end)),
+                                        _ => {
+                                            let err = Error::new(
+                                                ErrorKind::Malformed,
+                                                "`tun_auto_route.bypass_uids` invalid",
+                                                Some(format!("{uids} is not a UID or a range of UIDs like 1000-1999")),
+                                            );
+                                            return Err(err);
+                                        }
+                                    }
+                                }
+
+                                for cgroup in tun_auto_route.bypass_cgroups.unwrap_or_default() {
+                                    let cgroup = cgroup.trim_matches('/');
+                                    if cgroup.is_empty() || cgroup.contains(['"', '\\']) {
+                                        let err = Error::new(
+                                            ErrorKind::Malformed,
+                                            "`tun_auto_route.bypass_cgroups` invalid",
+                                            Some(format!("{cgroup:?} is not a cgroup path")),
+                                        );
+                                        return Err(err);
+                                    }
+                                    route_config.bypass_cgroups.push(cgroup.to_owned());
+                                }
+                                if !route_config.bypass_cgroups.is_empty() && route_config.fwmark.is_none() {
+                                    let err = Error::new(
+                                        ErrorKind::Invalid,
+                                        "`tun_auto_route.bypass_cgroups` requires `tun_auto_route.fwmark`",
+                                        None,
+                                    );
+                                    return Err(err);
+                                }
+                            }
+
+                            #[cfg(any(target_os = "linux", target_os = "macos"))]
+                            {
+                                route_config.ipv6 = tun_auto_route.ipv6.unwrap_or(false);
+
+                                if let Some(suffix) = tun_auto_route.ipv6_address_suffix {
+                                    match suffix.parse::<Ipv6Addr>() {
+                                        Ok(suffix) => route_config.ipv6_address_suffix = Some(suffix),
+                                        Err(..) => {
+                                            let err = Error::new(
+                                                ErrorKind::Malformed,
+                                                "`tun_auto_route.ipv6_address_suffix` invalid",
+                                                Some(format!("{suffix} is not an IPv6 interface identifier like ::1")),
+                                            );
+                                            return Err(err);
+                                        }
+                                    }
+                                    if !route_config.ipv6 {
+                                        let err = Error::new(
+                                            ErrorKind::Invalid,
+                                            "`tun_auto_route.ipv6_address_suffix` requires `tun_auto_route.ipv6`",
+                                            None,
+                                        );
+                                        return Err(err);
+                                    }
+                                }
+                            }
+
+                            local_config.tun_auto_route = Some(route_config);
+                        }
+
+                        #[cfg(feature = "local")]
+                        if let Some(socks5_auth_config_path) = local.socks5_auth_config_path {
+                            local_config.socks5_auth = Socks5AuthConfig::load_from_file(&socks5_auth_config_path)?;
+                        }
+
+                        #[cfg(feature = "local")]
+                        if let Some(socks5_udp_prebind) = local.socks5_udp_prebind {
+                            local_config.socks5_udp_prebind = socks5_udp_prebind;
+                        }
+
+                        #[cfg(feature = "local")]
+                        if let Some(external_addrs) = local.socks5_udp_external_addresses {
+                            for (local_ip, external_ip) in external_addrs {
+                                match (local_ip.parse::<IpAddr>(), external_ip.parse::<IpAddr>()) {
+                                    (Ok(local_ip), Ok(external_ip)) => {
+                                        local_config.socks5_udp_external_addrs.insert(local_ip, external_ip);
+                                    }
+                                    _ => {
+                                        let err = Error::new(
+                                            ErrorKind::Malformed,
+                                            "`socks5_udp_external_addresses` should map local IPs to external IPs",
+                                            Some(format!("{local_ip}: {external_ip}")),
+                                        );
+                                        return Err(err);
+                                    }
+                                }
+                            }
+                        }
+
+                        #[cfg(feature = "local")]
+                        if let Some(username_routing) = local.socks5_username_routing {
+                            local_config.socks5_auth.username_routing = username_routing;
+                        }
+
+                        #[cfg(feature = "local-http")]
+                        if let Some(http_auth_config_path) = local.http_auth_config_path {
+                            local_config.http_auth = HttpAuthConfig::load_from_file(&http_auth_config_path)?;
+                        }
+                        #[cfg(feature = "local-http")]
+                        if let Some(http_pac_path) = local.http_pac_path {
+                            if !http_pac_path.starts_with('/') {
+                                let err = Error::new(
+                                    ErrorKind::Malformed,
+                                    "`http_pac_path` should be an absolute path starting with `/`",
+                                    None,
+                                );
+                                return Err(err);
+                            }
+                            local_config.http_pac_path = Some(http_pac_path);
+                        }
+                        #[cfg(feature = "local-http")]
+                        if let Some(http_pac_acl) = local.http_pac_acl {
+                            local_config.http_pac_acl = http_pac_acl;
+                        }
+
+                        #[cfg(feature = "local-tls")]
+                        match (local.tls_cert, local.tls_key) {
+                            (Some(cert), Some(key)) => {
+                                let supported = match protocol {
+                                    ProtocolType::Socks => true,
+                                    #[cfg(feature = "local-http")]
+                                    ProtocolType::Http | ProtocolType::Mixed => true,
+                                    #[cfg(feature = "local-dns-server-tls")]
+                                    ProtocolType::Dns => true,
+                                    #[allow(unreachable_patterns)]
+                                    _ => false,
+                                };
+                                if !supported {
+                                    let err = Error::new(
+                                        ErrorKind::Invalid,
+                                        "`tls_cert` is only supported by socks, http, mixed and dns locals",
+                                        None,
+                                    );
+                                    return Err(err);
+                                }
+
+                                local_config.tls = Some(TlsListenerConfig {
+                                    cert: PathBuf::from(cert),
+                                    key: PathBuf::from(key),
+                                    client_ca: local.tls_client_ca.map(PathBuf::from),
+                                });
+                            }
+                            (None, None) => {
+                                if local.tls_client_ca.is_some() {
+                                    let err = Error::new(
+                                        ErrorKind::MissingField,
+                                        "`tls_client_ca` requires `tls_cert` and `tls_key`",
+                                        None,
+                                    );
                                     return Err(err);
                                 }
                             }
+                            _ => {
+                                let err = Error::new(
+                                    ErrorKind::MissingField,
+                                    "`tls_cert` and `tls_key` must be set together",
+                                    None,
+                                );
+                                return Err(err);
+                            }
                         }
 
-                        #[cfg(feature = "local-redir")]
-                        if let Some(udp_redir) = local.udp_redir {
-                            match udp_redir.parse::<RedirType>() {
-                                Ok(r) => local_config.udp_redir = r,
+                        #[cfg(feature = "local-dns-server-tls")]
+                        if let Some(dns_tls_address) = local.dns_tls_address {
+                            match dns_tls_address.parse::<ServerAddr>() {
+                                Ok(a) => local_config.dns_tls_addr = Some(a),
                                 Err(..) => {
-                                    let err = Error::new(ErrorKind::Malformed, "`udp_redir` invalid", None);
+                                    let err = Error::new(
+                                        ErrorKind::Invalid,
+                                        "invalid `dns_tls_address`, must be host:port",
+                                        None,
+                                    );
                                     return Err(err);
                                 }
                             }
                         }
-
-                        #[cfg(feature = "local-dns")]
-                        if let Some(local_dns_address) = local.local_dns_address {
-                            match local_dns_address.parse::<IpAddr>() {
-                                Ok(ip) => {
-                                    local_config.local_dns_addr = Some(NameServerAddr::SocketAddr(SocketAddr::new(
-                                        ip,
-                                        local.local_dns_port.unwrap_or(53),
-                                    )));
-                                }
-                                #[cfg(unix)]
-                                Err(..) => {
-                                    local_config.local_dns_addr =
-                                        Some(NameServerAddr::UnixSocketAddr(PathBuf::from(local_dns_address)));
+                        #[cfg(feature = "local-dns-server-https")]
+                        {
+                            if let Some(dns_https_address) = local.dns_https_address {
+                                match dns_https_address.parse::<ServerAddr>() {
+                                    Ok(a) => local_config.dns_https_addr = Some(a),
+                                    Err(..) => {
+                                        let err = Error::new(
+                                            ErrorKind::Invalid,
+                                            "invalid `dns_https_address`, must be host:port",
+                                            None,
+                                        );
+                                        return Err(err);
+                                    }
                                 }
-                                #[cfg(not(unix))]
-                                Err(..) => {
-                                    let err = Error::new(ErrorKind::Malformed, "`local_dns_address` invalid", None);
+                            }
+                            if let Some(dns_https_path) = local.dns_https_path {
+                                if !dns_https_path.starts_with('/') {
+                                    let err = Error::new(
+                                        ErrorKind::Malformed,
+                                        "`dns_https_path` must start with /",
+                                        Some(dns_https_path),
+                                    );
                                     return Err(err);
                                 }
+                                local_config.dns_https_path = Some(dns_https_path);
                             }
                         }
+                        #[cfg(feature = "local-dns-server-tls")]
+                        {
+                            #[cfg(feature = "local-dns-server-https")]
+                            let has_listener =
+                                local_config.dns_tls_addr.is_some() || local_config.dns_https_addr.is_some();
+                            #[cfg(not(feature = "local-dns-server-https"))]
+                            let has_listener = local_config.dns_tls_addr.is_some();
 
-                        #[cfg(feature = "local-dns")]
-                        if let Some(client_cache_size) = local.client_cache_size {
-                            local_config.client_cache_size = Some(client_cache_size);
-                        }
-
-                        #[cfg(feature = "local-dns")]
-                        if let Some(remote_dns_address) = local.remote_dns_address {
-                            let remote_dns_port = local.remote_dns_port.unwrap_or(53);
-                            local_config.remote_dns_addr = Some(match remote_dns_address.parse::<IpAddr>() {
-                                Ok(ip) => Address::from(SocketAddr::new(ip, remote_dns_port)),
-                                Err(..) => Address::from((remote_dns_address, remote_dns_port)),
-                            });
+                            if has_listener && local_config.tls.is_none() {
+                                let err = Error::new(
+                                    ErrorKind::MissingField,
+                                    "`dns_tls_address` and `dns_https_address` require `tls_cert` and `tls_key`",
+                                    None,
+                                );
+                                return Err(err);
+                            }
+                            if !has_listener && protocol == ProtocolType::Dns && local_config.tls.is_some() {
+                                let err = Error::new(
+                                    ErrorKind::MissingField,
+                                    "`tls_cert` of dns locals requires `dns_tls_address` or `dns_https_address`",
+                                    None,
+                                );
+                                return Err(err);
+                            }
                         }
 
-                        #[cfg(feature = "local-tun")]
-                        if let Some(tun_interface_address) = local.tun_interface_address {
-                            match tun_interface_address.parse::<IpNet>() {
-                                Ok(addr) => local_config.tun_interface_address = Some(addr),
-                                Err(..) => {
-                                    let err = Error::new(ErrorKind::Malformed, "`tun_interface_address` invalid", None);
+                        #[cfg(feature = "local")]
+                        {
+                            let limit = InboundLimitConfig {
+                                max_connections: local.max_connections,
+                                max_connections_per_ip: local.max_connections_per_ip,
+                                connection_rate: local.connection_rate,
+                                connection_rate_per_ip: local.connection_rate_per_ip,
+                            };
+                            if !limit.is_empty() {
+                                let supported = match protocol {
+                                    ProtocolType::Socks => true,
+                                    #[cfg(feature = "local-http")]
+                                    ProtocolType::Http | ProtocolType::Mixed => true,
+                                    #[cfg(feature = "local-tunnel")]
+                                    ProtocolType::Tunnel => true,
+                                    #[allow(unreachable_patterns)]
+                                    _ => false,
+                                };
+                                if !supported {
+                                    let err = Error::new(
+                                        ErrorKind::Invalid,
+                                        "connection limits are only supported by socks, http, mixed and tunnel locals",
+                                        None,
+                                    );
+                                    return Err(err);
+                                }
+                                if limit.max_connections == Some(0)
+                                    || limit.max_connections_per_ip == Some(0)
+                                    || limit.connection_rate == Some(0)
+                                    || limit.connection_rate_per_ip == Some(0)
+                                {
+                                    let err = Error::new(
+                                        ErrorKind::Invalid,
+                                        "`max_connections`, `max_connections_per_ip`, `connection_rate` and `connection_rate_per_ip` must be > 0",
+                                        None,
+                                    );
                                     return Err(err);
                                 }
+                                local_config.inbound_limit = limit;
                             }
                         }
 
-                        #[cfg(feature = "local-tun")]
-                        if let Some(tun_interface_destination) = local.tun_interface_destination {
-                            match tun_interface_destination.parse::<IpNet>() {
-                                Ok(addr) => local_config.tun_interface_destination = Some(addr),
-                                Err(..) => {
-                                    let err =
-                                        Error::new(ErrorKind::Malformed, "`tun_interface_destination` invalid", None);
-                                    return Err(err);
-                                }
+                        #[cfg(feature = "local")]
+                        if let Some(timeout) = local.sniff_timeout {
+                            let supported = match protocol {
+                                // Targets of socks, http and mixed locals are requested by clients
+                                ProtocolType::Socks => false,
+                                #[cfg(feature = "local-redir")]
+                                ProtocolType::Redir => true,
+                                #[cfg(feature = "local-tun")]
+                                ProtocolType::Tun => true,
+                                #[cfg(feature = "local-tunnel")]
+                                ProtocolType::Tunnel => true,
+                                #[allow(unreachable_patterns)]
+                                _ => false,
+                            };
+                            if !supported {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "`sniff_timeout` is only supported by redir, tun and tunnel locals",
+                                    None,
+                                );
+                                return Err(err);
+                            }
+                            if timeout == 0 {
+                                let err = Error::new(ErrorKind::Invalid, "`sniff_timeout` must be > 0", None);
+                                return Err(err);
                             }
+                            local_config.sniff_timeout = Some(Duration::from_millis(timeout));
                         }
 
-                        #[cfg(feature = "local-tun")]
-                        if let Some(tun_interface_name) = local.tun_interface_name {
-                            local_config.tun_interface_name = Some(tun_interface_name);
+                        #[cfg(feature = "local")]
+                        if let Some(udp_nat) = local.udp_nat {
+                            let supported = match protocol {
+                                #[cfg(feature = "local-redir")]
+                                ProtocolType::Redir => true,
+                                #[cfg(feature = "local-tun")]
+                                ProtocolType::Tun => true,
+                                #[allow(unreachable_patterns)]
+                                _ => false,
+                            };
+                            if !supported {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "`udp_nat` is only supported by redir and tun locals",
+                                    None,
+                                );
+                                return Err(err);
+                            }
+                            local_config.udp_nat = match udp_nat.parse::<UdpNatType>() {
+                                Ok(t) => t,
+                                Err(..) => {
+                                    let err = Error::new(
+                                        ErrorKind::Malformed,
+                                        "`udp_nat` invalid",
+                                        Some(format!("{udp_nat} is not one of \"full-cone\" or \"symmetric\"")),
+                                    );
+                                    return Err(err);
+                                }
+                            };
                         }
 
-                        #[cfg(all(feature = "local-tun", unix))]
-                        if let Some(tun_device_fd_from_path) = local.tun_device_fd_from_path {
-                            local_config.tun_device_fd_from_path = Some(From::from(tun_device_fd_from_path));
+                        #[cfg(all(feature = "local", any(target_os = "macos", windows)))]
+                        if let Some(system_proxy) = local.system_proxy {
+                            let supported = match protocol {
+                                ProtocolType::Socks => true,
+                                #[cfg(feature = "local-http")]
+                                ProtocolType::Http | ProtocolType::Mixed => true,
+                                #[allow(unreachable_patterns)]
+                                _ => false,
+                            };
+                            if system_proxy && !supported {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "`system_proxy` is only supported by socks, http and mixed locals",
+                                    None,
+                                );
+                                return Err(err);
+                            }
+                            if system_proxy && local_config.addr.is_none() {
+                                let err =
+                                    Error::new(ErrorKind::Invalid, "`system_proxy` requires `local_address`", None);
+                                return Err(err);
+                            }
+                            local_config.system_proxy = system_proxy;
                         }
 
+                        #[cfg(all(feature = "local", unix))]
+                        let ipc = local
+                            .local_unix_path
+                            .map(|p| ("local_unix_path", IpcAddr::UnixSocket(PathBuf::from(p))));
+                        #[cfg(all(feature = "local", windows))]
+                        let ipc = local
+                            .local_pipe_name
+                            .map(|n| ("local_pipe_name", IpcAddr::NamedPipe(n)));
                         #[cfg(feature = "local")]
-                        if let Some(socks5_auth_config_path) = local.socks5_auth_config_path {
-                            local_config.socks5_auth = Socks5AuthConfig::load_from_file(&socks5_auth_config_path)?;
+                        if let Some((key, ipc_addr)) = ipc {
+                            let supported = match protocol {
+                                ProtocolType::Socks => true,
+                                #[cfg(feature = "local-http")]
+                                ProtocolType::Http | ProtocolType::Mixed => true,
+                                #[allow(unreachable_patterns)]
+                                _ => false,
+                            };
+                            if !supported {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "IPC listener is only supported by socks, http and mixed locals",
+                                    Some(format!("`{key}` is set")),
+                                );
+                                return Err(err);
+                            }
+                            if local_config.addr.is_some() || local_config.udp_addr.is_some() {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "IPC listener conflicts with `local_address` / `local_udp_address`",
+                                    Some(format!("`{key}` is set")),
+                                );
+                                return Err(err);
+                            }
+                            #[cfg(feature = "local-http")]
+                            if local_config.http_pac_path.is_some() {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "`http_pac_path` is not supported by IPC listener",
+                                    Some(format!("`{key}` is set")),
+                                );
+                                return Err(err);
+                            }
+
+                            // UDP relay has no address for clients
+                            if mode_set && local_config.mode.enable_udp() {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "IPC listener only supports `mode` \"tcp_only\"",
+                                    Some(format!("`{key}` is set")),
+                                );
+                                return Err(err);
+                            }
+                            local_config.mode = Mode::TcpOnly;
+                            local_config.ipc_addr = Some(ipc_addr);
                         }
 
                         #[cfg(feature = "local-fake-dns")]
@@ -1806,9 +4935,33 @@ impl Config {
                             }
                         }
 
+                        if local.udp_timeout == Some(0) {
+                            let err = Error::new(ErrorKind::Invalid, "`udp_timeout` must be greater than 0", None);
+                            return Err(err);
+                        }
+                        if local.tcp_idle_timeout == Some(0) {
+                            let err = Error::new(ErrorKind::Invalid, "`tcp_idle_timeout` must be greater than 0", None);
+                            return Err(err);
+                        }
+                        if let Some(mss) = local.tcp_mss {
+                            if !(MIN_TCP_MSS..=MAX_TCP_MSS).contains(&mss) {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "`tcp_mss` out of range",
+                                    Some(format!("MSS must be {MIN_TCP_MSS} ~ {MAX_TCP_MSS}, but found {mss}")),
+                                );
+                                return Err(err);
+                            }
+                        }
+
                         let mut local_instance = LocalInstanceConfig {
                             config: local_config,
                             acl: None,
+                            no_delay: local.no_delay,
+                            udp_timeout: local.udp_timeout.map(Duration::from_secs),
+                            tcp_idle_timeout: local.tcp_idle_timeout.map(Duration::from_secs),
+                            tcp_half_close_timeout: local.tcp_half_close_timeout.map(Duration::from_secs),
+                            tcp_mss: local.tcp_mss,
                         };
 
                         if let Some(acl_path) = local.acl {
@@ -1958,6 +5111,26 @@ impl Config {
                     outbound_fwmark: config.outbound_fwmark,
                     outbound_bind_addr,
                     outbound_bind_interface: config.outbound_bind_interface.clone(),
+                    uplink: None,
+                    check_url: None,
+                    check_dns: None,
+                    check_interval: None,
+                    check_timeout: None,
+                    check_fail_threshold: None,
+                    check_recover_threshold: None,
+                    tier: 1,
+                    group: None,
+                    no_delay: None,
+                    max_connections: None,
+                    max_connections_per_ip: None,
+                    udp_max_associations_per_ip: None,
+                    rate_limit: None,
+                    user_rate_limits: HashMap::new(),
+                    user_acls: HashMap::new(),
+                    quota: QuotaConfig::default(),
+                    tcp_bind: false,
+                    reverse_tunnel: false,
+                    port_range: None,
                 };
 
                 nconfig.server.push(server_instance);
@@ -2028,10 +5201,38 @@ impl Config {
                 nsvr.set_source(server_source);
 
                 // Extensible Identity Header, Users
+                let mut user_rate_limits = HashMap::new();
+                let mut user_quotas = HashMap::new();
+                let mut user_acls = HashMap::new();
                 if let Some(users) = svr.users {
                     let mut user_manager = ServerUserManager::new();
 
                     for user in users {
+                        if let Some(limit) = RateLimitConfig::from_kbps(user.upload_kbps, user.download_kbps)? {
+                            user_rate_limits.insert(user.name.clone(), limit);
+                        }
+                        if let Some(quota) = user.quota {
+                            if quota == 0 {
+                                let err = Error::new(ErrorKind::Invalid, "`users[].quota` must be > 0", None);
+                                return Err(err);
+                            }
+                            user_quotas.insert(user.name.clone(), quota);
+                        }
+                        if let Some(ref acl_path) = user.acl {
+                            let acl = match AccessControl::load_from_file(acl_path) {
+                                Ok(acl) => acl,
+                                Err(err) => {
+                                    let err = Error::new(
+                                        ErrorKind::Invalid,
+                                        "`users[].acl` loading failed",
+                                        Some(format!("file {acl_path}, error: {err}")),
+                                    );
+                                    return Err(err);
+                                }
+                            };
+                            user_acls.insert(user.name.clone(), acl);
+                        }
+
                         let user = match ServerUser::with_encoded_key(user.name, &user.password) {
                             Ok(u) => u,
                             Err(..) => {
@@ -2141,6 +5342,26 @@ impl Config {
                     outbound_fwmark: config.outbound_fwmark,
                     outbound_bind_addr,
                     outbound_bind_interface: config.outbound_bind_interface.clone(),
+                    uplink: None,
+                    check_url: None,
+                    check_dns: None,
+                    check_interval: None,
+                    check_timeout: None,
+                    check_fail_threshold: None,
+                    check_recover_threshold: None,
+                    tier: 1,
+                    group: None,
+                    no_delay: None,
+                    max_connections: None,
+                    max_connections_per_ip: None,
+                    udp_max_associations_per_ip: None,
+                    rate_limit: None,
+                    user_rate_limits: HashMap::new(),
+                    user_acls: HashMap::new(),
+                    quota: QuotaConfig::default(),
+                    tcp_bind: false,
+                    reverse_tunnel: false,
+                    port_range: None,
                 };
 
                 if let Some(acl_path) = svr.acl {
@@ -2171,6 +5392,105 @@ impl Config {
                     server_instance.outbound_bind_interface = Some(outbound_bind_interface.clone());
                 }
 
+                server_instance.uplink = svr.uplink;
+
+                if let Some(check_url) = svr.check_url {
+                    match check_url.parse::<BalancerCheckUrl>() {
+                        Ok(u) => server_instance.check_url = Some(u),
+                        Err(..) => {
+                            let err = Error::new(
+                                ErrorKind::Invalid,
+                                "invalid `check_url`, must be http://host[:port][/path]",
+                                None,
+                            );
+                            return Err(err);
+                        }
+                    }
+                }
+
+                if let Some(check_dns) = svr.check_dns {
+                    match check_dns.parse::<ServerAddr>() {
+                        Ok(a) => server_instance.check_dns = Some(a),
+                        Err(..) => {
+                            let err = Error::new(ErrorKind::Invalid, "invalid `check_dns`, must be host:port", None);
+                            return Err(err);
+                        }
+                    }
+                }
+
+                if svr.check_fail_threshold == Some(0) || svr.check_recover_threshold == Some(0) {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "`check_fail_threshold` and `check_recover_threshold` must be > 0",
+                        None,
+                    );
+                    return Err(err);
+                }
+
+                if svr.tier == Some(0) {
+                    let err = Error::new(ErrorKind::Invalid, "`tier` must be > 0", None);
+                    return Err(err);
+                }
+
+                if svr.max_connections == Some(0) {
+                    let err = Error::new(ErrorKind::Invalid, "`max_connections` must be > 0", None);
+                    return Err(err);
+                }
+
+                if svr.max_connections_per_ip == Some(0) || svr.udp_max_associations_per_ip == Some(0) {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "`max_connections_per_ip` and `udp_max_associations_per_ip` must be > 0",
+                        None,
+                    );
+                    return Err(err);
+                }
+
+                server_instance.check_interval = svr.check_interval.map(Duration::from_secs);
+                server_instance.check_timeout = svr.check_timeout.map(Duration::from_secs);
+                server_instance.check_fail_threshold = svr.check_fail_threshold;
+                server_instance.check_recover_threshold = svr.check_recover_threshold;
+                server_instance.tier = svr.tier.unwrap_or(1);
+                server_instance.group = svr.group;
+                server_instance.no_delay = svr.no_delay;
+                server_instance.max_connections = svr.max_connections;
+                server_instance.max_connections_per_ip = svr.max_connections_per_ip;
+                server_instance.udp_max_associations_per_ip = svr.udp_max_associations_per_ip;
+                server_instance.rate_limit = RateLimitConfig::from_kbps(svr.upload_kbps, svr.download_kbps)?;
+                server_instance.user_rate_limits = user_rate_limits;
+                server_instance.user_acls = user_acls;
+
+                if svr.quota == Some(0) {
+                    let err = Error::new(ErrorKind::Invalid, "`quota` must be > 0", None);
+                    return Err(err);
+                }
+                if let Some(day) = svr.quota_reset_day {
+                    if !(1..=28).contains(&day) {
+                        let err = Error::new(ErrorKind::Invalid, "`quota_reset_day` must be in 1 - 28", None);
+                        return Err(err);
+                    }
+                }
+                server_instance.quota = QuotaConfig {
+                    quota: svr.quota,
+                    user_quotas,
+                    reset_day: svr.quota_reset_day,
+                };
+                server_instance.tcp_bind = svr.tcp_bind.unwrap_or(false);
+                server_instance.reverse_tunnel = svr.reverse_tunnel.unwrap_or(false);
+
+                if let Some(port_range) = svr.server_port_range {
+                    let range = parse_port_range(&port_range)?;
+                    if server_instance.config.plugin().is_some() {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "`server_port_range` is not supported with plugin",
+                            None,
+                        );
+                        return Err(err);
+                    }
+                    server_instance.port_range = Some(range);
+                }
+
                 nconfig.server.push(server_instance);
             }
         }
@@ -2251,9 +5571,71 @@ impl Config {
                 }
             }
 
+            #[cfg(feature = "manager-http")]
+            if let Some(manager_http) = config.manager_http {
+                let listen_addr = match manager_http.listen_address.parse::<SocketAddr>() {
+                    Ok(addr) => addr,
+                    Err(..) => {
+                        let err = Error::new(ErrorKind::Invalid, "invalid manager_http.listen_address", None);
+                        return Err(err);
+                    }
+                };
+
+                // Tokens are sent in plaintext, only for proxies that terminate TLS on the same host
+                if !listen_addr.ip().is_loopback() {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "manager_http must listen on a loopback address",
+                        None,
+                    );
+                    return Err(err);
+                }
+
+                if manager_http.token.is_empty() {
+                    let err = Error::new(ErrorKind::MissingField, "manager_http.token is required", None);
+                    return Err(err);
+                }
+
+                let mut allowed_ips = Vec::new();
+                for ip in manager_http.allowed_ips.unwrap_or_default() {
+                    let net = match ip.parse::<IpNet>() {
+                        Ok(net) => net,
+                        Err(..) => match ip.parse::<IpAddr>() {
+                            Ok(addr) => IpNet::from(addr),
+                            Err(..) => {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "invalid manager_http.allowed_ips",
+                                    Some(format!("{ip} is not an IP address or network")),
+                                );
+                                return Err(err);
+                            }
+                        },
+                    };
+                    allowed_ips.push(net);
+                }
+
+                manager_config.http = Some(ManagerHttpConfig {
+                    listen_addr,
+                    token: manager_http.token,
+                    allowed_ips,
+                });
+            }
+
             nconfig.manager = Some(manager_config);
         }
 
+        #[cfg(feature = "metrics")]
+        if let Some(metrics_address) = config.metrics_address {
+            nconfig.metrics_addr = match metrics_address.parse::<SocketAddr>() {
+                Ok(addr) => Some(addr),
+                Err(..) => {
+                    let err = Error::new(ErrorKind::Invalid, "invalid metrics_address", None);
+                    return Err(err);
+                }
+            };
+        }
+
         // DNS
         {
             match config.dns {
@@ -2310,6 +5692,22 @@ impl Config {
             nconfig.ipv6_only = o;
         }
 
+        // Disable the other family of outbound connections
+        if let Some(family) = config.outbound_addr_family {
+            nconfig.outbound_addr_family = match family.as_str() {
+                "ipv4" => Some(AddrFamily::Ipv4),
+                "ipv6" => Some(AddrFamily::Ipv6),
+                _ => {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "`outbound_addr_family` must be \"ipv4\" or \"ipv6\"",
+                        None,
+                    );
+                    return Err(err);
+                }
+            };
+        }
+
         // SO_MARK
         #[cfg(any(target_os = "linux", target_os = "android"))]
         if let Some(fwmark) = config.outbound_fwmark {
@@ -2331,43 +5729,534 @@ impl Config {
                     return Err(err);
                 }
             }
-        }
+        }
+
+        // Bind device / interface
+        nconfig.outbound_bind_interface = config.outbound_bind_interface;
+
+        // Multi-WAN uplinks
+        if let Some(uplinks) = config.uplinks {
+            for uplink in uplinks {
+                if uplink.name.is_empty() || nconfig.uplinks.iter().any(|u| u.name == uplink.name) {
+                    let err = Error::new(ErrorKind::Invalid, "uplinks must have unique non-empty names", None);
+                    return Err(err);
+                }
+
+                let bind_addr = match uplink.bind_addr {
+                    None => None,
+                    Some(addr) => match addr.parse::<IpAddr>() {
+                        Ok(a) => Some(a),
+                        Err(..) => {
+                            let err = Error::new(
+                                ErrorKind::Invalid,
+                                "invalid uplink bind_addr",
+                                Some(format!("uplink {}", uplink.name)),
+                            );
+                            return Err(err);
+                        }
+                    },
+                };
+
+                #[allow(unused_mut)]
+                let mut bound = uplink.interface.is_some() || bind_addr.is_some();
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                {
+                    bound = bound || uplink.fwmark.is_some();
+                }
+                if !bound {
+                    let err = Error::new(
+                        ErrorKind::MissingField,
+                        "uplink requires at least one of interface, bind_addr and fwmark",
+                        Some(format!("uplink {}", uplink.name)),
+                    );
+                    return Err(err);
+                }
+
+                if uplink.weight == Some(0) {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "uplink weight must be > 0",
+                        Some(format!("uplink {}", uplink.name)),
+                    );
+                    return Err(err);
+                }
+
+                nconfig.uplinks.push(UplinkConfig {
+                    name: uplink.name,
+                    bind_interface: uplink.interface,
+                    bind_addr,
+                    #[cfg(any(target_os = "linux", target_os = "android"))]
+                    fwmark: uplink.fwmark,
+                    weight: uplink.weight.unwrap_or(1),
+                });
+            }
+        }
+
+        // Security
+        if let Some(sec) = config.security {
+            if let Some(replay_attack) = sec.replay_attack {
+                if let Some(policy) = replay_attack.policy {
+                    match policy.parse::<ReplayAttackPolicy>() {
+                        Ok(p) => nconfig.security.replay_attack.policy = p,
+                        Err(..) => {
+                            let err = Error::new(ErrorKind::Invalid, "invalid replay attack policy", None);
+                            return Err(err);
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(balancer) = config.balancer {
+            let strategy = match balancer.strategy {
+                None => BalancerStrategy::default(),
+                Some(strategy) => match strategy.parse::<BalancerStrategy>() {
+                    Ok(s) => s,
+                    Err(..) => {
+                        let err = Error::new(ErrorKind::Invalid, "invalid balancer.strategy", None);
+                        return Err(err);
+                    }
+                },
+            };
+
+            let udp_strategy = match balancer.udp_strategy {
+                None => None,
+                Some(strategy) => match strategy.parse::<BalancerStrategy>() {
+                    Ok(s) => Some(s),
+                    Err(..) => {
+                        let err = Error::new(ErrorKind::Invalid, "invalid balancer.udp_strategy", None);
+                        return Err(err);
+                    }
+                },
+            };
+
+            let score_mode = match balancer.score_mode {
+                None => BalancerScoreMode::default(),
+                Some(mode) => match mode.parse::<BalancerScoreMode>() {
+                    Ok(m) => m,
+                    Err(..) => {
+                        let err = Error::new(ErrorKind::Invalid, "invalid balancer.score_mode", None);
+                        return Err(err);
+                    }
+                },
+            };
+
+            let check_url = match balancer.check_url {
+                None => None,
+                Some(check_url) => match check_url.parse::<BalancerCheckUrl>() {
+                    Ok(u) => Some(u),
+                    Err(..) => {
+                        let err = Error::new(ErrorKind::Invalid, "invalid balancer.check_url", None);
+                        return Err(err);
+                    }
+                },
+            };
+
+            let check_dns = match balancer.check_dns {
+                None => None,
+                Some(check_dns) => match check_dns.parse::<ServerAddr>() {
+                    Ok(a) => Some(a),
+                    Err(..) => {
+                        let err = Error::new(ErrorKind::Invalid, "invalid balancer.check_dns", None);
+                        return Err(err);
+                    }
+                },
+            };
+
+            let check_stun = match balancer.check_stun {
+                None => None,
+                Some(check_stun) => match check_stun.parse::<ServerAddr>() {
+                    Ok(a) => Some(a),
+                    Err(..) => {
+                        let err = Error::new(ErrorKind::Invalid, "invalid balancer.check_stun", None);
+                        return Err(err);
+                    }
+                },
+            };
+
+            let tcp_check_method = match balancer.tcp_check_method {
+                None => None,
+                Some(method) => match method.parse::<BalancerCheckMethod>() {
+                    Ok(m) if m.support_tcp() => Some(m),
+                    _ => {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "invalid balancer.tcp_check_method",
+                            Some(format!("{method:?} is not one of \"http\" or \"dns\"")),
+                        );
+                        return Err(err);
+                    }
+                },
+            };
+
+            let udp_check_method = match balancer.udp_check_method {
+                None => None,
+                Some(method) => match method.parse::<BalancerCheckMethod>() {
+                    Ok(m) if m.support_udp() => Some(m),
+                    _ => {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "invalid balancer.udp_check_method",
+                            Some(format!("{method:?} is not one of \"dns\" or \"stun\"")),
+                        );
+                        return Err(err);
+                    }
+                },
+            };
+
+            if let Some(packets) = balancer.udp_probe_packets {
+                if !(1..=100).contains(&packets) {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "balancer.udp_probe_packets must be in [1, 100]",
+                        None,
+                    );
+                    return Err(err);
+                }
+            }
+
+            if balancer.check_fail_threshold == Some(0) || balancer.check_recover_threshold == Some(0) {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "balancer.check_fail_threshold and balancer.check_recover_threshold must be > 0",
+                    None,
+                );
+                return Err(err);
+            }
+
+            #[cfg(feature = "local")]
+            if let Some(ref expr) = balancer.score_expr {
+                if let Err(err) = expr.parse::<ScoreExpr>() {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "balancer.score_expr is invalid",
+                        Some(err.to_string()),
+                    );
+                    return Err(err);
+                }
+            }
+
+            let routes = match balancer.routes {
+                None => None,
+                Some(routes_path) => match ServerRoutes::load_from_file(&routes_path) {
+                    Ok(r) => Some(r),
+                    Err(err) => {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "balancer.routes loading failed",
+                            Some(format!("file {routes_path}, error: {err}")),
+                        );
+                        return Err(err);
+                    }
+                },
+            };
+
+            nconfig.balancer = BalancerConfig {
+                max_server_rtt: balancer.max_server_rtt.map(Duration::from_secs),
+                check_interval: balancer.check_interval.map(Duration::from_secs),
+                check_best_interval: balancer.check_best_interval.map(Duration::from_secs),
+                score_tolerance: balancer.score_tolerance,
+                strategy,
+                udp_strategy,
+                score_mode,
+                ewma_alpha: balancer.ewma_alpha,
+                failure_penalty: balancer.failure_penalty,
+                rtt_weight: balancer.rtt_weight,
+                loss_weight: balancer.loss_weight,
+                verify_servers: balancer.verify_servers.unwrap_or(false),
+                check_url,
+                check_dns,
+                check_stun,
+                tcp_check_method,
+                udp_check_method,
+                udp_probe_packets: balancer.udp_probe_packets,
+                check_fail_threshold: balancer.check_fail_threshold,
+                check_recover_threshold: balancer.check_recover_threshold,
+                tier_max_latency: balancer.tier_max_latency.map(Duration::from_millis),
+                switch_margin: balancer.switch_margin,
+                switch_dwell: balancer.switch_dwell.map(Duration::from_secs),
+                warmup_checks: balancer.warmup_checks,
+                warmup_bypass: balancer.warmup_bypass.unwrap_or(false),
+                score_expr: balancer.score_expr,
+                routes,
+                state_file: balancer.state_file.map(PathBuf::from),
+            };
+        }
+
+        if let Some(mirror) = config.mirror {
+            nconfig.mirror = match (mirror.pcap_path, mirror.udp_sink) {
+                (None, None) => None,
+                (Some(path), None) => Some(TrafficMirrorSink::Pcap(PathBuf::from(path))),
+                (None, Some(addr)) => match addr.parse::<SocketAddr>() {
+                    Ok(addr) => Some(TrafficMirrorSink::Udp(addr)),
+                    Err(..) => {
+                        let err = Error::new(ErrorKind::Invalid, "invalid mirror.udp_sink", None);
+                        return Err(err);
+                    }
+                },
+                (Some(..), Some(..)) => {
+                    let err = Error::new(
+                        ErrorKind::Malformed,
+                        "mirror.pcap_path and mirror.udp_sink cannot be set at the same time",
+                        None,
+                    );
+                    return Err(err);
+                }
+            };
+        }
+
+        if let Some(qos) = config.qos {
+            if qos.upload_kbps.is_none() && qos.download_kbps.is_none() {
+                let err = Error::new(
+                    ErrorKind::MissingField,
+                    "qos requires at least one of upload_kbps and download_kbps",
+                    None,
+                );
+                return Err(err);
+            }
+            if qos.upload_kbps == Some(0) || qos.download_kbps == Some(0) {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "qos.upload_kbps and qos.download_kbps must be greater than 0",
+                    None,
+                );
+                return Err(err);
+            }
+
+            nconfig.qos = Some(QosConfig {
+                upload_kbps: qos.upload_kbps,
+                download_kbps: qos.download_kbps,
+                bulk_bytes: qos.bulk_bytes.unwrap_or(QosConfig::DEFAULT_BULK_BYTES),
+                bulk_age: qos
+                    .bulk_age
+                    .map(Duration::from_secs)
+                    .unwrap_or(QosConfig::DEFAULT_BULK_AGE),
+            });
+        }
+
+        if let Some(policy) = config.special_target_policy {
+            nconfig.special_target_policy = match policy.parse::<SpecialTargetPolicy>() {
+                Ok(p) => p,
+                Err(..) => {
+                    let err = Error::new(ErrorKind::Invalid, "invalid special_target_policy", None);
+                    return Err(err);
+                }
+            };
+        }
+
+        #[cfg(feature = "local-history")]
+        if let Some(history) = config.history {
+            if history.path.is_empty() {
+                let err = Error::new(ErrorKind::MissingField, "history.path is required", None);
+                return Err(err);
+            }
+            if history.retention_days == Some(0) || history.max_records == Some(0) {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "history.retention_days and history.max_records must be greater than 0",
+                    None,
+                );
+                return Err(err);
+            }
+
+            nconfig.history = Some(HistoryConfig {
+                path: PathBuf::from(history.path),
+                retention: history.retention_days.map(|d| Duration::from_secs(d * 24 * 60 * 60)),
+                max_records: history.max_records,
+            });
+        }
+
+        #[cfg(feature = "local-file-watch")]
+        if let Some(file_watch) = config.file_watch {
+            let mut file_watch_config = FileWatchConfig::default();
+            if let Some(debounce_ms) = file_watch.debounce_ms {
+                file_watch_config.debounce = Duration::from_millis(debounce_ms);
+            }
+            nconfig.file_watch = Some(file_watch_config);
+        }
+
+        #[cfg(feature = "local")]
+        if let Some(control_addr) = config.local_control_address {
+            nconfig.local_control_addr = match control_addr.parse::<ManagerAddr>() {
+                Ok(addr) => Some(addr),
+                Err(..) => {
+                    let err = Error::new(ErrorKind::Invalid, "invalid local_control_address", None);
+                    return Err(err);
+                }
+            };
+        }
+
+        #[cfg(feature = "local-http")]
+        if let Some(control_http) = config.local_control_http {
+            let listen_addr = match control_http.listen_address.parse::<SocketAddr>() {
+                Ok(addr) => addr,
+                Err(..) => {
+                    let err = Error::new(ErrorKind::Invalid, "invalid local_control_http.listen_address", None);
+                    return Err(err);
+                }
+            };
+
+            if control_http.token.is_empty() {
+                let err = Error::new(ErrorKind::MissingField, "local_control_http.token is required", None);
+                return Err(err);
+            }
+
+            let mut allowed_ips = Vec::new();
+            for ip in control_http.allowed_ips.unwrap_or_default() {
+                let net = match ip.parse::<IpNet>() {
+                    Ok(net) => net,
+                    Err(..) => match ip.parse::<IpAddr>() {
+                        Ok(addr) => IpNet::from(addr),
+                        Err(..) => {
+                            let err = Error::new(
+                                ErrorKind::Invalid,
+                                "invalid local_control_http.allowed_ips",
+                                Some(format!("{ip} is not an IP address or network")),
+                            );
+                            return Err(err);
+                        }
+                    },
+                };
+                allowed_ips.push(net);
+            }
+
+            let (tls_cert, tls_key) = match (control_http.tls_cert, control_http.tls_key) {
+                (Some(cert), Some(key)) => {
+                    if !cfg!(feature = "local-http-rustls") {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "local_control_http.tls_cert not supported",
+                            Some(
+                                "TLS requires feature \"local-http-rustls\", which is not enabled in this build"
+                                    .to_owned(),
+                            ),
+                        );
+                        return Err(err);
+                    }
+                    (Some(PathBuf::from(cert)), Some(PathBuf::from(key)))
+                }
+                (None, None) => {
+                    // Tokens are sent in plaintext, only for proxies that terminate TLS on the same host
+                    if !listen_addr.ip().is_loopback() {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "local_control_http without TLS must listen on a loopback address",
+                            None,
+                        );
+                        return Err(err);
+                    }
+                    (None, None)
+                }
+                _ => {
+                    let err = Error::new(
+                        ErrorKind::MissingField,
+                        "local_control_http.tls_cert and tls_key must be set together",
+                        None,
+                    );
+                    return Err(err);
+                }
+            };
+
+            nconfig.local_control_http = Some(ControlHttpConfig {
+                listen_addr,
+                token: control_http.token,
+                allowed_ips,
+                tls_cert,
+                tls_key,
+            });
+        }
+
+        #[cfg(feature = "local")]
+        if let Some(ha) = config.ha {
+            let listen_addr = match ha.listen_address.parse::<SocketAddr>() {
+                Ok(addr) => addr,
+                Err(..) => {
+                    let err = Error::new(ErrorKind::Invalid, "invalid ha.listen_address", None);
+                    return Err(err);
+                }
+            };
+            let peer_addr = match ha.peer_address.parse::<SocketAddr>() {
+                Ok(addr) => addr,
+                Err(..) => {
+                    let err = Error::new(ErrorKind::Invalid, "invalid ha.peer_address", None);
+                    return Err(err);
+                }
+            };
+
+            if ha.secret.is_empty() {
+                let err = Error::new(ErrorKind::Invalid, "ha.secret must not be empty", None);
+                return Err(err);
+            }
+
+            let heartbeat_interval = ha
+                .heartbeat_interval
+                .map(Duration::from_secs)
+                .unwrap_or(HaConfig::DEFAULT_HEARTBEAT_INTERVAL);
+            let failover_timeout = ha
+                .failover_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(HaConfig::DEFAULT_FAILOVER_TIMEOUT);
+            if heartbeat_interval.is_zero() || failover_timeout <= heartbeat_interval {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "ha.heartbeat_interval must be > 0, and less than ha.failover_timeout",
+                    None,
+                );
+                return Err(err);
+            }
 
-        // Bind device / interface
-        nconfig.outbound_bind_interface = config.outbound_bind_interface;
+            nconfig.ha = Some(HaConfig {
+                listen_addr,
+                peer_addr,
+                secret: ha.secret,
+                priority: ha.priority.unwrap_or(HaConfig::DEFAULT_PRIORITY),
+                heartbeat_interval,
+                failover_timeout,
+                notify_active: ha.notify_active,
+                notify_standby: ha.notify_standby,
+            });
+        }
 
-        // Security
-        if let Some(sec) = config.security {
-            if let Some(replay_attack) = sec.replay_attack {
-                if let Some(policy) = replay_attack.policy {
-                    match policy.parse::<ReplayAttackPolicy>() {
-                        Ok(p) => nconfig.security.replay_attack.policy = p,
-                        Err(..) => {
-                            let err = Error::new(ErrorKind::Invalid, "invalid replay attack policy", None);
-                            return Err(err);
-                        }
+        let mut rule_sources = RuleSources::default();
+        if let Some(rules) = config.rules {
+            for kind in rules.precedence.unwrap_or_default() {
+                match kind.parse::<RuleSourceKind>() {
+                    Ok(kind) if !rule_sources.precedence.contains(&kind) => rule_sources.precedence.push(kind),
+                    _ => {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "invalid rules.precedence",
+                            Some(format!(
+                                "{kind:?} is not one of \"inline\", \"acl\", \"rule_sets\" or is duplicated"
+                            )),
+                        );
+                        return Err(err);
                     }
                 }
             }
+            rule_sources.bypass_list = rules.bypass_list.unwrap_or_default();
+            rule_sources.proxy_list = rules.proxy_list.unwrap_or_default();
+            rule_sources.rule_sets = rules
+                .rule_sets
+                .unwrap_or_default()
+                .into_iter()
+                .map(|rule_set| RuleSet {
+                    path: PathBuf::from(rule_set.path),
+                    url: rule_set.url,
+                })
+                .collect();
+            rule_sources.geoip_dir = rules.geoip_dir.map(PathBuf::from);
         }
 
-        if let Some(balancer) = config.balancer {
-            nconfig.balancer = BalancerConfig {
-                max_server_rtt: balancer.max_server_rtt.map(Duration::from_secs),
-                check_interval: balancer.check_interval.map(Duration::from_secs),
-                check_best_interval: balancer.check_best_interval.map(Duration::from_secs),
-            };
-        }
-
-        if let Some(acl_path) = config.acl {
-            let acl = match AccessControl::load_from_file(&acl_path) {
+        if config.acl.is_some() || !rule_sources.is_empty() {
+            let acl = match AccessControl::load(config.acl.as_deref().map(Path::new), &rule_sources) {
                 Ok(acl) => acl,
                 Err(err) => {
-                    let err = Error::new(
-                        ErrorKind::Invalid,
-                        "acl loading failed",
-                        Some(format!("file {acl_path}, error: {err}")),
-                    );
+                    let detail = match config.acl {
+                        Some(ref acl_path) => format!("file {acl_path}, error: {err}"),
+                        None => format!("error: {err}"),
+                    };
+                    let err = Error::new(ErrorKind::Invalid, "acl loading failed", Some(detail));
                     return Err(err);
                 }
             };
@@ -2521,6 +6410,9 @@ impl Config {
     /// Load Config from a `str`
     pub fn load_from_str(s: &str, config_type: ConfigType) -> Result<Config, Error> {
         let c = json5::from_str::<SSConfig>(s)?;
+        if config_type.is_local() {
+            check_missing_features(&json5::from_str::<serde_json::Value>(s)?)?;
+        }
         Config::load_from_ssconfig(c, config_type)
     }
 
@@ -2582,6 +6474,79 @@ impl Config {
                     return Err(err);
                 }
             }
+
+            if let Some(tolerance) = self.balancer.score_tolerance {
+                if !tolerance.is_finite() || tolerance < 0.0 {
+                    let err = Error::new(ErrorKind::Invalid, "balancer.score_tolerance must be >= 0", None);
+                    return Err(err);
+                }
+            }
+
+            if let Some(margin) = self.balancer.switch_margin {
+                if !(0.0..1.0).contains(&margin) {
+                    let err = Error::new(ErrorKind::Invalid, "balancer.switch_margin must be in [0, 1)", None);
+                    return Err(err);
+                }
+            }
+
+            if let Some(alpha) = self.balancer.ewma_alpha {
+                if !(alpha > 0.0 && alpha <= 1.0) {
+                    let err = Error::new(ErrorKind::Invalid, "balancer.ewma_alpha must be in (0, 1]", None);
+                    return Err(err);
+                }
+            }
+
+            if let Some(penalty) = self.balancer.failure_penalty {
+                if !penalty.is_finite() || penalty <= 0.0 {
+                    let err = Error::new(ErrorKind::Invalid, "balancer.failure_penalty must be > 0", None);
+                    return Err(err);
+                }
+            }
+
+            if let Some(weight) = self.balancer.rtt_weight {
+                if !weight.is_finite() || weight < 0.0 {
+                    let err = Error::new(ErrorKind::Invalid, "balancer.rtt_weight must be >= 0", None);
+                    return Err(err);
+                }
+            }
+
+            if let Some(weight) = self.balancer.loss_weight {
+                if !weight.is_finite() || weight < 0.0 {
+                    let err = Error::new(ErrorKind::Invalid, "balancer.loss_weight must be >= 0", None);
+                    return Err(err);
+                }
+            }
+
+            // Uplinks related checks
+            if !self.uplinks.is_empty() {
+                #[allow(unused_mut)]
+                let mut bound = self.outbound_bind_interface.is_some() || self.outbound_bind_addr.is_some();
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                {
+                    bound = bound || self.outbound_fwmark.is_some();
+                }
+                if bound {
+                    let err = Error::new(
+                        ErrorKind::Malformed,
+                        "uplinks cannot be used with outbound_bind_interface, outbound_bind_addr or outbound_fwmark",
+                        None,
+                    );
+                    return Err(err);
+                }
+            }
+
+            for inst in &self.server {
+                if let Some(ref uplink) = inst.uplink {
+                    if !self.uplinks.iter().any(|u| &u.name == uplink) {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "server's uplink is not in uplinks",
+                            Some(format!("server {}, uplink {}", inst.config.addr(), uplink)),
+                        );
+                        return Err(err);
+                    }
+                }
+            }
         }
 
         if self.config_type.is_server() && self.server.is_empty() {
@@ -2711,7 +6676,14 @@ impl fmt::Display for Config {
 
         // Locals
         if !self.local.is_empty() {
-            if self.local.len() == 1 && self.local[0].config.is_basic() {
+            if self.local.len() == 1
+                && self.local[0].config.is_basic()
+                && self.local[0].no_delay.is_none()
+                && self.local[0].udp_timeout.is_none()
+                && self.local[0].tcp_idle_timeout.is_none()
+                && self.local[0].tcp_half_close_timeout.is_none()
+                && self.local[0].tcp_mss.is_none()
+            {
                 let local_instance = &self.local[0];
                 let local = &local_instance.config;
                 if let Some(ref a) = local.addr {
@@ -2737,7 +6709,7 @@ impl fmt::Display for Config {
 
                 // ACL
                 if let Some(ref acl) = local_instance.acl {
-                    jconf.acl = Some(acl.file_path().to_str().unwrap().to_owned());
+                    jconf.acl = acl.file_path().and_then(|p| p.to_str()).map(ToOwned::to_owned);
                 }
             } else {
                 let mut jlocals = Vec::with_capacity(self.local.len());
@@ -2768,6 +6740,31 @@ impl fmt::Display for Config {
                             #[allow(unreachable_patterns)]
                             p => Some(p.as_str().to_owned()),
                         },
+                        #[cfg(feature = "local")]
+                        max_connections: local.inbound_limit.max_connections,
+                        #[cfg(feature = "local")]
+                        max_connections_per_ip: local.inbound_limit.max_connections_per_ip,
+                        #[cfg(feature = "local")]
+                        connection_rate: local.inbound_limit.connection_rate,
+                        #[cfg(feature = "local")]
+                        connection_rate_per_ip: local.inbound_limit.connection_rate_per_ip,
+                        #[cfg(feature = "local")]
+                        sniff_timeout: local.sniff_timeout.as_ref().map(|d| d.as_millis() as u64),
+                        #[cfg(feature = "local")]
+                        udp_nat: match local.udp_nat {
+                            UdpNatType::FullCone => None,
+                            UdpNatType::Symmetric => Some(local.udp_nat.to_string()),
+                        },
+                        #[cfg(all(feature = "local", any(target_os = "macos", windows)))]
+                        system_proxy: if local.system_proxy { Some(true) } else { None },
+                        #[cfg(all(feature = "local", unix))]
+                        local_unix_path: local.ipc_addr.as_ref().and_then(|a| match *a {
+                            IpcAddr::UnixSocket(ref p) => p.to_str().map(ToOwned::to_owned),
+                        }),
+                        #[cfg(all(feature = "local", windows))]
+                        local_pipe_name: local.ipc_addr.as_ref().map(|a| match *a {
+                            IpcAddr::NamedPipe(ref n) => n.clone(),
+                        }),
                         #[cfg(target_os = "macos")]
                         launchd_tcp_socket_name: local.launchd_tcp_socket_name.clone(),
                         #[cfg(target_os = "macos")]
@@ -2784,6 +6781,22 @@ impl fmt::Display for Config {
                         } else {
                             None
                         },
+                        #[cfg(all(feature = "local-redir", target_os = "linux"))]
+                        redir_firewall: local.redir_firewall.as_ref().map(|f| SSRedirFirewallConfig {
+                            backend: Some(f.backend.to_string()),
+                            interfaces: if f.interfaces.is_empty() {
+                                None
+                            } else {
+                                Some(f.interfaces.clone())
+                            },
+                            bypass: if f.bypass.is_empty() {
+                                None
+                            } else {
+                                Some(f.bypass.iter().map(ToString::to_string).collect())
+                            },
+                            fwmark: Some(f.fwmark),
+                            route_table: Some(f.route_table),
+                        }),
                         #[cfg(feature = "local-tunnel")]
                         forward_address: match local.forward_addr {
                             None => None,
@@ -2800,6 +6813,34 @@ impl fmt::Display for Config {
                                 Address::DomainNameAddress(.., port) => Some(*port),
                             },
                         },
+                        #[cfg(feature = "local-tunnel")]
+                        forward_dns_timeout: local.forward_dns_timeout.as_ref().map(|d| d.as_millis() as u64),
+                        #[cfg(feature = "local-tunnel")]
+                        forwards: if local.tunnel_forwards.is_empty() {
+                            None
+                        } else {
+                            Some(
+                                local
+                                    .tunnel_forwards
+                                    .iter()
+                                    .map(|f| SSTunnelForwardConfig {
+                                        local_address: Some(match f.addr {
+                                            ServerAddr::SocketAddr(ref sa) => sa.ip().to_string(),
+                                            ServerAddr::DomainName(ref dm, ..) => dm.to_string(),
+                                        }),
+                                        local_port: f.addr.port(),
+                                        forward_address: match f.forward_addr {
+                                            Address::SocketAddress(ref sa) => sa.ip().to_string(),
+                                            Address::DomainNameAddress(ref dm, ..) => dm.to_string(),
+                                        },
+                                        forward_port: f.forward_addr.port(),
+                                        mode: f.mode.map(|m| m.to_string()),
+                                    })
+                                    .collect(),
+                            )
+                        },
+                        #[cfg(feature = "local-tunnel")]
+                        reverse_port: local.reverse_port,
                         #[cfg(feature = "local-dns")]
                         local_dns_address: match local.local_dns_addr {
                             None => None,
@@ -2837,21 +6878,248 @@ impl fmt::Display for Config {
                             },
                         },
                         #[cfg(feature = "local-dns")]
+                        local_dns_extra_addresses: if local.local_dns_extra_addrs.is_empty() {
+                            None
+                        } else {
+                            Some(local.local_dns_extra_addrs.iter().map(ToString::to_string).collect())
+                        },
+                        #[cfg(feature = "local-dns")]
+                        dns_upstream_strategy: local.dns_upstream_strategy.as_ref().map(ToString::to_string),
+                        #[cfg(feature = "local-dns-over-https")]
+                        remote_dns_https: local.remote_dns_https.as_ref().map(|c| SSDnsHttpsConfig {
+                            url: c.url.to_string(),
+                            bootstrap: if c.bootstrap.is_empty() {
+                                None
+                            } else {
+                                Some(c.bootstrap.iter().map(ToString::to_string).collect())
+                            },
+                        }),
+                        #[cfg(feature = "local-dns-over-tls")]
+                        local_dns_tls: local.local_dns_tls.as_ref().map(From::from),
+                        #[cfg(feature = "local-dns-over-tls")]
+                        remote_dns_tls: local.remote_dns_tls.as_ref().map(From::from),
+                        #[cfg(feature = "local-dns-over-quic")]
+                        local_dns_quic: local.local_dns_quic.as_ref().map(From::from),
+                        #[cfg(feature = "local-dns")]
                         client_cache_size: local.client_cache_size,
+                        #[cfg(feature = "local-dns")]
+                        dns_quarantine_failures: local.dns_quarantine_failures,
+                        #[cfg(feature = "local-dns")]
+                        dns_quarantine_duration: local.dns_quarantine_duration.map(|d| d.as_secs()),
+                        #[cfg(feature = "local-dns")]
+                        dns_cache_size: local.dns_cache_size,
+                        #[cfg(feature = "local-dns")]
+                        dns_cache_min_ttl: local.dns_cache_min_ttl.map(|d| d.as_secs()),
+                        #[cfg(feature = "local-dns")]
+                        dns_cache_max_ttl: local.dns_cache_max_ttl.map(|d| d.as_secs()),
+                        #[cfg(feature = "local-dns")]
+                        dns_rules: if local.dns_rules.is_empty() {
+                            None
+                        } else {
+                            Some(
+                                local
+                                    .dns_rules
+                                    .iter()
+                                    .map(|r| SSDnsRule {
+                                        domains: r.domains().to_vec(),
+                                        upstream: r.upstream().map(ToString::to_string),
+                                        drop_answers: r.drop_answers().map(|d| d.to_string()),
+                                    })
+                                    .collect(),
+                            )
+                        },
+                        #[cfg(feature = "local-dns")]
+                        dns_client_subnet: local.dns_client_subnet.as_ref().map(ToString::to_string),
+                        #[cfg(feature = "local-dns")]
+                        dns_acl_strategy: local.dns_acl_strategy.as_ref().map(ToString::to_string),
+                        #[cfg(feature = "local-dns")]
+                        dns_block_lists: if local.dns_block_lists.is_empty() {
+                            None
+                        } else {
+                            Some(
+                                local
+                                    .dns_block_lists
+                                    .iter()
+                                    .map(|p| p.to_str().expect("dns_block_lists is not utf-8").to_owned())
+                                    .collect(),
+                            )
+                        },
+                        #[cfg(feature = "local-dns")]
+                        dns_block_response: local.dns_block_response.as_ref().map(ToString::to_string),
+                        #[cfg(feature = "local-dns")]
+                        dns_hosts: if local.dns_hosts.is_empty() {
+                            None
+                        } else {
+                            Some(
+                                local
+                                    .dns_hosts
+                                    .iter()
+                                    .map(|(name, addrs)| {
+                                        (name.clone(), addrs.iter().map(ToString::to_string).collect())
+                                    })
+                                    .collect(),
+                            )
+                        },
+                        #[cfg(feature = "local-dns")]
+                        dns_query_log: local.dns_query_log.as_ref().map(|c| SSDnsQueryLogConfig {
+                            path: c.path.to_str().expect("dns_query_log is not utf-8").to_owned(),
+                            max_size: Some(c.max_size),
+                            max_files: Some(c.max_files),
+                        }),
+                        #[cfg(feature = "local-dns-server-tls")]
+                        dns_tls_address: local.dns_tls_addr.as_ref().map(ToString::to_string),
+                        #[cfg(feature = "local-dns-server-https")]
+                        dns_https_address: local.dns_https_addr.as_ref().map(ToString::to_string),
+                        #[cfg(feature = "local-dns-server-https")]
+                        dns_https_path: local.dns_https_path.clone(),
                         #[cfg(feature = "local-tun")]
                         tun_interface_name: local.tun_interface_name.clone(),
                         #[cfg(feature = "local-tun")]
                         tun_interface_address: local.tun_interface_address.as_ref().map(ToString::to_string),
                         #[cfg(feature = "local-tun")]
                         tun_interface_destination: local.tun_interface_destination.as_ref().map(ToString::to_string),
+                        #[cfg(feature = "local-tun")]
+                        tun_interface_mtu: local.tun_interface_mtu,
                         #[cfg(all(feature = "local-tun", unix))]
                         tun_device_fd_from_path: local
                             .tun_device_fd_from_path
                             .as_ref()
                             .map(|p| p.to_str().expect("tun_device_fd_from_path is not utf-8").to_owned()),
+                        #[cfg(all(feature = "local-tun", unix))]
+                        tun_device_fd: local.tun_device_fd,
+                        #[cfg(feature = "local-tun")]
+                        tun_dscp: local.tun_dscp.as_ref().map(|d| SSTunDscpConfig {
+                            preserve: Some(d.preserve),
+                            map: if d.map.is_empty() {
+                                None
+                            } else {
+                                Some(d.map.iter().map(|(from, to)| (from.to_string(), *to)).collect())
+                            },
+                        }),
+                        #[cfg(all(feature = "local-tun", feature = "local-fake-dns"))]
+                        tun_fake_dns: if local.tun_fake_dns { Some(true) } else { None },
+                        #[cfg(feature = "local-tun")]
+                        tun_dns_hijack: if local.tun_dns_hijack.is_empty() {
+                            None
+                        } else {
+                            Some(
+                                local
+                                    .tun_dns_hijack
+                                    .iter()
+                                    .map(|r| SSTunDnsHijackRule {
+                                        destination: r.destination.as_ref().map(ToString::to_string),
+                                        port: r.port,
+                                        action: r.action.to_string(),
+                                    })
+                                    .collect(),
+                            )
+                        },
+                        #[cfg(feature = "local-tun")]
+                        tun_icmp_echo: match local.tun_icmp_echo {
+                            TunIcmpEchoPolicy::Reply => None,
+                            policy => Some(policy.to_string()),
+                        },
+                        #[cfg(all(feature = "local-tun", any(target_os = "linux", target_os = "macos", windows)))]
+                        tun_auto_route: local.tun_auto_route.as_ref().map(|c| SSTunAutoRouteConfig {
+                            bypass: if c.bypass.is_empty() {
+                                None
+                            } else {
+                                Some(c.bypass.iter().map(ToString::to_string).collect())
+                            },
+                            #[cfg(target_os = "linux")]
+                            fwmark: c.fwmark,
+                            #[cfg(target_os = "linux")]
+                            route_table: if c.route_table == TunAutoRouteConfig::DEFAULT_ROUTE_TABLE {
+                                None
+                            } else {
+                                Some(c.route_table)
+                            },
+                            #[cfg(target_os = "linux")]
+                            bypass_uids: if c.bypass_uids.is_empty() {
+                                None
+                            } else {
+                                Some(
+                                    c.bypass_uids
+                                        .iter()
+                                        .map(|&(start, end)| {
+                                            if start == end {
+                                                start.to_string()
+                                            } else {
+                                                format!("{start}-{end}")
+                                            }
+                                        })
+                                        .collect(),
+                                )
+                            },
+                            #[cfg(target_os = "linux")]
+                            bypass_cgroups: if c.bypass_cgroups.is_empty() {
+                                None
+                            } else {
+                                Some(c.bypass_cgroups.clone())
+                            },
+                            #[cfg(any(target_os = "linux", target_os = "macos"))]
+                            ipv6: if c.ipv6 { Some(true) } else { None },
+                            #[cfg(any(target_os = "linux", target_os = "macos"))]
+                            ipv6_address_suffix: c.ipv6_address_suffix.map(|s| s.to_string()),
+                        }),
+                        #[cfg(all(feature = "local-tun", target_os = "linux"))]
+                        tun_offload: if local.tun_offload { Some(true) } else { None },
+                        #[cfg(all(feature = "local-tun", windows))]
+                        tun_ipv6: local.tun_ipv6.as_ref().map(|c| SSTunIpv6Config {
+                            address: c.address.to_string(),
+                            routes: if c.routes.is_empty() {
+                                None
+                            } else {
+                                Some(c.routes.iter().map(ToString::to_string).collect())
+                            },
+                            dns: if c.dns.is_empty() {
+                                None
+                            } else {
+                                Some(c.dns.iter().map(ToString::to_string).collect())
+                            },
+                        }),
 
                         #[cfg(feature = "local")]
                         socks5_auth_config_path: None,
+                        #[cfg(feature = "local")]
+                        socks5_udp_prebind: if local.socks5_udp_prebind { Some(true) } else { None },
+                        #[cfg(feature = "local")]
+                        socks5_udp_external_addresses: if local.socks5_udp_external_addrs.is_empty() {
+                            None
+                        } else {
+                            Some(
+                                local
+                                    .socks5_udp_external_addrs
+                                    .iter()
+                                    .map(|(l, e)| (l.to_string(), e.to_string()))
+                                    .collect(),
+                            )
+                        },
+                        #[cfg(feature = "local")]
+                        socks5_username_routing: if local.socks5_auth.username_routing {
+                            Some(true)
+                        } else {
+                            None
+                        },
+
+                        #[cfg(feature = "local-http")]
+                        http_auth_config_path: None,
+                        #[cfg(feature = "local-http")]
+                        http_pac_path: local.http_pac_path.clone(),
+                        #[cfg(feature = "local-http")]
+                        http_pac_acl: if local.http_pac_acl { Some(true) } else { None },
+
+                        #[cfg(feature = "local-tls")]
+                        tls_cert: local.tls.as_ref().and_then(|t| t.cert.to_str()).map(ToOwned::to_owned),
+                        #[cfg(feature = "local-tls")]
+                        tls_key: local.tls.as_ref().and_then(|t| t.key.to_str()).map(ToOwned::to_owned),
+                        #[cfg(feature = "local-tls")]
+                        tls_client_ca: local
+                            .tls
+                            .as_ref()
+                            .and_then(|t| t.client_ca.as_deref())
+                            .and_then(|p| p.to_str())
+                            .map(ToOwned::to_owned),
 
                         #[cfg(feature = "local-fake-dns")]
                         fake_dns_record_expire_duration: local.fake_dns_record_expire_duration.map(|d| d.as_secs()),
@@ -2865,10 +7133,16 @@ impl fmt::Display for Config {
                             .as_ref()
                             .and_then(|n| n.to_str().map(ToOwned::to_owned)),
 
+                        no_delay: local_instance.no_delay,
+                        udp_timeout: local_instance.udp_timeout.map(|t| t.as_secs()),
+                        tcp_idle_timeout: local_instance.tcp_idle_timeout.map(|t| t.as_secs()),
+                        tcp_half_close_timeout: local_instance.tcp_half_close_timeout.map(|t| t.as_secs()),
+                        tcp_mss: local_instance.tcp_mss,
                         acl: local_instance
                             .acl
                             .as_ref()
-                            .and_then(|a| a.file_path().to_str().map(ToOwned::to_owned)),
+                            .and_then(|a| a.file_path())
+                            .and_then(|p| p.to_str().map(ToOwned::to_owned)),
                     };
                     jlocals.push(jlocal);
                 }
@@ -2918,7 +7192,7 @@ impl fmt::Display for Config {
                 jconf.mode = Some(svr.mode().to_string());
 
                 if let Some(ref acl) = inst.acl {
-                    jconf.acl = Some(acl.file_path().to_str().unwrap().to_owned());
+                    jconf.acl = acl.file_path().and_then(|p| p.to_str()).map(ToOwned::to_owned);
                 }
             }
             // For >1 servers, uses extended multiple server format
@@ -2937,6 +7211,7 @@ impl fmt::Display for Config {
                             ServerAddr::SocketAddr(ref sa) => sa.port(),
                             ServerAddr::DomainName(.., port) => port,
                         },
+                        server_port_range: inst.port_range.as_ref().map(|r| format!("{}-{}", r.start(), r.end())),
                         password: if svr.method().is_none() {
                             None
                         } else {
@@ -2946,9 +7221,19 @@ impl fmt::Display for Config {
                         users: svr.user_manager().map(|m| {
                             let mut vu = Vec::new();
                             for u in m.users_iter() {
+                                let limit = inst.user_rate_limits.get(u.name());
                                 vu.push(SSServerUserConfig {
                                     name: u.name().to_owned(),
                                     password: u.encoded_key(),
+                                    upload_kbps: limit.and_then(|l| l.upload_kbps),
+                                    download_kbps: limit.and_then(|l| l.download_kbps),
+                                    quota: inst.quota.user_quotas.get(u.name()).copied(),
+                                    acl: inst
+                                        .user_acls
+                                        .get(u.name())
+                                        .and_then(|acl| acl.file_path())
+                                        .and_then(|p| p.to_str())
+                                        .map(ToOwned::to_owned),
                                 });
                             }
                             vu
@@ -2987,11 +7272,31 @@ impl fmt::Display for Config {
                         acl: inst
                             .acl
                             .as_ref()
-                            .and_then(|a| a.file_path().to_str().map(ToOwned::to_owned)),
+                            .and_then(|a| a.file_path())
+                            .and_then(|p| p.to_str().map(ToOwned::to_owned)),
                         #[cfg(any(target_os = "linux", target_os = "android"))]
                         outbound_fwmark: inst.outbound_fwmark,
                         outbound_bind_addr: inst.outbound_bind_addr,
                         outbound_bind_interface: inst.outbound_bind_interface.clone(),
+                        uplink: inst.uplink.clone(),
+                        check_url: inst.check_url.as_ref().map(ToString::to_string),
+                        check_dns: inst.check_dns.as_ref().map(ToString::to_string),
+                        check_interval: inst.check_interval.as_ref().map(Duration::as_secs),
+                        check_timeout: inst.check_timeout.as_ref().map(Duration::as_secs),
+                        check_fail_threshold: inst.check_fail_threshold,
+                        check_recover_threshold: inst.check_recover_threshold,
+                        tier: if inst.tier != 1 { Some(inst.tier) } else { None },
+                        group: inst.group.clone(),
+                        no_delay: inst.no_delay,
+                        max_connections: inst.max_connections,
+                        max_connections_per_ip: inst.max_connections_per_ip,
+                        udp_max_associations_per_ip: inst.udp_max_associations_per_ip,
+                        upload_kbps: inst.rate_limit.and_then(|l| l.upload_kbps),
+                        download_kbps: inst.rate_limit.and_then(|l| l.download_kbps),
+                        quota: inst.quota.quota,
+                        quota_reset_day: inst.quota.reset_day,
+                        tcp_bind: if inst.tcp_bind { Some(true) } else { None },
+                        reverse_tunnel: if inst.reverse_tunnel { Some(true) } else { None },
                     });
                 }
 
@@ -3014,6 +7319,19 @@ impl fmt::Display for Config {
                 ManagerAddr::UnixSocketAddr(..) => None,
             };
 
+            #[cfg(feature = "manager-http")]
+            if let Some(ref http) = m.http {
+                jconf.manager_http = Some(SSManagerHttpConfig {
+                    listen_address: http.listen_addr.to_string(),
+                    token: http.token.clone(),
+                    allowed_ips: if http.allowed_ips.is_empty() {
+                        None
+                    } else {
+                        Some(http.allowed_ips.iter().map(ToString::to_string).collect())
+                    },
+                });
+            }
+
             if jconf.mode.is_none() {
                 jconf.mode = Some(m.mode.to_string());
             }
@@ -3069,6 +7387,11 @@ impl fmt::Display for Config {
 
         jconf.udp_max_associations = self.udp_max_associations;
 
+        #[cfg(feature = "metrics")]
+        if let Some(ref metrics_addr) = self.metrics_addr {
+            jconf.metrics_address = Some(metrics_addr.to_string());
+        }
+
         jconf.udp_mtu = self.udp_mtu;
 
         #[cfg(all(unix, not(target_os = "android")))]
@@ -3084,6 +7407,16 @@ impl fmt::Display for Config {
             jconf.ipv6_only = Some(self.ipv6_only);
         }
 
+        if let Some(family) = self.outbound_addr_family {
+            jconf.outbound_addr_family = Some(
+                match family {
+                    AddrFamily::Ipv4 => "ipv4",
+                    AddrFamily::Ipv6 => "ipv6",
+                }
+                .to_owned(),
+            );
+        }
+
         #[cfg(any(target_os = "linux", target_os = "android"))]
         {
             jconf.outbound_fwmark = self.outbound_fwmark;
@@ -3097,6 +7430,22 @@ impl fmt::Display for Config {
         jconf.outbound_bind_addr = self.outbound_bind_addr.map(|i| i.to_string());
         jconf.outbound_bind_interface.clone_from(&self.outbound_bind_interface);
 
+        if !self.uplinks.is_empty() {
+            jconf.uplinks = Some(
+                self.uplinks
+                    .iter()
+                    .map(|u| SSUplinkConfig {
+                        name: u.name.clone(),
+                        interface: u.bind_interface.clone(),
+                        bind_addr: u.bind_addr.map(|a| a.to_string()),
+                        #[cfg(any(target_os = "linux", target_os = "android"))]
+                        fwmark: u.fwmark,
+                        weight: if u.weight != 1 { Some(u.weight) } else { None },
+                    })
+                    .collect(),
+            );
+        }
+
         // Security
         if self.security.replay_attack.policy != ReplayAttackPolicy::default() {
             jconf.security = Some(SSSecurityConfig {
@@ -3107,17 +7456,206 @@ impl fmt::Display for Config {
         }
 
         // Balancer
-        if self.balancer.max_server_rtt.is_some() || self.balancer.check_interval.is_some() {
+        if self.balancer.max_server_rtt.is_some()
+            || self.balancer.check_interval.is_some()
+            || self.balancer.score_tolerance.is_some()
+            || self.balancer.strategy != BalancerStrategy::default()
+            || self.balancer.udp_strategy.is_some()
+            || self.balancer.score_mode != BalancerScoreMode::default()
+            || self.balancer.ewma_alpha.is_some()
+            || self.balancer.failure_penalty.is_some()
+            || self.balancer.rtt_weight.is_some()
+            || self.balancer.loss_weight.is_some()
+            || self.balancer.verify_servers
+            || self.balancer.check_url.is_some()
+            || self.balancer.check_dns.is_some()
+            || self.balancer.check_stun.is_some()
+            || self.balancer.tcp_check_method.is_some()
+            || self.balancer.udp_check_method.is_some()
+            || self.balancer.udp_probe_packets.is_some()
+            || self.balancer.check_fail_threshold.is_some()
+            || self.balancer.check_recover_threshold.is_some()
+            || self.balancer.tier_max_latency.is_some()
+            || self.balancer.switch_margin.is_some()
+            || self.balancer.switch_dwell.is_some()
+            || self.balancer.warmup_checks.is_some()
+            || self.balancer.warmup_bypass
+            || self.balancer.score_expr.is_some()
+            || self.balancer.routes.is_some()
+            || self.balancer.state_file.is_some()
+        {
             jconf.balancer = Some(SSBalancerConfig {
                 max_server_rtt: self.balancer.max_server_rtt.as_ref().map(Duration::as_secs),
                 check_interval: self.balancer.check_interval.as_ref().map(Duration::as_secs),
                 check_best_interval: self.balancer.check_best_interval.as_ref().map(Duration::as_secs),
+                score_tolerance: self.balancer.score_tolerance,
+                strategy: match self.balancer.strategy {
+                    BalancerStrategy::BestScore => None,
+                    strategy => Some(strategy.to_string()),
+                },
+                udp_strategy: self.balancer.udp_strategy.as_ref().map(ToString::to_string),
+                score_mode: match self.balancer.score_mode {
+                    BalancerScoreMode::Combined => None,
+                    mode => Some(mode.to_string()),
+                },
+                ewma_alpha: self.balancer.ewma_alpha,
+                failure_penalty: self.balancer.failure_penalty,
+                rtt_weight: self.balancer.rtt_weight,
+                loss_weight: self.balancer.loss_weight,
+                verify_servers: if self.balancer.verify_servers { Some(true) } else { None },
+                check_url: self.balancer.check_url.as_ref().map(ToString::to_string),
+                check_dns: self.balancer.check_dns.as_ref().map(ToString::to_string),
+                check_stun: self.balancer.check_stun.as_ref().map(ToString::to_string),
+                tcp_check_method: self.balancer.tcp_check_method.as_ref().map(ToString::to_string),
+                udp_check_method: self.balancer.udp_check_method.as_ref().map(ToString::to_string),
+                udp_probe_packets: self.balancer.udp_probe_packets,
+                check_fail_threshold: self.balancer.check_fail_threshold,
+                check_recover_threshold: self.balancer.check_recover_threshold,
+                tier_max_latency: self.balancer.tier_max_latency.as_ref().map(|d| d.as_millis() as u64),
+                switch_margin: self.balancer.switch_margin,
+                switch_dwell: self.balancer.switch_dwell.as_ref().map(Duration::as_secs),
+                warmup_checks: self.balancer.warmup_checks,
+                warmup_bypass: if self.balancer.warmup_bypass { Some(true) } else { None },
+                score_expr: self.balancer.score_expr.clone(),
+                routes: self
+                    .balancer
+                    .routes
+                    .as_ref()
+                    .and_then(|r| r.file_path().to_str().map(ToOwned::to_owned)),
+                state_file: self
+                    .balancer
+                    .state_file
+                    .as_ref()
+                    .and_then(|p| p.to_str().map(ToOwned::to_owned)),
+            });
+        }
+
+        // Traffic mirror
+        if let Some(ref mirror) = self.mirror {
+            jconf.mirror = Some(match *mirror {
+                TrafficMirrorSink::Pcap(ref path) => SSMirrorConfig {
+                    pcap_path: Some(path.to_str().unwrap().to_owned()),
+                    udp_sink: None,
+                },
+                TrafficMirrorSink::Udp(addr) => SSMirrorConfig {
+                    pcap_path: None,
+                    udp_sink: Some(addr.to_string()),
+                },
+            });
+        }
+
+        // Traffic classification
+        if let Some(ref qos) = self.qos {
+            jconf.qos = Some(SSQosConfig {
+                upload_kbps: qos.upload_kbps,
+                download_kbps: qos.download_kbps,
+                bulk_bytes: Some(qos.bulk_bytes),
+                bulk_age: Some(qos.bulk_age.as_secs()),
+            });
+        }
+
+        // Special targets
+        if self.special_target_policy != SpecialTargetPolicy::default() {
+            jconf.special_target_policy = Some(self.special_target_policy.to_string());
+        }
+
+        // Session history
+        #[cfg(feature = "local-history")]
+        if let Some(ref history) = self.history {
+            jconf.history = Some(SSHistoryConfig {
+                path: history.path.to_str().unwrap().to_owned(),
+                retention_days: history.retention.map(|d| d.as_secs() / (24 * 60 * 60)),
+                max_records: history.max_records,
+            });
+        }
+
+        // File watching
+        #[cfg(feature = "local-file-watch")]
+        if let Some(ref file_watch) = self.file_watch {
+            jconf.file_watch = Some(SSFileWatchConfig {
+                debounce_ms: Some(file_watch.debounce.as_millis() as u64),
+            });
+        }
+
+        // Local control socket
+        #[cfg(feature = "local")]
+        if let Some(ref control_addr) = self.local_control_addr {
+            jconf.local_control_address = Some(control_addr.to_string());
+        }
+
+        #[cfg(feature = "local-http")]
+        if let Some(ref control_http) = self.local_control_http {
+            jconf.local_control_http = Some(SSLocalControlHttpConfig {
+                listen_address: control_http.listen_addr.to_string(),
+                token: control_http.token.clone(),
+                allowed_ips: if control_http.allowed_ips.is_empty() {
+                    None
+                } else {
+                    Some(control_http.allowed_ips.iter().map(ToString::to_string).collect())
+                },
+                tls_cert: control_http
+                    .tls_cert
+                    .as_ref()
+                    .and_then(|p| p.to_str())
+                    .map(ToOwned::to_owned),
+                tls_key: control_http
+                    .tls_key
+                    .as_ref()
+                    .and_then(|p| p.to_str())
+                    .map(ToOwned::to_owned),
+            });
+        }
+
+        // Active / standby pairing
+        #[cfg(feature = "local")]
+        if let Some(ref ha) = self.ha {
+            jconf.ha = Some(SSHaConfig {
+                listen_address: ha.listen_addr.to_string(),
+                peer_address: ha.peer_addr.to_string(),
+                secret: ha.secret.clone(),
+                priority: Some(ha.priority),
+                heartbeat_interval: Some(ha.heartbeat_interval.as_secs()),
+                failover_timeout: Some(ha.failover_timeout.as_secs()),
+                notify_active: ha.notify_active.clone(),
+                notify_standby: ha.notify_standby.clone(),
             });
         }
 
         // ACL
         if let Some(ref acl) = self.acl {
-            jconf.acl = Some(acl.file_path().to_str().unwrap().to_owned());
+            jconf.acl = acl.file_path().and_then(|p| p.to_str()).map(ToOwned::to_owned);
+
+            let sources = acl.rule_sources();
+            if *sources != RuleSources::default() {
+                let non_empty = |v: &Vec<String>| if v.is_empty() { None } else { Some(v.clone()) };
+                jconf.rules = Some(SSRulesConfig {
+                    precedence: if sources.precedence.is_empty() {
+                        None
+                    } else {
+                        Some(sources.precedence.iter().map(|k| k.as_str().to_owned()).collect())
+                    },
+                    bypass_list: non_empty(&sources.bypass_list),
+                    proxy_list: non_empty(&sources.proxy_list),
+                    rule_sets: if sources.rule_sets.is_empty() {
+                        None
+                    } else {
+                        Some(
+                            sources
+                                .rule_sets
+                                .iter()
+                                .map(|rule_set| SSRuleSetConfig {
+                                    path: rule_set.path.to_str().unwrap().to_owned(),
+                                    url: rule_set.url.clone(),
+                                })
+                                .collect(),
+                        )
+                    },
+                    geoip_dir: sources
+                        .geoip_dir
+                        .as_ref()
+                        .and_then(|p| p.to_str().map(ToOwned::to_owned)),
+                });
+            }
         }
 
         // OnlineConfig
@@ -3154,3 +7692,161 @@ pub fn read_variable_field_value(value: &str) -> Cow<'_, str> {
 
     value.into()
 }
+
+/// Maximum number of ports in `server_port_range`, each of them is a TCP and a UDP socket
+const MAX_SERVER_PORT_RANGE_LEN: usize = 1024;
+
+/// Parse ports of `server_port_range` in `start-end`
+fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, Error> {
+    let range = s.split_once('-').and_then(|(start, end)| {
+        let start = start.trim().parse::<u16>().ok()?;
+        let end = end.trim().parse::<u16>().ok()?;
+        if start == 0 || start > end {
+            return None;
+        }
+        Some(start..=end)
+    });
+
+    match range {
+        None => Err(Error::new(
+            ErrorKind::Invalid,
+            "invalid `server_port_range`",
+            Some(format!("{s} should be \"start-end\", 1 <= start <= end")),
+        )),
+        Some(range) if range.len() > MAX_SERVER_PORT_RANGE_LEN => Err(Error::new(
+            ErrorKind::Invalid,
+            "`server_port_range` too large",
+            Some(format!(
+                "{s} has {} ports, at most {MAX_SERVER_PORT_RANGE_LEN} are allowed",
+                range.len()
+            )),
+        )),
+        Some(range) => Ok(range),
+    }
+}
+
+/// Error of unrecognized `protocol`, reports the cargo feature if it is known but not enabled in this build
+fn invalid_protocol_error(p: &str) -> Error {
+    match ProtocolType::missing_feature(p) {
+        Some(feature) => Error::new(
+            ErrorKind::Invalid,
+            "`protocol` not supported",
+            Some(format!(
+                "protocol {p} requires feature \"{feature}\", which is not enabled in this build"
+            )),
+        ),
+        None => Error::new(
+            ErrorKind::Malformed,
+            "`protocol` invalid",
+            Some(format!("unrecognized protocol {p}")),
+        ),
+    }
+}
+
+/// Cargo feature required by configuration key `key`, if it is not enabled in this build
+fn missing_feature_of_key(key: &str) -> Option<&'static str> {
+    let (feature, enabled) = match key {
+        "local_control_address"
+        | "ha"
+        | "socks5_auth_config_path"
+        | "socks5_udp_prebind"
+        | "socks5_udp_external_addresses"
+        | "socks5_username_routing"
+        | "udp_nat"
+        | "system_proxy" => ("local", cfg!(feature = "local")),
+        "online_config" => ("local-online-config", cfg!(feature = "local-online-config")),
+        "local_control_http" | "http_auth_config_path" | "http_pac_path" | "http_pac_acl" => {
+            ("local-http", cfg!(feature = "local-http"))
+        }
+        "history" => ("local-history", cfg!(feature = "local-history")),
+        "manager_http" => ("manager-http", cfg!(feature = "manager-http")),
+        "metrics_address" => ("metrics", cfg!(feature = "metrics")),
+        "file_watch" => ("local-file-watch", cfg!(feature = "local-file-watch")),
+        "tls_cert" | "tls_key" | "tls_client_ca" => ("local-tls", cfg!(feature = "local-tls")),
+        "tcp_redir" | "udp_redir" | "redir_firewall" => ("local-redir", cfg!(feature = "local-redir")),
+        "local_dns_address"
+        | "local_dns_port"
+        | "local_dns_extra_addresses"
+        | "dns_upstream_strategy"
+        | "remote_dns_address"
+        | "remote_dns_port"
+        | "client_cache_size"
+        | "dns_quarantine_failures"
+        | "dns_quarantine_duration"
+        | "dns_cache_size"
+        | "dns_cache_min_ttl"
+        | "dns_cache_max_ttl"
+        | "dns_rules"
+        | "dns_client_subnet"
+        | "dns_acl_strategy"
+        | "dns_block_lists"
+        | "dns_block_response"
+        | "dns_hosts"
+        | "dns_query_log" => ("local-dns", cfg!(feature = "local-dns")),
+        "dns_tls_address" => ("local-dns-server-tls", cfg!(feature = "local-dns-server-tls")),
+        "dns_https_address" | "dns_https_path" => ("local-dns-server-https", cfg!(feature = "local-dns-server-https")),
+        "remote_dns_https" => ("local-dns-over-https", cfg!(feature = "local-dns-over-https")),
+        "local_dns_tls" | "remote_dns_tls" => ("local-dns-over-tls", cfg!(feature = "local-dns-over-tls")),
+        "local_dns_quic" => ("local-dns-over-quic", cfg!(feature = "local-dns-over-quic")),
+        "forward_address" | "forward_port" | "forward_dns_timeout" | "forwards" | "reverse_port" => {
+            ("local-tunnel", cfg!(feature = "local-tunnel"))
+        }
+        "tun_interface_name"
+        | "tun_interface_address"
+        | "tun_interface_destination"
+        | "tun_interface_mtu"
+        | "tun_device_fd_from_path"
+        | "tun_device_fd"
+        | "tun_dscp"
+        | "tun_dns_hijack"
+        | "tun_icmp_echo"
+        | "tun_auto_route"
+        | "tun_offload"
+        | "tun_ipv6" => ("local-tun", cfg!(feature = "local-tun")),
+        "fake_dns_record_expire_duration"
+        | "fake_dns_ipv4_network"
+        | "fake_dns_ipv6_network"
+        | "fake_dns_database_path"
+        | "tun_fake_dns" => ("local-fake-dns", cfg!(feature = "local-fake-dns")),
+        _ => return None,
+    };
+    if enabled {
+        None
+    } else {
+        Some(feature)
+    }
+}
+
+/// Check keys of the local configuration and its `locals`
+///
+/// Keys of disabled features are not parsed, they are reported instead of being ignored silently.
+fn check_missing_features(value: &serde_json::Value) -> Result<(), Error> {
+    let root = match value.as_object() {
+        Some(root) => root,
+        None => return Ok(()),
+    };
+
+    let locals = root
+        .get("locals")
+        .and_then(|locals| locals.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|local| local.as_object());
+
+    for object in std::iter::once(root).chain(locals) {
+        for key in object.keys() {
+            if let Some(feature) = missing_feature_of_key(key) {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "configuration key not supported",
+                    Some(format!(
+                        "`{key}` requires feature \"{feature}\", which is not enabled in this build"
+                    )),
+                );
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -66,6 +66,8 @@ mod dns;
 pub mod local;
 #[cfg(feature = "manager")]
 pub mod manager;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod net;
 #[cfg(feature = "server")]
 pub mod server;
@@ -1,25 +1,48 @@
 //! Shadowsocks Local Server Context
 
-use std::sync::Arc;
 #[cfg(feature = "local-dns")]
-use std::{net::IpAddr, time::Duration};
+use std::net::IpAddr;
+use std::{borrow::Cow, net::SocketAddr, sync::Arc, time::Duration};
 
+use arc_swap::ArcSwap;
+#[cfg(feature = "local-fake-dns")]
+use ipnet::IpNet;
+use log::debug;
 #[cfg(feature = "local-dns")]
 use lru_time_cache::LruCache;
 use shadowsocks::{
     config::ServerType,
     context::{Context, SharedContext},
     dns_resolver::DnsResolver,
-    net::{AcceptOpts, ConnectOpts},
+    net::{AcceptOpts, AddrFamily, ConnectOpts},
     relay::Address,
+    ServerAddr,
 };
 #[cfg(feature = "local-dns")]
 use tokio::sync::Mutex;
 #[cfg(feature = "local-fake-dns")]
 use tokio::sync::RwLock;
 
-use crate::{acl::AccessControl, config::SecurityConfig, net::FlowStat};
+use crate::{
+    acl::AccessControl,
+    config::{SecurityConfig, SpecialTargetPolicy, UdpNatType},
+    net::FlowStat,
+};
+
+use super::{
+    history::{SessionHistory, SessionRecorder},
+    net::{
+        mirror::{FlowMirror, TrafficMirror},
+        process::tcp_process_path,
+        qos::{FlowShaper, TrafficShaper},
+        special_target::{SpecialTarget, SpecialTargetStat},
+        uplink::UplinkBalancer,
+    },
+    utils::FirstPacketStat,
+};
 
+#[cfg(feature = "local-dns")]
+use super::dns::{DnsQueryStat, DnsUpstreamStat};
 #[cfg(feature = "local-fake-dns")]
 use super::fake_dns::manager::FakeDnsManager;
 
@@ -30,15 +53,51 @@ pub struct ServiceContext {
     connect_opts: ConnectOpts,
     accept_opts: AcceptOpts,
 
-    // Access Control
-    acl: Option<Arc<AccessControl>>,
+    // Timeouts of TCP tunnels
+    tcp_idle_timeout: Option<Duration>,
+    tcp_half_close_timeout: Option<Duration>,
+
+    // Clamped MSS of outbound TCP connections
+    tcp_mss: Option<u32>,
+
+    // Filtering of responses of UDP associations
+    udp_nat: UdpNatType,
+
+    // Access Control, shared with cloned contexts, replaced after its files changed
+    acl: Option<Arc<ArcSwap<AccessControl>>>,
 
     // Flow statistic report
     flow_stat: Arc<FlowStat>,
 
-    // For DNS relay's ACL domain name reverse lookup -- whether the IP shall be forwarded
+    // Mirror of plaintext traffic
+    traffic_mirror: Option<Arc<TrafficMirror>>,
+
+    // Bandwidth scheduling of proxied TCP flows
+    traffic_shaper: Option<Arc<TrafficShaper>>,
+
+    // Handling of unspecified, loopback, multicast and broadcast targets
+    special_target_policy: SpecialTargetPolicy,
+    special_target_stat: Arc<SpecialTargetStat>,
+
+    // Coalescing of clients' data into first packets of proxied TCP tunnels
+    first_packet_stat: Arc<FirstPacketStat>,
+
+    // Multi-WAN uplinks of outbound sockets
+    uplinks: Option<Arc<UplinkBalancer>>,
+
+    // Recorder of completed sessions
+    session_history: Option<Arc<SessionHistory>>,
+
+    // For DNS relay's ACL domain name reverse lookup -- the domain name resolved to the IP
     #[cfg(feature = "local-dns")]
-    reverse_lookup_cache: Arc<Mutex<LruCache<IpAddr, bool>>>,
+    reverse_lookup_cache: Arc<Mutex<LruCache<IpAddr, String>>>,
+
+    // Upstreams of all DNS relays
+    #[cfg(feature = "local-dns")]
+    dns_upstream_stats: Arc<Mutex<Vec<Arc<DnsUpstreamStat>>>>,
+    // Queries of all DNS relays
+    #[cfg(feature = "local-dns")]
+    dns_query_stats: Arc<Mutex<Vec<Arc<DnsQueryStat>>>>,
 
     #[cfg(feature = "local-fake-dns")]
     fake_dns_manager: Arc<RwLock<Vec<Arc<FakeDnsManager>>>>,
@@ -57,13 +116,25 @@ impl ServiceContext {
             context: Context::new_shared(ServerType::Local),
             connect_opts: ConnectOpts::default(),
             accept_opts: AcceptOpts::default(),
+            tcp_idle_timeout: None,
+            tcp_half_close_timeout: None,
+            tcp_mss: None,
+            udp_nat: UdpNatType::default(),
             acl: None,
             flow_stat: Arc::new(FlowStat::new()),
+            traffic_mirror: None,
+            traffic_shaper: None,
+            special_target_policy: SpecialTargetPolicy::default(),
+            special_target_stat: Arc::new(SpecialTargetStat::new()),
+            first_packet_stat: Arc::new(FirstPacketStat::new()),
+            uplinks: None,
+            session_history: None,
+            #[cfg(feature = "local-dns")]
+            reverse_lookup_cache: Arc::new(Mutex::new(new_reverse_lookup_cache())),
             #[cfg(feature = "local-dns")]
-            reverse_lookup_cache: Arc::new(Mutex::new(LruCache::with_expiry_duration_and_capacity(
-                Duration::from_secs(3 * 24 * 60 * 60),
-                10240, // XXX: It should be enough for a normal user.
-            ))),
+            dns_upstream_stats: Arc::new(Mutex::new(Vec::new())),
+            #[cfg(feature = "local-dns")]
+            dns_query_stats: Arc::new(Mutex::new(Vec::new())),
             #[cfg(feature = "local-fake-dns")]
             fake_dns_manager: Arc::new(RwLock::new(Vec::new())),
         }
@@ -89,6 +160,24 @@ impl ServiceContext {
         &self.connect_opts
     }
 
+    /// Set uplinks that outbound sockets are balanced across
+    pub fn set_uplinks(&mut self, uplinks: Arc<UplinkBalancer>) {
+        self.uplinks = Some(uplinks);
+    }
+
+    /// Get uplinks reference
+    pub fn uplinks(&self) -> Option<&UplinkBalancer> {
+        self.uplinks.as_deref()
+    }
+
+    /// Bind `opts` of a new outbound socket to an uplink chosen for it, if it is not bound yet
+    pub fn uplink_connect_opts<'a>(&self, opts: &'a ConnectOpts) -> Cow<'a, ConnectOpts> {
+        match self.uplinks {
+            Some(ref uplinks) => uplinks.connect_opts(opts),
+            None => Cow::Borrowed(opts),
+        }
+    }
+
     /// Set `AcceptOpts`
     pub fn set_accept_opts(&mut self, accept_opts: AcceptOpts) {
         self.accept_opts = accept_opts;
@@ -99,14 +188,68 @@ impl ServiceContext {
         self.accept_opts.clone()
     }
 
+    /// Close TCP tunnels that transfer nothing in both directions for `timeout`
+    pub fn set_tcp_idle_timeout(&mut self, timeout: Duration) {
+        self.tcp_idle_timeout = Some(timeout);
+    }
+
+    /// Get idle timeout of TCP tunnels
+    pub fn tcp_idle_timeout(&self) -> Option<Duration> {
+        self.tcp_idle_timeout
+    }
+
+    /// Close TCP tunnels `timeout` after one of their directions is closed, instead of keeping them half-closed
+    pub fn set_tcp_half_close_timeout(&mut self, timeout: Duration) {
+        self.tcp_half_close_timeout = Some(timeout);
+    }
+
+    /// Get half-close timeout of TCP tunnels
+    pub fn tcp_half_close_timeout(&self) -> Option<Duration> {
+        self.tcp_half_close_timeout
+    }
+
+    /// Clamp MSS of outbound TCP connections, to servers and bypassed targets, for overhead of encapsulation
+    pub fn set_tcp_mss(&mut self, mss: u32) {
+        self.tcp_mss = Some(mss);
+    }
+
+    /// `opts` with the clamped MSS of outbound TCP connections
+    pub fn clamp_tcp_mss<'a>(&self, opts: &'a ConnectOpts) -> Cow<'a, ConnectOpts> {
+        match self.tcp_mss {
+            Some(mss) if opts.tcp.mss.map_or(true, |m| m > mss) => {
+                let mut opts = opts.clone();
+                opts.tcp.mss = Some(mss);
+                Cow::Owned(opts)
+            }
+            _ => Cow::Borrowed(opts),
+        }
+    }
+
+    /// Set NAT behavior of UDP associations
+    pub fn set_udp_nat(&mut self, udp_nat: UdpNatType) {
+        self.udp_nat = udp_nat;
+    }
+
+    /// Get NAT behavior of UDP associations
+    pub fn udp_nat(&self) -> UdpNatType {
+        self.udp_nat
+    }
+
     /// Set Access Control List
     pub fn set_acl(&mut self, acl: Arc<AccessControl>) {
-        self.acl = Some(acl);
+        self.acl = Some(Arc::new(ArcSwap::new(acl)));
     }
 
-    /// Get Access Control List reference
-    pub fn acl(&self) -> Option<&AccessControl> {
-        self.acl.as_deref()
+    /// Get Access Control List
+    pub fn acl(&self) -> Option<Arc<AccessControl>> {
+        self.acl.as_ref().map(|acl| acl.load_full())
+    }
+
+    /// Replace Access Control List, which is also replaced in contexts cloned after `set_acl`
+    pub fn replace_acl(&self, acl: Arc<AccessControl>) {
+        if let Some(ref current) = self.acl {
+            current.store(acl);
+        }
     }
 
     /// Get cloned flow statistic
@@ -119,6 +262,83 @@ impl ServiceContext {
         self.flow_stat.as_ref()
     }
 
+    /// Set traffic mirror
+    pub fn set_traffic_mirror(&mut self, mirror: Arc<TrafficMirror>) {
+        self.traffic_mirror = Some(mirror);
+    }
+
+    /// Start mirroring a TCP flow, if target is in ACL's `[mirror_list]`
+    pub fn start_tcp_mirror(&self, src_addr: SocketAddr, addr: &Address) -> Option<FlowMirror> {
+        match (&self.traffic_mirror, &self.acl) {
+            (Some(mirror), Some(acl)) if acl.load().check_target_mirrored(addr) => {
+                Some(mirror.start_tcp_flow(src_addr, addr))
+            }
+            _ => None,
+        }
+    }
+
+    /// Set traffic shaper
+    pub fn set_traffic_shaper(&mut self, shaper: Arc<TrafficShaper>) {
+        self.traffic_shaper = Some(shaper);
+    }
+
+    /// Start scheduling a proxied TCP flow, if QoS is configured
+    pub fn start_tcp_shaping(&self) -> Option<FlowShaper> {
+        self.traffic_shaper.as_ref().map(TrafficShaper::start_tcp_flow)
+    }
+
+    /// Set recorder of completed sessions
+    pub fn set_session_history(&mut self, history: Arc<SessionHistory>) {
+        self.session_history = Some(history);
+    }
+
+    /// Start recording a TCP session to `addr`, proxied through `server`, if session history is enabled
+    pub fn start_tcp_session(&self, addr: &Address, server: Option<&ServerAddr>) -> Option<SessionRecorder> {
+        self.session_history
+            .as_ref()
+            .map(|history| history.start_tcp_session(addr, server))
+    }
+
+    /// Get `TCP_NODELAY` of connections to `addr` by the traffic class in ACL, `None` if not classified
+    pub fn target_nodelay(&self, addr: &Address) -> Option<bool> {
+        self.acl.as_ref().and_then(|acl| acl.load().check_target_nodelay(addr))
+    }
+
+    /// Set policy of special targets
+    pub fn set_special_target_policy(&mut self, policy: SpecialTargetPolicy) {
+        self.special_target_policy = policy;
+    }
+
+    /// Get policy of special targets
+    pub fn special_target_policy(&self) -> SpecialTargetPolicy {
+        self.special_target_policy
+    }
+
+    /// Get statistic of special targets
+    pub fn special_target_stat(&self) -> &SpecialTargetStat {
+        &self.special_target_stat
+    }
+
+    /// Get statistic of first packets of proxied TCP tunnels
+    pub fn first_packet_stat(&self) -> &FirstPacketStat {
+        &self.first_packet_stat
+    }
+
+    /// Check if `addr` is a special target that is handled by the policy instead of ACL, and count it
+    pub fn check_special_target(&self, addr: &Address, udp: bool) -> Option<(SpecialTarget, SpecialTargetPolicy)> {
+        if self.special_target_policy == SpecialTargetPolicy::Acl {
+            return None;
+        }
+
+        let target = SpecialTarget::classify(addr)?;
+        if udp {
+            self.special_target_stat.incr_udp(target);
+        } else {
+            self.special_target_stat.incr_tcp(target);
+        }
+        Some((target, self.special_target_policy))
+    }
+
     /// Set customized DNS resolver
     pub fn set_dns_resolver(&mut self, resolver: Arc<DnsResolver>) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set DNS resolver on a shared context");
@@ -132,17 +352,19 @@ impl ServiceContext {
 
     /// Check if target should be bypassed
     pub async fn check_target_bypassed(&self, addr: &Address) -> bool {
-        match self.acl {
+        match self.acl() {
             None => false,
-            Some(ref acl) => {
+            Some(acl) => {
                 #[cfg(feature = "local-dns")]
                 {
                     if let Address::SocketAddress(ref saddr) = addr {
                         // do the reverse lookup in our local cache
                         let mut reverse_lookup_cache = self.reverse_lookup_cache.lock().await;
-                        // if a qname is found
-                        if let Some(forward) = reverse_lookup_cache.get(&saddr.ip()) {
-                            return !*forward;
+                        // if a qname is found, it is checked by rules of this context's ACL
+                        if let Some(name) = reverse_lookup_cache.get(&saddr.ip()) {
+                            if let Some(proxied) = acl.check_ascii_host_in_proxy_list(name) {
+                                return !proxied;
+                            }
                         }
                     }
                 }
@@ -152,39 +374,92 @@ impl ServiceContext {
         }
     }
 
-    /// Add a record to the reverse lookup cache
+    /// Check if TCP connections from local address `peer_addr` should be bypassed, by process rules of its owner
+    ///
+    /// `None` if there are no process rules, or the owner is not found or matches no rules.
+    pub async fn check_process_bypassed(&self, peer_addr: SocketAddr) -> Option<bool> {
+        let acl = self.acl()?;
+        if acl.is_process_empty() {
+            return None;
+        }
+
+        match tcp_process_path(peer_addr).await {
+            Ok(path) => acl.check_process_in_proxy_list(&path?).map(|proxied| !proxied),
+            Err(err) => {
+                debug!(
+                    "failed to find process of tcp connection from {}, error: {}",
+                    peer_addr, err
+                );
+                None
+            }
+        }
+    }
+
+    /// Check if target should be bypassed, by rules of domain name `host` sniffed from the connection first
+    ///
+    /// Rules of `addr` are checked if no domain name rule matches `host`.
+    pub async fn check_sniffed_target_bypassed(&self, host: &str, addr: &Address) -> bool {
+        if let Some(acl) = self.acl() {
+            if let Some(proxied) = acl.check_host_in_proxy_list(host) {
+                return !proxied;
+            }
+        }
+        self.check_target_bypassed(addr).await
+    }
+
+    /// Add a record to the reverse lookup cache, `name` is a lowercase ASCII domain name that `addr` is resolved from
+    ///
+    /// The cache is shared by all local servers, each of them checks `name` by rules of its own ACL.
     #[cfg(feature = "local-dns")]
-    pub async fn add_to_reverse_lookup_cache(&self, addr: IpAddr, forward: bool) {
-        let is_exception = forward
-            != match self.acl {
-                // Proxy everything by default
-                None => true,
-                Some(ref a) => a.check_ip_in_proxy_list(&addr),
-            };
+    pub async fn add_to_reverse_lookup_cache(&self, addr: IpAddr, name: &str) {
         let mut reverse_lookup_cache = self.reverse_lookup_cache.lock().await;
         match reverse_lookup_cache.get_mut(&addr) {
-            Some(value) => {
-                if is_exception {
-                    *value = forward;
-                } else {
-                    // we do not need to remember the entry if it is already matched correctly
-                    reverse_lookup_cache.remove(&addr);
-                }
-            }
+            Some(value) if value == name => {}
+            Some(value) => name.clone_into(value),
             None => {
-                if is_exception {
-                    reverse_lookup_cache.insert(addr, forward);
-                }
+                reverse_lookup_cache.insert(addr, name.to_owned());
             }
         }
     }
 
+    /// Add a DNS relay's upstream statistic
+    #[cfg(feature = "local-dns")]
+    pub async fn add_dns_upstream_stat(&self, stat: Arc<DnsUpstreamStat>) {
+        let mut stats = self.dns_upstream_stats.lock().await;
+        stats.push(stat);
+    }
+
+    /// Get statistic of all DNS relays' upstreams
+    #[cfg(feature = "local-dns")]
+    pub async fn dns_upstream_stats(&self) -> Vec<Arc<DnsUpstreamStat>> {
+        self.dns_upstream_stats.lock().await.clone()
+    }
+
+    /// Add a DNS relay's query statistic
+    #[cfg(feature = "local-dns")]
+    pub async fn add_dns_query_stat(&self, stat: Arc<DnsQueryStat>) {
+        let mut stats = self.dns_query_stats.lock().await;
+        stats.push(stat);
+    }
+
+    /// Get query statistic of all DNS relays
+    #[cfg(feature = "local-dns")]
+    pub async fn dns_query_stats(&self) -> Vec<Arc<DnsQueryStat>> {
+        self.dns_query_stats.lock().await.clone()
+    }
+
     /// Try to connect IPv6 addresses first if hostname could be resolved to both IPv4 and IPv6
     pub fn set_ipv6_first(&mut self, ipv6_first: bool) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set ipv6_first on a shared context");
         context.set_ipv6_first(ipv6_first);
     }
 
+    /// Only connect addresses of `family`, the other family is disabled
+    pub fn set_addr_family(&mut self, family: AddrFamily) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set addr_family on a shared context");
+        context.set_addr_family(family);
+    }
+
     /// Set security config
     pub fn set_security_config(&mut self, security: &SecurityConfig) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set security on a shared context");
@@ -198,6 +473,14 @@ impl ServiceContext {
         managers.push(manager);
     }
 
+    /// Exclude `networks` from addresses of all fake DNS managers
+    #[cfg(feature = "local-fake-dns")]
+    pub async fn set_fake_dns_excluded_networks(&self, networks: Vec<IpNet>) {
+        for mgr in self.fake_dns_manager.read().await.iter() {
+            mgr.set_excluded_networks(networks.clone());
+        }
+    }
+
     /// Fake DNS maps IP to Domain
     #[cfg(feature = "local-fake-dns")]
     pub async fn try_map_fake_address(&self, addr: &Address) -> Option<Address> {
@@ -218,3 +501,11 @@ impl ServiceContext {
         None
     }
 }
+
+#[cfg(feature = "local-dns")]
+fn new_reverse_lookup_cache() -> LruCache<IpAddr, String> {
+    LruCache::with_expiry_duration_and_capacity(
+        Duration::from_secs(3 * 24 * 60 * 60),
+        10240, // XXX: It should be enough for a normal user.
+    )
+}
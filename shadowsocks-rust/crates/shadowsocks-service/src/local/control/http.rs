@@ -0,0 +1,290 @@
+//! Control API over HTTP(S)
+//!
+//! Serves the same commands of the control socket, for managing gateways remotely:
+//!
+//! ```plain
+//! POST /control HTTP/1.1
+//! Authorization: Bearer <token>
+//!
+//! stats
+//! ```
+//!
+//! Responds the result of the command in plain text, with status 400 if it is `err: <reason>`.
+//! Clients that are not in `allowed_ips` are disconnected before TLS handshake.
+//! The TLS certificate could be replaced without restarting, if its files are watched.
+
+use std::{
+    convert::Infallible,
+    io,
+    net::{IpAddr, SocketAddr},
+    str,
+    sync::Arc,
+    time::Duration,
+};
+
+#[cfg(feature = "local-http-rustls")]
+use arc_swap::ArcSwap;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{self, HeaderValue},
+    server::conn::http1,
+    service, Method, Request, Response, StatusCode,
+};
+use ipnet::IpNet;
+use log::{debug, error, info, trace, warn};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    time,
+};
+#[cfg(feature = "local-http-rustls")]
+use tokio_rustls::TlsAcceptor;
+
+#[cfg(all(feature = "local-http-rustls", feature = "local-file-watch"))]
+use crate::local::file_watch::FileWatcher;
+#[cfg(feature = "local-http-rustls")]
+use crate::local::net::tls::load_tls_acceptor;
+use crate::{config::ControlHttpConfig, local::http::TokioIo, net::utils::constant_time_eq};
+
+use super::ControlHandler;
+
+/// Path of the control API
+const CONTROL_HTTP_PATH: &str = "/control";
+
+/// Maximum size of a command
+const CONTROL_HTTP_MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// ControlHttpServer builder
+pub struct ControlHttpServerBuilder {
+    config: ControlHttpConfig,
+    handler: ControlHandler,
+}
+
+impl ControlHttpServerBuilder {
+    /// Create a Builder serving commands with `handler`
+    pub fn new(config: ControlHttpConfig, handler: ControlHandler) -> ControlHttpServerBuilder {
+        ControlHttpServerBuilder { config, handler }
+    }
+
+    /// Load TLS certificate and bind the listener
+    pub async fn build(self) -> io::Result<ControlHttpServer> {
+        #[cfg(feature = "local-http-rustls")]
+        let tls = match (self.config.tls_cert, self.config.tls_key) {
+            (Some(cert), Some(key)) => {
+                let acceptor = load_tls_acceptor(&cert, &key, None)?;
+                Some(ControlTls {
+                    cert,
+                    key,
+                    acceptor: Arc::new(ArcSwap::from_pointee(acceptor)),
+                })
+            }
+            _ => None,
+        };
+
+        let listener = TcpListener::bind(self.config.listen_addr).await?;
+
+        Ok(ControlHttpServer {
+            listener,
+            allowed_ips: self.config.allowed_ips,
+            service: Arc::new(ControlHttpService {
+                handler: self.handler,
+                token: self.config.token,
+            }),
+            #[cfg(feature = "local-http-rustls")]
+            tls,
+        })
+    }
+}
+
+/// TLS certificate of the control API, replaced after it is reloaded
+#[cfg(feature = "local-http-rustls")]
+#[cfg_attr(not(feature = "local-file-watch"), allow(dead_code))]
+#[derive(Clone)]
+struct ControlTls {
+    cert: std::path::PathBuf,
+    key: std::path::PathBuf,
+    acceptor: Arc<ArcSwap<TlsAcceptor>>,
+}
+
+/// Control API server over HTTP(S)
+pub struct ControlHttpServer {
+    listener: TcpListener,
+    allowed_ips: Vec<IpNet>,
+    service: Arc<ControlHttpService>,
+    #[cfg(feature = "local-http-rustls")]
+    tls: Option<ControlTls>,
+}
+
+impl ControlHttpServer {
+    /// Listen address
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Reload TLS certificate and key after any of them changed
+    #[cfg(all(feature = "local-http-rustls", feature = "local-file-watch"))]
+    pub fn watch_tls_files(&self, watcher: &mut FileWatcher) {
+        if let Some(ref tls) = self.tls {
+            let tls = tls.clone();
+            let paths = vec![tls.cert.clone(), tls.key.clone()];
+            watcher.watch("local_control_http TLS certificate", paths, move || {
+                let acceptor = load_tls_acceptor(&tls.cert, &tls.key, None)?;
+                tls.acceptor.store(Arc::new(acceptor));
+                Ok(())
+            });
+        }
+    }
+
+    /// Start serving
+    pub async fn run(self) -> io::Result<()> {
+        #[cfg(feature = "local-http-rustls")]
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        #[cfg(not(feature = "local-http-rustls"))]
+        let scheme = "http";
+        info!(
+            "shadowsocks local control listening on {}://{}{}",
+            scheme,
+            self.listener.local_addr()?,
+            CONTROL_HTTP_PATH
+        );
+
+        loop {
+            let (stream, peer_addr) = match self.listener.accept().await {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("local control http accept failed with error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            if !self.is_allowed(peer_addr.ip()) {
+                warn!("local control http rejected {}, not in allowed_ips", peer_addr);
+                continue;
+            }
+
+            trace!("local control http accepted client {}", peer_addr);
+
+            let service = self.service.clone();
+            #[cfg(feature = "local-http-rustls")]
+            if let Some(ref tls) = self.tls {
+                let tls_acceptor = TlsAcceptor::clone(&tls.acceptor.load());
+                tokio::spawn(async move {
+                    match tls_acceptor.accept(stream).await {
+                        Ok(stream) => service.serve_connection(stream, peer_addr).await,
+                        Err(err) => debug!(
+                            "local control http TLS handshake with {} failed, error: {}",
+                            peer_addr, err
+                        ),
+                    }
+                });
+                continue;
+            }
+
+            tokio::spawn(async move { service.serve_connection(stream, peer_addr).await });
+        }
+    }
+
+    fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.allowed_ips.is_empty() {
+            return true;
+        }
+
+        // IPv4 clients of dual-stack listeners
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        self.allowed_ips.iter().any(|net| net.contains(&ip))
+    }
+}
+
+struct ControlHttpService {
+    handler: ControlHandler,
+    token: String,
+}
+
+impl ControlHttpService {
+    async fn serve_connection<S>(self: Arc<Self>, stream: S, peer_addr: SocketAddr)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let result = http1::Builder::new()
+            .serve_connection(
+                TokioIo::new(stream),
+                service::service_fn(move |req| {
+                    let service = self.clone();
+                    async move { Ok::<_, Infallible>(service.serve_request(req, peer_addr).await) }
+                }),
+            )
+            .await;
+
+        if let Err(err) = result {
+            debug!("local control http connection {} failed, error: {}", peer_addr, err);
+        }
+    }
+
+    async fn serve_request(&self, req: Request<Incoming>, peer_addr: SocketAddr) -> Response<Full<Bytes>> {
+        if req.uri().path() != CONTROL_HTTP_PATH {
+            return make_response(StatusCode::NOT_FOUND, "err: not found");
+        }
+        if req.method() != Method::POST {
+            return make_response(StatusCode::METHOD_NOT_ALLOWED, "err: method not allowed");
+        }
+        if !self.check_token(&req) {
+            warn!("local control http rejected {}, invalid token", peer_addr);
+
+            let mut rsp = make_response(StatusCode::UNAUTHORIZED, "err: unauthorized");
+            rsp.headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            return rsp;
+        }
+
+        let body = match Limited::new(req.into_body(), CONTROL_HTTP_MAX_BODY_SIZE)
+            .collect()
+            .await
+        {
+            Ok(b) => b.to_bytes(),
+            Err(..) => return make_response(StatusCode::PAYLOAD_TOO_LARGE, "err: request is too large"),
+        };
+        let cmd = match str::from_utf8(&body) {
+            Ok(c) => c.trim(),
+            Err(..) => return make_response(StatusCode::BAD_REQUEST, "err: request is not UTF-8"),
+        };
+
+        trace!("local control http received {:?} from {}", cmd, peer_addr);
+
+        let rsp = self.handler.handle_request(cmd).await;
+        let status = if rsp.starts_with("err:") {
+            StatusCode::BAD_REQUEST
+        } else {
+            StatusCode::OK
+        };
+        make_response(status, rsp)
+    }
+
+    fn check_token(&self, req: &Request<Incoming>) -> bool {
+        let token = match req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+        {
+            Some(t) => t.trim(),
+            None => return false,
+        };
+
+        constant_time_eq(token.as_bytes(), self.token.as_bytes())
+    }
+}
+
+fn make_response<B: Into<Bytes>>(status: StatusCode, body: B) -> Response<Full<Bytes>> {
+    let mut rsp = Response::new(Full::new(body.into()));
+    *rsp.status_mut() = status;
+    rsp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    rsp
+}
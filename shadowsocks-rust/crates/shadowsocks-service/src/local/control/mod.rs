@@ -0,0 +1,639 @@
+//! Local control socket
+//!
+//! Accepts plain text commands in datagrams, one command per datagram:
+//!
+//! - `add: ss://...` - Add a server (SIP002 URL) with source `Api`, replacing the one with the same address
+//! - `remove: host:port` - Remove a server that was added by `add`
+//! - `list` - List all servers in the load balancer, one server per line
+//! - `stats` - Dump health statistic of all servers in the load balancer, in JSON
+//! - `pin: host:port` - Choose the server for all new sessions, bypassing scoring
+//! - `unpin` - Choose servers by scoring again
+//! - `exclude: host:port` - Stop choosing the server for new sessions until it is included again
+//! - `include: host:port` - Include an excluded server back
+//! - `dns` - Dump health statistic of DNS relays' upstreams, in JSON (feature = "local-dns")
+//! - `dns queries` - Dump counters of DNS relays' queries, in JSON (feature = "local-dns")
+//! - `rules explain` - Dump proxy / bypass rule sources in the effective precedence order, in JSON
+//! - `rules explain: host or IP` - Also explain which source decides the target, without DNS resolution
+//! - `uplinks` - Dump state of multi-WAN uplinks, in JSON
+//! - `targets` - Dump the policy of special targets, and counters of special targets handled by it, in JSON
+//! - `refresh` - Update servers from `online_config` now
+//! - `ha` - Dump state of this instance and its peer of the active / standby pair, in JSON
+//! - `ping` - Responds `pong`
+//!
+//! Responds `ok` or `err: <reason>` for commands that modify servers. Pinned and excluded servers are not saved,
+//! they are reset when the local service restarts.
+//!
+//! The same commands are also served over HTTP(S) by [`ControlHttpServer`] (feature = "local-http").
+
+use std::{collections::BTreeMap, fmt::Write, io, str, sync::Arc};
+
+use log::{error, info, trace, warn};
+use serde::Serialize;
+use shadowsocks::{
+    config::{ManagerAddr, ServerAddr, ServerConfig, ServerSource},
+    manager::datagram::{ManagerDatagram, ManagerSocketAddr},
+    relay::udprelay::MAXIMUM_UDP_PAYLOAD_SIZE,
+};
+use tokio::sync::Notify;
+
+use crate::{
+    config::ServerInstanceConfig,
+    local::{
+        context::ServiceContext,
+        ha::HaStatus,
+        loadbalancing::{
+            server_stat::{Score, LATENCY_HISTOGRAM_BOUNDS},
+            PingBalancer, ServerScore,
+        },
+        net::special_target::SpecialTarget,
+    },
+};
+
+#[cfg(feature = "local-http")]
+pub use self::http::{ControlHttpServer, ControlHttpServerBuilder};
+
+#[cfg(feature = "local-http")]
+mod http;
+
+/// Number of the latest checks' RTT in `stats`
+const STATS_RTT_SAMPLES: usize = 10;
+
+#[derive(Serialize)]
+struct ServerStats {
+    address: String,
+    remarks: Option<String>,
+    source: String,
+    tier: u32,
+    group: Option<String>,
+    pinned: bool,
+    excluded: bool,
+    /// Bytes sent to the server
+    tx: u64,
+    /// Bytes received from the server
+    rx: u64,
+    tcp: ServerScoreStats,
+    udp: ServerScoreStats,
+}
+
+#[derive(Serialize)]
+struct ServerScoreStats {
+    score: u32,
+    latency: u32,
+    fail_rate: f64,
+    down: bool,
+    active_sessions: usize,
+    /// RTT of the latest checks in milliseconds, from the oldest to the newest. `null` for failed checks
+    rtt_samples: Vec<Option<u32>>,
+    /// Percentiles of succeeded checks' RTT in the check window, in milliseconds. `null` if no check succeeded
+    rtt_p50: Option<u32>,
+    rtt_p95: Option<u32>,
+    rtt_p99: Option<u32>,
+    rtt_histogram: RttHistogramStats,
+    /// Average packet loss and jitter (in milliseconds) of UDP packet probes in the check window
+    packet_loss: f64,
+    jitter: u32,
+}
+
+#[derive(Serialize)]
+struct RttHistogramStats {
+    /// Upper bounds of buckets in milliseconds, RTT higher than the last bound is counted in an extra bucket
+    bounds: &'static [u32],
+    counts: Vec<u32>,
+}
+
+#[cfg(feature = "local-dns")]
+#[derive(Serialize)]
+struct DnsUpstreamStats {
+    kind: &'static str,
+    address: String,
+    queries: u64,
+    failures: u64,
+    consecutive_failures: u32,
+    success_rate: f64,
+    /// Average latency of successful queries in milliseconds
+    latency: u32,
+    quarantined: bool,
+    /// Remaining quarantine time in seconds
+    quarantine_remaining: u64,
+    quarantines: u64,
+}
+
+#[cfg(feature = "local-dns")]
+#[derive(Serialize)]
+struct DnsQueryStats {
+    bind_address: String,
+    queries: u64,
+    cache_hits: u64,
+    /// Answered by block lists or static hosts
+    filtered: u64,
+    /// Answered with NODATA by rules dropping answers
+    dropped: u64,
+    /// Answered by upstreams, including cached answers
+    local: u64,
+    remote: u64,
+    name_server: u64,
+    /// Responses by response code
+    noerror: u64,
+    nxdomain: u64,
+    servfail: u64,
+    other_rcodes: u64,
+}
+
+#[derive(Serialize)]
+struct RulesExplanation {
+    /// Action of targets that match no rules, `proxy` or `bypass`
+    default: &'static str,
+    /// Rule sources, earlier sources take precedence
+    sources: Vec<RuleSourceStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<RuleTargetExplanation>,
+}
+
+#[derive(Serialize)]
+struct RuleSourceStats {
+    kind: &'static str,
+    name: String,
+    proxy_ips: usize,
+    proxy_hosts: usize,
+    bypass_ips: usize,
+    bypass_hosts: usize,
+}
+
+#[derive(Serialize)]
+struct RuleTargetExplanation {
+    target: String,
+    action: &'static str,
+    /// The source that decided, `null` if the default decided
+    source: Option<String>,
+    /// Domain name matched no rules and may be decided by IP rules after resolution
+    resolve_required: bool,
+}
+
+#[derive(Serialize)]
+struct UplinkStats {
+    name: String,
+    weight: u32,
+    down: bool,
+    /// Outbound sockets created through the uplink
+    connections: usize,
+    /// Failed connects through the uplink
+    failures: usize,
+}
+
+#[derive(Serialize)]
+struct SpecialTargetStats {
+    policy: String,
+    /// TCP connections by kind of special target
+    tcp: BTreeMap<&'static str, usize>,
+    /// UDP packets by kind of special target
+    udp: BTreeMap<&'static str, usize>,
+}
+
+fn rule_action(proxied: bool) -> &'static str {
+    if proxied {
+        "proxy"
+    } else {
+        "bypass"
+    }
+}
+
+impl ServerScoreStats {
+    async fn new(score: &ServerScore) -> ServerScoreStats {
+        let rtt_samples = score
+            .recent_scores(STATS_RTT_SAMPLES)
+            .await
+            .into_iter()
+            .map(|s| match s {
+                Score::Latency(lat) => Some(lat),
+                Score::Errored => None,
+            })
+            .collect();
+        let histogram = score.latency_histogram().await;
+        let stat_data = score.stat_data().await;
+
+        ServerScoreStats {
+            score: score.score(),
+            latency: score.latency(),
+            fail_rate: stat_data.fail_rate,
+            down: score.is_down(),
+            active_sessions: score.active_sessions(),
+            rtt_samples,
+            rtt_p50: histogram.percentile(0.50),
+            rtt_p95: histogram.percentile(0.95),
+            rtt_p99: histogram.percentile(0.99),
+            rtt_histogram: RttHistogramStats {
+                bounds: &LATENCY_HISTOGRAM_BOUNDS,
+                counts: histogram.counts().to_vec(),
+            },
+            packet_loss: stat_data.packet_loss,
+            jitter: stat_data.latency_jitter,
+        }
+    }
+}
+
+/// ControlServer builder
+pub struct ControlServerBuilder {
+    bind_addr: ManagerAddr,
+    handler: ControlHandler,
+}
+
+impl ControlServerBuilder {
+    /// Create a Builder
+    pub fn new(context: Arc<ServiceContext>, bind_addr: ManagerAddr, balancer: PingBalancer) -> ControlServerBuilder {
+        ControlServerBuilder {
+            bind_addr,
+            handler: ControlHandler::new(context, balancer),
+        }
+    }
+
+    /// Set the notifier of updating `online_config`, for the `refresh` command
+    pub fn set_online_config_refresh(&mut self, refresh: Arc<Notify>) {
+        self.handler.set_online_config_refresh(refresh);
+    }
+
+    /// Set state of the active / standby pair, for the `ha` command
+    pub fn set_ha_status(&mut self, status: Arc<HaStatus>) {
+        self.handler.set_ha_status(status);
+    }
+
+    /// Build and bind the control socket
+    pub async fn build(self) -> io::Result<ControlServer> {
+        let listener = ManagerDatagram::bind(self.handler.context.context_ref(), &self.bind_addr).await?;
+        Ok(ControlServer {
+            listener,
+            handler: self.handler,
+        })
+    }
+}
+
+/// Local control socket server
+pub struct ControlServer {
+    listener: ManagerDatagram,
+    handler: ControlHandler,
+}
+
+impl ControlServer {
+    /// Control socket's listen address
+    pub fn local_addr(&self) -> io::Result<ManagerSocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Start serving
+    pub async fn run(self) -> io::Result<()> {
+        let local_addr = self.listener.local_addr()?;
+        info!("shadowsocks local control socket listening on {:?}", local_addr);
+
+        let mut buffer = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        loop {
+            let (n, peer_addr) = match self.listener.recv_from(&mut buffer).await {
+                Ok(r) => r,
+                Err(err) => {
+                    error!("local control recv_from error: {}", err);
+                    continue;
+                }
+            };
+
+            let rsp = match str::from_utf8(&buffer[..n]) {
+                Ok(req) => {
+                    trace!("local control received {:?} from {:?}", req, peer_addr);
+                    self.handler.handle_request(req.trim()).await
+                }
+                Err(..) => "err: request is not UTF-8".to_owned(),
+            };
+
+            if peer_addr.is_unnamed() {
+                continue;
+            }
+
+            if let Err(err) = self.listener.send_to(rsp.as_bytes(), &peer_addr).await {
+                warn!("local control send_to {:?} failed, error: {}", peer_addr, err);
+            }
+        }
+    }
+}
+
+/// Handler of control commands, shared by the control socket and HTTP server
+#[derive(Clone)]
+pub struct ControlHandler {
+    context: Arc<ServiceContext>,
+    balancer: PingBalancer,
+    online_config_refresh: Option<Arc<Notify>>,
+    ha_status: Option<Arc<HaStatus>>,
+}
+
+impl ControlHandler {
+    /// Create a handler managing servers in `balancer`
+    pub fn new(context: Arc<ServiceContext>, balancer: PingBalancer) -> ControlHandler {
+        ControlHandler {
+            context,
+            balancer,
+            online_config_refresh: None,
+            ha_status: None,
+        }
+    }
+
+    /// Set the notifier of updating `online_config`, for the `refresh` command
+    pub fn set_online_config_refresh(&mut self, refresh: Arc<Notify>) {
+        self.online_config_refresh = Some(refresh);
+    }
+
+    /// Set state of the active / standby pair, for the `ha` command
+    pub fn set_ha_status(&mut self, status: Arc<HaStatus>) {
+        self.ha_status = Some(status);
+    }
+
+    /// Handle a command, returns the response
+    pub async fn handle_request(&self, req: &str) -> String {
+        let (cmd, param) = match req.split_once(':') {
+            Some((cmd, param)) => (cmd.trim(), Some(param.trim())),
+            None => (req, None),
+        };
+
+        match (cmd, param) {
+            ("add", Some(url)) => self.handle_add(url).await,
+            ("remove", Some(addr)) => self.handle_remove(addr).await,
+            ("list", None) => self.handle_list(),
+            ("stats", None) => self.handle_stats().await,
+            ("pin", Some(addr)) => self.handle_pin(Some(addr)).await,
+            ("unpin", None) => self.handle_pin(None).await,
+            ("exclude", Some(addr)) => self.handle_exclude(addr, true).await,
+            ("include", Some(addr)) => self.handle_exclude(addr, false).await,
+            #[cfg(feature = "local-dns")]
+            ("dns", None) => self.handle_dns().await,
+            #[cfg(feature = "local-dns")]
+            ("dns queries", None) => self.handle_dns_queries().await,
+            ("rules explain", target) => self.handle_rules_explain(target),
+            ("uplinks", None) => self.handle_uplinks(),
+            ("targets", None) => self.handle_targets(),
+            ("refresh", None) => self.handle_refresh(),
+            ("ha", None) => match self.ha_status {
+                Some(ref status) => status.to_json(),
+                None => "err: instance is not paired by ha".to_owned(),
+            },
+            ("ping", None) => "pong".to_owned(),
+            _ => format!("err: invalid command {req:?}"),
+        }
+    }
+
+    async fn handle_add(&self, url: &str) -> String {
+        let mut svr_cfg = match ServerConfig::from_url(url) {
+            Ok(c) => c,
+            Err(err) => return format!("err: invalid server url, {err}"),
+        };
+        svr_cfg.set_source(ServerSource::Api);
+
+        let addr = svr_cfg.addr().clone();
+        match self
+            .balancer
+            .add_server(ServerInstanceConfig::with_server_config(svr_cfg))
+            .await
+        {
+            Ok(..) => {
+                info!("local control added server {}", addr);
+                "ok".to_owned()
+            }
+            Err(err) => format!("err: {err}"),
+        }
+    }
+
+    async fn handle_remove(&self, addr: &str) -> String {
+        let addr = match addr.parse::<ServerAddr>() {
+            Ok(a) => a,
+            Err(..) => return format!("err: invalid server address {addr:?}"),
+        };
+
+        match self.balancer.remove_server(&addr, ServerSource::Api).await {
+            Ok(true) => {
+                info!("local control removed server {}", addr);
+                "ok".to_owned()
+            }
+            Ok(false) => "err: server not found".to_owned(),
+            Err(err) => format!("err: {err}"),
+        }
+    }
+
+    async fn handle_pin(&self, addr: Option<&str>) -> String {
+        let addr = match addr {
+            None => None,
+            Some(addr) => match addr.parse::<ServerAddr>() {
+                Ok(a) => Some(a),
+                Err(..) => return format!("err: invalid server address {addr:?}"),
+            },
+        };
+
+        if self.balancer.pin_server(addr.clone()).await {
+            match addr {
+                Some(addr) => info!("local control pinned server {}", addr),
+                None => info!("local control unpinned server"),
+            }
+            "ok".to_owned()
+        } else {
+            "err: server not found".to_owned()
+        }
+    }
+
+    async fn handle_exclude(&self, addr: &str, excluded: bool) -> String {
+        let addr = match addr.parse::<ServerAddr>() {
+            Ok(a) => a,
+            Err(..) => return format!("err: invalid server address {addr:?}"),
+        };
+
+        if self.balancer.set_server_excluded(&addr, excluded).await {
+            if excluded {
+                info!("local control excluded server {}", addr);
+            } else {
+                info!("local control included server {}", addr);
+            }
+            "ok".to_owned()
+        } else {
+            "err: server not found".to_owned()
+        }
+    }
+
+    fn handle_refresh(&self) -> String {
+        match self.online_config_refresh {
+            Some(ref refresh) => {
+                info!("local control requested updating online_config");
+                refresh.notify_one();
+                "ok".to_owned()
+            }
+            None => "err: online_config is not configured".to_owned(),
+        }
+    }
+
+    fn handle_list(&self) -> String {
+        let mut rsp = String::new();
+        for server in self.balancer.servers() {
+            let svr_cfg = server.server_config();
+            let _ = writeln!(
+                rsp,
+                "{}\t{:?}\t{}",
+                svr_cfg.addr(),
+                svr_cfg.source(),
+                svr_cfg.remarks().unwrap_or_default()
+            );
+        }
+        rsp
+    }
+
+    async fn handle_stats(&self) -> String {
+        let mut servers = Vec::new();
+        for server in self.balancer.servers() {
+            let svr_cfg = server.server_config();
+            let flow_stat = server.flow_stat_ref();
+            servers.push(ServerStats {
+                address: svr_cfg.addr().to_string(),
+                remarks: svr_cfg.remarks().map(ToOwned::to_owned),
+                source: format!("{:?}", svr_cfg.source()),
+                tier: server.server_instance_config().tier,
+                group: server.server_instance_config().group.clone(),
+                pinned: self.balancer.is_server_pinned(svr_cfg.addr()),
+                excluded: server.is_excluded(),
+                tx: flow_stat.tx(),
+                rx: flow_stat.rx(),
+                tcp: ServerScoreStats::new(server.tcp_score()).await,
+                udp: ServerScoreStats::new(server.udp_score()).await,
+            });
+        }
+
+        match serde_json::to_string(&servers) {
+            Ok(rsp) => rsp,
+            Err(err) => format!("err: {err}"),
+        }
+    }
+
+    fn handle_rules_explain(&self, target: Option<&str>) -> String {
+        let acl = self.context.acl();
+        let acl = acl.as_deref();
+
+        let explanation = RulesExplanation {
+            default: rule_action(acl.map(|acl| acl.is_default_in_proxy_list()).unwrap_or(true)),
+            sources: acl
+                .into_iter()
+                .flat_map(|acl| acl.rule_layers())
+                .map(|layer| RuleSourceStats {
+                    kind: layer.kind.as_str(),
+                    name: layer.name.to_owned(),
+                    proxy_ips: layer.proxy_ips,
+                    proxy_hosts: layer.proxy_hosts,
+                    bypass_ips: layer.bypass_ips,
+                    bypass_hosts: layer.bypass_hosts,
+                })
+                .collect(),
+            target: target.map(|target| match acl {
+                Some(acl) => {
+                    let decision = acl.explain(target);
+                    RuleTargetExplanation {
+                        target: target.to_owned(),
+                        action: rule_action(decision.proxied),
+                        source: decision.source.map(ToOwned::to_owned),
+                        resolve_required: decision.resolve_required,
+                    }
+                }
+                None => RuleTargetExplanation {
+                    target: target.to_owned(),
+                    action: rule_action(true),
+                    source: None,
+                    resolve_required: false,
+                },
+            }),
+        };
+
+        match serde_json::to_string(&explanation) {
+            Ok(rsp) => rsp,
+            Err(err) => format!("err: {err}"),
+        }
+    }
+
+    fn handle_uplinks(&self) -> String {
+        let uplinks = self
+            .context
+            .uplinks()
+            .map(|u| u.uplinks())
+            .unwrap_or_default()
+            .iter()
+            .map(|uplink| UplinkStats {
+                name: uplink.name().to_owned(),
+                weight: uplink.config().weight,
+                down: uplink.is_down(),
+                connections: uplink.connections(),
+                failures: uplink.failures(),
+            })
+            .collect::<Vec<_>>();
+
+        match serde_json::to_string(&uplinks) {
+            Ok(rsp) => rsp,
+            Err(err) => format!("err: {err}"),
+        }
+    }
+
+    fn handle_targets(&self) -> String {
+        let stat = self.context.special_target_stat();
+        let stats = SpecialTargetStats {
+            policy: self.context.special_target_policy().to_string(),
+            tcp: SpecialTarget::ALL.iter().map(|t| (t.as_str(), stat.tcp(*t))).collect(),
+            udp: SpecialTarget::ALL.iter().map(|t| (t.as_str(), stat.udp(*t))).collect(),
+        };
+
+        match serde_json::to_string(&stats) {
+            Ok(rsp) => rsp,
+            Err(err) => format!("err: {err}"),
+        }
+    }
+
+    #[cfg(feature = "local-dns")]
+    async fn handle_dns(&self) -> String {
+        let upstreams = self
+            .context
+            .dns_upstream_stats()
+            .await
+            .iter()
+            .map(|stat| DnsUpstreamStats {
+                kind: stat.kind().as_str(),
+                address: stat.addr().to_owned(),
+                queries: stat.queries(),
+                failures: stat.failures(),
+                consecutive_failures: stat.consecutive_failures(),
+                success_rate: stat.success_rate(),
+                latency: stat.latency(),
+                quarantined: stat.is_quarantined(),
+                quarantine_remaining: stat.quarantine_remaining().map(|d| d.as_secs()).unwrap_or(0),
+                quarantines: stat.quarantines(),
+            })
+            .collect::<Vec<_>>();
+
+        match serde_json::to_string(&upstreams) {
+            Ok(rsp) => rsp,
+            Err(err) => format!("err: {err}"),
+        }
+    }
+
+    #[cfg(feature = "local-dns")]
+    async fn handle_dns_queries(&self) -> String {
+        let queries = self
+            .context
+            .dns_query_stats()
+            .await
+            .iter()
+            .map(|stat| DnsQueryStats {
+                bind_address: stat.bind_addr().to_owned(),
+                queries: stat.queries(),
+                cache_hits: stat.cache_hits(),
+                filtered: stat.filtered(),
+                dropped: stat.dropped(),
+                local: stat.local(),
+                remote: stat.remote(),
+                name_server: stat.name_server(),
+                noerror: stat.noerror(),
+                nxdomain: stat.nxdomain(),
+                servfail: stat.servfail(),
+                other_rcodes: stat.other_rcodes(),
+            })
+            .collect::<Vec<_>>();
+
+        match serde_json::to_string(&queries) {
+            Ok(rsp) => rsp,
+            Err(err) => format!("err: {err}"),
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! Cache of DNS responses
+//!
+//! Responses are kept for the lowest TTL of their answers. Negative responses (NXDOMAIN and NODATA) are kept for the
+//! TTL of the SOA record in their authority sections, RFC 2308 5, and they are not cached without SOA records.
+
+use std::time::{Duration, Instant};
+
+use hickory_resolver::proto::{
+    op::{response_code::ResponseCode, Message, Query},
+    rr::{Name, RData, Record, RecordType},
+};
+use log::trace;
+use lru_time_cache::LruCache;
+use spin::Mutex as SpinMutex;
+
+use super::upstream_stat::DnsUpstreamKind;
+
+/// Default maximum TTL of cached responses, 1 day
+pub const DEFAULT_MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+type CacheKey = (Name, RecordType, u16);
+
+struct CachedResponse {
+    message: Message,
+    kind: DnsUpstreamKind,
+    inserted: Instant,
+    expire: Instant,
+}
+
+/// Responses of queries, with the upstreams that answered them
+pub struct DnsCache {
+    cache: SpinMutex<LruCache<CacheKey, CachedResponse>>,
+    min_ttl: Duration,
+    max_ttl: Duration,
+}
+
+impl DnsCache {
+    /// Create a cache of at most `size` responses, their TTLs are clamped in `[min_ttl, max_ttl]`
+    pub fn new(size: usize, min_ttl: Duration, max_ttl: Duration) -> DnsCache {
+        DnsCache {
+            cache: SpinMutex::new(LruCache::with_capacity(size)),
+            min_ttl,
+            max_ttl: max_ttl.max(min_ttl),
+        }
+    }
+
+    fn key(query: &Query) -> CacheKey {
+        (
+            query.name().to_lowercase(),
+            query.query_type(),
+            u16::from(query.query_class()),
+        )
+    }
+
+    /// Cached response of `query` and the upstream that answered it, TTLs are decreased by its age
+    pub fn get(&self, query: &Query) -> Option<(Message, DnsUpstreamKind)> {
+        let key = DnsCache::key(query);
+        let now = Instant::now();
+
+        let mut cache = self.cache.lock();
+        let (mut message, kind, age) = match cache.get(&key) {
+            Some(cached) if cached.expire > now => (
+                cached.message.clone(),
+                cached.kind,
+                now.duration_since(cached.inserted).as_secs() as u32,
+            ),
+            Some(..) => {
+                cache.remove(&key);
+                return None;
+            }
+            None => return None,
+        };
+        drop(cache);
+
+        trace!("DNS cache hit {:?} {}, age {}s", query.query_type(), query.name(), age);
+
+        let decrease = |mut records: Vec<Record>| {
+            for record in records.iter_mut() {
+                let ttl = record.ttl().saturating_sub(age);
+                record.set_ttl(ttl);
+            }
+            records
+        };
+        let answers = decrease(message.take_answers());
+        message.insert_answers(answers);
+        let name_servers = decrease(message.take_name_servers());
+        message.insert_name_servers(name_servers);
+        let additionals = decrease(message.take_additionals());
+        message.insert_additionals(additionals);
+
+        Some((message, kind))
+    }
+
+    /// Cache `message`, the response of `query`, if it could be cached
+    pub fn insert(&self, query: &Query, message: &Message, kind: DnsUpstreamKind) {
+        let ttl = match response_ttl(message) {
+            Some(ttl) => Duration::from_secs(u64::from(ttl)).clamp(self.min_ttl, self.max_ttl),
+            None => return,
+        };
+        if ttl.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let cached = CachedResponse {
+            message: message.clone(),
+            kind,
+            inserted: now,
+            expire: now + ttl,
+        };
+        self.cache.lock().insert(DnsCache::key(query), cached);
+    }
+}
+
+/// TTL of a response, `None` if it shouldn't be cached
+fn response_ttl(message: &Message) -> Option<u32> {
+    if message.truncated() {
+        return None;
+    }
+
+    match message.response_code() {
+        ResponseCode::NoError if !message.answers().is_empty() => message.answers().iter().map(Record::ttl).min(),
+        // NODATA and NXDOMAIN
+        ResponseCode::NoError | ResponseCode::NXDomain => message.name_servers().iter().find_map(|r| match r.data() {
+            Some(RData::SOA(soa)) => Some(r.ttl().min(soa.minimum())),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::Ipv4Addr, str::FromStr};
+
+    use hickory_resolver::proto::rr::rdata::{A, SOA};
+
+    use super::*;
+
+    fn name(s: &str) -> Name {
+        Name::from_str(s).unwrap()
+    }
+
+    fn query(s: &str) -> Query {
+        Query::query(name(s), RecordType::A)
+    }
+
+    fn answer(ttls: &[u32]) -> Message {
+        let mut message = Message::new();
+        for (i, ttl) in ttls.iter().enumerate() {
+            let rdata = RData::A(A(Ipv4Addr::new(192, 0, 2, i as u8 + 1)));
+            message.add_answer(Record::from_rdata(name("example.com."), *ttl, rdata));
+        }
+        message
+    }
+
+    fn negative(code: ResponseCode, record_ttl: u32, minimum: u32) -> Message {
+        let mut message = Message::new();
+        message.set_response_code(code);
+        let soa = SOA::new(
+            name("ns.example.com."),
+            name("admin.example.com."),
+            1,
+            3600,
+            600,
+            86400,
+            minimum,
+        );
+        message.add_name_server(Record::from_rdata(name("example.com."), record_ttl, RData::SOA(soa)));
+        message
+    }
+
+    /// Move the cached response of `query` back in time by `age`
+    fn age(cache: &DnsCache, query: &Query, age: Duration) {
+        let mut cache = cache.cache.lock();
+        let cached = cache.get_mut(&DnsCache::key(query)).unwrap();
+        cached.inserted -= age;
+        cached.expire -= age;
+    }
+
+    #[test]
+    fn test_response_ttl() {
+        assert_eq!(response_ttl(&answer(&[300, 60, 120])), Some(60));
+
+        // SOA TTL or its MINIMUM, whichever is lower
+        assert_eq!(response_ttl(&negative(ResponseCode::NXDomain, 600, 30)), Some(30));
+        assert_eq!(response_ttl(&negative(ResponseCode::NoError, 20, 30)), Some(20));
+
+        // Negative responses without SOA, failures and truncated responses are not cached
+        let mut nxdomain = Message::new();
+        nxdomain.set_response_code(ResponseCode::NXDomain);
+        assert_eq!(response_ttl(&nxdomain), None);
+        assert_eq!(response_ttl(&Message::new()), None);
+        assert_eq!(response_ttl(&negative(ResponseCode::ServFail, 600, 30)), None);
+        let mut truncated = answer(&[300]);
+        truncated.set_truncated(true);
+        assert_eq!(response_ttl(&truncated), None);
+    }
+
+    #[test]
+    fn test_cache_hit_and_case_insensitive() {
+        let cache = DnsCache::new(16, Duration::ZERO, DEFAULT_MAX_TTL);
+        cache.insert(&query("Example.COM."), &answer(&[300]), DnsUpstreamKind::Remote);
+
+        let (message, kind) = cache.get(&query("example.com.")).unwrap();
+        assert_eq!(kind, DnsUpstreamKind::Remote);
+        assert_eq!(message.answers().len(), 1);
+        assert_eq!(message.answers()[0].ttl(), 300);
+
+        // Other record types are cached separately
+        assert!(cache
+            .get(&Query::query(name("example.com."), RecordType::AAAA))
+            .is_none());
+    }
+
+    #[test]
+    fn test_cache_ttl_decreased_and_expired() {
+        let cache = DnsCache::new(16, Duration::ZERO, DEFAULT_MAX_TTL);
+        let q = query("example.com.");
+        cache.insert(&q, &answer(&[300, 60]), DnsUpstreamKind::Local);
+
+        age(&cache, &q, Duration::from_secs(45));
+        let (message, _) = cache.get(&q).unwrap();
+        let ttls: Vec<u32> = message.answers().iter().map(Record::ttl).collect();
+        assert_eq!(ttls, vec![255, 15]);
+
+        // Expired with the lowest TTL
+        age(&cache, &q, Duration::from_secs(15));
+        assert!(cache.get(&q).is_none());
+        assert_eq!(cache.cache.lock().len(), 0);
+    }
+
+    #[test]
+    fn test_cache_ttl_clamped() {
+        let cache = DnsCache::new(16, Duration::from_secs(60), Duration::from_secs(600));
+        let short = query("short.example.com.");
+        let long = query("long.example.com.");
+        cache.insert(&short, &answer(&[1]), DnsUpstreamKind::Local);
+        cache.insert(&long, &answer(&[86400]), DnsUpstreamKind::Local);
+
+        // Kept for min_ttl, records keep their own TTLs
+        age(&cache, &short, Duration::from_secs(59));
+        let (message, _) = cache.get(&short).unwrap();
+        assert_eq!(message.answers()[0].ttl(), 0);
+
+        age(&cache, &long, Duration::from_secs(600));
+        assert!(cache.get(&long).is_none());
+
+        // max_ttl is not lower than min_ttl
+        let cache = DnsCache::new(16, Duration::from_secs(60), Duration::from_secs(10));
+        assert_eq!(cache.max_ttl, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_cache_zero_ttl() {
+        let cache = DnsCache::new(16, Duration::ZERO, DEFAULT_MAX_TTL);
+        let q = query("example.com.");
+        cache.insert(&q, &answer(&[0]), DnsUpstreamKind::Local);
+        assert!(cache.get(&q).is_none());
+    }
+
+    #[test]
+    fn test_cache_negative() {
+        let cache = DnsCache::new(16, Duration::ZERO, DEFAULT_MAX_TTL);
+        let q = query("nx.example.com.");
+        cache.insert(&q, &negative(ResponseCode::NXDomain, 600, 30), DnsUpstreamKind::Remote);
+
+        age(&cache, &q, Duration::from_secs(10));
+        let (message, _) = cache.get(&q).unwrap();
+        assert_eq!(message.response_code(), ResponseCode::NXDomain);
+        assert_eq!(message.name_servers()[0].ttl(), 590);
+
+        age(&cache, &q, Duration::from_secs(20));
+        assert!(cache.get(&q).is_none());
+
+        // Not cached without SOA
+        let mut nxdomain = Message::new();
+        nxdomain.set_response_code(ResponseCode::NXDomain);
+        cache.insert(&q, &nxdomain, DnsUpstreamKind::Remote);
+        assert!(cache.get(&q).is_none());
+    }
+}
@@ -18,6 +18,8 @@ use shadowsocks::{config::ServerConfig, net::ConnectOpts, relay::socks5::Address
 
 use crate::local::context::ServiceContext;
 
+#[cfg(feature = "local-dns-over-tls")]
+use super::tls::DnsTlsConnector;
 use super::upstream::DnsClient;
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq, PartialOrd, Ord)]
@@ -26,6 +28,10 @@ enum DnsClientKey {
     UdpLocal(SocketAddr),
     TcpRemote(Address),
     UdpRemote(Address),
+    #[cfg(feature = "local-dns-over-tls")]
+    TlsLocal(SocketAddr),
+    #[cfg(feature = "local-dns-over-tls")]
+    TlsRemote(Address),
 }
 
 pub struct DnsClientCache {
@@ -33,6 +39,10 @@ pub struct DnsClientCache {
     timeout: Duration,
     retry_count: usize,
     max_client_per_addr: usize,
+    #[cfg(feature = "local-dns-over-tls")]
+    local_tls: Option<DnsTlsConnector>,
+    #[cfg(feature = "local-dns-over-tls")]
+    remote_tls: Option<DnsTlsConnector>,
 }
 
 impl DnsClientCache {
@@ -42,9 +52,35 @@ impl DnsClientCache {
             timeout: Duration::from_secs(5),
             retry_count: 1,
             max_client_per_addr,
+            #[cfg(feature = "local-dns-over-tls")]
+            local_tls: None,
+            #[cfg(feature = "local-dns-over-tls")]
+            remote_tls: None,
         }
     }
 
+    /// Connect to local DNS servers with TLS
+    #[cfg(feature = "local-dns-over-tls")]
+    pub fn set_local_tls(&mut self, tls: DnsTlsConnector) {
+        self.local_tls = Some(tls);
+    }
+
+    /// Connect to remote DNS servers with TLS
+    #[cfg(feature = "local-dns-over-tls")]
+    pub fn set_remote_tls(&mut self, tls: DnsTlsConnector) {
+        self.remote_tls = Some(tls);
+    }
+
+    #[cfg(feature = "local-dns-over-tls")]
+    pub fn local_tls_enabled(&self) -> bool {
+        self.local_tls.is_some()
+    }
+
+    #[cfg(feature = "local-dns-over-tls")]
+    pub fn remote_tls_enabled(&self) -> bool {
+        self.remote_tls.is_some()
+    }
+
     pub async fn lookup_local(
         &self,
         ns: SocketAddr,
@@ -74,6 +110,37 @@ impl DnsClientCache {
         self.lookup_dns(&key, msg, None, Some(context), Some(svr_cfg)).await
     }
 
+    /// Lookup with the local DNS over TLS server, requires `set_local_tls`
+    #[cfg(feature = "local-dns-over-tls")]
+    pub async fn lookup_tls_local(
+        &self,
+        ns: SocketAddr,
+        msg: Message,
+        connect_opts: &ConnectOpts,
+    ) -> Result<Message, ProtoError> {
+        self.lookup_dns(&DnsClientKey::TlsLocal(ns), msg, Some(connect_opts), None, None)
+            .await
+    }
+
+    /// Lookup with the remote DNS over TLS server, requires `set_remote_tls`
+    #[cfg(feature = "local-dns-over-tls")]
+    pub async fn lookup_tls_remote(
+        &self,
+        context: &ServiceContext,
+        svr_cfg: &ServerConfig,
+        ns: &Address,
+        msg: Message,
+    ) -> Result<Message, ProtoError> {
+        self.lookup_dns(
+            &DnsClientKey::TlsRemote(ns.clone()),
+            msg,
+            None,
+            Some(context),
+            Some(svr_cfg),
+        )
+        .await
+    }
+
     #[cfg(unix)]
     pub async fn lookup_unix_stream<P: AsRef<Path>>(&self, ns: &P, msg: Message) -> Result<Message, ProtoError> {
         let mut last_err = None;
@@ -144,6 +211,24 @@ impl DnsClientCache {
                     )
                     .await;
                 }
+                #[cfg(feature = "local-dns-over-tls")]
+                DnsClientKey::TlsLocal(tls_l) => {
+                    let tls = self.local_tls.as_ref().expect("local DNS without TLS");
+                    dns_res = DnsClient::connect_tls_local(*tls_l, connect_opts.unwrap(), tls).await;
+                }
+                #[cfg(feature = "local-dns-over-tls")]
+                DnsClientKey::TlsRemote(tls_l) => {
+                    let tls = self.remote_tls.as_ref().expect("remote DNS without TLS");
+                    dns_res = DnsClient::connect_tls_remote(
+                        context.unwrap().context(),
+                        svr_cfg.unwrap(),
+                        tls_l,
+                        context.unwrap().connect_opts_ref(),
+                        context.unwrap().flow_stat(),
+                        tls,
+                    )
+                    .await;
+                }
             }
             match self.get_client_or_create(dck, async { dns_res }).await {
                 Ok(mut client) => match client.lookup_timeout(msg.clone(), self.timeout).await {
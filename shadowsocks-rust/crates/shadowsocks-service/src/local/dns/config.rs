@@ -1,7 +1,8 @@
 //! DNS configurations
 
 #[cfg(unix)]
-use std::{convert::Infallible, path::PathBuf};
+use std::convert::Infallible;
+use std::path::PathBuf;
 use std::{
     fmt::{self, Display},
     net::{IpAddr, SocketAddr},
@@ -54,3 +55,392 @@ impl Display for NameServerAddr {
         }
     }
 }
+
+/// Upstream of queries matched by a `DnsRule`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DnsRuleUpstream {
+    /// The local upstream
+    Local,
+    /// The remote upstream, through servers
+    Remote,
+    /// A name server that is queried directly
+    NameServer(NameServerAddr),
+}
+
+impl FromStr for DnsRuleUpstream {
+    type Err = NameServerAddrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(DnsRuleUpstream::Local),
+            "remote" => Ok(DnsRuleUpstream::Remote),
+            _ => s.parse::<NameServerAddr>().map(DnsRuleUpstream::NameServer),
+        }
+    }
+}
+
+impl Display for DnsRuleUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            DnsRuleUpstream::Local => f.write_str("local"),
+            DnsRuleUpstream::Remote => f.write_str("remote"),
+            DnsRuleUpstream::NameServer(ref ns) => Display::fmt(ns, f),
+        }
+    }
+}
+
+/// Answers of a record type that are dropped by a `DnsRule`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DnsRuleDropAnswers {
+    /// IPv4 addresses
+    A,
+    /// IPv6 addresses
+    Aaaa,
+}
+
+/// Parse `DnsRuleDropAnswers` error
+#[derive(Debug, Clone, Copy)]
+pub struct DnsRuleDropAnswersError;
+
+impl Display for DnsRuleDropAnswersError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid answers to drop, expecting \"A\" or \"AAAA\"")
+    }
+}
+
+impl FromStr for DnsRuleDropAnswers {
+    type Err = DnsRuleDropAnswersError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("A") {
+            Ok(DnsRuleDropAnswers::A)
+        } else if s.eq_ignore_ascii_case("AAAA") {
+            Ok(DnsRuleDropAnswers::Aaaa)
+        } else {
+            Err(DnsRuleDropAnswersError)
+        }
+    }
+}
+
+impl Display for DnsRuleDropAnswers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            DnsRuleDropAnswers::A => f.write_str("A"),
+            DnsRuleDropAnswers::Aaaa => f.write_str("AAAA"),
+        }
+    }
+}
+
+/// Send queries of `domains` and their subdomains to `upstream` instead of deciding by ACL, and drop answers of a
+/// record type of them
+#[derive(Debug, Clone)]
+pub struct DnsRule {
+    domains: Vec<String>,
+    upstream: Option<DnsRuleUpstream>,
+    drop_answers: Option<DnsRuleDropAnswers>,
+}
+
+impl DnsRule {
+    /// Create with `domains`, like `corp.example` or `*.corp.example`. Queries are decided by ACL without `upstream`
+    pub fn new<I, S>(domains: I, upstream: Option<DnsRuleUpstream>) -> DnsRule
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let domains = domains
+            .into_iter()
+            .map(|d| {
+                let d = d.as_ref().trim();
+                let d = d.strip_prefix("*.").unwrap_or(d);
+                d.trim_end_matches('.').to_ascii_lowercase()
+            })
+            .filter(|d| !d.is_empty())
+            .collect();
+        DnsRule {
+            domains,
+            upstream,
+            drop_answers: None,
+        }
+    }
+
+    /// Drop answers of a record type, queries of this type are answered with NODATA
+    pub fn set_drop_answers(&mut self, drop_answers: DnsRuleDropAnswers) {
+        self.drop_answers = Some(drop_answers);
+    }
+
+    /// Domains of this rule, in lowercase without the trailing `.`
+    pub fn domains(&self) -> &[String] {
+        &self.domains
+    }
+
+    /// Upstream of this rule
+    pub fn upstream(&self) -> Option<&DnsRuleUpstream> {
+        self.upstream.as_ref()
+    }
+
+    /// Answers dropped by this rule
+    pub fn drop_answers(&self) -> Option<DnsRuleDropAnswers> {
+        self.drop_answers
+    }
+
+    /// Check if `name` (in lowercase ASCII, without the trailing `.`) is one of the domains or their subdomains
+    pub fn matches(&self, name: &str) -> bool {
+        self.domains.iter().any(|d| {
+            name.len() >= d.len()
+                && name.ends_with(d.as_str())
+                && (name.len() == d.len() || name.as_bytes()[name.len() - d.len() - 1] == b'.')
+        })
+    }
+}
+
+/// How queries of names that don't match domain rules of ACL are resolved
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum DnsAclStrategy {
+    /// Resolved by the local upstream, and resolved again by the remote upstream if answers of the local upstream
+    /// should be proxied by IP rules of ACL
+    #[default]
+    Response,
+    /// Resolved by the upstream of the default mode of ACL, remote for `proxy_all` and local for `bypass_all`
+    Domain,
+}
+
+/// Parse `DnsAclStrategy` error
+#[derive(Debug, Clone, Copy)]
+pub struct DnsAclStrategyError;
+
+impl Display for DnsAclStrategyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid ACL strategy, expecting \"response\" or \"domain\"")
+    }
+}
+
+impl FromStr for DnsAclStrategy {
+    type Err = DnsAclStrategyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "response" => Ok(DnsAclStrategy::Response),
+            "domain" => Ok(DnsAclStrategy::Domain),
+            _ => Err(DnsAclStrategyError),
+        }
+    }
+}
+
+impl Display for DnsAclStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            DnsAclStrategy::Response => f.write_str("response"),
+            DnsAclStrategy::Domain => f.write_str("domain"),
+        }
+    }
+}
+
+/// How queries are sent to local upstreams, if there are more than one
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum DnsUpstreamStrategy {
+    /// Upstreams are queried in order, the next one is queried after the previous one failed
+    #[default]
+    Failover,
+    /// Like `Failover`, but each query starts from the next upstream of the previous query
+    RoundRobin,
+    /// Upstreams are queried concurrently, the first successful response is used
+    Parallel,
+}
+
+/// Parse `DnsUpstreamStrategy` error
+#[derive(Debug, Clone, Copy)]
+pub struct DnsUpstreamStrategyError;
+
+impl Display for DnsUpstreamStrategyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid upstream strategy, expecting \"failover\", \"round_robin\" or \"parallel\"")
+    }
+}
+
+impl FromStr for DnsUpstreamStrategy {
+    type Err = DnsUpstreamStrategyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "failover" => Ok(DnsUpstreamStrategy::Failover),
+            "round_robin" => Ok(DnsUpstreamStrategy::RoundRobin),
+            "parallel" => Ok(DnsUpstreamStrategy::Parallel),
+            _ => Err(DnsUpstreamStrategyError),
+        }
+    }
+}
+
+impl Display for DnsUpstreamStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            DnsUpstreamStrategy::Failover => f.write_str("failover"),
+            DnsUpstreamStrategy::RoundRobin => f.write_str("round_robin"),
+            DnsUpstreamStrategy::Parallel => f.write_str("parallel"),
+        }
+    }
+}
+
+/// EDNS Client Subnet (RFC 7871) of queries sent to the remote upstream
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum DnsClientSubnet {
+    /// Queries are sent without client subnets
+    #[default]
+    Strip,
+    /// Client subnets of requests are sent as is
+    PassThrough,
+    /// Queries are sent with this subnet, like the subnet of servers
+    Forge(IpAddr, u8),
+}
+
+/// Parse `DnsClientSubnet` error
+#[derive(Debug, Clone, Copy)]
+pub struct DnsClientSubnetError;
+
+impl Display for DnsClientSubnetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid client subnet, expecting \"strip\", \"passthrough\" or a subnet")
+    }
+}
+
+impl FromStr for DnsClientSubnet {
+    type Err = DnsClientSubnetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strip" => return Ok(DnsClientSubnet::Strip),
+            "passthrough" => return Ok(DnsClientSubnet::PassThrough),
+            _ => {}
+        }
+
+        // 192.0.2.0/24, the prefix is 24 (IPv4) or 56 (IPv6) by default, RFC 7871 11.1
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| DnsClientSubnetError)?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().map_err(|_| DnsClientSubnetError)?,
+            None if addr.is_ipv4() => 24,
+            None => 56,
+        };
+        if prefix > max_prefix {
+            return Err(DnsClientSubnetError);
+        }
+        Ok(DnsClientSubnet::Forge(addr, prefix))
+    }
+}
+
+impl Display for DnsClientSubnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            DnsClientSubnet::Strip => f.write_str("strip"),
+            DnsClientSubnet::PassThrough => f.write_str("passthrough"),
+            DnsClientSubnet::Forge(addr, prefix) => write!(f, "{}/{}", addr, prefix),
+        }
+    }
+}
+
+/// Response of queries of blocked names
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum DnsBlockResponse {
+    /// NXDOMAIN
+    #[default]
+    NxDomain,
+    /// `0.0.0.0` and `::`
+    Unspecified,
+    /// This address, queries of the other family are answered with NODATA
+    Address(IpAddr),
+}
+
+impl FromStr for DnsBlockResponse {
+    type Err = <IpAddr as FromStr>::Err;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nxdomain" => Ok(DnsBlockResponse::NxDomain),
+            "zero" => Ok(DnsBlockResponse::Unspecified),
+            _ => s.parse::<IpAddr>().map(DnsBlockResponse::Address),
+        }
+    }
+}
+
+impl Display for DnsBlockResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            DnsBlockResponse::NxDomain => f.write_str("nxdomain"),
+            DnsBlockResponse::Unspecified => f.write_str("zero"),
+            DnsBlockResponse::Address(ref addr) => Display::fmt(addr, f),
+        }
+    }
+}
+
+/// Default size of the query log file before it is rotated, 10 MiB
+pub const DEFAULT_QUERY_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// Default number of rotated query log files that are kept
+pub const DEFAULT_QUERY_LOG_MAX_FILES: usize = 3;
+
+/// Query log of DNS relays
+#[derive(Debug, Clone)]
+pub struct DnsQueryLogConfig {
+    /// Path of the log file
+    pub path: PathBuf,
+    /// Size of the log file in bytes before it is rotated, 0 disables rotation
+    pub max_size: u64,
+    /// Number of rotated files that are kept, 0 truncates the log file instead
+    pub max_files: usize,
+}
+
+impl DnsQueryLogConfig {
+    /// Create with `path`, rotated with default limits
+    pub fn new(path: PathBuf) -> DnsQueryLogConfig {
+        DnsQueryLogConfig {
+            path,
+            max_size: DEFAULT_QUERY_LOG_MAX_SIZE,
+            max_files: DEFAULT_QUERY_LOG_MAX_FILES,
+        }
+    }
+}
+
+/// DNS over HTTPS (RFC 8484) remote upstream
+#[cfg(feature = "local-dns-over-https")]
+#[derive(Debug, Clone)]
+pub struct DnsHttpsConfig {
+    /// URL of the endpoint, like `https://dns.google/dns-query`
+    pub url: hyper::Uri,
+    /// Addresses of the endpoint that are connected instead of resolving the host of `url`
+    pub bootstrap: Vec<IpAddr>,
+}
+
+#[cfg(feature = "local-dns-over-https")]
+impl DnsHttpsConfig {
+    /// Create with `url`, which has to be an `https` URL with host
+    pub fn new(url: hyper::Uri) -> Option<DnsHttpsConfig> {
+        if url.scheme() != Some(&hyper::http::uri::Scheme::HTTPS) || url.host().is_none() {
+            return None;
+        }
+        Some(DnsHttpsConfig {
+            url,
+            bootstrap: Vec::new(),
+        })
+    }
+
+    /// Host and port of the endpoint, 443 by default
+    pub fn host_port(&self) -> (&str, u16) {
+        let host = self.url.host().expect("DnsHttpsConfig url without host");
+        (
+            host.trim_start_matches('[').trim_end_matches(']'),
+            self.url.port_u16().unwrap_or(443),
+        )
+    }
+}
+
+/// DNS over TLS (RFC 7858) upstream
+#[cfg(feature = "local-dns-over-tls")]
+#[derive(Debug, Clone, Default)]
+pub struct DnsTlsConfig {
+    /// Name for SNI and certificate verification, the address of the upstream by default
+    pub server_name: Option<String>,
+    /// CA certificates in PEM verifying the upstream, instead of system and WebPKI roots
+    pub ca_certificates: Option<PathBuf>,
+}
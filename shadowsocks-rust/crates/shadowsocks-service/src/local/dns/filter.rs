@@ -0,0 +1,198 @@
+//! Blocking and static overrides of names
+//!
+//! Block lists could be in these formats, which could be mixed in one file:
+//!
+//! - hosts, like `0.0.0.0 ads.example.com`. Names are blocked without their subdomains
+//! - adblock, like `||ads.example.com^`. Names are blocked with their subdomains, `@@||` lines are exceptions
+//! - domains, one per line. Names are blocked with their subdomains
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, BufRead, BufReader},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+use hickory_resolver::proto::{
+    op::{response_code::ResponseCode, Message, Query},
+    rr::{
+        rdata::{A, AAAA},
+        RData, Record, RecordType,
+    },
+};
+use log::{debug, trace};
+
+use super::config::DnsBlockResponse;
+
+/// TTL of answers made by the filter
+const FILTER_ANSWER_TTL: u32 = 60;
+
+/// Names in hosts files that shouldn't be blocked
+const HOSTS_IGNORED_NAMES: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "0.0.0.0",
+];
+
+/// Answers of a filtered query
+pub enum FilterAnswer<'a> {
+    Blocked,
+    Hosts(&'a [IpAddr]),
+}
+
+/// Block lists and static overrides
+pub struct DnsFilter {
+    blocked: HashSet<String>,
+    blocked_suffixes: HashSet<String>,
+    allowed_suffixes: HashSet<String>,
+    block_response: DnsBlockResponse,
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl DnsFilter {
+    /// Create with static overrides `hosts`
+    pub fn new(block_response: DnsBlockResponse, hosts: HashMap<String, Vec<IpAddr>>) -> DnsFilter {
+        DnsFilter {
+            blocked: HashSet::new(),
+            blocked_suffixes: HashSet::new(),
+            allowed_suffixes: HashSet::new(),
+            block_response,
+            hosts: hosts
+                .into_iter()
+                .map(|(name, addrs)| (normalize_name(&name), addrs))
+                .collect(),
+        }
+    }
+
+    /// Load a block list from `path`
+    pub fn load_block_list<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let reader = BufReader::new(File::open(path)?);
+
+        let mut count = 0usize;
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with('!') || line.starts_with('[') {
+                continue;
+            }
+
+            if let Some(rule) = line.strip_prefix("@@||") {
+                if let Some(name) = adblock_domain(rule) {
+                    self.allowed_suffixes.insert(name);
+                }
+            } else if let Some(rule) = line.strip_prefix("||") {
+                if let Some(name) = adblock_domain(rule) {
+                    self.blocked_suffixes.insert(name);
+                    count += 1;
+                }
+            } else {
+                let mut fields = line.split('#').next().unwrap_or_default().split_whitespace();
+                let first = match fields.next() {
+                    Some(f) => f,
+                    None => continue,
+                };
+                if first.parse::<IpAddr>().is_ok() {
+                    for name in fields {
+                        let name = normalize_name(name);
+                        if !HOSTS_IGNORED_NAMES.contains(&name.as_str()) && !name.starts_with("ip6-") {
+                            self.blocked.insert(name);
+                            count += 1;
+                        }
+                    }
+                } else if fields.next().is_none() && is_domain(first) {
+                    self.blocked_suffixes.insert(normalize_name(first));
+                    count += 1;
+                }
+            }
+        }
+
+        debug!("DNS block list {} loaded, {} names", path.display(), count);
+        Ok(())
+    }
+
+    /// Check if `name` (in lowercase ASCII, without the trailing `.`) is filtered
+    pub fn check(&self, name: &str) -> Option<FilterAnswer<'_>> {
+        if let Some(addrs) = self.hosts.get(name) {
+            return Some(FilterAnswer::Hosts(addrs));
+        }
+
+        if self.blocked.contains(name) {
+            return Some(FilterAnswer::Blocked);
+        }
+
+        if self.blocked_suffixes.is_empty() {
+            return None;
+        }
+
+        // Exceptions are checked before blocked suffixes of the same or parent domains
+        let mut suffix = name;
+        loop {
+            if self.allowed_suffixes.contains(suffix) {
+                return None;
+            }
+            if self.blocked_suffixes.contains(suffix) {
+                return Some(FilterAnswer::Blocked);
+            }
+            match suffix.find('.') {
+                Some(pos) => suffix = &suffix[pos + 1..],
+                None => return None,
+            }
+        }
+    }
+
+    /// Fill `message`, the response of `query`, which is filtered with `answer`
+    pub fn respond(&self, query: &Query, answer: FilterAnswer<'_>, message: &mut Message) {
+        message.add_query(query.clone());
+
+        let addrs: &[IpAddr] = match answer {
+            FilterAnswer::Hosts(addrs) => addrs,
+            FilterAnswer::Blocked => match self.block_response {
+                DnsBlockResponse::NxDomain => {
+                    trace!("DNS blocked {} with NXDOMAIN", query.name());
+                    message.set_response_code(ResponseCode::NXDomain);
+                    return;
+                }
+                DnsBlockResponse::Unspecified => {
+                    &[IpAddr::V4(Ipv4Addr::UNSPECIFIED), IpAddr::V6(Ipv6Addr::UNSPECIFIED)]
+                }
+                DnsBlockResponse::Address(ref addr) => std::slice::from_ref(addr),
+            },
+        };
+
+        // Other types and addresses of the other family are answered with NODATA
+        for addr in addrs {
+            let rdata = match (query.query_type(), *addr) {
+                (RecordType::A, IpAddr::V4(v4)) => RData::A(A(v4)),
+                (RecordType::AAAA, IpAddr::V6(v6)) => RData::AAAA(AAAA(v6)),
+                _ => continue,
+            };
+            message.add_answer(Record::from_rdata(query.name().clone(), FILTER_ANSWER_TTL, rdata));
+        }
+
+        trace!("DNS filtered {} with {} answers", query.name(), message.answer_count());
+    }
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn is_domain(s: &str) -> bool {
+    s.contains('.')
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_')
+}
+
+/// Domain of adblock rule `example.com^`, rules with paths or options are not supported
+fn adblock_domain(rule: &str) -> Option<String> {
+    let domain = rule.strip_suffix('^').unwrap_or(rule);
+    if is_domain(domain) {
+        Some(normalize_name(domain))
+    } else {
+        None
+    }
+}
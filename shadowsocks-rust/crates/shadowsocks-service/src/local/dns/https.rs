@@ -0,0 +1,142 @@
+//! DNS over HTTPS (RFC 8484) remote upstream
+//!
+//! Queries are sent in POST requests of `application/dns-message` through shadowsocks servers.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bytes::Bytes;
+use hickory_resolver::proto::op::Message;
+use http_body_util::{BodyExt, Full};
+use hyper::{header, Method, Request, StatusCode};
+use log::{error, trace};
+use shadowsocks::relay::Address;
+use tokio::time;
+
+use crate::local::{context::ServiceContext, http::HttpClient, loadbalancing::ServerIdent};
+
+use super::config::DnsHttpsConfig;
+
+const DNS_MESSAGE_CONTENT_TYPE: &str = "application/dns-message";
+
+/// Responses larger than this are rejected
+const MAXIMUM_RESPONSE_SIZE: usize = 65535;
+
+pub struct DnsHttpsClient {
+    config: DnsHttpsConfig,
+    http_client: HttpClient<Full<Bytes>>,
+    timeout: Duration,
+    /// Index of the bootstrap address in use, moved to the next one after failures
+    bootstrap_index: AtomicUsize,
+}
+
+impl DnsHttpsClient {
+    pub fn new(config: DnsHttpsConfig) -> DnsHttpsClient {
+        DnsHttpsClient {
+            config,
+            http_client: HttpClient::new(),
+            timeout: Duration::from_secs(5),
+            bootstrap_index: AtomicUsize::new(0),
+        }
+    }
+
+    /// Address connected through servers, a bootstrap address or the host of URL
+    fn target_addr(&self) -> Address {
+        let (host, port) = self.config.host_port();
+        if self.config.bootstrap.is_empty() {
+            return match host.parse() {
+                Ok(ip) => Address::SocketAddress(SocketAddr::new(ip, port)),
+                Err(..) => Address::DomainNameAddress(host.to_owned(), port),
+            };
+        }
+
+        let index = self.bootstrap_index.load(Ordering::Relaxed) % self.config.bootstrap.len();
+        Address::SocketAddress(SocketAddr::new(self.config.bootstrap[index], port))
+    }
+
+    pub async fn lookup(
+        &self,
+        context: Arc<ServiceContext>,
+        server: &ServerIdent,
+        mut msg: Message,
+    ) -> io::Result<Message> {
+        // ID 0 is recommended for HTTP caches, RFC 8484 4.1
+        let id = msg.id();
+        msg.set_id(0);
+
+        let target = self.target_addr();
+        let result = time::timeout(self.timeout, self.lookup_inner(context, server, target.clone(), &msg)).await;
+        let result = match result {
+            Ok(r) => r,
+            Err(..) => Err(io::ErrorKind::TimedOut.into()),
+        };
+
+        match result {
+            Ok(mut response) => {
+                response.set_id(id);
+                Ok(response)
+            }
+            Err(err) => {
+                error!(
+                    "DNS over HTTPS {} via {} failed, error: {}",
+                    self.config.url, target, err
+                );
+                self.bootstrap_index.fetch_add(1, Ordering::Relaxed);
+                Err(err)
+            }
+        }
+    }
+
+    async fn lookup_inner(
+        &self,
+        context: Arc<ServiceContext>,
+        server: &ServerIdent,
+        target: Address,
+        msg: &Message,
+    ) -> io::Result<Message> {
+        let body = msg.to_vec()?;
+
+        let req = match Request::builder()
+            .method(Method::POST)
+            .uri(self.config.url.clone())
+            .header(header::CONTENT_TYPE, DNS_MESSAGE_CONTENT_TYPE)
+            .header(header::ACCEPT, DNS_MESSAGE_CONTENT_TYPE)
+            .body(Full::new(Bytes::from(body)))
+        {
+            Ok(r) => r,
+            Err(err) => return Err(io::Error::other(err)),
+        };
+
+        let rsp = match self
+            .http_client
+            .send_request_proxied(context, req, server, target)
+            .await
+        {
+            Ok(r) => r,
+            Err(err) => return Err(io::Error::other(err)),
+        };
+
+        trace!("DNS over HTTPS {} response: {:?}", self.config.url, rsp);
+
+        if rsp.status() != StatusCode::OK {
+            return Err(io::Error::other(format!("status {}", rsp.status())));
+        }
+
+        let body = match http_body_util::Limited::new(rsp.into_body(), MAXIMUM_RESPONSE_SIZE)
+            .collect()
+            .await
+        {
+            Ok(b) => b.to_bytes(),
+            Err(err) => return Err(io::Error::other(err)),
+        };
+
+        Message::from_vec(&body).map_err(From::from)
+    }
+}
@@ -0,0 +1,219 @@
+//! DNS over HTTPS (RFC 8484) listener of DNS relays
+//!
+//! Queries are accepted in `GET <path>?dns=<base64url>` and `POST <path>` with `application/dns-message` bodies,
+//! over HTTP/1.1 or HTTP/2 negotiated with ALPN.
+
+use std::{convert::Infallible, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use hickory_resolver::proto::op::Message;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{self, HeaderValue},
+    server::conn::{http1, http2},
+    service, Method, Request, Response, StatusCode,
+};
+use log::{debug, error, info, trace};
+use shadowsocks::{config::ServerAddr, net::TcpListener, relay::Address};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time,
+};
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+use crate::{
+    local::{context::ServiceContext, http::TokioIo, net::tcp::listener::create_standard_tcp_listener},
+    net::tokio_rt::TokioExecutor,
+};
+
+use super::{config::NameServerAddr, server::DnsClient};
+
+/// Default path of the endpoint
+pub const DEFAULT_HTTPS_PATH: &str = "/dns-query";
+
+/// Media type of DNS messages
+const DNS_MESSAGE_CONTENT_TYPE: &str = "application/dns-message";
+
+/// Largest DNS message
+const MAXIMUM_QUERY_SIZE: usize = 65535;
+
+/// DNS over HTTPS server instance
+pub struct DnsHttpsServer {
+    listener: TcpListener,
+    tls_acceptor: TlsAcceptor,
+    service: Arc<DnsHttpsService>,
+}
+
+impl DnsHttpsServer {
+    pub(super) async fn bind(
+        context: &ServiceContext,
+        bind_addr: &ServerAddr,
+        mut tls_config: ServerConfig,
+        path: String,
+        local_addr: Arc<NameServerAddr>,
+        remote_addr: Arc<Address>,
+        client: Arc<DnsClient>,
+    ) -> io::Result<DnsHttpsServer> {
+        tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        let listener = create_standard_tcp_listener(context, bind_addr).await?;
+        Ok(DnsHttpsServer {
+            listener,
+            tls_acceptor: TlsAcceptor::from(Arc::new(tls_config)),
+            service: Arc::new(DnsHttpsService {
+                path,
+                local_addr,
+                remote_addr,
+                client,
+            }),
+        })
+    }
+
+    /// Get server local address
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Start serving
+    pub async fn run(self) -> io::Result<()> {
+        info!(
+            "shadowsocks dns HTTPS listening on https://{}{}, local: {}, remote: {}",
+            self.listener.local_addr()?,
+            self.service.path,
+            self.service.local_addr,
+            self.service.remote_addr
+        );
+
+        loop {
+            let (stream, peer_addr) = match self.listener.accept().await {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("accept failed with error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            trace!("dns https accepted client {}", peer_addr);
+
+            let tls_acceptor = self.tls_acceptor.clone();
+            let service = self.service.clone();
+            tokio::spawn(async move {
+                let stream = match tls_acceptor.accept(stream).await {
+                    Ok(s) => s,
+                    Err(err) => {
+                        debug!("dns https TLS handshake with {} failed, error: {}", peer_addr, err);
+                        return;
+                    }
+                };
+                let h2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+                service.serve_connection(stream, peer_addr, h2).await;
+            });
+        }
+    }
+}
+
+struct DnsHttpsService {
+    path: String,
+    local_addr: Arc<NameServerAddr>,
+    remote_addr: Arc<Address>,
+    client: Arc<DnsClient>,
+}
+
+impl DnsHttpsService {
+    async fn serve_connection<S>(self: Arc<Self>, stream: S, peer_addr: SocketAddr, h2: bool)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let io = TokioIo::new(stream);
+        let service = service::service_fn(move |req| {
+            let service = self.clone();
+            async move { Ok::<_, Infallible>(service.serve_request(req, peer_addr).await) }
+        });
+
+        let result = if h2 {
+            http2::Builder::new(TokioExecutor).serve_connection(io, service).await
+        } else {
+            http1::Builder::new().serve_connection(io, service).await
+        };
+
+        if let Err(err) = result {
+            debug!("dns https connection {} failed, error: {}", peer_addr, err);
+        }
+    }
+
+    async fn serve_request(&self, req: Request<Incoming>, peer_addr: SocketAddr) -> Response<Full<Bytes>> {
+        if req.uri().path() != self.path {
+            return make_response(StatusCode::NOT_FOUND);
+        }
+
+        let query = match *req.method() {
+            Method::GET => {
+                let param = req
+                    .uri()
+                    .query()
+                    .and_then(|q| q.split('&').find_map(|p| p.strip_prefix("dns=")));
+                match param.and_then(|p| URL_SAFE_NO_PAD.decode(p.trim_end_matches('=')).ok()) {
+                    Some(q) => Bytes::from(q),
+                    None => return make_response(StatusCode::BAD_REQUEST),
+                }
+            }
+            Method::POST => {
+                let content_type = req.headers().get(header::CONTENT_TYPE);
+                if content_type.map(HeaderValue::as_bytes) != Some(DNS_MESSAGE_CONTENT_TYPE.as_bytes()) {
+                    return make_response(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+                }
+                match Limited::new(req.into_body(), MAXIMUM_QUERY_SIZE).collect().await {
+                    Ok(b) => b.to_bytes(),
+                    Err(..) => return make_response(StatusCode::PAYLOAD_TOO_LARGE),
+                }
+            }
+            _ => return make_response(StatusCode::METHOD_NOT_ALLOWED),
+        };
+
+        let message = match Message::from_vec(&query) {
+            Ok(m) => m,
+            Err(err) => {
+                debug!("dns https {} parse message failed, error: {}", peer_addr, err);
+                return make_response(StatusCode::BAD_REQUEST);
+            }
+        };
+
+        let response = match self
+            .client
+            .resolve(message, peer_addr, &self.local_addr, &self.remote_addr)
+            .await
+        {
+            Ok(m) => m,
+            Err(err) => {
+                error!("dns https {} lookup failed, error: {}", peer_addr, err);
+                return make_response(StatusCode::BAD_GATEWAY);
+            }
+        };
+        let body = match response.to_vec() {
+            Ok(b) => b,
+            Err(err) => {
+                error!("dns https {} serialize message failed, error: {}", peer_addr, err);
+                return make_response(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        };
+
+        let mut rsp = Response::new(Full::new(Bytes::from(body)));
+        rsp.headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(DNS_MESSAGE_CONTENT_TYPE));
+        // Freshness is the lowest TTL of answers, RFC 8484 5.1
+        if let Some(ttl) = response.answers().iter().map(|r| r.ttl()).min() {
+            if let Ok(value) = HeaderValue::from_str(&format!("max-age={}", ttl)) {
+                rsp.headers_mut().insert(header::CACHE_CONTROL, value);
+            }
+        }
+        rsp
+    }
+}
+
+fn make_response(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut rsp = Response::new(Full::new(Bytes::new()));
+    *rsp.status_mut() = status;
+    rsp
+}
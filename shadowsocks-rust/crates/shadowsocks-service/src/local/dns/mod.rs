@@ -2,11 +2,27 @@
 
 pub use self::{
     config::NameServerAddr,
+    query_log::DnsQueryStat,
     server::{Dns, DnsBuilder},
+    upstream_stat::{DnsUpstreamKind, DnsUpstreamStat},
 };
 
+mod cache;
 mod client_cache;
 pub mod config;
 pub mod dns_resolver;
+mod filter;
+#[cfg(feature = "local-dns-over-https")]
+mod https;
+#[cfg(feature = "local-dns-server-https")]
+pub mod https_server;
+pub mod query_log;
+#[cfg(feature = "local-dns-over-quic")]
+mod quic;
 pub mod server;
+#[cfg(feature = "local-dns-over-tls")]
+mod tls;
+#[cfg(feature = "local-dns-server-tls")]
+pub mod tls_server;
 mod upstream;
+pub mod upstream_stat;
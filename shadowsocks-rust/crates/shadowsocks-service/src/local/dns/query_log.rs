@@ -0,0 +1,315 @@
+//! Statistic and log of queries of DNS relays
+//!
+//! Queries are logged in JSON lines. The log file is rotated to `<path>.1`, `<path>.2`, ... when it exceeds `max_size`.
+
+use std::{
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use hickory_resolver::proto::op::response_code::ResponseCode;
+use log::{error, trace};
+use serde::Serialize;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::mpsc,
+};
+
+use super::{config::DnsQueryLogConfig, upstream_stat::DnsUpstreamKind};
+
+/// Records waiting to be written, new records are dropped if the writer couldn't keep up
+const QUERY_LOG_QUEUE_SIZE: usize = 4096;
+
+/// How a query was answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsQueryAnswer {
+    /// Answered by an upstream
+    Upstream(DnsUpstreamKind),
+    /// Answered by the cache, with the upstream that answered it before
+    Cache(DnsUpstreamKind),
+    /// Answered by block lists or static hosts
+    Filtered,
+    /// Answered with NODATA, answers of its type are dropped by rules
+    Dropped,
+    /// Not supported, or failed before choosing an upstream
+    Unanswered,
+}
+
+impl DnsQueryAnswer {
+    fn upstream(&self) -> Option<&'static str> {
+        match *self {
+            DnsQueryAnswer::Upstream(kind) | DnsQueryAnswer::Cache(kind) => Some(kind.as_str()),
+            DnsQueryAnswer::Filtered => Some("filter"),
+            DnsQueryAnswer::Dropped => Some("drop"),
+            DnsQueryAnswer::Unanswered => None,
+        }
+    }
+}
+
+/// Statistic of queries of one DNS relay
+#[derive(Debug)]
+pub struct DnsQueryStat {
+    bind_addr: String,
+    queries: AtomicU64,
+    cache_hits: AtomicU64,
+    filtered: AtomicU64,
+    dropped: AtomicU64,
+    local: AtomicU64,
+    remote: AtomicU64,
+    name_server: AtomicU64,
+    noerror: AtomicU64,
+    nxdomain: AtomicU64,
+    servfail: AtomicU64,
+    other_rcodes: AtomicU64,
+}
+
+macro_rules! counter_getter {
+    ($($(#[$attr:meta])* $name:ident),+ $(,)?) => {
+        $(
+            $(#[$attr])*
+            pub fn $name(&self) -> u64 {
+                self.$name.load(Ordering::Relaxed)
+            }
+        )+
+    };
+}
+
+impl DnsQueryStat {
+    /// Create a statistic of the relay listening on `bind_addr`
+    pub fn new<A: Display>(bind_addr: A) -> DnsQueryStat {
+        DnsQueryStat {
+            bind_addr: bind_addr.to_string(),
+            queries: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            filtered: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            local: AtomicU64::new(0),
+            remote: AtomicU64::new(0),
+            name_server: AtomicU64::new(0),
+            noerror: AtomicU64::new(0),
+            nxdomain: AtomicU64::new(0),
+            servfail: AtomicU64::new(0),
+            other_rcodes: AtomicU64::new(0),
+        }
+    }
+
+    /// Listening address of the relay
+    pub fn bind_addr(&self) -> &str {
+        &self.bind_addr
+    }
+
+    counter_getter! {
+        /// Total queries
+        queries,
+        /// Queries answered by the cache
+        cache_hits,
+        /// Queries answered by block lists or static hosts
+        filtered,
+        /// Queries answered with NODATA by rules dropping answers
+        dropped,
+        /// Queries answered by the local upstream, including cached answers
+        local,
+        /// Queries answered by the remote upstream, including cached answers
+        remote,
+        /// Queries answered by name servers of rules, including cached answers
+        name_server,
+        /// Responses with NOERROR
+        noerror,
+        /// Responses with NXDOMAIN
+        nxdomain,
+        /// Responses with SERVFAIL
+        servfail,
+        /// Responses with the other response codes
+        other_rcodes,
+    }
+
+    /// Record a query answered by `answer` with `rcode`
+    pub fn record(&self, answer: DnsQueryAnswer, rcode: ResponseCode) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+
+        let kind = match answer {
+            DnsQueryAnswer::Upstream(kind) => Some(kind),
+            DnsQueryAnswer::Cache(kind) => {
+                self.cache_hits.fetch_add(1, Ordering::Relaxed);
+                Some(kind)
+            }
+            DnsQueryAnswer::Filtered => {
+                self.filtered.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            DnsQueryAnswer::Dropped => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            DnsQueryAnswer::Unanswered => None,
+        };
+        match kind {
+            Some(DnsUpstreamKind::Local) => self.local.fetch_add(1, Ordering::Relaxed),
+            Some(DnsUpstreamKind::Remote) => self.remote.fetch_add(1, Ordering::Relaxed),
+            Some(DnsUpstreamKind::NameServer) => self.name_server.fetch_add(1, Ordering::Relaxed),
+            None => 0,
+        };
+
+        let counter = match rcode {
+            ResponseCode::NoError => &self.noerror,
+            ResponseCode::NXDomain => &self.nxdomain,
+            ResponseCode::ServFail => &self.servfail,
+            _ => &self.other_rcodes,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A line of the query log
+#[derive(Serialize)]
+pub struct DnsQueryRecord {
+    /// UNIX timestamp in milliseconds
+    pub time: u64,
+    pub client: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub query_type: String,
+    /// `local`, `remote`, `name_server`, `filter`, `drop`, or `null` if no upstream was chosen
+    pub upstream: Option<&'static str>,
+    pub cache_hit: bool,
+    pub rcode: String,
+    /// Milliseconds
+    pub latency: u64,
+}
+
+impl DnsQueryRecord {
+    /// Create a record of a query answered by `answer`
+    pub fn new(
+        client: String,
+        name: String,
+        query_type: String,
+        answer: DnsQueryAnswer,
+        rcode: ResponseCode,
+        latency: Duration,
+    ) -> DnsQueryRecord {
+        DnsQueryRecord {
+            time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            client,
+            name,
+            query_type,
+            upstream: answer.upstream(),
+            cache_hit: matches!(answer, DnsQueryAnswer::Cache(..)),
+            rcode: rcode_name(rcode),
+            latency: latency.as_millis() as u64,
+        }
+    }
+}
+
+fn rcode_name(rcode: ResponseCode) -> String {
+    match rcode {
+        ResponseCode::NoError => "NOERROR".to_owned(),
+        ResponseCode::FormErr => "FORMERR".to_owned(),
+        ResponseCode::ServFail => "SERVFAIL".to_owned(),
+        ResponseCode::NXDomain => "NXDOMAIN".to_owned(),
+        ResponseCode::NotImp => "NOTIMP".to_owned(),
+        ResponseCode::Refused => "REFUSED".to_owned(),
+        rcode => format!("RCODE{}", u16::from(rcode)),
+    }
+}
+
+/// Query log writer
+pub struct DnsQueryLog {
+    tx: mpsc::Sender<String>,
+}
+
+impl DnsQueryLog {
+    /// Open the log file and start writing in background
+    pub async fn open(config: DnsQueryLogConfig) -> io::Result<DnsQueryLog> {
+        let file = open_append(&config.path).await?;
+        let size = file.metadata().await?.len();
+
+        let (tx, rx) = mpsc::channel(QUERY_LOG_QUEUE_SIZE);
+        tokio::spawn(write_log(config, file, size, rx));
+        Ok(DnsQueryLog { tx })
+    }
+
+    /// Append a record, it is dropped if too many records are waiting
+    pub fn log(&self, record: &DnsQueryRecord) {
+        let mut line = match serde_json::to_string(record) {
+            Ok(l) => l,
+            Err(err) => {
+                error!("failed to serialize dns query record, error: {}", err);
+                return;
+            }
+        };
+        line.push('\n');
+
+        if self.tx.try_send(line).is_err() {
+            trace!("dns query log queue is full, record dropped");
+        }
+    }
+}
+
+async fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path).await
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(format!(".{}", index));
+    PathBuf::from(p)
+}
+
+async fn rotate(config: &DnsQueryLogConfig) -> io::Result<File> {
+    if config.max_files == 0 {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&config.path)
+            .await?;
+        return Ok(file);
+    }
+
+    for index in (1..config.max_files).rev() {
+        let from = rotated_path(&config.path, index);
+        if fs::metadata(&from).await.is_ok() {
+            fs::rename(&from, rotated_path(&config.path, index + 1)).await?;
+        }
+    }
+    fs::rename(&config.path, rotated_path(&config.path, 1)).await?;
+    open_append(&config.path).await
+}
+
+async fn write_log(config: DnsQueryLogConfig, mut file: File, mut size: u64, mut rx: mpsc::Receiver<String>) {
+    while let Some(line) = rx.recv().await {
+        if config.max_size > 0 && size > 0 && size + line.len() as u64 > config.max_size {
+            let _ = file.flush().await;
+            match rotate(&config).await {
+                Ok(f) => {
+                    file = f;
+                    size = 0;
+                }
+                Err(err) => {
+                    error!(
+                        "failed to rotate dns query log {}, error: {}",
+                        config.path.display(),
+                        err
+                    );
+                }
+            }
+        }
+
+        if let Err(err) = file.write_all(line.as_bytes()).await {
+            error!(
+                "failed to write dns query log {}, error: {}",
+                config.path.display(),
+                err
+            );
+            continue;
+        }
+        size += line.len() as u64;
+    }
+}
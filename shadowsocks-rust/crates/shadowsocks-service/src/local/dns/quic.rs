@@ -0,0 +1,111 @@
+//! DNS over QUIC (RFC 9250) local upstream
+//!
+//! Each query is sent in its own bidirectional stream of a shared connection, which is recreated after it is closed.
+
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use hickory_resolver::proto::op::Message;
+use log::{debug, trace};
+use quinn::{crypto::rustls::QuicClientConfig, ClientConfig, Connection, Endpoint, EndpointConfig, TokioRuntime};
+use shadowsocks::net::{ConnectOpts, UdpSocket as ShadowUdpSocket};
+use tokio::{sync::Mutex, time};
+
+use super::{config::DnsTlsConfig, tls::load_client_config};
+
+/// ALPN of DNS over QUIC, RFC 9250 4.1.1
+const DOQ_ALPN: &[u8] = b"doq";
+
+/// Largest DNS message with its 2-octet length
+const MAXIMUM_RESPONSE_SIZE: usize = 2 + 65535;
+
+pub struct DnsQuicClient {
+    client_config: ClientConfig,
+    server_name: String,
+    timeout: Duration,
+    /// Endpoints are kept with their connections
+    connection: Mutex<Option<(Endpoint, Connection)>>,
+}
+
+impl DnsQuicClient {
+    /// Create with `config`, `host` is the server name if `config` doesn't have one
+    pub fn new(config: &DnsTlsConfig, host: &str) -> io::Result<DnsQuicClient> {
+        let mut tls_config = load_client_config(config)?;
+        tls_config.alpn_protocols = vec![DOQ_ALPN.to_vec()];
+        let quic_config = QuicClientConfig::try_from(tls_config).map_err(io::Error::other)?;
+
+        Ok(DnsQuicClient {
+            client_config: ClientConfig::new(Arc::new(quic_config)),
+            server_name: config.server_name.clone().unwrap_or_else(|| host.to_owned()),
+            timeout: Duration::from_secs(5),
+            connection: Mutex::new(None),
+        })
+    }
+
+    async fn connection(&self, ns: SocketAddr, connect_opts: &ConnectOpts) -> io::Result<Connection> {
+        let mut connection = self.connection.lock().await;
+        if let Some((_, ref conn)) = *connection {
+            match conn.close_reason() {
+                None => return Ok(conn.clone()),
+                Some(reason) => debug!("DNS over QUIC connection to {} closed, reason: {}", ns, reason),
+            }
+        }
+
+        // Outbound sockets are created with options of this process, like fwmark and bind interface
+        let socket: tokio::net::UdpSocket = ShadowUdpSocket::connect_any_with_opts(&ns, connect_opts).await?.into();
+        let endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            None,
+            socket.into_std()?,
+            Arc::new(TokioRuntime),
+        )?;
+
+        let conn = endpoint
+            .connect_with(self.client_config.clone(), ns, &self.server_name)
+            .map_err(io::Error::other)?
+            .await
+            .map_err(io::Error::other)?;
+        trace!("DNS over QUIC connected to {} ({})", ns, self.server_name);
+
+        *connection = Some((endpoint, conn.clone()));
+        Ok(conn)
+    }
+
+    pub async fn lookup(&self, ns: SocketAddr, connect_opts: &ConnectOpts, msg: Message) -> io::Result<Message> {
+        match time::timeout(self.timeout, self.lookup_inner(ns, connect_opts, msg)).await {
+            Ok(r) => r,
+            Err(..) => Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+
+    async fn lookup_inner(&self, ns: SocketAddr, connect_opts: &ConnectOpts, mut msg: Message) -> io::Result<Message> {
+        // ID has to be 0, RFC 9250 4.2.1
+        let id = msg.id();
+        msg.set_id(0);
+
+        let conn = self.connection(ns, connect_opts).await?;
+        let (mut send, mut recv) = conn.open_bi().await.map_err(io::Error::other)?;
+
+        // [LENGTH][Message], the client closes its direction after the query
+        let req_bytes = msg.to_vec()?;
+        let mut buffer = Vec::with_capacity(2 + req_bytes.len());
+        buffer.extend_from_slice(&(req_bytes.len() as u16).to_be_bytes());
+        buffer.extend_from_slice(&req_bytes);
+        send.write_all(&buffer).await?;
+        send.finish().map_err(io::Error::other)?;
+
+        let rsp_bytes = recv
+            .read_to_end(MAXIMUM_RESPONSE_SIZE)
+            .await
+            .map_err(io::Error::other)?;
+        if rsp_bytes.len() < 2 || usize::from(u16::from_be_bytes([rsp_bytes[0], rsp_bytes[1]])) != rsp_bytes.len() - 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid DNS over QUIC response",
+            ));
+        }
+
+        let mut response = Message::from_vec(&rsp_bytes[2..])?;
+        response.set_id(id);
+        Ok(response)
+    }
+}
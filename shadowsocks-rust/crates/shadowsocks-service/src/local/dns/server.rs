@@ -4,12 +4,16 @@
 
 use std::{
     cmp::Ordering,
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     io::{self, ErrorKind},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
     str::FromStr,
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicUsize, Ordering as AtomicOrdering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use byteorder::{BigEndian, ByteOrder};
@@ -19,14 +23,17 @@ use futures::{
     FutureExt,
 };
 use hickory_resolver::proto::{
-    op::{header::MessageType, response_code::ResponseCode, Message, OpCode, Query},
-    rr::{DNSClass, Name, RData, RecordType},
+    op::{header::MessageType, response_code::ResponseCode, Edns, Message, OpCode, Query},
+    rr::{
+        rdata::opt::{ClientSubnet, EdnsCode, EdnsOption},
+        DNSClass, Name, RData, RecordType,
+    },
 };
 use log::{debug, error, info, trace, warn};
 use rand::{thread_rng, Rng};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpStream, UdpSocket},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::UdpSocket,
     time,
 };
 
@@ -46,7 +53,32 @@ use crate::{
     },
 };
 
-use super::{client_cache::DnsClientCache, config::NameServerAddr};
+#[cfg(feature = "local-dns-server-https")]
+use super::https_server::{DnsHttpsServer, DEFAULT_HTTPS_PATH};
+#[cfg(feature = "local-dns-over-quic")]
+use super::quic::DnsQuicClient;
+#[cfg(feature = "local-dns-server-tls")]
+use super::tls_server::DnsTlsServer;
+use super::{
+    cache::{DnsCache, DEFAULT_MAX_TTL},
+    client_cache::DnsClientCache,
+    config::{
+        DnsAclStrategy, DnsBlockResponse, DnsClientSubnet, DnsQueryLogConfig, DnsRule, DnsRuleDropAnswers,
+        DnsRuleUpstream, DnsUpstreamStrategy, NameServerAddr,
+    },
+    filter::{DnsFilter, FilterAnswer},
+    query_log::{DnsQueryAnswer, DnsQueryLog, DnsQueryRecord, DnsQueryStat},
+    upstream_stat::{DnsUpstreamKind, DnsUpstreamStat, DEFAULT_QUARANTINE_DURATION, DEFAULT_QUARANTINE_FAILURES},
+};
+#[cfg(feature = "local-dns-over-https")]
+use super::{config::DnsHttpsConfig, https::DnsHttpsClient};
+#[cfg(feature = "local-dns-over-tls")]
+use super::{config::DnsTlsConfig, tls::DnsTlsConnector};
+#[cfg(feature = "local-dns-server-tls")]
+use crate::local::net::tls::TlsListenerConfig;
+
+/// UDP payload size advertised in EDNS of remote queries, DNS Flag Day 2020
+const EDNS_MAX_PAYLOAD: u16 = 1232;
 
 /// DNS Relay server builder
 pub struct DnsBuilder {
@@ -57,6 +89,36 @@ pub struct DnsBuilder {
     bind_addr: ServerAddr,
     balancer: PingBalancer,
     client_cache_size: usize,
+    local_extra_addrs: Vec<NameServerAddr>,
+    upstream_strategy: DnsUpstreamStrategy,
+    quarantine_failures: u32,
+    quarantine_duration: Duration,
+    cache_size: usize,
+    cache_min_ttl: Duration,
+    cache_max_ttl: Duration,
+    rules: Vec<DnsRule>,
+    client_subnet: DnsClientSubnet,
+    acl_strategy: DnsAclStrategy,
+    block_lists: Vec<PathBuf>,
+    block_response: DnsBlockResponse,
+    hosts: HashMap<String, Vec<IpAddr>>,
+    query_log: Option<DnsQueryLogConfig>,
+    #[cfg(feature = "local-dns-over-https")]
+    remote_https: Option<DnsHttpsConfig>,
+    #[cfg(feature = "local-dns-over-tls")]
+    local_tls: Option<DnsTlsConfig>,
+    #[cfg(feature = "local-dns-over-tls")]
+    remote_tls: Option<DnsTlsConfig>,
+    #[cfg(feature = "local-dns-over-quic")]
+    local_quic: Option<DnsTlsConfig>,
+    #[cfg(feature = "local-dns-server-tls")]
+    tls: Option<TlsListenerConfig>,
+    #[cfg(feature = "local-dns-server-tls")]
+    tls_bind_addr: Option<ServerAddr>,
+    #[cfg(feature = "local-dns-server-https")]
+    https_bind_addr: Option<ServerAddr>,
+    #[cfg(feature = "local-dns-server-https")]
+    https_path: Option<String>,
     #[cfg(target_os = "macos")]
     launchd_tcp_socket_name: Option<String>,
     #[cfg(target_os = "macos")]
@@ -100,6 +162,36 @@ impl DnsBuilder {
             bind_addr,
             balancer,
             client_cache_size,
+            local_extra_addrs: Vec::new(),
+            upstream_strategy: DnsUpstreamStrategy::Failover,
+            quarantine_failures: DEFAULT_QUARANTINE_FAILURES,
+            quarantine_duration: DEFAULT_QUARANTINE_DURATION,
+            cache_size: 0,
+            cache_min_ttl: Duration::ZERO,
+            cache_max_ttl: DEFAULT_MAX_TTL,
+            rules: Vec::new(),
+            client_subnet: DnsClientSubnet::Strip,
+            acl_strategy: DnsAclStrategy::Response,
+            block_lists: Vec::new(),
+            block_response: DnsBlockResponse::NxDomain,
+            hosts: HashMap::new(),
+            query_log: None,
+            #[cfg(feature = "local-dns-over-https")]
+            remote_https: None,
+            #[cfg(feature = "local-dns-over-tls")]
+            local_tls: None,
+            #[cfg(feature = "local-dns-over-tls")]
+            remote_tls: None,
+            #[cfg(feature = "local-dns-over-quic")]
+            local_quic: None,
+            #[cfg(feature = "local-dns-server-tls")]
+            tls: None,
+            #[cfg(feature = "local-dns-server-tls")]
+            tls_bind_addr: None,
+            #[cfg(feature = "local-dns-server-https")]
+            https_bind_addr: None,
+            #[cfg(feature = "local-dns-server-https")]
+            https_path: None,
             #[cfg(target_os = "macos")]
             launchd_tcp_socket_name: None,
             #[cfg(target_os = "macos")]
@@ -112,6 +204,124 @@ impl DnsBuilder {
         self.mode = mode;
     }
 
+    /// Set local upstreams besides `local_addr`, which are queried in plain DNS
+    pub fn set_local_extra_addrs(&mut self, addrs: Vec<NameServerAddr>) {
+        self.local_extra_addrs = addrs;
+    }
+
+    /// Set how queries are sent to local upstreams, if there are extra local upstreams
+    pub fn set_upstream_strategy(&mut self, strategy: DnsUpstreamStrategy) {
+        self.upstream_strategy = strategy;
+    }
+
+    /// Set consecutive failures before quarantining an upstream, 0 disables quarantine
+    pub fn set_quarantine_failures(&mut self, failures: u32) {
+        self.quarantine_failures = failures;
+    }
+
+    /// Set how long a failing upstream stays quarantined
+    pub fn set_quarantine_duration(&mut self, duration: Duration) {
+        self.quarantine_duration = duration;
+    }
+
+    /// Set maximum number of cached responses, 0 disables cache
+    pub fn set_cache_size(&mut self, size: usize) {
+        self.cache_size = size;
+    }
+
+    /// Set minimum TTL of cached responses
+    pub fn set_cache_min_ttl(&mut self, ttl: Duration) {
+        self.cache_min_ttl = ttl;
+    }
+
+    /// Set maximum TTL of cached responses
+    pub fn set_cache_max_ttl(&mut self, ttl: Duration) {
+        self.cache_max_ttl = ttl;
+    }
+
+    /// Set rules of sending queries to upstreams by domains, the first matched rule is used
+    pub fn set_rules(&mut self, rules: Vec<DnsRule>) {
+        self.rules = rules;
+    }
+
+    /// Set EDNS Client Subnet of remote queries
+    pub fn set_client_subnet(&mut self, client_subnet: DnsClientSubnet) {
+        self.client_subnet = client_subnet;
+    }
+
+    /// Set how queries of names that don't match domain rules of ACL are resolved
+    pub fn set_acl_strategy(&mut self, strategy: DnsAclStrategy) {
+        self.acl_strategy = strategy;
+    }
+
+    /// Set files of names that are blocked
+    pub fn set_block_lists(&mut self, block_lists: Vec<PathBuf>) {
+        self.block_lists = block_lists;
+    }
+
+    /// Set response of queries of blocked names
+    pub fn set_block_response(&mut self, block_response: DnsBlockResponse) {
+        self.block_response = block_response;
+    }
+
+    /// Set static addresses of names, which are answered without upstreams
+    pub fn set_hosts(&mut self, hosts: HashMap<String, Vec<IpAddr>>) {
+        self.hosts = hosts;
+    }
+
+    /// Log queries to a file
+    pub fn set_query_log(&mut self, config: DnsQueryLogConfig) {
+        self.query_log = Some(config);
+    }
+
+    /// Send remote queries to a DNS over HTTPS endpoint through servers, instead of `remote_addr`
+    #[cfg(feature = "local-dns-over-https")]
+    pub fn set_remote_https(&mut self, config: DnsHttpsConfig) {
+        self.remote_https = Some(config);
+    }
+
+    /// Send local queries to `local_addr` with TLS
+    #[cfg(feature = "local-dns-over-tls")]
+    pub fn set_local_tls(&mut self, config: DnsTlsConfig) {
+        self.local_tls = Some(config);
+    }
+
+    /// Send remote queries to `remote_addr` with TLS through servers
+    #[cfg(feature = "local-dns-over-tls")]
+    pub fn set_remote_tls(&mut self, config: DnsTlsConfig) {
+        self.remote_tls = Some(config);
+    }
+
+    /// Send local queries to `local_addr` with QUIC
+    #[cfg(feature = "local-dns-over-quic")]
+    pub fn set_local_quic(&mut self, config: DnsTlsConfig) {
+        self.local_quic = Some(config);
+    }
+
+    /// Set certificate of DNS over TLS and DNS over HTTPS listeners
+    #[cfg(feature = "local-dns-server-tls")]
+    pub fn set_tls(&mut self, tls: TlsListenerConfig) {
+        self.tls = Some(tls);
+    }
+
+    /// Also serve DNS over TLS on `bind_addr`, requires [`DnsBuilder::set_tls`]
+    #[cfg(feature = "local-dns-server-tls")]
+    pub fn set_tls_bind_addr(&mut self, bind_addr: ServerAddr) {
+        self.tls_bind_addr = Some(bind_addr);
+    }
+
+    /// Also serve DNS over HTTPS on `bind_addr`, requires [`DnsBuilder::set_tls`]
+    #[cfg(feature = "local-dns-server-https")]
+    pub fn set_https_bind_addr(&mut self, bind_addr: ServerAddr) {
+        self.https_bind_addr = Some(bind_addr);
+    }
+
+    /// Set path of the DNS over HTTPS endpoint, `/dns-query` by default
+    #[cfg(feature = "local-dns-server-https")]
+    pub fn set_https_path(&mut self, path: String) {
+        self.https_path = Some(path);
+    }
+
     /// macOS launchd activate socket
     #[cfg(target_os = "macos")]
     pub fn set_launchd_tcp_socket_name(&mut self, n: String) {
@@ -126,12 +336,106 @@ impl DnsBuilder {
 
     /// Build DNS server
     pub async fn build(self) -> io::Result<Dns> {
-        let client = Arc::new(DnsClient::new(
+        let local_stat = Arc::new(DnsUpstreamStat::new(
+            DnsUpstreamKind::Local,
+            &self.local_addr,
+            self.quarantine_failures,
+            self.quarantine_duration,
+        ));
+        let remote_stat = Arc::new(DnsUpstreamStat::new(
+            DnsUpstreamKind::Remote,
+            &self.remote_addr,
+            self.quarantine_failures,
+            self.quarantine_duration,
+        ));
+        self.context.add_dns_upstream_stat(local_stat.clone()).await;
+        self.context.add_dns_upstream_stat(remote_stat.clone()).await;
+
+        let mut local_extras = Vec::with_capacity(self.local_extra_addrs.len());
+        for addr in self.local_extra_addrs {
+            let stat = Arc::new(DnsUpstreamStat::new(
+                DnsUpstreamKind::Local,
+                &addr,
+                self.quarantine_failures,
+                self.quarantine_duration,
+            ));
+            self.context.add_dns_upstream_stat(stat.clone()).await;
+            local_extras.push((addr, stat));
+        }
+
+        let mut client = DnsClient::new(
             self.context.clone(),
             self.balancer,
             self.mode,
             self.client_cache_size,
-        ));
+            local_stat,
+            remote_stat,
+        );
+        client.local_extras = local_extras;
+        client.upstream_strategy = self.upstream_strategy;
+        client.rules = self.rules;
+        client.client_subnet = self.client_subnet;
+        client.acl_strategy = self.acl_strategy;
+        if !self.block_lists.is_empty() || !self.hosts.is_empty() {
+            let mut filter = DnsFilter::new(self.block_response, self.hosts);
+            for path in &self.block_lists {
+                if let Err(err) = filter.load_block_list(path) {
+                    error!("failed to load DNS block list {}, error: {}", path.display(), err);
+                    return Err(err);
+                }
+            }
+            client.filter = Some(filter);
+        }
+        let query_stat = Arc::new(DnsQueryStat::new(&self.bind_addr));
+        self.context.add_dns_query_stat(query_stat.clone()).await;
+        client.query_stat = Some(query_stat);
+        if let Some(config) = self.query_log {
+            match DnsQueryLog::open(config.clone()).await {
+                Ok(log) => client.query_log = Some(log),
+                Err(err) => {
+                    error!("failed to open DNS query log {}, error: {}", config.path.display(), err);
+                    return Err(err);
+                }
+            }
+        }
+        if self.cache_size > 0 {
+            client.cache = Some(DnsCache::new(self.cache_size, self.cache_min_ttl, self.cache_max_ttl));
+        }
+        #[cfg(feature = "local-dns-over-https")]
+        if let Some(config) = self.remote_https {
+            client.remote_https = Some(DnsHttpsClient::new(config));
+        }
+        #[cfg(feature = "local-dns-over-tls")]
+        if let Some(ref config) = self.local_tls {
+            let host = match self.local_addr {
+                NameServerAddr::SocketAddr(ref sa) => sa.ip().to_string(),
+                #[cfg(unix)]
+                NameServerAddr::UnixSocketAddr(..) => {
+                    return Err(io::Error::other("local DNS over TLS requires an IP address"));
+                }
+            };
+            client.client_cache.set_local_tls(DnsTlsConnector::new(config, &host)?);
+        }
+        #[cfg(feature = "local-dns-over-tls")]
+        if let Some(ref config) = self.remote_tls {
+            let host = match self.remote_addr {
+                Address::SocketAddress(ref sa) => sa.ip().to_string(),
+                Address::DomainNameAddress(ref dm, ..) => dm.clone(),
+            };
+            client.client_cache.set_remote_tls(DnsTlsConnector::new(config, &host)?);
+        }
+        #[cfg(feature = "local-dns-over-quic")]
+        if let Some(ref config) = self.local_quic {
+            let host = match self.local_addr {
+                NameServerAddr::SocketAddr(ref sa) => sa.ip().to_string(),
+                #[cfg(unix)]
+                NameServerAddr::UnixSocketAddr(..) => {
+                    return Err(io::Error::other("local DNS over QUIC requires an IP address"));
+                }
+            };
+            client.local_quic = Some(DnsQuicClient::new(config, &host)?);
+        }
+        let client = Arc::new(client);
 
         let local_addr = Arc::new(self.local_addr);
         let remote_addr = Arc::new(self.remote_addr);
@@ -156,6 +460,53 @@ impl DnsBuilder {
             tcp_server = Some(server);
         }
 
+        #[cfg(feature = "local-dns-server-tls")]
+        let tls_config = match self.tls {
+            Some(ref tls) => Some(tls.load_server_config()?),
+            None => None,
+        };
+
+        #[cfg(feature = "local-dns-server-tls")]
+        let mut tls_server = None;
+        #[cfg(feature = "local-dns-server-tls")]
+        if let Some(ref tls_bind_addr) = self.tls_bind_addr {
+            let tls_config = match tls_config {
+                Some(ref c) => c.clone(),
+                None => return Err(io::Error::other("DNS over TLS listener requires a certificate")),
+            };
+            let server = DnsTlsServer::bind(
+                &self.context,
+                tls_bind_addr,
+                tls_config,
+                local_addr.clone(),
+                remote_addr.clone(),
+                client.clone(),
+            )
+            .await?;
+            tls_server = Some(server);
+        }
+
+        #[cfg(feature = "local-dns-server-https")]
+        let mut https_server = None;
+        #[cfg(feature = "local-dns-server-https")]
+        if let Some(ref https_bind_addr) = self.https_bind_addr {
+            let tls_config = match tls_config {
+                Some(c) => c,
+                None => return Err(io::Error::other("DNS over HTTPS listener requires a certificate")),
+            };
+            let server = DnsHttpsServer::bind(
+                &self.context,
+                https_bind_addr,
+                tls_config,
+                self.https_path.unwrap_or_else(|| DEFAULT_HTTPS_PATH.to_owned()),
+                local_addr.clone(),
+                remote_addr.clone(),
+                client.clone(),
+            )
+            .await?;
+            https_server = Some(server);
+        }
+
         let mut udp_server = None;
         if self.mode.enable_udp() {
             #[allow(unused_mut)]
@@ -170,7 +521,14 @@ impl DnsBuilder {
             udp_server = Some(server);
         }
 
-        Ok(Dns { tcp_server, udp_server })
+        Ok(Dns {
+            tcp_server,
+            udp_server,
+            #[cfg(feature = "local-dns-server-tls")]
+            tls_server,
+            #[cfg(feature = "local-dns-server-https")]
+            https_server,
+        })
     }
 }
 
@@ -279,13 +637,16 @@ impl DnsTcpServer {
         }
     }
 
-    async fn handle_tcp_stream(
+    pub(super) async fn handle_tcp_stream<S>(
         client: Arc<DnsClient>,
-        mut stream: TcpStream,
+        mut stream: S,
         peer_addr: SocketAddr,
         local_addr: Arc<NameServerAddr>,
         remote_addr: Arc<Address>,
-    ) -> io::Result<()> {
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         let mut length_buf = [0u8; 2];
         let mut message_buf = BytesMut::new();
         loop {
@@ -324,7 +685,7 @@ impl DnsTcpServer {
                 }
             };
 
-            let respond_message = match client.resolve(message, &local_addr, &remote_addr).await {
+            let respond_message = match client.resolve(message, peer_addr, &local_addr, &remote_addr).await {
                 Ok(m) => m,
                 Err(err) => {
                     error!("dns tcp {} lookup error: {}", peer_addr, err);
@@ -471,7 +832,7 @@ impl DnsUdpServer {
         local_addr: Arc<NameServerAddr>,
         remote_addr: Arc<Address>,
     ) -> io::Result<()> {
-        let respond_message = match client.resolve(message, &local_addr, &remote_addr).await {
+        let respond_message = match client.resolve(message, peer_addr, &local_addr, &remote_addr).await {
             Ok(m) => m,
             Err(err) => {
                 error!("dns udp {} lookup failed, error: {}", peer_addr, err);
@@ -490,6 +851,10 @@ impl DnsUdpServer {
 pub struct Dns {
     tcp_server: Option<DnsTcpServer>,
     udp_server: Option<DnsUdpServer>,
+    #[cfg(feature = "local-dns-server-tls")]
+    tls_server: Option<DnsTlsServer>,
+    #[cfg(feature = "local-dns-server-https")]
+    https_server: Option<DnsHttpsServer>,
 }
 
 impl Dns {
@@ -503,6 +868,18 @@ impl Dns {
         self.udp_server.as_ref()
     }
 
+    /// Get DNS over TLS server instance
+    #[cfg(feature = "local-dns-server-tls")]
+    pub fn tls_server(&self) -> Option<&DnsTlsServer> {
+        self.tls_server.as_ref()
+    }
+
+    /// Get DNS over HTTPS server instance
+    #[cfg(feature = "local-dns-server-https")]
+    pub fn https_server(&self) -> Option<&DnsHttpsServer> {
+        self.https_server.as_ref()
+    }
+
     /// Run server
     pub async fn run(self) -> io::Result<()> {
         let mut vfut = Vec::new();
@@ -517,6 +894,16 @@ impl Dns {
             vfut.push(udp_server.run().boxed());
         }
 
+        #[cfg(feature = "local-dns-server-tls")]
+        if let Some(tls_server) = self.tls_server {
+            vfut.push(tls_server.run().boxed());
+        }
+
+        #[cfg(feature = "local-dns-server-https")]
+        if let Some(https_server) = self.https_server {
+            vfut.push(https_server.run().boxed());
+        }
+
         let (res, ..) = future::select_all(vfut).await;
         res
     }
@@ -577,17 +964,28 @@ fn check_name_in_proxy_list(acl: &AccessControl, name: &Name) -> Option<bool> {
     }
 }
 
-/// given the query, determine whether remote/local query should be used, or inconclusive
-fn should_forward_by_query(context: &ServiceContext, balancer: &PingBalancer, query: &Query) -> Option<bool> {
-    // No server was configured, then always resolve with local
-    if balancer.is_empty() {
-        return Some(false);
+fn drop_record_type(drop_answers: DnsRuleDropAnswers) -> RecordType {
+    match drop_answers {
+        DnsRuleDropAnswers::A => RecordType::A,
+        DnsRuleDropAnswers::Aaaa => RecordType::AAAA,
     }
+}
 
-    // Check if we are trying to make queries for remote servers
-    //
-    // This happens normally because VPN or TUN device receives DNS queries from local servers' plugins
-    // https://github.com/shadowsocks/shadowsocks-android/issues/2722
+/// `name` in lowercase ASCII without the trailing `.`
+fn ascii_name(name: &Name) -> String {
+    let mut name = name.to_ascii();
+    name.make_ascii_lowercase();
+    if name.ends_with('.') {
+        name.pop();
+    }
+    name
+}
+
+/// Check if we are trying to make queries for remote servers
+///
+/// This happens normally because VPN or TUN device receives DNS queries from local servers' plugins
+/// https://github.com/shadowsocks/shadowsocks-android/issues/2722
+fn is_server_name_query(balancer: &PingBalancer, query: &Query) -> bool {
     for server in balancer.servers() {
         let svr_cfg = server.server_config();
         if let ServerAddr::DomainName(ref dn, ..) = svr_cfg.addr() {
@@ -596,15 +994,33 @@ fn should_forward_by_query(context: &ServiceContext, balancer: &PingBalancer, qu
             if let Ok(name) = Name::from_str(dn) {
                 // cmp will handle FQDN in case insensitive way
                 if let Ordering::Equal = query.name().cmp(&name) {
-                    // It seems that query is for this server, just bypass it to local resolver
                     trace!("DNS querying name {} of server {:?}", query.name(), svr_cfg);
-                    return Some(false);
+                    return true;
                 }
             }
         }
     }
+    false
+}
 
-    if let Some(acl) = context.acl() {
+/// given the query, determine whether remote/local query should be used, or inconclusive
+fn should_forward_by_query(
+    context: &ServiceContext,
+    balancer: &PingBalancer,
+    acl_strategy: DnsAclStrategy,
+    query: &Query,
+) -> Option<bool> {
+    // No server was configured, then always resolve with local
+    if balancer.is_empty() {
+        return Some(false);
+    }
+
+    // Query for remote servers, just bypass it to local resolver
+    if is_server_name_query(balancer, query) {
+        return Some(false);
+    }
+
+    if let Some(ref acl) = context.acl() {
         if query.query_class() != DNSClass::IN {
             // unconditionally use default for all non-IN queries
             Some(acl.is_default_in_proxy_list())
@@ -612,7 +1028,9 @@ fn should_forward_by_query(context: &ServiceContext, balancer: &PingBalancer, qu
             Some(should_forward_by_ptr_name(acl, query.name()))
         } else {
             let result = check_name_in_proxy_list(acl, query.name());
-            if result.is_none() && acl.is_ip_empty() && acl.is_host_empty() {
+            if result.is_none()
+                && (acl_strategy == DnsAclStrategy::Domain || (acl.is_ip_empty() && acl.is_host_empty()))
+            {
                 Some(acl.is_default_in_proxy_list())
             } else {
                 result
@@ -704,31 +1122,74 @@ fn should_forward_by_response(
     }
 }
 
-struct DnsClient {
+pub(super) struct DnsClient {
     context: Arc<ServiceContext>,
     client_cache: DnsClientCache,
     mode: Mode,
     balancer: PingBalancer,
     attempts: usize,
+    local_stat: Arc<DnsUpstreamStat>,
+    remote_stat: Arc<DnsUpstreamStat>,
+    local_extras: Vec<(NameServerAddr, Arc<DnsUpstreamStat>)>,
+    upstream_strategy: DnsUpstreamStrategy,
+    next_local_upstream: AtomicUsize,
+    cache: Option<DnsCache>,
+    rules: Vec<DnsRule>,
+    client_subnet: DnsClientSubnet,
+    acl_strategy: DnsAclStrategy,
+    filter: Option<DnsFilter>,
+    query_stat: Option<Arc<DnsQueryStat>>,
+    query_log: Option<DnsQueryLog>,
+    #[cfg(feature = "local-dns-over-https")]
+    remote_https: Option<DnsHttpsClient>,
+    #[cfg(feature = "local-dns-over-quic")]
+    local_quic: Option<DnsQuicClient>,
 }
 
 impl DnsClient {
-    fn new(context: Arc<ServiceContext>, balancer: PingBalancer, mode: Mode, client_cache_size: usize) -> DnsClient {
+    fn new(
+        context: Arc<ServiceContext>,
+        balancer: PingBalancer,
+        mode: Mode,
+        client_cache_size: usize,
+        local_stat: Arc<DnsUpstreamStat>,
+        remote_stat: Arc<DnsUpstreamStat>,
+    ) -> DnsClient {
         DnsClient {
             context,
             client_cache: DnsClientCache::new(client_cache_size),
             mode,
             balancer,
             attempts: 2,
+            local_stat,
+            remote_stat,
+            local_extras: Vec::new(),
+            upstream_strategy: DnsUpstreamStrategy::Failover,
+            next_local_upstream: AtomicUsize::new(0),
+            cache: None,
+            rules: Vec::new(),
+            client_subnet: DnsClientSubnet::Strip,
+            acl_strategy: DnsAclStrategy::Response,
+            filter: None,
+            query_stat: None,
+            query_log: None,
+            #[cfg(feature = "local-dns-over-https")]
+            remote_https: None,
+            #[cfg(feature = "local-dns-over-quic")]
+            local_quic: None,
         }
     }
 
-    async fn resolve(
+    pub(super) async fn resolve(
         &self,
         request: Message,
+        peer_addr: SocketAddr,
         local_addr: &NameServerAddr,
         remote_addr: &Address,
     ) -> io::Result<Message> {
+        let start = Instant::now();
+        let mut answer = DnsQueryAnswer::Unanswered;
+
         let mut message = Message::new();
         message.set_id(request.id());
         message.set_recursion_desired(true);
@@ -744,22 +1205,39 @@ impl DnsClient {
             // Other ops are not supported
 
             message.set_response_code(ResponseCode::NotImp);
+        } else if let Some((filter, query, filter_answer)) = self.check_filter(&request) {
+            // Blocked or static names are answered without upstreams
+
+            filter.respond(query, filter_answer, &mut message);
+            answer = DnsQueryAnswer::Filtered;
+        } else if let Some(query) = self.check_dropped(&request) {
+            // Queries of answers dropped by rules are answered with NODATA
+
+            message.add_query(query.clone());
+            answer = DnsQueryAnswer::Dropped;
         } else if request.query_count() > 0 {
             // Make queries according to ACL rules
 
-            let (r, forward) = self.acl_lookup(&request.queries()[0], local_addr, remote_addr).await;
-            if let Ok(result) = r {
+            let subnet = self.query_subnet(&request);
+            let (r, lookup_answer) = self
+                .cached_acl_lookup(&request.queries()[0], subnet.as_ref(), local_addr, remote_addr)
+                .await;
+            answer = lookup_answer;
+            if let Ok(mut result) = r {
+                self.drop_answers(&request.queries()[0], &mut result);
+                // Addresses are mapped to the queried name, which is checked by ACL of each local server
+                let name = ascii_name(request.queries()[0].name());
                 for rec in result.answers() {
                     trace!("dns answer: {:?}", rec);
                     match rec.data() {
                         Some(RData::A(ip)) => {
                             self.context
-                                .add_to_reverse_lookup_cache(Ipv4Addr::from(*ip).into(), forward)
+                                .add_to_reverse_lookup_cache(Ipv4Addr::from(*ip).into(), &name)
                                 .await
                         }
                         Some(RData::AAAA(ip)) => {
                             self.context
-                                .add_to_reverse_lookup_cache(Ipv6Addr::from(*ip).into(), forward)
+                                .add_to_reverse_lookup_cache(Ipv6Addr::from(*ip).into(), &name)
                                 .await
                         }
                         _ => (),
@@ -771,42 +1249,193 @@ impl DnsClient {
                 message.set_response_code(ResponseCode::ServFail);
             }
         }
+
+        self.record_query(&request, peer_addr, answer, &message, start.elapsed());
         Ok(message)
     }
 
+    fn record_query(
+        &self,
+        request: &Message,
+        peer_addr: SocketAddr,
+        answer: DnsQueryAnswer,
+        response: &Message,
+        latency: Duration,
+    ) {
+        if let Some(ref stat) = self.query_stat {
+            stat.record(answer, response.response_code());
+        }
+
+        if let Some(ref log) = self.query_log {
+            let (name, query_type) = match request.queries().first() {
+                Some(query) => (ascii_name(query.name()), query.query_type().to_string()),
+                None => (String::new(), String::new()),
+            };
+            let record = DnsQueryRecord::new(
+                peer_addr.to_string(),
+                name,
+                query_type,
+                answer,
+                response.response_code(),
+                latency,
+            );
+            log.log(&record);
+        }
+    }
+
+    fn check_filter<'a>(&'a self, request: &'a Message) -> Option<(&'a DnsFilter, &'a Query, FilterAnswer<'a>)> {
+        let filter = self.filter.as_ref()?;
+        let query = request.queries().first()?;
+        let answer = filter.check(&ascii_name(query.name()))?;
+        Some((filter, query, answer))
+    }
+
+    fn check_dropped<'a>(&self, request: &'a Message) -> Option<&'a Query> {
+        let query = request.queries().first()?;
+        let drop_answers = self.match_rule(query.name())?.drop_answers()?;
+        if query.query_type() == drop_record_type(drop_answers) {
+            trace!("DNS lookup {:?} {} dropped by rule", query.query_type(), query.name());
+            Some(query)
+        } else {
+            None
+        }
+    }
+
+    /// Drop answers of the rule matching `query` from `response`
+    fn drop_answers(&self, query: &Query, response: &mut Message) {
+        let record_type = match self.match_rule(query.name()).and_then(DnsRule::drop_answers) {
+            Some(d) => drop_record_type(d),
+            None => return,
+        };
+
+        let mut answers = response.take_answers();
+        answers.retain(|rec| rec.record_type() != record_type);
+        response.insert_answers(answers);
+        let mut additionals = response.take_additionals();
+        additionals.retain(|rec| rec.record_type() != record_type);
+        response.insert_additionals(additionals);
+    }
+
+    /// Client subnet of remote queries of `request`
+    fn query_subnet(&self, request: &Message) -> Option<ClientSubnet> {
+        match self.client_subnet {
+            DnsClientSubnet::Strip => None,
+            DnsClientSubnet::PassThrough => match request.extensions() {
+                Some(edns) => match edns.option(EdnsCode::Subnet) {
+                    Some(EdnsOption::Subnet(subnet)) => Some(*subnet),
+                    _ => None,
+                },
+                None => None,
+            },
+            DnsClientSubnet::Forge(addr, prefix) => Some(ClientSubnet::new(addr, prefix, 0)),
+        }
+    }
+
+    async fn cached_acl_lookup(
+        &self,
+        query: &Query,
+        subnet: Option<&ClientSubnet>,
+        local_addr: &NameServerAddr,
+        remote_addr: &Address,
+    ) -> (io::Result<Message>, DnsQueryAnswer) {
+        // Responses of subnets passed through are different for each client
+        let cache = match self.cache {
+            Some(ref cache) if !(self.client_subnet == DnsClientSubnet::PassThrough && subnet.is_some()) => cache,
+            _ => {
+                let (r, kind) = self.acl_lookup(query, subnet, local_addr, remote_addr).await;
+                return (r, DnsQueryAnswer::Upstream(kind));
+            }
+        };
+
+        if let Some((message, kind)) = cache.get(query) {
+            return (Ok(message), DnsQueryAnswer::Cache(kind));
+        }
+
+        let (r, kind) = self.acl_lookup(query, subnet, local_addr, remote_addr).await;
+        if let Ok(ref message) = r {
+            cache.insert(query, message, kind);
+        }
+        (r, DnsQueryAnswer::Upstream(kind))
+    }
+
     async fn acl_lookup(
         &self,
         query: &Query,
+        subnet: Option<&ClientSubnet>,
         local_addr: &NameServerAddr,
         remote_addr: &Address,
-    ) -> (io::Result<Message>, bool) {
+    ) -> (io::Result<Message>, DnsUpstreamKind) {
         // Start querying name servers
         debug!("DNS lookup {:?} {}", query.query_type(), query.name());
 
-        match should_forward_by_query(&self.context, &self.balancer, query) {
+        if let Some(upstream) = self.match_rule(query.name()).and_then(DnsRule::upstream) {
+            trace!("DNS lookup {} matched rule to {}", query.name(), upstream);
+            return match *upstream {
+                DnsRuleUpstream::Local => (self.lookup_local(query, local_addr).await, DnsUpstreamKind::Local),
+                DnsRuleUpstream::Remote => (
+                    self.lookup_remote(query, subnet, remote_addr).await,
+                    DnsUpstreamKind::Remote,
+                ),
+                DnsRuleUpstream::NameServer(ref ns) => {
+                    (self.lookup_name_server(query, ns).await, DnsUpstreamKind::NameServer)
+                }
+            };
+        }
+
+        let mut forward = should_forward_by_query(&self.context, &self.balancer, self.acl_strategy, query);
+
+        // Fail over to remote while local upstream is quarantined.
+        // Queries for remote are never sent to local, which may be polluted
+        if forward != Some(true)
+            && !self.balancer.is_empty()
+            && self.is_local_quarantined()
+            && !self.remote_stat.is_quarantined()
+            && !is_server_name_query(&self.balancer, query)
+        {
+            debug!(
+                "DNS lookup {:?} {} fail over to remote, local upstream is quarantined",
+                query.query_type(),
+                query.name()
+            );
+            forward = Some(true);
+        }
+
+        match forward {
             Some(true) => {
-                let remote_response = self.lookup_remote(query, remote_addr).await;
+                let remote_response = self.lookup_remote(query, subnet, remote_addr).await;
                 trace!("pick remote response (query): {:?}", remote_response);
-                return (remote_response, true);
+                return (remote_response, DnsUpstreamKind::Remote);
             }
             Some(false) => {
                 let local_response = self.lookup_local(query, local_addr).await;
                 trace!("pick local response (query): {:?}", local_response);
-                return (local_response, false);
+                return (local_response, DnsUpstreamKind::Local);
             }
             None => (),
         }
 
         let decider = async {
             let local_response = self.lookup_local(query, local_addr).await;
-            if should_forward_by_response(self.context.acl(), &local_response, query) {
+            if should_forward_by_response(self.context.acl().as_deref(), &local_response, query) {
                 None
             } else {
                 Some(local_response)
             }
         };
 
-        let remote_response_fut = self.lookup_remote(query, remote_addr);
+        // Don't wait for remote upstream while it is quarantined, unless local response couldn't be used
+        if self.remote_stat.is_quarantined() {
+            if let Some(local_response) = decider.await {
+                trace!("pick local response (response): {:?}", local_response);
+                return (local_response, DnsUpstreamKind::Local);
+            }
+
+            let remote_response = self.lookup_remote(query, subnet, remote_addr).await;
+            trace!("pick remote response (response): {:?}", remote_response);
+            return (remote_response, DnsUpstreamKind::Remote);
+        }
+
+        let remote_response_fut = self.lookup_remote(query, subnet, remote_addr);
         tokio::pin!(remote_response_fut, decider);
 
         let mut use_remote = false;
@@ -816,7 +1445,7 @@ impl DnsClient {
                 response = &mut remote_response_fut, if remote_response.is_none() => {
                     if use_remote {
                         trace!("pick remote response (response): {:?}", response);
-                        return (response, true);
+                        return (response, DnsUpstreamKind::Remote);
                     } else {
                         remote_response = Some(response);
                     }
@@ -824,10 +1453,10 @@ impl DnsClient {
                 decision = &mut decider, if !use_remote => {
                     if let Some(local_response) = decision {
                         trace!("pick local response (response): {:?}", local_response);
-                        return (local_response, false);
+                        return (local_response, DnsUpstreamKind::Local);
                     } else if let Some(remote_response) = remote_response {
                         trace!("pick remote response (response): {:?}", remote_response);
-                        return (remote_response, true);
+                        return (remote_response, DnsUpstreamKind::Remote);
                     } else {
                         use_remote = true;
                     }
@@ -837,30 +1466,87 @@ impl DnsClient {
         }
     }
 
-    async fn lookup_remote(&self, query: &Query, remote_addr: &Address) -> io::Result<Message> {
+    fn match_rule(&self, name: &Name) -> Option<&DnsRule> {
+        if self.rules.is_empty() {
+            return None;
+        }
+
+        let name = ascii_name(name);
+        self.rules.iter().find(|rule| rule.matches(&name))
+    }
+
+    async fn lookup_remote(
+        &self,
+        query: &Query,
+        subnet: Option<&ClientSubnet>,
+        remote_addr: &Address,
+    ) -> io::Result<Message> {
         let mut last_err = io::Error::new(ErrorKind::InvalidData, "resolve empty");
 
         for _ in 0..self.attempts {
-            match self.lookup_remote_inner(query, remote_addr).await {
+            let start = Instant::now();
+            match self.lookup_remote_inner(query, subnet, remote_addr).await {
                 Ok(m) => {
+                    self.remote_stat.record_success(start.elapsed());
                     return Ok(m);
                 }
-                Err(err) => last_err = err,
+                Err(err) => {
+                    self.remote_stat.record_failure();
+                    last_err = err;
+                }
             }
         }
 
         Err(last_err)
     }
 
-    async fn lookup_remote_inner(&self, query: &Query, remote_addr: &Address) -> io::Result<Message> {
+    async fn lookup_remote_inner(
+        &self,
+        query: &Query,
+        subnet: Option<&ClientSubnet>,
+        remote_addr: &Address,
+    ) -> io::Result<Message> {
         let mut message = Message::new();
         message.set_id(thread_rng().gen());
         message.set_recursion_desired(true);
         message.add_query(query.clone());
 
+        if let Some(subnet) = subnet {
+            let mut edns = Edns::new();
+            edns.set_max_payload(EDNS_MAX_PAYLOAD);
+            edns.options_mut().insert(EdnsOption::Subnet(*subnet));
+            message.set_edns(edns);
+        }
+
+        #[cfg(feature = "local-dns-over-https")]
+        if let Some(ref https) = self.remote_https {
+            let server = self.balancer.best_tcp_server();
+            return https.lookup(self.context.clone(), &server, message).await;
+        }
+
+        // DNS over TLS is always in TCP
+        #[cfg(feature = "local-dns-over-tls")]
+        if self.client_cache.remote_tls_enabled() {
+            let server = self.balancer.best_tcp_server();
+            return self
+                .client_cache
+                .lookup_tls_remote(&self.context, server.server_config(), remote_addr, message)
+                .await
+                .map_err(From::from);
+        }
+
         // Query UDP and TCP
+        //
+        // Only with protocols that are served by at least one of the servers
+        let mode = match self.mode {
+            Mode::TcpAndUdp if !self.balancer.has_udp_server() => Mode::TcpOnly,
+            Mode::TcpAndUdp if !self.balancer.has_tcp_server() => Mode::UdpOnly,
+            Mode::UdpOnly if !self.balancer.has_udp_server() => Mode::TcpOnly,
+            Mode::TcpOnly if !self.balancer.has_tcp_server() => Mode::UdpOnly,
+            mode => mode,
+        };
 
-        match self.mode {
+        match mode {
             Mode::TcpOnly => {
                 let server = self.balancer.best_tcp_server();
                 self.client_cache
@@ -917,14 +1603,23 @@ impl DnsClient {
     }
 
     async fn lookup_local(&self, query: &Query, local_addr: &NameServerAddr) -> io::Result<Message> {
+        if !self.local_extras.is_empty() {
+            return self.lookup_local_upstreams(query, local_addr).await;
+        }
+
         let mut last_err = io::Error::new(ErrorKind::InvalidData, "resolve empty");
 
         for _ in 0..self.attempts {
+            let start = Instant::now();
             match self.lookup_local_inner(query, local_addr).await {
                 Ok(m) => {
+                    self.local_stat.record_success(start.elapsed());
                     return Ok(m);
                 }
-                Err(err) => last_err = err,
+                Err(err) => {
+                    self.local_stat.record_failure();
+                    last_err = err;
+                }
             }
         }
 
@@ -937,32 +1632,119 @@ impl DnsClient {
         message.set_recursion_desired(true);
         message.add_query(query.clone());
 
+        #[cfg(feature = "local-dns-over-quic")]
+        if let (Some(ref quic), NameServerAddr::SocketAddr(ns)) = (&self.local_quic, local_addr) {
+            return quic.lookup(*ns, self.context.connect_opts_ref(), message).await;
+        }
+
         match *local_addr {
-            NameServerAddr::SocketAddr(ns) => {
-                // Query UDP then TCP
+            #[cfg(feature = "local-dns-over-tls")]
+            NameServerAddr::SocketAddr(ns) if self.client_cache.local_tls_enabled() => self
+                .client_cache
+                .lookup_tls_local(ns, message, self.context.connect_opts_ref())
+                .await
+                .map_err(From::from),
+            NameServerAddr::SocketAddr(ns) => self.lookup_plain(ns, message).await,
+            #[cfg(unix)]
+            NameServerAddr::UnixSocketAddr(ref path) => self
+                .client_cache
+                .lookup_unix_stream(path, message)
+                .await
+                .map_err(From::from),
+        }
+    }
 
-                let udp_query =
-                    self.client_cache
-                        .lookup_local(ns, message.clone(), self.context.connect_opts_ref(), true);
-                let tcp_query = async move {
-                    // Send TCP query after 500ms, because UDP will always return faster than TCP, there is no need to send queries simutaneously
-                    time::sleep(Duration::from_millis(500)).await;
+    /// Query `local_addr` and extra local upstreams by `upstream_strategy`
+    async fn lookup_local_upstreams(&self, query: &Query, local_addr: &NameServerAddr) -> io::Result<Message> {
+        // Quarantined upstreams are skipped, unless all of them are quarantined
+        let count = self.local_extras.len() + 1;
+        let mut indexes = (0..count)
+            .filter(|&i| !self.local_upstream_stat(i).is_quarantined())
+            .collect::<Vec<_>>();
+        if indexes.is_empty() {
+            indexes.extend(0..count);
+        }
 
-                    self.client_cache
-                        .lookup_local(ns, message, self.context.connect_opts_ref(), false)
-                        .await
-                };
+        if self.upstream_strategy == DnsUpstreamStrategy::Parallel {
+            let queries = indexes
+                .into_iter()
+                .map(|i| Box::pin(self.lookup_local_upstream(query, local_addr, i)));
+            return future::select_ok(queries).await.map(|(m, ..)| m);
+        }
 
-                tokio::pin!(udp_query);
-                tokio::pin!(tcp_query);
+        if self.upstream_strategy == DnsUpstreamStrategy::RoundRobin {
+            let start = self.next_local_upstream.fetch_add(1, AtomicOrdering::Relaxed) % indexes.len();
+            indexes.rotate_left(start);
+        }
 
-                match future::select(udp_query, tcp_query).await {
-                    Either::Left((Ok(m), ..)) => Ok(m),
-                    Either::Left((Err(..), next)) => next.await.map_err(From::from),
-                    Either::Right((Ok(m), ..)) => Ok(m),
-                    Either::Right((Err(..), next)) => next.await.map_err(From::from),
-                }
+        let mut last_err = io::Error::new(ErrorKind::InvalidData, "resolve empty");
+        for i in indexes {
+            match self.lookup_local_upstream(query, local_addr, i).await {
+                Ok(m) => return Ok(m),
+                Err(err) => last_err = err,
             }
+        }
+        Err(last_err)
+    }
+
+    /// Query local upstream `index`, 0 is `local_addr` and the others are extra local upstreams
+    async fn lookup_local_upstream(
+        &self,
+        query: &Query,
+        local_addr: &NameServerAddr,
+        index: usize,
+    ) -> io::Result<Message> {
+        let start = Instant::now();
+        let result = match index {
+            0 => self.lookup_local_inner(query, local_addr).await,
+            i => self.lookup_name_server_once(query, &self.local_extras[i - 1].0).await,
+        };
+
+        let stat = self.local_upstream_stat(index);
+        match result {
+            Ok(..) => stat.record_success(start.elapsed()),
+            Err(ref err) => {
+                trace!("DNS local upstream {} failed, error: {}", stat.addr(), err);
+                stat.record_failure();
+            }
+        }
+        result
+    }
+
+    fn local_upstream_stat(&self, index: usize) -> &DnsUpstreamStat {
+        match index {
+            0 => &self.local_stat,
+            i => &self.local_extras[i - 1].1,
+        }
+    }
+
+    /// All local upstreams are quarantined
+    fn is_local_quarantined(&self) -> bool {
+        self.local_stat.is_quarantined() && self.local_extras.iter().all(|(_, stat)| stat.is_quarantined())
+    }
+
+    /// Query a name server of rules, which is not the local upstream
+    async fn lookup_name_server(&self, query: &Query, ns: &NameServerAddr) -> io::Result<Message> {
+        let mut last_err = io::Error::new(ErrorKind::InvalidData, "resolve empty");
+
+        for _ in 0..self.attempts {
+            match self.lookup_name_server_once(query, ns).await {
+                Ok(m) => return Ok(m),
+                Err(err) => last_err = err,
+            }
+        }
+
+        Err(last_err)
+    }
+
+    async fn lookup_name_server_once(&self, query: &Query, ns: &NameServerAddr) -> io::Result<Message> {
+        let mut message = Message::new();
+        message.set_id(thread_rng().gen());
+        message.set_recursion_desired(true);
+        message.add_query(query.clone());
+
+        match *ns {
+            NameServerAddr::SocketAddr(ns) => self.lookup_plain(ns, message).await,
             #[cfg(unix)]
             NameServerAddr::UnixSocketAddr(ref path) => self
                 .client_cache
@@ -971,4 +1753,30 @@ impl DnsClient {
                 .map_err(From::from),
         }
     }
+
+    async fn lookup_plain(&self, ns: SocketAddr, message: Message) -> io::Result<Message> {
+        // Query UDP then TCP
+
+        let udp_query = self
+            .client_cache
+            .lookup_local(ns, message.clone(), self.context.connect_opts_ref(), true);
+        let tcp_query = async move {
+            // Send TCP query after 500ms, because UDP will always return faster than TCP, there is no need to send queries simutaneously
+            time::sleep(Duration::from_millis(500)).await;
+
+            self.client_cache
+                .lookup_local(ns, message, self.context.connect_opts_ref(), false)
+                .await
+        };
+
+        tokio::pin!(udp_query);
+        tokio::pin!(tcp_query);
+
+        match future::select(udp_query, tcp_query).await {
+            Either::Left((Ok(m), ..)) => Ok(m),
+            Either::Left((Err(..), next)) => next.await.map_err(From::from),
+            Either::Right((Ok(m), ..)) => Ok(m),
+            Either::Right((Err(..), next)) => next.await.map_err(From::from),
+        }
+    }
 }
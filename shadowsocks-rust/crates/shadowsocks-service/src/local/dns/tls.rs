@@ -0,0 +1,89 @@
+//! DNS over TLS (RFC 7858) upstreams
+
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    sync::Arc,
+};
+
+use log::warn;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+
+use super::config::DnsTlsConfig;
+
+/// TLS connector of an upstream
+pub struct DnsTlsConnector {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl DnsTlsConnector {
+    /// Create with `config`, `host` is the server name if `config` doesn't have one
+    pub fn new(config: &DnsTlsConfig, host: &str) -> io::Result<DnsTlsConnector> {
+        let name = config.server_name.as_deref().unwrap_or(host);
+        let server_name = match ServerName::try_from(name.to_owned()) {
+            Ok(n) => n,
+            Err(..) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid server name \"{name}\""),
+                ));
+            }
+        };
+
+        let config = load_client_config(config)?;
+
+        Ok(DnsTlsConnector {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+
+    /// TLS handshake on a connected `stream`
+    pub async fn connect<S>(&self, stream: S) -> io::Result<TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.connector.connect(self.server_name.clone(), stream).await
+    }
+}
+
+/// Client configuration verifying servers with CA certificates of `config`, or system and WebPKI roots
+pub fn load_client_config(config: &DnsTlsConfig) -> io::Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    match config.ca_certificates {
+        Some(ref path) => {
+            for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(path)?)) {
+                roots.add(cert?).map_err(io::Error::other)?;
+            }
+            if roots.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("no certificate in {}", path.display()),
+                ));
+            }
+        }
+        None => {
+            // Load WebPKI roots (Mozilla's root certificates)
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+            if let Ok(certs) = rustls_native_certs::load_native_certs() {
+                for cert in certs {
+                    if let Err(err) = roots.add(cert) {
+                        warn!("failed to add cert (native), error: {}", err);
+                    }
+                }
+            }
+        }
+    }
+
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(config)
+}
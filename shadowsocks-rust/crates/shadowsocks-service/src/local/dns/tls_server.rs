@@ -0,0 +1,89 @@
+//! DNS over TLS (RFC 7858) listener of DNS relays
+//!
+//! Queries are served the same as the TCP listener, after TLS handshake.
+
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use log::{debug, error, info, trace};
+use shadowsocks::{config::ServerAddr, net::TcpListener, relay::Address};
+use tokio::time;
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+use crate::local::{context::ServiceContext, net::tcp::listener::create_standard_tcp_listener};
+
+use super::{
+    config::NameServerAddr,
+    server::{DnsClient, DnsTcpServer},
+};
+
+/// DNS over TLS server instance
+pub struct DnsTlsServer {
+    listener: TcpListener,
+    tls_acceptor: TlsAcceptor,
+    local_addr: Arc<NameServerAddr>,
+    remote_addr: Arc<Address>,
+    client: Arc<DnsClient>,
+}
+
+impl DnsTlsServer {
+    pub(super) async fn bind(
+        context: &ServiceContext,
+        bind_addr: &ServerAddr,
+        tls_config: ServerConfig,
+        local_addr: Arc<NameServerAddr>,
+        remote_addr: Arc<Address>,
+        client: Arc<DnsClient>,
+    ) -> io::Result<DnsTlsServer> {
+        let listener = create_standard_tcp_listener(context, bind_addr).await?;
+        Ok(DnsTlsServer {
+            listener,
+            tls_acceptor: TlsAcceptor::from(Arc::new(tls_config)),
+            local_addr,
+            remote_addr,
+            client,
+        })
+    }
+
+    /// Get server local address
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Start serving
+    pub async fn run(self) -> io::Result<()> {
+        info!(
+            "shadowsocks dns TLS listening on {}, local: {}, remote: {}",
+            self.listener.local_addr()?,
+            self.local_addr,
+            self.remote_addr
+        );
+
+        loop {
+            let (stream, peer_addr) = match self.listener.accept().await {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("accept failed with error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            trace!("dns tls accepted client {}", peer_addr);
+
+            let tls_acceptor = self.tls_acceptor.clone();
+            let client = self.client.clone();
+            let local_addr = self.local_addr.clone();
+            let remote_addr = self.remote_addr.clone();
+            tokio::spawn(async move {
+                let stream = match tls_acceptor.accept(stream).await {
+                    Ok(s) => s,
+                    Err(err) => {
+                        debug!("dns tls handshake with {} failed, error: {}", peer_addr, err);
+                        return;
+                    }
+                };
+                let _ = DnsTcpServer::handle_tcp_stream(client, stream, peer_addr, local_addr, remote_addr).await;
+            });
+        }
+    }
+}
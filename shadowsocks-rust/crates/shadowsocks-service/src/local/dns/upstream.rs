@@ -36,7 +36,11 @@ use tokio::{
     net::UdpSocket,
     time,
 };
+#[cfg(feature = "local-dns-over-tls")]
+use tokio_rustls::client::TlsStream;
 
+#[cfg(feature = "local-dns-over-tls")]
+use super::tls::DnsTlsConnector;
 use crate::{
     local::net::udp::generate_client_session_id,
     net::{packet_window::PacketWindowFilter, FlowStat, MonProxySocket, MonProxyStream},
@@ -66,6 +70,14 @@ pub enum DnsClient {
         control: UdpSocketControlData,
         server_windows: LruCache<u64, PacketWindowFilter>,
     },
+    #[cfg(feature = "local-dns-over-tls")]
+    TlsLocal {
+        stream: TlsStream<ShadowTcpStream>,
+    },
+    #[cfg(feature = "local-dns-over-tls")]
+    TlsRemote {
+        stream: TlsStream<ProxyClientStream<MonProxyStream<ShadowTcpStream>>>,
+    },
 }
 
 impl DnsClient {
@@ -125,6 +137,36 @@ impl DnsClient {
         })
     }
 
+    /// Connect to local provided DNS over TLS server
+    #[cfg(feature = "local-dns-over-tls")]
+    pub async fn connect_tls_local(
+        ns: SocketAddr,
+        connect_opts: &ConnectOpts,
+        tls: &DnsTlsConnector,
+    ) -> io::Result<DnsClient> {
+        let stream = ShadowTcpStream::connect_with_opts(&ns, connect_opts).await?;
+        let stream = tls.connect(stream).await?;
+        Ok(DnsClient::TlsLocal { stream })
+    }
+
+    /// Connect to remote DNS over TLS server through proxy
+    #[cfg(feature = "local-dns-over-tls")]
+    pub async fn connect_tls_remote(
+        context: SharedContext,
+        svr_cfg: &ServerConfig,
+        ns: &Address,
+        connect_opts: &ConnectOpts,
+        flow_stat: Arc<FlowStat>,
+        tls: &DnsTlsConnector,
+    ) -> io::Result<DnsClient> {
+        let stream = ProxyClientStream::connect_with_opts_map(context, svr_cfg, ns, connect_opts, |s| {
+            MonProxyStream::from_stream(s, flow_stat)
+        })
+        .await?;
+        let stream = tls.connect(stream).await?;
+        Ok(DnsClient::TlsRemote { stream })
+    }
+
     /// Make a DNS lookup
     #[allow(dead_code)]
     pub async fn lookup(&mut self, mut msg: Message) -> Result<Message, ProtoError> {
@@ -195,6 +237,10 @@ impl DnsClient {
 
                 Message::from_vec(&recv_buf[..n])
             }
+            #[cfg(feature = "local-dns-over-tls")]
+            DnsClient::TlsLocal { ref mut stream } => stream_query(stream, msg).await,
+            #[cfg(feature = "local-dns-over-tls")]
+            DnsClient::TlsRemote { ref mut stream } => stream_query(stream, msg).await,
         }
     }
 
@@ -268,6 +314,10 @@ impl DnsClient {
             DnsClient::UnixStream { ref mut stream } => check_peekable(stream),
             DnsClient::TcpRemote { ref mut stream } => check_peekable(stream.get_mut().get_mut()),
             DnsClient::UdpRemote { .. } => true,
+            #[cfg(feature = "local-dns-over-tls")]
+            DnsClient::TlsLocal { ref mut stream } => check_peekable(stream.get_mut().0),
+            #[cfg(feature = "local-dns-over-tls")]
+            DnsClient::TlsRemote { ref mut stream } => check_peekable(stream.get_mut().0.get_mut().get_mut()),
         }
     }
 }
@@ -0,0 +1,190 @@
+//! Health statistic of DNS upstreams
+//!
+//! Upstreams that failed `quarantine_failures` times in a row are quarantined for `quarantine_duration`.
+//! Queries are sent to the other upstream if possible while it is quarantined.
+
+use std::{
+    fmt::{self, Display},
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use spin::Mutex as SpinMutex;
+
+/// Default consecutive failures before quarantining an upstream
+pub const DEFAULT_QUARANTINE_FAILURES: u32 = 5;
+/// Default quarantine duration
+pub const DEFAULT_QUARANTINE_DURATION: Duration = Duration::from_secs(60);
+
+/// Kind of DNS upstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsUpstreamKind {
+    /// Queried directly
+    Local,
+    /// Queried through proxy
+    Remote,
+    /// Name servers of rules, queried directly
+    NameServer,
+}
+
+impl DnsUpstreamKind {
+    /// Name of the kind
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            DnsUpstreamKind::Local => "local",
+            DnsUpstreamKind::Remote => "remote",
+            DnsUpstreamKind::NameServer => "name_server",
+        }
+    }
+}
+
+impl Display for DnsUpstreamKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Statistic of one DNS upstream
+#[derive(Debug)]
+pub struct DnsUpstreamStat {
+    kind: DnsUpstreamKind,
+    addr: String,
+    quarantine_failures: u32,
+    quarantine_duration: Duration,
+    queries: AtomicU64,
+    failures: AtomicU64,
+    consecutive_failures: AtomicU32,
+    quarantines: AtomicU64,
+    /// EWMA of successful queries' latency in milliseconds
+    latency: AtomicU32,
+    quarantined_until: SpinMutex<Option<Instant>>,
+}
+
+impl DnsUpstreamStat {
+    /// Create a statistic for upstream `addr`. `quarantine_failures` = 0 disables quarantine
+    pub fn new<A: Display>(
+        kind: DnsUpstreamKind,
+        addr: A,
+        quarantine_failures: u32,
+        quarantine_duration: Duration,
+    ) -> DnsUpstreamStat {
+        DnsUpstreamStat {
+            kind,
+            addr: addr.to_string(),
+            quarantine_failures,
+            quarantine_duration,
+            queries: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            consecutive_failures: AtomicU32::new(0),
+            quarantines: AtomicU64::new(0),
+            latency: AtomicU32::new(0),
+            quarantined_until: SpinMutex::new(None),
+        }
+    }
+
+    /// Kind of upstream
+    pub fn kind(&self) -> DnsUpstreamKind {
+        self.kind
+    }
+
+    /// Address of upstream
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Total queries
+    pub fn queries(&self) -> u64 {
+        self.queries.load(Ordering::Relaxed)
+    }
+
+    /// Total failed queries
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Failed queries since the last success
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures.load(Ordering::Relaxed)
+    }
+
+    /// Times of being quarantined
+    pub fn quarantines(&self) -> u64 {
+        self.quarantines.load(Ordering::Relaxed)
+    }
+
+    /// Success rate of all queries, 1.0 if no queries were made
+    pub fn success_rate(&self) -> f64 {
+        let queries = self.queries();
+        if queries == 0 {
+            return 1.0;
+        }
+        (queries - self.failures().min(queries)) as f64 / queries as f64
+    }
+
+    /// Average latency of successful queries in milliseconds
+    pub fn latency(&self) -> u32 {
+        self.latency.load(Ordering::Relaxed)
+    }
+
+    /// Remaining quarantine time, `None` if it is not quarantined
+    pub fn quarantine_remaining(&self) -> Option<Duration> {
+        let until = (*self.quarantined_until.lock())?;
+        until.checked_duration_since(Instant::now()).filter(|d| !d.is_zero())
+    }
+
+    /// Check if upstream is quarantined
+    pub fn is_quarantined(&self) -> bool {
+        self.quarantine_remaining().is_some()
+    }
+
+    /// Record a successful query
+    pub fn record_success(&self, elapsed: Duration) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+
+        let elapsed = elapsed.as_millis().min(u32::MAX as u128) as u32;
+        let latency = match self.latency() {
+            0 => elapsed,
+            prev => ((prev as u64 * 7 + elapsed as u64) / 8) as u32,
+        };
+        self.latency.store(latency, Ordering::Relaxed);
+
+        let failures = self.consecutive_failures.swap(0, Ordering::Relaxed);
+        let quarantined = self.quarantined_until.lock().take().is_some();
+        if quarantined || (self.quarantine_failures > 0 && failures >= self.quarantine_failures) {
+            info!(
+                "dns {} upstream {} recovered after {} consecutive failures",
+                self.kind, self.addr, failures
+            );
+        }
+    }
+
+    /// Record a failed query, quarantine the upstream if it failed too many times in a row
+    pub fn record_failure(&self) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if self.quarantine_failures == 0 || failures < self.quarantine_failures {
+            return;
+        }
+
+        let mut quarantined_until = self.quarantined_until.lock();
+        let now = Instant::now();
+        if matches!(*quarantined_until, Some(until) if until > now) {
+            return;
+        }
+        *quarantined_until = Some(now + self.quarantine_duration);
+        drop(quarantined_until);
+
+        self.quarantines.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "dns {} upstream {} quarantined for {:?} after {} consecutive failures, success rate {:.2}",
+            self.kind,
+            self.addr,
+            self.quarantine_duration,
+            failures,
+            self.success_rate()
+        );
+    }
+}
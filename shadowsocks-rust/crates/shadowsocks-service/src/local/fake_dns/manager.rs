@@ -2,32 +2,149 @@
 
 use std::{
     io,
-    iter::Cycle,
+    marker::PhantomData,
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
     path::Path,
+    str::{self, FromStr},
     time::{Duration, SystemTime},
 };
 
 use hickory_resolver::proto::rr::Name;
-use ipnet::{Ipv4AddrRange, Ipv4Net, Ipv6AddrRange, Ipv6Net};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use log::{trace, warn};
 use sled::{Config as SledConfig, Db as SledDatabase};
+use spin::Mutex as SpinMutex;
 use tokio::sync::Mutex;
 
 use super::proto;
 
 const FAKE_DNS_MANAGER_STORAGE_VERSION: u32 = 2;
 
+/// Default path of the database
+pub const DEFAULT_DATABASE_PATH: &str = "shadowsocks-fakedns.sled";
+
+/// Default expire duration of mappings
+pub const DEFAULT_EXPIRE_DURATION: Duration = Duration::from_secs(10);
+
+/// Default network of IPv4 fake addresses, 172.16.0.0/12
+pub fn default_ipv4_network() -> Ipv4Net {
+    Ipv4Net::new(Ipv4Addr::new(172, 16, 0, 0), 12).unwrap()
+}
+
+/// Default network of IPv6 fake addresses, fc00::/7
+pub fn default_ipv6_network() -> Ipv6Net {
+    Ipv6Net::new(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7).unwrap()
+}
+
+const IPV4_ALLOC_KEY: &str = "shadowsocks_fakedns_alloc_ipv4";
+const IPV6_ALLOC_KEY: &str = "shadowsocks_fakedns_alloc_ipv6";
+
+/// Addresses allocated by `AddrAllocator`
+trait AllocAddr: Copy + Into<IpAddr> {
+    fn into_u128(self) -> u128;
+    fn from_u128(v: u128) -> Self;
+}
+
+impl AllocAddr for Ipv4Addr {
+    fn into_u128(self) -> u128 {
+        u32::from(self) as u128
+    }
+
+    fn from_u128(v: u128) -> Ipv4Addr {
+        Ipv4Addr::from(v as u32)
+    }
+}
+
+impl AllocAddr for Ipv6Addr {
+    fn into_u128(self) -> u128 {
+        u128::from(self)
+    }
+
+    fn from_u128(v: u128) -> Ipv6Addr {
+        Ipv6Addr::from(v)
+    }
+}
+
+/// Allocates host addresses of a network cyclically, skipping excluded networks
+struct AddrAllocator<A> {
+    first: u128,
+    last: u128,
+    next: u128,
+    _marker: PhantomData<A>,
+}
+
+impl<A: AllocAddr> AddrAllocator<A> {
+    /// Starts from the one after `last`, so addresses that may be cached by clients are not reused right after restarts
+    fn new<R>(mut hosts: R, last: Option<A>) -> AddrAllocator<A>
+    where
+        R: DoubleEndedIterator<Item = A>,
+    {
+        let first = hosts.next().map_or(0, A::into_u128);
+        let end = hosts.next_back().map_or(first, A::into_u128);
+
+        let mut allocator = AddrAllocator {
+            first,
+            last: end,
+            next: first,
+            _marker: PhantomData,
+        };
+        if let Some(last) = last.map(A::into_u128) {
+            if first <= last && last <= end {
+                allocator.next = allocator.after(last);
+            }
+        }
+        allocator
+    }
+
+    fn after(&self, v: u128) -> u128 {
+        if v >= self.last {
+            self.first
+        } else {
+            v + 1
+        }
+    }
+
+    /// Next address that is not in `excluded`, `None` if all addresses are excluded
+    fn next(&mut self, excluded: &[IpNet]) -> Option<A> {
+        // Each excluded network is jumped over at most once in a round
+        for _ in 0..excluded.len() + 2 {
+            let addr = A::from_u128(self.next);
+            let ip: IpAddr = addr.into();
+            match excluded.iter().find(|net| net.contains(&ip)) {
+                None => {
+                    self.next = self.after(self.next);
+                    return Some(addr);
+                }
+                Some(net) => {
+                    let broadcast = match net.broadcast() {
+                        IpAddr::V4(v4) => v4.into_u128(),
+                        IpAddr::V6(v6) => v6.into_u128(),
+                    };
+                    self.next = self.after(broadcast);
+                }
+            }
+        }
+        None
+    }
+}
+
+fn last_allocated<A: FromStr>(db: &SledDatabase, key: &str) -> io::Result<Option<A>> {
+    Ok(db
+        .get(key)?
+        .and_then(|v| str::from_utf8(&v).ok().and_then(|s| s.parse::<A>().ok())))
+}
+
 /// Fake DNS manager
 pub struct FakeDnsManager {
     db: SledDatabase,
-    ipv4_network: Mutex<Cycle<Ipv4AddrRange>>,
-    ipv6_network: Mutex<Cycle<Ipv6AddrRange>>,
+    ipv4_network: Mutex<AddrAllocator<Ipv4Addr>>,
+    ipv6_network: Mutex<AddrAllocator<Ipv6Addr>>,
+    excluded_networks: SpinMutex<Vec<IpNet>>,
     expire_duration: Duration,
 }
 
 macro_rules! map_domain_ip {
-    ($self:ident, $domain:ident, $addr_ty:ty, $addr_field:ident, $network_field:ident) => {{
+    ($self:ident, $domain:ident, $addr_ty:ty, $addr_field:ident, $network_field:ident, $alloc_key:expr) => {{
         let name2ip_key = FakeDnsManager::get_name2ip_key($domain);
 
         loop {
@@ -40,6 +157,9 @@ macro_rules! map_domain_ip {
 
                 if !domain_name_mapping.$addr_field.is_empty() {
                     match domain_name_mapping.$addr_field.parse::<$addr_ty>() {
+                        Ok(i) if $self.is_excluded(i.into()) => {
+                            trace!("fakedns mapping {} -> {} is excluded, going to replace", $domain, i);
+                        }
                         Ok(i) => {
                             let now = FakeDnsManager::get_current_timestamp();
                             let expire_secs =
@@ -98,8 +218,18 @@ macro_rules! map_domain_ip {
                 }
             }
 
-            // Allocate a new IPv4 address for this domain
-            'alloc_network: while let Some(ip) = $self.$network_field.lock().await.next() {
+            // Allocate a new address for this domain
+            let excluded = $self.excluded_networks.lock().clone();
+            'alloc_network: loop {
+                let mut allocator = $self.$network_field.lock().await;
+                let ip = match allocator.next(&excluded) {
+                    Some(ip) => ip,
+                    None => {
+                        return Err(io::Error::other(
+                            "all fakedns addresses are excluded by upstream networks",
+                        ));
+                    }
+                };
                 let ip2name_key = FakeDnsManager::get_ip2name_key(ip.into());
 
                 loop {
@@ -132,6 +262,7 @@ macro_rules! map_domain_ip {
                             .db
                             .compare_and_swap(&name2ip_key, name2ip_value.as_ref(), Some(nv))
                         {
+                            $self.db.insert($alloc_key, ip.to_string().as_bytes())?;
                             trace!(
                                 "fakedns mapping {} -> {}, expires {} created",
                                 $domain,
@@ -206,14 +337,30 @@ impl FakeDnsManager {
             trace!("FakeDNS database created. {:?}", c);
         }
 
+        let ipv4_allocator = AddrAllocator::new(ipv4_network.hosts(), last_allocated(&db, IPV4_ALLOC_KEY)?);
+        let ipv6_allocator = AddrAllocator::new(ipv6_network.hosts(), last_allocated(&db, IPV6_ALLOC_KEY)?);
+
         Ok(FakeDnsManager {
             db,
-            ipv4_network: Mutex::new(ipv4_network.hosts().cycle()),
-            ipv6_network: Mutex::new(ipv6_network.hosts().cycle()),
+            ipv4_network: Mutex::new(ipv4_allocator),
+            ipv6_network: Mutex::new(ipv6_allocator),
+            excluded_networks: SpinMutex::new(Vec::new()),
             expire_duration,
         })
     }
 
+    /// Exclude addresses of `networks` from fake addresses, like prefixes of the upstream network that may overlap
+    /// with the configured networks
+    ///
+    /// Mappings of excluded addresses are reallocated, and excluded addresses are no longer mapped back to names.
+    pub fn set_excluded_networks(&self, networks: Vec<IpNet>) {
+        *self.excluded_networks.lock() = networks;
+    }
+
+    fn is_excluded(&self, ip: IpAddr) -> bool {
+        self.excluded_networks.lock().iter().any(|net| net.contains(&ip))
+    }
+
     #[inline]
     fn get_current_timestamp() -> i64 {
         SystemTime::now()
@@ -234,62 +381,77 @@ impl FakeDnsManager {
 
     /// Get or create an IPv4 mapping for `domain`
     pub async fn map_domain_ipv4(&self, domain: &Name) -> io::Result<(Ipv4Addr, Duration)> {
-        map_domain_ip!(self, domain, Ipv4Addr, ipv4_addr, ipv4_network)
+        map_domain_ip!(self, domain, Ipv4Addr, ipv4_addr, ipv4_network, IPV4_ALLOC_KEY)
     }
 
     /// Get or create an IPv6 mapping for `domain`
     pub async fn map_domain_ipv6(&self, domain: &Name) -> io::Result<(Ipv6Addr, Duration)> {
-        map_domain_ip!(self, domain, Ipv6Addr, ipv6_addr, ipv6_network)
+        map_domain_ip!(self, domain, Ipv6Addr, ipv6_addr, ipv6_network, IPV6_ALLOC_KEY)
     }
 
     /// Get IP mapped domain name
+    ///
+    /// Expired mappings are still used if their addresses are not allocated to other names, like mappings that were
+    /// expired while the service was stopped.
     pub async fn map_ip_domain(&self, ip: IpAddr) -> io::Result<Option<Name>> {
-        let ip2name_key = FakeDnsManager::get_ip2name_key(ip);
+        if self.is_excluded(ip) {
+            return Ok(None);
+        }
 
-        let ip2name_value = self.db.get(&ip2name_key)?;
-        match ip2name_value {
-            None => Ok(None),
-            Some(ref v) => {
-                let mut ip_mapping = proto::IpAddrMapping::decode(v)?;
+        let ip2name_key = FakeDnsManager::get_ip2name_key(ip);
 
-                let now = FakeDnsManager::get_current_timestamp();
-                if ip_mapping.expire_time >= now {
-                    // Ok. It is not expired yet. Try to extend its expire time.
-                    ip_mapping.expire_time = now + self.expire_duration.as_secs() as i64;
-                    let nv = ip_mapping.encode_to_vec()?;
-                    let _ = self
-                        .db
-                        .compare_and_swap(&ip2name_key, ip2name_value.as_ref(), Some(nv))?;
+        let ip2name_value = match self.db.get(&ip2name_key)? {
+            None => return Ok(None),
+            Some(v) => v,
+        };
+        let mut ip_mapping = proto::IpAddrMapping::decode(&ip2name_value)?;
+
+        let name = match ip_mapping.domain_name.parse::<Name>() {
+            Ok(n) => n,
+            Err(..) => return Ok(None),
+        };
+
+        let name2ip_key = FakeDnsManager::get_name2ip_key(&name);
+        let name2ip_value = match self.db.get(&name2ip_key)? {
+            Some(v) => v,
+            None => {
+                // Interesting. No name2ip.
+                return Ok(None);
+            }
+        };
+        let mut domain_name_mapping = proto::DomainNameMapping::decode(&name2ip_value)?;
+
+        let now = FakeDnsManager::get_current_timestamp();
+        if ip_mapping.expire_time < now {
+            let mapped_addr = match ip {
+                IpAddr::V4(..) => &domain_name_mapping.ipv4_addr,
+                IpAddr::V6(..) => &domain_name_mapping.ipv6_addr,
+            };
+            if *mapped_addr != ip.to_string() {
+                return Ok(None);
+            }
+            trace!("fakedns mapping {} -> {} expired, reusing", name, ip);
+        }
 
-                    // Update name2ip's expire time
+        // Try to extend its expire time
+        ip_mapping.expire_time = now + self.expire_duration.as_secs() as i64;
+        let nv = ip_mapping.encode_to_vec()?;
+        let _ = self.db.compare_and_swap(&ip2name_key, Some(ip2name_value), Some(nv))?;
 
-                    let name = match ip_mapping.domain_name.parse::<Name>() {
-                        Ok(n) => n,
-                        Err(..) => return Ok(None),
-                    };
+        // Update name2ip's expire time
+        domain_name_mapping.expire_time = ip_mapping.expire_time;
+        let nv = domain_name_mapping.encode_to_vec()?;
+        let _ = self.db.compare_and_swap(&name2ip_key, Some(name2ip_value), Some(nv));
 
-                    {
-                        let name2ip_key = FakeDnsManager::get_name2ip_key(&name);
-                        let name2ip_value = self.db.get(&name2ip_key)?;
-                        match name2ip_value {
-                            Some(ref v) => {
-                                let mut domain_name_mapping = proto::DomainNameMapping::decode(v)?;
-                                domain_name_mapping.expire_time = ip_mapping.expire_time;
-                                let nv = domain_name_mapping.encode_to_vec()?;
-                                let _ = self.db.compare_and_swap(&name2ip_key, name2ip_value.as_ref(), Some(nv));
-                            }
-                            None => {
-                                // Interesting. No name2ip.
-                                return Ok(None);
-                            }
-                        }
-                    }
+        Ok(Some(name))
+    }
+}
 
-                    Ok(Some(name))
-                } else {
-                    Ok(None)
-                }
-            }
+impl Drop for FakeDnsManager {
+    fn drop(&mut self) {
+        // Mappings are flushed periodically, flush the latest ones before exiting
+        if let Err(err) = self.db.flush() {
+            warn!("failed to flush fakedns database, error: {}", err);
         }
     }
 }
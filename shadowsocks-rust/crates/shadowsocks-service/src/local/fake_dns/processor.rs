@@ -13,6 +13,7 @@ use log::{debug, trace};
 
 use super::manager::FakeDnsManager;
 
+/// Answer `req_message` with fake addresses allocated by `manager`
 pub async fn handle_dns_request(req_message: &Message, manager: &FakeDnsManager) -> io::Result<Message> {
     let mut rsp_message = Message::new();
     let rsp_header = Header::response_from_request(req_message.header());
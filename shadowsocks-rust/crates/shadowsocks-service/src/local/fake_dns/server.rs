@@ -2,7 +2,6 @@
 
 use std::{
     io,
-    net::{Ipv4Addr, Ipv6Addr},
     path::{Path, PathBuf},
     sync::Arc,
     time::Duration,
@@ -14,7 +13,13 @@ use shadowsocks::config::{Mode, ServerAddr};
 
 use crate::local::context::ServiceContext;
 
-use super::{manager::FakeDnsManager, tcp_server::FakeDnsTcpServer, udp_server::FakeDnsUdpServer};
+use super::{
+    manager::{
+        default_ipv4_network, default_ipv6_network, FakeDnsManager, DEFAULT_DATABASE_PATH, DEFAULT_EXPIRE_DURATION,
+    },
+    tcp_server::FakeDnsTcpServer,
+    udp_server::FakeDnsUdpServer,
+};
 
 /// Fake DNS builder
 pub struct FakeDnsBuilder {
@@ -40,10 +45,10 @@ impl FakeDnsBuilder {
             context,
             mode: Mode::TcpAndUdp,
             client_addr,
-            database_path: "shadowsocks-fakedns.sled".into(),
-            ipv4_network: Ipv4Net::new(Ipv4Addr::new(172, 16, 0, 0), 12).unwrap(),
-            ipv6_network: Ipv6Net::new(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7).unwrap(),
-            expire_duration: Duration::from_secs(10),
+            database_path: DEFAULT_DATABASE_PATH.into(),
+            ipv4_network: default_ipv4_network(),
+            ipv6_network: default_ipv6_network(),
+            expire_duration: DEFAULT_EXPIRE_DURATION,
         }
     }
 
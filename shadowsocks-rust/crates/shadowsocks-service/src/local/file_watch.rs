@@ -0,0 +1,245 @@
+//! Watching external files referenced by the configuration
+//!
+//! Subsystems register their files with a reload function. Changes are debounced together,
+//! then each subsystem with changed files is reloaded once, and the result is reported as a `FileWatchEvent`.
+//!
+//! Parent directories are watched instead of files, so files that are replaced by renaming
+//! (which is what most editors and tools do) are still tracked.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, future, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::{error, info, trace, warn};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::{self, Duration, Instant},
+};
+
+use crate::config::FileWatchConfig;
+
+use super::context::ServiceContext;
+
+/// Capacity of unreceived events of each subscriber
+const FILE_WATCH_EVENT_CHANNEL_SIZE: usize = 16;
+
+type ReloadFn = Box<dyn Fn() -> io::Result<()> + Send + Sync>;
+
+/// Result of applying changes of a subsystem's files
+#[derive(Debug, Clone)]
+pub struct FileWatchEvent {
+    /// Name of the subsystem
+    pub subsystem: Arc<str>,
+    /// Files that changed
+    pub paths: Vec<PathBuf>,
+    /// Error of reloading, the previous state is kept
+    pub error: Option<String>,
+}
+
+impl fmt::Display for FileWatchEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.error {
+            None => write!(f, "{} reloaded after {:?} changed", self.subsystem, self.paths),
+            Some(ref err) => write!(
+                f,
+                "{} failed to reload after {:?} changed, error: {}",
+                self.subsystem, self.paths, err
+            ),
+        }
+    }
+}
+
+struct WatchedSubsystem {
+    name: Arc<str>,
+    /// Files, or directories whose entries are all watched
+    paths: Vec<PathBuf>,
+    reload: ReloadFn,
+}
+
+impl WatchedSubsystem {
+    fn is_affected_by(&self, path: &Path) -> bool {
+        self.paths
+            .iter()
+            .any(|p| path == p || (p.is_dir() && path.starts_with(p)))
+    }
+}
+
+/// Watcher of files of all subsystems
+pub struct FileWatcher {
+    debounce: Duration,
+    subsystems: Vec<WatchedSubsystem>,
+    event_tx: broadcast::Sender<FileWatchEvent>,
+}
+
+impl FileWatcher {
+    /// Create a watcher without any files
+    pub fn new(config: &FileWatchConfig) -> FileWatcher {
+        let (event_tx, _) = broadcast::channel(FILE_WATCH_EVENT_CHANNEL_SIZE);
+        FileWatcher {
+            debounce: config.debounce,
+            subsystems: Vec::new(),
+            event_tx,
+        }
+    }
+
+    /// Watch `paths` of `subsystem`, `reload` is called after any of them changed
+    pub fn watch<N, F>(&mut self, subsystem: N, paths: Vec<PathBuf>, reload: F)
+    where
+        N: Into<Arc<str>>,
+        F: Fn() -> io::Result<()> + Send + Sync + 'static,
+    {
+        let paths: Vec<PathBuf> = paths.iter().map(|p| absolute_path(p)).collect();
+        if paths.is_empty() {
+            return;
+        }
+
+        self.subsystems.push(WatchedSubsystem {
+            name: subsystem.into(),
+            paths,
+            reload: Box::new(reload),
+        });
+    }
+
+    /// Watch ACL file, rule sets and GeoIP lists of `context`'s ACL, it is replaced after any of them changed
+    pub fn watch_acl<N>(&mut self, subsystem: N, context: &ServiceContext)
+    where
+        N: Into<Arc<str>>,
+    {
+        let acl = match context.acl() {
+            Some(acl) => acl,
+            None => return,
+        };
+
+        let sources = acl.rule_sources();
+        let mut paths = Vec::new();
+        paths.extend(acl.file_path().map(Path::to_path_buf));
+        paths.extend(sources.rule_sets.iter().map(|rule_set| rule_set.path.clone()));
+        paths.extend(sources.geoip_dir.clone());
+
+        let context = context.clone();
+        self.watch(subsystem, paths, move || {
+            let acl = match context.acl() {
+                Some(acl) => acl,
+                None => return Ok(()),
+            };
+            context.replace_acl(Arc::new(acl.reload()?));
+            Ok(())
+        });
+    }
+
+    /// Subscribe results of applying changes
+    pub fn subscribe(&self) -> broadcast::Receiver<FileWatchEvent> {
+        self.event_tx.subscribe()
+    }
+
+    /// Check if there is no file to watch
+    pub fn is_empty(&self) -> bool {
+        self.subsystems.is_empty()
+    }
+
+    /// Start watching
+    pub async fn run(self) -> io::Result<()> {
+        if let Err(err) = self.run_watcher().await {
+            // Serving shouldn't be stopped, files are just not watched anymore
+            error!("file watcher exited with error: {}", err);
+        }
+        future::pending().await
+    }
+
+    async fn run_watcher(self) -> notify::Result<()> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |ev: notify::Result<Event>| {
+            let _ = tx.send(ev);
+        })?;
+
+        let mut directories = BTreeSet::new();
+        for subsystem in &self.subsystems {
+            for path in &subsystem.paths {
+                if path.is_dir() {
+                    directories.insert(path.clone());
+                } else if let Some(parent) = path.parent() {
+                    directories.insert(parent.to_path_buf());
+                }
+            }
+        }
+        for directory in directories {
+            match watcher.watch(&directory, RecursiveMode::NonRecursive) {
+                Ok(..) => trace!("watching directory {}", directory.display()),
+                Err(err) => warn!("failed to watch directory {}, error: {}", directory.display(), err),
+            }
+        }
+
+        info!(
+            "watching files of {}",
+            self.subsystems
+                .iter()
+                .map(|s| s.name.as_ref())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        // Changed files of each subsystem, applied after `deadline`
+        let mut pending: BTreeMap<usize, BTreeSet<PathBuf>> = BTreeMap::new();
+        let mut deadline = Instant::now();
+
+        loop {
+            tokio::select! {
+                ev = rx.recv() => {
+                    let ev = match ev {
+                        Some(Ok(ev)) => ev,
+                        Some(Err(err)) => {
+                            warn!("file watcher error: {}", err);
+                            continue;
+                        }
+                        None => return Ok(()),
+                    };
+                    if matches!(ev.kind, EventKind::Access(..)) {
+                        continue;
+                    }
+
+                    for path in ev.paths {
+                        for (idx, subsystem) in self.subsystems.iter().enumerate() {
+                            if subsystem.is_affected_by(&path) {
+                                trace!("{} changed, {:?}", path.display(), ev.kind);
+                                pending.entry(idx).or_default().insert(path.clone());
+                                deadline = Instant::now() + self.debounce;
+                            }
+                        }
+                    }
+                }
+
+                _ = time::sleep_until(deadline), if !pending.is_empty() => {
+                    for (idx, paths) in std::mem::take(&mut pending) {
+                        let subsystem = &self.subsystems[idx];
+                        let event = FileWatchEvent {
+                            subsystem: subsystem.name.clone(),
+                            paths: paths.into_iter().collect(),
+                            error: (subsystem.reload)().err().map(|err| err.to_string()),
+                        };
+
+                        if event.error.is_none() {
+                            info!("{}", event);
+                        } else {
+                            error!("{}", event);
+                        }
+                        let _ = self.event_tx.send(event);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn absolute_path(path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        return path.to_path_buf();
+    }
+    match std::env::current_dir() {
+        Ok(dir) => dir.join(path),
+        Err(..) => path.to_path_buf(),
+    }
+}
@@ -0,0 +1,447 @@
+//! Active / standby pairing of two instances
+//!
+//! Both instances exchange heartbeats over UDP. Only the active one opens local listeners, the standby takes over
+//! when heartbeats of the active are lost for `failover_timeout`. The active stays active when the other one comes
+//! back, it starts as standby.
+//!
+//! If both are active (e.g. the heartbeat link was broken), the one with lower priority steps down.
+//!
+//! Heartbeats are authenticated by HMAC-SHA256 with the shared secret, covering the sequence number and the sending
+//! time, so forged heartbeats are dropped, and replayed ones are dropped by their sequence numbers and times.
+//!
+//! The standby serves the `ha` and `ping` commands on the control socket, other commands are only served by the
+//! active. UDP associations are not handed over, clients have to associate again after failover.
+
+use std::{
+    io,
+    process::Command,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use log::{debug, error, info, trace, warn};
+use serde::Serialize;
+use sha2::Sha256;
+use shadowsocks::{
+    config::{ManagerAddr, ServerType},
+    context::Context,
+    manager::datagram::ManagerDatagram,
+    relay::udprelay::MAXIMUM_UDP_PAYLOAD_SIZE,
+};
+use spin::Mutex as SpinMutex;
+use tokio::{net::UdpSocket, time};
+
+use crate::config::{Config, HaConfig};
+
+use super::Server;
+
+const HEARTBEAT_MAGIC: &[u8; 4] = b"SSHA";
+const HEARTBEAT_VERSION: u8 = 2;
+/// Length of the authenticated part
+const HEARTBEAT_DATA_LEN: usize = 31;
+const HEARTBEAT_MAC_LEN: usize = 32;
+const HEARTBEAT_LEN: usize = HEARTBEAT_DATA_LEN + HEARTBEAT_MAC_LEN;
+
+/// Heartbeats sent earlier or later than this (by clocks of the pair) are dropped
+const HEARTBEAT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// State of an instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HaState {
+    /// Waiting for the active instance to fail
+    Standby,
+    /// Serving local listeners
+    Active,
+}
+
+impl HaState {
+    /// Name of the state
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            HaState::Standby => "standby",
+            HaState::Active => "active",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Heartbeat {
+    state: HaState,
+    priority: u8,
+    node_id: u64,
+    /// Incremented in every heartbeat of a node
+    seq: u64,
+    /// Sending time in milliseconds since UNIX epoch
+    timestamp: u64,
+}
+
+impl Heartbeat {
+    fn encode(&self, secret: &[u8]) -> [u8; HEARTBEAT_LEN] {
+        let mut buf = [0u8; HEARTBEAT_LEN];
+        buf[0..4].copy_from_slice(HEARTBEAT_MAGIC);
+        buf[4] = HEARTBEAT_VERSION;
+        buf[5] = (self.state == HaState::Active) as u8;
+        buf[6] = self.priority;
+        buf[7..15].copy_from_slice(&self.node_id.to_be_bytes());
+        buf[15..23].copy_from_slice(&self.seq.to_be_bytes());
+        buf[23..31].copy_from_slice(&self.timestamp.to_be_bytes());
+
+        let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+        mac.update(&buf[..HEARTBEAT_DATA_LEN]);
+        buf[HEARTBEAT_DATA_LEN..].copy_from_slice(&mac.finalize().into_bytes());
+        buf
+    }
+
+    /// Decode an authenticated heartbeat, `None` if it is malformed or its MAC doesn't match
+    fn decode(buf: &[u8], secret: &[u8]) -> Option<Heartbeat> {
+        if buf.len() != HEARTBEAT_LEN || &buf[0..4] != HEARTBEAT_MAGIC || buf[4] != HEARTBEAT_VERSION {
+            return None;
+        }
+
+        let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+        mac.update(&buf[..HEARTBEAT_DATA_LEN]);
+        mac.verify_slice(&buf[HEARTBEAT_DATA_LEN..]).ok()?;
+
+        let read_u64 = |b: &[u8]| {
+            let mut v = [0u8; 8];
+            v.copy_from_slice(b);
+            u64::from_be_bytes(v)
+        };
+        Some(Heartbeat {
+            state: if buf[5] != 0 { HaState::Active } else { HaState::Standby },
+            priority: buf[6],
+            node_id: read_u64(&buf[7..15]),
+            seq: read_u64(&buf[15..23]),
+            timestamp: read_u64(&buf[23..31]),
+        })
+    }
+
+    /// Rank of instances for choosing the active one, node ID breaks the tie of priority
+    fn rank(&self) -> (u8, u64) {
+        (self.priority, self.node_id)
+    }
+}
+
+/// Milliseconds since UNIX epoch
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[derive(Serialize)]
+struct HaStats {
+    state: &'static str,
+    priority: u8,
+    /// The latest heartbeat of the peer, `null` if none was received
+    peer: Option<HaPeerStats>,
+}
+
+#[derive(Serialize)]
+struct HaPeerStats {
+    state: &'static str,
+    priority: u8,
+    /// Milliseconds since the latest heartbeat was received
+    last_heartbeat: u64,
+    /// Heartbeats were received in `failover_timeout`
+    alive: bool,
+}
+
+/// State of the pair, shared with the control socket
+pub struct HaStatus {
+    priority: u8,
+    failover_timeout: Duration,
+    state: SpinMutex<(HaState, Option<(Heartbeat, Instant)>)>,
+}
+
+impl HaStatus {
+    /// Current state of this instance
+    pub fn state(&self) -> HaState {
+        self.state.lock().0
+    }
+
+    /// Dump state of this instance and the peer, in JSON
+    pub fn to_json(&self) -> String {
+        let (state, peer) = *self.state.lock();
+        let stats = HaStats {
+            state: state.as_str(),
+            priority: self.priority,
+            peer: peer.map(|(heartbeat, received)| HaPeerStats {
+                state: heartbeat.state.as_str(),
+                priority: heartbeat.priority,
+                last_heartbeat: received.elapsed().as_millis() as u64,
+                alive: received.elapsed() < self.failover_timeout,
+            }),
+        };
+
+        match serde_json::to_string(&stats) {
+            Ok(rsp) => rsp,
+            Err(err) => format!("err: {err}"),
+        }
+    }
+}
+
+/// An instance of the pair
+pub struct HaNode {
+    config: HaConfig,
+    socket: UdpSocket,
+    node_id: u64,
+    seq: u64,
+    state: HaState,
+    started: Instant,
+    peer: Option<(Heartbeat, Instant)>,
+    status: Arc<HaStatus>,
+}
+
+impl HaNode {
+    /// Bind the heartbeat socket, the instance starts as standby
+    pub async fn bind(config: HaConfig) -> io::Result<HaNode> {
+        let socket = UdpSocket::bind(config.listen_addr).await?;
+
+        info!(
+            "ha heartbeat listening on {}, peer {}, priority {}",
+            config.listen_addr, config.peer_addr, config.priority
+        );
+
+        let status = Arc::new(HaStatus {
+            priority: config.priority,
+            failover_timeout: config.failover_timeout,
+            state: SpinMutex::new((HaState::Standby, None)),
+        });
+
+        Ok(HaNode {
+            config,
+            socket,
+            node_id: rand::random(),
+            seq: 0,
+            state: HaState::Standby,
+            started: Instant::now(),
+            peer: None,
+            status,
+        })
+    }
+
+    /// Current state
+    pub fn state(&self) -> HaState {
+        self.state
+    }
+
+    /// State of the pair, which is kept updated by `next_state`
+    pub fn status(&self) -> Arc<HaStatus> {
+        self.status.clone()
+    }
+
+    /// Exchange heartbeats until the state changes, returns the new state
+    pub async fn next_state(&mut self) -> io::Result<HaState> {
+        let mut interval = time::interval(self.config.heartbeat_interval);
+        let mut buffer = [0u8; 128];
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    self.seq += 1;
+                    let heartbeat = self.heartbeat().encode(self.config.secret.as_bytes());
+                    if let Err(err) = self.socket.send_to(&heartbeat, self.config.peer_addr).await {
+                        debug!("ha heartbeat to {} failed, error: {}", self.config.peer_addr, err);
+                    }
+                }
+                r = self.socket.recv_from(&mut buffer) => {
+                    let (n, addr) = match r {
+                        Ok(r) => r,
+                        Err(err) => {
+                            // ICMP port unreachable of the heartbeat sent to a dead peer, on some platforms
+                            trace!("ha heartbeat recv failed, error: {}", err);
+                            continue;
+                        }
+                    };
+
+                    if addr.ip() != self.config.peer_addr.ip() {
+                        warn!("ha ignored heartbeat from {}, which is not the peer", addr);
+                        continue;
+                    }
+
+                    let heartbeat = match Heartbeat::decode(&buffer[..n], self.config.secret.as_bytes()) {
+                        Some(heartbeat) => heartbeat,
+                        None => {
+                            warn!("ha ignored malformed or unauthenticated heartbeat from {}", addr);
+                            continue;
+                        }
+                    };
+
+                    if unix_millis().abs_diff(heartbeat.timestamp) > HEARTBEAT_MAX_CLOCK_SKEW.as_millis() as u64 {
+                        warn!(
+                            "ha ignored heartbeat from {}, which was sent at {} (ms), too far from now",
+                            addr, heartbeat.timestamp
+                        );
+                        continue;
+                    }
+                    if let Some((ref last, _)) = self.peer {
+                        if last.node_id == heartbeat.node_id && heartbeat.seq <= last.seq {
+                            warn!("ha ignored replayed heartbeat from {}, seq {}", addr, heartbeat.seq);
+                            continue;
+                        }
+                    }
+
+                    self.peer = Some((heartbeat, Instant::now()));
+                }
+            }
+
+            let state = self.evaluate();
+            *self.status.state.lock() = (state, self.peer);
+            if state != self.state {
+                self.state = state;
+                return Ok(state);
+            }
+        }
+    }
+
+    fn heartbeat(&self) -> Heartbeat {
+        Heartbeat {
+            state: self.state,
+            priority: self.config.priority,
+            node_id: self.node_id,
+            seq: self.seq,
+            timestamp: unix_millis(),
+        }
+    }
+
+    fn evaluate(&self) -> HaState {
+        let peer = self
+            .peer
+            .as_ref()
+            .filter(|(_, received)| received.elapsed() < self.config.failover_timeout)
+            .map(|(heartbeat, _)| heartbeat);
+        let outranks = |peer: &Heartbeat| self.heartbeat().rank() > peer.rank();
+
+        match (self.state, peer) {
+            // Wait for heartbeats of an active peer before taking over
+            (HaState::Standby, None) if self.started.elapsed() < self.config.failover_timeout => HaState::Standby,
+            (_, None) => HaState::Active,
+            (HaState::Standby, Some(peer)) => match peer.state {
+                HaState::Active => HaState::Standby,
+                // Both are starting
+                HaState::Standby if outranks(peer) => HaState::Active,
+                HaState::Standby => HaState::Standby,
+            },
+            (HaState::Active, Some(peer)) => match peer.state {
+                HaState::Standby => HaState::Active,
+                // Split brain
+                HaState::Active if outranks(peer) => HaState::Active,
+                HaState::Active => HaState::Standby,
+            },
+        }
+    }
+
+    /// Run the notify command of `state`
+    async fn notify(&self, state: HaState) {
+        let command = match state {
+            HaState::Active => self.config.notify_active.clone(),
+            HaState::Standby => self.config.notify_standby.clone(),
+        };
+        let command = match command {
+            Some(c) => c,
+            None => return,
+        };
+
+        let result = tokio::task::spawn_blocking(move || {
+            #[cfg(not(windows))]
+            let mut cmd = {
+                let mut cmd = Command::new("sh");
+                cmd.arg("-c").arg(&command);
+                cmd
+            };
+            #[cfg(windows)]
+            let mut cmd = {
+                let mut cmd = Command::new("cmd");
+                cmd.arg("/C").arg(&command);
+                cmd
+            };
+            cmd.env("SS_HA_STATE", state.as_str()).status().map(|s| (command, s))
+        })
+        .await;
+
+        match result {
+            Ok(Ok((_, status))) if status.success() => {}
+            Ok(Ok((command, status))) => error!("ha notify command \"{}\" exited with {}", command, status),
+            Ok(Err(err)) => error!("ha notify command failed to run, error: {}", err),
+            Err(err) => error!("ha notify command panicked, error: {}", err),
+        }
+    }
+}
+
+/// Serve the control socket while standby, only `ha` and `ping` are served
+async fn serve_standby_control(bind_addr: &ManagerAddr, status: &HaStatus) -> io::Result<()> {
+    let context = Context::new(ServerType::Local);
+    let listener = ManagerDatagram::bind(&context, bind_addr).await?;
+    info!("ha standby control socket listening on {:?}", listener.local_addr()?);
+
+    let mut buffer = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+    loop {
+        let (n, peer_addr) = match listener.recv_from(&mut buffer).await {
+            Ok(r) => r,
+            Err(err) => {
+                error!("ha standby control recv_from error: {}", err);
+                continue;
+            }
+        };
+
+        let rsp = match std::str::from_utf8(&buffer[..n]).map(str::trim) {
+            Ok("ha") => status.to_json(),
+            Ok("ping") => "pong".to_owned(),
+            Ok(..) => "err: instance is standby".to_owned(),
+            Err(..) => "err: request is not UTF-8".to_owned(),
+        };
+
+        if peer_addr.is_unnamed() {
+            continue;
+        }
+
+        if let Err(err) = listener.send_to(rsp.as_bytes(), &peer_addr).await {
+            warn!("ha standby control send_to {:?} failed, error: {}", peer_addr, err);
+        }
+    }
+}
+
+/// Run local server `config` as one of the pair configured by `ha`
+///
+/// Listeners are opened when the instance becomes active, and closed when it steps down
+pub async fn run(config: Config, ha: HaConfig) -> io::Result<()> {
+    let mut node = HaNode::bind(ha).await?;
+    let status = node.status();
+
+    loop {
+        // The control socket is closed before the active one is bound on the same address
+        let standby_control = async {
+            match config.local_control_addr {
+                Some(ref addr) => serve_standby_control(addr, &status).await,
+                None => std::future::pending().await,
+            }
+        };
+        let become_active = async {
+            while node.next_state().await? != HaState::Active {}
+            Ok::<_, io::Error>(())
+        };
+        tokio::select! {
+            r = become_active => r?,
+            r = standby_control => return r,
+        }
+
+        info!("ha instance became active, opening listeners");
+        node.notify(HaState::Active).await;
+
+        let server = Server::new_paired(config.clone(), Some(status.clone())).await?;
+        tokio::select! {
+            r = server.run() => return r,
+            r = node.next_state() => {
+                r?;
+            }
+        }
+
+        warn!("ha instance stepped down to standby, the peer is active, closing listeners");
+        node.notify(HaState::Standby).await;
+    }
+}
@@ -0,0 +1,102 @@
+//! History of completed sessions
+//!
+//! Relays record sessions with `SessionRecorder`s, which are sent to the background writer when sessions are
+//! closed. Relays never wait for the writer, records are dropped if it couldn't catch up.
+
+use std::{
+    mem,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use shadowsocks::{relay::socks5::Address, ServerAddr};
+use tokio::sync::mpsc;
+
+#[cfg(feature = "local-history")]
+pub use self::sqlite::{query_history, HistoryQuery};
+
+#[cfg(feature = "local-history")]
+mod sqlite;
+
+/// A completed session
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SessionRecord {
+    /// Unix timestamp (in millisec) when the session started
+    pub start_time: u64,
+    /// Duration (in millisec) of the session
+    pub duration: u64,
+    /// `tcp`
+    pub protocol: String,
+    /// Domain name or IP address of the target
+    pub host: String,
+    /// Port of the target
+    pub port: u16,
+    /// Server that the session was proxied through, `None` if it was bypassed
+    pub server: Option<String>,
+    /// Bytes sent to the target
+    pub uploaded: u64,
+    /// Bytes received from the target
+    pub downloaded: u64,
+}
+
+/// Recorder of completed sessions
+#[derive(Debug)]
+pub struct SessionHistory {
+    tx: mpsc::Sender<SessionRecord>,
+}
+
+impl SessionHistory {
+    /// Start recording a TCP session to `addr`, proxied through `server`
+    pub fn start_tcp_session(&self, addr: &Address, server: Option<&ServerAddr>) -> SessionRecorder {
+        let (host, port) = match *addr {
+            Address::SocketAddress(sa) => (sa.ip().to_string(), sa.port()),
+            Address::DomainNameAddress(ref dname, port) => (dname.clone(), port),
+        };
+
+        SessionRecorder {
+            tx: self.tx.clone(),
+            started: Instant::now(),
+            record: SessionRecord {
+                start_time: unix_millis(SystemTime::now()),
+                duration: 0,
+                protocol: "tcp".to_owned(),
+                host,
+                port,
+                server: server.map(ToString::to_string),
+                uploaded: 0,
+                downloaded: 0,
+            },
+        }
+    }
+}
+
+/// A session that is being recorded. The record is sent to the writer when dropped
+#[derive(Debug)]
+pub struct SessionRecorder {
+    tx: mpsc::Sender<SessionRecord>,
+    started: Instant,
+    record: SessionRecord,
+}
+
+impl SessionRecorder {
+    /// Data sent to the target
+    pub fn uploaded(&mut self, n: usize) {
+        self.record.uploaded += n as u64;
+    }
+
+    /// Data received from the target
+    pub fn downloaded(&mut self, n: usize) {
+        self.record.downloaded += n as u64;
+    }
+}
+
+impl Drop for SessionRecorder {
+    fn drop(&mut self) {
+        self.record.duration = self.started.elapsed().as_millis() as u64;
+        let _ = self.tx.try_send(mem::take(&mut self.record));
+    }
+}
+
+fn unix_millis(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
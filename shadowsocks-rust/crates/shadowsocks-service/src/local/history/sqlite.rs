@@ -0,0 +1,196 @@
+//! SQLite database of session history
+//!
+//! Records are written behind by a background thread, in one transaction for each batch.
+
+use std::{
+    io,
+    path::Path,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::{debug, error, info, warn};
+use rusqlite::{params, Connection, OpenFlags};
+use tokio::sync::mpsc;
+
+use crate::config::HistoryConfig;
+
+use super::{unix_millis, SessionHistory, SessionRecord};
+
+/// Pending records in the writer channel
+const HISTORY_CHANNEL_SIZE: usize = 4096;
+
+/// Maximum records written in one transaction
+const HISTORY_BATCH_SIZE: usize = 256;
+
+/// Interval of deleting records by retention policies
+const HISTORY_PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+const HISTORY_SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY,
+    start_time INTEGER NOT NULL,
+    duration INTEGER NOT NULL,
+    protocol TEXT NOT NULL,
+    host TEXT NOT NULL,
+    port INTEGER NOT NULL,
+    server TEXT,
+    uploaded INTEGER NOT NULL,
+    downloaded INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS sessions_start_time ON sessions (start_time);
+CREATE INDEX IF NOT EXISTS sessions_host ON sessions (host);
+";
+
+impl SessionHistory {
+    /// Open the database and start the background writer
+    pub fn open(config: HistoryConfig) -> io::Result<SessionHistory> {
+        let conn = Connection::open(&config.path).map_err(io::Error::other)?;
+        conn.execute_batch(HISTORY_SCHEMA).map_err(io::Error::other)?;
+
+        info!("recording session history into {}", config.path.display());
+
+        let (tx, rx) = mpsc::channel(HISTORY_CHANNEL_SIZE);
+        thread::Builder::new()
+            .name("session-history".to_owned())
+            .spawn(move || run_writer(conn, rx, config))?;
+
+        Ok(SessionHistory { tx })
+    }
+}
+
+fn run_writer(mut conn: Connection, mut rx: mpsc::Receiver<SessionRecord>, config: HistoryConfig) {
+    let mut last_pruned: Option<Instant> = None;
+    let mut batch = Vec::with_capacity(HISTORY_BATCH_SIZE);
+
+    while let Some(record) = rx.blocking_recv() {
+        batch.push(record);
+        while batch.len() < HISTORY_BATCH_SIZE {
+            match rx.try_recv() {
+                Ok(record) => batch.push(record),
+                Err(..) => break,
+            }
+        }
+
+        if let Err(err) = insert_records(&mut conn, &batch) {
+            error!(
+                "failed to write {} session records into {}, error: {}",
+                batch.len(),
+                config.path.display(),
+                err
+            );
+        }
+        batch.clear();
+
+        if last_pruned.map_or(true, |t| t.elapsed() >= HISTORY_PRUNE_INTERVAL) {
+            match prune_records(&conn, &config) {
+                Ok(n) => debug!("pruned {} session records from {}", n, config.path.display()),
+                Err(err) => warn!(
+                    "failed to prune session records from {}, error: {}",
+                    config.path.display(),
+                    err
+                ),
+            }
+            last_pruned = Some(Instant::now());
+        }
+    }
+}
+
+fn insert_records(conn: &mut Connection, records: &[SessionRecord]) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO sessions (start_time, duration, protocol, host, port, server, uploaded, downloaded)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )?;
+        for record in records {
+            stmt.execute(params![
+                record.start_time as i64,
+                record.duration as i64,
+                record.protocol,
+                record.host,
+                record.port,
+                record.server,
+                record.uploaded as i64,
+                record.downloaded as i64,
+            ])?;
+        }
+    }
+    tx.commit()
+}
+
+fn prune_records(conn: &Connection, config: &HistoryConfig) -> rusqlite::Result<usize> {
+    let mut pruned = 0;
+
+    if let Some(retention) = config.retention {
+        let before = unix_millis(SystemTime::now()).saturating_sub(retention.as_millis() as u64);
+        pruned += conn.execute("DELETE FROM sessions WHERE start_time < ?1", params![before as i64])?;
+    }
+
+    if let Some(max_records) = config.max_records {
+        pruned += conn.execute(
+            "DELETE FROM sessions WHERE id <= (SELECT id FROM sessions ORDER BY id DESC LIMIT 1 OFFSET ?1)",
+            params![max_records as i64],
+        )?;
+    }
+
+    Ok(pruned)
+}
+
+/// Filters of querying session history
+#[derive(Debug, Clone, Default)]
+pub struct HistoryQuery {
+    /// Sessions to this domain name (or IP address), including its subdomains
+    pub domain: Option<String>,
+    /// Sessions through this server, address in the same format of configuration
+    pub server: Option<String>,
+    /// Sessions started at or after this time
+    pub since: Option<SystemTime>,
+    /// Sessions started before this time
+    pub until: Option<SystemTime>,
+    /// Maximum records returned, latest first
+    pub limit: Option<usize>,
+}
+
+/// Query session history in database `path`
+pub fn query_history(path: &Path, query: &HistoryQuery) -> io::Result<Vec<SessionRecord>> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(io::Error::other)?;
+
+    let mut stmt = conn
+        .prepare(
+            "SELECT start_time, duration, protocol, host, port, server, uploaded, downloaded FROM sessions
+             WHERE (?1 IS NULL OR host = ?1 OR (length(host) > length(?1) AND substr(host, -length(?1) - 1) = '.' || ?1))
+               AND (?2 IS NULL OR server = ?2)
+               AND (?3 IS NULL OR start_time >= ?3)
+               AND (?4 IS NULL OR start_time < ?4)
+             ORDER BY start_time DESC
+             LIMIT ?5",
+        )
+        .map_err(io::Error::other)?;
+
+    let domain = query
+        .domain
+        .as_ref()
+        .map(|d| d.trim_end_matches('.').to_ascii_lowercase());
+    let since = query.since.map(|t| unix_millis(t) as i64);
+    let until = query.until.map(|t| unix_millis(t) as i64);
+    // Negative LIMIT means no limit
+    let limit = query.limit.map_or(-1, |l| l as i64);
+
+    let rows = stmt
+        .query_map(params![domain, query.server, since, until, limit], |row| {
+            Ok(SessionRecord {
+                start_time: row.get::<_, i64>(0)? as u64,
+                duration: row.get::<_, i64>(1)? as u64,
+                protocol: row.get(2)?,
+                host: row.get(3)?,
+                port: row.get(4)?,
+                server: row.get(5)?,
+                uploaded: row.get::<_, i64>(6)? as u64,
+                downloaded: row.get::<_, i64>(7)? as u64,
+            })
+        })
+        .map_err(io::Error::other)?;
+
+    rows.collect::<Result<Vec<_>, _>>().map_err(io::Error::other)
+}
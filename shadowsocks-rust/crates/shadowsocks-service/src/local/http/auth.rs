@@ -0,0 +1,634 @@
+//! HTTP proxy authentication
+//!
+//! Clients are authenticated by `Proxy-Authorization` with Basic (RFC 7617) or Digest (RFC 7616, MD5) scheme,
+//! and challenged by `407 Proxy Authentication Required` if they failed.
+//!
+//! Digest nonces are signed with a random key of the process and expired after `DIGEST_NONCE_LIFETIME`. Digest
+//! credentials must be computed for the request target, with `qop=auth` and a nonce count (`nc`) greater than the
+//! ones used with the same nonce before, so they can't be replayed.
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::Engine as _;
+use hyper::{
+    header::{self, HeaderValue},
+    HeaderMap, Method, Uri,
+};
+use md5::{Digest, Md5};
+use spin::Mutex as SpinMutex;
+
+use crate::net::utils::constant_time_eq;
+
+use super::config::HttpAuthConfig;
+
+/// Lifetime of Digest nonces, clients will retry with a new one (`stale=true`) after it expired
+const DIGEST_NONCE_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+/// Expired nonces are removed from nonce counts when there are more than this
+const DIGEST_NONCE_COUNTS_PRUNE_THRESHOLD: usize = 4096;
+
+/// Result of authentication
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpAuthResult {
+    /// Authenticated
+    Accepted,
+    /// Missing or invalid credentials
+    Rejected,
+    /// Valid Digest credentials with an expired nonce
+    Stale,
+}
+
+/// Authenticator of HTTP proxy clients
+#[derive(Debug)]
+pub struct HttpAuthenticator {
+    config: HttpAuthConfig,
+    nonce_key: [u8; 16],
+    /// The greatest nonce count used with each nonce
+    nonce_counts: SpinMutex<HashMap<String, u32>>,
+}
+
+impl HttpAuthenticator {
+    /// Create an authenticator of users in `config`
+    pub fn new(config: HttpAuthConfig) -> HttpAuthenticator {
+        HttpAuthenticator {
+            config,
+            nonce_key: rand::random(),
+            nonce_counts: SpinMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Authenticate request of `method` and `uri` (the request target) by its `Proxy-Authorization` headers
+    pub fn authenticate(&self, method: &Method, uri: &Uri, headers: &HeaderMap) -> HttpAuthResult {
+        let mut result = HttpAuthResult::Rejected;
+
+        for value in headers.get_all(header::PROXY_AUTHORIZATION) {
+            let value = match value.to_str() {
+                Ok(v) => v.trim(),
+                Err(..) => continue,
+            };
+            let (scheme, credentials) = match value.split_once(' ') {
+                Some((s, c)) => (s, c.trim()),
+                None => continue,
+            };
+
+            let r = if scheme.eq_ignore_ascii_case("Basic") && self.config.basic() {
+                self.authenticate_basic(credentials)
+            } else if scheme.eq_ignore_ascii_case("Digest") && self.config.digest() {
+                self.authenticate_digest(method, uri, credentials)
+            } else {
+                continue;
+            };
+
+            match r {
+                HttpAuthResult::Accepted => return r,
+                HttpAuthResult::Stale => result = r,
+                HttpAuthResult::Rejected => {}
+            }
+        }
+
+        result
+    }
+
+    /// `Proxy-Authenticate` challenges for 407 responses
+    pub fn challenges(&self, stale: bool) -> Vec<HeaderValue> {
+        let mut challenges = Vec::with_capacity(2);
+
+        if self.config.digest() {
+            let mut challenge = format!(
+                "Digest realm=\"{}\", qop=\"auth\", algorithm=MD5, nonce=\"{}\"",
+                self.config.realm(),
+                self.make_nonce(unix_secs())
+            );
+            if stale {
+                challenge.push_str(", stale=true");
+            }
+            if let Ok(challenge) = HeaderValue::from_str(&challenge) {
+                challenges.push(challenge);
+            }
+        }
+
+        if self.config.basic() {
+            let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.config.realm());
+            if let Ok(challenge) = HeaderValue::from_str(&challenge) {
+                challenges.push(challenge);
+            }
+        }
+
+        challenges
+    }
+
+    fn authenticate_basic(&self, credentials: &str) -> HttpAuthResult {
+        let decoded = match base64::engine::general_purpose::STANDARD.decode(credentials) {
+            Ok(d) => d,
+            Err(..) => return HttpAuthResult::Rejected,
+        };
+        let decoded = match String::from_utf8(decoded) {
+            Ok(d) => d,
+            Err(..) => return HttpAuthResult::Rejected,
+        };
+        let (user_name, password) = match decoded.split_once(':') {
+            Some(c) => c,
+            None => return HttpAuthResult::Rejected,
+        };
+
+        match self.config.password(user_name) {
+            Some(expected) if constant_time_eq(password.as_bytes(), expected.as_bytes()) => HttpAuthResult::Accepted,
+            _ => HttpAuthResult::Rejected,
+        }
+    }
+
+    fn authenticate_digest(&self, method: &Method, target: &Uri, credentials: &str) -> HttpAuthResult {
+        let mut user_name = None;
+        let mut realm = None;
+        let mut nonce = None;
+        let mut uri = None;
+        let mut response = None;
+        let mut algorithm = None;
+        let mut qop = None;
+        let mut nc = None;
+        let mut cnonce = None;
+
+        for (key, value) in parse_digest_params(credentials) {
+            let param = match key.to_ascii_lowercase().as_str() {
+                "username" => &mut user_name,
+                "realm" => &mut realm,
+                "nonce" => &mut nonce,
+                "uri" => &mut uri,
+                "response" => &mut response,
+                "algorithm" => &mut algorithm,
+                "qop" => &mut qop,
+                "nc" => &mut nc,
+                "cnonce" => &mut cnonce,
+                _ => continue,
+            };
+            *param = Some(value);
+        }
+
+        let (user_name, realm, nonce, uri, response) = match (user_name, realm, nonce, uri, response) {
+            (Some(u), Some(r), Some(n), Some(i), Some(p)) => (u, r, n, i, p),
+            _ => return HttpAuthResult::Rejected,
+        };
+        if realm != self.config.realm() {
+            return HttpAuthResult::Rejected;
+        }
+        // Credentials computed for another target
+        if uri != target.to_string() {
+            return HttpAuthResult::Rejected;
+        }
+        if let Some(ref algorithm) = algorithm {
+            if !algorithm.eq_ignore_ascii_case("MD5") {
+                return HttpAuthResult::Rejected;
+            }
+        }
+        let password = match self.config.password(&user_name) {
+            Some(p) => p,
+            None => return HttpAuthResult::Rejected,
+        };
+
+        let ha1 = md5_hex(&format!("{user_name}:{realm}:{password}"));
+        let ha2 = md5_hex(&format!("{method}:{uri}"));
+        // Without qop (RFC 2069), there is no nonce count against replays
+        let (nc, cnonce) = match (qop.as_deref(), nc, cnonce) {
+            (Some("auth"), Some(nc), Some(cnonce)) => (nc, cnonce),
+            _ => return HttpAuthResult::Rejected,
+        };
+        let nonce_count = match u32::from_str_radix(&nc, 16) {
+            Ok(n) if nc.len() == 8 => n,
+            _ => return HttpAuthResult::Rejected,
+        };
+        let expected = md5_hex(&format!("{ha1}:{nonce}:{nc}:{cnonce}:auth:{ha2}"));
+        if !constant_time_eq(response.to_ascii_lowercase().as_bytes(), expected.as_bytes()) {
+            return HttpAuthResult::Rejected;
+        }
+
+        // Credentials are valid, check if they were computed with our nonce
+        match self.nonce_timestamp(&nonce) {
+            Some(t) if unix_secs().saturating_sub(t) <= DIGEST_NONCE_LIFETIME.as_secs() => {
+                if self.update_nonce_count(nonce, nonce_count) {
+                    HttpAuthResult::Accepted
+                } else {
+                    HttpAuthResult::Rejected
+                }
+            }
+            Some(..) => HttpAuthResult::Stale,
+            None => HttpAuthResult::Rejected,
+        }
+    }
+
+    /// Record `nonce_count` of `nonce`, `false` if it is not greater than the ones used before, which is a replay
+    fn update_nonce_count(&self, nonce: String, nonce_count: u32) -> bool {
+        let mut nonce_counts = self.nonce_counts.lock();
+
+        if nonce_counts.len() >= DIGEST_NONCE_COUNTS_PRUNE_THRESHOLD && !nonce_counts.contains_key(&nonce) {
+            let now = unix_secs();
+            nonce_counts.retain(|nonce, _| {
+                nonce_timestamp_unchecked(nonce)
+                    .is_some_and(|t| now.saturating_sub(t) <= DIGEST_NONCE_LIFETIME.as_secs())
+            });
+        }
+
+        match nonce_counts.get_mut(&nonce) {
+            Some(last) if nonce_count <= *last => false,
+            Some(last) => {
+                *last = nonce_count;
+                true
+            }
+            None => {
+                nonce_counts.insert(nonce, nonce_count);
+                true
+            }
+        }
+    }
+
+    /// Nonce is `timestamp:signature`, signature is MD5 of the timestamp and `nonce_key`
+    fn make_nonce(&self, timestamp: u64) -> String {
+        let mut hasher = Md5::new();
+        hasher.update(timestamp.to_be_bytes());
+        hasher.update(self.nonce_key);
+        format!("{:x}:{}", timestamp, to_hex(&hasher.finalize()))
+    }
+
+    fn nonce_timestamp(&self, nonce: &str) -> Option<u64> {
+        let timestamp = nonce_timestamp_unchecked(nonce)?;
+        if constant_time_eq(nonce.as_bytes(), self.make_nonce(timestamp).as_bytes()) {
+            Some(timestamp)
+        } else {
+            None
+        }
+    }
+}
+
+/// Timestamp of `nonce`, without checking its signature
+fn nonce_timestamp_unchecked(nonce: &str) -> Option<u64> {
+    let (timestamp, _) = nonce.split_once(':')?;
+    u64::from_str_radix(timestamp, 16).ok()
+}
+
+/// Parse `key=value, key="quoted value"` parameters of Digest credentials
+fn parse_digest_params(mut s: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+
+    loop {
+        s = s.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        let (key, rest) = match s.split_once('=') {
+            Some((k, r)) => (k.trim(), r.trim_start()),
+            None => break,
+        };
+
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = None;
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some((_, c)) = chars.next() {
+                            value.push(c);
+                        }
+                    }
+                    '"' => {
+                        end = Some(i + 1);
+                        break;
+                    }
+                    c => value.push(c),
+                }
+            }
+
+            match end {
+                Some(end) => {
+                    s = &quoted[end..];
+                    value
+                }
+                // Unterminated quoted string
+                None => break,
+            }
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            s = &rest[end..];
+            rest[..end].trim().to_owned()
+        };
+
+        params.push((key.to_owned(), value));
+    }
+
+    params
+}
+
+fn md5_hex(s: &str) -> String {
+    to_hex(&Md5::digest(s.as_bytes()))
+}
+
+fn to_hex(buf: &[u8]) -> String {
+    let mut s = String::with_capacity(buf.len() * 2);
+    for b in buf {
+        let _ = write!(s, "{b:02x}");
+    }
+    s
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        env, fs, process,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+
+    // RFC 7616 3.9.1
+    const REALM: &str = "http-auth@example.org";
+    const USER_NAME: &str = "Mufasa";
+    const PASSWORD: &str = "Circle of Life";
+    const URI: &str = "/dir/index.html";
+    const CNONCE: &str = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+
+    fn authenticator() -> HttpAuthenticator {
+        static FILE_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let path = env::temp_dir().join(format!(
+            "shadowsocks-http-auth-test-{}-{}.json",
+            process::id(),
+            FILE_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(
+            &path,
+            format!(
+                r#"{{ "realm": "{REALM}", "users": [{{ "user_name": "{USER_NAME}", "password": "{PASSWORD}" }}] }}"#
+            ),
+        )
+        .unwrap();
+        let config = HttpAuthConfig::load_from_file(&path);
+        let _ = fs::remove_file(&path);
+        HttpAuthenticator::new(config.unwrap())
+    }
+
+    /// `response` of RFC 7616 MD5 with `qop=auth`
+    fn digest_response(realm: &str, password: &str, uri: &str, nonce: &str, nc: &str) -> String {
+        let ha1 = md5_hex(&format!("{USER_NAME}:{realm}:{password}"));
+        let ha2 = md5_hex(&format!("GET:{uri}"));
+        md5_hex(&format!("{ha1}:{nonce}:{nc}:{CNONCE}:auth:{ha2}"))
+    }
+
+    fn digest_headers(realm: &str, uri: &str, nonce: &str, nc: &str, response: &str) -> HeaderMap {
+        let value = format!(
+            "Digest username=\"{USER_NAME}\", realm=\"{realm}\", uri=\"{uri}\", algorithm=MD5, nonce=\"{nonce}\", \
+             nc={nc}, cnonce=\"{CNONCE}\", qop=auth, response=\"{response}\""
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert(header::PROXY_AUTHORIZATION, HeaderValue::from_str(&value).unwrap());
+        headers
+    }
+
+    fn authenticate_digest(auth: &HttpAuthenticator, realm: &str, uri: &str, nonce: &str, nc: &str) -> HttpAuthResult {
+        let response = digest_response(realm, PASSWORD, uri, nonce, nc);
+        let headers = digest_headers(realm, uri, nonce, nc, &response);
+        auth.authenticate(&Method::GET, &URI.parse().unwrap(), &headers)
+    }
+
+    #[test]
+    fn test_parse_digest_params() {
+        let params = parse_digest_params(
+            r#"username="Mufasa", realm="a \"quoted\" \\realm", nc=00000001 ,qop=auth,, uri="/a, b""#,
+        );
+        let expected = [
+            ("username", "Mufasa"),
+            ("realm", r#"a "quoted" \realm"#),
+            ("nc", "00000001"),
+            ("qop", "auth"),
+            ("uri", "/a, b"),
+        ];
+        assert_eq!(params.len(), expected.len());
+        for ((key, value), (ekey, evalue)) in params.iter().zip(expected) {
+            assert_eq!(key, ekey);
+            assert_eq!(value, evalue);
+        }
+
+        // Parameters after an unterminated quoted string are dropped
+        let params = parse_digest_params(r#"username="Mufasa", realm="unterminated, nc=00000001"#);
+        assert_eq!(params, [("username".to_owned(), "Mufasa".to_owned())]);
+        let params = parse_digest_params(r#"response="abc\"#);
+        assert!(params.is_empty());
+
+        assert!(parse_digest_params("").is_empty());
+        assert!(parse_digest_params("no parameters").is_empty());
+    }
+
+    #[test]
+    fn test_digest_rfc7616_accepted() {
+        // Example of RFC 7616 3.9.1 with its own nonce
+        assert_eq!(
+            digest_response(
+                REALM,
+                PASSWORD,
+                URI,
+                "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v",
+                "00000001"
+            ),
+            "8ca523f5e9506fed4657c9700eebdbec"
+        );
+
+        let auth = authenticator();
+        let nonce = auth.make_nonce(unix_secs());
+        assert_eq!(
+            authenticate_digest(&auth, REALM, URI, &nonce, "00000001"),
+            HttpAuthResult::Accepted
+        );
+
+        // Upper case response
+        let response = digest_response(REALM, PASSWORD, URI, &nonce, "00000002").to_ascii_uppercase();
+        let headers = digest_headers(REALM, URI, &nonce, "00000002", &response);
+        assert_eq!(
+            auth.authenticate(&Method::GET, &URI.parse().unwrap(), &headers),
+            HttpAuthResult::Accepted
+        );
+    }
+
+    #[test]
+    fn test_digest_rejected() {
+        let auth = authenticator();
+        let nonce = auth.make_nonce(unix_secs());
+
+        // Credentials for another target
+        assert_eq!(
+            authenticate_digest(&auth, REALM, "/other.html", &nonce, "00000001"),
+            HttpAuthResult::Rejected
+        );
+        // Another realm
+        assert_eq!(
+            authenticate_digest(&auth, "shadowsocks", URI, &nonce, "00000001"),
+            HttpAuthResult::Rejected
+        );
+        // Wrong password
+        let response = digest_response(REALM, "Circle of Death", URI, &nonce, "00000001");
+        let headers = digest_headers(REALM, URI, &nonce, "00000001", &response);
+        assert_eq!(
+            auth.authenticate(&Method::GET, &URI.parse().unwrap(), &headers),
+            HttpAuthResult::Rejected
+        );
+        // Computed for another method
+        let response = digest_response(REALM, PASSWORD, URI, &nonce, "00000001");
+        let headers = digest_headers(REALM, URI, &nonce, "00000001", &response);
+        assert_eq!(
+            auth.authenticate(&Method::POST, &URI.parse().unwrap(), &headers),
+            HttpAuthResult::Rejected
+        );
+        // Malformed nonce count
+        assert_eq!(
+            authenticate_digest(&auth, REALM, URI, &nonce, "1"),
+            HttpAuthResult::Rejected
+        );
+        // None of them consumed the nonce count
+        assert_eq!(
+            authenticate_digest(&auth, REALM, URI, &nonce, "00000001"),
+            HttpAuthResult::Accepted
+        );
+    }
+
+    #[test]
+    fn test_digest_nonce_count_replay() {
+        let auth = authenticator();
+        let nonce = auth.make_nonce(unix_secs());
+
+        assert_eq!(
+            authenticate_digest(&auth, REALM, URI, &nonce, "00000002"),
+            HttpAuthResult::Accepted
+        );
+        // Replayed
+        assert_eq!(
+            authenticate_digest(&auth, REALM, URI, &nonce, "00000002"),
+            HttpAuthResult::Rejected
+        );
+        // Lower
+        assert_eq!(
+            authenticate_digest(&auth, REALM, URI, &nonce, "00000001"),
+            HttpAuthResult::Rejected
+        );
+        assert_eq!(
+            authenticate_digest(&auth, REALM, URI, &nonce, "0000000a"),
+            HttpAuthResult::Accepted
+        );
+
+        assert!(auth.update_nonce_count("nonce".to_owned(), 5));
+        assert!(!auth.update_nonce_count("nonce".to_owned(), 5));
+        assert!(!auth.update_nonce_count("nonce".to_owned(), 4));
+        assert!(auth.update_nonce_count("nonce".to_owned(), 6));
+        assert!(auth.update_nonce_count("another".to_owned(), 1));
+    }
+
+    #[test]
+    fn test_digest_forged_nonce() {
+        let auth = authenticator();
+        let now = unix_secs();
+        let nonce = auth.make_nonce(now);
+
+        let (timestamp, signature) = nonce.split_once(':').unwrap();
+        let forged = format!("{}:{}", timestamp, "0".repeat(signature.len()));
+        assert_eq!(auth.nonce_timestamp(&forged), None);
+        assert_eq!(
+            authenticate_digest(&auth, REALM, URI, &forged, "00000001"),
+            HttpAuthResult::Rejected
+        );
+
+        // Signature of another timestamp
+        let forged = format!("{:x}:{}", now + 60, signature);
+        assert_eq!(
+            authenticate_digest(&auth, REALM, URI, &forged, "00000001"),
+            HttpAuthResult::Rejected
+        );
+
+        // Signed by another process
+        let nonce = authenticator().make_nonce(now);
+        assert_eq!(
+            authenticate_digest(&auth, REALM, URI, &nonce, "00000001"),
+            HttpAuthResult::Rejected
+        );
+
+        for nonce in ["", ":", "xyz:abc", "no-timestamp"] {
+            assert_eq!(auth.nonce_timestamp(nonce), None);
+        }
+    }
+
+    #[test]
+    fn test_digest_stale_nonce() {
+        let auth = authenticator();
+        let nonce = auth.make_nonce(unix_secs() - DIGEST_NONCE_LIFETIME.as_secs() - 1);
+        assert_eq!(
+            authenticate_digest(&auth, REALM, URI, &nonce, "00000001"),
+            HttpAuthResult::Stale
+        );
+
+        let challenges = auth.challenges(true);
+        assert!(challenges[0].to_str().unwrap().starts_with("Digest "));
+        assert!(challenges[0].to_str().unwrap().ends_with(", stale=true"));
+        assert!(challenges[1].to_str().unwrap().starts_with("Basic "));
+    }
+
+    #[test]
+    fn test_digest_nonce_counts_prune() {
+        let auth = authenticator();
+        let now = unix_secs();
+        let expired = now - DIGEST_NONCE_LIFETIME.as_secs() - 1;
+
+        for i in 0..DIGEST_NONCE_COUNTS_PRUNE_THRESHOLD as u64 - 2 {
+            assert!(auth.update_nonce_count(format!("{:x}:{}", expired, i), 1));
+        }
+        assert!(auth.update_nonce_count(format!("{:x}:live", now), 1));
+        assert!(auth.update_nonce_count(format!("{:x}:live", now + 1), 1));
+        assert_eq!(auth.nonce_counts.lock().len(), DIGEST_NONCE_COUNTS_PRUNE_THRESHOLD);
+
+        // Existing nonces don't prune
+        assert!(auth.update_nonce_count(format!("{:x}:live", now), 2));
+        assert_eq!(auth.nonce_counts.lock().len(), DIGEST_NONCE_COUNTS_PRUNE_THRESHOLD);
+
+        // Expired ones are removed before inserting a new one
+        assert!(auth.update_nonce_count(format!("{:x}:new", now), 1));
+        let nonce_counts = auth.nonce_counts.lock();
+        assert_eq!(nonce_counts.len(), 3);
+        assert_eq!(nonce_counts.get(&format!("{:x}:live", now)), Some(&2));
+    }
+
+    #[test]
+    fn test_basic() {
+        let auth = authenticator();
+        let uri = URI.parse().unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::PROXY_AUTHORIZATION,
+            HeaderValue::from_static("Basic TXVmYXNhOkNpcmNsZSBvZiBMaWZl"),
+        );
+        assert_eq!(
+            auth.authenticate(&Method::GET, &uri, &headers),
+            HttpAuthResult::Accepted
+        );
+
+        for value in [
+            "Basic TXVmYXNhOkNpcmNsZQ==",
+            "Basic !!!",
+            "Basic TXVmYXNh",
+            "Bearer TXVmYXNhOkNpcmNsZSBvZiBMaWZl",
+        ] {
+            headers.insert(header::PROXY_AUTHORIZATION, HeaderValue::from_static(value));
+            assert_eq!(
+                auth.authenticate(&Method::GET, &uri, &headers),
+                HttpAuthResult::Rejected,
+                "{value}"
+            );
+        }
+        assert_eq!(
+            auth.authenticate(&Method::GET, &uri, &HeaderMap::new()),
+            HttpAuthResult::Rejected
+        );
+    }
+}
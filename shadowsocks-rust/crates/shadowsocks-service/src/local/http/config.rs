@@ -0,0 +1,189 @@
+//! HTTP proxy configuration
+
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::{self, Read},
+    path::Path,
+};
+
+use log::trace;
+use serde::Deserialize;
+
+use crate::local::socks::config::Socks5AuthConfig;
+
+/// Default realm of authentication challenges
+pub const DEFAULT_HTTP_AUTH_REALM: &str = "shadowsocks";
+
+#[derive(Deserialize, Debug)]
+struct SSHttpAuthUserConfig {
+    user_name: String,
+    password: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct SSHttpAuthConfig {
+    #[serde(default)]
+    realm: Option<String>,
+    #[serde(default)]
+    basic: Option<bool>,
+    #[serde(default)]
+    digest: Option<bool>,
+    users: Vec<SSHttpAuthUserConfig>,
+}
+
+/// HTTP proxy authentication (`Proxy-Authorization`) configuration
+#[derive(Debug, Clone)]
+pub struct HttpAuthConfig {
+    realm: String,
+    basic: bool,
+    digest: bool,
+    users: HashMap<String, String>,
+}
+
+impl HttpAuthConfig {
+    /// Create an empty configuration, which accepts any clients
+    pub fn new() -> HttpAuthConfig {
+        HttpAuthConfig {
+            realm: DEFAULT_HTTP_AUTH_REALM.to_owned(),
+            basic: true,
+            digest: true,
+            users: HashMap::new(),
+        }
+    }
+
+    /// Load from configuration file
+    ///
+    /// ```json
+    /// {
+    ///     "realm": "shadowsocks",
+    ///     "basic": true,
+    ///     "digest": true,
+    ///     "users": [
+    ///         {
+    ///             "user_name": "USER_NAME",
+    ///             "password": "PASSWORD"
+    ///         }
+    ///     ]
+    /// }
+    /// ```
+    pub fn load_from_file<P: AsRef<Path> + ?Sized>(filename: &P) -> io::Result<HttpAuthConfig> {
+        let filename = filename.as_ref();
+
+        trace!("loading http authentication configuration from {}", filename.display());
+
+        let mut reader = OpenOptions::new().read(true).open(filename)?;
+        let mut content = String::new();
+        reader.read_to_string(&mut content)?;
+
+        let jconf: SSHttpAuthConfig = match json5::from_str(&content) {
+            Ok(c) => c,
+            Err(err) => return Err(io::Error::other(err)),
+        };
+
+        let mut config = HttpAuthConfig::new();
+        if let Some(realm) = jconf.realm {
+            if realm.contains('"') || realm.contains('\\') {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "realm of http authentication must not contain '\"' or '\\'",
+                ));
+            }
+            config.realm = realm;
+        }
+        if let Some(basic) = jconf.basic {
+            config.basic = basic;
+        }
+        if let Some(digest) = jconf.digest {
+            config.digest = digest;
+        }
+        if !config.basic && !config.digest {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "at least one of basic and digest http authentication must be enabled",
+            ));
+        }
+
+        for user in jconf.users {
+            if user.user_name.contains(':') {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "user_name {} of http authentication must not contain ':'",
+                        user.user_name
+                    ),
+                ));
+            }
+            config.add_user(user.user_name, user.password);
+        }
+
+        Ok(config)
+    }
+
+    /// Accept the same users of SOCKS5 authentication, for HTTP requests served on a SOCKS port
+    pub fn from_socks5_auth(socks5_auth: &Socks5AuthConfig) -> HttpAuthConfig {
+        let mut config = HttpAuthConfig::new();
+        for (user_name, password) in socks5_auth.passwd.users() {
+            config.add_user(user_name, password);
+        }
+        config
+    }
+
+    /// Add a user with password
+    pub fn add_user<U, P>(&mut self, user_name: U, password: P)
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        self.users.insert(user_name.into(), password.into());
+    }
+
+    /// Password of `user_name`
+    pub fn password<U>(&self, user_name: U) -> Option<&str>
+    where
+        U: AsRef<str>,
+    {
+        self.users.get(user_name.as_ref()).map(String::as_str)
+    }
+
+    /// Realm of authentication challenges
+    pub fn realm(&self) -> &str {
+        &self.realm
+    }
+
+    /// Set realm of authentication challenges
+    pub fn set_realm<R: Into<String>>(&mut self, realm: R) {
+        self.realm = realm.into();
+    }
+
+    /// Check if Basic scheme is accepted
+    pub fn basic(&self) -> bool {
+        self.basic
+    }
+
+    /// Accept Basic scheme, which sends passwords in plain text
+    pub fn set_basic(&mut self, basic: bool) {
+        self.basic = basic;
+    }
+
+    /// Check if Digest scheme is accepted
+    pub fn digest(&self) -> bool {
+        self.digest
+    }
+
+    /// Accept Digest scheme
+    pub fn set_digest(&mut self, digest: bool) {
+        self.digest = digest;
+    }
+
+    /// Check if authentication is required
+    pub fn auth_required(&self) -> bool {
+        !self.users.is_empty()
+    }
+}
+
+impl Default for HttpAuthConfig {
+    fn default() -> HttpAuthConfig {
+        HttpAuthConfig::new()
+    }
+}
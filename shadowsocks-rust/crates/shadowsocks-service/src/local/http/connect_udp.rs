@@ -0,0 +1,263 @@
+//! Proxying UDP in HTTP (RFC 9298)
+//!
+//! Clients request `/.well-known/masque/udp/{target_host}/{target_port}/` with
+//! - HTTP/1.1 `Upgrade: connect-udp`
+//! - HTTP/2 extended CONNECT (RFC 8441) with `:protocol` = `connect-udp`
+//!
+//! UDP payloads are carried in DATAGRAM capsules (RFC 9297) on the request stream, context ID 0.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use hyper::{ext::Protocol, header, upgrade::Upgraded, Method, Request, Uri, Version};
+use log::{debug, trace};
+use shadowsocks::relay::{udprelay::MAXIMUM_UDP_PAYLOAD_SIZE, Address};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    sync::mpsc,
+};
+
+use crate::local::{
+    context::ServiceContext,
+    loadbalancing::PingBalancer,
+    net::{UdpAssociationManager, UdpInboundWrite},
+};
+
+use super::tokio_rt::TokioIo;
+
+/// Upgrade token and `:protocol` of UDP proxying
+pub const CONNECT_UDP_PROTOCOL: &str = "connect-udp";
+
+/// Header indicating that the request stream carries capsules, `?1`
+pub const CAPSULE_PROTOCOL_HEADER: &str = "Capsule-Protocol";
+
+/// Default URI template path, `/.well-known/masque/udp/{target_host}/{target_port}/`
+const CONNECT_UDP_PATH_PREFIX: &str = "/.well-known/masque/udp/";
+
+/// Capsule type of HTTP Datagrams
+const CAPSULE_TYPE_DATAGRAM: u64 = 0x00;
+
+/// Pending datagrams from target to client
+const CONNECT_UDP_CHANNEL_SIZE: usize = 64;
+
+/// Check if `req` is a UDP proxying request
+pub fn is_connect_udp_request<B>(req: &Request<B>) -> bool {
+    match req.version() {
+        Version::HTTP_2 | Version::HTTP_3 => {
+            req.method() == Method::CONNECT
+                && req
+                    .extensions()
+                    .get::<Protocol>()
+                    .map(|p| p.as_str().eq_ignore_ascii_case(CONNECT_UDP_PROTOCOL))
+                    .unwrap_or(false)
+        }
+        Version::HTTP_11 => {
+            req.method() == Method::GET
+                && req.headers().get_all(header::UPGRADE).iter().any(|value| {
+                    value
+                        .to_str()
+                        .map(|v| {
+                            v.split(',')
+                                .any(|p| p.trim().eq_ignore_ascii_case(CONNECT_UDP_PROTOCOL))
+                        })
+                        .unwrap_or(false)
+                })
+        }
+        _ => false,
+    }
+}
+
+/// Target of UDP proxying from `/.well-known/masque/udp/{target_host}/{target_port}/`
+pub fn connect_udp_target(uri: &Uri) -> Option<Address> {
+    let path = uri.path().strip_prefix(CONNECT_UDP_PATH_PREFIX)?;
+    let path = path.strip_suffix('/').unwrap_or(path);
+    let (host, port) = path.split_once('/')?;
+
+    let host = percent_decode(host)?;
+    let port = port.parse::<u16>().ok()?;
+    if host.is_empty() || port == 0 {
+        return None;
+    }
+
+    // IPv6 addresses are percent-encoded without brackets, like 2001%3Adb8%3A%3A42
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Some(Address::from(SocketAddr::new(ip, port))),
+        Err(..) => Some(Address::DomainNameAddress(host, port)),
+    }
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Relay datagrams between the upgraded stream of `peer_addr` and `target`
+pub async fn serve_connect_udp(
+    context: Arc<ServiceContext>,
+    balancer: PingBalancer,
+    peer_addr: SocketAddr,
+    target: Address,
+    upgraded: Upgraded,
+    udp_expiry_duration: Option<Duration>,
+) -> io::Result<()> {
+    let (reader, mut writer) = tokio::io::split(TokioIo::new(upgraded));
+    let mut reader = BufReader::new(reader);
+
+    let (tx, mut rx) = mpsc::channel::<Bytes>(CONNECT_UDP_CHANNEL_SIZE);
+    let (mut manager, _, mut keepalive_rx) = UdpAssociationManager::new(
+        context,
+        ConnectUdpInboundWriter { tx },
+        udp_expiry_duration,
+        Some(1),
+        balancer,
+    );
+
+    let uplink = relay_uplink(&mut reader, &mut manager, &mut keepalive_rx, peer_addr, &target);
+    let downlink = async {
+        while let Some(capsule) = rx.recv().await {
+            writer.write_all(&capsule).await?;
+        }
+        Ok::<_, io::Error>(())
+    };
+
+    tokio::select! {
+        r = uplink => r,
+        r = downlink => r,
+    }
+}
+
+/// Sends UDP payloads of DATAGRAM capsules from client to `target`
+async fn relay_uplink<R>(
+    reader: &mut R,
+    manager: &mut UdpAssociationManager<ConnectUdpInboundWriter>,
+    keepalive_rx: &mut mpsc::Receiver<SocketAddr>,
+    peer_addr: SocketAddr,
+    target: &Address,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut payload = Vec::new();
+    loop {
+        let capsule_type = match read_varint(reader).await {
+            Ok(t) => t,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let length = read_varint(reader).await?;
+        if length > (MAXIMUM_UDP_PAYLOAD_SIZE + 8) as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("capsule length {length} too large"),
+            ));
+        }
+
+        payload.resize(length as usize, 0);
+        reader.read_exact(&mut payload).await?;
+
+        // Unknown capsules must be ignored
+        if capsule_type != CAPSULE_TYPE_DATAGRAM {
+            trace!(
+                "connect-udp {} -> {} ignored capsule type {:#x}",
+                peer_addr,
+                target,
+                capsule_type
+            );
+            continue;
+        }
+
+        let mut datagram = &payload[..];
+        let context_id = read_varint(&mut datagram).await?;
+        if context_id != 0 {
+            trace!(
+                "connect-udp {} -> {} dropped datagram of context ID {}",
+                peer_addr,
+                target,
+                context_id
+            );
+            continue;
+        }
+
+        while let Ok(addr) = keepalive_rx.try_recv() {
+            manager.keep_alive(&addr).await;
+        }
+
+        if let Err(err) = manager.send_to(peer_addr, target.clone(), datagram).await {
+            debug!(
+                "connect-udp {} -> {} payload.size: {} bytes failed, error: {}",
+                peer_addr,
+                target,
+                datagram.len(),
+                err
+            );
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ConnectUdpInboundWriter {
+    tx: mpsc::Sender<Bytes>,
+}
+
+#[async_trait]
+impl UdpInboundWrite for ConnectUdpInboundWriter {
+    async fn send_to(&self, _peer_addr: SocketAddr, _remote_addr: &Address, data: &[u8]) -> io::Result<()> {
+        // Context ID 0 and the UDP payload
+        let length = 1 + data.len() as u64;
+
+        let mut capsule = BytesMut::with_capacity(1 + 8 + length as usize);
+        put_varint(&mut capsule, CAPSULE_TYPE_DATAGRAM);
+        put_varint(&mut capsule, length);
+        put_varint(&mut capsule, 0);
+        capsule.put_slice(data);
+
+        self.tx
+            .send(capsule.freeze())
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "connect-udp stream closed"))
+    }
+}
+
+/// QUIC variable-length integer (RFC 9000 Section 16)
+async fn read_varint<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<u64> {
+    let first = reader.read_u8().await?;
+    let length = 1usize << (first >> 6);
+
+    let mut value = u64::from(first & 0x3F);
+    for _ in 1..length {
+        value = (value << 8) | u64::from(reader.read_u8().await?);
+    }
+    Ok(value)
+}
+
+fn put_varint(buf: &mut BytesMut, value: u64) {
+    if value < (1 << 6) {
+        buf.put_u8(value as u8);
+    } else if value < (1 << 14) {
+        buf.put_u16(0x4000 | value as u16);
+    } else if value < (1 << 30) {
+        buf.put_u32(0x8000_0000 | value as u32);
+    } else {
+        buf.put_u64(0xC000_0000_0000_0000 | value);
+    }
+}
@@ -13,7 +13,7 @@ use std::{
 
 use hyper::{
     body::{self, Body},
-    client::conn::{http1, http2},
+    client::conn::{http1, http2, TrySendError},
     http::uri::Scheme,
     rt::{Sleep, Timer},
     Request, Response,
@@ -24,7 +24,11 @@ use pin_project::pin_project;
 use shadowsocks::relay::Address;
 use tokio::sync::Mutex;
 
-use crate::local::{context::ServiceContext, loadbalancing::PingBalancer, net::AutoProxyClientStream};
+use crate::local::{
+    context::ServiceContext,
+    loadbalancing::{PingBalancer, ServerIdent},
+    net::AutoProxyClientStream,
+};
 
 use super::{
     http_stream::ProxyHttpStream,
@@ -34,6 +38,25 @@ use super::{
 
 const CONNECTION_EXPIRE_DURATION: Duration = Duration::from_secs(20);
 
+/// Maximum cached connections of each origin
+const CONNECTION_CACHE_PER_HOST: usize = 8;
+
+/// Connections are reused by requests with the same scheme and host
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct ConnectionKey {
+    https: bool,
+    host: Address,
+}
+
+/// How connections of requests are made
+#[derive(Clone, Copy)]
+enum ConnectVia<'a> {
+    /// Bypassed or proxied by ACL rules, bypassed if no balancer is given
+    Balancer(Option<&'a PingBalancer>),
+    /// Always proxied through the server
+    Server(&'a ServerIdent),
+}
+
 /// HTTPClient API request errors
 #[derive(thiserror::Error, Debug)]
 pub enum HttpClientError {
@@ -93,7 +116,7 @@ impl TokioSleep {
 /// HTTPClient, supporting HTTP/1.1 and H2, HTTPS.
 pub struct HttpClient<B> {
     #[allow(clippy::type_complexity)]
-    cache_conn: Arc<Mutex<LruCache<Address, VecDeque<(HttpConnection<B>, Instant)>>>>,
+    cache_conn: Arc<Mutex<LruCache<ConnectionKey, VecDeque<(HttpConnection<B>, Instant)>>>>,
 }
 
 impl<B> Clone for HttpClient<B> {
@@ -141,14 +164,54 @@ where
             None => panic!("URI missing host: {}", req.uri()),
         };
 
+        self.send_request_via(context, req, host, ConnectVia::Balancer(balancer))
+            .await
+    }
+
+    /// Make HTTP requests through `server`, connecting to `host` instead of the host in URI
+    ///
+    /// The host in URI is still used for TLS server name and HTTP `Host`, like requests to bootstrap addresses
+    pub async fn send_request_proxied(
+        &self,
+        context: Arc<ServiceContext>,
+        req: Request<B>,
+        server: &ServerIdent,
+        host: Address,
+    ) -> Result<Response<body::Incoming>, HttpClientError> {
+        self.send_request_via(context, req, host, ConnectVia::Server(server))
+            .await
+    }
+
+    async fn send_request_via(
+        &self,
+        context: Arc<ServiceContext>,
+        req: Request<B>,
+        host: Address,
+        via: ConnectVia<'_>,
+    ) -> Result<Response<body::Incoming>, HttpClientError> {
+        let key = ConnectionKey {
+            https: req.uri().scheme() == Some(&Scheme::HTTPS),
+            host: host.clone(),
+        };
+
         // 1. Check if there is an available client
-        //
-        // FIXME: If the cached connection is closed unexpectly, this request will fail immediately.
-        if let Some(c) = self.get_cached_connection(&host).await {
+        let mut req = req;
+        if let Some(c) = self.get_cached_connection(&key).await {
             trace!("HTTP client for host: {} taken from cache", host);
-            match self.send_request_conn(host, c, req).await {
+            match self.send_request_cached_conn(key.clone(), c, req).await {
                 Ok(o) => return Ok(o),
-                Err(err) => return Err(err.into()),
+                Err(mut err) => match err.take_message() {
+                    // Cached connection was closed before sending the request, it is safe to retry
+                    Some(r) => {
+                        trace!(
+                            "HTTP cached connection for host: {} closed, error: {}",
+                            host,
+                            err.into_error()
+                        );
+                        req = r;
+                    }
+                    None => return Err(err.into_error().into()),
+                },
             }
         }
 
@@ -164,7 +227,7 @@ where
             .unwrap()
             .trim_start_matches('[')
             .trim_start_matches(']');
-        let c = match HttpConnection::connect(context.clone(), scheme, host.clone(), domain, balancer).await {
+        let c = match HttpConnection::connect(context.clone(), scheme, host.clone(), domain, via).await {
             Ok(c) => c,
             Err(err) => {
                 error!("failed to connect to host: {}, error: {}", host, err);
@@ -172,51 +235,82 @@ where
             }
         };
 
-        self.send_request_conn(host, c, req).await.map_err(Into::into)
+        self.send_request_conn(key, c, req).await.map_err(Into::into)
     }
 
-    async fn get_cached_connection(&self, host: &Address) -> Option<HttpConnection<B>> {
-        if let Some(q) = self.cache_conn.lock().await.get_mut(host) {
-            while let Some((c, inst)) = q.pop_front() {
-                let now = Instant::now();
-                if now - inst >= CONNECTION_EXPIRE_DURATION {
-                    continue;
-                }
-                if c.is_closed() {
-                    continue;
-                }
-                return Some(c);
-            }
+    async fn get_cached_connection(&self, key: &ConnectionKey) -> Option<HttpConnection<B>> {
+        let mut cache = self.cache_conn.lock().await;
+        let q = cache.get_mut(key)?;
+
+        let now = Instant::now();
+        q.retain(|(c, inst)| now - *inst < CONNECTION_EXPIRE_DURATION && !c.is_closed());
+
+        // HTTP/1.x connections serve one request at a time, busy ones are kept for later requests.
+        // HTTP/2 connections are shared by concurrent requests, so they are kept in cache.
+        let idx = q.iter().position(|(c, ..)| c.is_ready())?;
+        if let HttpConnection::Http2(ref r) = q[idx].0 {
+            let c = HttpConnection::Http2(r.clone());
+            q[idx].1 = now;
+            return Some(c);
         }
-        None
+        q.remove(idx).map(|(c, ..)| c)
     }
 
     async fn send_request_conn(
         &self,
-        host: Address,
+        key: ConnectionKey,
         mut c: HttpConnection<B>,
         req: Request<B>,
     ) -> hyper::Result<Response<body::Incoming>> {
-        trace!("HTTP making request to host: {}, request: {:?}", host, req);
+        trace!("HTTP making request to host: {}, request: {:?}", key.host, req);
         let response = c.send_request(req).await?;
-        trace!("HTTP received response from host: {}, response: {:?}", host, response);
+        trace!(
+            "HTTP received response from host: {}, response: {:?}",
+            key.host,
+            response
+        );
 
+        self.cache_connection(key, c, &response).await;
+        Ok(response)
+    }
+
+    async fn send_request_cached_conn(
+        &self,
+        key: ConnectionKey,
+        mut c: HttpConnection<B>,
+        req: Request<B>,
+    ) -> Result<Response<body::Incoming>, TrySendError<Request<B>>> {
+        trace!("HTTP making request to host: {}, request: {:?}", key.host, req);
+        let response = c.try_send_request(req).await?;
+        trace!(
+            "HTTP received response from host: {}, response: {:?}",
+            key.host,
+            response
+        );
+
+        // HTTP/2 connections are still in cache
+        if let HttpConnection::Http1(..) = c {
+            self.cache_connection(key, c, &response).await;
+        }
+        Ok(response)
+    }
+
+    async fn cache_connection(&self, key: ConnectionKey, c: HttpConnection<B>, response: &Response<body::Incoming>) {
         // Check keep-alive
-        if check_keep_alive(response.version(), response.headers(), false) {
+        if !check_keep_alive(response.version(), response.headers(), false) {
+            return;
+        }
+
+        let mut cache = self.cache_conn.lock().await;
+        let q = cache.entry(key.clone()).or_insert_with(VecDeque::new);
+        if q.len() < CONNECTION_CACHE_PER_HOST {
             trace!(
                 "HTTP connection keep-alive for host: {}, response: {:?}",
-                host,
+                key.host,
                 response
             );
-            self.cache_conn
-                .lock()
-                .await
-                .entry(host)
-                .or_insert_with(VecDeque::new)
-                .push_back((c, Instant::now()));
+            q.push_back((c, Instant::now()));
         }
-
-        Ok(response)
     }
 }
 
@@ -236,13 +330,29 @@ where
         scheme: &Scheme,
         host: Address,
         domain: &str,
-        balancer: Option<&PingBalancer>,
+        via: ConnectVia<'_>,
     ) -> io::Result<HttpConnection<B>> {
         if *scheme != Scheme::HTTP && *scheme != Scheme::HTTPS {
             return Err(io::Error::new(ErrorKind::InvalidInput, "invalid scheme"));
         }
 
-        let (stream, _) = connect_host(context, &host, balancer).await?;
+        let stream = match via {
+            ConnectVia::Balancer(balancer) => connect_host(context, &host, None, balancer).await?.0,
+            ConnectVia::Server(server) => {
+                match AutoProxyClientStream::connect_proxied(context, server, host.clone()).await {
+                    Ok(s) => s,
+                    Err(err) => {
+                        error!(
+                            "failed to connect host {} proxied, svr_cfg: {}, error: {}",
+                            host,
+                            server.server_config().addr(),
+                            err
+                        );
+                        return Err(err);
+                    }
+                }
+            }
+        };
 
         if *scheme == Scheme::HTTP {
             HttpConnection::connect_http_http1(scheme, host, stream).await
@@ -274,7 +384,7 @@ where
             .await
         {
             Ok(s) => s,
-            Err(err) => return Err(io::Error::new(ErrorKind::Other, err)),
+            Err(err) => return Err(io::Error::other(err)),
         };
 
         tokio::spawn(async move {
@@ -306,7 +416,7 @@ where
                 .await
             {
                 Ok(s) => s,
-                Err(err) => return Err(io::Error::new(ErrorKind::Other, err)),
+                Err(err) => return Err(io::Error::other(err)),
             };
 
             tokio::spawn(async move {
@@ -325,7 +435,7 @@ where
                 .await
             {
                 Ok(s) => s,
-                Err(err) => return Err(io::Error::new(ErrorKind::Other, err)),
+                Err(err) => return Err(io::Error::other(err)),
             };
 
             tokio::spawn(async move {
@@ -346,10 +456,29 @@ where
         }
     }
 
+    /// Send a request, which is given back in the error if the connection was closed before sending it
+    #[inline]
+    pub async fn try_send_request(
+        &mut self,
+        req: Request<B>,
+    ) -> Result<Response<body::Incoming>, TrySendError<Request<B>>> {
+        match self {
+            HttpConnection::Http1(r) => r.try_send_request(req).await,
+            HttpConnection::Http2(r) => r.try_send_request(req).await,
+        }
+    }
+
     pub fn is_closed(&self) -> bool {
         match self {
             HttpConnection::Http1(r) => r.is_closed(),
             HttpConnection::Http2(r) => r.is_closed(),
         }
     }
+
+    pub fn is_ready(&self) -> bool {
+        match self {
+            HttpConnection::Http1(r) => r.is_ready(),
+            HttpConnection::Http2(r) => r.is_ready(),
+        }
+    }
 }
@@ -1,6 +1,6 @@
 //! Shadowsocks HTTP Proxy server dispatcher
 
-use std::{net::SocketAddr, str::FromStr, sync::Arc};
+use std::{io, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
 
 use bytes::Bytes;
 use http_body_util::{combinators::BoxBody, BodyExt};
@@ -11,11 +11,17 @@ use hyper::{
     HeaderMap, Method, Request, Response, StatusCode, Uri, Version,
 };
 use log::{debug, error, trace};
-use shadowsocks::relay::Address;
+use shadowsocks::{config::Mode, relay::Address};
 
 use crate::local::{
     context::ServiceContext,
-    http::{http_client::HttpClientError, tokio_rt::TokioIo},
+    http::{
+        auth::{HttpAuthResult, HttpAuthenticator},
+        connect_udp::{self, CAPSULE_PROTOCOL_HEADER, CONNECT_UDP_PROTOCOL},
+        http_client::HttpClientError,
+        pac::{PacFile, PAC_CONTENT_TYPE},
+        tokio_rt::TokioIo,
+    },
     loadbalancing::PingBalancer,
     net::AutoProxyIo,
     utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
@@ -31,20 +37,33 @@ pub struct HttpService {
     peer_addr: SocketAddr,
     http_client: HttpClient<body::Incoming>,
     balancer: PingBalancer,
+    auth: Option<Arc<HttpAuthenticator>>,
+    mode: Mode,
+    udp_expiry_duration: Option<Duration>,
+    pac: Option<Arc<PacFile>>,
 }
 
 impl HttpService {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         context: Arc<ServiceContext>,
         peer_addr: SocketAddr,
         http_client: HttpClient<body::Incoming>,
         balancer: PingBalancer,
+        auth: Option<Arc<HttpAuthenticator>>,
+        mode: Mode,
+        udp_expiry_duration: Option<Duration>,
+        pac: Option<Arc<PacFile>>,
     ) -> HttpService {
         HttpService {
             context,
             peer_addr,
             http_client,
             balancer,
+            auth,
+            mode,
+            udp_expiry_duration,
+            pac,
         }
     }
 
@@ -54,6 +73,30 @@ impl HttpService {
     ) -> hyper::Result<Response<BoxBody<Bytes, hyper::Error>>> {
         trace!("request {} {:?}", self.peer_addr, req);
 
+        // PAC file is requested directly instead of through the proxy, and browsers don't authenticate for it
+        if let Some(ref pac) = self.pac {
+            if matches!(*req.method(), Method::GET | Method::HEAD)
+                && req.uri().authority().is_none()
+                && req.uri().path() == pac.path()
+            {
+                return self.serve_pac(pac, &req);
+            }
+        }
+
+        if let Some(ref auth) = self.auth {
+            match auth.authenticate(req.method(), req.uri(), req.headers()) {
+                HttpAuthResult::Accepted => {}
+                result => {
+                    debug!("HTTP {} {} authentication failed", self.peer_addr, req.uri());
+                    return make_proxy_auth_required(auth, result == HttpAuthResult::Stale);
+                }
+            }
+        }
+
+        if connect_udp::is_connect_udp_request(&req) {
+            return self.serve_connect_udp(req);
+        }
+
         // Parse URI
         //
         // Proxy request URI must contains a host
@@ -84,13 +127,15 @@ impl HttpService {
             // Connect to Shadowsocks' remote
             //
             // FIXME: What STATUS should I return for connection error?
-            let (mut stream, server_opt) = match connect_host(self.context, &host, Some(&self.balancer)).await {
-                Ok(s) => s,
-                Err(err) => {
-                    error!("failed to CONNECT host: {}, error: {}", host, err);
-                    return make_internal_server_error();
-                }
-            };
+            let context = self.context.clone();
+            let (mut stream, server_opt) =
+                match connect_host(self.context, &host, Some(&self.peer_addr), Some(&self.balancer)).await {
+                    Ok(s) => s,
+                    Err(err) => {
+                        error!("failed to CONNECT host: {}, error: {}", host, err);
+                        return make_connect_error(&err);
+                    }
+                };
 
             debug!(
                 "CONNECT relay connected {} <-> {} ({})",
@@ -110,6 +155,7 @@ impl HttpService {
                         let _ = match server_opt {
                             Some(server) => {
                                 establish_tcp_tunnel(
+                                    &context,
                                     server.server_config(),
                                     &mut upgraded_io,
                                     &mut stream,
@@ -119,7 +165,14 @@ impl HttpService {
                                 .await
                             }
                             None => {
-                                establish_tcp_tunnel_bypassed(&mut upgraded_io, &mut stream, client_addr, &host).await
+                                establish_tcp_tunnel_bypassed(
+                                    &context,
+                                    &mut upgraded_io,
+                                    &mut stream,
+                                    client_addr,
+                                    &host,
+                                )
+                                .await
                             }
                         };
                     }
@@ -144,8 +197,13 @@ impl HttpService {
         // Remove non-forwardable headers
         clear_hop_headers(req.headers_mut());
 
+        // Requests of HTTP/2 clients are relayed with HTTP/1.1, or HTTP/2 if the remote negotiated it
+        if matches!(version, Version::HTTP_2 | Version::HTTP_3) {
+            *req.version_mut() = Version::HTTP_11;
+        }
+
         // Set keep-alive for connection with remote
-        set_conn_keep_alive(version, req.headers_mut(), conn_keep_alive);
+        set_conn_keep_alive(req.version(), req.headers_mut(), conn_keep_alive);
 
         let mut res = match self
             .http_client
@@ -156,7 +214,7 @@ impl HttpService {
             Err(HttpClientError::Hyper(e)) => return Err(e),
             Err(HttpClientError::Io(err)) => {
                 error!("failed to make request to host: {}, error: {}", host, err);
-                return make_internal_server_error();
+                return make_connect_error(&err);
             }
         };
 
@@ -182,6 +240,97 @@ impl HttpService {
 
         Ok(res.map(|b| b.boxed()))
     }
+
+    fn serve_pac(
+        &self,
+        pac: &PacFile,
+        req: &Request<body::Incoming>,
+    ) -> hyper::Result<Response<BoxBody<Bytes, hyper::Error>>> {
+        debug!("HTTP {} requested PAC file {}", self.peer_addr, req.uri());
+
+        let acl = self.context.acl();
+        let script = pac.generate(req.headers(), acl.as_deref());
+
+        let body = if req.method() == Method::HEAD {
+            empty_body()
+        } else {
+            http_body_util::Full::new(Bytes::from(script))
+                .map_err(|never| match never {})
+                .boxed()
+        };
+
+        Ok(Response::builder()
+            .header(header::CONTENT_TYPE, PAC_CONTENT_TYPE)
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(body)
+            .unwrap())
+    }
+
+    /// Proxying UDP in HTTP (RFC 9298)
+    fn serve_connect_udp(self, req: Request<body::Incoming>) -> hyper::Result<Response<BoxBody<Bytes, hyper::Error>>> {
+        if !self.mode.enable_udp() {
+            debug!("HTTP connect-udp {} rejected, UDP is not enabled", req.uri());
+            return make_not_implemented();
+        }
+
+        let target = match connect_udp::connect_udp_target(req.uri()) {
+            Some(t) => t,
+            None => {
+                error!("HTTP connect-udp URI {} doesn't have a valid target", req.uri());
+                return make_bad_request();
+            }
+        };
+
+        debug!("HTTP connect-udp {} {:?}", target, req.version());
+
+        let version = req.version();
+        let HttpService {
+            context,
+            peer_addr,
+            balancer,
+            udp_expiry_duration,
+            ..
+        } = self;
+
+        tokio::spawn(async move {
+            match hyper::upgrade::on(req).await {
+                Ok(upgraded) => {
+                    trace!("connect-udp upgrade success, {} <-> {}", peer_addr, target);
+
+                    if let Err(err) = connect_udp::serve_connect_udp(
+                        context,
+                        balancer,
+                        peer_addr,
+                        target.clone(),
+                        upgraded,
+                        udp_expiry_duration,
+                    )
+                    .await
+                    {
+                        debug!("connect-udp relay {} <-> {} error: {}", peer_addr, target, err);
+                    }
+                }
+                Err(err) => {
+                    error!("failed to upgrade connect-udp request, error: {}", err);
+                }
+            }
+        });
+
+        // HTTP/1.1 switches protocol, HTTP/2 extended CONNECT is accepted with 2xx
+        let builder = if version == Version::HTTP_11 {
+            Response::builder()
+                .status(StatusCode::SWITCHING_PROTOCOLS)
+                .header(header::CONNECTION, "Upgrade")
+                .header(header::UPGRADE, CONNECT_UDP_PROTOCOL)
+        } else {
+            Response::builder().status(StatusCode::OK)
+        };
+
+        Ok(builder
+            .header(CAPSULE_PROTOCOL_HEADER, "?1")
+            .body(empty_body())
+            .unwrap())
+    }
 }
 
 fn empty_body() -> BoxBody<Bytes, hyper::Error> {
@@ -197,6 +346,27 @@ fn make_bad_request() -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::E
         .unwrap())
 }
 
+fn make_proxy_auth_required(
+    auth: &HttpAuthenticator,
+    stale: bool,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    let mut resp = Response::builder()
+        .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+        .body(empty_body())
+        .unwrap();
+    for challenge in auth.challenges(stale) {
+        resp.headers_mut().append(header::PROXY_AUTHENTICATE, challenge);
+    }
+    Ok(resp)
+}
+
+fn make_not_implemented() -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    Ok(Response::builder()
+        .status(StatusCode::NOT_IMPLEMENTED)
+        .body(empty_body())
+        .unwrap())
+}
+
 fn make_internal_server_error() -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
     Ok(Response::builder()
         .status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -204,6 +374,18 @@ fn make_internal_server_error() -> Result<Response<BoxBody<Bytes, hyper::Error>>
         .unwrap())
 }
 
+/// Special targets rejected (or dropped) by the policy are `403 Forbidden`
+fn make_connect_error(err: &io::Error) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    if err.kind() != io::ErrorKind::PermissionDenied {
+        return make_internal_server_error();
+    }
+
+    Ok(Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(empty_body())
+        .unwrap())
+}
+
 fn get_extra_headers(headers: header::GetAll<HeaderValue>) -> Vec<String> {
     let mut extra_headers = Vec::new();
     for connection in headers {
@@ -250,17 +432,16 @@ fn clear_hop_headers(headers: &mut HeaderMap<HeaderValue>) {
 
 fn set_conn_keep_alive(version: Version, headers: &mut HeaderMap<HeaderValue>, keep_alive: bool) {
     match version {
-        Version::HTTP_09 | Version::HTTP_10 => {
-            // HTTP/1.0 close connection by default
-            if keep_alive {
-                headers.insert("Connection", HeaderValue::from_static("keep-alive"));
-            }
+        // HTTP/1.0 close connection by default
+        Version::HTTP_09 | Version::HTTP_10 if keep_alive => {
+            headers.insert("Connection", HeaderValue::from_static("keep-alive"));
+        }
+        // HTTP/1.1 keep-alive connection by default
+        Version::HTTP_11 if !keep_alive => {
+            headers.insert("Connection", HeaderValue::from_static("close"));
         }
         _ => {
-            // HTTP/1.1, HTTP/2, HTTP/3 keep-alive connection by default
-            if !keep_alive {
-                headers.insert("Connection", HeaderValue::from_static("close"));
-            }
+            // HTTP/2, HTTP/3 don't have connection-specific headers
         }
     }
 }
@@ -1,7 +1,7 @@
 //! Proxied HTTP stream
 
 use std::{
-    io::{self, ErrorKind},
+    io,
     pin::Pin,
     task::{self, Poll},
 };
@@ -33,7 +33,7 @@ impl ProxyHttpStream {
         let cx = match TlsConnector::builder().request_alpns(&["h2", "http/1.1"]).build() {
             Ok(c) => c,
             Err(err) => {
-                return Err(io::Error::new(ErrorKind::Other, format!("tls build: {err}")));
+                return Err(io::Error::other(format!("tls build: {err}")));
             }
         };
         let cx = tokio_native_tls::TlsConnector::from(cx);
@@ -44,7 +44,7 @@ impl ProxyHttpStream {
                     Ok(Some(alpn)) => alpn == b"h2",
                     Ok(None) => false,
                     Err(err) => {
-                        let ierr = io::Error::new(ErrorKind::Other, format!("tls alpn negotiate: {err}"));
+                        let ierr = io::Error::other(format!("tls alpn negotiate: {err}"));
                         return Err(ierr);
                     }
                 };
@@ -52,7 +52,7 @@ impl ProxyHttpStream {
                 Ok(ProxyHttpStream::Https(s, negotiated_h2))
             }
             Err(err) => {
-                let ierr = io::Error::new(ErrorKind::Other, format!("tls connect: {err}"));
+                let ierr = io::Error::other(format!("tls connect: {err}"));
                 Err(ierr)
             }
         }
@@ -98,7 +98,7 @@ impl ProxyHttpStream {
             Ok(n) => n,
             Err(_) => {
                 return Err(io::Error::new(
-                    ErrorKind::InvalidInput,
+                    io::ErrorKind::InvalidInput,
                     format!("invalid dnsname \"{domain}\""),
                 ));
             }
@@ -114,8 +114,7 @@ impl ProxyHttpStream {
 
     #[cfg(not(any(feature = "local-http-native-tls", feature = "local-http-rustls")))]
     pub async fn connect_https(_stream: AutoProxyClientStream, _domain: &str) -> io::Result<ProxyHttpStream> {
-        let err = io::Error::new(
-            ErrorKind::Other,
+        let err = io::Error::other(
             "https is not supported, consider enable it by feature \"local-http-native-tls\" or \"local-http-rustls\"",
         );
         Err(err)
@@ -3,13 +3,21 @@
 //! https://www.ietf.org/rfc/rfc2068.txt
 
 pub use self::{
+    config::HttpAuthConfig,
     http_client::{HttpClient, HttpClientError},
+    pac::PacFile,
     server::{Http, HttpBuilder, HttpConnectionHandler},
 };
+pub(crate) use crate::net::tokio_rt::TokioIo;
 
+mod auth;
+pub mod config;
+mod connect_udp;
 mod http_client;
 mod http_service;
 mod http_stream;
+pub mod pac;
 pub mod server;
-mod tokio_rt;
 mod utils;
+
+use crate::net::tokio_rt;
@@ -0,0 +1,220 @@
+//! Proxy auto-config (PAC) file of the HTTP local server
+//!
+//! Browsers and systems could be configured with one URL, like `http://127.0.0.1:3128/proxy.pac`.
+
+use std::fmt::Write;
+
+use hyper::{header, HeaderMap};
+use shadowsocks::ServerAddr;
+
+use crate::acl::{AccessControl, Mode, RuleList};
+
+/// MIME type of PAC files
+pub const PAC_CONTENT_TYPE: &str = "application/x-ns-proxy-autoconfig";
+
+const PAC_FUNCTIONS: &str = r#"
+function matchHost(list, host) {
+    if (list.hosts.hasOwnProperty(host)) {
+        return true;
+    }
+    var suffix = host;
+    while (true) {
+        if (list.domains.hasOwnProperty(suffix)) {
+            return true;
+        }
+        var pos = suffix.indexOf(".");
+        if (pos < 0) {
+            break;
+        }
+        suffix = suffix.substring(pos + 1);
+    }
+    for (var i = 0; i < list.patterns.length; i++) {
+        if (list.patterns[i] && list.patterns[i].test(host)) {
+            return true;
+        }
+    }
+    return false;
+}
+
+function matchIpv4(list, ip) {
+    for (var i = 0; i < list.ipv4.length; i++) {
+        if (((ip & list.ipv4[i][1]) >>> 0) === list.ipv4[i][0]) {
+            return true;
+        }
+    }
+    return false;
+}
+
+function parseIpv4(host) {
+    var parts = host.split(".");
+    if (parts.length !== 4) {
+        return null;
+    }
+    var ip = 0;
+    for (var i = 0; i < 4; i++) {
+        if (!/^\d{1,3}$/.test(parts[i]) || parseInt(parts[i], 10) > 255) {
+            return null;
+        }
+        ip = ip * 256 + parseInt(parts[i], 10);
+    }
+    return ip;
+}
+
+function regex(pattern) {
+    try {
+        return new RegExp(pattern, "i");
+    } catch (e) {
+        return null;
+    }
+}
+
+function FindProxyForURL(url, host) {
+    host = host.toLowerCase().replace(/\.$/, "");
+    var ip = parseIpv4(host);
+    for (var i = 0; i < LAYERS.length; i++) {
+        var layer = LAYERS[i];
+        if (ip !== null) {
+            var first = BYPASS_FIRST ? layer.bypass : layer.proxy;
+            var second = BYPASS_FIRST ? layer.proxy : layer.bypass;
+            if (matchIpv4(first, ip)) {
+                return first === layer.proxy ? PROXY : DIRECT;
+            }
+            if (matchIpv4(second, ip)) {
+                return second === layer.proxy ? PROXY : DIRECT;
+            }
+        } else {
+            if (matchHost(layer.proxy, host)) {
+                return PROXY;
+            }
+            if (matchHost(layer.bypass, host)) {
+                return DIRECT;
+            }
+        }
+    }
+    return DEFAULT_PROXIED ? PROXY : DIRECT;
+}
+"#;
+
+/// PAC file served on `path`
+#[derive(Debug, Clone)]
+pub struct PacFile {
+    path: String,
+    listen_addr: ServerAddr,
+    acl: bool,
+    tls: bool,
+}
+
+impl PacFile {
+    /// PAC file pointing clients to the HTTP proxy listening on `listen_addr`
+    pub fn new(path: String, listen_addr: ServerAddr) -> PacFile {
+        PacFile {
+            path,
+            listen_addr,
+            acl: false,
+            tls: false,
+        }
+    }
+
+    /// Embed bypass / proxy rules of ACL, so bypassed targets are connected directly by clients
+    pub fn set_acl(&mut self, acl: bool) {
+        self.acl = acl;
+    }
+
+    /// Point clients to the proxy with `HTTPS` instead of `PROXY`, if the proxy accepts clients with TLS
+    pub fn set_tls(&mut self, tls: bool) {
+        self.tls = tls;
+    }
+
+    /// Path of the PAC file
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Generate the script for a request with `headers`, with the current ACL of the server
+    ///
+    /// If the proxy listens on an unspecified address, the `Host` of the request is used as the proxy address.
+    pub fn generate(&self, headers: &HeaderMap, acl: Option<&AccessControl>) -> String {
+        let proxy = self.proxy_addr(headers);
+
+        let mut script = String::new();
+        let _ = writeln!(script, "// Generated by shadowsocks-rust");
+        let keyword = if self.tls { "HTTPS" } else { "PROXY" };
+        let _ = writeln!(script, "var PROXY = {};", json_string(&format!("{keyword} {proxy}")));
+        let _ = writeln!(script, "var DIRECT = \"DIRECT\";");
+
+        match acl {
+            Some(acl) if self.acl => {
+                let _ = writeln!(script, "var DEFAULT_PROXIED = {};", acl.is_default_in_proxy_list());
+                let _ = writeln!(script, "var BYPASS_FIRST = {};", acl.mode() == Mode::BlackList);
+                let _ = writeln!(script, "var LAYERS = [");
+                for layer in acl.rule_lists() {
+                    let _ = writeln!(script, "// {}", layer.name.replace(['\r', '\n'], " "));
+                    let _ = write!(script, "{{\"proxy\":");
+                    write_rule_list(&mut script, &layer.proxy);
+                    let _ = write!(script, ",\"bypass\":");
+                    write_rule_list(&mut script, &layer.bypass);
+                    let _ = writeln!(script, "}},");
+                }
+                let _ = writeln!(script, "];");
+            }
+            _ => {
+                let _ = writeln!(script, "var DEFAULT_PROXIED = true;");
+                let _ = writeln!(script, "var BYPASS_FIRST = true;");
+                let _ = writeln!(script, "var LAYERS = [];");
+            }
+        }
+
+        script.push_str(PAC_FUNCTIONS);
+        script
+    }
+
+    fn proxy_addr(&self, headers: &HeaderMap) -> String {
+        if let ServerAddr::SocketAddr(ref sa) = self.listen_addr {
+            if sa.ip().is_unspecified() {
+                if let Some(host) = headers.get(header::HOST).and_then(|h| h.to_str().ok()) {
+                    if !host.is_empty() && !host.contains(|c: char| c == ';' || c.is_whitespace()) {
+                        return host.to_owned();
+                    }
+                }
+            }
+        }
+        self.listen_addr.to_string()
+    }
+}
+
+/// Rules of a list as a JavaScript object, IPv6 networks are skipped since PAC has no portable function for them
+fn write_rule_list(script: &mut String, list: &RuleList<'_>) {
+    script.push_str("{\"hosts\":{");
+    for (idx, host) in list.hosts.iter().enumerate() {
+        if idx > 0 {
+            script.push(',');
+        }
+        let _ = write!(script, "{}:1", json_string(&host.to_ascii_lowercase()));
+    }
+    script.push_str("},\"domains\":{");
+    for (idx, domain) in list.domains.iter().enumerate() {
+        if idx > 0 {
+            script.push(',');
+        }
+        let _ = write!(script, "{}:1", json_string(domain));
+    }
+    script.push_str("},\"patterns\":[");
+    for (idx, pattern) in list.host_patterns.iter().enumerate() {
+        if idx > 0 {
+            script.push(',');
+        }
+        let _ = write!(script, "regex({})", json_string(pattern));
+    }
+    script.push_str("],\"ipv4\":[");
+    for (idx, net) in list.ipv4.iter().enumerate() {
+        if idx > 0 {
+            script.push(',');
+        }
+        let _ = write!(script, "[{},{}]", u32::from(net.network()), u32::from(net.netmask()));
+    }
+    script.push_str("]}");
+}
+
+fn json_string(s: &str) -> String {
+    serde_json::to_string(s).expect("serialize string")
+}
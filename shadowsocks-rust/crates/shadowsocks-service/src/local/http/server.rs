@@ -4,25 +4,64 @@
 
 use std::{io, net::SocketAddr, sync::Arc, time::Duration};
 
-use hyper::{body, server::conn::http1, service};
-use log::{error, info, trace};
-use shadowsocks::{config::ServerAddr, net::TcpListener};
+use hyper::{
+    body,
+    server::conn::{http1, http2},
+    service,
+};
+use log::{debug, error, info, trace};
+use shadowsocks::{
+    config::{Mode, ServerAddr},
+    net::TcpListener,
+};
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite},
     time,
 };
+#[cfg(feature = "local-tls")]
+use tokio_rustls::TlsAcceptor;
 
-use crate::local::{
-    context::ServiceContext, loadbalancing::PingBalancer, net::tcp::listener::create_standard_tcp_listener,
+#[cfg(feature = "local-tls")]
+use crate::local::net::tls::TlsListenerConfig;
+use crate::{
+    config::InboundLimitConfig,
+    local::{
+        context::ServiceContext,
+        loadbalancing::PingBalancer,
+        net::{
+            ipc::{IpcAddr, IpcListener, IPC_PEER_ADDR},
+            limit::{InboundLimiter, InboundPermit, InboundRejection},
+            rewind::RewindStream,
+            tcp::listener::create_standard_tcp_listener,
+        },
+    },
 };
 
-use super::{http_client::HttpClient, http_service::HttpService, tokio_rt::TokioIo};
+use super::{
+    auth::HttpAuthenticator,
+    config::HttpAuthConfig,
+    http_client::{HttpClient, TokioTimer},
+    http_service::HttpService,
+    pac::PacFile,
+    tokio_rt::{TokioExecutor, TokioIo},
+};
+
+/// HTTP/2 connection preface, sent first by clients with prior knowledge (h2c)
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
 
 /// HTTP Local server builder
 pub struct HttpBuilder {
     context: Arc<ServiceContext>,
     client_config: ServerAddr,
     balancer: PingBalancer,
+    auth: HttpAuthConfig,
+    mode: Mode,
+    udp_expiry_duration: Option<Duration>,
+    pac: Option<PacFile>,
+    #[cfg(feature = "local-tls")]
+    tls: Option<TlsListenerConfig>,
+    ipc_addr: Option<IpcAddr>,
+    inbound_limit: InboundLimitConfig,
     #[cfg(target_os = "macos")]
     launchd_tcp_socket_name: Option<String>,
 }
@@ -44,11 +83,59 @@ impl HttpBuilder {
             context,
             client_config,
             balancer,
+            auth: HttpAuthConfig::default(),
+            mode: Mode::TcpOnly,
+            udp_expiry_duration: None,
+            pac: None,
+            #[cfg(feature = "local-tls")]
+            tls: None,
+            ipc_addr: None,
+            inbound_limit: InboundLimitConfig::default(),
             #[cfg(target_os = "macos")]
             launchd_tcp_socket_name: None,
         }
     }
 
+    /// Authenticate clients with `Proxy-Authorization`
+    pub fn set_auth(&mut self, auth: HttpAuthConfig) {
+        self.auth = auth;
+    }
+
+    /// Set server mode, UDP proxying (`connect-udp`) is served if UDP is enabled
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    /// Set UDP association's expiry duration
+    pub fn set_udp_expiry_duration(&mut self, d: Duration) {
+        self.udp_expiry_duration = Some(d);
+    }
+
+    /// Serve a PAC file pointing clients to this server
+    pub fn set_pac(&mut self, pac: PacFile) {
+        self.pac = Some(pac);
+    }
+
+    /// Accept clients with TLS (HTTPS proxy)
+    #[cfg(feature = "local-tls")]
+    pub fn set_tls(&mut self, tls: TlsListenerConfig) {
+        self.tls = Some(tls);
+    }
+
+    /// Accept clients on a Unix domain socket or Windows named pipe, instead of the TCP `client_config`
+    ///
+    /// UDP proxying should be disabled with `Mode::TcpOnly`
+    pub fn set_ipc_addr(&mut self, addr: IpcAddr) {
+        self.ipc_addr = Some(addr);
+    }
+
+    /// Limit concurrent and new connections of clients
+    ///
+    /// Rejected clients are responded `503 Service Unavailable`, or `429 Too Many Requests` if they are rate limited
+    pub fn set_inbound_limit(&mut self, limit: InboundLimitConfig) {
+        self.inbound_limit = limit;
+    }
+
     #[cfg(target_os = "macos")]
     pub fn set_launchd_tcp_socket_name(&mut self, n: String) {
         self.launchd_tcp_socket_name = Some(n);
@@ -56,22 +143,34 @@ impl HttpBuilder {
 
     /// Build HTTP server instance
     pub async fn build(self) -> io::Result<Http> {
-        cfg_if::cfg_if! {
-            if #[cfg(target_os = "macos")] {
-                let listener = if let Some(launchd_socket_name) = self.launchd_tcp_socket_name {
-                    use tokio::net::TcpListener as TokioTcpListener;
-                    use crate::net::launch_activate_socket::get_launch_activate_tcp_listener;
-
-                    let std_listener = get_launch_activate_tcp_listener(&launchd_socket_name, true)?;
-                    let tokio_listener = TokioTcpListener::from_std(std_listener)?;
-                    TcpListener::from_listener(tokio_listener, self.context.accept_opts())?
-                } else {
-                    create_standard_tcp_listener(&self.context, &self.client_config).await?
-                };
-            } else {
-                let listener = create_standard_tcp_listener(&self.context, &self.client_config).await?;
+        #[cfg(feature = "local-tls")]
+        let tls_acceptor = match self.tls {
+            Some(ref tls) => Some(tls.load_acceptor()?),
+            None => None,
+        };
+
+        let listener = match self.ipc_addr {
+            Some(addr) => HttpListener::Ipc(IpcListener::bind(addr)?),
+            None => {
+                cfg_if::cfg_if! {
+                    if #[cfg(target_os = "macos")] {
+                        let listener = if let Some(launchd_socket_name) = self.launchd_tcp_socket_name {
+                            use tokio::net::TcpListener as TokioTcpListener;
+                            use crate::net::launch_activate_socket::get_launch_activate_tcp_listener;
+
+                            let std_listener = get_launch_activate_tcp_listener(&launchd_socket_name, true)?;
+                            let tokio_listener = TokioTcpListener::from_std(std_listener)?;
+                            TcpListener::from_listener(tokio_listener, self.context.accept_opts())?
+                        } else {
+                            create_standard_tcp_listener(&self.context, &self.client_config).await?
+                        };
+                    } else {
+                        let listener = create_standard_tcp_listener(&self.context, &self.client_config).await?;
+                    }
+                }
+                HttpListener::Tcp(listener)
             }
-        }
+        };
 
         // let proxy_client_cache = Arc::new(ProxyClientCache::new(self.context.clone()));
 
@@ -79,21 +178,50 @@ impl HttpBuilder {
             context: self.context,
             listener,
             balancer: self.balancer,
+            auth: self.auth,
+            mode: self.mode,
+            udp_expiry_duration: self.udp_expiry_duration,
+            pac: self.pac,
+            #[cfg(feature = "local-tls")]
+            tls_acceptor,
+            inbound_limiter: if self.inbound_limit.is_empty() {
+                None
+            } else {
+                Some(Arc::new(InboundLimiter::new(self.inbound_limit)))
+            },
         })
     }
 }
 
+enum HttpListener {
+    Tcp(TcpListener),
+    Ipc(IpcListener),
+}
+
 /// HTTP Local server
 pub struct Http {
     context: Arc<ServiceContext>,
-    listener: TcpListener,
+    listener: HttpListener,
     balancer: PingBalancer,
+    auth: HttpAuthConfig,
+    mode: Mode,
+    udp_expiry_duration: Option<Duration>,
+    pac: Option<PacFile>,
+    #[cfg(feature = "local-tls")]
+    tls_acceptor: Option<TlsAcceptor>,
+    inbound_limiter: Option<Arc<InboundLimiter>>,
 }
 
 impl Http {
     /// Server's local address
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.listener.local_addr()
+        match self.listener {
+            HttpListener::Tcp(ref listener) => listener.local_addr(),
+            HttpListener::Ipc(ref listener) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("listening on {}", listener.addr()),
+            )),
+        }
     }
 
     /// Run server
@@ -101,32 +229,148 @@ impl Http {
         // https://www.ietf.org/rfc/rfc2068.txt
         // HTTP Proxy is based on HTTP/1.1
 
-        info!(
-            "shadowsocks HTTP listening on {}",
-            self.listener.local_addr().expect("http local_addr")
-        );
+        match self.listener {
+            HttpListener::Tcp(ref listener) => info!(
+                "shadowsocks HTTP listening on {}",
+                listener.local_addr().expect("http local_addr")
+            ),
+            HttpListener::Ipc(ref listener) => info!("shadowsocks HTTP listening on {}", listener.addr()),
+        }
+
+        let mut handler = HttpConnectionHandler::new(self.context, self.balancer);
+        handler.set_auth(self.auth);
+        handler.set_mode(self.mode);
+        if let Some(d) = self.udp_expiry_duration {
+            handler.set_udp_expiry_duration(d);
+        }
+        if let Some(pac) = self.pac {
+            handler.set_pac(pac);
+        }
 
-        let handler = HttpConnectionHandler::new(self.context, self.balancer);
+        #[cfg(feature = "local-tls")]
+        let tls_acceptor = self.tls_acceptor;
 
-        loop {
-            let (stream, peer_addr) = match self.listener.accept().await {
+        match self.listener {
+            HttpListener::Tcp(listener) => loop {
+                let (stream, peer_addr) = match listener.accept().await {
+                    Ok(s) => s,
+                    Err(err) => {
+                        error!("failed to accept HTTP clients, err: {}", err);
+                        time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                trace!("HTTP accepted client from {}", peer_addr);
+
+                let permit = match self.inbound_limiter {
+                    None => None,
+                    Some(ref limiter) => match limiter.acquire(&peer_addr) {
+                        Ok(permit) => Some(permit),
+                        Err(reason) => {
+                            debug!("HTTP rejected {}, {}", peer_addr, reason);
+                            #[cfg(feature = "local-tls")]
+                            let plaintext = tls_acceptor.is_none();
+                            #[cfg(not(feature = "local-tls"))]
+                            let plaintext = true;
+                            if plaintext {
+                                // Best effort, the client is disconnected anyway
+                                let _ = stream.try_write(rejection_response(reason));
+                            }
+                            continue;
+                        }
+                    },
+                };
+
+                spawn_client(
+                    handler.clone(),
+                    stream,
+                    peer_addr,
+                    permit,
+                    #[cfg(feature = "local-tls")]
+                    tls_acceptor.as_ref(),
+                );
+            },
+            HttpListener::Ipc(mut listener) => loop {
+                let stream = match listener.accept().await {
+                    Ok(s) => s,
+                    Err(err) => {
+                        error!("failed to accept HTTP clients, err: {}", err);
+                        time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                trace!("HTTP accepted client on {}", listener.addr());
+
+                let permit = match self.inbound_limiter {
+                    None => None,
+                    Some(ref limiter) => match limiter.acquire(&IPC_PEER_ADDR) {
+                        Ok(permit) => Some(permit),
+                        Err(reason) => {
+                            debug!("HTTP rejected client on {}, {}", listener.addr(), reason);
+                            continue;
+                        }
+                    },
+                };
+
+                spawn_client(
+                    handler.clone(),
+                    stream,
+                    IPC_PEER_ADDR,
+                    permit,
+                    #[cfg(feature = "local-tls")]
+                    tls_acceptor.as_ref(),
+                );
+            },
+        }
+    }
+}
+
+/// Response of clients rejected by inbound limits
+fn rejection_response(reason: InboundRejection) -> &'static [u8] {
+    if reason.is_rate_limited() {
+        b"HTTP/1.1 429 Too Many Requests\r\nRetry-After: 1\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+    } else {
+        b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+    }
+}
+
+/// Serve an accepted client, after TLS handshake if it is enabled
+fn spawn_client<S>(
+    handler: HttpConnectionHandler,
+    stream: S,
+    peer_addr: SocketAddr,
+    permit: Option<InboundPermit>,
+    #[cfg(feature = "local-tls")] tls_acceptor: Option<&TlsAcceptor>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    #[cfg(feature = "local-tls")]
+    if let Some(tls_acceptor) = tls_acceptor {
+        let tls_acceptor = tls_acceptor.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let stream = match tls_acceptor.accept(stream).await {
                 Ok(s) => s,
                 Err(err) => {
-                    error!("failed to accept HTTP clients, err: {}", err);
-                    time::sleep(Duration::from_secs(1)).await;
-                    continue;
+                    debug!("HTTP TLS handshake with {} failed, error: {}", peer_addr, err);
+                    return;
                 }
             };
+            if let Err(err) = handler.serve_connection(stream, peer_addr).await {
+                error!("HTTP connection {} handler failed with error: {}", peer_addr, err);
+            }
+        });
+        return;
+    }
 
-            trace!("HTTP accepted client from {}", peer_addr);
-            let handler = handler.clone();
-            tokio::spawn(async move {
-                if let Err(err) = handler.serve_connection(stream, peer_addr).await {
-                    error!("HTTP connection {} handler failed with error: {}", peer_addr, err);
-                }
-            });
+    tokio::spawn(async move {
+        let _permit = permit;
+        if let Err(err) = handler.serve_connection(stream, peer_addr).await {
+            error!("HTTP connection {} handler failed with error: {}", peer_addr, err);
         }
-    }
+    });
 }
 
 /// HTTP Proxy handler for `accept()`ed HTTP clients
@@ -137,6 +381,10 @@ pub struct HttpConnectionHandler {
     context: Arc<ServiceContext>,
     balancer: PingBalancer,
     http_client: HttpClient<body::Incoming>,
+    auth: Option<Arc<HttpAuthenticator>>,
+    mode: Mode,
+    udp_expiry_duration: Option<Duration>,
+    pac: Option<Arc<PacFile>>,
 }
 
 impl HttpConnectionHandler {
@@ -146,11 +394,41 @@ impl HttpConnectionHandler {
             context,
             balancer,
             http_client: HttpClient::new(),
+            auth: None,
+            mode: Mode::TcpOnly,
+            udp_expiry_duration: None,
+            pac: None,
         }
     }
 
+    /// Authenticate clients with `Proxy-Authorization`, if `auth` has any users
+    pub fn set_auth(&mut self, auth: HttpAuthConfig) {
+        self.auth = if auth.auth_required() {
+            Some(Arc::new(HttpAuthenticator::new(auth)))
+        } else {
+            None
+        };
+    }
+
+    /// Set server mode, UDP proxying (`connect-udp`) is served if UDP is enabled
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    /// Set UDP association's expiry duration
+    pub fn set_udp_expiry_duration(&mut self, d: Duration) {
+        self.udp_expiry_duration = Some(d);
+    }
+
+    /// Serve a PAC file for requests of its path
+    pub fn set_pac(&mut self, pac: PacFile) {
+        self.pac = Some(Arc::new(pac));
+    }
+
     /// Handle a TCP HTTP connection
-    pub async fn serve_connection<S>(self, stream: S, peer_addr: SocketAddr) -> hyper::Result<()>
+    ///
+    /// HTTP/2 is served for clients with prior knowledge, which start with the connection preface
+    pub async fn serve_connection<S>(self, mut stream: S, peer_addr: SocketAddr) -> hyper::Result<()>
     where
         S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
@@ -158,9 +436,52 @@ impl HttpConnectionHandler {
             context,
             balancer,
             http_client,
+            auth,
+            mode,
+            udp_expiry_duration,
+            pac,
         } = self;
 
-        let io = TokioIo::new(stream);
+        let mut preface = Vec::with_capacity(HTTP2_PREFACE.len());
+        while preface.len() < HTTP2_PREFACE.len() && HTTP2_PREFACE.starts_with(&preface) {
+            let mut buffer = [0u8; HTTP2_PREFACE.len()];
+            let remaining = HTTP2_PREFACE.len() - preface.len();
+            match stream.read(&mut buffer[..remaining]).await {
+                Ok(0) => break,
+                Ok(n) => preface.extend_from_slice(&buffer[..n]),
+                Err(err) => {
+                    trace!("HTTP {} failed to read request, error: {}", peer_addr, err);
+                    return Ok(());
+                }
+            }
+        }
+        let is_http2 = preface == HTTP2_PREFACE;
+
+        let io = TokioIo::new(RewindStream::new(preface, stream));
+        let service = service::service_fn(move |req| {
+            HttpService::new(
+                context.clone(),
+                peer_addr,
+                http_client.clone(),
+                balancer.clone(),
+                auth.clone(),
+                mode,
+                udp_expiry_duration,
+                pac.clone(),
+            )
+            .serve_connection(req)
+        });
+
+        if is_http2 {
+            trace!("HTTP {} is serving with HTTP/2", peer_addr);
+
+            // Extended CONNECT (RFC 8441) is required by connect-udp
+            return http2::Builder::new(TokioExecutor)
+                .timer(TokioTimer)
+                .enable_connect_protocol()
+                .serve_connection(io, service)
+                .await;
+        }
 
         // NOTE: Some stupid clients requires HTTP header keys to be case-sensitive.
         // For example: Nintendo Switch
@@ -168,13 +489,7 @@ impl HttpConnectionHandler {
             .keep_alive(true)
             .title_case_headers(true)
             .preserve_header_case(true)
-            .serve_connection(
-                io,
-                service::service_fn(move |req| {
-                    HttpService::new(context.clone(), peer_addr, http_client.clone(), balancer.clone())
-                        .serve_connection(req)
-                }),
-            )
+            .serve_connection(io, service)
             .with_upgrades()
             .await
     }
@@ -113,9 +113,14 @@ pub fn check_keep_alive(version: Version, headers: &HeaderMap<HeaderValue>, chec
     conn_keep_alive
 }
 
+/// Connect to `host`, through servers of `balancer` if it is not empty
+///
+/// `client_addr` is only known for tunnels (CONNECT). Plain HTTP connections are shared between clients,
+/// so they are not kept on the same server by the `source_hash` strategy.
 pub async fn connect_host(
     context: Arc<ServiceContext>,
     host: &Address,
+    client_addr: Option<&SocketAddr>,
     balancer: Option<&PingBalancer>,
 ) -> io::Result<(AutoProxyClientStream, Option<Arc<ServerIdent>>)> {
     match balancer {
@@ -134,7 +139,10 @@ pub async fn connect_host(
             }
         },
         Some(balancer) => {
-            let server = balancer.best_tcp_server();
+            let server = match client_addr {
+                Some(client_addr) => balancer.best_tcp_server_for_client(client_addr, host),
+                None => balancer.best_tcp_server_for(host),
+            };
 
             match AutoProxyClientStream::connect_with_opts(context, server.as_ref(), host, server.connect_opts_ref())
                 .await
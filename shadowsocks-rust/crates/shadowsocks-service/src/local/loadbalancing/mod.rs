@@ -2,9 +2,12 @@
 
 pub use self::{
     ping_balancer::{PingBalancer, PingBalancerBuilder, ServerType},
-    server_data::{ServerIdent, ServerScore},
+    server_data::{ServerIdent, ServerScore, ServerSessionGuard},
 };
 
 pub mod ping_balancer;
+pub mod score_expr;
 pub mod server_data;
 pub mod server_stat;
+pub mod server_state;
+pub mod server_verify;
@@ -2,12 +2,16 @@
 
 use std::{
     cmp,
+    collections::hash_map::DefaultHasher,
     fmt::{self, Debug, Display},
+    hash::{Hash, Hasher},
     io,
     iter::Iterator,
-    net::{Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    str,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
         Arc,
     },
     time::{Duration, Instant},
@@ -18,7 +22,7 @@ use byte_string::ByteStr;
 use futures::future;
 use log::{debug, error, info, trace, warn};
 use shadowsocks::{
-    config::{Mode, ServerSource},
+    config::{Mode, ServerAddr, ServerSource},
     plugin::{Plugin, PluginMode},
     relay::{
         socks5::Address,
@@ -29,21 +33,28 @@ use shadowsocks::{
 };
 use spin::Mutex as SpinMutex;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    sync::Notify,
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{Mutex as AsyncMutex, Notify},
     task::JoinHandle,
     time,
 };
 
-use crate::{config::ServerInstanceConfig, local::context::ServiceContext};
+use crate::{
+    acl::ServerRoutes,
+    config::{BalancerCheckMethod, BalancerCheckUrl, BalancerScoreMode, BalancerStrategy, ServerInstanceConfig},
+    local::context::ServiceContext,
+};
 
 use super::{
-    server_data::ServerIdent,
-    server_stat::{Score, DEFAULT_CHECK_INTERVAL_SEC, DEFAULT_CHECK_TIMEOUT_SEC},
+    score_expr::{ScoreClock, ScoreExpr, ScoreInputs},
+    server_data::{ServerIdent, ServerScore},
+    server_stat::{
+        PacketProbe, Score, ScoreOptions, DEFAULT_CHECK_INTERVAL_SEC, DEFAULT_CHECK_TIMEOUT_SEC,
+        EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW, MAX_LATENCY_SCORE,
+    },
+    server_state::ServerStateFile,
 };
 
-const EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW: u32 = 67;
-
 /// Remote Server Type
 #[derive(Debug, Clone, Copy)]
 pub enum ServerType {
@@ -60,6 +71,24 @@ impl fmt::Display for ServerType {
     }
 }
 
+/// Hysteresis of switching the best server
+#[derive(Debug, Clone, Copy, Default)]
+struct SwitchOptions {
+    /// Ratio of the best server's score that another server must be better by
+    margin: f64,
+    /// Time that another server must keep better by `margin`
+    dwell: Duration,
+}
+
+/// Warm-up of servers added at runtime
+#[derive(Debug, Clone, Copy, Default)]
+struct WarmupOptions {
+    /// Succeeded checks that a new server needs before being chosen
+    checks: u32,
+    /// Choose servers that are still warming up if no other server is alive
+    bypass: bool,
+}
+
 /// Build a `PingBalancer`
 pub struct PingBalancerBuilder {
     servers: Vec<Arc<ServerIdent>>,
@@ -68,6 +97,22 @@ pub struct PingBalancerBuilder {
     max_server_rtt: Duration,
     check_interval: Duration,
     check_best_interval: Option<Duration>,
+    score_tolerance: Option<f64>,
+    strategy: BalancerStrategy,
+    udp_strategy: Option<BalancerStrategy>,
+    score_opts: ScoreOptions,
+    check_url: Option<BalancerCheckUrl>,
+    check_dns: Option<ServerAddr>,
+    check_stun: Option<ServerAddr>,
+    tcp_check_method: BalancerCheckMethod,
+    udp_check_method: BalancerCheckMethod,
+    udp_probe_packets: Option<u32>,
+    tier_max_latency: Option<Duration>,
+    switch_opts: SwitchOptions,
+    warmup_opts: WarmupOptions,
+    score_expr: Option<Arc<ScoreExpr>>,
+    routes: Option<Arc<ServerRoutes>>,
+    state_file: Option<Arc<ServerStateFile>>,
 }
 
 impl PingBalancerBuilder {
@@ -79,6 +124,22 @@ impl PingBalancerBuilder {
             max_server_rtt: Duration::from_secs(DEFAULT_CHECK_TIMEOUT_SEC),
             check_interval: Duration::from_secs(DEFAULT_CHECK_INTERVAL_SEC),
             check_best_interval: None,
+            score_tolerance: None,
+            strategy: BalancerStrategy::default(),
+            udp_strategy: None,
+            score_opts: ScoreOptions::default(),
+            check_url: None,
+            check_dns: None,
+            check_stun: None,
+            tcp_check_method: BalancerCheckMethod::DEFAULT_TCP,
+            udp_check_method: BalancerCheckMethod::DEFAULT_UDP,
+            udp_probe_packets: None,
+            tier_max_latency: None,
+            switch_opts: SwitchOptions::default(),
+            warmup_opts: WarmupOptions::default(),
+            score_expr: None,
+            routes: None,
+            state_file: None,
         }
     }
 
@@ -87,7 +148,8 @@ impl PingBalancerBuilder {
             self.context.clone(),
             server,
             self.max_server_rtt,
-            self.check_interval * EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW,
+            self.check_interval,
+            self.score_opts,
         );
         self.servers.push(Arc::new(ident));
     }
@@ -104,6 +166,134 @@ impl PingBalancerBuilder {
         self.check_best_interval = Some(intv);
     }
 
+    /// Choose servers randomly by their weights if their scores are within `tolerance` ratio of the best server's score
+    ///
+    /// For example, `0.1` means servers that are at most 10% worse than the best server will be chosen
+    pub fn score_tolerance(&mut self, tolerance: f64) {
+        self.score_tolerance = Some(tolerance);
+    }
+
+    /// Strategy of choosing server for new sessions
+    pub fn strategy(&mut self, strategy: BalancerStrategy) {
+        self.strategy = strategy;
+    }
+
+    /// Strategy of choosing server for new UDP sessions, same as `strategy` by default
+    pub fn udp_strategy(&mut self, strategy: BalancerStrategy) {
+        self.udp_strategy = Some(strategy);
+    }
+
+    /// Scoring function of servers
+    pub fn score_mode(&mut self, mode: BalancerScoreMode) {
+        self.score_opts.mode = mode;
+    }
+
+    /// Smooth servers' latencies with EWMA instead of using medians in the check window
+    pub fn ewma_alpha(&mut self, alpha: f64) {
+        self.score_opts.ewma_alpha = Some(alpha);
+    }
+
+    /// Latency of a failed check in EWMA, as a multiple of `max_server_rtt`
+    pub fn failure_penalty(&mut self, penalty: f64) {
+        self.score_opts.failure_penalty = penalty;
+    }
+
+    /// Weight of latency in combined score
+    pub fn rtt_weight(&mut self, weight: f64) {
+        self.score_opts.rtt_weight = weight;
+    }
+
+    /// Weight of failure rate in combined score
+    pub fn loss_weight(&mut self, weight: f64) {
+        self.score_opts.loss_weight = weight;
+    }
+
+    /// Consecutive failed checks before marking a server down
+    ///
+    /// By default, a server is marked down if all the checks in the check window failed
+    pub fn check_fail_threshold(&mut self, threshold: u32) {
+        self.score_opts.fail_threshold = Some(threshold);
+    }
+
+    /// Consecutive succeeded checks before bringing a down server back. Default is 1
+    pub fn check_recover_threshold(&mut self, threshold: u32) {
+        self.score_opts.recover_threshold = threshold;
+    }
+
+    /// URL for checking TCP connectivity of servers that didn't set their own `check_url`
+    pub fn check_url(&mut self, url: BalancerCheckUrl) {
+        self.check_url = Some(url);
+    }
+
+    /// DNS server for checking connectivity of servers that didn't set their own `check_dns`
+    pub fn check_dns(&mut self, addr: ServerAddr) {
+        self.check_dns = Some(addr);
+    }
+
+    /// STUN server for checking UDP connectivity with `BalancerCheckMethod::Stun`
+    pub fn check_stun(&mut self, addr: ServerAddr) {
+        self.check_stun = Some(addr);
+    }
+
+    /// Method of checking TCP connectivity, `Http` by default
+    pub fn tcp_check_method(&mut self, method: BalancerCheckMethod) {
+        self.tcp_check_method = method;
+    }
+
+    /// Method of checking UDP connectivity, `Dns` by default
+    pub fn udp_check_method(&mut self, method: BalancerCheckMethod) {
+        self.udp_check_method = method;
+    }
+
+    /// Send a burst of `packets` UDP packets through servers in each UDP check, measures packet loss and jitter for scoring
+    pub fn udp_probe_packets(&mut self, packets: u32) {
+        self.udp_probe_packets = Some(packets);
+    }
+
+    /// Servers with latency higher than `latency` are treated as unavailable when choosing the serving tier
+    ///
+    /// By default, a tier is serving until all of its servers are marked down
+    pub fn tier_max_latency(&mut self, latency: Duration) {
+        self.tier_max_latency = Some(latency);
+    }
+
+    /// Switch the best server only if another server's score is better by `margin` ratio of the best server's score
+    ///
+    /// For example, `0.1` means another server must be at least 10% better
+    pub fn switch_margin(&mut self, margin: f64) {
+        self.switch_opts.margin = margin;
+    }
+
+    /// Switch the best server only if another server keeps better by `switch_margin` for `dwell`
+    pub fn switch_dwell(&mut self, dwell: Duration) {
+        self.switch_opts.dwell = dwell;
+    }
+
+    /// Servers added at runtime, which are not in the balancer before, are not chosen until `checks` checks succeeded
+    pub fn warmup_checks(&mut self, checks: u32) {
+        self.warmup_opts.checks = checks;
+    }
+
+    /// Choose servers that are still warming up if no other server is alive
+    pub fn warmup_bypass(&mut self, bypass: bool) {
+        self.warmup_opts.bypass = bypass;
+    }
+
+    /// Choose the best server by scores computed by `expr`, instead of the built-in score
+    pub fn score_expr(&mut self, expr: ScoreExpr) {
+        self.score_expr = Some(Arc::new(expr));
+    }
+
+    /// Send destinations matched by `routes` through servers in their groups
+    pub fn routes(&mut self, routes: ServerRoutes) {
+        self.routes = Some(Arc::new(routes));
+    }
+
+    /// Save servers' probe history into `path` periodically and reload it on startup
+    pub fn state_file(&mut self, path: PathBuf) {
+        self.state_file = Some(Arc::new(ServerStateFile::new(path)));
+    }
+
     fn find_best_idx(servers: &[Arc<ServerIdent>], mode: Mode) -> (usize, usize) {
         if servers.is_empty() {
             trace!("init without any TCP and UDP servers");
@@ -114,14 +304,19 @@ impl PingBalancerBuilder {
         let mut best_udp_idx = 0;
 
         if mode.enable_tcp() {
-            let mut found_tcp_idx = false;
-            for (idx, server) in servers.iter().enumerate() {
-                if PingBalancerContext::check_server_tcp_enabled(server.server_config()) {
+            // The first server in the highest priority tier
+            let found_tcp_idx = match servers
+                .iter()
+                .enumerate()
+                .filter(|(_, server)| PingBalancerContext::check_server_tcp_enabled(server.server_config()))
+                .min_by_key(|(_, server)| server.server_instance_config().tier)
+            {
+                Some((idx, ..)) => {
                     best_tcp_idx = idx;
-                    found_tcp_idx = true;
-                    break;
+                    true
                 }
-            }
+                None => false,
+            };
 
             if !found_tcp_idx {
                 warn!(
@@ -137,14 +332,19 @@ impl PingBalancerBuilder {
         }
 
         if mode.enable_udp() {
-            let mut found_udp_idx = false;
-            for (idx, server) in servers.iter().enumerate() {
-                if PingBalancerContext::check_server_udp_enabled(server.server_config()) {
+            // The first server in the highest priority tier
+            let found_udp_idx = match servers
+                .iter()
+                .enumerate()
+                .filter(|(_, server)| PingBalancerContext::check_server_udp_enabled(server.server_config()))
+                .min_by_key(|(_, server)| server.server_instance_config().tier)
+            {
+                Some((idx, ..)) => {
                     best_udp_idx = idx;
-                    found_udp_idx = true;
-                    break;
+                    true
                 }
-            }
+                None => false,
+            };
 
             if !found_udp_idx {
                 warn!(
@@ -165,10 +365,18 @@ impl PingBalancerBuilder {
     pub async fn build(self) -> io::Result<PingBalancer> {
         if let Some(intv) = self.check_best_interval {
             if intv > self.check_interval {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "check_interval must be >= check_best_interval",
-                ));
+                return Err(io::Error::other("check_interval must be >= check_best_interval"));
+            }
+        }
+
+        // Restore before the first check, so the first choice is made with the history
+        if let Some(ref state_file) = self.state_file {
+            if let Err(err) = state_file.load(&self.servers).await {
+                warn!(
+                    "balancer failed to load state file {}, error: {}",
+                    state_file.path().display(),
+                    err
+                );
             }
         }
 
@@ -179,6 +387,22 @@ impl PingBalancerBuilder {
             self.max_server_rtt,
             self.check_interval,
             self.check_best_interval,
+            self.score_tolerance,
+            self.strategy,
+            self.udp_strategy.unwrap_or(self.strategy),
+            self.score_opts,
+            self.check_url,
+            self.check_dns,
+            self.check_stun,
+            self.tcp_check_method,
+            self.udp_check_method,
+            self.udp_probe_packets,
+            self.tier_max_latency,
+            self.switch_opts,
+            self.warmup_opts,
+            self.score_expr,
+            self.routes,
+            self.state_file,
         )
         .await?;
 
@@ -186,6 +410,8 @@ impl PingBalancerBuilder {
             inner: Arc::new(PingBalancerInner {
                 context: ArcSwap::new(shared_context),
                 task_abortable: SpinMutex::new(task_abortable),
+                update_lock: AsyncMutex::new(()),
+                overrides: SpinMutex::new(ServerOverrides::default()),
             }),
         })
     }
@@ -205,8 +431,19 @@ impl Drop for PingBalancerContextTask {
     }
 }
 
+/// Servers that could be chosen by strategies
+#[derive(Clone, Copy)]
+enum Candidates<'a> {
+    /// Servers in the serving tier
+    Serving,
+    /// Servers in the group and tier
+    Group(&'a str, u32),
+}
+
 struct PingBalancerContext {
     servers: Vec<Arc<ServerIdent>>,
+    has_tcp_server: bool,
+    has_udp_server: bool,
     best_tcp_idx: AtomicUsize,
     best_udp_idx: AtomicUsize,
     context: Arc<ServiceContext>,
@@ -214,27 +451,536 @@ struct PingBalancerContext {
     max_server_rtt: Duration,
     check_interval: Duration,
     check_best_interval: Option<Duration>,
+    score_tolerance: Option<f64>,
+    strategy: BalancerStrategy,
+    udp_strategy: BalancerStrategy,
+    score_opts: ScoreOptions,
+    check_url: Option<BalancerCheckUrl>,
+    check_dns: Option<ServerAddr>,
+    check_stun: Option<ServerAddr>,
+    tcp_check_method: BalancerCheckMethod,
+    udp_check_method: BalancerCheckMethod,
+    udp_probe_packets: Option<u32>,
+    tier_max_latency: Option<Duration>,
+    switch_opts: SwitchOptions,
+    warmup_opts: WarmupOptions,
+    score_expr: Option<Arc<ScoreExpr>>,
+    routes: Option<Arc<ServerRoutes>>,
+    state_file: Option<Arc<ServerStateFile>>,
+    active_tcp_tier: AtomicU32,
+    active_udp_tier: AtomicU32,
+    tcp_switch_pending: SpinMutex<Option<(usize, Instant)>>,
+    udp_switch_pending: SpinMutex<Option<(usize, Instant)>>,
+    tcp_warmup_bypassed: AtomicBool,
+    udp_warmup_bypassed: AtomicBool,
+    pinned_idx: AtomicUsize,
     best_task_notify: Notify,
 }
 
 impl PingBalancerContext {
-    fn best_tcp_server(&self) -> Arc<ServerIdent> {
+    fn best_tcp_server(&self, target_addr: Option<&Address>, client_ip: Option<IpAddr>) -> Arc<ServerIdent> {
         assert!(!self.is_empty(), "no available server");
-        self.servers[self.best_tcp_idx.load(Ordering::Relaxed)].clone()
+        if let Some(server) = self.pinned_server(ServerType::Tcp) {
+            return server;
+        }
+        if let Some(server) = self.route_server(ServerType::Tcp, target_addr, client_ip) {
+            return server;
+        }
+        let best_idx = self.best_tcp_idx.load(Ordering::Relaxed);
+        self.choose_server(ServerType::Tcp, best_idx, target_addr, client_ip, Candidates::Serving)
     }
 
-    fn best_udp_server(&self) -> Arc<ServerIdent> {
+    fn best_udp_server(&self, target_addr: Option<&Address>, client_ip: Option<IpAddr>) -> Arc<ServerIdent> {
         assert!(!self.is_empty(), "no available server");
-        self.servers[self.best_udp_idx.load(Ordering::Relaxed)].clone()
+        if let Some(server) = self.pinned_server(ServerType::Udp) {
+            return server;
+        }
+        if let Some(server) = self.route_server(ServerType::Udp, target_addr, client_ip) {
+            return server;
+        }
+        let best_idx = self.best_udp_idx.load(Ordering::Relaxed);
+        self.choose_server(ServerType::Udp, best_idx, target_addr, client_ip, Candidates::Serving)
+    }
+
+    /// Choose a server in the group that `target_addr` is routed to by `routes`
+    ///
+    /// Returns `None` if target is not routed, or no server in that group is available for `server_type`
+    fn route_server(
+        &self,
+        server_type: ServerType,
+        target_addr: Option<&Address>,
+        client_ip: Option<IpAddr>,
+    ) -> Option<Arc<ServerIdent>> {
+        let target_addr = target_addr?;
+        let group = self.routes.as_ref()?.check_target_group(target_addr)?;
+
+        match self.group_server(server_type, group, Some(target_addr), client_ip) {
+            Some(server) => {
+                trace!(
+                    "{} target {} routed to server group {}",
+                    server_type,
+                    target_addr,
+                    group
+                );
+                Some(server)
+            }
+            None => {
+                trace!(
+                    "{} target {} routed to server group {}, which is down",
+                    server_type,
+                    target_addr,
+                    group
+                );
+                None
+            }
+        }
+    }
+
+    /// Choose a server in `group`
+    ///
+    /// Returns `None` if no server in that group is available for `server_type`
+    fn group_server(
+        &self,
+        server_type: ServerType,
+        group: &str,
+        target_addr: Option<&Address>,
+        client_ip: Option<IpAddr>,
+    ) -> Option<Arc<ServerIdent>> {
+        // The best server in the group, prefers servers that are not down, then by tiers and scores
+        let clock = ScoreClock::now();
+        let (best_idx, best_server) = self
+            .servers
+            .iter()
+            .enumerate()
+            .filter(|(_, server)| {
+                let enabled = match server_type {
+                    ServerType::Tcp => PingBalancerContext::check_server_tcp_enabled(server.server_config()),
+                    ServerType::Udp => PingBalancerContext::check_server_udp_enabled(server.server_config()),
+                };
+                enabled
+                    && self.is_selectable(server, server_type)
+                    && server.server_instance_config().group.as_deref() == Some(group)
+            })
+            .min_by_key(|(_, server)| {
+                let score = match server_type {
+                    ServerType::Tcp => server.tcp_score(),
+                    ServerType::Udp => server.udp_score(),
+                };
+                (
+                    score.is_down(),
+                    server.server_instance_config().tier,
+                    self.selection_score(server, server_type, &clock),
+                )
+            })?;
+
+        let best_down = match server_type {
+            ServerType::Tcp => best_server.tcp_score().is_down(),
+            ServerType::Udp => best_server.udp_score().is_down(),
+        };
+        if best_down {
+            return None;
+        }
+
+        let candidates = Candidates::Group(group, best_server.server_instance_config().tier);
+        Some(self.choose_server(server_type, best_idx, target_addr, client_ip, candidates))
+    }
+
+    fn choose_server(
+        &self,
+        server_type: ServerType,
+        best_idx: usize,
+        target_addr: Option<&Address>,
+        client_ip: Option<IpAddr>,
+        candidates: Candidates<'_>,
+    ) -> Arc<ServerIdent> {
+        let strategy = match server_type {
+            ServerType::Tcp => self.strategy,
+            ServerType::Udp => self.udp_strategy,
+        };
+
+        let server = match (strategy, self.score_tolerance) {
+            (BalancerStrategy::BestScore, None) => self.servers[best_idx].clone(),
+            (BalancerStrategy::BestScore, Some(tolerance)) => {
+                self.choose_weighted_server(server_type, best_idx, tolerance, candidates)
+            }
+            (BalancerStrategy::LeastConnections, tolerance) => {
+                self.choose_least_connections_server(server_type, best_idx, tolerance, candidates)
+            }
+            (BalancerStrategy::ConsistentHash, ..) => match target_addr {
+                Some(target_addr) => self.choose_consistent_hash_server(server_type, best_idx, target_addr, candidates),
+                None => self.servers[best_idx].clone(),
+            },
+            (BalancerStrategy::SourceHash, ..) => match client_ip {
+                Some(client_ip) => self.choose_consistent_hash_server(server_type, best_idx, &client_ip, candidates),
+                None => self.servers[best_idx].clone(),
+            },
+        };
+
+        if !server.is_full() {
+            return server;
+        }
+
+        match self.choose_spillover_server(server_type, candidates) {
+            Some(spillover) => {
+                trace!(
+                    "{} server {} is full, spilled over to {}",
+                    server_type,
+                    ServerConfigFormatter::new(server.server_config()),
+                    ServerConfigFormatter::new(spillover.server_config())
+                );
+                spillover
+            }
+            None => {
+                debug!(
+                    "{} server {} is full, but no other server is available",
+                    server_type,
+                    ServerConfigFormatter::new(server.server_config())
+                );
+                server
+            }
+        }
+    }
+
+    /// Choose the next best server that is not full, when the chosen server has reached its `max_connections`
+    ///
+    /// Prefers `candidates`, then servers that are not down, then by tiers and scores
+    fn choose_spillover_server(&self, server_type: ServerType, candidates: Candidates<'_>) -> Option<Arc<ServerIdent>> {
+        let clock = ScoreClock::now();
+        self.servers
+            .iter()
+            .filter(|server| {
+                let enabled = match server_type {
+                    ServerType::Tcp => PingBalancerContext::check_server_tcp_enabled(server.server_config()),
+                    ServerType::Udp => PingBalancerContext::check_server_udp_enabled(server.server_config()),
+                };
+                enabled && !server.is_full() && self.is_selectable(server, server_type)
+            })
+            .min_by_key(|server| {
+                let score = match server_type {
+                    ServerType::Tcp => server.tcp_score(),
+                    ServerType::Udp => server.udp_score(),
+                };
+                (
+                    !self.is_candidate(server, server_type, candidates),
+                    score.is_down(),
+                    server.server_instance_config().tier,
+                    self.selection_score(server, server_type, &clock),
+                )
+            })
+            .cloned()
+    }
+
+    /// Choose server by weighted rendezvous hashing of `key`, the target address or the client IP
+    ///
+    /// The same key will always be sent through the same server, until that server is
+    /// unavailable (failed all the recent probes) or removed. Only keys of that server will be remapped.
+    fn choose_consistent_hash_server<K: Hash + ?Sized>(
+        &self,
+        server_type: ServerType,
+        best_idx: usize,
+        key: &K,
+        candidates: Candidates<'_>,
+    ) -> Arc<ServerIdent> {
+        let mut target_hasher = DefaultHasher::new();
+        key.hash(&mut target_hasher);
+
+        let mut chosen: Option<(&Arc<ServerIdent>, f64)> = None;
+        for server in self.servers.iter() {
+            let (enabled, score, weight) = match server_type {
+                ServerType::Tcp => (
+                    PingBalancerContext::check_server_tcp_enabled(server.server_config()),
+                    server.tcp_score(),
+                    server.server_config().weight().tcp_weight(),
+                ),
+                ServerType::Udp => (
+                    PingBalancerContext::check_server_udp_enabled(server.server_config()),
+                    server.udp_score(),
+                    server.server_config().weight().udp_weight(),
+                ),
+            };
+            // Servers of weight 0 would score 0 for every key
+            if !enabled
+                || weight <= 0.0
+                || !self.is_candidate(server, server_type, candidates)
+                || score.latency_score() >= MAX_LATENCY_SCORE
+            {
+                continue;
+            }
+
+            let mut hasher = target_hasher.clone();
+            server.server_config().addr().hash(&mut hasher);
+            let hash = hasher.finish();
+
+            // Map hash into (0, 1), then weighted rendezvous score = -weight / ln(h)
+            let h = (hash as f64 + 1.0) / (u64::MAX as f64 + 2.0);
+            let hash_score = -(weight as f64) / h.ln();
+
+            if chosen.map_or(true, |(_, chosen_score)| hash_score > chosen_score) {
+                chosen = Some((server, hash_score));
+            }
+        }
+
+        // The best server is chosen only if no other servers are available, even if its weight is 0
+        match chosen {
+            Some((server, ..)) => server.clone(),
+            None => self.servers[best_idx].clone(),
+        }
+    }
+
+    /// Choose the server that has the least active sessions relative to its weight
+    ///
+    /// Servers that failed all the recent probes are not considered. If `tolerance` is set,
+    /// only servers that have comparable latency scores with the best server are considered.
+    fn choose_least_connections_server(
+        &self,
+        server_type: ServerType,
+        best_idx: usize,
+        tolerance: Option<f64>,
+        candidates: Candidates<'_>,
+    ) -> Arc<ServerIdent> {
+        let best_server = &self.servers[best_idx];
+
+        fn server_score(server: &ServerIdent, server_type: ServerType) -> &ServerScore {
+            match server_type {
+                ServerType::Tcp => server.tcp_score(),
+                ServerType::Udp => server.udp_score(),
+            }
+        }
+        let server_weight = |server: &ServerIdent| match server_type {
+            ServerType::Tcp => server.server_config().weight().tcp_weight(),
+            ServerType::Udp => server.server_config().weight().udp_weight(),
+        };
+        let server_enabled = |server: &ServerIdent| {
+            let enabled = match server_type {
+                ServerType::Tcp => PingBalancerContext::check_server_tcp_enabled(server.server_config()),
+                ServerType::Udp => PingBalancerContext::check_server_udp_enabled(server.server_config()),
+            };
+            enabled && self.is_candidate(server, server_type, candidates)
+        };
+
+        let max_score = match tolerance {
+            Some(tolerance) => server_score(best_server, server_type).latency_score() as f64 * (1.0 + tolerance),
+            None => f64::MAX,
+        };
+
+        let mut chosen: Option<(&Arc<ServerIdent>, f64, u32)> = None;
+        for server in self.servers.iter() {
+            if !server_enabled(server) {
+                continue;
+            }
+
+            let score = server_score(server, server_type);
+            let latency_score = score.latency_score();
+            if latency_score >= MAX_LATENCY_SCORE || latency_score as f64 > max_score {
+                continue;
+            }
+
+            // Load of servers of weight 0 is NaN or infinite
+            let weight = server_weight(server);
+            if weight <= 0.0 {
+                continue;
+            }
+
+            let load = score.active_sessions() as f64 / weight as f64;
+            let better = match chosen {
+                None => true,
+                Some((_, chosen_load, chosen_score)) => {
+                    load < chosen_load || (load == chosen_load && score.score() < chosen_score)
+                }
+            };
+            if better {
+                chosen = Some((server, load, score.score()));
+            }
+        }
+
+        match chosen {
+            Some((server, ..)) => server.clone(),
+            None => best_server.clone(),
+        }
+    }
+
+    /// Choose one of the servers that have comparable latency scores with the best server,
+    /// with probability proportional to their weights
+    fn choose_weighted_server(
+        &self,
+        server_type: ServerType,
+        best_idx: usize,
+        tolerance: f64,
+        candidates: Candidates<'_>,
+    ) -> Arc<ServerIdent> {
+        let best_server = &self.servers[best_idx];
+
+        // Comparing with unweighted scores, weights are only used for distributing among comparable servers
+        let latency_score = |server: &ServerIdent| match server_type {
+            ServerType::Tcp => server.tcp_score().latency_score(),
+            ServerType::Udp => server.udp_score().latency_score(),
+        };
+        let server_weight = |server: &ServerIdent| match server_type {
+            ServerType::Tcp => server.server_config().weight().tcp_weight(),
+            ServerType::Udp => server.server_config().weight().udp_weight(),
+        };
+        let server_enabled = |server: &ServerIdent| {
+            let enabled = match server_type {
+                ServerType::Tcp => PingBalancerContext::check_server_tcp_enabled(server.server_config()),
+                ServerType::Udp => PingBalancerContext::check_server_udp_enabled(server.server_config()),
+            };
+            enabled && self.is_candidate(server, server_type, candidates)
+        };
+
+        let max_score = latency_score(best_server) as f64 * (1.0 + tolerance);
+
+        let mut candidates = Vec::with_capacity(self.servers.len());
+        let mut total_weight = 0.0f64;
+        for server in self.servers.iter() {
+            if server_enabled(server) && latency_score(server) as f64 <= max_score {
+                let weight = server_weight(server) as f64;
+                candidates.push((server, weight));
+                total_weight += weight;
+            }
+        }
+
+        if candidates.len() <= 1 || total_weight <= 0.0 {
+            return best_server.clone();
+        }
+
+        let mut point = rand::random::<f64>() * total_weight;
+        for (server, weight) in candidates {
+            if point < weight {
+                return server.clone();
+            }
+            point -= weight;
+        }
+
+        best_server.clone()
+    }
+
+    /// Check if `server` could be chosen for new sessions of `server_type`,
+    /// which is in the tier that is currently serving and not excluded
+    fn is_serving(&self, server: &ServerIdent, server_type: ServerType) -> bool {
+        let active_tier = match server_type {
+            ServerType::Tcp => self.active_tcp_tier.load(Ordering::Relaxed),
+            ServerType::Udp => self.active_udp_tier.load(Ordering::Relaxed),
+        };
+        server.server_instance_config().tier == active_tier && self.is_selectable(server, server_type)
+    }
+
+    /// Check if `server` is one of the `candidates` for new sessions of `server_type`
+    fn is_candidate(&self, server: &ServerIdent, server_type: ServerType, candidates: Candidates<'_>) -> bool {
+        match candidates {
+            Candidates::Serving => self.is_serving(server, server_type),
+            Candidates::Group(group, tier) => {
+                let instance = server.server_instance_config();
+                instance.group.as_deref() == Some(group)
+                    && instance.tier == tier
+                    && self.is_selectable(server, server_type)
+            }
+        }
+    }
+
+    /// Check if `server` could be chosen for new sessions of `server_type`, which is not excluded and not warming up.
+    /// Servers that are warming up are chosen only if `warmup_bypass` is enabled and no other server is alive
+    fn is_selectable(&self, server: &ServerIdent, server_type: ServerType) -> bool {
+        if server.is_excluded() {
+            return false;
+        }
+        let (score, bypassed) = match server_type {
+            ServerType::Tcp => (server.tcp_score(), &self.tcp_warmup_bypassed),
+            ServerType::Udp => (server.udp_score(), &self.udp_warmup_bypassed),
+        };
+        !score.is_warming_up() || bypassed.load(Ordering::Relaxed)
+    }
+
+    /// Allow choosing servers that are still warming up if `warmup_bypass` is enabled and no other server is alive
+    fn update_warmup_bypassed(&self, server_type: ServerType) {
+        if !self.warmup_opts.bypass {
+            return;
+        }
+
+        let alive = self.servers.iter().any(|server| {
+            let (enabled, score) = match server_type {
+                ServerType::Tcp => (
+                    PingBalancerContext::check_server_tcp_enabled(server.server_config()),
+                    server.tcp_score(),
+                ),
+                ServerType::Udp => (
+                    PingBalancerContext::check_server_udp_enabled(server.server_config()),
+                    server.udp_score(),
+                ),
+            };
+            enabled && !server.is_excluded() && !score.is_warming_up() && !score.is_down()
+        });
+
+        let bypassed = match server_type {
+            ServerType::Tcp => &self.tcp_warmup_bypassed,
+            ServerType::Udp => &self.udp_warmup_bypassed,
+        };
+        if bypassed.swap(!alive, Ordering::Relaxed) == alive {
+            if alive {
+                info!("{} servers that are warming up won't be chosen anymore", server_type);
+            } else {
+                warn!(
+                    "no {} server is alive, choosing servers that are still warming up",
+                    server_type
+                );
+            }
+        }
+    }
+
+    /// Get the pinned server, if it could serve `server_type`
+    fn pinned_server(&self, server_type: ServerType) -> Option<Arc<ServerIdent>> {
+        let server = self.servers.get(self.pinned_idx.load(Ordering::Acquire))?;
+        let enabled = match server_type {
+            ServerType::Tcp => PingBalancerContext::check_server_tcp_enabled(server.server_config()),
+            ServerType::Udp => PingBalancerContext::check_server_udp_enabled(server.server_config()),
+        };
+        if enabled {
+            Some(server.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Apply runtime pinned and excluded servers, and choose the best servers again
+    fn apply_overrides(&self, overrides: &ServerOverrides) {
+        let mut pinned_idx = usize::MAX;
+        for (idx, server) in self.servers.iter().enumerate() {
+            let addr = server.server_config().addr();
+            server.set_excluded(overrides.excluded.contains(addr));
+            if pinned_idx == usize::MAX && overrides.pinned.as_ref() == Some(addr) {
+                pinned_idx = idx;
+            }
+        }
+        self.pinned_idx.store(pinned_idx, Ordering::Release);
+
+        if self.servers.is_empty() {
+            return;
+        }
+        if self.mode.enable_tcp() {
+            self.update_best_idx(ServerType::Tcp, false);
+        }
+        if self.mode.enable_udp() {
+            self.update_best_idx(ServerType::Udp, false);
+        }
     }
 
     #[inline]
     fn is_empty(&self) -> bool {
         self.servers.is_empty()
     }
+
+    #[inline]
+    fn has_tcp_server(&self) -> bool {
+        self.has_tcp_server
+    }
+
+    #[inline]
+    fn has_udp_server(&self) -> bool {
+        self.has_udp_server
+    }
 }
 
 impl PingBalancerContext {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn new(
         mut servers: Vec<Arc<ServerIdent>>,
         context: Arc<ServiceContext>,
@@ -242,6 +988,22 @@ impl PingBalancerContext {
         max_server_rtt: Duration,
         check_interval: Duration,
         check_best_interval: Option<Duration>,
+        score_tolerance: Option<f64>,
+        strategy: BalancerStrategy,
+        udp_strategy: BalancerStrategy,
+        score_opts: ScoreOptions,
+        check_url: Option<BalancerCheckUrl>,
+        check_dns: Option<ServerAddr>,
+        check_stun: Option<ServerAddr>,
+        tcp_check_method: BalancerCheckMethod,
+        udp_check_method: BalancerCheckMethod,
+        udp_probe_packets: Option<u32>,
+        tier_max_latency: Option<Duration>,
+        switch_opts: SwitchOptions,
+        warmup_opts: WarmupOptions,
+        score_expr: Option<Arc<ScoreExpr>>,
+        routes: Option<Arc<ServerRoutes>>,
+        state_file: Option<Arc<ServerStateFile>>,
     ) -> io::Result<(Arc<PingBalancerContext>, PingBalancerContextTask)> {
         let plugin_abortable = {
             // Start plugins for TCP proxies
@@ -305,9 +1067,26 @@ impl PingBalancerContext {
         };
 
         let (best_tcp_idx, best_udp_idx) = PingBalancerBuilder::find_best_idx(&servers, mode);
+        let (active_tcp_tier, active_udp_tier) = if servers.is_empty() {
+            (1, 1)
+        } else {
+            (
+                servers[best_tcp_idx].server_instance_config().tier,
+                servers[best_udp_idx].server_instance_config().tier,
+            )
+        };
+
+        let has_tcp_server = servers
+            .iter()
+            .any(|server| PingBalancerContext::check_server_tcp_enabled(server.server_config()));
+        let has_udp_server = servers
+            .iter()
+            .any(|server| PingBalancerContext::check_server_udp_enabled(server.server_config()));
 
         let balancer_context = PingBalancerContext {
             servers,
+            has_tcp_server,
+            has_udp_server,
             best_tcp_idx: AtomicUsize::new(best_tcp_idx),
             best_udp_idx: AtomicUsize::new(best_udp_idx),
             context,
@@ -315,6 +1094,29 @@ impl PingBalancerContext {
             max_server_rtt,
             check_interval,
             check_best_interval,
+            score_tolerance,
+            strategy,
+            udp_strategy,
+            score_opts,
+            check_url,
+            check_dns,
+            check_stun,
+            tcp_check_method,
+            udp_check_method,
+            udp_probe_packets,
+            tier_max_latency,
+            switch_opts,
+            warmup_opts,
+            score_expr,
+            routes,
+            state_file,
+            active_tcp_tier: AtomicU32::new(active_tcp_tier),
+            active_udp_tier: AtomicU32::new(active_udp_tier),
+            tcp_switch_pending: SpinMutex::new(None),
+            udp_switch_pending: SpinMutex::new(None),
+            tcp_warmup_bypassed: AtomicBool::new(false),
+            udp_warmup_bypassed: AtomicBool::new(false),
+            pinned_idx: AtomicUsize::new(usize::MAX),
             best_task_notify: Notify::new(),
         };
 
@@ -343,6 +1145,24 @@ impl PingBalancerContext {
         self.check_once(true).await;
     }
 
+    fn ping_checker(&self, server: &Arc<ServerIdent>, server_type: ServerType) -> PingChecker {
+        let svr_inst = server.server_instance_config();
+        PingChecker {
+            server: server.clone(),
+            server_type,
+            context: self.context.clone(),
+            max_server_rtt: server.max_server_rtt(),
+            check_method: match server_type {
+                ServerType::Tcp => self.tcp_check_method,
+                ServerType::Udp => self.udp_check_method,
+            },
+            check_url: svr_inst.check_url.clone().or_else(|| self.check_url.clone()),
+            check_dns: svr_inst.check_dns.clone().or_else(|| self.check_dns.clone()),
+            check_stun: self.check_stun.clone(),
+            udp_probe_packets: self.udp_probe_packets,
+        }
+    }
+
     fn check_server_tcp_enabled(svr_cfg: &ServerConfig) -> bool {
         svr_cfg.mode().enable_tcp() && svr_cfg.weight().tcp_weight() > 0.0
     }
@@ -387,40 +1207,56 @@ impl PingBalancerContext {
 
     /// Check each servers' score and update the best server's index
     async fn check_once(&self, first_run: bool) {
+        self.check_servers(first_run, Duration::MAX).await
+    }
+
+    /// Check servers that reached their own check interval, with `slack` tolerance
+    async fn check_due_servers(&self, slack: Duration) {
+        self.check_servers(false, slack).await
+    }
+
+    async fn check_servers(&self, first_run: bool, slack: Duration) {
         let servers = &self.servers;
         if servers.is_empty() {
             return;
         }
 
+        let now = Instant::now();
+
+        let mut tcp_count = 0;
+        let mut udp_count = 0;
         let mut vfut_tcp = Vec::with_capacity(servers.len());
         let mut vfut_udp = Vec::with_capacity(servers.len());
 
         for server in servers.iter() {
             let svr_cfg = server.server_config();
 
-            if self.mode.enable_tcp() && PingBalancerContext::check_server_tcp_enabled(svr_cfg) {
-                let checker = PingChecker {
-                    server: server.clone(),
-                    server_type: ServerType::Tcp,
-                    context: self.context.clone(),
-                    max_server_rtt: self.max_server_rtt,
-                };
+            let check_tcp = self.mode.enable_tcp() && PingBalancerContext::check_server_tcp_enabled(svr_cfg);
+            let check_udp = self.mode.enable_udp() && PingBalancerContext::check_server_udp_enabled(svr_cfg);
+            if check_tcp {
+                tcp_count += 1;
+            }
+            if check_udp {
+                udp_count += 1;
+            }
+
+            if (!check_tcp && !check_udp) || !server.check_due(now, slack) {
+                continue;
+            }
+
+            if check_tcp {
+                let checker = self.ping_checker(server, ServerType::Tcp);
                 vfut_tcp.push(checker.check_update_score());
             }
 
-            if self.mode.enable_udp() && PingBalancerContext::check_server_udp_enabled(svr_cfg) {
-                let checker = PingChecker {
-                    server: server.clone(),
-                    server_type: ServerType::Udp,
-                    context: self.context.clone(),
-                    max_server_rtt: self.max_server_rtt,
-                };
+            if check_udp {
+                let checker = self.ping_checker(server, ServerType::Udp);
                 vfut_udp.push(checker.check_update_score());
             }
         }
 
-        let check_tcp = vfut_tcp.len() > 1;
-        let check_udp = vfut_udp.len() > 1;
+        let check_tcp = tcp_count > 1 && !vfut_tcp.is_empty();
+        let check_udp = udp_count > 1 && !vfut_udp.is_empty();
 
         if !check_tcp && !check_udp {
             return;
@@ -439,17 +1275,7 @@ impl PingBalancerContext {
 
         if self.mode.enable_tcp() && check_tcp {
             let old_best_idx = self.best_tcp_idx.load(Ordering::Acquire);
-
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.tcp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
-            self.best_tcp_idx.store(best_idx, Ordering::Release);
+            let best_idx = self.update_best_idx(ServerType::Tcp, !first_run);
 
             if first_run {
                 info!(
@@ -472,17 +1298,7 @@ impl PingBalancerContext {
 
         if self.mode.enable_udp() && check_udp {
             let old_best_idx = self.best_udp_idx.load(Ordering::Acquire);
-
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.udp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
-            self.best_udp_idx.store(best_idx, Ordering::Release);
+            let best_idx = self.update_best_idx(ServerType::Udp, !first_run);
 
             if first_run {
                 info!(
@@ -525,23 +1341,13 @@ impl PingBalancerContext {
         let mut check_udp = false;
 
         if self.mode.enable_tcp() && PingBalancerContext::check_server_tcp_enabled(best_tcp_svr_cfg) {
-            let checker = PingChecker {
-                server: best_tcp_server.clone(),
-                server_type: ServerType::Tcp,
-                context: self.context.clone(),
-                max_server_rtt: self.max_server_rtt,
-            };
+            let checker = self.ping_checker(best_tcp_server, ServerType::Tcp);
             vfut.push(checker.check_update_score());
             check_tcp = true;
         }
 
         if self.mode.enable_udp() && PingBalancerContext::check_server_udp_enabled(best_udp_svr_cfg) {
-            let checker = PingChecker {
-                server: best_udp_server.clone(),
-                server_type: ServerType::Udp,
-                context: self.context.clone(),
-                max_server_rtt: self.max_server_rtt,
-            };
+            let checker = self.ping_checker(best_udp_server, ServerType::Udp);
             vfut.push(checker.check_update_score());
             check_udp = true;
         }
@@ -550,17 +1356,7 @@ impl PingBalancerContext {
 
         if self.mode.enable_tcp() && check_tcp {
             let old_best_idx = self.best_tcp_idx.load(Ordering::Acquire);
-
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.tcp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
-            self.best_tcp_idx.store(best_idx, Ordering::Release);
+            let best_idx = self.update_best_idx(ServerType::Tcp, true);
 
             if best_idx != old_best_idx {
                 if best_idx != old_best_idx {
@@ -580,17 +1376,7 @@ impl PingBalancerContext {
 
         if self.mode.enable_udp() && check_udp {
             let old_best_idx = self.best_udp_idx.load(Ordering::Acquire);
-
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.udp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
-            self.best_udp_idx.store(best_idx, Ordering::Release);
+            let best_idx = self.update_best_idx(ServerType::Udp, true);
 
             if best_idx != old_best_idx {
                 if best_idx != old_best_idx {
@@ -609,6 +1395,184 @@ impl PingBalancerContext {
         }
     }
 
+    /// Choose the serving tier for `server_type`, which is the highest priority tier (the smallest number)
+    /// that has any available server. A server is unavailable if it was marked down or its latency exceeds `tier_max_latency`.
+    ///
+    /// Falls back to the highest priority tier if there is no available server in any tiers.
+    fn choose_active_tier(&self, server_type: ServerType) -> u32 {
+        let tier_max_latency = self.tier_max_latency.map(|d| d.as_millis() as u32);
+
+        let mut highest_tier = None;
+        let mut available_tier = None;
+        for server in self.servers.iter() {
+            let (enabled, score) = match server_type {
+                ServerType::Tcp => (
+                    PingBalancerContext::check_server_tcp_enabled(server.server_config()),
+                    server.tcp_score(),
+                ),
+                ServerType::Udp => (
+                    PingBalancerContext::check_server_udp_enabled(server.server_config()),
+                    server.udp_score(),
+                ),
+            };
+            if !enabled || !self.is_selectable(server, server_type) {
+                continue;
+            }
+
+            let tier = server.server_instance_config().tier;
+            highest_tier = Some(highest_tier.map_or(tier, |t: u32| t.min(tier)));
+
+            let available = !score.is_down() && tier_max_latency.map_or(true, |max| score.latency() <= max);
+            if available {
+                available_tier = Some(available_tier.map_or(tier, |t: u32| t.min(tier)));
+            }
+        }
+
+        available_tier.or(highest_tier).unwrap_or(1)
+    }
+
+    /// Update the serving tier and the best server in that tier for `server_type`, returns the best server's index
+    ///
+    /// With `hysteresis`, the current best server is kept until another server is significantly better for a while
+    fn update_best_idx(&self, server_type: ServerType, hysteresis: bool) -> usize {
+        let (active_tier, best_idx_ref) = match server_type {
+            ServerType::Tcp => (&self.active_tcp_tier, &self.best_tcp_idx),
+            ServerType::Udp => (&self.active_udp_tier, &self.best_udp_idx),
+        };
+        let old_best_idx = best_idx_ref.load(Ordering::Acquire);
+
+        self.update_warmup_bypassed(server_type);
+
+        let tier = self.choose_active_tier(server_type);
+        let old_tier = active_tier.swap(tier, Ordering::AcqRel);
+        if tier != old_tier {
+            info!(
+                "switched {} servers from tier {} to tier {}",
+                server_type, old_tier, tier
+            );
+        }
+
+        let clock = ScoreClock::now();
+        let mut best_idx = 0;
+        let mut best_score = u32::MAX;
+        let mut old_best_score = None;
+        for (idx, server) in self.servers.iter().enumerate() {
+            let (enabled, score) = match server_type {
+                ServerType::Tcp => (
+                    PingBalancerContext::check_server_tcp_enabled(server.server_config()),
+                    server.tcp_score(),
+                ),
+                ServerType::Udp => (
+                    PingBalancerContext::check_server_udp_enabled(server.server_config()),
+                    server.udp_score(),
+                ),
+            };
+            if !enabled || !self.is_selectable(server, server_type) || server.server_instance_config().tier != tier {
+                continue;
+            }
+
+            let is_down = score.is_down();
+            let score = self.selection_score(server, server_type, &clock);
+            if idx == old_best_idx && !is_down {
+                old_best_score = Some(score);
+            }
+            if score < best_score {
+                best_idx = idx;
+                best_score = score;
+            }
+        }
+
+        // The current best server is switched immediately if it is down or not serving anymore
+        match old_best_score {
+            Some(old_best_score) if hysteresis && tier == old_tier => {
+                best_idx = self.keep_best_idx(server_type, old_best_idx, old_best_score, best_idx, best_score);
+            }
+            _ => {
+                self.switch_pending(server_type).lock().take();
+            }
+        }
+        best_idx_ref.store(best_idx, Ordering::Release);
+
+        best_idx
+    }
+
+    /// Score of `server` for choosing the best server, computed by `score_expr` if it is set
+    fn selection_score(&self, server: &ServerIdent, server_type: ServerType, clock: &ScoreClock) -> u32 {
+        let (score, weight) = match server_type {
+            ServerType::Tcp => (server.tcp_score(), server.server_config().weight().tcp_weight()),
+            ServerType::Udp => (server.udp_score(), server.server_config().weight().udp_weight()),
+        };
+
+        let expr = match self.score_expr {
+            Some(ref expr) => expr,
+            None => return score.score(),
+        };
+
+        let [p50, p95, p99] = score.latency_percentiles();
+        expr.evaluate(&ScoreInputs {
+            score: score.score(),
+            latency: score.latency(),
+            p50,
+            p95,
+            p99,
+            fail_rate: score.fail_rate(),
+            down: score.is_down(),
+            sessions: score.active_sessions(),
+            tier: server.server_instance_config().tier,
+            weight,
+            clock: *clock,
+        })
+    }
+
+    fn switch_pending(&self, server_type: ServerType) -> &SpinMutex<Option<(usize, Instant)>> {
+        match server_type {
+            ServerType::Tcp => &self.tcp_switch_pending,
+            ServerType::Udp => &self.udp_switch_pending,
+        }
+    }
+
+    /// Keep the current best server `old_best_idx`, unless server `best_idx` is better by `switch_margin`
+    /// for at least `switch_dwell`
+    fn keep_best_idx(
+        &self,
+        server_type: ServerType,
+        old_best_idx: usize,
+        old_best_score: u32,
+        best_idx: usize,
+        best_score: u32,
+    ) -> usize {
+        let mut pending = self.switch_pending(server_type).lock();
+
+        let better = (best_score as f64) < (old_best_score as f64) * (1.0 - self.switch_opts.margin);
+        if best_idx == old_best_idx || !better {
+            *pending = None;
+            return old_best_idx;
+        }
+
+        let now = Instant::now();
+        let since = match *pending {
+            Some((idx, since)) if idx == best_idx => since,
+            _ => {
+                *pending = Some((best_idx, now));
+                now
+            }
+        };
+
+        if now.saturating_duration_since(since) >= self.switch_opts.dwell {
+            *pending = None;
+            best_idx
+        } else {
+            trace!(
+                "{} server {} is better than the best server {}, waiting for {:?}",
+                server_type,
+                ServerConfigFormatter::new(self.servers[best_idx].server_config()),
+                ServerConfigFormatter::new(self.servers[old_best_idx].server_config()),
+                self.switch_opts.dwell
+            );
+            old_best_idx
+        }
+    }
+
     async fn checker_task_real(&self) {
         if self.check_best_interval.is_none() {
             return self.checker_task_all_servers().await;
@@ -641,12 +1605,35 @@ impl PingBalancerContext {
             trace!("finished initializing server scores");
         }
 
+        // Servers may have their own check intervals, wake up for the most frequent one
+        let tick = self
+            .servers
+            .iter()
+            .map(|server| server.check_interval())
+            .min()
+            .unwrap_or(self.check_interval);
+
         loop {
-            time::sleep(self.check_interval).await;
+            time::sleep(tick).await;
 
             // Sleep before check.
             // PingBalancer already checked once when constructing
-            self.check_once(false).await;
+            self.check_due_servers(tick / 2).await;
+
+            self.save_state();
+        }
+    }
+
+    /// Save servers' probe history into the state file
+    fn save_state(&self) {
+        if let Some(ref state_file) = self.state_file {
+            if let Err(err) = state_file.save(&self.servers) {
+                warn!(
+                    "balancer failed to save state file {}, error: {}",
+                    state_file.path().display(),
+                    err
+                );
+            }
         }
     }
 
@@ -667,13 +1654,24 @@ impl PingBalancerContext {
     }
 }
 
+/// Servers pinned or excluded at runtime, kept when servers are replaced
+#[derive(Default)]
+struct ServerOverrides {
+    pinned: Option<ServerAddr>,
+    excluded: Vec<ServerAddr>,
+}
+
 struct PingBalancerInner {
     context: ArcSwap<PingBalancerContext>,
     task_abortable: SpinMutex<PingBalancerContextTask>,
+    // Serializes server list updates, which are load-modify-store of `context`
+    update_lock: AsyncMutex<()>,
+    overrides: SpinMutex<ServerOverrides>,
 }
 
 impl Drop for PingBalancerInner {
     fn drop(&mut self) {
+        self.context.load().save_state();
         trace!("ping balancer stopped");
     }
 }
@@ -694,13 +1692,67 @@ impl PingBalancer {
     /// Pick the best TCP server
     pub fn best_tcp_server(&self) -> Arc<ServerIdent> {
         let context = self.inner.context.load();
-        context.best_tcp_server()
+        context.best_tcp_server(None, None)
+    }
+
+    /// Pick the best TCP server for connecting to `target_addr`
+    ///
+    /// Target address is used by strategies that keep session affinity
+    pub fn best_tcp_server_for(&self, target_addr: &Address) -> Arc<ServerIdent> {
+        let context = self.inner.context.load();
+        context.best_tcp_server(Some(target_addr), None)
+    }
+
+    /// Pick the best TCP server for connecting to `target_addr` on behalf of client `client_addr`
+    ///
+    /// Client IP is used by the `source_hash` strategy, so each client always exits via the same server
+    pub fn best_tcp_server_for_client(&self, client_addr: &SocketAddr, target_addr: &Address) -> Arc<ServerIdent> {
+        let context = self.inner.context.load();
+        context.best_tcp_server(Some(target_addr), Some(client_source_ip(client_addr)))
+    }
+
+    /// Pick the best TCP server in `group` for connecting to `target_addr` on behalf of client `client_addr`
+    ///
+    /// Returns `None` if no server in `group` is available, pinned servers and routes are not applied
+    pub fn best_tcp_server_in_group(
+        &self,
+        group: &str,
+        client_addr: &SocketAddr,
+        target_addr: &Address,
+    ) -> Option<Arc<ServerIdent>> {
+        let context = self.inner.context.load();
+        context.group_server(
+            ServerType::Tcp,
+            group,
+            Some(target_addr),
+            Some(client_source_ip(client_addr)),
+        )
     }
 
     /// Pick the best UDP server
     pub fn best_udp_server(&self) -> Arc<ServerIdent> {
         let context = self.inner.context.load();
-        context.best_udp_server()
+        context.best_udp_server(None, None)
+    }
+
+    /// Pick the best UDP server for sending packets to `target_addr`
+    ///
+    /// Target address is used by strategies that keep session affinity
+    pub fn best_udp_server_for(&self, target_addr: &Address) -> Arc<ServerIdent> {
+        let context = self.inner.context.load();
+        context.best_udp_server(Some(target_addr), None)
+    }
+
+    /// Pick the best UDP server for sending packets to `target_addr` on behalf of client `client_addr`
+    ///
+    /// Client IP is used by the `source_hash` strategy, so each client always exits via the same server
+    pub fn best_udp_server_for_client(
+        &self,
+        client_addr: &SocketAddr,
+        target_addr: Option<&Address>,
+    ) -> Arc<ServerIdent> {
+        let context = self.inner.context.load();
+        context.best_udp_server(target_addr, Some(client_source_ip(client_addr)))
     }
 
     /// Check if there is no available server
@@ -710,6 +1762,20 @@ impl PingBalancer {
         context.is_empty()
     }
 
+    /// Check if there is any server that could serve TCP connections
+    #[inline]
+    pub fn has_tcp_server(&self) -> bool {
+        let context = self.inner.context.load();
+        context.has_tcp_server()
+    }
+
+    /// Check if there is any server that could serve UDP associations
+    #[inline]
+    pub fn has_udp_server(&self) -> bool {
+        let context = self.inner.context.load();
+        context.has_udp_server()
+    }
+
     /// Get the server list
     pub fn servers(&self) -> PingServerIter<'_> {
         let context = self.inner.context.load();
@@ -726,49 +1792,164 @@ impl PingBalancer {
         servers: Vec<ServerInstanceConfig>,
         replace_server_sources: &[ServerSource],
     ) -> io::Result<()> {
-        let old_context = self.inner.context.load();
+        let _guard = self.inner.update_lock.lock().await;
 
-        let mut old_servers = old_context.servers.clone();
-        let mut idx = 0;
-        while idx < old_servers.len() {
-            let source_match = replace_server_sources
+        trace!(
+            "ping balancer going to replace servers with {} servers, sources: {:?}",
+            servers.len(),
+            replace_server_sources
+        );
+
+        self.replace_servers(servers, |server| {
+            replace_server_sources
                 .iter()
-                .any(|src| *src == old_servers[idx].server_config().source());
-            if source_match {
-                old_servers.swap_remove(idx);
-            } else {
-                idx += 1;
+                .any(|src| *src == server.server_config().source())
+        })
+        .await
+    }
+
+    /// Add a server into load balancer at runtime, with the source set in `server.config`
+    ///
+    /// Server that has the same address and the same source will be replaced.
+    /// Servers from the other sources are kept, so it won't be overwritten by configuration reloading or online config.
+    pub async fn add_server(&self, server: ServerInstanceConfig) -> io::Result<()> {
+        let _guard = self.inner.update_lock.lock().await;
+
+        let addr = server.config.addr().clone();
+        let source = server.config.source();
+
+        trace!("ping balancer going to add server {}, source: {:?}", addr, source);
+
+        self.replace_servers(vec![server], |old_server| {
+            let svr_cfg = old_server.server_config();
+            svr_cfg.source() == source && *svr_cfg.addr() == addr
+        })
+        .await
+    }
+
+    /// Remove servers that have address `addr` and `source` from load balancer at runtime
+    ///
+    /// Returns `false` if there is no such server
+    pub async fn remove_server(&self, addr: &ServerAddr, source: ServerSource) -> io::Result<bool> {
+        let _guard = self.inner.update_lock.lock().await;
+
+        let matched = |server: &ServerIdent| {
+            let svr_cfg = server.server_config();
+            svr_cfg.source() == source && svr_cfg.addr() == addr
+        };
+
+        if !self.inner.context.load().servers.iter().any(|s| matched(s.as_ref())) {
+            return Ok(false);
+        }
+
+        trace!("ping balancer going to remove server {}, source: {:?}", addr, source);
+
+        self.replace_servers(Vec::new(), matched).await?;
+        Ok(true)
+    }
+
+    /// Pin new sessions to the server that has address `addr`, bypassing scoring. Unpin if `addr` is `None`
+    ///
+    /// Returns `false` if there is no such server
+    pub async fn pin_server(&self, addr: Option<ServerAddr>) -> bool {
+        let _guard = self.inner.update_lock.lock().await;
+
+        let context = self.inner.context.load();
+        if let Some(ref addr) = addr {
+            if !context.servers.iter().any(|s| s.server_config().addr() == addr) {
+                return false;
             }
         }
 
+        let mut overrides = self.inner.overrides.lock();
+        overrides.pinned = addr;
+        context.apply_overrides(&overrides);
+        true
+    }
+
+    /// Exclude servers that have address `addr` from choosing for new sessions, or include them back
+    ///
+    /// Returns `false` if there is no such server
+    pub async fn set_server_excluded(&self, addr: &ServerAddr, excluded: bool) -> bool {
+        let _guard = self.inner.update_lock.lock().await;
+
+        let context = self.inner.context.load();
+        if !context.servers.iter().any(|s| s.server_config().addr() == addr) {
+            return false;
+        }
+
+        let mut overrides = self.inner.overrides.lock();
+        overrides.excluded.retain(|a| a != addr);
+        if excluded {
+            overrides.excluded.push(addr.clone());
+        }
+        context.apply_overrides(&overrides);
+        true
+    }
+
+    /// Check if the server that has address `addr` is pinned
+    pub fn is_server_pinned(&self, addr: &ServerAddr) -> bool {
+        self.inner.overrides.lock().pinned.as_ref() == Some(addr)
+    }
+
+    /// Replace servers that matched `replace` with `servers`, must be called with `update_lock` held
+    async fn replace_servers<F>(&self, servers: Vec<ServerInstanceConfig>, replace: F) -> io::Result<()>
+    where
+        F: Fn(&ServerIdent) -> bool,
+    {
+        let old_context = self.inner.context.load();
+
+        let mut old_servers = old_context.servers.clone();
+        old_servers.retain(|server| !replace(server.as_ref()));
+
         trace!(
-            "ping balancer going to replace {} servers (total: {}) with {} servers, sources: {:?}",
+            "ping balancer going to replace {} servers (total: {}) with {} servers",
             old_context.servers.len() - old_servers.len(),
             old_context.servers.len(),
             servers.len(),
-            replace_server_sources
         );
 
         let mut servers = servers
             .into_iter()
             .map(|s| {
-                Arc::new(ServerIdent::new(
+                let server = ServerIdent::new(
                     old_context.context.clone(),
                     s,
                     old_context.max_server_rtt,
-                    old_context.check_interval * EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW,
-                ))
+                    old_context.check_interval,
+                    old_context.score_opts,
+                );
+
+                // Servers that were already in the balancer keep their warm-up progress.
+                // Nothing to warm up against if the balancer was empty.
+                let old_server = old_context
+                    .servers
+                    .iter()
+                    .find(|old_server| old_server.server_config().addr() == server.server_config().addr());
+                match old_server {
+                    Some(old_server) => server.inherit_warmup(old_server),
+                    None if !old_context.servers.is_empty() => {
+                        server.tcp_score().set_warmup_remaining(old_context.warmup_opts.checks);
+                        server.udp_score().set_warmup_remaining(old_context.warmup_opts.checks);
+                    }
+                    None => {}
+                }
+
+                Arc::new(server)
             })
             .collect::<Vec<Arc<ServerIdent>>>();
 
         // Recreate a new instance for old servers (old server instance may still being held by clients)
         for old_server in old_servers {
-            servers.push(Arc::new(ServerIdent::new(
+            let server = ServerIdent::new(
                 old_context.context.clone(),
                 old_server.server_instance_config().clone(),
                 old_context.max_server_rtt,
-                old_context.check_interval * EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW,
-            )));
+                old_context.check_interval,
+                old_context.score_opts,
+            );
+            server.inherit_warmup(&old_server);
+            servers.push(Arc::new(server));
         }
 
         trace!("ping balancer merged {} new servers", servers.len());
@@ -780,9 +1961,27 @@ impl PingBalancer {
             old_context.max_server_rtt,
             old_context.check_interval,
             old_context.check_best_interval,
+            old_context.score_tolerance,
+            old_context.strategy,
+            old_context.udp_strategy,
+            old_context.score_opts,
+            old_context.check_url.clone(),
+            old_context.check_dns.clone(),
+            old_context.check_stun.clone(),
+            old_context.tcp_check_method,
+            old_context.udp_check_method,
+            old_context.udp_probe_packets,
+            old_context.tier_max_latency,
+            old_context.switch_opts,
+            old_context.warmup_opts,
+            old_context.score_expr.clone(),
+            old_context.routes.clone(),
+            old_context.state_file.clone(),
         )
         .await?;
 
+        shared_context.apply_overrides(&self.inner.overrides.lock());
+
         {
             // Stop the previous task and replace with the new task
             let mut abortable = self.inner.task_abortable.lock();
@@ -808,11 +2007,48 @@ impl Debug for PingBalancer {
     }
 }
 
+/// Client IP used as the key of `source_hash`, IPv4-mapped IPv6 addresses are treated as the IPv4 addresses
+fn client_source_ip(client_addr: &SocketAddr) -> IpAddr {
+    match client_addr.ip() {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(v6),
+        },
+        ip => ip,
+    }
+}
+
+// TransactionID: 0x1234
+// Flags: 0x0100 RD
+// Questions: 0x0001
+// Answer RRs: 0x0000
+// Authority RRs: 0x0000
+// Additional RRs: 0x0000
+// Queries
+//    - QNAME: \x07 firefox \x03 com \x00
+//    - QTYPE: 0x0001 A
+//    - QCLASS: 0x0001 IN
+static CHECK_DNS_QUERY: &[u8] =
+    b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07firefox\x03com\x00\x00\x01\x00\x01";
+
+const STUN_MAGIC_COOKIE: u32 = 0x2112_A442;
+
+/// Maximum bytes read from responses of HTTP checks
+const CHECK_HTTP_MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+/// Interval between packets in a burst of UDP packet probes
+const UDP_PROBE_PACKET_INTERVAL: Duration = Duration::from_millis(20);
+
 struct PingChecker {
     server: Arc<ServerIdent>,
     server_type: ServerType,
     context: Arc<ServiceContext>,
     max_server_rtt: Duration,
+    check_method: BalancerCheckMethod,
+    check_url: Option<BalancerCheckUrl>,
+    check_dns: Option<ServerAddr>,
+    check_stun: Option<ServerAddr>,
+    udp_probe_packets: Option<u32>,
 }
 
 impl PingChecker {
@@ -823,7 +2059,34 @@ impl PingChecker {
             ServerType::Udp => self.server.udp_score(),
         };
 
-        let (score, stat_data) = match self.check_delay().await {
+        let check_result = self.check_delay().await;
+
+        // Servers that failed the check have already been penalized
+        if let (ServerType::Udp, Some(packets), Ok(..)) = (self.server_type, self.udp_probe_packets, &check_result) {
+            match self.probe_udp_packets(packets).await {
+                Ok(probe) => {
+                    trace!(
+                        "probed remote {} server {} with {} packets, {:?}",
+                        self.server_type,
+                        ServerConfigFormatter::new(self.server.server_config()),
+                        packets,
+                        probe
+                    );
+                    server_score.push_packet_probe(probe).await;
+                }
+                Err(err) => {
+                    debug!(
+                        "failed to probe {} server {} with {} packets, error: {}",
+                        self.server_type,
+                        ServerConfigFormatter::new(self.server.server_config()),
+                        packets,
+                        err
+                    );
+                }
+            }
+        }
+
+        let (score, stat_data) = match check_result {
             Ok(d) => server_score.push_score_fetch_statistic(Score::Latency(d)).await,
             // Penalty
             Err(..) => server_score.push_score_fetch_statistic(Score::Errored).await,
@@ -851,53 +2114,135 @@ impl PingChecker {
     /// Detect TCP connectivity with Chromium [Network Portal Detection](https://www.chromium.org/chromium-os/chromiumos-design-docs/network-portal-detection)
     #[allow(dead_code)]
     async fn check_request_tcp_chromium(&self) -> io::Result<()> {
-        use std::io::{Error, ErrorKind};
+        let addr = Address::DomainNameAddress("clients3.google.com".to_owned(), 80);
+        self.check_request_tcp_http(&addr, "clients3.google.com", "/generate_204", |code| code == 204)
+            .await
+    }
+
+    /// Detect TCP connectivity with Firefox's http://detectportal.firefox.com/success.txt
+    async fn check_request_tcp_firefox(&self) -> io::Result<()> {
+        let addr = Address::DomainNameAddress("detectportal.firefox.com".to_owned(), 80);
+        self.check_request_tcp_http(&addr, "detectportal.firefox.com", "/success.txt", |code| {
+            matches!(code, 200 | 204)
+        })
+        .await
+    }
 
-        static GET_BODY: &[u8] =
-            b"GET /generate_204 HTTP/1.1\r\nHost: clients3.google.com\r\nConnection: close\r\nAccept: */*\r\n\r\n";
+    /// Detect TCP connectivity with user's `check_url`, which should respond with 2xx
+    async fn check_request_tcp_url(&self, url: &BalancerCheckUrl) -> io::Result<()> {
+        let addr = Address::from(url.addr());
+        self.check_request_tcp_http(&addr, &url.host(), url.path(), |code| (200..=299).contains(&code))
+            .await
+    }
 
-        let addr = Address::DomainNameAddress("clients3.google.com".to_owned(), 80);
+    /// Fetch `http://host/path` through the server
+    ///
+    /// The whole response (up to `CHECK_HTTP_MAX_RESPONSE_SIZE`) must be relayed back, so servers that accept
+    /// connections but fail in the middle of the tunnel (wrong key, cipher mismatch, firewalled upstream) are failures.
+    async fn check_request_tcp_http(
+        &self,
+        addr: &Address,
+        host: &str,
+        path: &str,
+        accepted: fn(u16) -> bool,
+    ) -> io::Result<()> {
+        use std::io::{Error, ErrorKind};
+
+        let get_body = format!("GET {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\nAccept: */*\r\n\r\n");
 
         let mut stream = ProxyClientStream::connect_with_opts(
             self.context.context(),
             self.server.server_config(),
-            &addr,
+            addr,
             self.server.connect_opts_ref(),
         )
         .await?;
-        stream.write_all(GET_BODY).await?;
-
-        let mut reader = BufReader::new(stream);
+        stream.write_all(get_body.as_bytes()).await?;
+
+        let mut buf = Vec::with_capacity(1024);
+        let mut chunk = [0u8; 4096];
+
+        // Response header
+        let (code, header_len, content_length) = loop {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("truncated response from http://{host}{path}, {:?}", ByteStr::new(&buf)),
+                ));
+            }
+            buf.extend_from_slice(&chunk[..n]);
+
+            let mut headers = [httparse::EMPTY_HEADER; 32];
+            let mut response = httparse::Response::new(&mut headers);
+            match response.parse(&buf) {
+                Ok(httparse::Status::Complete(header_len)) => {
+                    let content_length = response
+                        .headers
+                        .iter()
+                        .find(|h| h.name.eq_ignore_ascii_case("Content-Length"))
+                        .and_then(|h| str::from_utf8(h.value).ok())
+                        .and_then(|v| v.trim().parse::<usize>().ok());
+                    break (response.code.unwrap_or(0), header_len, content_length);
+                }
+                Ok(httparse::Status::Partial) if buf.len() < CHECK_HTTP_MAX_RESPONSE_SIZE => continue,
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("unexpected response from http://{host}{path}, {:?}", ByteStr::new(&buf)),
+                    ));
+                }
+            }
+        };
 
-        let mut buf = Vec::new();
-        reader.read_until(b'\n', &mut buf).await?;
+        if !accepted(code) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "unexpected response from http://{host}{path}, {:?}",
+                    ByteStr::new(&buf[..header_len])
+                ),
+            ));
+        }
 
-        let mut headers = [httparse::EMPTY_HEADER; 1];
-        let mut response = httparse::Response::new(&mut headers);
+        // Response body, delimited by Content-Length or closing the connection
+        let mut body_len = buf.len() - header_len;
+        let expected_len = match content_length {
+            _ if code == 204 || code == 304 => 0,
+            Some(len) => len.min(CHECK_HTTP_MAX_RESPONSE_SIZE),
+            None => CHECK_HTTP_MAX_RESPONSE_SIZE,
+        };
 
-        if let Ok(..) = response.parse(&buf) {
-            if matches!(response.code, Some(204)) {
-                return Ok(());
+        while body_len < expected_len {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                if content_length.is_none() {
+                    break;
+                }
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!(
+                        "truncated response body from http://{host}{path}, received {body_len} of {expected_len} bytes"
+                    ),
+                ));
             }
+            body_len += n;
         }
 
-        Err(Error::new(
-            ErrorKind::InvalidData,
-            format!(
-                "unexpected response from http://clients3.google.com/generate_204, {:?}",
-                ByteStr::new(&buf)
-            ),
-        ))
+        Ok(())
     }
 
-    /// Detect TCP connectivity with Firefox's http://detectportal.firefox.com/success.txt
-    async fn check_request_tcp_firefox(&self) -> io::Result<()> {
-        use std::io::{Error, ErrorKind};
-
-        static GET_BODY: &[u8] =
-            b"GET /success.txt HTTP/1.1\r\nHost: detectportal.firefox.com\r\nConnection: close\r\nAccept: */*\r\n\r\n";
+    /// DNS server for `BalancerCheckMethod::Dns`, 8.8.8.8:53 by default
+    fn check_dns_addr(&self) -> Address {
+        match self.check_dns {
+            Some(ref dns) => Address::from(dns),
+            None => Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(8, 8, 8, 8).into(), 53)),
+        }
+    }
 
-        let addr = Address::DomainNameAddress("detectportal.firefox.com".to_owned(), 80);
+    /// Detect TCP connectivity with a length prefixed DNS query (DNS over TCP)
+    async fn check_request_tcp_dns(&self) -> io::Result<()> {
+        let addr = self.check_dns_addr();
 
         let mut stream = ProxyClientStream::connect_with_opts(
             self.context.context(),
@@ -906,46 +2251,29 @@ impl PingChecker {
             self.server.connect_opts_ref(),
         )
         .await?;
-        stream.write_all(GET_BODY).await?;
 
-        let mut reader = BufReader::new(stream);
+        let mut query = Vec::with_capacity(2 + CHECK_DNS_QUERY.len());
+        query.extend_from_slice(&(CHECK_DNS_QUERY.len() as u16).to_be_bytes());
+        query.extend_from_slice(CHECK_DNS_QUERY);
+        stream.write_all(&query).await?;
 
-        let mut buf = Vec::new();
-        reader.read_until(b'\n', &mut buf).await?;
+        let mut header = [0u8; 2 + 12];
+        stream.read_exact(&mut header).await?;
 
-        let mut headers = [httparse::EMPTY_HEADER; 1];
-        let mut response = httparse::Response::new(&mut headers);
+        if &header[2..4] != b"\x12\x34" {
+            use std::io::{Error, ErrorKind};
 
-        if let Ok(..) = response.parse(&buf) {
-            if matches!(response.code, Some(200) | Some(204)) {
-                return Ok(());
-            }
+            debug!("unexpected response from {}, {:?}", addr, ByteStr::new(&header));
+
+            let err = Error::new(ErrorKind::InvalidData, format!("unexpected response from {}", addr));
+            return Err(err);
         }
 
-        Err(Error::new(
-            ErrorKind::InvalidData,
-            format!(
-                "unexpected response from http://detectportal.firefox.com/success.txt, {:?}",
-                ByteStr::new(&buf)
-            ),
-        ))
+        Ok(())
     }
 
     async fn check_request_udp(&self) -> io::Result<()> {
-        // TransactionID: 0x1234
-        // Flags: 0x0100 RD
-        // Questions: 0x0001
-        // Answer RRs: 0x0000
-        // Authority RRs: 0x0000
-        // Additional RRs: 0x0000
-        // Queries
-        //    - QNAME: \x07 firefox \x03 com \x00
-        //    - QTYPE: 0x0001 A
-        //    - QCLASS: 0x0001 IN
-        static DNS_QUERY: &[u8] =
-            b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07firefox\x03com\x00\x00\x01\x00\x01";
-
-        let addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(8, 8, 8, 8).into(), 53));
+        let addr = self.check_dns_addr();
 
         let client = ProxySocket::connect_with_opts(
             self.context.context(),
@@ -957,7 +2285,7 @@ impl PingChecker {
         let mut control = UdpSocketControlData::default();
         control.client_session_id = rand::random::<u64>();
         control.packet_id = 1;
-        client.send_with_ctrl(&addr, &control, DNS_QUERY).await?;
+        client.send_with_ctrl(&addr, &control, CHECK_DNS_QUERY).await?;
 
         let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
         let (n, ..) = client.recv(&mut buffer).await?;
@@ -968,19 +2296,164 @@ impl PingChecker {
         if dns_answer.len() < 12 || &dns_answer[0..2] != b"\x12\x34" {
             use std::io::{Error, ErrorKind};
 
-            debug!("unexpected response from 8.8.8.8:53, {:?}", ByteStr::new(dns_answer));
+            debug!("unexpected response from {}, {:?}", addr, ByteStr::new(dns_answer));
+
+            let err = Error::new(ErrorKind::InvalidData, format!("unexpected response from {}", addr));
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// STUN server for `BalancerCheckMethod::Stun`, stun.l.google.com:19302 by default
+    fn check_stun_addr(&self) -> Address {
+        match self.check_stun {
+            Some(ref stun) => Address::from(stun),
+            None => Address::DomainNameAddress("stun.l.google.com".to_owned(), 19302),
+        }
+    }
+
+    /// Detect UDP connectivity with a STUN binding request
+    async fn check_request_udp_stun(&self) -> io::Result<()> {
+        let addr = self.check_stun_addr();
+
+        let transaction_id = rand::random::<[u8; 12]>();
+        let request = stun_binding_request(&transaction_id);
+
+        let client = ProxySocket::connect_with_opts(
+            self.context.context(),
+            self.server.server_config(),
+            self.server.connect_opts_ref(),
+        )
+        .await?;
+
+        let mut control = UdpSocketControlData::default();
+        control.client_session_id = rand::random::<u64>();
+        control.packet_id = 1;
+        client.send_with_ctrl(&addr, &control, &request).await?;
+
+        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        let (n, ..) = client.recv(&mut buffer).await?;
+
+        let response = &buffer[..n];
+
+        // Binding Success Response (0x0101) with the same transaction
+        if response.len() < 20
+            || response[0..2] != 0x0101u16.to_be_bytes()
+            || response[4..8] != STUN_MAGIC_COOKIE.to_be_bytes()
+            || response[8..20] != transaction_id
+        {
+            use std::io::{Error, ErrorKind};
+
+            debug!("unexpected response from {}, {:?}", addr, ByteStr::new(response));
 
-            let err = Error::new(ErrorKind::InvalidData, "unexpected response from 8.8.8.8:53");
+            let err = Error::new(ErrorKind::InvalidData, format!("unexpected response from {}", addr));
             return Err(err);
         }
 
         Ok(())
     }
 
+    /// Send a burst of `packets` requests of the UDP check method through the server, measures packet loss and jitter
+    ///
+    /// Requests that are not answered in `max_server_rtt` after the last one was sent are lost
+    async fn probe_udp_packets(&self, packets: u32) -> io::Result<PacketProbe> {
+        // Requests are told apart by DNS query's ID or STUN's transaction ID
+        let (addr, template, key) = match self.check_method {
+            BalancerCheckMethod::Stun => (self.check_stun_addr(), stun_binding_request(&[0u8; 12]).to_vec(), 8..20),
+            _ => (self.check_dns_addr(), CHECK_DNS_QUERY.to_vec(), 0..2),
+        };
+
+        let requests: Vec<Vec<u8>> = (0..packets)
+            .map(|i| {
+                let mut request = template.clone();
+                let random = rand::random::<[u8; 12]>();
+                request[key.clone()].copy_from_slice(&random[..key.len()]);
+                request[key.end - 2..key.end].copy_from_slice(&(i as u16).to_be_bytes());
+                request
+            })
+            .collect();
+
+        let client = ProxySocket::connect_with_opts(
+            self.context.context(),
+            self.server.server_config(),
+            self.server.connect_opts_ref(),
+        )
+        .await?;
+
+        let client_session_id = rand::random::<u64>();
+        let sent_at = SpinMutex::new(vec![None; requests.len()]);
+        let mut latency: Vec<Option<Duration>> = vec![None; requests.len()];
+
+        {
+            let send_fut = async {
+                for (i, request) in requests.iter().enumerate() {
+                    let mut control = UdpSocketControlData::default();
+                    control.client_session_id = client_session_id;
+                    control.packet_id = i as u64 + 1;
+
+                    sent_at.lock()[i] = Some(Instant::now());
+                    client.send_with_ctrl(&addr, &control, request).await?;
+                    time::sleep(UDP_PROBE_PACKET_INTERVAL).await;
+                }
+
+                // Responses of the last requests
+                time::sleep(self.max_server_rtt).await;
+                Ok::<_, io::Error>(())
+            };
+
+            let recv_fut = async {
+                let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+                let mut received = 0;
+                while received < requests.len() {
+                    let (n, ..) = client.recv(&mut buffer).await?;
+                    let response = &buffer[..n];
+                    if response.len() < key.end {
+                        continue;
+                    }
+
+                    let idx = match requests.iter().position(|r| r[key.clone()] == response[key.clone()]) {
+                        Some(idx) => idx,
+                        None => continue,
+                    };
+                    if latency[idx].is_none() {
+                        if let Some(sent) = sent_at.lock()[idx] {
+                            latency[idx] = Some(sent.elapsed());
+                            received += 1;
+                        }
+                    }
+                }
+                Ok::<_, io::Error>(())
+            };
+
+            tokio::select! {
+                r = send_fut => r?,
+                r = recv_fut => r?,
+            }
+        }
+
+        let rtts: Vec<u32> = latency.iter().flatten().map(|d| d.as_millis() as u32).collect();
+        let jitter = if rtts.len() < 2 {
+            0
+        } else {
+            rtts.windows(2).map(|w| w[0].abs_diff(w[1])).sum::<u32>() / (rtts.len() - 1) as u32
+        };
+
+        Ok(PacketProbe {
+            loss: 1.0 - rtts.len() as f64 / requests.len() as f64,
+            jitter,
+        })
+    }
+
     async fn check_request(&self) -> io::Result<()> {
-        match self.server_type {
-            ServerType::Tcp => self.check_request_tcp_firefox().await,
-            ServerType::Udp => self.check_request_udp().await,
+        match (self.server_type, self.check_method) {
+            (ServerType::Tcp, BalancerCheckMethod::Dns) => self.check_request_tcp_dns().await,
+            (ServerType::Tcp, ..) => match self.check_url {
+                Some(ref url) => self.check_request_tcp_url(url).await,
+                None => self.check_request_tcp_firefox().await,
+            },
+            (ServerType::Udp, BalancerCheckMethod::Stun) => self.check_request_udp_stun().await,
+            (ServerType::Udp, ..) => self.check_request_udp().await,
         }
     }
 
@@ -1032,12 +2505,26 @@ impl PingChecker {
     }
 }
 
-struct ServerConfigFormatter<'a> {
+/// STUN Binding Request, without attributes
+///
+///  - Type: 0x0001
+///  - Length: 0x0000
+///  - Magic Cookie: 0x2112A442
+///  - Transaction ID: 12 bytes
+fn stun_binding_request(transaction_id: &[u8; 12]) -> [u8; 20] {
+    let mut request = [0u8; 20];
+    request[0..2].copy_from_slice(&0x0001u16.to_be_bytes());
+    request[4..8].copy_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    request[8..20].copy_from_slice(transaction_id);
+    request
+}
+
+pub(crate) struct ServerConfigFormatter<'a> {
     server_config: &'a ServerConfig,
 }
 
 impl<'a> ServerConfigFormatter<'a> {
-    fn new(server_config: &'a ServerConfig) -> ServerConfigFormatter<'a> {
+    pub(crate) fn new(server_config: &'a ServerConfig) -> ServerConfigFormatter<'a> {
         ServerConfigFormatter { server_config }
     }
 }
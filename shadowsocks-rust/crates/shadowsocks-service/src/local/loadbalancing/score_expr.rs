@@ -0,0 +1,642 @@
+//! Custom scoring policy of the balancer
+//!
+//! A score expression computes each server's score from its probe data, the server with the lowest score is chosen.
+//! The result is truncated to a non-negative integer, like the built-in score, so small values like `fail_rate`
+//! should be scaled up.
+//!
+//! ```plain
+//! p95 + fail_rate * 5000 + (hour >= 9 && hour < 18 ? tier * 1000 : 0)
+//! ```
+//!
+//! Variables:
+//!
+//! - `score` - Built-in score
+//! - `latency` - Latency used by the built-in score, in milliseconds
+//! - `p50`, `p95`, `p99` - Percentiles of checks' RTT in the check window, in milliseconds
+//! - `fail_rate` - Ratio of failed checks in the check window, in `[0, 1]`
+//! - `down` - `1` if the server is marked down, otherwise `0`
+//! - `sessions` - Number of active sessions
+//! - `tier` - Server's `tier`
+//! - `weight` - Server's `tcp_weight` or `udp_weight`
+//! - `hour`, `minute` - Time of the day in UTC
+//! - `weekday` - Day of the week in UTC, `0` is Monday
+//!
+//! Operators are `+ - * / %`, `== != < <= > >=`, `&& || !` and `cond ? a : b`. Functions are `min(a, b, ...)`,
+//! `max(a, b, ...)` and `abs(a)`. Comparisons and logical operators yield `1` or `0`, and any non-zero value is true.
+
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Inputs of a score expression for a server
+#[derive(Debug, Clone, Copy)]
+pub struct ScoreInputs {
+    pub score: u32,
+    pub latency: u32,
+    pub p50: u32,
+    pub p95: u32,
+    pub p99: u32,
+    pub fail_rate: f64,
+    pub down: bool,
+    pub sessions: usize,
+    pub tier: u32,
+    pub weight: f32,
+    pub clock: ScoreClock,
+}
+
+/// Time of the day in UTC
+#[derive(Debug, Clone, Copy)]
+pub struct ScoreClock {
+    pub hour: u32,
+    pub minute: u32,
+    /// `0` is Monday
+    pub weekday: u32,
+}
+
+impl ScoreClock {
+    /// Current time
+    pub fn now() -> ScoreClock {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let days = secs / 86400;
+        let secs_of_day = secs % 86400;
+
+        ScoreClock {
+            hour: (secs_of_day / 3600) as u32,
+            minute: (secs_of_day % 3600 / 60) as u32,
+            // 1970-01-01 is Thursday
+            weekday: ((days + 3) % 7) as u32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Var {
+    Score,
+    Latency,
+    P50,
+    P95,
+    P99,
+    FailRate,
+    Down,
+    Sessions,
+    Tier,
+    Weight,
+    Hour,
+    Minute,
+    Weekday,
+}
+
+impl Var {
+    fn from_name(name: &str) -> Option<Var> {
+        Some(match name {
+            "score" => Var::Score,
+            "latency" => Var::Latency,
+            "p50" => Var::P50,
+            "p95" => Var::P95,
+            "p99" => Var::P99,
+            "fail_rate" => Var::FailRate,
+            "down" => Var::Down,
+            "sessions" => Var::Sessions,
+            "tier" => Var::Tier,
+            "weight" => Var::Weight,
+            "hour" => Var::Hour,
+            "minute" => Var::Minute,
+            "weekday" => Var::Weekday,
+            _ => return None,
+        })
+    }
+
+    fn value(self, inputs: &ScoreInputs) -> f64 {
+        match self {
+            Var::Score => inputs.score as f64,
+            Var::Latency => inputs.latency as f64,
+            Var::P50 => inputs.p50 as f64,
+            Var::P95 => inputs.p95 as f64,
+            Var::P99 => inputs.p99 as f64,
+            Var::FailRate => inputs.fail_rate,
+            Var::Down => bool_value(inputs.down),
+            Var::Sessions => inputs.sessions as f64,
+            Var::Tier => inputs.tier as f64,
+            Var::Weight => inputs.weight as f64,
+            Var::Hour => inputs.clock.hour as f64,
+            Var::Minute => inputs.clock.minute as f64,
+            Var::Weekday => inputs.clock.weekday as f64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Func {
+    Min,
+    Max,
+    Abs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(f64),
+    Var(Var),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+    Call(Func, Vec<Expr>),
+}
+
+fn bool_value(b: bool) -> f64 {
+    if b {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+impl Expr {
+    fn eval(&self, inputs: &ScoreInputs) -> f64 {
+        match *self {
+            Expr::Number(n) => n,
+            Expr::Var(var) => var.value(inputs),
+            Expr::Neg(ref e) => -e.eval(inputs),
+            Expr::Not(ref e) => bool_value(e.eval(inputs) == 0.0),
+            Expr::Binary(op, ref lhs, ref rhs) => {
+                let l = lhs.eval(inputs);
+                match op {
+                    BinaryOp::And => return bool_value(l != 0.0 && rhs.eval(inputs) != 0.0),
+                    BinaryOp::Or => return bool_value(l != 0.0 || rhs.eval(inputs) != 0.0),
+                    _ => {}
+                }
+
+                let r = rhs.eval(inputs);
+                match op {
+                    BinaryOp::Add => l + r,
+                    BinaryOp::Sub => l - r,
+                    BinaryOp::Mul => l * r,
+                    BinaryOp::Div => l / r,
+                    BinaryOp::Rem => l % r,
+                    BinaryOp::Eq => bool_value(l == r),
+                    BinaryOp::Ne => bool_value(l != r),
+                    BinaryOp::Lt => bool_value(l < r),
+                    BinaryOp::Le => bool_value(l <= r),
+                    BinaryOp::Gt => bool_value(l > r),
+                    BinaryOp::Ge => bool_value(l >= r),
+                    BinaryOp::And | BinaryOp::Or => unreachable!(),
+                }
+            }
+            Expr::Cond(ref cond, ref then, ref otherwise) => {
+                if cond.eval(inputs) != 0.0 {
+                    then.eval(inputs)
+                } else {
+                    otherwise.eval(inputs)
+                }
+            }
+            Expr::Call(func, ref args) => {
+                let mut values = args.iter().map(|e| e.eval(inputs));
+                match func {
+                    Func::Min => values.fold(f64::INFINITY, f64::min),
+                    Func::Max => values.fold(f64::NEG_INFINITY, f64::max),
+                    Func::Abs => values.next().unwrap_or(0.0).abs(),
+                }
+            }
+        }
+    }
+}
+
+/// Parsing ScoreExpr error
+#[derive(Debug, Clone)]
+pub struct ScoreExprError(String);
+
+impl Display for ScoreExprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid score expression, {}", self.0)
+    }
+}
+
+impl std::error::Error for ScoreExprError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Op(&'static str),
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, ScoreExprError> {
+    const OPS: [&str; 19] = [
+        "==", "!=", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "!", "?", ":", "(", ")", ",",
+    ];
+
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_digit() || c == '.' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(rest.len());
+            match rest[..len].parse::<f64>() {
+                Ok(n) => tokens.push(Token::Number(n)),
+                Err(..) => return Err(ScoreExprError(format!("invalid number \"{}\"", &rest[..len]))),
+            }
+            len
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..len].to_owned()));
+            len
+        } else {
+            match OPS.iter().find(|op| rest.starts_with(**op)) {
+                Some(op) => {
+                    tokens.push(Token::Op(op));
+                    op.len()
+                }
+                None => return Err(ScoreExprError(format!("unexpected character '{c}'"))),
+            }
+        };
+
+        rest = rest[len..].trim_start();
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(*op),
+            _ => None,
+        }
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<(), ScoreExprError> {
+        if self.eat_op(op) {
+            Ok(())
+        } else {
+            Err(ScoreExprError(format!("expecting \"{op}\"")))
+        }
+    }
+
+    // cond := or ('?' cond ':' cond)?
+    fn parse_cond(&mut self) -> Result<Expr, ScoreExprError> {
+        let cond = self.parse_binary(0)?;
+        if !self.eat_op("?") {
+            return Ok(cond);
+        }
+        let then = self.parse_cond()?;
+        self.expect_op(":")?;
+        let otherwise = self.parse_cond()?;
+        Ok(Expr::Cond(Box::new(cond), Box::new(then), Box::new(otherwise)))
+    }
+
+    // Binary operators, from the lowest precedence to the highest
+    fn parse_binary(&mut self, level: usize) -> Result<Expr, ScoreExprError> {
+        const LEVELS: [&[(&str, BinaryOp)]; 5] = [
+            &[("||", BinaryOp::Or)],
+            &[("&&", BinaryOp::And)],
+            &[
+                ("==", BinaryOp::Eq),
+                ("!=", BinaryOp::Ne),
+                ("<=", BinaryOp::Le),
+                (">=", BinaryOp::Ge),
+                ("<", BinaryOp::Lt),
+                (">", BinaryOp::Gt),
+            ],
+            &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+            &[("*", BinaryOp::Mul), ("/", BinaryOp::Div), ("%", BinaryOp::Rem)],
+        ];
+
+        if level == LEVELS.len() {
+            return self.parse_unary();
+        }
+
+        let mut lhs = self.parse_binary(level + 1)?;
+        loop {
+            let op = match self.peek_op() {
+                Some(op) => LEVELS[level].iter().find(|(s, ..)| *s == op).map(|(_, op)| *op),
+                None => None,
+            };
+            let op = match op {
+                Some(op) => op,
+                None => return Ok(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.parse_binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ScoreExprError> {
+        if self.eat_op("-") {
+            return Ok(Expr::Neg(Box::new(self.parse_unary()?)));
+        }
+        if self.eat_op("!") {
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, ScoreExprError> {
+        let token = match self.tokens.get(self.pos) {
+            Some(t) => t.clone(),
+            None => return Err(ScoreExprError("unexpected end".to_owned())),
+        };
+        self.pos += 1;
+
+        match token {
+            Token::Number(n) => Ok(Expr::Number(n)),
+            Token::Op("(") => {
+                let e = self.parse_cond()?;
+                self.expect_op(")")?;
+                Ok(e)
+            }
+            Token::Op(op) => Err(ScoreExprError(format!("unexpected \"{op}\""))),
+            Token::Ident(name) => {
+                if !self.eat_op("(") {
+                    return match Var::from_name(&name) {
+                        Some(var) => Ok(Expr::Var(var)),
+                        None => Err(ScoreExprError(format!("unknown variable \"{name}\""))),
+                    };
+                }
+
+                let func = match name.as_str() {
+                    "min" => Func::Min,
+                    "max" => Func::Max,
+                    "abs" => Func::Abs,
+                    _ => return Err(ScoreExprError(format!("unknown function \"{name}\""))),
+                };
+
+                let mut args = Vec::new();
+                if !self.eat_op(")") {
+                    loop {
+                        args.push(self.parse_cond()?);
+                        if self.eat_op(")") {
+                            break;
+                        }
+                        self.expect_op(",")?;
+                    }
+                }
+
+                let valid = match func {
+                    Func::Min | Func::Max => !args.is_empty(),
+                    Func::Abs => args.len() == 1,
+                };
+                if !valid {
+                    return Err(ScoreExprError(format!("wrong number of arguments of \"{name}\"")));
+                }
+
+                Ok(Expr::Call(func, args))
+            }
+        }
+    }
+}
+
+/// Score expression, evaluated for each server when choosing the best server
+#[derive(Debug, Clone)]
+pub struct ScoreExpr {
+    expr: Expr,
+    source: String,
+}
+
+impl ScoreExpr {
+    /// Evaluate score of a server, lower is better
+    pub fn evaluate(&self, inputs: &ScoreInputs) -> u32 {
+        let score = self.expr.eval(inputs);
+        if score.is_nan() {
+            u32::MAX
+        } else {
+            score.clamp(0.0, u32::MAX as f64) as u32
+        }
+    }
+}
+
+impl FromStr for ScoreExpr {
+    type Err = ScoreExprError;
+
+    fn from_str(s: &str) -> Result<ScoreExpr, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let expr = parser.parse_cond()?;
+        if parser.pos != parser.tokens.len() {
+            return Err(ScoreExprError("unexpected trailing tokens".to_owned()));
+        }
+
+        Ok(ScoreExpr {
+            expr,
+            source: s.to_owned(),
+        })
+    }
+}
+
+impl Display for ScoreExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn inputs() -> ScoreInputs {
+        ScoreInputs {
+            score: 120,
+            latency: 80,
+            p50: 50,
+            p95: 95,
+            p99: 99,
+            fail_rate: 0.25,
+            down: true,
+            sessions: 7,
+            tier: 2,
+            weight: 1.5,
+            clock: ScoreClock {
+                hour: 10,
+                minute: 30,
+                weekday: 4,
+            },
+        }
+    }
+
+    fn eval(s: &str) -> f64 {
+        s.parse::<ScoreExpr>().unwrap().expr.eval(&inputs())
+    }
+
+    #[test]
+    fn test_score_expr_precedence() {
+        assert_eq!(eval("1 + 2 * 3"), 7.0);
+        assert_eq!(eval("(1 + 2) * 3"), 9.0);
+        assert_eq!(eval("10 - 4 - 3"), 3.0);
+        assert_eq!(eval("24 / 4 / 2"), 3.0);
+        assert_eq!(eval("17 % 5 * 2"), 4.0);
+        assert_eq!(eval("-2 * 3 + 10"), 4.0);
+        assert_eq!(eval("--2"), 2.0);
+        assert_eq!(eval("1 + 2 < 4"), 1.0);
+        assert_eq!(eval("1 < 2 == 1"), 1.0);
+        assert_eq!(eval("1 || 0 && 0"), 1.0);
+        assert_eq!(eval("!0 + 1"), 2.0);
+        assert_eq!(eval("!(0 + 1)"), 0.0);
+    }
+
+    #[test]
+    fn test_score_expr_comparison_and_logical() {
+        assert_eq!(eval("1 == 1"), 1.0);
+        assert_eq!(eval("1 != 1"), 0.0);
+        assert_eq!(eval("1 < 2"), 1.0);
+        assert_eq!(eval("2 <= 2"), 1.0);
+        assert_eq!(eval("2 > 2"), 0.0);
+        assert_eq!(eval("2 >= 3"), 0.0);
+        assert_eq!(eval("2 && 3"), 1.0);
+        assert_eq!(eval("0 && 3"), 0.0);
+        assert_eq!(eval("0 || -1"), 1.0);
+        assert_eq!(eval("0 || 0"), 0.0);
+        assert_eq!(eval("!5"), 0.0);
+    }
+
+    #[test]
+    fn test_score_expr_conditional() {
+        assert_eq!(eval("1 ? 10 : 20"), 10.0);
+        assert_eq!(eval("0 ? 10 : 20"), 20.0);
+        // Right associative
+        assert_eq!(eval("0 ? 1 : 0 ? 2 : 3"), 3.0);
+        assert_eq!(eval("1 ? 0 ? 1 : 2 : 3"), 2.0);
+        assert_eq!(eval("1 + (hour >= 9 && hour < 18 ? tier * 1000 : 0)"), 2001.0);
+        // Short-circuit doesn't evaluate the other branch
+        assert_eq!(eval("1 ? 5 : 0 / 0"), 5.0);
+        assert_eq!(eval("0 && 0 / 0"), 0.0);
+    }
+
+    #[test]
+    fn test_score_expr_variables() {
+        assert_eq!(eval("score"), 120.0);
+        assert_eq!(eval("latency"), 80.0);
+        assert_eq!(eval("p50"), 50.0);
+        assert_eq!(eval("p95"), 95.0);
+        assert_eq!(eval("p99"), 99.0);
+        assert_eq!(eval("fail_rate"), 0.25);
+        assert_eq!(eval("down"), 1.0);
+        assert_eq!(eval("sessions"), 7.0);
+        assert_eq!(eval("tier"), 2.0);
+        assert_eq!(eval("weight"), 1.5);
+        assert_eq!(eval("hour"), 10.0);
+        assert_eq!(eval("minute"), 30.0);
+        assert_eq!(eval("weekday"), 4.0);
+    }
+
+    #[test]
+    fn test_score_expr_functions() {
+        assert_eq!(eval("min(3, 1, 2)"), 1.0);
+        assert_eq!(eval("min(3)"), 3.0);
+        assert_eq!(eval("max(3, 1, 2)"), 3.0);
+        assert_eq!(eval("max(p50, p95 - 100)"), 50.0);
+        assert_eq!(eval("abs(-4)"), 4.0);
+        assert_eq!(eval("abs(2 - 5) * 2"), 6.0);
+        assert_eq!(eval("min(max(1, 2), abs(-3))"), 2.0);
+    }
+
+    #[test]
+    fn test_score_expr_evaluate() {
+        let e = "p95 + fail_rate * 5000".parse::<ScoreExpr>().unwrap();
+        assert_eq!(e.evaluate(&inputs()), 1345);
+        assert_eq!(e.to_string(), "p95 + fail_rate * 5000");
+
+        // Truncated and clamped to u32
+        assert_eq!("1.9".parse::<ScoreExpr>().unwrap().evaluate(&inputs()), 1);
+        assert_eq!("-5".parse::<ScoreExpr>().unwrap().evaluate(&inputs()), 0);
+        // No exponent notation
+        assert!("1e10".parse::<ScoreExpr>().is_err());
+        assert_eq!(
+            "10000000000".parse::<ScoreExpr>().unwrap().evaluate(&inputs()),
+            u32::MAX
+        );
+    }
+
+    #[test]
+    fn test_score_expr_division_by_zero_and_nan() {
+        assert_eq!(eval("1 / 0"), f64::INFINITY);
+        assert!(eval("0 / 0").is_nan());
+        assert!(eval("1 % 0").is_nan());
+
+        // Worst score, so the server is never preferred
+        for s in ["1 / 0", "-1 / 0 * -1", "0 / 0", "5 % 0", "max(0 / 0, 1) * 0 / 0"] {
+            assert_eq!(s.parse::<ScoreExpr>().unwrap().evaluate(&inputs()), u32::MAX, "{s}");
+        }
+        assert_eq!("-1 / 0".parse::<ScoreExpr>().unwrap().evaluate(&inputs()), 0);
+
+        // NaN is not equal to anything and is true
+        assert_eq!(eval("0 / 0 == 0 / 0"), 0.0);
+        assert_eq!(eval("0 / 0 ? 1 : 2"), 1.0);
+    }
+
+    #[test]
+    fn test_score_expr_invalid() {
+        for s in [
+            "",
+            "   ",
+            "(1 + 2",
+            "1 + 2)",
+            "((p50)",
+            "min(1, 2",
+            "1 +",
+            "* 2",
+            "1 2",
+            "p50 p95",
+            "1 ? 2",
+            "1 ? 2 : ",
+            "rtt",
+            "score()",
+            "sqrt(4)",
+            "min()",
+            "max()",
+            "abs()",
+            "abs(1, 2)",
+            "min(1,)",
+            "min(,1)",
+            "1.2.3",
+            "1 $ 2",
+            "1 = 2",
+            "1 & 2",
+            "p95 ^ 2",
+            "()",
+        ] {
+            assert!(s.parse::<ScoreExpr>().is_err(), "{s:?}");
+        }
+    }
+}
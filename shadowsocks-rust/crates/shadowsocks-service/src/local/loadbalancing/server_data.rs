@@ -3,34 +3,68 @@
 use std::{
     fmt::{self, Debug},
     sync::{
-        atomic::{AtomicU32, Ordering},
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use shadowsocks::{net::ConnectOpts, ServerConfig};
+use spin::Mutex as SpinMutex;
 use tokio::sync::Mutex;
 
-use crate::{config::ServerInstanceConfig, local::context::ServiceContext};
+use crate::{config::ServerInstanceConfig, local::context::ServiceContext, net::FlowStat};
 
-use super::server_stat::{Score, ServerStat, ServerStatData};
+use super::server_stat::{
+    LatencyHistogram, PacketProbe, Score, ScoreOptions, ServerStat, ServerStatData,
+    EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW,
+};
+
+/// Percentiles of `ServerScore::latency_percentiles`
+const LATENCY_PERCENTILES: [f64; 3] = [0.50, 0.95, 0.99];
 
 /// Server's statistic score
 pub struct ServerScore {
     stat_data: Mutex<ServerStat>,
     score: AtomicU32,
+    latency_score: AtomicU32,
+    latency: AtomicU32,
+    latency_percentiles: [AtomicU32; 3],
+    fail_rate: AtomicU32,
+    down: AtomicBool,
+    warmup_remaining: AtomicU32,
+    active_sessions: Arc<AtomicUsize>,
+    sessions: AtomicUsize,
+    failures: AtomicUsize,
 }
 
 impl ServerScore {
     /// Create a `ServerScore`
-    pub fn new(user_weight: f32, max_server_rtt: Duration, check_window: Duration) -> ServerScore {
+    pub fn new(
+        user_weight: f32,
+        max_server_rtt: Duration,
+        check_window: Duration,
+        score_opts: ScoreOptions,
+    ) -> ServerScore {
         let max_server_rtt = max_server_rtt.as_millis() as u32;
         assert!(max_server_rtt > 0);
 
         ServerScore {
-            stat_data: Mutex::new(ServerStat::new(user_weight, max_server_rtt, check_window)),
+            stat_data: Mutex::new(ServerStat::new(user_weight, max_server_rtt, check_window, score_opts)),
             score: AtomicU32::new(u32::MAX),
+            latency_score: AtomicU32::new(u32::MAX),
+            latency: AtomicU32::new(u32::MAX),
+            latency_percentiles: [
+                AtomicU32::new(u32::MAX),
+                AtomicU32::new(u32::MAX),
+                AtomicU32::new(u32::MAX),
+            ],
+            fail_rate: AtomicU32::new(1.0f32.to_bits()),
+            down: AtomicBool::new(false),
+            warmup_remaining: AtomicU32::new(0),
+            active_sessions: Arc::new(AtomicUsize::new(0)),
+            sessions: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
         }
     }
 
@@ -39,40 +73,180 @@ impl ServerScore {
         self.score.load(Ordering::Acquire)
     }
 
+    /// Get server's current statistic scores without applying user's weight
+    pub fn latency_score(&self) -> u32 {
+        self.latency_score.load(Ordering::Acquire)
+    }
+
+    /// Get number of sessions that are currently relaying through the server
+    pub fn active_sessions(&self) -> usize {
+        self.active_sessions.load(Ordering::Relaxed)
+    }
+
+    /// Get number of sessions that have relayed through the server
+    pub fn sessions(&self) -> usize {
+        self.sessions.load(Ordering::Relaxed)
+    }
+
+    /// Get number of failed requests reported by `report_failure`
+    pub fn failures(&self) -> usize {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Mark a new session relaying through the server, which lasts until the returned guard is dropped
+    pub fn start_session(&self) -> ServerSessionGuard {
+        self.active_sessions.fetch_add(1, Ordering::Relaxed);
+        self.sessions.fetch_add(1, Ordering::Relaxed);
+        ServerSessionGuard {
+            active_sessions: self.active_sessions.clone(),
+        }
+    }
+
+    /// Get server's latency (in millisec) that is used for scoring
+    pub fn latency(&self) -> u32 {
+        self.latency.load(Ordering::Acquire)
+    }
+
+    /// Get server's latency (in millisec) at P50, P95 and P99 of the check window
+    pub fn latency_percentiles(&self) -> [u32; 3] {
+        [
+            self.latency_percentiles[0].load(Ordering::Acquire),
+            self.latency_percentiles[1].load(Ordering::Acquire),
+            self.latency_percentiles[2].load(Ordering::Acquire),
+        ]
+    }
+
+    /// Get ratio of failed probes in the check window
+    pub fn fail_rate(&self) -> f64 {
+        f32::from_bits(self.fail_rate.load(Ordering::Acquire)) as f64
+    }
+
     /// Append a `Score` into statistic and recalculate score of the server
     pub async fn push_score(&self, score: Score) -> u32 {
-        let updated_score = {
-            let mut stat = self.stat_data.lock().await;
-            stat.push_score(score)
-        };
-        self.score.store(updated_score, Ordering::Release);
-        updated_score
+        self.push_score_fetch_statistic(score).await.0
     }
 
     /// Append a `Score` into statistic and recalculate score of the server
     pub async fn push_score_fetch_statistic(&self, score: Score) -> (u32, ServerStatData) {
-        let (updated_score, data) = {
-            let mut stat = self.stat_data.lock().await;
-            (stat.push_score(score), stat.data().clone())
-        };
+        if let Score::Latency(..) = score {
+            let _ = self
+                .warmup_remaining
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+        }
+
+        let mut stat = self.stat_data.lock().await;
+        let updated_score = stat.push_score(score);
+        self.update_stat(&stat, updated_score);
+        (updated_score, *stat.data())
+    }
+
+    /// Restore scores with their ages, from the oldest to the newest, which were saved by `try_score_history`
+    ///
+    /// Scores older than the check window are ignored
+    pub async fn restore_score_history(&self, history: &[(Score, Duration)]) {
+        let now = Instant::now();
+
+        let mut stat = self.stat_data.lock().await;
+        let mut updated_score = None;
+        for (score, age) in history {
+            if let Some(inst) = now.checked_sub(*age) {
+                updated_score = Some(stat.push_score_at(*score, inst));
+            }
+        }
+        if let Some(updated_score) = updated_score {
+            self.update_stat(&stat, updated_score);
+        }
+    }
+
+    /// Get scores in the check window with their ages, from the oldest to the newest
+    ///
+    /// `None` if the statistic is being updated
+    pub fn try_score_history(&self) -> Option<Vec<(Score, Duration)>> {
+        self.stat_data.try_lock().ok().map(|stat| stat.score_history())
+    }
+
+    fn update_stat(&self, stat: &ServerStat, updated_score: u32) {
         self.score.store(updated_score, Ordering::Release);
-        (updated_score, data)
+        self.latency_score.store(stat.latency_score(), Ordering::Release);
+        self.latency.store(stat.latency(), Ordering::Release);
+        self.fail_rate
+            .store((stat.data().fail_rate as f32).to_bits(), Ordering::Release);
+        for (latency, p) in self.latency_percentiles.iter().zip(LATENCY_PERCENTILES) {
+            latency.store(stat.latency_percentile(p), Ordering::Release);
+        }
+        self.down.store(stat.is_down(), Ordering::Release);
+    }
+
+    /// Append a `PacketProbe` into statistic, which takes effect in the next pushed `Score`
+    pub async fn push_packet_probe(&self, probe: PacketProbe) {
+        self.stat_data.lock().await.push_packet_probe(probe);
     }
 
     /// Report request failure of this server, which will eventually records an `Errored` score
     pub async fn report_failure(&self) -> u32 {
+        self.failures.fetch_add(1, Ordering::Relaxed);
         self.push_score(Score::Errored).await
     }
 
     /// Get statistic data
     pub async fn stat_data(&self) -> ServerStatData {
-        self.stat_data.lock().await.data().clone()
+        *self.stat_data.lock().await.data()
+    }
+
+    /// Get the latest `n` scores in the check window, from the oldest to the newest
+    pub async fn recent_scores(&self, n: usize) -> Vec<Score> {
+        self.stat_data.lock().await.recent_scores(n)
+    }
+
+    /// Get latency histogram of succeeded probes in the check window
+    pub async fn latency_histogram(&self) -> LatencyHistogram {
+        self.stat_data.lock().await.latency_histogram().clone()
+    }
+
+    /// Check if server is marked down by consecutive failed probes
+    pub fn is_down(&self) -> bool {
+        self.down.load(Ordering::Acquire)
+    }
+
+    /// Check if server still needs succeeded probes before being chosen
+    pub fn is_warming_up(&self) -> bool {
+        self.warmup_remaining() > 0
+    }
+
+    /// Get number of succeeded probes that server still needs before being chosen
+    pub fn warmup_remaining(&self) -> u32 {
+        self.warmup_remaining.load(Ordering::Acquire)
+    }
+
+    /// Require `n` succeeded probes before server could be chosen
+    pub fn set_warmup_remaining(&self, n: u32) {
+        self.warmup_remaining.store(n, Ordering::Release);
     }
 }
 
 impl Debug for ServerScore {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("ServerScore").field("score", &self.score()).finish()
+        f.debug_struct("ServerScore")
+            .field("score", &self.score())
+            .field("active_sessions", &self.active_sessions())
+            .finish()
+    }
+}
+
+/// An active session of a server, created by `ServerScore::start_session`
+pub struct ServerSessionGuard {
+    active_sessions: Arc<AtomicUsize>,
+}
+
+impl Drop for ServerSessionGuard {
+    fn drop(&mut self) {
+        self.active_sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Debug for ServerSessionGuard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ServerSessionGuard").finish()
     }
 }
 
@@ -83,15 +257,24 @@ pub struct ServerIdent {
     udp_score: ServerScore,
     svr_cfg: ServerInstanceConfig,
     connect_opts: ConnectOpts,
+    flow_stat: Arc<FlowStat>,
+    excluded: AtomicBool,
+    max_server_rtt: Duration,
+    check_interval: Duration,
+    last_checked: SpinMutex<Option<Instant>>,
 }
 
 impl ServerIdent {
     /// Create a `ServerIdent`
+    ///
+    /// `max_server_rtt`, `check_interval` and `score_opts` are the balancer's global options,
+    /// which could be overridden by the server's private options in `svr_cfg`
     pub fn new(
         context: Arc<ServiceContext>,
         svr_cfg: ServerInstanceConfig,
         max_server_rtt: Duration,
-        check_window: Duration,
+        check_interval: Duration,
+        mut score_opts: ScoreOptions,
     ) -> ServerIdent {
         #[allow(unused_mut)]
         let mut connect_opts = context.connect_opts_ref().clone();
@@ -109,11 +292,48 @@ impl ServerIdent {
             connect_opts.bind_interface = Some(bind_interface.clone());
         }
 
+        // Pinned to an uplink, it won't be balanced across uplinks
+        if let Some(ref name) = svr_cfg.uplink {
+            if let Some(uplink) = context.uplinks().and_then(|u| u.find(name)) {
+                uplink.apply(&mut connect_opts);
+            }
+        }
+
+        if let Some(nodelay) = svr_cfg.no_delay {
+            connect_opts.tcp.nodelay = nodelay;
+        }
+
+        let max_server_rtt = svr_cfg.check_timeout.unwrap_or(max_server_rtt);
+        let check_interval = svr_cfg.check_interval.unwrap_or(check_interval);
+        let check_window = check_interval * EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW;
+
+        if let Some(threshold) = svr_cfg.check_fail_threshold {
+            score_opts.fail_threshold = Some(threshold);
+        }
+        if let Some(threshold) = svr_cfg.check_recover_threshold {
+            score_opts.recover_threshold = threshold;
+        }
+
         ServerIdent {
-            tcp_score: ServerScore::new(svr_cfg.config.weight().tcp_weight(), max_server_rtt, check_window),
-            udp_score: ServerScore::new(svr_cfg.config.weight().udp_weight(), max_server_rtt, check_window),
+            tcp_score: ServerScore::new(
+                svr_cfg.config.weight().tcp_weight(),
+                max_server_rtt,
+                check_window,
+                score_opts,
+            ),
+            udp_score: ServerScore::new(
+                svr_cfg.config.weight().udp_weight(),
+                max_server_rtt,
+                check_window,
+                score_opts,
+            ),
+            flow_stat: Arc::new(FlowStat::with_parent(context.flow_stat())),
+            excluded: AtomicBool::new(false),
             svr_cfg,
             connect_opts,
+            max_server_rtt,
+            check_interval,
+            last_checked: SpinMutex::new(None),
         }
     }
 
@@ -133,6 +353,45 @@ impl ServerIdent {
         &self.svr_cfg
     }
 
+    /// Flow statistic of traffic relayed through this server, which also counts into the global one
+    pub fn flow_stat(&self) -> Arc<FlowStat> {
+        self.flow_stat.clone()
+    }
+
+    /// Flow statistic reference of traffic relayed through this server
+    pub fn flow_stat_ref(&self) -> &FlowStat {
+        &self.flow_stat
+    }
+
+    /// Check if server is excluded from choosing for new sessions
+    pub fn is_excluded(&self) -> bool {
+        self.excluded.load(Ordering::Relaxed)
+    }
+
+    /// Exclude server from choosing for new sessions, it is still being checked
+    pub fn set_excluded(&self, excluded: bool) {
+        self.excluded.store(excluded, Ordering::Relaxed);
+    }
+
+    /// Number of TCP and UDP sessions that are currently relaying through the server
+    pub fn active_sessions(&self) -> usize {
+        self.tcp_score.active_sessions() + self.udp_score.active_sessions()
+    }
+
+    /// Check if server has reached its `max_connections`
+    pub fn is_full(&self) -> bool {
+        match self.svr_cfg.max_connections {
+            Some(max) => self.active_sessions() >= max,
+            None => false,
+        }
+    }
+
+    /// Continue warming up from `old`, which is the previous instance of the same server
+    pub fn inherit_warmup(&self, old: &ServerIdent) {
+        self.tcp_score.set_warmup_remaining(old.tcp_score.warmup_remaining());
+        self.udp_score.set_warmup_remaining(old.udp_score.warmup_remaining());
+    }
+
     pub fn tcp_score(&self) -> &ServerScore {
         &self.tcp_score
     }
@@ -140,4 +399,26 @@ impl ServerIdent {
     pub fn udp_score(&self) -> &ServerScore {
         &self.udp_score
     }
+
+    /// Timeout of each check
+    pub fn max_server_rtt(&self) -> Duration {
+        self.max_server_rtt
+    }
+
+    /// Interval between each check
+    pub fn check_interval(&self) -> Duration {
+        self.check_interval
+    }
+
+    /// Returns `true` and records `now` as the last checked time if `check_interval` has elapsed, tolerating `slack`
+    pub fn check_due(&self, now: Instant, slack: Duration) -> bool {
+        let mut last_checked = self.last_checked.lock();
+        if let Some(last) = *last_checked {
+            if now.saturating_duration_since(last).saturating_add(slack) < self.check_interval {
+                return false;
+            }
+        }
+        *last_checked = Some(now);
+        true
+    }
 }
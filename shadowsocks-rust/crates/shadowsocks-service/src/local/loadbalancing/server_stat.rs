@@ -5,10 +5,19 @@ use std::{
     time::{Duration, Instant},
 };
 
+use crate::config::BalancerScoreMode;
+
 /// Interval between each check
 pub const DEFAULT_CHECK_INTERVAL_SEC: u64 = 10;
 /// Timeout of each check
 pub const DEFAULT_CHECK_TIMEOUT_SEC: u64 = 5; // A common connection timeout of 5 seconds.
+/// Latency score of a server that failed (or timed out) all the probes
+pub const MAX_LATENCY_SCORE: u32 = 10000;
+/// Number of checks expected in a check window
+pub const EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW: u32 = 67;
+/// Upper bounds (in millisec) of latency histogram's buckets. Latency higher than the last bound is counted in an extra bucket
+pub const LATENCY_HISTOGRAM_BOUNDS: [u32; 14] =
+    [10, 20, 50, 100, 150, 200, 300, 500, 750, 1000, 2000, 3000, 5000, 10000];
 
 /// Statistic score
 #[derive(Debug, Copy, Clone)]
@@ -19,6 +28,122 @@ pub enum Score {
     Errored,
 }
 
+/// Quality of a UDP path, measured by a burst of probe packets
+#[derive(Debug, Clone, Copy)]
+pub struct PacketProbe {
+    /// Ratio of probe packets that were not answered, in `[0, 1]`
+    pub loss: f64,
+    /// Mean difference of latency (in millisec) between consecutive answered packets
+    pub jitter: u32,
+}
+
+/// Options of calculating server's score
+#[derive(Debug, Clone, Copy)]
+pub struct ScoreOptions {
+    /// Scoring function
+    pub mode: BalancerScoreMode,
+    /// Smoothing factor of latency's Exponentially Weighted Moving Average, in `(0, 1]`
+    ///
+    /// Latency median in the check window will be used if it is not set
+    pub ewma_alpha: Option<f64>,
+    /// Latency of a failed check in EWMA, as a multiple of MAX server's RTT
+    pub failure_penalty: f64,
+    /// Weight of latency in combined score
+    pub rtt_weight: f64,
+    /// Weight of failure rate in combined score
+    pub loss_weight: f64,
+    /// Consecutive failures before marking server down
+    ///
+    /// Server will be marked down if all the probes in the check window failed if it is not set
+    pub fail_threshold: Option<u32>,
+    /// Consecutive successes before bringing a down server back
+    pub recover_threshold: u32,
+}
+
+impl Default for ScoreOptions {
+    fn default() -> ScoreOptions {
+        ScoreOptions {
+            mode: BalancerScoreMode::default(),
+            ewma_alpha: None,
+            failure_penalty: 1.0,
+            rtt_weight: 1.0,
+            loss_weight: 3.0,
+            fail_threshold: None,
+            recover_threshold: 1,
+        }
+    }
+}
+
+/// Histogram of succeeded probes' latency
+///
+/// Percentiles show what a single average hides, for example a server that is fast but stalls for seconds periodically
+#[derive(Debug, Clone, Default)]
+pub struct LatencyHistogram {
+    counts: [u32; LATENCY_HISTOGRAM_BOUNDS.len() + 1],
+}
+
+impl LatencyHistogram {
+    fn bucket(latency: u32) -> usize {
+        LATENCY_HISTOGRAM_BOUNDS
+            .iter()
+            .position(|bound| latency <= *bound)
+            .unwrap_or(LATENCY_HISTOGRAM_BOUNDS.len())
+    }
+
+    fn add(&mut self, latency: u32) {
+        self.counts[LatencyHistogram::bucket(latency)] += 1;
+    }
+
+    fn remove(&mut self, latency: u32) {
+        let count = &mut self.counts[LatencyHistogram::bucket(latency)];
+        *count = count.saturating_sub(1);
+    }
+
+    /// Number of samples in each bucket of `LATENCY_HISTOGRAM_BOUNDS`
+    pub fn counts(&self) -> &[u32] {
+        &self.counts
+    }
+
+    /// Total number of samples
+    pub fn total(&self) -> u32 {
+        self.counts.iter().sum()
+    }
+
+    /// Estimated latency (in millisec) at percentile `p` in `[0, 1]`, `None` if there is no sample
+    ///
+    /// Samples are assumed to be spread evenly in a bucket. Latency in the extra bucket is estimated as the last bound
+    pub fn percentile(&self, p: f64) -> Option<u32> {
+        let total = self.total();
+        if total == 0 {
+            return None;
+        }
+
+        // 1-based rank of the sample
+        let rank = (p * total as f64).ceil().max(1.0);
+
+        let mut seen = 0;
+        for (idx, count) in self.counts.iter().copied().enumerate() {
+            if count == 0 {
+                continue;
+            }
+
+            if (seen + count) as f64 >= rank {
+                let lower = if idx == 0 { 0 } else { LATENCY_HISTOGRAM_BOUNDS[idx - 1] };
+                let upper = match LATENCY_HISTOGRAM_BOUNDS.get(idx) {
+                    Some(upper) => *upper,
+                    None => return Some(lower),
+                };
+                let ratio = (rank - seen as f64) / count as f64;
+                return Some(lower + ((upper - lower) as f64 * ratio) as u32);
+            }
+
+            seen += count;
+        }
+
+        None
+    }
+}
+
 /// Server statistic data
 #[derive(Debug, Clone, Copy)]
 pub struct ServerStatData {
@@ -35,6 +160,10 @@ pub struct ServerStatData {
     pub latency_mean: f64,
     /// Score's median absolute deviation
     pub latency_mad: u32,
+    /// Average packet loss of probe bursts in the check window
+    pub packet_loss: f64,
+    /// Average jitter (in millisec) of probe bursts in the check window
+    pub latency_jitter: u32,
 }
 
 /// Statistic of a remote server
@@ -50,6 +179,20 @@ pub struct ServerStat {
     user_weight: f32,
     /// Checking window size
     check_window: Duration,
+    /// Options of calculating score
+    score_opts: ScoreOptions,
+    /// Latency EWMA, if enabled by `ScoreOptions::ewma_alpha`
+    latency_ewma: Option<f64>,
+    /// Number of the latest probes that failed in a row
+    consecutive_failures: u32,
+    /// Number of the latest probes that succeeded in a row
+    consecutive_successes: u32,
+    /// Server is down, by `ScoreOptions::fail_threshold`
+    down: bool,
+    /// Latency histogram of probes in the check window
+    latency_histogram: LatencyHistogram,
+    /// Recently packet probe data
+    packet_queue: VecDeque<(PacketProbe, Instant)>,
     /// Statistic Data
     data: ServerStatData,
 }
@@ -64,7 +207,7 @@ fn max_latency_stdev(max_server_rtt: u32) -> f64 {
 }
 
 impl ServerStat {
-    pub fn new(user_weight: f32, max_server_rtt: u32, check_window: Duration) -> ServerStat {
+    pub fn new(user_weight: f32, max_server_rtt: u32, check_window: Duration, score_opts: ScoreOptions) -> ServerStat {
         assert!((0.0..=1.0).contains(&user_weight));
 
         let max_latency_stdev = max_latency_stdev(max_server_rtt);
@@ -74,68 +217,204 @@ impl ServerStat {
             max_latency_stdev,
             user_weight,
             check_window,
+            score_opts,
+            latency_ewma: None,
+            consecutive_failures: 0,
+            consecutive_successes: 0,
+            down: false,
+            latency_histogram: LatencyHistogram::default(),
+            packet_queue: VecDeque::new(),
             data: ServerStatData {
                 latency_median: max_server_rtt,
                 fail_rate: 1.0,
                 latency_stdev: max_latency_stdev,
                 latency_mean: max_server_rtt as f64,
                 latency_mad: max_server_rtt,
+                packet_loss: 0.0,
+                latency_jitter: 0,
             },
         }
     }
 
-    fn score(&self) -> u32 {
+    /// Score without applying user's weight, in `[0, 1]`
+    fn unweighted_score(&self) -> f64 {
+        if self.down {
+            return 1.0;
+        }
+
         // Normalize rtt
-        let nrtt = self.data.latency_median as f64 / self.max_server_rtt as f64;
+        // Failure penalty may make EWMA larger than MAX rtt
+        let nrtt = (self.scoring_latency() / self.max_server_rtt as f64).min(1.0);
 
         // Normalize stdev
         // let nstdev = self.data.latency_stdev / self.max_latency_stdev;
         // Mormalize mad
         let nmad = self.data.latency_mad as f64 / self.max_server_rtt as f64;
+        // Normalize jitter of packet probes
+        let njitter = (self.data.latency_jitter as f64 / self.max_server_rtt as f64).min(1.0);
+
+        // Packets may still be lost through a server that passed the check
+        let fail_rate = self.data.fail_rate + (1.0 - self.data.fail_rate) * self.data.packet_loss;
+
+        match self.score_opts.mode {
+            // The lower latency, the better
+            BalancerScoreMode::Latency => nrtt,
+            // The lower errored count, the better. Latency only matters between servers with the same failure rate
+            BalancerScoreMode::Loss => {
+                const SCORE_FAIL_WEIGHT: f64 = 100.0;
+                (fail_rate * SCORE_FAIL_WEIGHT + nrtt) / (SCORE_FAIL_WEIGHT + 1.0)
+            }
+            BalancerScoreMode::Combined => {
+                let score_rtt_weight = self.score_opts.rtt_weight;
+                let score_fail_weight = self.score_opts.loss_weight;
+                // const SCORE_STDEV_WEIGHT: f64 = 0.0;
+                const SCORE_MAD_WEIGHT: f64 = 1.0;
+
+                // Score = (norm_lat * 1.0 + prop_err * 3.0 + (stdev || mad) * 1.0) / 5.0
+                //
+                // 1. The lower latency, the better
+                // 2. The lower errored count, the better
+                // 3. The lower latency's stdev / mad (or jitter of packet probes), the better
+                (nrtt * score_rtt_weight + fail_rate * score_fail_weight + nmad.max(njitter) * SCORE_MAD_WEIGHT)
+                    / (score_rtt_weight + score_fail_weight + SCORE_MAD_WEIGHT)
+            }
+        }
+    }
 
-        const SCORE_RTT_WEIGHT: f64 = 1.0;
-        const SCORE_FAIL_WEIGHT: f64 = 3.0;
-        // const SCORE_STDEV_WEIGHT: f64 = 0.0;
-        const SCORE_MAD_WEIGHT: f64 = 1.0;
+    /// Latency used for scoring, EWMA or median in the check window
+    fn scoring_latency(&self) -> f64 {
+        match (self.score_opts.ewma_alpha, self.latency_ewma) {
+            (Some(..), Some(ewma)) => ewma,
+            (Some(..), None) => self.max_server_rtt as f64,
+            (None, ..) => self.data.latency_median as f64,
+        }
+    }
+
+    /// Latency (in millisec) used for scoring, EWMA or median in the check window
+    pub fn latency(&self) -> u32 {
+        self.scoring_latency() as u32
+    }
 
+    fn score(&self) -> u32 {
         // [EPSILON, 1]
         // Just for avoiding divide by 0
         let user_weight = self.user_weight.max(f32::EPSILON);
 
-        // Score = (norm_lat * 1.0 + prop_err * 3.0 + (stdev || mad) * 1.0) / 5.0 / user_weight
-        //
-        // 1. The lower latency, the better
-        // 2. The lower errored count, the better
-        // 3. The lower latency's stdev / mad, the better
-        // 4. The higher user's weight, the better
-        let score = (nrtt * SCORE_RTT_WEIGHT + self.data.fail_rate * SCORE_FAIL_WEIGHT + nmad * SCORE_MAD_WEIGHT)
-            / (SCORE_RTT_WEIGHT + SCORE_FAIL_WEIGHT + SCORE_MAD_WEIGHT)
-            / user_weight as f64;
+        // The higher user's weight, the better
+        let score = self.unweighted_score() / user_weight as f64;
 
         // Times 10000 converts to u32, for 0.0001 precision
         (score * 10000.0) as u32
     }
 
+    /// Current score without applying user's weight
+    ///
+    /// Same precision as the weighted score, could be used for comparing servers' latency only
+    pub fn latency_score(&self) -> u32 {
+        (self.unweighted_score() * MAX_LATENCY_SCORE as f64) as u32
+    }
+
     pub fn push_score(&mut self, score: Score) -> u32 {
+        self.push_score_at(score, Instant::now())
+    }
+
+    /// Append a `Score` that was probed at `inst`, which should be later than the last pushed one
+    pub fn push_score_at(&mut self, score: Score, inst: Instant) -> u32 {
         let now = Instant::now();
 
-        self.latency_queue.push_back((score, now));
+        self.latency_queue.push_back((score, inst));
+        if let Score::Latency(lat) = score {
+            self.latency_histogram.add(lat);
+        }
+
+        if let Some(alpha) = self.score_opts.ewma_alpha {
+            let latency = match score {
+                Score::Latency(lat) => lat as f64,
+                Score::Errored => self.max_server_rtt as f64 * self.score_opts.failure_penalty,
+            };
+            self.latency_ewma = Some(match self.latency_ewma {
+                Some(ewma) => alpha * latency + (1.0 - alpha) * ewma,
+                None => latency,
+            });
+        }
+
+        match score {
+            Score::Latency(..) => {
+                self.consecutive_failures = 0;
+                self.consecutive_successes = self.consecutive_successes.saturating_add(1);
+            }
+            Score::Errored => {
+                self.consecutive_successes = 0;
+                self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+            }
+        }
 
         // Removes stats that are not in the check window
-        while let Some((_, inst)) = self.latency_queue.front() {
+        while let Some((score, inst)) = self.latency_queue.front() {
             if now - *inst > self.check_window {
+                if let Score::Latency(lat) = *score {
+                    self.latency_histogram.remove(lat);
+                }
                 self.latency_queue.pop_front();
             } else {
                 break;
             }
         }
+        self.expire_packet_probes(now);
+
+        self.recalculate_score();
+        self.update_down();
+        self.score()
+    }
+
+    /// Append a `PacketProbe` into statistic, which takes effect in the next pushed `Score`
+    pub fn push_packet_probe(&mut self, probe: PacketProbe) {
+        let now = Instant::now();
+        self.packet_queue.push_back((probe, now));
+        self.expire_packet_probes(now);
+    }
+
+    fn expire_packet_probes(&mut self, now: Instant) {
+        while let Some((_, inst)) = self.packet_queue.front() {
+            if now - *inst > self.check_window {
+                self.packet_queue.pop_front();
+            } else {
+                break;
+            }
+        }
 
-        self.recalculate_score()
+        if self.packet_queue.is_empty() {
+            self.data.packet_loss = 0.0;
+            self.data.latency_jitter = 0;
+        } else {
+            let n = self.packet_queue.len();
+            self.data.packet_loss = self.packet_queue.iter().map(|(p, ..)| p.loss).sum::<f64>() / n as f64;
+            self.data.latency_jitter =
+                (self.packet_queue.iter().map(|(p, ..)| p.jitter as u64).sum::<u64>() / n as u64) as u32;
+        }
+    }
+
+    fn update_down(&mut self) {
+        if self.down {
+            if self.consecutive_successes >= self.score_opts.recover_threshold {
+                self.down = false;
+            }
+        } else {
+            self.down = match self.score_opts.fail_threshold {
+                Some(threshold) => self.consecutive_failures >= threshold,
+                None => self.data.fail_rate >= 1.0,
+            };
+        }
     }
 
-    fn recalculate_score(&mut self) -> u32 {
+    /// Server is down, could be brought back by consecutive successful probes
+    pub fn is_down(&self) -> bool {
+        self.down
+    }
+
+    fn recalculate_score(&mut self) {
         if self.latency_queue.is_empty() {
-            return self.score();
+            return;
         }
 
         let mut vlat = Vec::with_capacity(self.latency_queue.len());
@@ -188,7 +467,7 @@ impl ServerStat {
                 // MAD
                 let mut vlat_abs_diff: Vec<u32> = vlat
                     .iter()
-                    .map(|s| (*s as i32 - self.data.latency_median as i32).abs() as u32)
+                    .map(|s| (*s as i32 - self.data.latency_median as i32).unsigned_abs())
                     .collect();
                 vlat_abs_diff.sort_unstable();
 
@@ -203,8 +482,31 @@ impl ServerStat {
                 self.data.latency_mad = 0;
             }
         }
+    }
 
-        self.score()
+    /// Scores in the check window with their ages, from the oldest to the newest
+    pub fn score_history(&self) -> Vec<(Score, Duration)> {
+        let now = Instant::now();
+        self.latency_queue
+            .iter()
+            .map(|(score, inst)| (*score, now.saturating_duration_since(*inst)))
+            .collect()
+    }
+
+    /// The latest `n` scores in the check window, from the oldest to the newest
+    pub fn recent_scores(&self, n: usize) -> Vec<Score> {
+        let skip = self.latency_queue.len().saturating_sub(n);
+        self.latency_queue.iter().skip(skip).map(|(score, ..)| *score).collect()
+    }
+
+    /// Latency (in millisec) at percentile `p` of succeeded probes in the check window, MAX server's RTT if there is none
+    pub fn latency_percentile(&self, p: f64) -> u32 {
+        self.latency_histogram.percentile(p).unwrap_or(self.max_server_rtt)
+    }
+
+    /// Latency histogram of succeeded probes in the check window
+    pub fn latency_histogram(&self) -> &LatencyHistogram {
+        &self.latency_histogram
     }
 
     pub fn data(&self) -> &ServerStatData {
@@ -0,0 +1,159 @@
+//! Probe history of servers, persisted across restarts
+//!
+//! Balancer reloads recent probes from the state file on startup, so it could choose a good server immediately
+//! instead of re-learning servers' latency in the first minutes.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, trace};
+use serde::{Deserialize, Serialize};
+
+use super::{server_stat::Score, ServerIdent, ServerScore};
+
+/// Unix timestamp (in millisec) of a probe, and its latency (in millisec), `null` if it failed
+type ProbeRecord = (u64, Option<u32>);
+
+#[derive(Serialize, Deserialize, Default)]
+struct BalancerState {
+    servers: HashMap<String, ServerState>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ServerState {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tcp: Vec<ProbeRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    udp: Vec<ProbeRecord>,
+}
+
+/// State file of balancer
+#[derive(Debug)]
+pub struct ServerStateFile {
+    path: PathBuf,
+}
+
+impl ServerStateFile {
+    /// Create with the path of state file
+    pub fn new<P: Into<PathBuf>>(path: P) -> ServerStateFile {
+        ServerStateFile { path: path.into() }
+    }
+
+    /// Path of state file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Restore probe history of `servers` from the state file
+    pub async fn load(&self, servers: &[Arc<ServerIdent>]) -> io::Result<()> {
+        let content = match fs::read(&self.path) {
+            Ok(c) => c,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let state: BalancerState = serde_json::from_slice(&content)?;
+
+        let now = unix_millis(SystemTime::now());
+        let mut restored = 0;
+        for server in servers {
+            let server_state = match state.servers.get(&server.server_config().addr().to_string()) {
+                Some(s) => s,
+                None => continue,
+            };
+
+            restore_history(server.tcp_score(), &server_state.tcp, now).await;
+            restore_history(server.udp_score(), &server_state.udp, now).await;
+            restored += 1;
+        }
+
+        debug!(
+            "balancer restored probe history of {} servers from {}",
+            restored,
+            self.path.display()
+        );
+        Ok(())
+    }
+
+    /// Save probe history of `servers` into the state file
+    ///
+    /// Servers that are being probed are skipped
+    pub fn save(&self, servers: &[Arc<ServerIdent>]) -> io::Result<()> {
+        let now = SystemTime::now();
+
+        let mut state = BalancerState::default();
+        for server in servers {
+            let (tcp, udp) = match (
+                server.tcp_score().try_score_history(),
+                server.udp_score().try_score_history(),
+            ) {
+                (Some(tcp), Some(udp)) => (tcp, udp),
+                _ => continue,
+            };
+            if tcp.is_empty() && udp.is_empty() {
+                continue;
+            }
+
+            state.servers.insert(
+                server.server_config().addr().to_string(),
+                ServerState {
+                    tcp: probe_records(&tcp, now),
+                    udp: probe_records(&udp, now),
+                },
+            );
+        }
+
+        // Write to a temporary file and then replace, so the state file won't be truncated if we are killed
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, serde_json::to_vec(&state)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        trace!(
+            "balancer saved probe history of {} servers into {}",
+            state.servers.len(),
+            self.path.display()
+        );
+        Ok(())
+    }
+}
+
+fn unix_millis(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+fn probe_records(history: &[(Score, Duration)], now: SystemTime) -> Vec<ProbeRecord> {
+    history
+        .iter()
+        .map(|(score, age)| {
+            let latency = match *score {
+                Score::Latency(lat) => Some(lat),
+                Score::Errored => None,
+            };
+            (unix_millis(now - *age), latency)
+        })
+        .collect()
+}
+
+async fn restore_history(score: &ServerScore, records: &[ProbeRecord], now: u64) {
+    let history: Vec<(Score, Duration)> = records
+        .iter()
+        // Probes from the future, the clock was changed
+        .filter(|(ts, ..)| *ts <= now)
+        .map(|(ts, latency)| {
+            let score = match *latency {
+                Some(lat) => Score::Latency(lat),
+                None => Score::Errored,
+            };
+            (score, Duration::from_millis(now - ts))
+        })
+        .collect();
+
+    if !history.is_empty() {
+        score.restore_score_history(&history).await;
+    }
+}
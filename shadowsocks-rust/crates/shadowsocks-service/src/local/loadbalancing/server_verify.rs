@@ -0,0 +1,159 @@
+//! Verify servers' credentials by one authenticated round trip
+
+use std::{
+    fmt::{self, Display},
+    io::{self, ErrorKind},
+    time::{Duration, Instant},
+};
+
+use futures::future;
+use log::{debug, error, info, warn};
+use shadowsocks::{
+    config::ServerSource,
+    relay::{socks5::Address, tcprelay::proxy_stream::ProxyClientStream},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    time,
+};
+
+use crate::local::context::ServiceContext;
+
+use super::{ping_balancer::ServerConfigFormatter, PingBalancer, ServerIdent};
+
+/// Reason of failing to verify a server
+#[derive(Debug)]
+pub enum ServerVerifyError {
+    /// Couldn't connect to the server, credential is unknown
+    Network(io::Error),
+    /// Server closed the connection or responded with data that couldn't be decrypted,
+    /// which is mostly because of mismatched key or cipher
+    Rejected(io::Error),
+    /// Server accepted the connection but never responded.
+    /// Some servers hold unauthenticated connections silently, so it may also be caused by mismatched key or cipher
+    NoResponse,
+}
+
+impl Display for ServerVerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerVerifyError::Network(ref err) => write!(f, "network error: {err}"),
+            ServerVerifyError::Rejected(ref err) => write!(f, "credential rejected: {err}"),
+            ServerVerifyError::NoResponse => f.write_str("no response, credential may be rejected"),
+        }
+    }
+}
+
+static VERIFY_REQUEST: &[u8] =
+    b"GET /success.txt HTTP/1.1\r\nHost: detectportal.firefox.com\r\nConnection: close\r\nAccept: */*\r\n\r\n";
+
+/// Make one authenticated round trip through `server`, returns the elapsed time
+///
+/// Any data that could be decrypted proves that server accepted our key and cipher.
+pub async fn verify_server(
+    context: &ServiceContext,
+    server: &ServerIdent,
+    timeout: Duration,
+) -> Result<Duration, ServerVerifyError> {
+    let start = Instant::now();
+
+    let addr = Address::DomainNameAddress("detectportal.firefox.com".to_owned(), 80);
+
+    let mut stream = match time::timeout(
+        timeout,
+        ProxyClientStream::connect_with_opts(
+            context.context(),
+            server.server_config(),
+            &addr,
+            server.connect_opts_ref(),
+        ),
+    )
+    .await
+    {
+        Ok(Ok(s)) => s,
+        Ok(Err(err)) => return Err(ServerVerifyError::Network(err)),
+        Err(..) => return Err(ServerVerifyError::Network(ErrorKind::TimedOut.into())),
+    };
+
+    let round_trip = async {
+        if let Err(err) = stream.write_all(VERIFY_REQUEST).await {
+            return Err(ServerVerifyError::Network(err));
+        }
+
+        let mut reader = BufReader::new(stream);
+        let mut buf = Vec::new();
+        match reader.read_until(b'\n', &mut buf).await {
+            Ok(0) => Err(ServerVerifyError::Rejected(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed without response",
+            ))),
+            Ok(..) => Ok(()),
+            Err(err) => match err.kind() {
+                ErrorKind::UnexpectedEof
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::InvalidData
+                | ErrorKind::Other => Err(ServerVerifyError::Rejected(err)),
+                _ => Err(ServerVerifyError::Network(err)),
+            },
+        }
+    };
+
+    match time::timeout(timeout.saturating_sub(start.elapsed()), round_trip).await {
+        Ok(Ok(())) => Ok(start.elapsed()),
+        Ok(Err(err)) => Err(err),
+        Err(..) => Err(ServerVerifyError::NoResponse),
+    }
+}
+
+/// Verify all servers configured statically in `balancer`, and report the results in logs
+pub async fn verify_balancer_servers(balancer: PingBalancer, timeout: Duration) {
+    let context = balancer.context();
+
+    let mut vfut = Vec::new();
+    for server in balancer.servers() {
+        if matches!(server.server_config().source(), ServerSource::OnlineConfig) {
+            continue;
+        }
+
+        if !server.server_config().mode().enable_tcp() {
+            debug!(
+                "server {} skipped verification because it doesn't serve TCP",
+                ServerConfigFormatter::new(server.server_config())
+            );
+            continue;
+        }
+
+        let context = context.clone();
+        vfut.push(async move { (server, verify_server(&context, server, timeout).await) });
+    }
+
+    let total = vfut.len();
+    let mut failed = 0;
+    for (server, result) in future::join_all(vfut).await {
+        match result {
+            Ok(elapsed) => info!(
+                "server {} verified in {} ms",
+                ServerConfigFormatter::new(server.server_config()),
+                elapsed.as_millis()
+            ),
+            Err(err) => {
+                failed += 1;
+                match err {
+                    ServerVerifyError::Rejected(..) => error!(
+                        "server {} verification failed, {}. check its \"method\" and \"password\"",
+                        ServerConfigFormatter::new(server.server_config()),
+                        err
+                    ),
+                    _ => warn!(
+                        "server {} verification failed, {}",
+                        ServerConfigFormatter::new(server.server_config()),
+                        err
+                    ),
+                }
+            }
+        }
+    }
+
+    info!("verified {} servers, {} failed", total, failed);
+}
@@ -0,0 +1,174 @@
+//! Prometheus metrics of local servers
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::metrics::{MetricType, MetricsCollector, MetricsWriter};
+
+use super::{
+    context::ServiceContext,
+    loadbalancing::{PingBalancer, ServerScore},
+};
+
+type ScoreMetric = (&'static str, MetricType, &'static str, fn(&ServerScore) -> Option<f64>);
+
+const SCORE_METRICS: [ScoreMetric; 7] = [
+    (
+        "shadowsocks_local_server_sessions",
+        MetricType::Gauge,
+        "Active sessions relaying through the server, UDP associations for udp",
+        |score| Some(score.active_sessions() as f64),
+    ),
+    (
+        "shadowsocks_local_server_sessions_total",
+        MetricType::Counter,
+        "Sessions relayed through the server",
+        |score| Some(score.sessions() as f64),
+    ),
+    (
+        "shadowsocks_local_server_errors_total",
+        MetricType::Counter,
+        "Failed connections to the server",
+        |score| Some(score.failures() as f64),
+    ),
+    (
+        "shadowsocks_local_server_score",
+        MetricType::Gauge,
+        "Score of the server in the load balancer, lower is better",
+        |score| Some(score.score() as f64),
+    ),
+    (
+        "shadowsocks_local_server_latency_milliseconds",
+        MetricType::Gauge,
+        "Latency of the server used for scoring",
+        |score| match score.latency() {
+            u32::MAX => None,
+            latency => Some(latency as f64),
+        },
+    ),
+    (
+        "shadowsocks_local_server_fail_rate",
+        MetricType::Gauge,
+        "Ratio of failed checks in the check window",
+        |score| Some(score.fail_rate()),
+    ),
+    (
+        "shadowsocks_local_server_down",
+        MetricType::Gauge,
+        "Whether the server is marked down by consecutive failed checks",
+        |score| Some(if score.is_down() { 1.0 } else { 0.0 }),
+    ),
+];
+
+/// Metrics of servers in the load balancer, labeled by their addresses
+pub struct LocalMetrics {
+    context: Arc<ServiceContext>,
+    balancer: PingBalancer,
+}
+
+impl LocalMetrics {
+    /// Create metrics of servers in `balancer`
+    pub fn new(context: Arc<ServiceContext>, balancer: PingBalancer) -> LocalMetrics {
+        LocalMetrics { context, balancer }
+    }
+
+    fn collect_servers(&self, writer: &mut MetricsWriter) {
+        writer.family(
+            "shadowsocks_local_server_tx_bytes_total",
+            MetricType::Counter,
+            "Bytes sent to the server",
+        );
+        for server in self.balancer.servers() {
+            let addr = server.server_config().addr().to_string();
+            writer.sample(&[("server", &addr)], server.flow_stat_ref().tx());
+        }
+
+        writer.family(
+            "shadowsocks_local_server_rx_bytes_total",
+            MetricType::Counter,
+            "Bytes received from the server",
+        );
+        for server in self.balancer.servers() {
+            let addr = server.server_config().addr().to_string();
+            writer.sample(&[("server", &addr)], server.flow_stat_ref().rx());
+        }
+
+        for (name, kind, help, value) in SCORE_METRICS {
+            writer.family(name, kind, help);
+            for server in self.balancer.servers() {
+                let addr = server.server_config().addr().to_string();
+                for (protocol, score) in [("tcp", server.tcp_score()), ("udp", server.udp_score())] {
+                    if let Some(v) = value(score) {
+                        writer.sample(&[("server", &addr), ("protocol", protocol)], v);
+                    }
+                }
+            }
+        }
+    }
+
+    fn collect_first_packets(&self, writer: &mut MetricsWriter) {
+        let stat = self.context.first_packet_stat();
+
+        writer.family(
+            "shadowsocks_local_tcp_first_packets_total",
+            MetricType::Counter,
+            "First packets of proxied TCP tunnels sent with data of clients",
+        );
+        writer.sample(&[], stat.packets());
+
+        writer.family(
+            "shadowsocks_local_tcp_first_packet_coalesced_reads_total",
+            MetricType::Counter,
+            "Additional reads of clients coalesced into first packets, each saves a packet to the server",
+        );
+        writer.sample(&[], stat.coalesced_reads());
+
+        writer.family(
+            "shadowsocks_local_tcp_first_packet_bytes_total",
+            MetricType::Counter,
+            "Bytes of data of clients sent in first packets",
+        );
+        writer.sample(&[], stat.bytes());
+    }
+
+    #[cfg(feature = "local-dns")]
+    async fn collect_dns(&self, writer: &mut MetricsWriter) {
+        let upstreams = self.context.dns_upstream_stats().await;
+
+        writer.family(
+            "shadowsocks_local_dns_upstream_queries_total",
+            MetricType::Counter,
+            "Queries sent to the DNS upstream",
+        );
+        for stat in &upstreams {
+            writer.sample(
+                &[("upstream", stat.addr()), ("kind", stat.kind().as_str())],
+                stat.queries(),
+            );
+        }
+
+        writer.family(
+            "shadowsocks_local_dns_upstream_errors_total",
+            MetricType::Counter,
+            "Failed queries of the DNS upstream",
+        );
+        for stat in &upstreams {
+            writer.sample(
+                &[("upstream", stat.addr()), ("kind", stat.kind().as_str())],
+                stat.failures(),
+            );
+        }
+    }
+}
+
+#[async_trait]
+impl MetricsCollector for LocalMetrics {
+    async fn collect(&self, writer: &mut MetricsWriter) {
+        self.collect_servers(writer);
+        self.collect_first_packets(writer);
+
+        #[cfg(feature = "local-dns")]
+        self.collect_dns(writer).await;
+    }
+}
@@ -2,7 +2,7 @@
 
 use std::{
     future::Future,
-    io::{self, ErrorKind},
+    io,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
@@ -14,51 +14,79 @@ use log::trace;
 use shadowsocks::{
     config::Mode,
     net::{AcceptOpts, ConnectOpts},
+    ServerAddr,
 };
 use tokio::task::JoinHandle;
 
+#[cfg(feature = "metrics")]
+use crate::metrics::MetricsServer;
 #[cfg(feature = "local-flow-stat")]
 use crate::{config::LocalFlowStatAddress, net::FlowStat};
 use crate::{
-    config::{Config, ConfigType, ProtocolType},
+    config::{Config, ConfigType, LocalConfig, ProtocolType},
     dns::build_dns_resolver,
 };
 
 use self::{
     context::ServiceContext,
-    loadbalancing::{PingBalancer, PingBalancerBuilder},
+    control::{ControlServer, ControlServerBuilder},
+    ha::HaStatus,
+    loadbalancing::{
+        server_stat::DEFAULT_CHECK_TIMEOUT_SEC, server_verify::verify_balancer_servers, PingBalancer,
+        PingBalancerBuilder,
+    },
+    net::{ipc::IPC_PEER_ADDR, mirror::TrafficMirror, qos::TrafficShaper, uplink::UplinkBalancer},
 };
 
+#[cfg(feature = "local-http")]
+use self::control::{ControlHandler, ControlHttpServer, ControlHttpServerBuilder};
 #[cfg(feature = "local-dns")]
 use self::dns::{Dns, DnsBuilder};
 #[cfg(feature = "local-fake-dns")]
 use self::fake_dns::{FakeDns, FakeDnsBuilder};
+#[cfg(feature = "local-file-watch")]
+use self::file_watch::FileWatcher;
+#[cfg(feature = "local-history")]
+use self::history::SessionHistory;
 #[cfg(feature = "local-http")]
-use self::http::{Http, HttpBuilder};
+use self::http::{Http, HttpBuilder, PacFile};
+#[cfg(feature = "metrics")]
+use self::metrics::LocalMetrics;
 #[cfg(feature = "local-online-config")]
 use self::online_config::{OnlineConfigService, OnlineConfigServiceBuilder};
 #[cfg(feature = "local-redir")]
 use self::redir::{Redir, RedirBuilder};
 use self::socks::{Socks, SocksBuilder};
+#[cfg(any(target_os = "macos", windows))]
+use self::system_proxy::{SystemProxy, SystemProxyTargets};
 #[cfg(feature = "local-tun")]
 use self::tun::{Tun, TunBuilder};
 #[cfg(feature = "local-tunnel")]
-use self::tunnel::{Tunnel, TunnelBuilder};
+use self::tunnel::{ReverseTunnel, ReverseTunnelBuilder, Tunnel, TunnelBuilder};
 
 pub mod context;
+pub mod control;
 #[cfg(feature = "local-dns")]
 pub mod dns;
 #[cfg(feature = "local-fake-dns")]
 pub mod fake_dns;
+#[cfg(feature = "local-file-watch")]
+pub mod file_watch;
+pub mod ha;
+pub mod history;
 #[cfg(feature = "local-http")]
 pub mod http;
 pub mod loadbalancing;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod net;
 #[cfg(feature = "local-online-config")]
 pub mod online_config;
 #[cfg(feature = "local-redir")]
 pub mod redir;
 pub mod socks;
+#[cfg(any(target_os = "macos", windows))]
+pub mod system_proxy;
 #[cfg(feature = "local-tun")]
 pub mod tun;
 #[cfg(feature = "local-tunnel")]
@@ -86,7 +114,7 @@ impl Future for ServerHandle {
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         match ready!(Pin::new(&mut self.0).poll(cx)) {
             Ok(res) => res.into(),
-            Err(err) => Err(io::Error::new(ErrorKind::Other, err)).into(),
+            Err(err) => Err(io::Error::other(err)).into(),
         }
     }
 }
@@ -97,6 +125,8 @@ pub struct Server {
     socks_servers: Vec<Socks>,
     #[cfg(feature = "local-tunnel")]
     tunnel_servers: Vec<Tunnel>,
+    #[cfg(feature = "local-tunnel")]
+    reverse_tunnels: Vec<ReverseTunnel>,
     #[cfg(feature = "local-http")]
     http_servers: Vec<Http>,
     #[cfg(feature = "local-tun")]
@@ -113,11 +143,25 @@ pub struct Server {
     flow_stat: Arc<FlowStat>,
     #[cfg(feature = "local-online-config")]
     online_config: Option<OnlineConfigService>,
+    control_server: Option<ControlServer>,
+    #[cfg(feature = "local-http")]
+    control_http_server: Option<ControlHttpServer>,
+    #[cfg(feature = "metrics")]
+    metrics_server: Option<MetricsServer>,
+    #[cfg(feature = "local-file-watch")]
+    file_watcher: Option<FileWatcher>,
+    #[cfg(any(target_os = "macos", windows))]
+    system_proxy: Option<SystemProxy>,
 }
 
 impl Server {
     /// Create a shadowsocks local server
     pub async fn new(config: Config) -> io::Result<Server> {
+        Server::new_paired(config, None).await
+    }
+
+    /// Create a shadowsocks local server of an active / standby pair, whose state is served by control sockets
+    pub(crate) async fn new_paired(config: Config, ha_status: Option<Arc<HaStatus>>) -> io::Result<Server> {
         assert!(config.config_type == ConfigType::Local && !config.local.is_empty());
 
         trace!("{:?}", config);
@@ -152,12 +196,22 @@ impl Server {
 
             #[cfg(target_os = "android")]
             vpn_protect_path: config.outbound_vpn_protect_path,
+            #[cfg(target_os = "android")]
+            vpn_socket_protect: config.outbound_vpn_socket_protect,
 
             bind_interface: config.outbound_bind_interface,
             bind_local_addr: config.outbound_bind_addr,
 
             ..Default::default()
         };
+        // Outbound sockets bypass tun with the mark of its policy routing, if they are not marked by `outbound_fwmark`
+        #[cfg(all(feature = "local-tun", target_os = "linux"))]
+        if connect_opts.fwmark.is_none() {
+            connect_opts.fwmark = config
+                .local
+                .iter()
+                .find_map(|local| local.config.tun_auto_route.as_ref().and_then(|r| r.fwmark));
+        }
         connect_opts.tcp.send_buffer_size = config.outbound_send_buffer_size;
         connect_opts.tcp.recv_buffer_size = config.outbound_recv_buffer_size;
         connect_opts.tcp.nodelay = config.no_delay;
@@ -195,14 +249,59 @@ impl Server {
             context.set_ipv6_first(config.ipv6_first);
         }
 
+        if let Some(family) = config.outbound_addr_family {
+            context.set_addr_family(family);
+        }
+
         if let Some(acl) = config.acl {
+            // Remote rule sets are downloaded before serving, ACL is loaded again if any of them changed
+            #[cfg(feature = "local-online-config")]
+            let acl = {
+                let rule_sets = &acl.rule_sources().rule_sets;
+                if online_config::download_rule_sets(Arc::new(context.clone()), rule_sets).await {
+                    match acl.reload() {
+                        Ok(reloaded) => reloaded,
+                        Err(err) => {
+                            log::error!("failed to load downloaded rule sets, error: {}", err);
+                            acl
+                        }
+                    }
+                } else {
+                    acl
+                }
+            };
+
             context.set_acl(Arc::new(acl));
         }
 
         context.set_security_config(&config.security);
 
+        if let Some(sink) = config.mirror {
+            let mirror = TrafficMirror::new(sink).await?;
+            context.set_traffic_mirror(Arc::new(mirror));
+        }
+
+        if let Some(ref qos) = config.qos {
+            context.set_traffic_shaper(Arc::new(TrafficShaper::new(qos)));
+        }
+
+        context.set_special_target_policy(config.special_target_policy);
+
+        #[cfg(feature = "local-history")]
+        if let Some(history) = config.history {
+            context.set_session_history(Arc::new(SessionHistory::open(history)?));
+        }
+
+        if !config.uplinks.is_empty() {
+            context.set_uplinks(Arc::new(UplinkBalancer::new(&config.uplinks)));
+        }
+
         assert!(!config.local.is_empty(), "no valid local server configuration");
 
+        // Servers are routed outside of tun devices with automatic routes
+        #[cfg(all(feature = "local-tun", any(target_os = "linux", target_os = "macos", windows)))]
+        let mut server_addrs = Vec::new();
+
         // Create a service balancer for choosing between multiple servers
         let balancer = {
             let mut mode: Option<Mode> = None;
@@ -232,18 +331,140 @@ impl Server {
                 balancer_builder.check_best_interval(intv);
             }
 
+            if let Some(tolerance) = config.balancer.score_tolerance {
+                balancer_builder.score_tolerance(tolerance);
+            }
+
+            balancer_builder.strategy(config.balancer.strategy);
+            if let Some(strategy) = config.balancer.udp_strategy {
+                balancer_builder.udp_strategy(strategy);
+            }
+            balancer_builder.score_mode(config.balancer.score_mode);
+
+            if let Some(alpha) = config.balancer.ewma_alpha {
+                balancer_builder.ewma_alpha(alpha);
+            }
+
+            if let Some(penalty) = config.balancer.failure_penalty {
+                balancer_builder.failure_penalty(penalty);
+            }
+
+            if let Some(weight) = config.balancer.rtt_weight {
+                balancer_builder.rtt_weight(weight);
+            }
+
+            if let Some(weight) = config.balancer.loss_weight {
+                balancer_builder.loss_weight(weight);
+            }
+
+            if let Some(threshold) = config.balancer.check_fail_threshold {
+                balancer_builder.check_fail_threshold(threshold);
+            }
+
+            if let Some(threshold) = config.balancer.check_recover_threshold {
+                balancer_builder.check_recover_threshold(threshold);
+            }
+
+            if let Some(latency) = config.balancer.tier_max_latency {
+                balancer_builder.tier_max_latency(latency);
+            }
+
+            if let Some(margin) = config.balancer.switch_margin {
+                balancer_builder.switch_margin(margin);
+            }
+
+            if let Some(dwell) = config.balancer.switch_dwell {
+                balancer_builder.switch_dwell(dwell);
+            }
+
+            if let Some(checks) = config.balancer.warmup_checks {
+                balancer_builder.warmup_checks(checks);
+            }
+
+            if config.balancer.warmup_bypass {
+                balancer_builder.warmup_bypass(true);
+            }
+
+            if let Some(ref expr) = config.balancer.score_expr {
+                balancer_builder.score_expr(expr.parse().map_err(io::Error::other)?);
+            }
+
+            if let Some(url) = config.balancer.check_url.clone() {
+                balancer_builder.check_url(url);
+            }
+
+            if let Some(addr) = config.balancer.check_dns.clone() {
+                balancer_builder.check_dns(addr);
+            }
+
+            if let Some(addr) = config.balancer.check_stun.clone() {
+                balancer_builder.check_stun(addr);
+            }
+
+            if let Some(method) = config.balancer.tcp_check_method {
+                balancer_builder.tcp_check_method(method);
+            }
+
+            if let Some(method) = config.balancer.udp_check_method {
+                balancer_builder.udp_check_method(method);
+            }
+
+            if let Some(packets) = config.balancer.udp_probe_packets {
+                balancer_builder.udp_probe_packets(packets);
+            }
+
+            if let Some(routes) = config.balancer.routes.clone() {
+                balancer_builder.routes(routes);
+            }
+
+            if let Some(path) = config.balancer.state_file.clone() {
+                balancer_builder.state_file(path);
+            }
+
             for server in config.server {
+                #[cfg(all(feature = "local-tun", any(target_os = "linux", target_os = "macos", windows)))]
+                server_addrs.push(server.config.addr().clone());
                 balancer_builder.add_server(server);
             }
 
             balancer_builder.build().await?
         };
 
+        if config.balancer.verify_servers {
+            let timeout = config
+                .balancer
+                .max_server_rtt
+                .unwrap_or(Duration::from_secs(DEFAULT_CHECK_TIMEOUT_SEC));
+            tokio::spawn(verify_balancer_servers(balancer.clone(), timeout));
+        }
+
+        #[cfg(feature = "local-online-config")]
+        let online_config = match config.online_config {
+            None => None,
+            Some(online_config) => {
+                let mut builder = OnlineConfigServiceBuilder::new(
+                    Arc::new(context.clone()),
+                    online_config.config_url,
+                    balancer.clone(),
+                );
+                if let Some(update_interval) = online_config.update_interval {
+                    builder.set_update_interval(update_interval);
+                }
+                Some(builder.build().await?)
+            }
+        };
+        #[cfg(feature = "local-online-config")]
+        let online_config_refresh = online_config.as_ref().map(OnlineConfigService::refresh_notify);
+        #[cfg(not(feature = "local-online-config"))]
+        let online_config_refresh: Option<Arc<tokio::sync::Notify>> = None;
+
         let mut local_server = Server {
             balancer: balancer.clone(),
             socks_servers: Vec::new(),
             #[cfg(feature = "local-tunnel")]
             tunnel_servers: Vec::new(),
+            #[cfg(feature = "local-tunnel")]
+            reverse_tunnels: Vec::new(),
             #[cfg(feature = "local-http")]
             http_servers: Vec::new(),
             #[cfg(feature = "local-tun")]
@@ -259,92 +480,201 @@ impl Server {
             #[cfg(feature = "local-flow-stat")]
             flow_stat: context.flow_stat(),
             #[cfg(feature = "local-online-config")]
-            online_config: match config.online_config {
+            online_config,
+            control_server: match config.local_control_addr {
                 None => None,
-                Some(online_config) => {
-                    let mut builder = OnlineConfigServiceBuilder::new(
-                        Arc::new(context.clone()),
-                        online_config.config_url,
-                        balancer.clone(),
-                    );
-                    if let Some(update_interval) = online_config.update_interval {
-                        builder.set_update_interval(update_interval);
+                Some(control_addr) => {
+                    let mut builder =
+                        ControlServerBuilder::new(Arc::new(context.clone()), control_addr, balancer.clone());
+                    if let Some(ref refresh) = online_config_refresh {
+                        builder.set_online_config_refresh(refresh.clone());
+                    }
+                    if let Some(ref ha_status) = ha_status {
+                        builder.set_ha_status(ha_status.clone());
                     }
                     Some(builder.build().await?)
                 }
             },
+            #[cfg(feature = "local-http")]
+            control_http_server: match config.local_control_http {
+                None => None,
+                Some(control_http) => {
+                    let mut handler = ControlHandler::new(Arc::new(context.clone()), balancer.clone());
+                    if let Some(ref refresh) = online_config_refresh {
+                        handler.set_online_config_refresh(refresh.clone());
+                    }
+                    if let Some(ref ha_status) = ha_status {
+                        handler.set_ha_status(ha_status.clone());
+                    }
+                    Some(ControlHttpServerBuilder::new(control_http, handler).build().await?)
+                }
+            },
+            #[cfg(feature = "metrics")]
+            metrics_server: match config.metrics_addr {
+                None => None,
+                Some(metrics_addr) => {
+                    let metrics = LocalMetrics::new(Arc::new(context.clone()), balancer.clone());
+                    Some(MetricsServer::bind(metrics_addr, Arc::new(metrics)).await?)
+                }
+            },
+            #[cfg(feature = "local-file-watch")]
+            file_watcher: config.file_watch.as_ref().map(FileWatcher::new),
+            #[cfg(any(target_os = "macos", windows))]
+            system_proxy: None,
         };
 
+        #[cfg(feature = "local-file-watch")]
+        if let Some(ref mut file_watcher) = local_server.file_watcher {
+            file_watcher.watch_acl("acl", &context);
+
+            #[cfg(feature = "local-http-rustls")]
+            if let Some(ref control_http_server) = local_server.control_http_server {
+                control_http_server.watch_tls_files(file_watcher);
+            }
+        }
+
+        #[cfg(any(target_os = "macos", windows))]
+        let mut system_proxy_targets = SystemProxyTargets::default();
+
         for local_instance in config.local {
             let local_config = local_instance.config;
 
+            #[cfg(any(target_os = "macos", windows))]
+            if local_config.system_proxy {
+                if let Some(ref addr) = local_config.addr {
+                    match local_config.protocol {
+                        ProtocolType::Socks => system_proxy_targets.set_socks(addr),
+                        #[cfg(feature = "local-http")]
+                        ProtocolType::Http => system_proxy_targets.set_http(addr),
+                        #[cfg(feature = "local-http")]
+                        ProtocolType::Mixed => {
+                            system_proxy_targets.set_socks(addr);
+                            system_proxy_targets.set_http(addr);
+                        }
+                        #[allow(unreachable_patterns)]
+                        _ => {}
+                    }
+                }
+            }
+
             // Clone from global ServiceContext instance
             // It will shares Shadowsocks' global context, and FlowStat, DNS reverse cache
             let mut context = context.clone();
 
             // Private ACL
             if let Some(acl) = local_instance.acl {
-                context.set_acl(Arc::new(acl))
+                context.set_acl(Arc::new(acl));
+
+                #[cfg(feature = "local-file-watch")]
+                if let Some(ref mut file_watcher) = local_server.file_watcher {
+                    let subsystem = match local_config.addr {
+                        Some(ref addr) => format!("acl of local {addr}"),
+                        None => "acl of local".to_owned(),
+                    };
+                    file_watcher.watch_acl(subsystem, &context);
+                }
             }
 
-            let context = Arc::new(context);
-            let balancer = balancer.clone();
+            // Private TCP_NODELAY of accepted and direct connections
+            if let Some(nodelay) = local_instance.no_delay {
+                let mut accept_opts = context.accept_opts();
+                accept_opts.tcp.nodelay = nodelay;
+                context.set_accept_opts(accept_opts);
 
-            match local_config.protocol {
-                ProtocolType::Socks => {
-                    let client_addr = match local_config.addr {
-                        Some(a) => a,
-                        None => return Err(io::Error::new(ErrorKind::Other, "socks requires local address")),
-                    };
+                let mut connect_opts = context.connect_opts_ref().clone();
+                connect_opts.tcp.nodelay = nodelay;
+                context.set_connect_opts(connect_opts);
+            }
 
-                    let mut server_builder = SocksBuilder::with_context(context.clone(), client_addr, balancer);
-                    server_builder.set_mode(local_config.mode);
-                    server_builder.set_socks5_auth(local_config.socks5_auth);
+            // Private timeouts of TCP tunnels and UDP associations
+            if let Some(d) = local_instance.tcp_idle_timeout {
+                context.set_tcp_idle_timeout(d);
+            }
+            if let Some(d) = local_instance.tcp_half_close_timeout {
+                context.set_tcp_half_close_timeout(d);
+            }
 
-                    if let Some(c) = config.udp_max_associations {
-                        server_builder.set_udp_capacity(c);
-                    }
-                    if let Some(d) = config.udp_timeout {
-                        server_builder.set_udp_expiry_duration(d);
-                    }
-                    if let Some(b) = local_config.udp_addr {
-                        server_builder.set_udp_bind_addr(b.clone());
-                    }
+            // Private MSS clamping of outbound connections
+            if let Some(mss) = local_instance.tcp_mss {
+                context.set_tcp_mss(mss);
+            }
 
-                    #[cfg(target_os = "macos")]
-                    if let Some(n) = local_config.launchd_tcp_socket_name {
-                        server_builder.set_launchd_tcp_socket_name(n);
-                    }
-                    #[cfg(target_os = "macos")]
-                    if let Some(n) = local_config.launchd_udp_socket_name {
-                        server_builder.set_launchd_udp_socket_name(n);
-                    }
+            // Filtering of responses of UDP associations of redir and tun
+            context.set_udp_nat(local_config.udp_nat);
+            let udp_timeout = local_instance.udp_timeout.or(config.udp_timeout);
 
+            let context = Arc::new(context);
+            let balancer = balancer.clone();
+
+            match local_config.protocol {
+                ProtocolType::Socks => {
+                    let server_builder = socks_builder(
+                        context.clone(),
+                        balancer,
+                        local_config,
+                        config.udp_max_associations,
+                        udp_timeout,
+                    )?;
+                    let server = server_builder.build().await?;
+                    local_server.socks_servers.push(server);
+                }
+                #[cfg(feature = "local-http")]
+                ProtocolType::Mixed => {
+                    let http_auth = local_config.http_auth.clone();
+                    let mut server_builder = socks_builder(
+                        context.clone(),
+                        balancer,
+                        local_config,
+                        config.udp_max_associations,
+                        udp_timeout,
+                    )?;
+                    server_builder.set_http_auth(http_auth);
                     let server = server_builder.build().await?;
                     local_server.socks_servers.push(server);
                 }
                 #[cfg(feature = "local-tunnel")]
                 ProtocolType::Tunnel => {
-                    let client_addr = match local_config.addr {
-                        Some(a) => a,
-                        None => return Err(io::Error::new(ErrorKind::Other, "tunnel requires local address")),
+                    // Mappings without `mode` follow the tunnel
+                    let tunnel_mode = local_config.mode;
+                    let mut forwards = local_config.tunnel_forwards.into_iter().map(|mut f| {
+                        f.mode = Some(f.mode.unwrap_or(tunnel_mode));
+                        f
+                    });
+
+                    // The first mapping is served as `addr -> forward_addr` if it is not configured
+                    let (client_addr, forward_addr, mode) = match (local_config.addr, local_config.forward_addr) {
+                        (Some(a), Some(f)) => (a, f, tunnel_mode),
+                        (None, None) => match forwards.next() {
+                            Some(f) => (f.addr, f.forward_addr, f.mode.unwrap_or(tunnel_mode)),
+                            None => return Err(io::Error::other("tunnel requires forward address")),
+                        },
+                        (None, Some(..)) => return Err(io::Error::other("tunnel requires local address")),
+                        (Some(..), None) => return Err(io::Error::other("tunnel requires forward address")),
                     };
 
-                    let forward_addr = local_config.forward_addr.expect("tunnel requires forward address");
-
                     let mut server_builder =
-                        TunnelBuilder::with_context(context.clone(), forward_addr.clone(), client_addr, balancer);
+                        TunnelBuilder::with_context(context.clone(), forward_addr, client_addr, balancer);
 
                     if let Some(c) = config.udp_max_associations {
                         server_builder.set_udp_capacity(c);
                     }
-                    if let Some(d) = config.udp_timeout {
+                    if let Some(d) = udp_timeout {
                         server_builder.set_udp_expiry_duration(d);
                     }
-                    server_builder.set_mode(local_config.mode);
+                    server_builder.set_mode(mode);
+                    for forward in forwards {
+                        server_builder.add_forward(forward);
+                    }
                     if let Some(udp_addr) = local_config.udp_addr {
                         server_builder.set_udp_bind_addr(udp_addr);
                     }
+                    if let Some(timeout) = local_config.forward_dns_timeout {
+                        server_builder.set_udp_dns_query_timeout(timeout);
+                    }
+                    server_builder.set_inbound_limit(local_config.inbound_limit);
+                    if let Some(timeout) = local_config.sniff_timeout {
+                        server_builder.set_sniff_timeout(timeout);
+                    }
 
                     #[cfg(target_os = "macos")]
                     if let Some(n) = local_config.launchd_tcp_socket_name {
@@ -358,15 +688,55 @@ impl Server {
                     let server = server_builder.build().await?;
                     local_server.tunnel_servers.push(server);
                 }
+                #[cfg(feature = "local-tunnel")]
+                ProtocolType::ReverseTunnel => {
+                    let forward_addr = match local_config.forward_addr {
+                        Some(a) => a,
+                        None => return Err(io::Error::other("reverse-tunnel requires forward address")),
+                    };
+                    if balancer.is_empty() {
+                        return Err(io::Error::other("reverse-tunnel requires servers"));
+                    }
+
+                    let mut server_builder =
+                        ReverseTunnelBuilder::with_context(context.clone(), forward_addr, balancer);
+                    if let Some(port) = local_config.reverse_port {
+                        server_builder.set_reverse_port(port);
+                    }
+
+                    let server = server_builder.build();
+                    local_server.reverse_tunnels.push(server);
+                }
                 #[cfg(feature = "local-http")]
                 ProtocolType::Http => {
                     let client_addr = match local_config.addr {
                         Some(a) => a,
-                        None => return Err(io::Error::new(ErrorKind::Other, "http requires local address")),
+                        // IPC listeners have no address, it is never bound
+                        None if local_config.ipc_addr.is_some() => ServerAddr::from(IPC_PEER_ADDR),
+                        None => return Err(io::Error::other("http requires local address")),
                     };
 
-                    #[allow(unused_mut)]
-                    let mut builder = HttpBuilder::with_context(context.clone(), client_addr, balancer);
+                    let mut builder = HttpBuilder::with_context(context.clone(), client_addr.clone(), balancer);
+                    if let Some(addr) = local_config.ipc_addr {
+                        builder.set_ipc_addr(addr);
+                    }
+                    builder.set_inbound_limit(local_config.inbound_limit);
+                    builder.set_auth(local_config.http_auth);
+                    builder.set_mode(local_config.mode);
+                    if let Some(d) = udp_timeout {
+                        builder.set_udp_expiry_duration(d);
+                    }
+                    if let Some(path) = local_config.http_pac_path {
+                        let mut pac = PacFile::new(path, client_addr);
+                        pac.set_acl(local_config.http_pac_acl);
+                        #[cfg(feature = "local-tls")]
+                        pac.set_tls(local_config.tls.is_some());
+                        builder.set_pac(pac);
+                    }
+                    #[cfg(feature = "local-tls")]
+                    if let Some(tls) = local_config.tls {
+                        builder.set_tls(tls);
+                    }
 
                     #[cfg(target_os = "macos")]
                     if let Some(n) = local_config.launchd_tcp_socket_name {
@@ -380,14 +750,14 @@ impl Server {
                 ProtocolType::Redir => {
                     let client_addr = match local_config.addr {
                         Some(a) => a,
-                        None => return Err(io::Error::new(ErrorKind::Other, "redir requires local address")),
+                        None => return Err(io::Error::other("redir requires local address")),
                     };
 
                     let mut server_builder = RedirBuilder::with_context(context.clone(), client_addr, balancer);
                     if let Some(c) = config.udp_max_associations {
                         server_builder.set_udp_capacity(c);
                     }
-                    if let Some(d) = config.udp_timeout {
+                    if let Some(d) = udp_timeout {
                         server_builder.set_udp_expiry_duration(d);
                     }
                     server_builder.set_mode(local_config.mode);
@@ -396,6 +766,13 @@ impl Server {
                     if let Some(udp_addr) = local_config.udp_addr {
                         server_builder.set_udp_bind_addr(udp_addr);
                     }
+                    if let Some(timeout) = local_config.sniff_timeout {
+                        server_builder.set_sniff_timeout(timeout);
+                    }
+                    #[cfg(target_os = "linux")]
+                    if let Some(firewall) = local_config.redir_firewall {
+                        server_builder.set_firewall(firewall);
+                    }
 
                     let server = server_builder.build().await?;
                     local_server.redir_servers.push(server);
@@ -404,7 +781,7 @@ impl Server {
                 ProtocolType::Dns => {
                     let client_addr = match local_config.addr {
                         Some(a) => a,
-                        None => return Err(io::Error::new(ErrorKind::Other, "dns requires local address")),
+                        None => return Err(io::Error::other("dns requires local address")),
                     };
 
                     let mut server_builder = {
@@ -422,6 +799,80 @@ impl Server {
                         )
                     };
                     server_builder.set_mode(local_config.mode);
+                    if let Some(n) = local_config.dns_quarantine_failures {
+                        server_builder.set_quarantine_failures(n);
+                    }
+                    if let Some(d) = local_config.dns_quarantine_duration {
+                        server_builder.set_quarantine_duration(d);
+                    }
+                    if let Some(n) = local_config.dns_cache_size {
+                        server_builder.set_cache_size(n);
+                    }
+                    if let Some(ttl) = local_config.dns_cache_min_ttl {
+                        server_builder.set_cache_min_ttl(ttl);
+                    }
+                    if let Some(ttl) = local_config.dns_cache_max_ttl {
+                        server_builder.set_cache_max_ttl(ttl);
+                    }
+                    if !local_config.dns_rules.is_empty() {
+                        server_builder.set_rules(local_config.dns_rules);
+                    }
+                    if let Some(subnet) = local_config.dns_client_subnet {
+                        server_builder.set_client_subnet(subnet);
+                    }
+                    if let Some(strategy) = local_config.dns_acl_strategy {
+                        server_builder.set_acl_strategy(strategy);
+                    }
+                    if !local_config.local_dns_extra_addrs.is_empty() {
+                        server_builder.set_local_extra_addrs(local_config.local_dns_extra_addrs);
+                    }
+                    if let Some(strategy) = local_config.dns_upstream_strategy {
+                        server_builder.set_upstream_strategy(strategy);
+                    }
+                    if !local_config.dns_block_lists.is_empty() {
+                        server_builder.set_block_lists(local_config.dns_block_lists);
+                    }
+                    if let Some(r) = local_config.dns_block_response {
+                        server_builder.set_block_response(r);
+                    }
+                    if !local_config.dns_hosts.is_empty() {
+                        server_builder.set_hosts(local_config.dns_hosts);
+                    }
+                    if let Some(query_log) = local_config.dns_query_log {
+                        server_builder.set_query_log(query_log);
+                    }
+                    #[cfg(feature = "local-dns-over-https")]
+                    if let Some(https) = local_config.remote_dns_https {
+                        server_builder.set_remote_https(https);
+                    }
+                    #[cfg(feature = "local-dns-over-tls")]
+                    if let Some(tls) = local_config.local_dns_tls {
+                        server_builder.set_local_tls(tls);
+                    }
+                    #[cfg(feature = "local-dns-over-tls")]
+                    if let Some(tls) = local_config.remote_dns_tls {
+                        server_builder.set_remote_tls(tls);
+                    }
+                    #[cfg(feature = "local-dns-over-quic")]
+                    if let Some(quic) = local_config.local_dns_quic {
+                        server_builder.set_local_quic(quic);
+                    }
+                    #[cfg(feature = "local-dns-server-tls")]
+                    if let Some(tls) = local_config.tls {
+                        server_builder.set_tls(tls);
+                    }
+                    #[cfg(feature = "local-dns-server-tls")]
+                    if let Some(addr) = local_config.dns_tls_addr {
+                        server_builder.set_tls_bind_addr(addr);
+                    }
+                    #[cfg(feature = "local-dns-server-https")]
+                    if let Some(addr) = local_config.dns_https_addr {
+                        server_builder.set_https_bind_addr(addr);
+                    }
+                    #[cfg(feature = "local-dns-server-https")]
+                    if let Some(path) = local_config.dns_https_path {
+                        server_builder.set_https_path(path);
+                    }
 
                     #[cfg(target_os = "macos")]
                     if let Some(n) = local_config.launchd_tcp_socket_name {
@@ -447,13 +898,62 @@ impl Server {
                     if let Some(name) = local_config.tun_interface_name {
                         builder.name(&name);
                     }
+                    if let Some(mtu) = local_config.tun_interface_mtu {
+                        builder.mtu(mtu);
+                    }
                     if let Some(c) = config.udp_max_associations {
                         builder.udp_capacity(c);
                     }
-                    if let Some(d) = config.udp_timeout {
+                    if let Some(d) = udp_timeout {
                         builder.udp_expiry_duration(d);
                     }
                     builder.mode(local_config.mode);
+                    if let Some(dscp) = local_config.tun_dscp {
+                        builder.dscp(dscp);
+                    }
+                    #[cfg(windows)]
+                    if let Some(ipv6) = local_config.tun_ipv6 {
+                        builder.ipv6(ipv6);
+                    }
+                    #[cfg(feature = "local-fake-dns")]
+                    if local_config.tun_fake_dns {
+                        use std::path::Path;
+
+                        use self::fake_dns::manager::{
+                            default_ipv4_network, default_ipv6_network, FakeDnsManager, DEFAULT_DATABASE_PATH,
+                            DEFAULT_EXPIRE_DURATION,
+                        };
+
+                        let manager = FakeDnsManager::open(
+                            local_config
+                                .fake_dns_database_path
+                                .as_deref()
+                                .unwrap_or(Path::new(DEFAULT_DATABASE_PATH)),
+                            local_config.fake_dns_ipv4_network.unwrap_or_else(default_ipv4_network),
+                            local_config.fake_dns_ipv6_network.unwrap_or_else(default_ipv6_network),
+                            local_config
+                                .fake_dns_record_expire_duration
+                                .unwrap_or(DEFAULT_EXPIRE_DURATION),
+                        )?;
+                        let manager = Arc::new(manager);
+                        context.add_fake_dns_manager(manager.clone()).await;
+                        builder.fake_dns(manager);
+                    }
+                    if !local_config.tun_dns_hijack.is_empty() {
+                        builder.dns_hijack(local_config.tun_dns_hijack);
+                    }
+                    builder.icmp_echo(local_config.tun_icmp_echo);
+                    #[cfg(all(feature = "local-tun", any(target_os = "linux", target_os = "macos", windows)))]
+                    if let Some(auto_route) = local_config.tun_auto_route {
+                        builder.auto_route(auto_route, server_addrs.clone());
+                    }
+                    #[cfg(target_os = "linux")]
+                    if local_config.tun_offload {
+                        builder.offload(true);
+                    }
+                    if let Some(timeout) = local_config.sniff_timeout {
+                        builder.sniff_timeout(timeout);
+                    }
                     #[cfg(unix)]
                     if let Some(fd) = local_config.tun_device_fd {
                         builder.file_descriptor(fd);
@@ -515,7 +1015,7 @@ impl Server {
                 ProtocolType::FakeDns => {
                     let client_addr = match local_config.addr {
                         Some(a) => a,
-                        None => return Err(io::Error::new(ErrorKind::Other, "dns requires local address")),
+                        None => return Err(io::Error::other("dns requires local address")),
                     };
 
                     let mut builder = FakeDnsBuilder::new(client_addr);
@@ -540,11 +1040,24 @@ impl Server {
             }
         }
 
+        // Listeners are all bound, it is safe to direct the system to them
+        #[cfg(any(target_os = "macos", windows))]
+        if !system_proxy_targets.is_empty() {
+            let system_proxy = tokio::task::spawn_blocking(move || SystemProxy::set(system_proxy_targets))
+                .await
+                .map_err(io::Error::other)??;
+            local_server.system_proxy = Some(system_proxy);
+        }
+
         Ok(local_server)
     }
 
     /// Run local server
     pub async fn run(self) -> io::Result<()> {
+        // Restored when the server stops
+        #[cfg(any(target_os = "macos", windows))]
+        let _system_proxy = self.system_proxy;
+
         let mut vfut = Vec::new();
 
         for svr in self.socks_servers {
@@ -556,6 +1069,11 @@ impl Server {
             vfut.push(ServerHandle(tokio::spawn(svr.run())));
         }
 
+        #[cfg(feature = "local-tunnel")]
+        for svr in self.reverse_tunnels {
+            vfut.push(ServerHandle(tokio::spawn(svr.run())));
+        }
+
         #[cfg(feature = "local-http")]
         for svr in self.http_servers {
             vfut.push(ServerHandle(tokio::spawn(svr.run())));
@@ -594,6 +1112,27 @@ impl Server {
             vfut.push(ServerHandle(tokio::spawn(online_config.run())));
         }
 
+        if let Some(control_server) = self.control_server {
+            vfut.push(ServerHandle(tokio::spawn(control_server.run())));
+        }
+
+        #[cfg(feature = "local-http")]
+        if let Some(control_http_server) = self.control_http_server {
+            vfut.push(ServerHandle(tokio::spawn(control_http_server.run())));
+        }
+
+        #[cfg(feature = "metrics")]
+        if let Some(metrics_server) = self.metrics_server {
+            vfut.push(ServerHandle(tokio::spawn(metrics_server.run())));
+        }
+
+        #[cfg(feature = "local-file-watch")]
+        if let Some(file_watcher) = self.file_watcher {
+            if !file_watcher.is_empty() {
+                vfut.push(ServerHandle(tokio::spawn(file_watcher.run())));
+            }
+        }
+
         let (res, ..) = future::select_all(vfut).await;
         res
     }
@@ -603,6 +1142,17 @@ impl Server {
         &self.balancer
     }
 
+    /// Get the local control socket server
+    pub fn control_server(&self) -> Option<&ControlServer> {
+        self.control_server.as_ref()
+    }
+
+    /// Get the watcher of external files, for subscribing results of applying their changes
+    #[cfg(feature = "local-file-watch")]
+    pub fn file_watcher(&self) -> Option<&FileWatcher> {
+        self.file_watcher.as_ref()
+    }
+
     /// Get SOCKS server instances
     pub fn socks_servers(&self) -> &[Socks] {
         &self.socks_servers
@@ -614,6 +1164,12 @@ impl Server {
         &self.tunnel_servers
     }
 
+    /// Get Reverse Tunnel instances
+    #[cfg(feature = "local-tunnel")]
+    pub fn reverse_tunnels(&self) -> &[ReverseTunnel] {
+        &self.reverse_tunnels
+    }
+
     /// Get HTTP server instances
     #[cfg(feature = "local-http")]
     pub fn http_servers(&self) -> &[Http] {
@@ -645,6 +1201,58 @@ impl Server {
     }
 }
 
+/// SOCKS server of `local_config`, which also serves HTTP clients on the same port
+fn socks_builder(
+    context: Arc<ServiceContext>,
+    balancer: PingBalancer,
+    local_config: LocalConfig,
+    udp_max_associations: Option<usize>,
+    udp_timeout: Option<Duration>,
+) -> io::Result<SocksBuilder> {
+    let client_addr = match local_config.addr {
+        Some(a) => a,
+        // IPC listeners have no address, it is never bound
+        None if local_config.ipc_addr.is_some() => ServerAddr::from(IPC_PEER_ADDR),
+        None => return Err(io::Error::other("socks requires local address")),
+    };
+
+    let mut server_builder = SocksBuilder::with_context(context, client_addr, balancer);
+    if let Some(addr) = local_config.ipc_addr {
+        server_builder.set_ipc_addr(addr);
+    }
+    server_builder.set_inbound_limit(local_config.inbound_limit);
+    server_builder.set_mode(local_config.mode);
+    server_builder.set_socks5_auth(local_config.socks5_auth);
+    server_builder.set_udp_prebind(local_config.socks5_udp_prebind);
+    server_builder.set_udp_external_addrs(local_config.socks5_udp_external_addrs);
+
+    if let Some(c) = udp_max_associations {
+        server_builder.set_udp_capacity(c);
+    }
+    if let Some(d) = udp_timeout {
+        server_builder.set_udp_expiry_duration(d);
+    }
+    if let Some(b) = local_config.udp_addr {
+        server_builder.set_udp_bind_addr(b);
+    }
+
+    #[cfg(feature = "local-tls")]
+    if let Some(tls) = local_config.tls {
+        server_builder.set_tls(tls);
+    }
+
+    #[cfg(target_os = "macos")]
+    if let Some(n) = local_config.launchd_tcp_socket_name {
+        server_builder.set_launchd_tcp_socket_name(n);
+    }
+    #[cfg(target_os = "macos")]
+    if let Some(n) = local_config.launchd_udp_socket_name {
+        server_builder.set_launchd_udp_socket_name(n);
+    }
+
+    Ok(server_builder)
+}
+
 #[cfg(feature = "local-flow-stat")]
 async fn flow_report_task(stat_addr: LocalFlowStatAddress, flow_stat: Arc<FlowStat>) -> io::Result<()> {
     use std::slice;
@@ -722,6 +1330,11 @@ async fn flow_report_task(stat_addr: LocalFlowStatAddress, flow_stat: Arc<FlowSt
 }
 
 /// Create then run a Local Server
-pub async fn run(config: Config) -> io::Result<()> {
+///
+/// Listeners are only opened when this instance is active, if it is paired by `ha`
+pub async fn run(mut config: Config) -> io::Result<()> {
+    if let Some(ha) = config.ha.take() {
+        return ha::run(config, ha).await;
+    }
     Server::new(config).await?.run().await
 }
@@ -0,0 +1,111 @@
+//! Local listeners on Unix domain sockets or Windows named pipes
+//!
+//! Clients are granted by permissions of the socket file (or the pipe), instead of a loopback port.
+
+use std::{
+    fmt, io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+};
+#[cfg(unix)]
+use std::{fs, os::unix::fs::FileTypeExt, path::PathBuf};
+
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+/// Peer address of IPC clients, which don't have socket addresses
+pub const IPC_PEER_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+/// Stream of an IPC client
+#[cfg(unix)]
+pub type IpcStream = UnixStream;
+/// Stream of an IPC client
+#[cfg(windows)]
+pub type IpcStream = NamedPipeServer;
+
+/// Listen address of IPC
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IpcAddr {
+    /// Path of Unix domain socket
+    #[cfg(unix)]
+    UnixSocket(PathBuf),
+    /// Name of Windows named pipe, like `\\.\pipe\shadowsocks`
+    #[cfg(windows)]
+    NamedPipe(String),
+}
+
+impl fmt::Display for IpcAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            #[cfg(unix)]
+            IpcAddr::UnixSocket(ref path) => write!(f, "unix:{}", path.display()),
+            #[cfg(windows)]
+            IpcAddr::NamedPipe(ref name) => f.write_str(name),
+        }
+    }
+}
+
+/// Listener of IPC clients
+pub struct IpcListener {
+    addr: IpcAddr,
+    #[cfg(unix)]
+    listener: UnixListener,
+    /// Pipe instance waiting for the next client
+    #[cfg(windows)]
+    next: NamedPipeServer,
+}
+
+impl IpcListener {
+    /// Listen on `addr`, a stale Unix domain socket on the path is removed
+    #[cfg(unix)]
+    pub fn bind(addr: IpcAddr) -> io::Result<IpcListener> {
+        let IpcAddr::UnixSocket(ref path) = addr;
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            if meta.file_type().is_socket() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        let listener = UnixListener::bind(path)?;
+        Ok(IpcListener { addr, listener })
+    }
+
+    /// Listen on `addr`, fails if the pipe is already served by another process
+    #[cfg(windows)]
+    pub fn bind(addr: IpcAddr) -> io::Result<IpcListener> {
+        let IpcAddr::NamedPipe(ref name) = addr;
+        let next = ServerOptions::new().first_pipe_instance(true).create(name)?;
+        Ok(IpcListener { addr, next })
+    }
+
+    /// Listen address
+    pub fn addr(&self) -> &IpcAddr {
+        &self.addr
+    }
+
+    /// Accept a new client
+    #[cfg(unix)]
+    pub async fn accept(&mut self) -> io::Result<IpcStream> {
+        let (stream, _) = self.listener.accept().await?;
+        Ok(stream)
+    }
+
+    /// Accept a new client
+    #[cfg(windows)]
+    pub async fn accept(&mut self) -> io::Result<IpcStream> {
+        let IpcAddr::NamedPipe(ref name) = self.addr;
+        self.next.connect().await?;
+        // Another instance waits for the next client before the connected one is handed out
+        let next = ServerOptions::new().create(name)?;
+        Ok(std::mem::replace(&mut self.next, next))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for IpcListener {
+    fn drop(&mut self) {
+        let IpcAddr::UnixSocket(ref path) = self.addr;
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,218 @@
+//! Limits of inbound connections of a local server
+//!
+//! Concurrent connections are counted until they are closed. New connections are limited by token buckets
+//! refilled at the configured rate, which could be used up at once (a burst of one second).
+//!
+//! Clients that exceed any limit are disconnected right after they are accepted.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use spin::Mutex as SpinMutex;
+
+use crate::config::InboundLimitConfig;
+
+/// Idle states of clients are removed after this interval
+const PEER_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Reason of rejecting a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InboundRejection {
+    /// Reached `max_connections`
+    MaxConnections,
+    /// Reached `max_connections_per_ip`
+    MaxConnectionsPerIp,
+    /// Exceeded `connection_rate`
+    ConnectionRate,
+    /// Exceeded `connection_rate_per_ip`
+    ConnectionRatePerIp,
+}
+
+impl fmt::Display for InboundRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            InboundRejection::MaxConnections => f.write_str("reached max_connections"),
+            InboundRejection::MaxConnectionsPerIp => f.write_str("reached max_connections_per_ip"),
+            InboundRejection::ConnectionRate => f.write_str("exceeded connection_rate"),
+            InboundRejection::ConnectionRatePerIp => f.write_str("exceeded connection_rate_per_ip"),
+        }
+    }
+}
+
+impl InboundRejection {
+    /// Whether the client could retry soon, after tokens are refilled
+    pub fn is_rate_limited(&self) -> bool {
+        matches!(
+            *self,
+            InboundRejection::ConnectionRate | InboundRejection::ConnectionRatePerIp
+        )
+    }
+}
+
+/// New connections per second
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: rate as f64,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, rate: u32, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate as f64).min(rate as f64);
+        self.last_refill = now;
+    }
+
+    fn is_full(&self, rate: u32) -> bool {
+        self.tokens >= rate as f64
+    }
+}
+
+#[derive(Debug)]
+struct PeerState {
+    connections: usize,
+    bucket: Option<TokenBucket>,
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    connections: usize,
+    bucket: Option<TokenBucket>,
+    peers: HashMap<IpAddr, PeerState>,
+    last_cleanup: Instant,
+}
+
+/// Limiter of inbound connections of a local server
+#[derive(Debug)]
+pub struct InboundLimiter {
+    config: InboundLimitConfig,
+    state: SpinMutex<LimiterState>,
+}
+
+impl InboundLimiter {
+    /// Create with configured limits
+    pub fn new(config: InboundLimitConfig) -> InboundLimiter {
+        let now = Instant::now();
+        InboundLimiter {
+            state: SpinMutex::new(LimiterState {
+                connections: 0,
+                bucket: config.connection_rate.map(|r| TokenBucket::new(r, now)),
+                peers: HashMap::new(),
+                last_cleanup: now,
+            }),
+            config,
+        }
+    }
+
+    /// Admit a new client from `peer_addr`, the connection is counted until the permit is dropped
+    pub fn acquire(self: &Arc<Self>, peer_addr: &SocketAddr) -> Result<InboundPermit, InboundRejection> {
+        let ip = peer_ip(peer_addr);
+        let now = Instant::now();
+        let config = &self.config;
+
+        let mut state = self.state.lock();
+        let state = &mut *state;
+
+        if now.saturating_duration_since(state.last_cleanup) >= PEER_CLEANUP_INTERVAL {
+            // Clients are forgotten after they are disconnected and their rates are recovered
+            state.peers.retain(|_, peer| {
+                peer.connections > 0
+                    || match (config.connection_rate_per_ip, &mut peer.bucket) {
+                        (Some(rate), Some(bucket)) => {
+                            bucket.refill(rate, now);
+                            !bucket.is_full(rate)
+                        }
+                        _ => false,
+                    }
+            });
+            state.last_cleanup = now;
+        }
+
+        if let Some(max) = config.max_connections {
+            if state.connections >= max {
+                return Err(InboundRejection::MaxConnections);
+            }
+        }
+
+        let peer = state.peers.entry(ip).or_insert_with(|| PeerState {
+            connections: 0,
+            bucket: config.connection_rate_per_ip.map(|r| TokenBucket::new(r, now)),
+        });
+        if let Some(max) = config.max_connections_per_ip {
+            if peer.connections >= max {
+                return Err(InboundRejection::MaxConnectionsPerIp);
+            }
+        }
+
+        // Tokens are only taken if both buckets have them, rejected clients don't use up the rate of others
+        if let (Some(rate), Some(bucket)) = (config.connection_rate, &mut state.bucket) {
+            bucket.refill(rate, now);
+            if bucket.tokens < 1.0 {
+                return Err(InboundRejection::ConnectionRate);
+            }
+        }
+        if let (Some(rate), Some(bucket)) = (config.connection_rate_per_ip, &mut peer.bucket) {
+            bucket.refill(rate, now);
+            if bucket.tokens < 1.0 {
+                return Err(InboundRejection::ConnectionRatePerIp);
+            }
+            bucket.tokens -= 1.0;
+        }
+        if let Some(ref mut bucket) = state.bucket {
+            bucket.tokens -= 1.0;
+        }
+
+        peer.connections += 1;
+        state.connections += 1;
+
+        Ok(InboundPermit {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    fn release(&self, ip: &IpAddr) {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        state.connections = state.connections.saturating_sub(1);
+        if let Some(peer) = state.peers.get_mut(ip) {
+            peer.connections = peer.connections.saturating_sub(1);
+            if peer.connections == 0 && peer.bucket.is_none() {
+                state.peers.remove(ip);
+            }
+        }
+    }
+}
+
+/// An admitted client, which is counted until it is dropped
+#[derive(Debug)]
+pub struct InboundPermit {
+    limiter: Arc<InboundLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for InboundPermit {
+    fn drop(&mut self) {
+        self.limiter.release(&self.ip);
+    }
+}
+
+/// IPv4 clients of dual-stack listeners are counted by their IPv4 addresses
+fn peer_ip(peer_addr: &SocketAddr) -> IpAddr {
+    match peer_addr.ip() {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(IpAddr::V6(v6), IpAddr::V4),
+        ip => ip,
+    }
+}
@@ -0,0 +1,274 @@
+//! Traffic mirroring for debugging
+//!
+//! Plaintext data of TCP relays are encapsulated in synthesized IPv4 / IPv6 + TCP packets
+//! (`LINKTYPE_RAW`), so they could be inspected with Wireshark directly.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use log::{debug, error, info};
+use shadowsocks::relay::socks5::Address;
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    net::UdpSocket,
+    sync::mpsc,
+};
+
+use crate::config::TrafficMirrorSink;
+
+/// Pending packets in the mirror channel. Packets are dropped if the sink couldn't catch up
+const MIRROR_CHANNEL_SIZE: usize = 1024;
+
+/// Maximum TCP payload in each synthesized packet
+const MIRROR_MAX_SEGMENT_SIZE: usize = 65000;
+
+const PCAP_LINKTYPE_RAW: u32 = 101;
+
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_SYN: u8 = 0x02;
+const TCP_FLAG_PSH: u8 = 0x08;
+const TCP_FLAG_ACK: u8 = 0x10;
+
+/// Mirrors plaintext traffic of relays into a sink
+#[derive(Debug)]
+pub struct TrafficMirror {
+    tx: mpsc::Sender<Bytes>,
+}
+
+impl TrafficMirror {
+    /// Open the sink and start the background writer
+    pub async fn new(sink: TrafficMirrorSink) -> io::Result<TrafficMirror> {
+        let (tx, mut rx) = mpsc::channel::<Bytes>(MIRROR_CHANNEL_SIZE);
+
+        match sink {
+            TrafficMirrorSink::Pcap(path) => {
+                let file = File::create(&path).await?;
+                let mut writer = BufWriter::new(file);
+                writer.write_all(&pcap_file_header()).await?;
+                writer.flush().await?;
+
+                info!("mirroring traffic into pcap file {}", path.display());
+
+                tokio::spawn(async move {
+                    while let Some(packet) = rx.recv().await {
+                        let result = async {
+                            writer.write_all(&pcap_record_header(packet.len())).await?;
+                            writer.write_all(&packet).await?;
+                            if rx.is_empty() {
+                                writer.flush().await?;
+                            }
+                            Ok::<_, io::Error>(())
+                        }
+                        .await;
+
+                        if let Err(err) = result {
+                            error!(
+                                "failed to write mirrored traffic into {}, error: {}",
+                                path.display(),
+                                err
+                            );
+                            break;
+                        }
+                    }
+                });
+            }
+            TrafficMirrorSink::Udp(addr) => {
+                let bind_addr = match addr {
+                    SocketAddr::V4(..) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+                    SocketAddr::V6(..) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+                };
+                let socket = UdpSocket::bind(bind_addr).await?;
+                socket.connect(addr).await?;
+
+                info!("mirroring traffic to udp sink {}", addr);
+
+                tokio::spawn(async move {
+                    while let Some(packet) = rx.recv().await {
+                        if let Err(err) = socket.send(&packet).await {
+                            debug!("failed to send mirrored traffic to {}, error: {}", addr, err);
+                        }
+                    }
+                });
+            }
+        }
+
+        Ok(TrafficMirror { tx })
+    }
+
+    /// Start mirroring a TCP flow from `src_addr` to `target_addr`
+    ///
+    /// Domain name targets are mapped to addresses in `198.18.0.0/15` (benchmarking network, RFC 2544)
+    pub fn start_tcp_flow(&self, src_addr: SocketAddr, target_addr: &Address) -> FlowMirror {
+        let dst_addr = match *target_addr {
+            Address::SocketAddress(addr) => addr,
+            Address::DomainNameAddress(ref host, port) => {
+                let mut hasher = DefaultHasher::new();
+                host.hash(&mut hasher);
+                let h = hasher.finish().to_be_bytes();
+                let addr = SocketAddr::new(Ipv4Addr::new(198, 18 | (h[0] & 0x01), h[1], h[2]).into(), port);
+                info!("mirroring tcp flow {} -> {} as {}", src_addr, target_addr, addr);
+                addr
+            }
+        };
+
+        let mut flow = FlowMirror {
+            tx: self.tx.clone(),
+            src_addr,
+            dst_addr,
+            l2r_seq: rand::random(),
+            r2l_seq: rand::random(),
+        };
+
+        // Handshake, for letting Wireshark track the stream from the beginning
+        flow.send_segment(true, TCP_FLAG_SYN, &[]);
+        flow.l2r_seq = flow.l2r_seq.wrapping_add(1);
+        flow.send_segment(false, TCP_FLAG_SYN | TCP_FLAG_ACK, &[]);
+        flow.r2l_seq = flow.r2l_seq.wrapping_add(1);
+        flow.send_segment(true, TCP_FLAG_ACK, &[]);
+
+        flow
+    }
+}
+
+/// A mirrored TCP flow. FIN will be sent in both directions when dropped
+pub struct FlowMirror {
+    tx: mpsc::Sender<Bytes>,
+    src_addr: SocketAddr,
+    dst_addr: SocketAddr,
+    l2r_seq: u32,
+    r2l_seq: u32,
+}
+
+impl FlowMirror {
+    /// Data sent from local to remote
+    pub fn local_to_remote(&mut self, data: &[u8]) {
+        for chunk in data.chunks(MIRROR_MAX_SEGMENT_SIZE) {
+            self.send_segment(true, TCP_FLAG_PSH | TCP_FLAG_ACK, chunk);
+            self.l2r_seq = self.l2r_seq.wrapping_add(chunk.len() as u32);
+        }
+    }
+
+    /// Data received from remote to local
+    pub fn remote_to_local(&mut self, data: &[u8]) {
+        for chunk in data.chunks(MIRROR_MAX_SEGMENT_SIZE) {
+            self.send_segment(false, TCP_FLAG_PSH | TCP_FLAG_ACK, chunk);
+            self.r2l_seq = self.r2l_seq.wrapping_add(chunk.len() as u32);
+        }
+    }
+
+    fn send_segment(&self, l2r: bool, flags: u8, payload: &[u8]) {
+        let packet = if l2r {
+            build_tcp_packet(self.src_addr, self.dst_addr, self.l2r_seq, self.r2l_seq, flags, payload)
+        } else {
+            build_tcp_packet(self.dst_addr, self.src_addr, self.r2l_seq, self.l2r_seq, flags, payload)
+        };
+
+        // Never block the relay. Drop the packet if sink is busy
+        let _ = self.tx.try_send(packet);
+    }
+}
+
+impl Drop for FlowMirror {
+    fn drop(&mut self) {
+        self.send_segment(true, TCP_FLAG_FIN | TCP_FLAG_ACK, &[]);
+        self.l2r_seq = self.l2r_seq.wrapping_add(1);
+        self.send_segment(false, TCP_FLAG_FIN | TCP_FLAG_ACK, &[]);
+    }
+}
+
+fn pcap_file_header() -> [u8; 24] {
+    let mut buf = [0u8; 24];
+    buf[0..4].copy_from_slice(&0xa1b2c3d4u32.to_ne_bytes());
+    buf[4..6].copy_from_slice(&2u16.to_ne_bytes());
+    buf[6..8].copy_from_slice(&4u16.to_ne_bytes());
+    // thiszone, sigfigs = 0
+    buf[16..20].copy_from_slice(&65535u32.to_ne_bytes());
+    buf[20..24].copy_from_slice(&PCAP_LINKTYPE_RAW.to_ne_bytes());
+    buf
+}
+
+fn pcap_record_header(len: usize) -> [u8; 16] {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    let mut buf = [0u8; 16];
+    buf[0..4].copy_from_slice(&(now.as_secs() as u32).to_ne_bytes());
+    buf[4..8].copy_from_slice(&now.subsec_micros().to_ne_bytes());
+    buf[8..12].copy_from_slice(&(len as u32).to_ne_bytes());
+    buf[12..16].copy_from_slice(&(len as u32).to_ne_bytes());
+    buf
+}
+
+fn build_tcp_packet(src: SocketAddr, dst: SocketAddr, seq: u32, ack: u32, flags: u8, payload: &[u8]) -> Bytes {
+    const IPV4_HEADER_LEN: usize = 20;
+    const IPV6_HEADER_LEN: usize = 40;
+    const TCP_HEADER_LEN: usize = 20;
+
+    let tcp_len = TCP_HEADER_LEN + payload.len();
+
+    let mut buf;
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            buf = BytesMut::with_capacity(IPV4_HEADER_LEN + tcp_len);
+
+            buf.put_u8(0x45); // Version 4, IHL 5
+            buf.put_u8(0);
+            buf.put_u16((IPV4_HEADER_LEN + tcp_len) as u16);
+            buf.put_u16(0); // Identification
+            buf.put_u16(0x4000); // Don't fragment
+            buf.put_u8(64); // TTL
+            buf.put_u8(6); // TCP
+            buf.put_u16(0); // Checksum
+            buf.put_slice(&src_ip.octets());
+            buf.put_slice(&dst_ip.octets());
+
+            let checksum = ipv4_header_checksum(&buf[..IPV4_HEADER_LEN]);
+            buf[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        (src_ip, dst_ip) => {
+            let to_ipv6 = |ip: IpAddr| match ip {
+                IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                IpAddr::V6(v6) => v6,
+            };
+
+            buf = BytesMut::with_capacity(IPV6_HEADER_LEN + tcp_len);
+
+            buf.put_u32(0x6000_0000); // Version 6
+            buf.put_u16(tcp_len as u16);
+            buf.put_u8(6); // TCP
+            buf.put_u8(64); // Hop limit
+            buf.put_slice(&to_ipv6(src_ip).octets());
+            buf.put_slice(&to_ipv6(dst_ip).octets());
+        }
+    }
+
+    buf.put_u16(src.port());
+    buf.put_u16(dst.port());
+    buf.put_u32(seq);
+    buf.put_u32(ack);
+    buf.put_u8((TCP_HEADER_LEN as u8 / 4) << 4);
+    buf.put_u8(flags);
+    buf.put_u16(u16::MAX); // Window
+    buf.put_u16(0); // Checksum, not validated by Wireshark by default
+    buf.put_u16(0); // Urgent pointer
+    buf.put_slice(payload);
+
+    buf.freeze()
+}
+
+fn ipv4_header_checksum(header: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for word in header.chunks(2) {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
@@ -1,9 +1,20 @@
 //! Shadowsocks Local Network Utilities
 
 pub use self::{
-    tcp::{auto_proxy_io::AutoProxyIo, auto_proxy_stream::AutoProxyClientStream},
+    tcp::{auto_proxy_io::AutoProxyIo, auto_proxy_stream::AutoProxyClientStream, LocalStream},
     udp::{UdpAssociationManager, UdpInboundWrite},
 };
 
+pub mod ipc;
+pub mod limit;
+pub mod mirror;
+pub mod process;
+pub mod qos;
+pub mod rewind;
+pub mod sniff;
+pub mod special_target;
 pub(crate) mod tcp;
+#[cfg(feature = "local-tls")]
+pub mod tls;
 pub(crate) mod udp;
+pub mod uplink;
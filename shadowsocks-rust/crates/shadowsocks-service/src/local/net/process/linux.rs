@@ -0,0 +1,333 @@
+//! Lookups with netlink `sock_diag` (or `/proc/net/tcp{,6}`) and `/proc/*/fd`
+
+use std::{
+    fs, io, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::fs::MetadataExt,
+    },
+    path::PathBuf,
+    ptr,
+};
+
+use log::trace;
+
+use super::RecentOwners;
+
+static RECENT_OWNERS: RecentOwners = RecentOwners::new();
+
+// linux/sock_diag.h, linux/inet_diag.h
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+const INET_DIAG_NOCOOKIE: u32 = !0;
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct InetDiagSockId {
+    idiag_sport: u16,
+    idiag_dport: u16,
+    idiag_src: [u32; 4],
+    idiag_dst: [u32; 4],
+    idiag_if: u32,
+    idiag_cookie: [u32; 2],
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct InetDiagReqV2 {
+    sdiag_family: u8,
+    sdiag_protocol: u8,
+    idiag_ext: u8,
+    pad: u8,
+    idiag_states: u32,
+    id: InetDiagSockId,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct InetDiagMsg {
+    idiag_family: u8,
+    idiag_state: u8,
+    idiag_timer: u8,
+    idiag_retrans: u8,
+    id: InetDiagSockId,
+    idiag_expires: u32,
+    idiag_rqueue: u32,
+    idiag_wqueue: u32,
+    idiag_uid: u32,
+    idiag_inode: u32,
+}
+
+#[repr(C)]
+struct DiagRequest {
+    header: libc::nlmsghdr,
+    request: InetDiagReqV2,
+}
+
+/// Socket of a TCP connection
+struct TcpSocket {
+    inode: u64,
+    uid: u32,
+}
+
+/// Connections of dual-stack sockets are listed with IPv4-mapped addresses
+fn unmap_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(IpAddr::V4(v4), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(..) => addr,
+    }
+}
+
+/// Address of `sock_diag` socket IDs, 32-bit words in network order
+fn diag_addr(family: u8, words: &[u32; 4], port: u16) -> SocketAddr {
+    let mut octets = [0u8; 16];
+    for (i, word) in words.iter().enumerate() {
+        octets[i * 4..(i + 1) * 4].copy_from_slice(&word.to_ne_bytes());
+    }
+    let ip = if family as i32 == libc::AF_INET {
+        IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(octets))
+    };
+    unmap_addr(SocketAddr::new(ip, u16::from_be(port)))
+}
+
+/// Dump TCP sockets of `family` with `sock_diag`, find the one with local address `addr`
+fn find_tcp_socket_diag_family(family: i32, addr: SocketAddr) -> io::Result<Option<TcpSocket>> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            libc::NETLINK_SOCK_DIAG,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let request = DiagRequest {
+        header: libc::nlmsghdr {
+            nlmsg_len: mem::size_of::<DiagRequest>() as u32,
+            nlmsg_type: SOCK_DIAG_BY_FAMILY,
+            nlmsg_flags: (libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16,
+            nlmsg_seq: 1,
+            nlmsg_pid: 0,
+        },
+        request: InetDiagReqV2 {
+            sdiag_family: family as u8,
+            sdiag_protocol: libc::IPPROTO_TCP as u8,
+            idiag_ext: 0,
+            pad: 0,
+            // All states
+            idiag_states: !0,
+            id: InetDiagSockId {
+                idiag_sport: 0,
+                idiag_dport: 0,
+                idiag_src: [0; 4],
+                idiag_dst: [0; 4],
+                idiag_if: 0,
+                idiag_cookie: [INET_DIAG_NOCOOKIE; 2],
+            },
+        },
+    };
+    let n = unsafe {
+        libc::send(
+            socket.as_raw_fd(),
+            &request as *const DiagRequest as *const libc::c_void,
+            mem::size_of::<DiagRequest>(),
+            0,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let header_len = mem::size_of::<libc::nlmsghdr>();
+    let mut buffer = vec![0u8; 32 * 1024];
+    loop {
+        let n = unsafe {
+            libc::recv(
+                socket.as_raw_fd(),
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                0,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let n = n as usize;
+        if n == 0 {
+            return Ok(None);
+        }
+
+        let mut offset = 0;
+        while offset + header_len <= n {
+            let header = unsafe { ptr::read_unaligned(buffer[offset..].as_ptr() as *const libc::nlmsghdr) };
+            let len = header.nlmsg_len as usize;
+            if len < header_len || offset + len > n {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "truncated sock_diag message",
+                ));
+            }
+
+            match header.nlmsg_type as i32 {
+                libc::NLMSG_DONE => return Ok(None),
+                libc::NLMSG_ERROR => {
+                    if len < header_len + mem::size_of::<i32>() {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated sock_diag error"));
+                    }
+                    let errno = unsafe { ptr::read_unaligned(buffer[offset + header_len..].as_ptr() as *const i32) };
+                    return Err(io::Error::from_raw_os_error(-errno));
+                }
+                _ if len >= header_len + mem::size_of::<InetDiagMsg>() => {
+                    let msg =
+                        unsafe { ptr::read_unaligned(buffer[offset + header_len..].as_ptr() as *const InetDiagMsg) };
+                    if msg.idiag_inode != 0
+                        && diag_addr(msg.idiag_family, &msg.id.idiag_src, msg.id.idiag_sport) == addr
+                    {
+                        return Ok(Some(TcpSocket {
+                            inode: msg.idiag_inode as u64,
+                            uid: msg.idiag_uid,
+                        }));
+                    }
+                }
+                _ => {}
+            }
+
+            // NLMSG_ALIGN
+            offset += (len + 3) & !3;
+        }
+    }
+}
+
+fn find_tcp_socket_diag(addr: SocketAddr) -> io::Result<Option<TcpSocket>> {
+    // IPv4 connections of dual-stack sockets are in the IPv6 table
+    if addr.is_ipv4() {
+        if let Some(socket) = find_tcp_socket_diag_family(libc::AF_INET, addr)? {
+            return Ok(Some(socket));
+        }
+    }
+    find_tcp_socket_diag_family(libc::AF_INET6, addr)
+}
+
+/// Parse an address of `/proc/net/tcp{,6}`, 32-bit words of the address are printed in native endian
+fn parse_proc_addr(s: &str) -> Option<SocketAddr> {
+    let (ip, port) = s.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+
+    let mut octets = [0u8; 16];
+    let words = ip.len() / 8;
+    if (words != 1 && words != 4) || ip.len() % 8 != 0 {
+        return None;
+    }
+    for i in 0..words {
+        let word = u32::from_str_radix(&ip[i * 8..(i + 1) * 8], 16).ok()?;
+        octets[i * 4..(i + 1) * 4].copy_from_slice(&word.to_ne_bytes());
+    }
+
+    let ip = if words == 1 {
+        IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(octets))
+    };
+
+    Some(unmap_addr(SocketAddr::new(ip, port)))
+}
+
+/// Find the socket with local address `addr` in `/proc/net/tcp{,6}`
+fn find_tcp_socket_proc(addr: SocketAddr) -> io::Result<Option<TcpSocket>> {
+    for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let content = match fs::read_to_string(table) {
+            Ok(c) => c,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+
+        // sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode ...
+        for line in content.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 {
+                continue;
+            }
+
+            if parse_proc_addr(fields[1]) == Some(addr) {
+                if let (Ok(inode), Ok(uid)) = (fields[9].parse::<u64>(), fields[7].parse::<u32>()) {
+                    if inode != 0 {
+                        return Ok(Some(TcpSocket { inode, uid }));
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Socket with local address `addr`
+fn find_tcp_socket(addr: SocketAddr) -> io::Result<Option<TcpSocket>> {
+    let addr = unmap_addr(addr);
+    match find_tcp_socket_diag(addr) {
+        Ok(socket) => Ok(socket),
+        Err(err) => {
+            // sock_diag is not permitted in some sandboxes, like apps on Android
+            trace!(
+                "sock_diag lookup of {} failed, fallback to /proc/net, error: {}",
+                addr,
+                err
+            );
+            find_tcp_socket_proc(addr)
+        }
+    }
+}
+
+fn all_pids() -> io::Result<Vec<libc::pid_t>> {
+    let mut pids = Vec::new();
+    for entry in fs::read_dir("/proc")? {
+        let entry = entry?;
+        if let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<libc::pid_t>().ok())
+        {
+            pids.push(pid);
+        }
+    }
+    Ok(pids)
+}
+
+/// Whether process `pid` of `uid` has an fd of the socket, links of which are `socket:[inode]`
+fn process_owns_socket(pid: libc::pid_t, uid: u32, socket_link: &str) -> bool {
+    let process_dir = PathBuf::from(format!("/proc/{pid}"));
+
+    // Sockets are owned by processes of the same user mostly, which is far cheaper to check than fds
+    if !fs::metadata(&process_dir).is_ok_and(|meta| meta.uid() == uid) {
+        return false;
+    }
+
+    // Processes of other users are not accessible without privileges
+    let fds = match fs::read_dir(process_dir.join("fd")) {
+        Ok(fds) => fds,
+        Err(..) => return false,
+    };
+    fds.flatten()
+        .any(|fd| fs::read_link(fd.path()).is_ok_and(|link| link.as_os_str() == socket_link))
+}
+
+pub fn find_tcp_process_path(addr: SocketAddr) -> io::Result<Option<PathBuf>> {
+    let socket = match find_tcp_socket(addr)? {
+        Some(socket) => socket,
+        None => return Ok(None),
+    };
+    let socket_link = format!("socket:[{}]", socket.inode);
+
+    let pid = RECENT_OWNERS.find(all_pids, |pid| process_owns_socket(pid, socket.uid, &socket_link))?;
+    Ok(pid.and_then(|pid| fs::read_link(format!("/proc/{pid}/exe")).ok()))
+}
@@ -0,0 +1,171 @@
+//! Lookups with `proc_pidinfo`, `proc_pidfdinfo` and `proc_pidpath`
+
+use std::{
+    ffi::OsString,
+    io, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::unix::ffi::OsStringExt,
+    path::PathBuf,
+    process,
+};
+
+use super::RecentOwners;
+
+static RECENT_OWNERS: RecentOwners = RecentOwners::new();
+
+// sys/proc_info.h
+const PROC_PIDLISTFDS: libc::c_int = 1;
+const PROC_PIDFDSOCKETINFO: libc::c_int = 3;
+const PROX_FDTYPE_SOCKET: u32 = 2;
+const SOCKINFO_IN: i32 = 1;
+const SOCKINFO_TCP: i32 = 2;
+const INI_IPV4: u8 = 0x1;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ProcFdInfo {
+    proc_fd: i32,
+    proc_fdtype: u32,
+}
+
+/// Leading fields of `struct socket_fdinfo`, up to the local address of `soi_proto.pri_in` (or `pri_tcp.tcpsi_ini`)
+///
+/// Fields not used are left as paddings, the buffer is larger than `struct socket_fdinfo`.
+#[allow(dead_code)]
+#[repr(C)]
+struct SocketFdInfo {
+    /// `pfi`, `psi.soi_stat`, `soi_so`, `soi_pcb`, `soi_type`, `soi_protocol`
+    _pad0: [u8; 24 + 136 + 8 + 8 + 4 + 4],
+    soi_family: i32,
+    /// `soi_options` .. `soi_snd`
+    _pad1: [u8; 68],
+    soi_kind: i32,
+    _rfu_1: u32,
+    insi_fport: i32,
+    insi_lport: i32,
+    insi_gencnt: u64,
+    insi_flags: u32,
+    insi_flow: u32,
+    insi_vflag: u8,
+    insi_ip_ttl: u8,
+    _rfu_2: u32,
+    insi_faddr: [u8; 16],
+    insi_laddr: [u8; 16],
+    _pad2: [u8; 1024],
+}
+
+/// Local address of the TCP socket `fd` of process `pid`
+fn socket_local_addr(pid: libc::pid_t, fd: i32) -> Option<SocketAddr> {
+    let mut info: SocketFdInfo = unsafe { mem::zeroed() };
+    let size = mem::size_of::<SocketFdInfo>() as libc::c_int;
+    let n = unsafe {
+        libc::proc_pidfdinfo(
+            pid,
+            fd,
+            PROC_PIDFDSOCKETINFO,
+            &mut info as *mut SocketFdInfo as *mut libc::c_void,
+            size,
+        )
+    };
+    if n <= 0 {
+        return None;
+    }
+    if (info.soi_kind != SOCKINFO_TCP && info.soi_kind != SOCKINFO_IN)
+        || (info.soi_family != libc::AF_INET && info.soi_family != libc::AF_INET6)
+    {
+        return None;
+    }
+
+    // Ports are in network order, addresses of IPv4 are the last 4 bytes of `in4in6_addr`
+    let port = u16::from_be(info.insi_lport as u16);
+    let ip = if info.insi_vflag & INI_IPV4 != 0 {
+        let a = &info.insi_laddr[12..16];
+        IpAddr::V4(Ipv4Addr::new(a[0], a[1], a[2], a[3]))
+    } else {
+        let ip = Ipv6Addr::from(info.insi_laddr);
+        match ip.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(ip),
+        }
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+fn socket_fds(pid: libc::pid_t) -> Vec<i32> {
+    let size = unsafe { libc::proc_pidinfo(pid, PROC_PIDLISTFDS, 0, std::ptr::null_mut(), 0) };
+    if size <= 0 {
+        return Vec::new();
+    }
+
+    // Room for fds opened between calls
+    let mut fds = vec![ProcFdInfo::default(); size as usize / mem::size_of::<ProcFdInfo>() + 32];
+    let size = unsafe {
+        libc::proc_pidinfo(
+            pid,
+            PROC_PIDLISTFDS,
+            0,
+            fds.as_mut_ptr() as *mut libc::c_void,
+            (fds.len() * mem::size_of::<ProcFdInfo>()) as libc::c_int,
+        )
+    };
+    if size <= 0 {
+        return Vec::new();
+    }
+    fds.truncate(size as usize / mem::size_of::<ProcFdInfo>());
+
+    fds.into_iter()
+        .filter(|fd| fd.proc_fdtype == PROX_FDTYPE_SOCKET)
+        .map(|fd| fd.proc_fd)
+        .collect()
+}
+
+fn all_pids() -> io::Result<Vec<libc::pid_t>> {
+    let count = unsafe { libc::proc_listallpids(std::ptr::null_mut(), 0) };
+    if count <= 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Room for processes started between calls
+    let mut pids = vec![0 as libc::pid_t; count as usize + 64];
+    let count = unsafe {
+        libc::proc_listallpids(
+            pids.as_mut_ptr() as *mut libc::c_void,
+            (pids.len() * mem::size_of::<libc::pid_t>()) as libc::c_int,
+        )
+    };
+    if count <= 0 {
+        return Err(io::Error::last_os_error());
+    }
+    pids.truncate(count as usize);
+    Ok(pids)
+}
+
+fn process_path(pid: libc::pid_t) -> Option<PathBuf> {
+    let mut buffer = vec![0u8; libc::PROC_PIDPATHINFO_MAXSIZE as usize];
+    let len = unsafe { libc::proc_pidpath(pid, buffer.as_mut_ptr() as *mut _, buffer.len() as u32) };
+    if len <= 0 {
+        return None;
+    }
+    buffer.truncate(len as usize);
+    Some(PathBuf::from(OsString::from_vec(buffer)))
+}
+
+pub fn find_tcp_process_path(addr: SocketAddr) -> io::Result<Option<PathBuf>> {
+    let addr = match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(IpAddr::V4(v4), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(..) => addr,
+    };
+
+    // Our own sockets are skipped, redir accepts the connection from `addr`
+    let current_pid = process::id() as libc::pid_t;
+    let pid = RECENT_OWNERS.find(all_pids, |pid| {
+        pid != current_pid
+            && socket_fds(pid)
+                .into_iter()
+                .any(|fd| socket_local_addr(pid, fd) == Some(addr))
+    })?;
+    Ok(pid.and_then(process_path))
+}
@@ -0,0 +1,78 @@
+//! Owning processes of local TCP connections, for process rules of ACL
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+use std::{collections::VecDeque, sync::Mutex};
+use std::{io, net::SocketAddr, path::PathBuf};
+
+use cfg_if::cfg_if;
+use log::trace;
+
+cfg_if! {
+    if #[cfg(any(target_os = "linux", target_os = "android"))] {
+        mod linux;
+        use self::linux::find_tcp_process_path;
+    } else if #[cfg(target_os = "macos")] {
+        mod macos;
+        use self::macos::find_tcp_process_path;
+    } else if #[cfg(windows)] {
+        mod windows;
+        use self::windows::find_tcp_process_path;
+    } else {
+        fn find_tcp_process_path(_addr: SocketAddr) -> io::Result<Option<PathBuf>> {
+            Ok(None)
+        }
+    }
+}
+
+/// Processes owning recent connections
+///
+/// Finding the owner of a socket requires walking file descriptors of processes, but new connections are
+/// mostly made by processes that made connections recently, so they are searched before all the others.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+struct RecentOwners {
+    pids: Mutex<VecDeque<libc::pid_t>>,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+impl RecentOwners {
+    const CAPACITY: usize = 16;
+
+    const fn new() -> RecentOwners {
+        RecentOwners {
+            pids: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Find the process that `owns` the socket, in recent owners and then in `all_pids`
+    fn find<A, F>(&self, all_pids: A, mut owns: F) -> io::Result<Option<libc::pid_t>>
+    where
+        A: FnOnce() -> io::Result<Vec<libc::pid_t>>,
+        F: FnMut(libc::pid_t) -> bool,
+    {
+        let recent = self.pids.lock().unwrap().clone();
+        let mut found = recent.iter().copied().find(|pid| owns(*pid));
+        if found.is_none() {
+            found = all_pids()?
+                .into_iter()
+                .filter(|pid| !recent.contains(pid))
+                .find(|pid| owns(*pid));
+        }
+
+        if let Some(pid) = found {
+            let mut pids = self.pids.lock().unwrap();
+            pids.retain(|p| *p != pid);
+            pids.push_front(pid);
+            pids.truncate(Self::CAPACITY);
+        }
+        Ok(found)
+    }
+}
+
+/// Executable path of the process owning the TCP connection from local address `addr`
+///
+/// `None` if the connection is not found, or its owner is not visible to the current user.
+pub async fn tcp_process_path(addr: SocketAddr) -> io::Result<Option<PathBuf>> {
+    let path = tokio::task::spawn_blocking(move || find_tcp_process_path(addr)).await??;
+    trace!("tcp connection from {} is owned by process {:?}", addr, path);
+    Ok(path)
+}
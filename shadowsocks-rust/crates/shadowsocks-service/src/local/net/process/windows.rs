@@ -0,0 +1,120 @@
+//! Lookups with `GetExtendedTcpTable` and `QueryFullProcessImageNameW`
+
+use std::{
+    ffi::OsString,
+    io, mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::windows::ffi::OsStringExt,
+    path::PathBuf,
+    slice,
+};
+
+use windows_sys::Win32::{
+    Foundation::{CloseHandle, ERROR_INSUFFICIENT_BUFFER, NO_ERROR},
+    NetworkManagement::IpHelper::{
+        GetExtendedTcpTable, MIB_TCP6ROW_OWNER_PID, MIB_TCP6TABLE_OWNER_PID, MIB_TCPROW_OWNER_PID,
+        MIB_TCPTABLE_OWNER_PID, TCP_TABLE_OWNER_PID_ALL,
+    },
+    Networking::WinSock::{AF_INET, AF_INET6},
+    System::Threading::{
+        OpenProcess, QueryFullProcessImageNameW, PROCESS_NAME_WIN32, PROCESS_QUERY_LIMITED_INFORMATION,
+    },
+};
+
+/// Raw table of `GetExtendedTcpTable`, in `u64`s for alignment of rows
+fn extended_tcp_table(family: u16) -> io::Result<Vec<u64>> {
+    let mut size = 0u32;
+    let mut table = Vec::new();
+
+    loop {
+        let ret = unsafe {
+            GetExtendedTcpTable(
+                table.as_mut_ptr() as *mut _,
+                &mut size,
+                0,
+                family as u32,
+                TCP_TABLE_OWNER_PID_ALL,
+                0,
+            )
+        };
+
+        match ret {
+            NO_ERROR => return Ok(table),
+            // Table may grow between calls
+            ERROR_INSUFFICIENT_BUFFER => table = vec![0u64; (size as usize).div_ceil(mem::size_of::<u64>())],
+            err => return Err(io::Error::from_raw_os_error(err as i32)),
+        }
+    }
+}
+
+/// Port of rows, in network order in the lower 16 bits
+fn row_port(port: u32) -> u16 {
+    u16::from_be(port as u16)
+}
+
+fn find_tcp_pid(addr: SocketAddr) -> io::Result<Option<u32>> {
+    // Connections of dual-stack listeners are in the IPv4 table
+    let addr = match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(v4.into(), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(..) => addr,
+    };
+
+    match addr {
+        SocketAddr::V4(v4) => {
+            let table = extended_tcp_table(AF_INET)?;
+            if table.is_empty() {
+                return Ok(None);
+            }
+            let table = table.as_ptr() as *const MIB_TCPTABLE_OWNER_PID;
+            let rows: &[MIB_TCPROW_OWNER_PID] =
+                unsafe { slice::from_raw_parts((*table).table.as_ptr(), (*table).dwNumEntries as usize) };
+
+            Ok(rows
+                .iter()
+                .find(|row| {
+                    Ipv4Addr::from(row.dwLocalAddr.to_ne_bytes()) == *v4.ip() && row_port(row.dwLocalPort) == v4.port()
+                })
+                .map(|row| row.dwOwningPid))
+        }
+        SocketAddr::V6(v6) => {
+            let table = extended_tcp_table(AF_INET6)?;
+            if table.is_empty() {
+                return Ok(None);
+            }
+            let table = table.as_ptr() as *const MIB_TCP6TABLE_OWNER_PID;
+            let rows: &[MIB_TCP6ROW_OWNER_PID] =
+                unsafe { slice::from_raw_parts((*table).table.as_ptr(), (*table).dwNumEntries as usize) };
+
+            Ok(rows
+                .iter()
+                .find(|row| Ipv6Addr::from(row.ucLocalAddr) == *v6.ip() && row_port(row.dwLocalPort) == v6.port())
+                .map(|row| row.dwOwningPid))
+        }
+    }
+}
+
+fn process_image_path(pid: u32) -> Option<PathBuf> {
+    unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if process.is_null() {
+            return None;
+        }
+
+        let mut buffer = [0u16; 1024];
+        let mut size = buffer.len() as u32;
+        let ret = QueryFullProcessImageNameW(process, PROCESS_NAME_WIN32, buffer.as_mut_ptr(), &mut size);
+        CloseHandle(process);
+
+        if ret == 0 {
+            return None;
+        }
+        Some(PathBuf::from(OsString::from_wide(&buffer[..size as usize])))
+    }
+}
+
+pub fn find_tcp_process_path(addr: SocketAddr) -> io::Result<Option<PathBuf>> {
+    Ok(find_tcp_pid(addr)?.and_then(process_image_path))
+}
@@ -0,0 +1,130 @@
+//! Traffic classification of proxied TCP flows
+//!
+//! A flow that keeps transferring data (a burst of at least `bulk_bytes` lasting `bulk_age`), for example a long
+//! download, is classified as bulk. Other flows are interactive. A flow starts a new burst after being idle.
+//!
+//! Each direction has a token bucket refilled at the configured bandwidth. Interactive flows never wait, their
+//! data is only accounted in the bucket, so bulk flows only get the bandwidth left by interactive flows.
+//! Bandwidth should be a little lower than the real link, so queues are built here instead of the congested link.
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{self, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::time::Sleep;
+
+use crate::{
+    config::QosConfig,
+    net::rate_limit::{poll_buckets_ready, TokenBucket},
+};
+
+/// A flow that has been idle for this long starts a new burst
+const FLOW_IDLE_RESET: Duration = Duration::from_secs(1);
+
+/// Schedules bandwidth between proxied TCP flows
+pub struct TrafficShaper {
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+    bulk_bytes: u64,
+    bulk_age: Duration,
+}
+
+impl TrafficShaper {
+    /// Create with configured bandwidth and classification thresholds
+    pub fn new(config: &QosConfig) -> TrafficShaper {
+        TrafficShaper {
+            upload: config.upload_kbps.map(TokenBucket::new),
+            download: config.download_kbps.map(TokenBucket::new),
+            bulk_bytes: config.bulk_bytes,
+            bulk_age: config.bulk_age,
+        }
+    }
+
+    /// Start scheduling a TCP flow
+    pub fn start_tcp_flow(self: &Arc<Self>) -> FlowShaper {
+        let now = Instant::now();
+        FlowShaper {
+            shaper: self.clone(),
+            burst_start: now,
+            burst_bytes: 0,
+            last_active: now,
+            upload_delay: None,
+            download_delay: None,
+        }
+    }
+}
+
+/// A scheduled TCP flow
+pub struct FlowShaper {
+    shaper: Arc<TrafficShaper>,
+    burst_start: Instant,
+    burst_bytes: u64,
+    last_active: Instant,
+    upload_delay: Option<Pin<Box<Sleep>>>,
+    download_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl FlowShaper {
+    /// Check if the flow is in a bulk burst
+    pub fn is_bulk(&self) -> bool {
+        self.burst_bytes >= self.shaper.bulk_bytes
+            && self.last_active.elapsed() < FLOW_IDLE_RESET
+            && self.last_active.saturating_duration_since(self.burst_start) >= self.shaper.bulk_age
+    }
+
+    /// Wait until data could be sent from local to remote
+    pub fn poll_upload_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<()> {
+        self.poll_ready(cx, true)
+    }
+
+    /// Wait until data could be received from remote to local
+    pub fn poll_download_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<()> {
+        self.poll_ready(cx, false)
+    }
+
+    /// Data sent from local to remote
+    pub fn uploaded(&mut self, n: usize) {
+        self.record(n);
+        if let Some(ref bucket) = self.shaper.upload {
+            bucket.consume(n);
+        }
+    }
+
+    /// Data received from remote to local
+    pub fn downloaded(&mut self, n: usize) {
+        self.record(n);
+        if let Some(ref bucket) = self.shaper.download {
+            bucket.consume(n);
+        }
+    }
+
+    fn poll_ready(&mut self, cx: &mut task::Context<'_>, upload: bool) -> Poll<()> {
+        // Interactive flows are never held
+        if !self.is_bulk() {
+            return Poll::Ready(());
+        }
+
+        if upload {
+            poll_buckets_ready(self.shaper.upload.iter(), &mut self.upload_delay, cx)
+        } else {
+            poll_buckets_ready(self.shaper.download.iter(), &mut self.download_delay, cx)
+        }
+    }
+
+    fn record(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        if now.saturating_duration_since(self.last_active) >= FLOW_IDLE_RESET {
+            self.burst_start = now;
+            self.burst_bytes = 0;
+        }
+        self.burst_bytes += n as u64;
+        self.last_active = now;
+    }
+}
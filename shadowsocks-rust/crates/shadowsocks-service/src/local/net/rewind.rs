@@ -0,0 +1,75 @@
+//! Stream with bytes already read from it put back
+
+use std::{
+    io::{self, IoSlice},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Stream that replays bytes already read from `stream`
+pub struct RewindStream<S> {
+    prefix: Vec<u8>,
+    position: usize,
+    stream: S,
+}
+
+impl<S> RewindStream<S> {
+    /// Read `prefix` before reading from `stream`
+    pub fn new(prefix: Vec<u8>, stream: S) -> RewindStream<S> {
+        RewindStream {
+            prefix,
+            position: 0,
+            stream,
+        }
+    }
+}
+
+impl<S> AsyncRead for RewindStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.position < this.prefix.len() {
+            let remaining = &this.prefix[this.position..];
+            let n = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..n]);
+            this.position += n;
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for RewindStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
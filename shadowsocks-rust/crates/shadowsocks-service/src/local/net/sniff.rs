@@ -0,0 +1,405 @@
+//! Sniffing domain names of transparently intercepted TCP connections
+//!
+//! Only IP addresses are known for clients of redir, tun and tunnel locals. The TLS SNI or the HTTP `Host` in the
+//! first bytes sent by clients could be matched by ACL domain rules, while connections are still made to the
+//! original IP addresses.
+//!
+//! Bytes read while sniffing are replayed to the remote with [`RewindStream`](super::rewind::RewindStream).
+
+use std::{io, net::IpAddr, str, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    time::{self, Instant},
+};
+
+/// Sniffing gives up after this many bytes, a TLS record is at most 16KiB
+const SNIFF_MAX_SIZE: usize = 16 * 1024 + 5;
+
+/// Request methods of HTTP/1.x
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"POST ",
+    b"HEAD ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+];
+
+#[derive(Debug)]
+enum Sniffed {
+    /// More bytes are required
+    Incomplete,
+    /// Not TLS or HTTP, or without a domain name
+    Unknown,
+    /// Domain name of the target
+    Host(String),
+}
+
+/// Read the first bytes from `stream` until a domain name is found, or waited for `timeout`
+///
+/// Returns bytes that are read and the domain name. Clients of server-first protocols (like SMTP) send nothing
+/// until the timeout, so it should be short.
+pub async fn sniff_host<S>(stream: &mut S, timeout: Duration) -> io::Result<(Vec<u8>, Option<String>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let deadline = Instant::now() + timeout;
+    let mut buffer = Vec::new();
+
+    loop {
+        match sniff_buffer(&buffer) {
+            Sniffed::Incomplete => {}
+            Sniffed::Unknown => return Ok((buffer, None)),
+            Sniffed::Host(host) => return Ok((buffer, Some(host))),
+        }
+        if buffer.len() >= SNIFF_MAX_SIZE {
+            return Ok((buffer, None));
+        }
+
+        let start = buffer.len();
+        buffer.resize((start + 4096).min(SNIFF_MAX_SIZE), 0);
+        match time::timeout_at(deadline, stream.read(&mut buffer[start..])).await {
+            Ok(Ok(n)) if n > 0 => buffer.truncate(start + n),
+            // EOF or timed out
+            Ok(Ok(..)) | Err(..) => {
+                buffer.truncate(start);
+                return Ok((buffer, None));
+            }
+            Ok(Err(err)) => return Err(err),
+        }
+    }
+}
+
+fn sniff_buffer(buf: &[u8]) -> Sniffed {
+    match buf.first() {
+        None => Sniffed::Incomplete,
+        // TLS handshake record
+        Some(0x16) => sniff_tls(buf),
+        Some(..) => sniff_http(buf),
+    }
+}
+
+/// SNI of TLS ClientHello, which could be split into multiple records
+fn sniff_tls(mut buf: &[u8]) -> Sniffed {
+    let mut handshake = Vec::new();
+
+    while buf.len() >= 5 {
+        // ContentType handshake(22), ProtocolVersion 3.x
+        if buf[0] != 0x16 || buf[1] != 0x03 {
+            return Sniffed::Unknown;
+        }
+        let record_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+        let end = (5 + record_len).min(buf.len());
+        handshake.extend_from_slice(&buf[5..end]);
+        buf = &buf[end..];
+    }
+    if buf.len() >= 2 && handshake.is_empty() && buf[1] != 0x03 {
+        return Sniffed::Unknown;
+    }
+
+    // HandshakeType client_hello(1), uint24 length
+    if handshake.len() < 4 {
+        return Sniffed::Incomplete;
+    }
+    if handshake[0] != 0x01 {
+        return Sniffed::Unknown;
+    }
+    let hello_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+    if hello_len + 4 > SNIFF_MAX_SIZE {
+        return Sniffed::Unknown;
+    }
+    if handshake.len() < hello_len + 4 {
+        return Sniffed::Incomplete;
+    }
+
+    match parse_client_hello_sni(&handshake[4..4 + hello_len]) {
+        Some(host) => Sniffed::Host(host),
+        None => Sniffed::Unknown,
+    }
+}
+
+fn parse_client_hello_sni(mut hello: &[u8]) -> Option<String> {
+    // client_version, random
+    take(&mut hello, 2 + 32)?;
+    // legacy_session_id
+    let n = take(&mut hello, 1)?[0] as usize;
+    take(&mut hello, n)?;
+    // cipher_suites
+    let n = take_u16(&mut hello)? as usize;
+    take(&mut hello, n)?;
+    // legacy_compression_methods
+    let n = take(&mut hello, 1)?[0] as usize;
+    take(&mut hello, n)?;
+
+    let n = take_u16(&mut hello)? as usize;
+    let mut extensions = take(&mut hello, n)?;
+    while !extensions.is_empty() {
+        let ty = take_u16(&mut extensions)?;
+        let n = take_u16(&mut extensions)? as usize;
+        let mut data = take(&mut extensions, n)?;
+        // server_name
+        if ty != 0x0000 {
+            continue;
+        }
+
+        let n = take_u16(&mut data)? as usize;
+        let mut names = take(&mut data, n)?;
+        while !names.is_empty() {
+            let name_type = take(&mut names, 1)?[0];
+            let n = take_u16(&mut names)? as usize;
+            let name = take(&mut names, n)?;
+            // host_name
+            if name_type == 0x00 {
+                return to_domain_name(name);
+            }
+        }
+        return None;
+    }
+
+    None
+}
+
+/// `Host` header of HTTP/1.x request
+fn sniff_http(buf: &[u8]) -> Sniffed {
+    let is_http = HTTP_METHODS.iter().any(|m| buf.starts_with(m));
+    if !is_http {
+        if HTTP_METHODS.iter().any(|m| m.starts_with(buf)) {
+            return Sniffed::Incomplete;
+        }
+        return Sniffed::Unknown;
+    }
+
+    // Complete lines, skipping the request line
+    let mut lines = buf.split(|b| *b == b'\n').skip(1).peekable();
+    while let Some(line) = lines.next() {
+        if lines.peek().is_none() {
+            // Not terminated yet
+            break;
+        }
+
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            // End of headers
+            return Sniffed::Unknown;
+        }
+
+        let (name, value) = match line.iter().position(|b| *b == b':') {
+            Some(pos) => (&line[..pos], &line[pos + 1..]),
+            None => continue,
+        };
+        if !name.eq_ignore_ascii_case(b"host") {
+            continue;
+        }
+
+        let value = match str::from_utf8(value) {
+            Ok(v) => v.trim(),
+            Err(..) => return Sniffed::Unknown,
+        };
+        // Port is stripped, IP literals (including IPv6 in brackets) are not domain names
+        let host = match value.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() => host,
+            _ => value,
+        };
+        return match to_domain_name(host.as_bytes()) {
+            Some(host) => Sniffed::Host(host),
+            None => Sniffed::Unknown,
+        };
+    }
+
+    if buf.len() >= SNIFF_MAX_SIZE {
+        Sniffed::Unknown
+    } else {
+        Sniffed::Incomplete
+    }
+}
+
+fn to_domain_name(name: &[u8]) -> Option<String> {
+    let name = str::from_utf8(name).ok()?.trim_end_matches('.');
+    if name.is_empty()
+        || name.len() > 253
+        || !name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_')
+        || name.parse::<IpAddr>().is_ok()
+    {
+        return None;
+    }
+    Some(name.to_ascii_lowercase())
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if buf.len() < n {
+        return None;
+    }
+    let (head, tail) = buf.split_at(n);
+    *buf = tail;
+    Some(head)
+}
+
+fn take_u16(buf: &mut &[u8]) -> Option<u16> {
+    take(buf, 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// TLS record of a ClientHello with extension `server_name` of `host`, if any
+    fn client_hello(host: Option<&str>) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        // supported_versions, before server_name
+        extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        if let Some(host) = host {
+            let name_len = host.len() as u16;
+            extensions.extend_from_slice(&[0x00, 0x00]);
+            extensions.extend_from_slice(&(name_len + 5).to_be_bytes());
+            extensions.extend_from_slice(&(name_len + 3).to_be_bytes());
+            extensions.push(0x00);
+            extensions.extend_from_slice(&name_len.to_be_bytes());
+            extensions.extend_from_slice(host.as_bytes());
+        }
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0u8; 32]);
+        // legacy_session_id, cipher_suites, legacy_compression_methods
+        hello.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut handshake = vec![0x01];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    fn sniffed_host(buf: &[u8]) -> Option<String> {
+        match sniff_buffer(buf) {
+            Sniffed::Host(host) => Some(host),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_sniff_tls_sni() {
+        let record = client_hello(Some("Example.COM."));
+        assert_eq!(sniffed_host(&record).as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn test_sniff_tls_incomplete() {
+        let record = client_hello(Some("example.com"));
+        for len in [0, 1, 5, 8, record.len() - 1] {
+            assert!(
+                matches!(sniff_buffer(&record[..len]), Sniffed::Incomplete),
+                "length {len}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_sniff_tls_fragmented_records() {
+        let record = client_hello(Some("example.com"));
+        let handshake = &record[5..];
+        let (first, second) = handshake.split_at(10);
+
+        let mut buf = Vec::new();
+        for part in [first, second] {
+            buf.extend_from_slice(&[0x16, 0x03, 0x01]);
+            buf.extend_from_slice(&(part.len() as u16).to_be_bytes());
+            buf.extend_from_slice(part);
+        }
+        assert_eq!(sniffed_host(&buf).as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn test_sniff_tls_without_sni() {
+        assert!(matches!(sniff_buffer(&client_hello(None)), Sniffed::Unknown));
+        // IP literals are not domain names
+        assert!(matches!(
+            sniff_buffer(&client_hello(Some("192.168.1.1"))),
+            Sniffed::Unknown
+        ));
+        assert!(matches!(
+            sniff_buffer(&client_hello(Some("bad host"))),
+            Sniffed::Unknown
+        ));
+    }
+
+    #[test]
+    fn test_sniff_tls_malformed() {
+        // Not a handshake of TLS 1.x
+        assert!(matches!(
+            sniff_buffer(&[0x16, 0x02, 0x00, 0x00, 0x01, 0x01]),
+            Sniffed::Unknown
+        ));
+        // ServerHello
+        let mut record = client_hello(Some("example.com"));
+        record[5] = 0x02;
+        assert!(matches!(sniff_buffer(&record), Sniffed::Unknown));
+        // Length of extensions exceeds the ClientHello
+        let mut record = client_hello(Some("example.com"));
+        let at = 5 + 4 + 2 + 32 + 7;
+        record[at] = 0xff;
+        assert!(matches!(sniff_buffer(&record), Sniffed::Unknown));
+        // ClientHello larger than sniffing buffers
+        let mut record = vec![0x16, 0x03, 0x01, 0x00, 0x04, 0x01, 0xff, 0xff, 0xff];
+        record.resize(64, 0);
+        assert!(matches!(sniff_buffer(&record), Sniffed::Unknown));
+    }
+
+    #[test]
+    fn test_sniff_http_host() {
+        let buf = b"GET / HTTP/1.1\r\nUser-Agent: test\r\nhost: Example.com:8080\r\n\r\n";
+        assert_eq!(sniffed_host(buf).as_deref(), Some("example.com"));
+        let buf = b"POST /a HTTP/1.1\nHost: example.com\n\n";
+        assert_eq!(sniffed_host(buf).as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn test_sniff_http_incomplete() {
+        assert!(matches!(sniff_buffer(b"GE"), Sniffed::Incomplete));
+        assert!(matches!(sniff_buffer(b"GET / HTTP/1.1\r\n"), Sniffed::Incomplete));
+        // Host line is not terminated yet
+        assert!(matches!(
+            sniff_buffer(b"GET / HTTP/1.1\r\nHost: example.c"),
+            Sniffed::Incomplete
+        ));
+    }
+
+    #[test]
+    fn test_sniff_http_unknown() {
+        assert!(matches!(sniff_buffer(b"SSH-2.0-OpenSSH\r\n"), Sniffed::Unknown));
+        assert!(matches!(sniff_buffer(b"GET / HTTP/1.1\r\n\r\n"), Sniffed::Unknown));
+        assert!(matches!(
+            sniff_buffer(b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n"),
+            Sniffed::Unknown
+        ));
+        assert!(matches!(
+            sniff_buffer(b"GET / HTTP/1.1\r\nHost: 10.0.0.1\r\n\r\n"),
+            Sniffed::Unknown
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sniff_host_stream() {
+        let record = client_hello(Some("example.com"));
+        let mut stream = &record[..];
+        let (buf, host) = sniff_host(&mut stream, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(buf, record);
+        assert_eq!(host.as_deref(), Some("example.com"));
+
+        // Closed before a domain name is found, bytes read are returned
+        let mut stream = &b"GET / HTTP/1.1\r\n"[..];
+        let (buf, host) = sniff_host(&mut stream, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(buf, b"GET / HTTP/1.1\r\n");
+        assert!(host.is_none());
+    }
+}
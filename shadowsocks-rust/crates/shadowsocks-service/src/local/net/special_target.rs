@@ -0,0 +1,167 @@
+//! Special targets requested by clients
+//!
+//! Unspecified, loopback, multicast and broadcast addresses are not real destinations on the Internet. Where they
+//! go depends on the host that connects them, so they are handled by `SpecialTargetPolicy` consistently in TCP
+//! and UDP relays, instead of the ACL.
+
+use std::{
+    error,
+    fmt::{self, Display},
+    io,
+    net::IpAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use shadowsocks::relay::Address;
+
+use crate::config::SpecialTargetPolicy;
+
+/// Kind of special target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialTarget {
+    /// `0.0.0.0` or `::`
+    Unspecified,
+    /// `127.0.0.0/8` or `::1`
+    Loopback,
+    /// `224.0.0.0/4` or `ff00::/8`
+    Multicast,
+    /// `255.255.255.255`
+    Broadcast,
+}
+
+impl SpecialTarget {
+    const COUNT: usize = 4;
+
+    /// All kinds of special targets
+    pub const ALL: [SpecialTarget; SpecialTarget::COUNT] = [
+        SpecialTarget::Unspecified,
+        SpecialTarget::Loopback,
+        SpecialTarget::Multicast,
+        SpecialTarget::Broadcast,
+    ];
+
+    /// Classify target `addr`, `None` if it is not special. Domain names are never special
+    pub fn classify(addr: &Address) -> Option<SpecialTarget> {
+        let ip = match *addr {
+            Address::SocketAddress(ref sa) => sa.ip(),
+            Address::DomainNameAddress(..) => return None,
+        };
+        // IPv4-mapped IPv6 addresses are classified as IPv4
+        let ip = match ip {
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => IpAddr::V4(v4),
+                None => ip,
+            },
+            IpAddr::V4(..) => ip,
+        };
+
+        if ip.is_unspecified() {
+            Some(SpecialTarget::Unspecified)
+        } else if ip.is_loopback() {
+            Some(SpecialTarget::Loopback)
+        } else if ip.is_multicast() {
+            Some(SpecialTarget::Multicast)
+        } else if matches!(ip, IpAddr::V4(v4) if v4.is_broadcast()) {
+            Some(SpecialTarget::Broadcast)
+        } else {
+            None
+        }
+    }
+
+    /// Name of the kind
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            SpecialTarget::Unspecified => "unspecified",
+            SpecialTarget::Loopback => "loopback",
+            SpecialTarget::Multicast => "multicast",
+            SpecialTarget::Broadcast => "broadcast",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+impl Display for SpecialTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Counters of special targets handled by the policy
+#[derive(Debug, Default)]
+pub struct SpecialTargetStat {
+    tcp: [AtomicUsize; SpecialTarget::COUNT],
+    udp: [AtomicUsize; SpecialTarget::COUNT],
+}
+
+impl SpecialTargetStat {
+    /// Create an empty statistic
+    pub fn new() -> SpecialTargetStat {
+        SpecialTargetStat::default()
+    }
+
+    /// Count a TCP connection to `target`
+    pub fn incr_tcp(&self, target: SpecialTarget) {
+        self.tcp[target.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an UDP packet to `target`
+    pub fn incr_udp(&self, target: SpecialTarget) {
+        self.udp[target.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// TCP connections to `target`
+    pub fn tcp(&self, target: SpecialTarget) -> usize {
+        self.tcp[target.index()].load(Ordering::Relaxed)
+    }
+
+    /// UDP packets to `target`
+    pub fn udp(&self, target: SpecialTarget) -> usize {
+        self.udp[target.index()].load(Ordering::Relaxed)
+    }
+}
+
+/// Connection to a special target is rejected or dropped by the policy
+///
+/// Carried in an `io::Error` of `ErrorKind::PermissionDenied`
+#[derive(Debug)]
+pub struct SpecialTargetError {
+    target: SpecialTarget,
+    addr: String,
+    dropped: bool,
+}
+
+impl SpecialTargetError {
+    /// Create an `io::Error` for connecting `addr`, a `target`, that is handled by `policy`
+    pub fn new_io_error(target: SpecialTarget, addr: &Address, policy: SpecialTargetPolicy) -> io::Error {
+        let err = SpecialTargetError {
+            target,
+            addr: addr.to_string(),
+            dropped: policy == SpecialTargetPolicy::Drop,
+        };
+        io::Error::new(io::ErrorKind::PermissionDenied, err)
+    }
+
+    /// Check if `err` is a dropped connection, which shouldn't be responded to clients
+    pub fn is_dropped(err: &io::Error) -> bool {
+        err.get_ref()
+            .and_then(|e| e.downcast_ref::<SpecialTargetError>())
+            .is_some_and(|e| e.dropped)
+    }
+}
+
+impl Display for SpecialTargetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} target {} {}",
+            self.target,
+            self.addr,
+            if self.dropped { "dropped" } else { "rejected" }
+        )
+    }
+}
+
+impl error::Error for SpecialTargetError {}
@@ -8,6 +8,7 @@ use std::{
     task::{self, Poll},
 };
 
+use futures::ready;
 use pin_project::pin_project;
 use shadowsocks::{
     net::{ConnectOpts, TcpStream},
@@ -16,7 +17,14 @@ use shadowsocks::{
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 use crate::{
-    local::{context::ServiceContext, loadbalancing::ServerIdent},
+    acl::AccessControl,
+    config::SpecialTargetPolicy,
+    local::{
+        context::ServiceContext,
+        history::SessionRecorder,
+        loadbalancing::{ServerIdent, ServerSessionGuard},
+        net::{mirror::FlowMirror, qos::FlowShaper, special_target::SpecialTargetError},
+    },
     net::MonProxyStream,
 };
 
@@ -26,8 +34,14 @@ use super::auto_proxy_io::AutoProxyIo;
 #[allow(clippy::large_enum_variant)]
 #[pin_project(project = AutoProxyClientStreamProj)]
 pub enum AutoProxyClientStream {
-    Proxied(#[pin] ProxyClientStream<MonProxyStream<TcpStream>>),
-    Bypassed(#[pin] TcpStream),
+    Proxied(
+        #[pin] ProxyClientStream<MonProxyStream<TcpStream>>,
+        ServerSessionGuard,
+        Option<FlowMirror>,
+        Option<FlowShaper>,
+        Option<SessionRecorder>,
+    ),
+    Bypassed(#[pin] TcpStream, Option<FlowMirror>, Option<SessionRecorder>),
 }
 
 impl AutoProxyClientStream {
@@ -50,11 +64,102 @@ impl AutoProxyClientStream {
         addr: A,
         opts: &ConnectOpts,
     ) -> io::Result<AutoProxyClientStream>
+    where
+        A: Into<Address>,
+    {
+        AutoProxyClientStream::connect_with_acl_opts(context, server, addr, None, opts).await
+    }
+
+    /// Connect to target `addr` via shadowsocks' server configured by `svr_cfg`,
+    /// bypassed or proxied by `acl` instead of the ACL of `context` if it is set
+    pub async fn connect_with_acl_opts<A>(
+        context: Arc<ServiceContext>,
+        server: &ServerIdent,
+        addr: A,
+        acl: Option<&AccessControl>,
+        opts: &ConnectOpts,
+    ) -> io::Result<AutoProxyClientStream>
+    where
+        A: Into<Address>,
+    {
+        #[cfg_attr(not(feature = "local-fake-dns"), allow(unused_mut))]
+        let mut addr = addr.into();
+        // Domain rules apply to fake addresses
+        #[cfg(feature = "local-fake-dns")]
+        if let Some(mapped_addr) = context.try_map_fake_address(&addr).await {
+            addr = mapped_addr;
+        }
+        match context.check_special_target(&addr, false) {
+            None => {}
+            Some((_, SpecialTargetPolicy::Proxy)) => {
+                return AutoProxyClientStream::connect_proxied_with_opts(context, server, addr, opts).await;
+            }
+            Some((target, policy)) => return Err(SpecialTargetError::new_io_error(target, &addr, policy)),
+        }
+
+        let bypassed = match acl {
+            Some(acl) => acl.check_target_bypassed(context.context_ref(), &addr).await,
+            None => context.check_target_bypassed(&addr).await,
+        };
+        if bypassed {
+            AutoProxyClientStream::connect_bypassed_with_opts(context, addr, opts).await
+        } else {
+            AutoProxyClientStream::connect_proxied_with_opts(context, server, addr, opts).await
+        }
+    }
+
+    /// Connect to target `addr` via shadowsocks' server configured by `svr_cfg`,
+    /// bypassed by ACL rules of domain name `host` sniffed from the client before rules of `addr`
+    pub async fn connect_sniffed_with_opts<A>(
+        context: Arc<ServiceContext>,
+        server: &ServerIdent,
+        addr: A,
+        host: &str,
+        opts: &ConnectOpts,
+    ) -> io::Result<AutoProxyClientStream>
+    where
+        A: Into<Address>,
+    {
+        let addr = addr.into();
+        match context.check_special_target(&addr, false) {
+            None => {}
+            Some((_, SpecialTargetPolicy::Proxy)) => {
+                return AutoProxyClientStream::connect_proxied_with_opts(context, server, addr, opts).await;
+            }
+            Some((target, policy)) => return Err(SpecialTargetError::new_io_error(target, &addr, policy)),
+        }
+
+        if context.check_sniffed_target_bypassed(host, &addr).await {
+            AutoProxyClientStream::connect_bypassed_with_opts(context, addr, opts).await
+        } else {
+            AutoProxyClientStream::connect_proxied_with_opts(context, server, addr, opts).await
+        }
+    }
+
+    /// Connect to target `addr` via shadowsocks' server configured by `svr_cfg`,
+    /// or directly if `bypassed` was decided before ACL rules of `addr`, like by process rules
+    ///
+    /// Special targets are still handled by the policy
+    pub async fn connect_decided_with_opts<A>(
+        context: Arc<ServiceContext>,
+        server: &ServerIdent,
+        addr: A,
+        bypassed: bool,
+        opts: &ConnectOpts,
+    ) -> io::Result<AutoProxyClientStream>
     where
         A: Into<Address>,
     {
         let addr = addr.into();
-        if context.check_target_bypassed(&addr).await {
+        match context.check_special_target(&addr, false) {
+            None => {}
+            Some((_, SpecialTargetPolicy::Proxy)) => {
+                return AutoProxyClientStream::connect_proxied_with_opts(context, server, addr, opts).await;
+            }
+            Some((target, policy)) => return Err(SpecialTargetError::new_io_error(target, &addr, policy)),
+        }
+
+        if bypassed {
             AutoProxyClientStream::connect_bypassed_with_opts(context, addr, opts).await
         } else {
             AutoProxyClientStream::connect_proxied_with_opts(context, server, addr, opts).await
@@ -62,10 +167,18 @@ impl AutoProxyClientStream {
     }
 
     /// Connect directly to target `addr`
+    ///
+    /// Special targets are still rejected or dropped by the policy, there is no server to proxy them
     pub async fn connect_bypassed<A>(context: Arc<ServiceContext>, addr: A) -> io::Result<AutoProxyClientStream>
     where
         A: Into<Address>,
     {
+        let addr = addr.into();
+        match context.check_special_target(&addr, false) {
+            None | Some((_, SpecialTargetPolicy::Proxy)) => {}
+            Some((target, policy)) => return Err(SpecialTargetError::new_io_error(target, &addr, policy)),
+        }
+
         AutoProxyClientStream::connect_bypassed_with_opts(context.clone(), addr, context.connect_opts_ref()).await
     }
 
@@ -85,8 +198,23 @@ impl AutoProxyClientStream {
         if let Some(mapped_addr) = context.try_map_fake_address(&addr).await {
             addr = mapped_addr;
         }
-        let stream = TcpStream::connect_remote_with_opts(context.context_ref(), &addr, connect_opts).await?;
-        Ok(AutoProxyClientStream::Bypassed(stream))
+        let connect_opts = context.clamp_tcp_mss(connect_opts);
+        let uplink = context.uplinks().and_then(|u| u.select_for(&connect_opts));
+        let connect_opts = uplink.as_ref().map_or(&*connect_opts, |(_, opts)| opts);
+        let result = TcpStream::connect_remote_with_opts(context.context_ref(), &addr, connect_opts).await;
+        if let Some((ref uplink, ..)) = uplink {
+            uplink.report_connect(result.is_ok());
+        }
+        let stream = result?;
+        if let Some(nodelay) = context.target_nodelay(&addr) {
+            stream.set_nodelay(nodelay)?;
+        }
+        let mirror = match stream.local_addr() {
+            Ok(local_addr) => context.start_tcp_mirror(local_addr, &addr),
+            Err(..) => None,
+        };
+        let session = context.start_tcp_session(&addr, None);
+        Ok(AutoProxyClientStream::Bypassed(stream, mirror, session))
     }
 
     /// Connect to target `addr` via shadowsocks' server configured by `svr_cfg`
@@ -118,36 +246,66 @@ impl AutoProxyClientStream {
         if let Some(mapped_addr) = context.try_map_fake_address(&addr).await {
             addr = mapped_addr;
         }
-        let flow_stat = context.flow_stat();
-        let stream = match ProxyClientStream::connect_with_opts_map(
+        if !server.server_config().mode().enable_tcp() {
+            return Err(io::Error::other("no server in balancer serves TCP"));
+        }
+
+        let connect_opts = context.clamp_tcp_mss(connect_opts);
+        let uplink = context.uplinks().and_then(|u| u.select_for(&connect_opts));
+        let connect_opts = uplink.as_ref().map_or(&*connect_opts, |(_, opts)| opts);
+        let flow_stat = server.flow_stat();
+        let result = ProxyClientStream::connect_with_opts_map(
             context.context(),
             server.server_config(),
-            addr,
+            addr.clone(),
             connect_opts,
             |stream| MonProxyStream::from_stream(stream, flow_stat),
         )
-        .await
-        {
+        .await;
+        if let Some((ref uplink, ..)) = uplink {
+            uplink.report_connect(result.is_ok());
+        }
+        let stream = match result {
             Ok(s) => s,
             Err(err) => {
                 server.tcp_score().report_failure().await;
                 return Err(err);
             }
         };
-        Ok(AutoProxyClientStream::Proxied(stream))
+        if let Some(nodelay) = context.target_nodelay(&addr) {
+            stream.get_ref().get_ref().set_nodelay(nodelay)?;
+        }
+        let mirror = match stream.get_ref().get_ref().local_addr() {
+            Ok(local_addr) => context.start_tcp_mirror(local_addr, &addr),
+            Err(..) => None,
+        };
+        Ok(AutoProxyClientStream::Proxied(
+            stream,
+            server.tcp_score().start_session(),
+            mirror,
+            context.start_tcp_shaping(),
+            context.start_tcp_session(&addr, Some(server.server_config().addr())),
+        ))
     }
 
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         match *self {
-            AutoProxyClientStream::Proxied(ref s) => s.get_ref().get_ref().local_addr(),
-            AutoProxyClientStream::Bypassed(ref s) => s.local_addr(),
+            AutoProxyClientStream::Proxied(ref s, ..) => s.get_ref().get_ref().local_addr(),
+            AutoProxyClientStream::Bypassed(ref s, ..) => s.local_addr(),
         }
     }
 
     pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
         match *self {
-            AutoProxyClientStream::Proxied(ref s) => s.get_ref().get_ref().set_nodelay(nodelay),
-            AutoProxyClientStream::Bypassed(ref s) => s.set_nodelay(nodelay),
+            AutoProxyClientStream::Proxied(ref s, ..) => s.get_ref().get_ref().set_nodelay(nodelay),
+            AutoProxyClientStream::Bypassed(ref s, ..) => s.set_nodelay(nodelay),
+        }
+    }
+
+    pub fn set_tos(&self, tos: u8) -> io::Result<()> {
+        match *self {
+            AutoProxyClientStream::Proxied(ref s, ..) => s.get_ref().get_ref().set_tos(tos),
+            AutoProxyClientStream::Bypassed(ref s, ..) => s.set_tos(tos),
         }
     }
 }
@@ -160,32 +318,71 @@ impl AutoProxyIo for AutoProxyClientStream {
 
 impl AsyncRead for AutoProxyClientStream {
     fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
-        match self.project() {
-            AutoProxyClientStreamProj::Proxied(s) => s.poll_read(cx, buf),
-            AutoProxyClientStreamProj::Bypassed(s) => s.poll_read(cx, buf),
+        let filled = buf.filled().len();
+        let (result, mirror, session) = match self.project() {
+            AutoProxyClientStreamProj::Proxied(s, _, mirror, shaper, session) => match shaper {
+                Some(shaper) => {
+                    ready!(shaper.poll_download_ready(cx));
+                    let result = s.poll_read(cx, buf);
+                    if let Poll::Ready(Ok(())) = result {
+                        shaper.downloaded(buf.filled().len() - filled);
+                    }
+                    (result, mirror, session)
+                }
+                None => (s.poll_read(cx, buf), mirror, session),
+            },
+            AutoProxyClientStreamProj::Bypassed(s, mirror, session) => (s.poll_read(cx, buf), mirror, session),
+        };
+        if let Poll::Ready(Ok(())) = result {
+            if let Some(mirror) = mirror {
+                mirror.remote_to_local(&buf.filled()[filled..]);
+            }
+            if let Some(session) = session {
+                session.downloaded(buf.filled().len() - filled);
+            }
         }
+        result
     }
 }
 
 impl AsyncWrite for AutoProxyClientStream {
     fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
-        match self.project() {
-            AutoProxyClientStreamProj::Proxied(s) => s.poll_write(cx, buf),
-            AutoProxyClientStreamProj::Bypassed(s) => s.poll_write(cx, buf),
+        let (result, mirror, session) = match self.project() {
+            AutoProxyClientStreamProj::Proxied(s, _, mirror, shaper, session) => match shaper {
+                Some(shaper) => {
+                    ready!(shaper.poll_upload_ready(cx));
+                    let result = s.poll_write(cx, buf);
+                    if let Poll::Ready(Ok(n)) = result {
+                        shaper.uploaded(n);
+                    }
+                    (result, mirror, session)
+                }
+                None => (s.poll_write(cx, buf), mirror, session),
+            },
+            AutoProxyClientStreamProj::Bypassed(s, mirror, session) => (s.poll_write(cx, buf), mirror, session),
+        };
+        if let Poll::Ready(Ok(n)) = result {
+            if let Some(mirror) = mirror {
+                mirror.local_to_remote(&buf[..n]);
+            }
+            if let Some(session) = session {
+                session.uploaded(n);
+            }
         }
+        result
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
         match self.project() {
-            AutoProxyClientStreamProj::Proxied(s) => s.poll_flush(cx),
-            AutoProxyClientStreamProj::Bypassed(s) => s.poll_flush(cx),
+            AutoProxyClientStreamProj::Proxied(s, ..) => s.poll_flush(cx),
+            AutoProxyClientStreamProj::Bypassed(s, ..) => s.poll_flush(cx),
         }
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
         match self.project() {
-            AutoProxyClientStreamProj::Proxied(s) => s.poll_shutdown(cx),
-            AutoProxyClientStreamProj::Bypassed(s) => s.poll_shutdown(cx),
+            AutoProxyClientStreamProj::Proxied(s, ..) => s.poll_shutdown(cx),
+            AutoProxyClientStreamProj::Bypassed(s, ..) => s.poll_shutdown(cx),
         }
     }
 
@@ -194,15 +391,36 @@ impl AsyncWrite for AutoProxyClientStream {
         cx: &mut task::Context<'_>,
         bufs: &[IoSlice<'_>],
     ) -> Poll<io::Result<usize>> {
-        match self.project() {
-            AutoProxyClientStreamProj::Proxied(s) => s.poll_write_vectored(cx, bufs),
-            AutoProxyClientStreamProj::Bypassed(s) => s.poll_write_vectored(cx, bufs),
+        let (result, mirror, session) = match self.project() {
+            AutoProxyClientStreamProj::Proxied(s, _, mirror, shaper, session) => match shaper {
+                Some(shaper) => {
+                    ready!(shaper.poll_upload_ready(cx));
+                    let result = s.poll_write_vectored(cx, bufs);
+                    if let Poll::Ready(Ok(n)) = result {
+                        shaper.uploaded(n);
+                    }
+                    (result, mirror, session)
+                }
+                None => (s.poll_write_vectored(cx, bufs), mirror, session),
+            },
+            AutoProxyClientStreamProj::Bypassed(s, mirror, session) => {
+                (s.poll_write_vectored(cx, bufs), mirror, session)
+            }
+        };
+        if let (Poll::Ready(Ok(n)), Some(session)) = (&result, session) {
+            session.uploaded(*n);
         }
-    }
-}
-
-impl From<ProxyClientStream<MonProxyStream<TcpStream>>> for AutoProxyClientStream {
-    fn from(s: ProxyClientStream<MonProxyStream<TcpStream>>) -> Self {
-        AutoProxyClientStream::Proxied(s)
+        if let (Poll::Ready(Ok(n)), Some(mirror)) = (&result, mirror) {
+            let mut remaining = *n;
+            for buf in bufs {
+                if remaining == 0 {
+                    break;
+                }
+                let len = remaining.min(buf.len());
+                mirror.local_to_remote(&buf[..len]);
+                remaining -= len;
+            }
+        }
+        result
     }
 }
@@ -1,3 +1,62 @@
 pub mod auto_proxy_io;
 pub mod auto_proxy_stream;
 pub mod listener;
+
+use std::{io, net::SocketAddr};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, BufReader},
+    net::TcpStream,
+};
+
+use super::ipc::IpcStream;
+
+/// Stream of a client accepted by local servers, plain TCP, IPC or TLS
+pub trait LocalStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    /// Set `TCP_NODELAY` of the underlying TCP connection
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()>;
+
+    /// Local address of the underlying TCP connection
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl LocalStream for TcpStream {
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+}
+
+impl LocalStream for IpcStream {
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl<S: LocalStream> LocalStream for BufReader<S> {
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.get_ref().set_nodelay(nodelay)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().local_addr()
+    }
+}
+
+#[cfg(feature = "local-tls")]
+impl<S: LocalStream> LocalStream for tokio_rustls::server::TlsStream<S> {
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.get_ref().0.set_nodelay(nodelay)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.local_addr()
+    }
+}
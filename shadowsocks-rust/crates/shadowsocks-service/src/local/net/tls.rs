@@ -0,0 +1,81 @@
+//! TLS of local listeners
+
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio_rustls::{
+    rustls::{server::WebPkiClientVerifier, RootCertStore, ServerConfig},
+    TlsAcceptor,
+};
+
+/// TLS configuration of a local listener
+#[derive(Debug, Clone)]
+pub struct TlsListenerConfig {
+    /// Certificate chain in PEM
+    pub cert: PathBuf,
+    /// Private key in PEM
+    pub key: PathBuf,
+    /// CA certificates in PEM for verifying client certificates, clients without a valid certificate are rejected
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsListenerConfig {
+    /// Load certificates and create an acceptor
+    pub fn load_acceptor(&self) -> io::Result<TlsAcceptor> {
+        load_tls_acceptor(&self.cert, &self.key, self.client_ca.as_deref())
+    }
+
+    /// Load certificates, for listeners that have to customize the configuration, like ALPN
+    pub fn load_server_config(&self) -> io::Result<ServerConfig> {
+        load_tls_server_config(&self.cert, &self.key, self.client_ca.as_deref())
+    }
+}
+
+/// Create a TLS acceptor with certificate `cert` and private key `key`,
+/// clients are required to present certificates issued by `client_ca` if it is set
+pub fn load_tls_acceptor(cert: &Path, key: &Path, client_ca: Option<&Path>) -> io::Result<TlsAcceptor> {
+    let config = load_tls_server_config(cert, key, client_ca)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Create a TLS server configuration, like [`load_tls_acceptor`]
+pub fn load_tls_server_config(cert: &Path, key: &Path, client_ca: Option<&Path>) -> io::Result<ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?)).collect::<Result<Vec<_>, _>>()?;
+    let private_key = match rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))? {
+        Some(k) => k,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no private key in {}", key.display()),
+            ));
+        }
+    };
+
+    let builder = ServerConfig::builder();
+    let builder = match client_ca {
+        None => builder.with_no_client_auth(),
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(client_ca)?)) {
+                roots.add(cert?).map_err(io::Error::other)?;
+            }
+            if roots.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("no certificate in {}", client_ca.display()),
+                ));
+            }
+
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(io::Error::other)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+    };
+
+    builder.with_single_cert(certs, private_key).map_err(io::Error::other)
+}
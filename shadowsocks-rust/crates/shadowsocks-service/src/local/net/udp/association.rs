@@ -2,7 +2,7 @@
 
 use std::{
     cell::RefCell,
-    io::{self, ErrorKind},
+    io::{self},
     marker::PhantomData,
     net::{SocketAddr, SocketAddrV6},
     sync::Arc,
@@ -27,7 +27,11 @@ use shadowsocks::{
 };
 
 use crate::{
-    local::{context::ServiceContext, loadbalancing::PingBalancer},
+    config::{SpecialTargetPolicy, UdpNatType},
+    local::{
+        context::ServiceContext,
+        loadbalancing::{PingBalancer, ServerSessionGuard},
+    },
     net::{
         packet_window::PacketWindowFilter, MonProxySocket, UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE,
         UDP_ASSOCIATION_SEND_CHANNEL_SIZE,
@@ -96,8 +100,21 @@ where
     }
 
     /// Sends `data` from `peer_addr` to `target_addr`
+    pub async fn send_to(&mut self, peer_addr: SocketAddr, target_addr: Address, data: &[u8]) -> io::Result<()> {
+        self.send_to_with_tos(peer_addr, target_addr, data, None).await
+    }
+
+    /// Sends `data` from `peer_addr` to `target_addr`
+    ///
+    /// `tos` is applied to outbound sockets of the association if it is created by this packet.
     #[cfg_attr(not(feature = "local-fake-dns"), allow(unused_mut))]
-    pub async fn send_to(&mut self, peer_addr: SocketAddr, mut target_addr: Address, data: &[u8]) -> io::Result<()> {
+    pub async fn send_to_with_tos(
+        &mut self,
+        peer_addr: SocketAddr,
+        mut target_addr: Address,
+        data: &[u8],
+        tos: Option<u8>,
+    ) -> io::Result<()> {
         #[cfg(feature = "local-fake-dns")]
         if let Some(mapped_addr) = self.context.try_map_fake_address(&target_addr).await {
             target_addr = mapped_addr;
@@ -116,6 +133,8 @@ where
             self.balancer.clone(),
             self.respond_writer.clone(),
             self.server_session_expire_duration,
+            tos,
+            false,
         );
 
         debug!("created udp association for {}", peer_addr);
@@ -126,6 +145,31 @@ where
         Ok(())
     }
 
+    /// Create an association for `peer_addr` before its first packet arrives
+    ///
+    /// The proxied socket to the best UDP server is connected immediately,
+    /// so the first packet won't wait for connecting to the server.
+    pub async fn prebind(&mut self, peer_addr: SocketAddr) {
+        if self.assoc_map.get(&peer_addr).is_some() {
+            return;
+        }
+
+        let assoc = UdpAssociation::new(
+            self.context.clone(),
+            peer_addr,
+            self.keepalive_tx.clone(),
+            self.balancer.clone(),
+            self.respond_writer.clone(),
+            self.server_session_expire_duration,
+            None,
+            true,
+        );
+
+        debug!("created udp association for {} (prebind)", peer_addr);
+
+        self.assoc_map.insert(peer_addr, assoc);
+    }
+
     /// Cleanup expired associations
     pub async fn cleanup_expired(&mut self) {
         self.assoc_map.iter();
@@ -159,6 +203,7 @@ impl<W> UdpAssociation<W>
 where
     W: UdpInboundWrite + Send + Sync + Unpin + 'static,
 {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         context: Arc<ServiceContext>,
         peer_addr: SocketAddr,
@@ -166,6 +211,8 @@ where
         balancer: PingBalancer,
         respond_writer: W,
         server_session_expire_duration: Duration,
+        tos: Option<u8>,
+        prebind: bool,
     ) -> UdpAssociation<W> {
         let (assoc_handle, sender) = UdpAssociationContext::create(
             context,
@@ -174,6 +221,8 @@ where
             balancer,
             respond_writer,
             server_session_expire_duration,
+            tos,
+            prebind,
         );
         UdpAssociation {
             assoc_handle,
@@ -184,7 +233,7 @@ where
 
     fn try_send(&self, data: (Address, Bytes)) -> io::Result<()> {
         if self.sender.try_send(data).is_err() {
-            let err = io::Error::new(ErrorKind::Other, "udp relay channel full");
+            let err = io::Error::other("udp relay channel full");
             return Err(err);
         }
         Ok(())
@@ -196,6 +245,39 @@ struct ServerContext {
     packet_window_filter: PacketWindowFilter,
 }
 
+/// Targets that a client has sent to, the only ones allowed to respond with `UdpNatType::Symmetric`
+struct SymmetricNatFilter {
+    targets: LruCache<Address, ()>,
+    // Responses of domain name targets are from resolved addresses, which could only be matched by ports
+    domain_ports: LruCache<u16, ()>,
+}
+
+impl SymmetricNatFilter {
+    fn new(expire_duration: Duration) -> SymmetricNatFilter {
+        SymmetricNatFilter {
+            targets: LruCache::with_expiry_duration(expire_duration),
+            domain_ports: LruCache::with_expiry_duration(expire_duration),
+        }
+    }
+
+    fn add_target(&mut self, target_addr: &Address) {
+        if let Address::DomainNameAddress(_, port) = *target_addr {
+            self.domain_ports.insert(port, ());
+        }
+        self.targets.insert(target_addr.clone(), ());
+    }
+
+    fn is_allowed(&mut self, addr: &Address) -> bool {
+        if self.targets.get(addr).is_some() {
+            return true;
+        }
+        match *addr {
+            Address::SocketAddress(sa) => self.domain_ports.get(&sa.port()).is_some(),
+            Address::DomainNameAddress(..) => false,
+        }
+    }
+}
+
 #[derive(Clone)]
 struct ServerSessionContext {
     server_session_map: LruCache<u64, ServerContext>,
@@ -218,6 +300,7 @@ where
     bypassed_ipv4_socket: Option<ShadowUdpSocket>,
     bypassed_ipv6_socket: Option<ShadowUdpSocket>,
     proxied_socket: Option<MonProxySocket>,
+    proxied_session: Option<ServerSessionGuard>,
     keepalive_tx: mpsc::Sender<SocketAddr>,
     keepalive_flag: bool,
     balancer: PingBalancer,
@@ -226,6 +309,8 @@ where
     client_packet_id: u64,
     server_session: Option<ServerSessionContext>,
     server_session_expire_duration: Duration,
+    tos: Option<u8>,
+    nat_filter: Option<SymmetricNatFilter>,
 }
 
 impl<W> Drop for UdpAssociationContext<W>
@@ -251,6 +336,7 @@ impl<W> UdpAssociationContext<W>
 where
     W: UdpInboundWrite + Send + Sync + Unpin + 'static,
 {
+    #[allow(clippy::too_many_arguments)]
     fn create(
         context: Arc<ServiceContext>,
         peer_addr: SocketAddr,
@@ -258,18 +344,26 @@ where
         balancer: PingBalancer,
         respond_writer: W,
         server_session_expire_duration: Duration,
+        tos: Option<u8>,
+        prebind: bool,
     ) -> (JoinHandle<()>, mpsc::Sender<(Address, Bytes)>) {
         // Pending packets UDP_ASSOCIATION_SEND_CHANNEL_SIZE for each association should be good enough for a server.
         // If there are plenty of packets stuck in the channel, dropping excessive packets is a good way to protect the server from
         // being OOM.
         let (sender, receiver) = mpsc::channel(UDP_ASSOCIATION_SEND_CHANNEL_SIZE);
 
+        let nat_filter = match context.udp_nat() {
+            UdpNatType::FullCone => None,
+            UdpNatType::Symmetric => Some(SymmetricNatFilter::new(server_session_expire_duration)),
+        };
+
         let mut assoc = UdpAssociationContext {
             context,
             peer_addr,
             bypassed_ipv4_socket: None,
             bypassed_ipv6_socket: None,
             proxied_socket: None,
+            proxied_session: None,
             keepalive_tx,
             keepalive_flag: false,
             balancer,
@@ -280,8 +374,15 @@ where
             client_packet_id: 0,
             server_session: None,
             server_session_expire_duration,
+            tos,
+            nat_filter,
         };
-        let handle = tokio::spawn(async move { assoc.dispatch_packet(receiver).await });
+        let handle = tokio::spawn(async move {
+            if prebind {
+                assoc.prebind_proxied_socket().await;
+            }
+            assoc.dispatch_packet(receiver).await
+        });
 
         (handle, sender)
     }
@@ -343,6 +444,7 @@ where
                             error!("udp relay {} <- ... (proxied) failed, error: {}", self.peer_addr, err);
                             // Socket failure. Reset for recreation.
                             self.proxied_socket = None;
+                            self.proxied_session = None;
                             continue;
                         }
                     };
@@ -424,9 +526,83 @@ where
         }
     }
 
+    async fn prebind_proxied_socket(&mut self) {
+        if self.balancer.is_empty() || !self.balancer.has_udp_server() {
+            return;
+        }
+
+        // Target is unknown yet, strategies that keep session affinity by target will fallback to the best server
+        let server = self.balancer.best_udp_server_for_client(&self.peer_addr, None);
+        let svr_cfg = server.server_config();
+
+        let connect_opts = self.context.uplink_connect_opts(server.connect_opts_ref());
+        match ProxySocket::connect_with_opts(self.context.context(), svr_cfg, &connect_opts).await {
+            Ok(socket) => {
+                trace!(
+                    "udp association for {} prebound to server {}",
+                    self.peer_addr,
+                    svr_cfg.addr()
+                );
+
+                self.set_proxied_socket_tos(&socket);
+                self.proxied_socket = Some(MonProxySocket::from_socket(socket, server.flow_stat()));
+                self.proxied_session = Some(server.udp_score().start_session());
+            }
+            Err(err) => {
+                debug!(
+                    "udp association for {} failed to prebind to server {}, error: {}",
+                    self.peer_addr,
+                    svr_cfg.addr(),
+                    err
+                );
+            }
+        }
+    }
+
+    fn set_bypassed_socket_tos(&self, socket: &ShadowUdpSocket) {
+        if let Some(tos) = self.tos {
+            if let Err(err) = socket.set_tos(tos) {
+                debug!(
+                    "udp association for {} failed to set bypassed socket TOS {:#04x}, error: {}",
+                    self.peer_addr, tos, err
+                );
+            }
+        }
+    }
+
+    fn set_proxied_socket_tos(&self, socket: &ProxySocket) {
+        if let Some(tos) = self.tos {
+            if let Err(err) = socket.set_tos(tos) {
+                debug!(
+                    "udp association for {} failed to set proxied socket TOS {:#04x}, error: {}",
+                    self.peer_addr, tos, err
+                );
+            }
+        }
+    }
+
     async fn dispatch_received_packet(&mut self, target_addr: &Address, data: &[u8]) {
         // Check if target should be bypassed. If so, send packets directly.
-        let bypassed = self.balancer.is_empty() || self.context.check_target_bypassed(target_addr).await;
+        let bypassed = match self.context.check_special_target(target_addr, true) {
+            None => self.balancer.is_empty() || self.context.check_target_bypassed(target_addr).await,
+            Some((_, SpecialTargetPolicy::Proxy)) => self.balancer.is_empty(),
+            Some((target, policy)) => {
+                // There is no way to tell the client, rejected packets are discarded as dropped ones
+                trace!(
+                    "udp relay {} -> {} {} target with {} bytes, {}",
+                    self.peer_addr,
+                    target_addr,
+                    target,
+                    data.len(),
+                    if policy == SpecialTargetPolicy::Drop {
+                        "dropped"
+                    } else {
+                        "rejected"
+                    }
+                );
+                return;
+            }
+        };
 
         trace!(
             "udp relay {} -> {} ({}) with {} bytes",
@@ -436,6 +612,10 @@ where
             data.len()
         );
 
+        if let Some(ref mut nat_filter) = self.nat_filter {
+            nat_filter.add_target(target_addr);
+        }
+
         if bypassed {
             if let Err(err) = self.dispatch_received_bypassed_packet(target_addr, data).await {
                 error!(
@@ -485,9 +665,9 @@ where
             match self.bypassed_ipv6_socket {
                 Some(ref mut socket) => socket,
                 None => {
-                    let socket =
-                        ShadowUdpSocket::connect_any_with_opts(AddrFamily::Ipv6, self.context.connect_opts_ref())
-                            .await?;
+                    let connect_opts = self.context.uplink_connect_opts(self.context.connect_opts_ref());
+                    let socket = ShadowUdpSocket::connect_any_with_opts(AddrFamily::Ipv6, &connect_opts).await?;
+                    self.set_bypassed_socket_tos(&socket);
                     self.bypassed_ipv6_socket.insert(socket)
                 }
             }
@@ -496,18 +676,18 @@ where
                 SocketAddr::V4(..) => match self.bypassed_ipv4_socket {
                     Some(ref mut socket) => socket,
                     None => {
-                        let socket =
-                            ShadowUdpSocket::connect_any_with_opts(&target_addr, self.context.connect_opts_ref())
-                                .await?;
+                        let connect_opts = self.context.uplink_connect_opts(self.context.connect_opts_ref());
+                        let socket = ShadowUdpSocket::connect_any_with_opts(&target_addr, &connect_opts).await?;
+                        self.set_bypassed_socket_tos(&socket);
                         self.bypassed_ipv4_socket.insert(socket)
                     }
                 },
                 SocketAddr::V6(..) => match self.bypassed_ipv6_socket {
                     Some(ref mut socket) => socket,
                     None => {
-                        let socket =
-                            ShadowUdpSocket::connect_any_with_opts(&target_addr, self.context.connect_opts_ref())
-                                .await?;
+                        let connect_opts = self.context.uplink_connect_opts(self.context.connect_opts_ref());
+                        let socket = ShadowUdpSocket::connect_any_with_opts(&target_addr, &connect_opts).await?;
+                        self.set_bypassed_socket_tos(&socket);
                         self.bypassed_ipv6_socket.insert(socket)
                     }
                 },
@@ -555,6 +735,7 @@ where
                 );
 
                 self.proxied_socket.take();
+                self.proxied_session.take();
                 self.client_packet_id = 1;
                 self.client_session_id = new_session_id;
 
@@ -567,13 +748,21 @@ where
             None => {
                 // Create a new connection to proxy server
 
-                let server = self.balancer.best_udp_server();
+                let server = self
+                    .balancer
+                    .best_udp_server_for_client(&self.peer_addr, Some(target_addr));
                 let svr_cfg = server.server_config();
 
-                let socket =
-                    ProxySocket::connect_with_opts(self.context.context(), svr_cfg, server.connect_opts_ref()).await?;
-                let socket = MonProxySocket::from_socket(socket, self.context.flow_stat());
+                if !svr_cfg.mode().enable_udp() {
+                    return Err(io::Error::other("no server in balancer serves UDP"));
+                }
+
+                let connect_opts = self.context.uplink_connect_opts(server.connect_opts_ref());
+                let socket = ProxySocket::connect_with_opts(self.context.context(), svr_cfg, &connect_opts).await?;
+                self.set_proxied_socket_tos(&socket);
+                let socket = MonProxySocket::from_socket(socket, server.flow_stat());
 
+                self.proxied_session = Some(server.udp_score().start_session());
                 self.proxied_socket.insert(socket)
             }
         };
@@ -595,6 +784,7 @@ where
 
                 // Drop the socket and reconnect to another server.
                 self.proxied_socket = None;
+                self.proxied_session = None;
             }
         }
 
@@ -610,6 +800,18 @@ where
             data.len(),
         );
 
+        if let Some(ref mut nat_filter) = self.nat_filter {
+            if !nat_filter.is_allowed(addr) {
+                trace!(
+                    "udp relay {} <- {} dropped {} bytes, client hasn't sent to it",
+                    self.peer_addr,
+                    addr,
+                    data.len()
+                );
+                return;
+            }
+        }
+
         // Keep association alive in map
         self.keepalive_flag = true;
 
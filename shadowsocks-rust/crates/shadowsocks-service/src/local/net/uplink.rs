@@ -0,0 +1,204 @@
+//! Multi-WAN outbound
+//!
+//! New outbound sockets (to servers, or bypassed targets) are balanced across local uplinks by smooth weighted
+//! round robin. An uplink is skipped for a while after consecutive failed connects, unless all uplinks are down.
+//!
+//! Servers that have their own outbound options, or are pinned to an uplink, are not balanced.
+
+use std::{
+    borrow::Cow,
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use shadowsocks::net::ConnectOpts;
+use spin::Mutex as SpinMutex;
+
+use crate::config::UplinkConfig;
+
+/// Consecutive failed connects before an uplink is marked down
+const UPLINK_FAIL_THRESHOLD: u32 = 3;
+
+/// Uplinks that are down are skipped for this long
+const UPLINK_DOWN_DURATION: Duration = Duration::from_secs(30);
+
+/// An uplink (local interface / source address) of outbound sockets
+#[derive(Debug)]
+pub struct Uplink {
+    config: UplinkConfig,
+    connections: AtomicUsize,
+    failures: AtomicUsize,
+    consecutive_failures: AtomicU32,
+    down_until: SpinMutex<Option<Instant>>,
+}
+
+impl Uplink {
+    fn new(config: UplinkConfig) -> Uplink {
+        Uplink {
+            config,
+            connections: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+            consecutive_failures: AtomicU32::new(0),
+            down_until: SpinMutex::new(None),
+        }
+    }
+
+    /// Name of uplink
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    /// Configuration of uplink
+    pub fn config(&self) -> &UplinkConfig {
+        &self.config
+    }
+
+    /// Bind sockets created with `opts` to this uplink
+    pub fn apply(&self, opts: &mut ConnectOpts) {
+        opts.bind_interface.clone_from(&self.config.bind_interface);
+        opts.bind_local_addr = self.config.bind_addr;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            opts.fwmark = self.config.fwmark;
+        }
+    }
+
+    /// Report result of a connect through this uplink
+    pub fn report_connect(&self, succeeded: bool) {
+        if succeeded {
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+            return;
+        }
+
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= UPLINK_FAIL_THRESHOLD {
+            let mut down_until = self.down_until.lock();
+            if down_until.is_none() {
+                warn!(
+                    "uplink {} is down after {} consecutive failed connects",
+                    self.config.name, failures
+                );
+            }
+            *down_until = Some(Instant::now() + UPLINK_DOWN_DURATION);
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Check if uplink is skipped for new sockets
+    pub fn is_down(&self) -> bool {
+        let mut down_until = self.down_until.lock();
+        match *down_until {
+            Some(t) if t > Instant::now() => true,
+            Some(..) => {
+                // Try it again
+                *down_until = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Outbound sockets created through this uplink
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Failed connects through this uplink
+    pub fn failures(&self) -> usize {
+        self.failures.load(Ordering::Relaxed)
+    }
+}
+
+/// Balancer of uplinks
+#[derive(Debug)]
+pub struct UplinkBalancer {
+    uplinks: Vec<Arc<Uplink>>,
+    // Current weights of smooth weighted round robin
+    current_weights: SpinMutex<Vec<i64>>,
+}
+
+impl UplinkBalancer {
+    /// Create with configurations of uplinks
+    pub fn new(configs: &[UplinkConfig]) -> UplinkBalancer {
+        UplinkBalancer {
+            uplinks: configs.iter().cloned().map(|c| Arc::new(Uplink::new(c))).collect(),
+            current_weights: SpinMutex::new(vec![0; configs.len()]),
+        }
+    }
+
+    /// All uplinks
+    pub fn uplinks(&self) -> &[Arc<Uplink>] {
+        &self.uplinks
+    }
+
+    /// Find uplink by `name`
+    pub fn find(&self, name: &str) -> Option<&Arc<Uplink>> {
+        self.uplinks.iter().find(|u| u.name() == name)
+    }
+
+    /// Choose an uplink for a new outbound socket
+    pub fn select(&self) -> Option<Arc<Uplink>> {
+        let up = self.uplinks.iter().map(|u| !u.is_down()).collect::<Vec<_>>();
+        let all_down = !up.iter().any(|up| *up);
+
+        let mut current_weights = self.current_weights.lock();
+        let mut total = 0i64;
+        let mut best: Option<usize> = None;
+        for (idx, uplink) in self.uplinks.iter().enumerate() {
+            if !up[idx] && !all_down {
+                continue;
+            }
+
+            let weight = i64::from(uplink.config.weight);
+            current_weights[idx] += weight;
+            total += weight;
+            if best.map_or(true, |b| current_weights[idx] > current_weights[b]) {
+                best = Some(idx);
+            }
+        }
+
+        let best = best?;
+        current_weights[best] -= total;
+
+        let uplink = self.uplinks[best].clone();
+        uplink.connections.fetch_add(1, Ordering::Relaxed);
+        Some(uplink)
+    }
+
+    /// Choose an uplink for a new outbound socket created with `opts`, and bind `opts` to it
+    ///
+    /// Returns `None` if `opts` is already bound to an interface, address or fwmark, by server's own outbound
+    /// options or its pinned uplink
+    pub fn select_for(&self, opts: &ConnectOpts) -> Option<(Arc<Uplink>, ConnectOpts)> {
+        if is_bound(opts) {
+            return None;
+        }
+
+        let uplink = self.select()?;
+        let mut opts = opts.clone();
+        uplink.apply(&mut opts);
+        Some((uplink, opts))
+    }
+
+    /// Same as `select_for`, without tracking the result of connect
+    pub fn connect_opts<'a>(&self, opts: &'a ConnectOpts) -> Cow<'a, ConnectOpts> {
+        match self.select_for(opts) {
+            Some((_, opts)) => Cow::Owned(opts),
+            None => Cow::Borrowed(opts),
+        }
+    }
+}
+
+fn is_bound(opts: &ConnectOpts) -> bool {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if opts.fwmark.is_some() {
+        return true;
+    }
+
+    opts.bind_interface.is_some() || opts.bind_local_addr.is_some()
+}
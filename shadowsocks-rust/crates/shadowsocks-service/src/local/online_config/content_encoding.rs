@@ -46,7 +46,7 @@ pub async fn read_body<B>(encoding: ContentEncoding, body: &mut B) -> io::Result
 where
     B: Body + Sized + Unpin + 'static,
     B::Data: AsRef<[u8]>,
-    B::Error: Into<Box<(dyn ::std::error::Error + Send + Sync + 'static)>>,
+    B::Error: Into<Box<dyn ::std::error::Error + Send + Sync + 'static>>,
 {
     let mut raw_body = Vec::new();
 
@@ -54,7 +54,7 @@ where
     while let Some(data) = body_stream.next().await {
         match data {
             Ok(data) => raw_body.extend_from_slice(data.as_ref()),
-            Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
+            Err(err) => return Err(io::Error::other(err)),
         }
     }
 
@@ -17,11 +17,13 @@ use http::StatusCode;
 use log::{debug, error, trace, warn};
 use mime::Mime;
 use shadowsocks::config::ServerSource;
-use tokio::time;
+use tokio::{sync::Notify, time};
 
 use self::content_encoding::{read_body, ContentEncoding};
+pub use self::rule_set::download_rule_sets;
 
 mod content_encoding;
+mod rule_set;
 
 /// OnlineConfigService builder pattern
 pub struct OnlineConfigServiceBuilder {
@@ -55,6 +57,7 @@ impl OnlineConfigServiceBuilder {
             config_url: self.config_url,
             config_update_interval: self.config_update_interval,
             balancer: self.balancer,
+            refresh: Arc::new(Notify::new()),
         };
 
         // Run once after creation.
@@ -70,9 +73,15 @@ pub struct OnlineConfigService {
     config_url: String,
     config_update_interval: Duration,
     balancer: PingBalancer,
+    refresh: Arc<Notify>,
 }
 
 impl OnlineConfigService {
+    /// Notifier that makes the service update servers immediately
+    pub fn refresh_notify(&self) -> Arc<Notify> {
+        self.refresh.clone()
+    }
+
     async fn run_once(&mut self) -> io::Result<()> {
         match time::timeout(Duration::from_secs(30), self.run_once_impl()).await {
             Ok(o) => o,
@@ -98,7 +107,7 @@ impl OnlineConfigService {
             Ok(r) => r,
             Err(err) => {
                 error!("server-loader task failed to make hyper::Request, error: {}", err);
-                return Err(io::Error::new(io::ErrorKind::Other, err));
+                return Err(io::Error::other(err));
             }
         };
 
@@ -106,7 +115,7 @@ impl OnlineConfigService {
             Ok(r) => r,
             Err(err) => {
                 error!("server-loader task failed to get {}, error: {}", self.config_url, err);
-                return Err(io::Error::new(io::ErrorKind::Other, err));
+                return Err(io::Error::other(err));
             }
         };
 
@@ -121,10 +130,7 @@ impl OnlineConfigService {
                 self.config_url,
                 rsp.status()
             );
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("status: {}", rsp.status()),
-            ));
+            return Err(io::Error::other(format!("status: {}", rsp.status())));
         }
 
         // Content-Type: application/json; charset=utf-8
@@ -164,7 +170,7 @@ impl OnlineConfigService {
                 Ok(ce) => ce,
                 Err(..) => {
                     error!("unrecognized Content-Encoding: {:?}", ce);
-                    return Err(io::Error::new(io::ErrorKind::Other, "unrecognized Content-Encoding"));
+                    return Err(io::Error::other("unrecognized Content-Encoding"));
                 }
             },
         };
@@ -172,7 +178,7 @@ impl OnlineConfigService {
         let body = read_body(content_encoding, &mut rsp).await?;
         let parsed_body = match String::from_utf8(body) {
             Ok(b) => b,
-            Err(..) => return Err(io::Error::new(io::ErrorKind::Other, "body contains non-utf8 bytes")),
+            Err(..) => return Err(io::Error::other("body contains non-utf8 bytes")),
         };
 
         let online_config = match Config::load_from_str(&parsed_body, ConfigType::OnlineConfig) {
@@ -182,7 +188,7 @@ impl OnlineConfigService {
                     "server-loader task failed to load from url: {}, error: {}",
                     self.config_url, err
                 );
-                return Err(io::Error::new(io::ErrorKind::Other, err));
+                return Err(io::Error::other(err));
             }
         };
 
@@ -191,7 +197,7 @@ impl OnlineConfigService {
                 "server-loader task failed to load from url: {}, error: {}",
                 self.config_url, err
             );
-            return Err(io::Error::new(io::ErrorKind::Other, err));
+            return Err(io::Error::other(err));
         }
 
         let after_read_time = Instant::now();
@@ -234,7 +240,12 @@ impl OnlineConfigService {
         );
 
         loop {
-            time::sleep(self.config_update_interval).await;
+            tokio::select! {
+                _ = time::sleep(self.config_update_interval) => {}
+                _ = self.refresh.notified() => {
+                    debug!("server-loader task refresh requested, url: {}", self.config_url);
+                }
+            }
             let _ = self.run_once().await;
         }
     }
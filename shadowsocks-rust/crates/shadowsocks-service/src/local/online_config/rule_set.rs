@@ -0,0 +1,112 @@
+//! Remote rule sets
+//!
+//! Rule sets with `url` are downloaded to their `path` before ACL is loaded into the local service.
+
+use std::{fs, io, sync::Arc, time::Duration};
+
+use http::StatusCode;
+use log::{error, info, trace};
+use tokio::time;
+
+use crate::{
+    acl::RuleSet,
+    local::{context::ServiceContext, http::HttpClient},
+};
+
+use super::content_encoding::{read_body, ContentEncoding};
+
+/// Download remote rule sets to their paths
+///
+/// Returns `true` if any rule set file was changed
+pub async fn download_rule_sets(context: Arc<ServiceContext>, rule_sets: &[RuleSet]) -> bool {
+    let http_client = HttpClient::<String>::new();
+    let mut updated = false;
+
+    for rule_set in rule_sets {
+        let url = match rule_set.url {
+            Some(ref url) => url,
+            None => continue,
+        };
+
+        let body = match time::timeout(
+            Duration::from_secs(30),
+            download_rule_set(&http_client, context.clone(), url),
+        )
+        .await
+        {
+            Ok(Ok(body)) => body,
+            Ok(Err(err)) => {
+                error!("rule set failed to download from {}, error: {}", url, err);
+                continue;
+            }
+            Err(..) => {
+                error!("rule set download timeout, url: {}", url);
+                continue;
+            }
+        };
+
+        if fs::read(&rule_set.path).ok().as_deref() == Some(body.as_slice()) {
+            trace!("rule set {} from {} is not changed", rule_set.path.display(), url);
+            continue;
+        }
+
+        match fs::write(&rule_set.path, &body) {
+            Ok(..) => {
+                info!(
+                    "rule set {} updated from {}, {} bytes",
+                    rule_set.path.display(),
+                    url,
+                    body.len()
+                );
+                updated = true;
+            }
+            Err(err) => {
+                error!(
+                    "rule set {} failed to save, url: {}, error: {}",
+                    rule_set.path.display(),
+                    url,
+                    err
+                );
+            }
+        }
+    }
+
+    updated
+}
+
+async fn download_rule_set(
+    http_client: &HttpClient<String>,
+    context: Arc<ServiceContext>,
+    url: &str,
+) -> io::Result<Vec<u8>> {
+    static SHADOWSOCKS_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+    let uri = url.parse::<hyper::Uri>().map_err(io::Error::other)?;
+    if uri.host().is_none() {
+        return Err(io::Error::other("URL missing host"));
+    }
+
+    let req = hyper::Request::builder()
+        .header("User-Agent", SHADOWSOCKS_USER_AGENT)
+        .header("Accept-Encoding", "deflate, gzip, br, zstd")
+        .method("GET")
+        .uri(uri)
+        .body(String::new())
+        .map_err(io::Error::other)?;
+
+    let mut rsp = http_client
+        .send_request(context, req, None)
+        .await
+        .map_err(io::Error::other)?;
+
+    if rsp.status() != StatusCode::OK {
+        return Err(io::Error::other(format!("status: {}", rsp.status())));
+    }
+
+    let content_encoding = match rsp.headers().get(http::header::CONTENT_ENCODING) {
+        None => ContentEncoding::Identity,
+        Some(ce) => ContentEncoding::try_from(ce).map_err(|_| io::Error::other("unrecognized Content-Encoding"))?,
+    };
+
+    read_body(content_encoding, &mut rsp).await
+}
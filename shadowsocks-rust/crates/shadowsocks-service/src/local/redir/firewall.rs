@@ -0,0 +1,423 @@
+//! Firewall rules of transparent proxies on Linux
+//!
+//! Rules are named after the TCP port (or the UDP port if TCP is not enabled), so rules left by a crashed
+//! process are removed before installing them again.
+//!
+//! Only forwarded traffic (`PREROUTING`) is intercepted, connections of this host are not redirected, so there
+//! is no loop with outbound connections of the proxy itself.
+
+use std::{
+    fmt::Write as _,
+    io::{self, Write as _},
+    net::IpAddr,
+    process::{Command, Stdio},
+};
+
+use ipnet::IpNet;
+use log::{debug, error, info, warn};
+
+use crate::config::{RedirFirewallBackend, RedirFirewallConfig, RedirType};
+
+/// Reserved networks that are never intercepted
+const RESERVED_NETWORKS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// What is intercepted
+#[derive(Debug, Clone, Copy)]
+pub struct RedirTargets {
+    /// TCP transparent proxy type and port
+    pub tcp: Option<(RedirType, u16)>,
+    /// UDP port, always with TPROXY
+    pub udp: Option<u16>,
+}
+
+impl RedirTargets {
+    fn tproxy_enabled(&self) -> bool {
+        self.udp.is_some() || matches!(self.tcp, Some((RedirType::TProxy, _)))
+    }
+
+    fn name_port(&self) -> u16 {
+        match (self.tcp, self.udp) {
+            (Some((_, port)), _) => port,
+            (None, Some(port)) => port,
+            (None, None) => 0,
+        }
+    }
+}
+
+fn run(program: &str, args: &[&str], stdin: Option<&str>) -> io::Result<String> {
+    debug!("[REDIR] {} {}", program, args.join(" "));
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        pipe.write_all(input.as_bytes())?;
+    }
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!(
+            "{} {} exited with {}, {}",
+            program,
+            args.join(" "),
+            output.status,
+            stderr.trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn is_available(program: &str) -> bool {
+    Command::new(program)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Installed firewall rules, removed when dropped
+pub struct RedirFirewall {
+    config: RedirFirewallConfig,
+    targets: RedirTargets,
+    backend: InstalledBackend,
+    name: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum InstalledBackend {
+    Nftables,
+    Iptables { v4: &'static str, v6: &'static str },
+}
+
+impl RedirFirewall {
+    /// Install rules of `targets`, replacing rules left by a previous process with the same ports
+    pub fn install(config: RedirFirewallConfig, targets: RedirTargets) -> io::Result<RedirFirewall> {
+        let backend = match config.backend {
+            RedirFirewallBackend::Nftables => InstalledBackend::Nftables,
+            RedirFirewallBackend::Iptables => iptables_backend(),
+            RedirFirewallBackend::Auto => {
+                if is_available("nft") {
+                    InstalledBackend::Nftables
+                } else {
+                    iptables_backend()
+                }
+            }
+        };
+
+        let port = targets.name_port();
+        let name = match backend {
+            InstalledBackend::Nftables => format!("shadowsocks_redir_{port}"),
+            InstalledBackend::Iptables { .. } => format!("SS_REDIR_{port}"),
+        };
+
+        let firewall = RedirFirewall {
+            config,
+            targets,
+            backend,
+            name,
+        };
+
+        // Rules of a crashed process
+        firewall.remove();
+
+        // Partially installed rules are removed by dropping
+        firewall.try_install()?;
+
+        info!(
+            "shadowsocks redir installed firewall rules {} with {:?}",
+            firewall.name, firewall.backend
+        );
+        Ok(firewall)
+    }
+
+    fn bypass_networks(&self) -> io::Result<Vec<IpNet>> {
+        let mut networks = Vec::with_capacity(RESERVED_NETWORKS.len() + self.config.bypass.len());
+        for network in RESERVED_NETWORKS {
+            networks.push(network.parse::<IpNet>().map_err(io::Error::other)?);
+        }
+        networks.extend_from_slice(&self.config.bypass);
+        Ok(networks)
+    }
+
+    fn try_install(&self) -> io::Result<()> {
+        if self.targets.tproxy_enabled() {
+            self.install_policy_routing()?;
+        }
+
+        match self.backend {
+            InstalledBackend::Nftables => {
+                let ruleset = self.nftables_ruleset()?;
+                run("nft", &["-f", "-"], Some(&ruleset))?;
+            }
+            InstalledBackend::Iptables { v4, v6 } => {
+                self.install_iptables(v4, false)?;
+                self.install_iptables(v6, true)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Packets marked by TPROXY are delivered to the local stack
+    fn install_policy_routing(&self) -> io::Result<()> {
+        let fwmark = self.config.fwmark.to_string();
+        let table = self.config.route_table.to_string();
+
+        for family in ["-4", "-6"] {
+            run(
+                "ip",
+                &[family, "rule", "add", "fwmark", &fwmark, "lookup", &table],
+                None,
+            )?;
+
+            let default = if family == "-4" { "0.0.0.0/0" } else { "::/0" };
+            run(
+                "ip",
+                &[
+                    family, "route", "replace", "local", default, "dev", "lo", "table", &table,
+                ],
+                None,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn remove_policy_routing(&self) {
+        let fwmark = self.config.fwmark.to_string();
+        let table = self.config.route_table.to_string();
+
+        for family in ["-4", "-6"] {
+            // Rules may be added more than once by crashed processes
+            while run(
+                "ip",
+                &[family, "rule", "del", "fwmark", &fwmark, "lookup", &table],
+                None,
+            )
+            .is_ok()
+            {}
+
+            let default = if family == "-4" { "0.0.0.0/0" } else { "::/0" };
+            let _ = run(
+                "ip",
+                &[family, "route", "del", "local", default, "dev", "lo", "table", &table],
+                None,
+            );
+        }
+    }
+
+    fn nftables_ruleset(&self) -> io::Result<String> {
+        let mut bypass_v4 = Vec::new();
+        let mut bypass_v6 = Vec::new();
+        for network in self.bypass_networks()? {
+            match network {
+                IpNet::V4(..) => bypass_v4.push(network.to_string()),
+                IpNet::V6(..) => bypass_v6.push(network.to_string()),
+            }
+        }
+
+        let iifname = if self.config.interfaces.is_empty() {
+            String::new()
+        } else {
+            let interfaces: Vec<String> = self.config.interfaces.iter().map(|i| format!("\"{i}\"")).collect();
+            format!("iifname {{ {} }} ", interfaces.join(", "))
+        };
+
+        let mut ruleset = String::new();
+        let _ = writeln!(ruleset, "table inet {} {{", self.name);
+        let _ = writeln!(
+            ruleset,
+            "    set bypass_v4 {{ type ipv4_addr; flags interval; elements = {{ {} }} }}",
+            bypass_v4.join(", ")
+        );
+        let _ = writeln!(
+            ruleset,
+            "    set bypass_v6 {{ type ipv6_addr; flags interval; elements = {{ {} }} }}",
+            bypass_v6.join(", ")
+        );
+
+        if let Some((RedirType::Redirect, port)) = self.targets.tcp {
+            let _ = writeln!(ruleset, "    chain prerouting_nat {{");
+            let _ = writeln!(
+                ruleset,
+                "        type nat hook prerouting priority dstnat; policy accept;"
+            );
+            let _ = writeln!(ruleset, "        ip daddr @bypass_v4 return");
+            let _ = writeln!(ruleset, "        ip6 daddr @bypass_v6 return");
+            let _ = writeln!(ruleset, "        {iifname}meta l4proto tcp redirect to :{port}");
+            let _ = writeln!(ruleset, "    }}");
+        }
+
+        if self.targets.tproxy_enabled() {
+            let fwmark = self.config.fwmark;
+            let _ = writeln!(ruleset, "    chain prerouting_mangle {{");
+            let _ = writeln!(
+                ruleset,
+                "        type filter hook prerouting priority mangle; policy accept;"
+            );
+            let _ = writeln!(ruleset, "        ip daddr @bypass_v4 return");
+            let _ = writeln!(ruleset, "        ip6 daddr @bypass_v6 return");
+            if let Some((RedirType::TProxy, port)) = self.targets.tcp {
+                let _ = writeln!(
+                    ruleset,
+                    "        {iifname}meta l4proto tcp tproxy to :{port} meta mark set {fwmark} accept"
+                );
+            }
+            if let Some(port) = self.targets.udp {
+                let _ = writeln!(
+                    ruleset,
+                    "        {iifname}meta l4proto udp tproxy to :{port} meta mark set {fwmark} accept"
+                );
+            }
+            let _ = writeln!(ruleset, "    }}");
+        }
+
+        let _ = writeln!(ruleset, "}}");
+        Ok(ruleset)
+    }
+
+    fn install_iptables(&self, program: &str, v6: bool) -> io::Result<()> {
+        let bypass: Vec<String> = self
+            .bypass_networks()?
+            .into_iter()
+            .filter(|network| matches!(network.addr(), IpAddr::V6(..)) == v6)
+            .map(|network| network.to_string())
+            .collect();
+
+        // Rules of each table, in whitespace separated arguments
+        let mut chains = Vec::new();
+        if let Some((RedirType::Redirect, port)) = self.targets.tcp {
+            chains.push(("nat", vec![format!("-p tcp -j REDIRECT --to-ports {port}")]));
+        }
+        if self.targets.tproxy_enabled() {
+            let fwmark = self.config.fwmark;
+            let mut rules = Vec::new();
+            if let Some((RedirType::TProxy, port)) = self.targets.tcp {
+                rules.push(format!(
+                    "-p tcp -j TPROXY --on-port {port} --tproxy-mark {fwmark}/{fwmark}"
+                ));
+            }
+            if let Some(port) = self.targets.udp {
+                rules.push(format!(
+                    "-p udp -j TPROXY --on-port {port} --tproxy-mark {fwmark}/{fwmark}"
+                ));
+            }
+            chains.push(("mangle", rules));
+        }
+
+        for (table, rules) in chains {
+            run(program, &["-t", table, "-N", &self.name], None)?;
+            for network in &bypass {
+                run(
+                    program,
+                    &["-t", table, "-A", &self.name, "-d", network, "-j", "RETURN"],
+                    None,
+                )?;
+            }
+            for rule in rules {
+                let mut args = vec!["-t", table, "-A", &self.name];
+                args.extend(rule.split_whitespace());
+                run(program, &args, None)?;
+            }
+
+            if self.config.interfaces.is_empty() {
+                run(program, &["-t", table, "-A", "PREROUTING", "-j", &self.name], None)?;
+            } else {
+                for interface in &self.config.interfaces {
+                    run(
+                        program,
+                        &["-t", table, "-A", "PREROUTING", "-i", interface, "-j", &self.name],
+                        None,
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn remove_iptables(&self, program: &str) {
+        for table in ["nat", "mangle"] {
+            // Jumps are found by listing, interfaces of a crashed process may be different
+            if let Ok(rules) = run(program, &["-t", table, "-S", "PREROUTING"], None) {
+                for rule in rules.lines() {
+                    let mut args: Vec<&str> = rule.split_whitespace().collect();
+                    if args.first() != Some(&"-A") || !args.windows(2).any(|w| w == ["-j", self.name.as_str()]) {
+                        continue;
+                    }
+                    args[0] = "-D";
+
+                    let mut full_args = vec!["-t", table];
+                    full_args.extend(args);
+                    if let Err(err) = run(program, &full_args, None) {
+                        warn!("[REDIR] failed to remove firewall rule, error: {}", err);
+                    }
+                }
+            }
+
+            let _ = run(program, &["-t", table, "-F", &self.name], None);
+            let _ = run(program, &["-t", table, "-X", &self.name], None);
+        }
+    }
+
+    fn remove(&self) {
+        match self.backend {
+            InstalledBackend::Nftables => {
+                let _ = run("nft", &["delete", "table", "inet", &self.name], None);
+            }
+            InstalledBackend::Iptables { v4, v6 } => {
+                self.remove_iptables(v4);
+                self.remove_iptables(v6);
+            }
+        }
+
+        if self.targets.tproxy_enabled() {
+            self.remove_policy_routing();
+        }
+    }
+}
+
+impl Drop for RedirFirewall {
+    fn drop(&mut self) {
+        self.remove();
+        info!("shadowsocks redir removed firewall rules {}", self.name);
+    }
+}
+
+/// iptables-legacy is preferred, iptables may be the nftables compatible layer which is not useful as a fallback
+fn iptables_backend() -> InstalledBackend {
+    if is_available("iptables-legacy") {
+        InstalledBackend::Iptables {
+            v4: "iptables-legacy",
+            v6: "ip6tables-legacy",
+        }
+    } else {
+        if !is_available("iptables") {
+            error!("[REDIR] neither nft nor iptables is available");
+        }
+        InstalledBackend::Iptables {
+            v4: "iptables",
+            v6: "ip6tables",
+        }
+    }
+}
@@ -2,6 +2,8 @@
 
 pub use self::server::{Redir, RedirBuilder};
 
+#[cfg(target_os = "linux")]
+mod firewall;
 mod redir_ext;
 mod server;
 mod sys;
@@ -5,11 +5,15 @@ use std::{io, sync::Arc, time::Duration};
 use futures::{future, FutureExt};
 use shadowsocks::{config::Mode, ServerAddr};
 
+#[cfg(target_os = "linux")]
+use crate::config::RedirFirewallConfig;
 use crate::{
     config::RedirType,
     local::{context::ServiceContext, loadbalancing::PingBalancer},
 };
 
+#[cfg(target_os = "linux")]
+use super::firewall::{RedirFirewall, RedirTargets};
 use super::{tcprelay::RedirTcpServer, udprelay::RedirUdpServer};
 
 /// Transparent Proxy builder
@@ -23,6 +27,9 @@ pub struct RedirBuilder {
     client_addr: ServerAddr,
     udp_bind_addr: Option<ServerAddr>,
     balancer: PingBalancer,
+    sniff_timeout: Option<Duration>,
+    #[cfg(target_os = "linux")]
+    firewall: Option<RedirFirewallConfig>,
 }
 
 impl RedirBuilder {
@@ -44,6 +51,9 @@ impl RedirBuilder {
             client_addr,
             udp_bind_addr: None,
             balancer,
+            sniff_timeout: None,
+            #[cfg(target_os = "linux")]
+            firewall: None,
         }
     }
 
@@ -77,6 +87,17 @@ impl RedirBuilder {
         self.udp_bind_addr = Some(addr);
     }
 
+    /// Sniff TLS SNI or HTTP `Host` of TCP clients for ACL domain rules, waiting at most `timeout` for them
+    pub fn set_sniff_timeout(&mut self, timeout: Duration) {
+        self.sniff_timeout = Some(timeout);
+    }
+
+    /// Install firewall rules intercepting forwarded traffic to the listening ports, removed when the server stops
+    #[cfg(target_os = "linux")]
+    pub fn set_firewall(&mut self, config: RedirFirewallConfig) {
+        self.firewall = Some(config);
+    }
+
     pub async fn build(self) -> io::Result<Redir> {
         let mut tcp_server = None;
         if self.mode.enable_tcp() {
@@ -85,6 +106,7 @@ impl RedirBuilder {
                 &self.client_addr,
                 self.balancer.clone(),
                 self.tcp_redir,
+                self.sniff_timeout,
             )
             .await?;
             tcp_server = Some(server);
@@ -106,7 +128,33 @@ impl RedirBuilder {
             udp_server = Some(server);
         }
 
-        Ok(Redir { tcp_server, udp_server })
+        #[cfg(target_os = "linux")]
+        let firewall = match self.firewall {
+            None => None,
+            Some(config) => {
+                let targets = RedirTargets {
+                    tcp: match tcp_server {
+                        Some(ref s) => Some((self.tcp_redir, s.local_addr()?.port())),
+                        None => None,
+                    },
+                    udp: match udp_server {
+                        Some(ref s) => Some(s.local_addr()?.port()),
+                        None => None,
+                    },
+                };
+                match tokio::task::spawn_blocking(move || RedirFirewall::install(config, targets)).await {
+                    Ok(r) => Some(r?),
+                    Err(err) => return Err(io::Error::other(err)),
+                }
+            }
+        };
+
+        Ok(Redir {
+            tcp_server,
+            udp_server,
+            #[cfg(target_os = "linux")]
+            firewall,
+        })
     }
 }
 
@@ -114,6 +162,8 @@ impl RedirBuilder {
 pub struct Redir {
     tcp_server: Option<RedirTcpServer>,
     udp_server: Option<RedirUdpServer>,
+    #[cfg(target_os = "linux")]
+    firewall: Option<RedirFirewall>,
 }
 
 impl Redir {
@@ -129,6 +179,10 @@ impl Redir {
 
     /// Start serving
     pub async fn run(self) -> io::Result<()> {
+        // Rules are removed when serving stops
+        #[cfg(target_os = "linux")]
+        let _firewall = self.firewall;
+
         let mut vfut = Vec::new();
 
         if let Some(tcp_server) = self.tcp_server {
@@ -19,7 +19,7 @@ use crate::{
     local::{
         context::ServiceContext,
         loadbalancing::PingBalancer,
-        net::AutoProxyClientStream,
+        net::{rewind::RewindStream, sniff::sniff_host, AutoProxyClientStream},
         redir::redir_ext::{TcpListenerRedirExt, TcpStreamRedirExt},
         utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
     },
@@ -32,25 +32,69 @@ mod sys;
 /// Established Client Transparent Proxy
 ///
 /// This method must be called after handshaking with client (for example, socks5 handshaking)
-async fn establish_client_tcp_redir<'a>(
+async fn establish_client_tcp_redir(
     context: Arc<ServiceContext>,
     balancer: PingBalancer,
     mut stream: TcpStream,
     peer_addr: SocketAddr,
     addr: &Address,
+    sniff_timeout: Option<Duration>,
 ) -> io::Result<()> {
+    if let Some(nodelay) = context.target_nodelay(addr) {
+        stream.set_nodelay(nodelay)?;
+    }
+
     if balancer.is_empty() {
-        let mut remote = AutoProxyClientStream::connect_bypassed(context, addr).await?;
-        return establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, addr).await;
+        let mut remote = AutoProxyClientStream::connect_bypassed(context.clone(), addr).await?;
+        return establish_tcp_tunnel_bypassed(&context, &mut stream, &mut remote, peer_addr, addr).await;
     }
 
-    let server = balancer.best_tcp_server();
+    let server = balancer.best_tcp_server_for_client(&peer_addr, addr);
     let svr_cfg = server.server_config();
 
-    let mut remote =
-        AutoProxyClientStream::connect_with_opts(context, &server, addr, server.connect_opts_ref()).await?;
+    // Process rules take precedence over rules of targets, there is no need to sniff
+    let process_bypassed = context.check_process_bypassed(peer_addr).await;
+
+    let (prefix, host) = match sniff_timeout {
+        Some(timeout) if process_bypassed.is_none() => sniff_host(&mut stream, timeout).await?,
+        _ => (Vec::new(), None),
+    };
+    let mut stream = RewindStream::new(prefix, stream);
+
+    let mut remote = match (process_bypassed, host) {
+        (Some(bypassed), _) => {
+            trace!(
+                "TCP redirect {} -> {} bypassed: {} by process rules",
+                peer_addr,
+                addr,
+                bypassed
+            );
+            AutoProxyClientStream::connect_decided_with_opts(
+                context.clone(),
+                &server,
+                addr,
+                bypassed,
+                server.connect_opts_ref(),
+            )
+            .await?
+        }
+        (None, Some(ref host)) => {
+            trace!("TCP redirect {} -> {} sniffed host {}", peer_addr, addr, host);
+            AutoProxyClientStream::connect_sniffed_with_opts(
+                context.clone(),
+                &server,
+                addr,
+                host,
+                server.connect_opts_ref(),
+            )
+            .await?
+        }
+        (None, None) => {
+            AutoProxyClientStream::connect_with_opts(context.clone(), &server, addr, server.connect_opts_ref()).await?
+        }
+    };
 
-    establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, addr).await
+    establish_tcp_tunnel(&context, svr_cfg, &mut stream, &mut remote, peer_addr, addr).await
 }
 
 async fn handle_redir_client(
@@ -59,6 +103,7 @@ async fn handle_redir_client(
     s: TcpStream,
     peer_addr: SocketAddr,
     mut daddr: SocketAddr,
+    sniff_timeout: Option<Duration>,
 ) -> io::Result<()> {
     // Get forward address from socket
     //
@@ -69,7 +114,7 @@ async fn handle_redir_client(
         }
     }
     let target_addr = Address::from(daddr);
-    establish_client_tcp_redir(context, balancer, s, peer_addr, &target_addr).await
+    establish_client_tcp_redir(context, balancer, s, peer_addr, &target_addr, sniff_timeout).await
 }
 
 /// Redir TCP server instance
@@ -78,6 +123,7 @@ pub struct RedirTcpServer {
     listener: TcpListener,
     balancer: PingBalancer,
     redir_ty: RedirType,
+    sniff_timeout: Option<Duration>,
 }
 
 impl RedirTcpServer {
@@ -86,6 +132,7 @@ impl RedirTcpServer {
         client_config: &ServerAddr,
         balancer: PingBalancer,
         redir_ty: RedirType,
+        sniff_timeout: Option<Duration>,
     ) -> io::Result<RedirTcpServer> {
         let listener = match *client_config {
             ServerAddr::SocketAddr(ref saddr) => {
@@ -104,6 +151,7 @@ impl RedirTcpServer {
             listener,
             balancer,
             redir_ty,
+            sniff_timeout,
         })
     }
 
@@ -138,6 +186,7 @@ impl RedirTcpServer {
             let context = self.context.clone();
             let balancer = self.balancer.clone();
             let redir_ty = self.redir_ty;
+            let sniff_timeout = self.sniff_timeout;
             tokio::spawn(async move {
                 let dst_addr = match socket.destination_addr(redir_ty) {
                     Ok(d) => d,
@@ -150,7 +199,9 @@ impl RedirTcpServer {
                     }
                 };
 
-                if let Err(err) = handle_redir_client(context, balancer, socket, peer_addr, dst_addr).await {
+                if let Err(err) =
+                    handle_redir_client(context, balancer, socket, peer_addr, dst_addr, sniff_timeout).await
+                {
                     debug!("TCP redirect client, error: {:?}", err);
                 }
             });
@@ -6,7 +6,6 @@ use std::{
 use async_trait::async_trait;
 use log::warn;
 use shadowsocks::net::{is_dual_stack_addr, set_tcp_fastopen, AcceptOpts};
-use socket2::Protocol;
 use tokio::net::{TcpListener, TcpSocket, TcpStream};
 
 use crate::{
@@ -27,6 +26,9 @@ impl TcpListenerRedirExt for TcpListener {
             #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "ios"))]
             RedirType::IpFirewall => {}
 
+            #[cfg(target_os = "openbsd")]
+            RedirType::PacketFilterDivert => {}
+
             _ => {
                 return Err(Error::new(
                     ErrorKind::InvalidInput,
@@ -101,6 +103,8 @@ impl TcpStreamRedirExt for TcpStream {
         match ty {
             #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "ios"))]
             RedirType::PacketFilter => {
+                use socket2::Protocol;
+
                 use crate::local::redir::sys::bsd_pf::PF;
 
                 let peer_addr = self.peer_addr()?;
@@ -117,6 +121,15 @@ impl TcpStreamRedirExt for TcpStream {
                 // FreeBSD: https://www.freebsd.org/doc/handbook/firewalls-ipfw.html
                 self.local_addr()
             }
+            #[cfg(target_os = "openbsd")]
+            RedirType::PacketFilterDivert => {
+                // ## pf divert-to
+                //
+                // Diverted connections are accepted with their original destinations, retrieved by getsockname()
+                //
+                // OpenBSD: https://man.openbsd.org/pf.conf#divert-to
+                self.local_addr()
+            }
             _ => unreachable!("not supported tcp transparent proxy type"),
         }
     }
@@ -238,6 +238,11 @@ impl RedirUdpServer {
         })
     }
 
+    /// Get server local address
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
     pub async fn run(self) -> io::Result<()> {
         let local_addr = self.listener.local_addr().expect("determine port bound to");
         info!(
@@ -10,6 +10,9 @@ cfg_if! {
     } else if #[cfg(any(target_os = "freebsd"))] {
         mod bsd;
         pub use self::bsd::*;
+    } else if #[cfg(target_os = "openbsd")] {
+        mod openbsd;
+        pub use self::openbsd::*;
     } else {
         mod not_supported;
         pub use self::not_supported::*;
@@ -0,0 +1,245 @@
+use std::{
+    io::{self, Error, ErrorKind},
+    mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+    os::unix::io::AsRawFd,
+    ptr,
+    task::{Context, Poll},
+};
+
+use futures::{future::poll_fn, ready};
+use log::{error, trace};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::io::unix::AsyncFd;
+
+use crate::{
+    config::RedirType,
+    local::redir::redir_ext::{RedirSocketOpts, UdpSocketRedir},
+};
+
+// netinet/in.h
+const IP_RECVDSTADDR: libc::c_int = 7; // bool; receive IP dst addr w/dgram
+const IP_RECVDSTPORT: libc::c_int = 33; // bool; receive IP dst port w/dgram
+
+// netinet6/in6.h
+const IPV6_RECVDSTPORT: libc::c_int = 64; // bool; receive IP dst port w/dgram
+
+pub struct UdpRedirSocket {
+    io: AsyncFd<UdpSocket>,
+}
+
+impl UdpRedirSocket {
+    /// Create a new UDP socket binded to `addr`
+    ///
+    /// This will allow listening to `addr` that is not in local host
+    pub fn listen(ty: RedirType, addr: SocketAddr) -> io::Result<UdpRedirSocket> {
+        UdpRedirSocket::bind(ty, addr, false)
+    }
+
+    /// Create a new UDP socket binded to `addr`
+    ///
+    /// This will allow binding to `addr` that is not in local host
+    pub fn bind_nonlocal(ty: RedirType, addr: SocketAddr, _: &RedirSocketOpts) -> io::Result<UdpRedirSocket> {
+        UdpRedirSocket::bind(ty, addr, true)
+    }
+
+    fn bind(ty: RedirType, addr: SocketAddr, reuse_port: bool) -> io::Result<UdpRedirSocket> {
+        if ty != RedirType::PacketFilterDivert {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "not supported udp transparent proxy type",
+            ));
+        }
+
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        set_socket_before_bind(&addr, &socket)?;
+
+        socket.set_nonblocking(true)?;
+        socket.set_reuse_address(true)?;
+        if reuse_port {
+            if let Err(err) = socket.set_reuse_port(true) {
+                if let Some(libc::ENOPROTOOPT) = err.raw_os_error() {
+                    trace!("failed to set SO_REUSEPORT, error: {}", err);
+                } else {
+                    error!("failed to set SO_REUSEPORT, error: {}", err);
+                    return Err(err);
+                }
+            }
+        }
+
+        // OpenBSD doesn't support dual-stack sockets, IPv6 sockets are always IPV6_V6ONLY
+        socket.bind(&SockAddr::from(addr))?;
+
+        let io = AsyncFd::new(socket.into())?;
+        Ok(UdpRedirSocket { io })
+    }
+
+    /// Send data to the socket to the given target address
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_send_to(cx, buf, target)).await
+    }
+
+    fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<io::Result<usize>> {
+        loop {
+            let mut write_guard = ready!(self.io.poll_write_ready(cx))?;
+
+            match self.io.get_ref().send_to(buf, target) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    write_guard.clear_ready();
+                }
+                x => return Poll::Ready(x),
+            }
+        }
+    }
+
+    /// Returns the local address that this socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.get_ref().local_addr()
+    }
+}
+
+impl UdpSocketRedir for UdpRedirSocket {
+    fn poll_recv_dest_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr, SocketAddr)>> {
+        loop {
+            let mut read_guard = ready!(self.io.poll_read_ready(cx))?;
+
+            match recv_dest_from(self.io.get_ref(), buf) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    read_guard.clear_ready();
+                }
+                x => return Poll::Ready(x),
+            }
+        }
+    }
+}
+
+fn setsockopt_enable(socket: &Socket, level: libc::c_int, opt: libc::c_int) -> io::Result<()> {
+    let enable: libc::c_int = 1;
+
+    unsafe {
+        let ret = libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            opt,
+            &enable as *const _ as *const _,
+            mem::size_of_val(&enable) as libc::socklen_t,
+        );
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+fn set_socket_before_bind(addr: &SocketAddr, socket: &Socket) -> io::Result<()> {
+    // 1. BINDANY, for sending back with original destinations as sources
+    //
+    // https://man.openbsd.org/getsockopt.2
+    setsockopt_enable(socket, libc::SOL_SOCKET, libc::SO_BINDANY)?;
+
+    // 2. receive original destination address and port of diverted packets
+    //
+    // https://man.openbsd.org/ip.4 , https://man.openbsd.org/ip6.4
+    match *addr {
+        SocketAddr::V4(..) => {
+            setsockopt_enable(socket, libc::IPPROTO_IP, IP_RECVDSTADDR)?;
+            setsockopt_enable(socket, libc::IPPROTO_IP, IP_RECVDSTPORT)?;
+        }
+        SocketAddr::V6(..) => {
+            setsockopt_enable(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO)?;
+            setsockopt_enable(socket, libc::IPPROTO_IPV6, IPV6_RECVDSTPORT)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn get_destination_addr(msg: &libc::msghdr) -> io::Result<SocketAddr> {
+    // Called `recvmsg` with `IP_RECVDSTADDR` and `IP_RECVDSTPORT` (or IPv6 equivalents) set
+    //
+    // Ports are in network byte order
+
+    let mut ipv4_addr = None;
+    let mut ipv6_addr = None;
+    let mut port = None;
+
+    unsafe {
+        let mut cmsg: *mut libc::cmsghdr = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            let rcmsg = &*cmsg;
+            match (rcmsg.cmsg_level, rcmsg.cmsg_type) {
+                (libc::IPPROTO_IP, IP_RECVDSTADDR) => {
+                    let mut octets = [0u8; 4];
+                    ptr::copy_nonoverlapping(libc::CMSG_DATA(cmsg), octets.as_mut_ptr(), octets.len());
+                    ipv4_addr = Some(Ipv4Addr::from(octets));
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                    // struct in6_pktinfo starts with ipi6_addr
+                    let mut octets = [0u8; 16];
+                    ptr::copy_nonoverlapping(libc::CMSG_DATA(cmsg), octets.as_mut_ptr(), octets.len());
+                    ipv6_addr = Some(Ipv6Addr::from(octets));
+                }
+                (libc::IPPROTO_IP, IP_RECVDSTPORT) | (libc::IPPROTO_IPV6, IPV6_RECVDSTPORT) => {
+                    let mut be_port = [0u8; 2];
+                    ptr::copy_nonoverlapping(libc::CMSG_DATA(cmsg), be_port.as_mut_ptr(), be_port.len());
+                    port = Some(u16::from_be_bytes(be_port));
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+    }
+
+    match (ipv4_addr, ipv6_addr, port) {
+        (Some(ip), _, Some(port)) => Ok(SocketAddr::V4(SocketAddrV4::new(ip, port))),
+        (_, Some(ip), Some(port)) => Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0))),
+        _ => {
+            let err = Error::new(ErrorKind::InvalidData, "missing destination address in msghdr");
+            Err(err)
+        }
+    }
+}
+
+fn recv_dest_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    unsafe {
+        let mut control_buf = [0u8; 128];
+        let mut src_addr: libc::sockaddr_storage = mem::zeroed();
+
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_name = &mut src_addr as *mut _ as *mut _;
+        msg.msg_namelen = mem::size_of_val(&src_addr) as libc::socklen_t;
+
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut _,
+            iov_len: buf.len() as libc::size_t,
+        };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+
+        msg.msg_control = control_buf.as_mut_ptr() as *mut _;
+        msg.msg_controllen = control_buf.len() as libc::socklen_t;
+
+        let fd = socket.as_raw_fd();
+        let ret = libc::recvmsg(fd, &mut msg, 0);
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let (_, src_saddr) = SockAddr::try_init(|a, l| {
+            ptr::copy_nonoverlapping(msg.msg_name, a as *mut _, msg.msg_namelen as usize);
+            *l = msg.msg_namelen;
+            Ok(())
+        })?;
+
+        Ok((
+            ret as usize,
+            src_saddr.as_socket().expect("SocketAddr"),
+            get_destination_addr(&msg)?,
+        ))
+    }
+}
@@ -3,17 +3,28 @@
 use std::{
     collections::HashMap,
     fs::OpenOptions,
-    io::{self, ErrorKind, Read},
+    io::{self, Read},
     path::Path,
+    sync::Arc,
 };
 
 use log::trace;
 use serde::Deserialize;
 
+use crate::{acl::AccessControl, net::rate_limit::RateLimiter};
+
 #[derive(Deserialize, Debug)]
 struct SSSocks5AuthPasswordUserConfig {
     user_name: String,
     password: String,
+    #[serde(default)]
+    acl: Option<String>,
+    #[serde(default)]
+    server_group: Option<String>,
+    #[serde(default)]
+    upload_kbps: Option<u64>,
+    #[serde(default)]
+    download_kbps: Option<u64>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -31,6 +42,8 @@ struct SSSocks5AuthConfig {
 #[derive(Debug, Clone)]
 pub struct Socks5AuthConfig {
     pub passwd: Socks5AuthPasswdConfig,
+    /// Interpret `USER@HINT` user names as routing hints, see [`Socks5RouteHint`]
+    pub username_routing: bool,
 }
 
 impl Socks5AuthConfig {
@@ -38,6 +51,7 @@ impl Socks5AuthConfig {
     pub fn new() -> Socks5AuthConfig {
         Socks5AuthConfig {
             passwd: Socks5AuthPasswdConfig::new(),
+            username_routing: false,
         }
     }
 
@@ -49,7 +63,12 @@ impl Socks5AuthConfig {
     ///         "users": [
     ///             {
     ///                 "user_name": "USER_NAME",
-    ///                 "password": "PASSWORD"
+    ///                 "password": "PASSWORD",
+    ///                 // Optional policies of this user
+    ///                 "acl": "/path/to/user.acl",
+    ///                 "server_group": "GROUP",
+    ///                 "upload_kbps": 10000,
+    ///                 "download_kbps": 50000
     ///             }
     ///         ]
     ///      }
@@ -68,17 +87,57 @@ impl Socks5AuthConfig {
 
         let jconf: SSSocks5AuthConfig = match json5::from_str(&content) {
             Ok(c) => c,
-            Err(err) => return Err(io::Error::new(ErrorKind::Other, err)),
+            Err(err) => return Err(io::Error::other(err)),
         };
 
         let mut passwd = Socks5AuthPasswdConfig::new();
         if let Some(p) = jconf.password {
             for user in p.users {
-                passwd.add_user(user.user_name, user.password);
+                if user.upload_kbps == Some(0) || user.download_kbps == Some(0) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "upload_kbps and download_kbps of user {} must be greater than 0",
+                            user.user_name
+                        ),
+                    ));
+                }
+
+                let acl = match user.acl {
+                    Some(acl_path) => match AccessControl::load_from_file(&acl_path) {
+                        Ok(acl) => Some(Arc::new(acl)),
+                        Err(err) => {
+                            return Err(io::Error::new(
+                                err.kind(),
+                                format!(
+                                    "failed to load acl {} of user {}, error: {}",
+                                    acl_path, user.user_name, err
+                                ),
+                            ));
+                        }
+                    },
+                    None => None,
+                };
+                let rate_limiter = if user.upload_kbps.is_some() || user.download_kbps.is_some() {
+                    Some(Arc::new(RateLimiter::new(user.upload_kbps, user.download_kbps)))
+                } else {
+                    None
+                };
+
+                let policy = Socks5UserPolicy {
+                    acl,
+                    server_group: user.server_group,
+                    rate_limiter,
+                    bypass: false,
+                };
+                passwd.add_user_with_policy(user.user_name, user.password, policy);
             }
         }
 
-        Ok(Socks5AuthConfig { passwd })
+        Ok(Socks5AuthConfig {
+            passwd,
+            username_routing: false,
+        })
     }
 
     /// Check if authentication is required
@@ -93,18 +152,87 @@ impl Default for Socks5AuthConfig {
     }
 }
 
+/// Policies of a SOCKS5 user, applied to its TCP connections
+#[derive(Debug, Clone, Default)]
+pub struct Socks5UserPolicy {
+    /// Proxy / bypass rules replacing the global ACL, targets in `[outbound_block_list]` are rejected
+    pub acl: Option<Arc<AccessControl>>,
+    /// Connections are only sent through servers in this group
+    pub server_group: Option<String>,
+    /// Bandwidth shared by all connections of the user
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// Connections are made directly without servers, chosen by a routing hint
+    pub bypass: bool,
+}
+
+impl Socks5UserPolicy {
+    /// Check if there is no policy
+    pub fn is_empty(&self) -> bool {
+        self.acl.is_none() && self.server_group.is_none() && self.rate_limiter.is_none() && !self.bypass
+    }
+
+    /// Apply a routing hint of the user name
+    ///
+    /// Hints couldn't escape the `server_group` that the user is restricted to, they are ignored for such users.
+    pub fn with_route_hint(&self, hint: &Socks5RouteHint) -> Option<Socks5UserPolicy> {
+        if self.server_group.is_some() {
+            return None;
+        }
+
+        let mut policy = self.clone();
+        match *hint {
+            Socks5RouteHint::Direct => policy.bypass = true,
+            Socks5RouteHint::Group(ref group) => policy.server_group = Some(group.clone()),
+        }
+        Some(policy)
+    }
+}
+
+/// Routing hint in SOCKS5 user names, like `USER@tokyo`
+///
+/// Applications could choose their exits through one SOCKS5 listener with different user names. Only TCP CONNECTs
+/// are routed by hints.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Socks5RouteHint {
+    /// `USER@direct`, connect to targets directly
+    Direct,
+    /// `USER@GROUP`, connect through servers in the group
+    Group(String),
+}
+
+impl Socks5RouteHint {
+    /// Split `USER@HINT` into the user and its hint, at the last `@`
+    pub fn split_user_name(user_name: &str) -> Option<(&str, Socks5RouteHint)> {
+        let (user, hint) = user_name.rsplit_once('@')?;
+        if hint.is_empty() {
+            return None;
+        }
+
+        let hint = if hint.eq_ignore_ascii_case("direct") {
+            Socks5RouteHint::Direct
+        } else {
+            Socks5RouteHint::Group(hint.to_owned())
+        };
+        Some((user, hint))
+    }
+}
+
 /// SOCKS5 server User/Password Authentication configuration
 ///
 /// RFC1929 https://datatracker.ietf.org/doc/html/rfc1929
 #[derive(Debug, Clone)]
 pub struct Socks5AuthPasswdConfig {
     passwd: HashMap<String, String>,
+    policies: HashMap<String, Arc<Socks5UserPolicy>>,
 }
 
 impl Socks5AuthPasswdConfig {
     /// Create an empty `Passwd` configuration
     pub fn new() -> Socks5AuthPasswdConfig {
-        Socks5AuthPasswdConfig { passwd: HashMap::new() }
+        Socks5AuthPasswdConfig {
+            passwd: HashMap::new(),
+            policies: HashMap::new(),
+        }
     }
 
     /// Add a user with password
@@ -113,7 +241,32 @@ impl Socks5AuthPasswdConfig {
         U: Into<String>,
         P: Into<String>,
     {
-        self.passwd.insert(user_name.into(), password.into());
+        let user_name = user_name.into();
+        self.policies.remove(&user_name);
+        self.passwd.insert(user_name, password.into());
+    }
+
+    /// Add a user with password and policies
+    pub fn add_user_with_policy<U, P>(&mut self, user_name: U, password: P, policy: Socks5UserPolicy)
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        let user_name = user_name.into();
+        if policy.is_empty() {
+            self.policies.remove(&user_name);
+        } else {
+            self.policies.insert(user_name.clone(), Arc::new(policy));
+        }
+        self.passwd.insert(user_name, password.into());
+    }
+
+    /// Policies of `user_name`, `None` if the user has no policy
+    pub fn user_policy<U>(&self, user_name: U) -> Option<&Arc<Socks5UserPolicy>>
+    where
+        U: AsRef<str>,
+    {
+        self.policies.get(user_name.as_ref())
     }
 
     /// Check if `user_name` exists and validate `password`
@@ -128,6 +281,11 @@ impl Socks5AuthPasswdConfig {
         }
     }
 
+    /// Iterate user names and passwords
+    pub fn users(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.passwd.iter().map(|(u, p)| (u.as_str(), p.as_str()))
+    }
+
     /// Total users
     pub fn total_users(&self) -> usize {
         self.passwd.len()
@@ -1,11 +1,19 @@
 //! Shadowsocks SOCKS Local Server
 
-use std::{io, sync::Arc, time::Duration};
+use std::{collections::HashMap, io, net::IpAddr, sync::Arc, time::Duration};
 
 use futures::{future, FutureExt};
 use shadowsocks::{config::Mode, ServerAddr};
+use tokio::sync::mpsc;
 
-use crate::local::{context::ServiceContext, loadbalancing::PingBalancer};
+#[cfg(feature = "local-http")]
+use crate::local::http::HttpAuthConfig;
+#[cfg(feature = "local-tls")]
+use crate::local::net::tls::TlsListenerConfig;
+use crate::{
+    config::InboundLimitConfig,
+    local::{context::ServiceContext, loadbalancing::PingBalancer, net::ipc::IpcAddr},
+};
 
 pub use self::server::{SocksTcpServer, SocksTcpServerBuilder, SocksUdpServer};
 use self::socks5::Socks5UdpServerBuilder;
@@ -18,6 +26,9 @@ mod server;
 mod socks4;
 mod socks5;
 
+/// Pending `UDP ASSOCIATE` requests waiting for creating associations in advance
+const UDP_PREBIND_CHANNEL_SIZE: usize = 64;
+
 /// SOCKS4/4a, SOCKS5 Local Server builder
 pub struct SocksBuilder {
     context: Arc<ServiceContext>,
@@ -25,7 +36,15 @@ pub struct SocksBuilder {
     udp_expiry_duration: Option<Duration>,
     udp_capacity: Option<usize>,
     udp_bind_addr: Option<ServerAddr>,
+    udp_prebind: bool,
+    udp_external_addrs: HashMap<IpAddr, IpAddr>,
     socks5_auth: Socks5AuthConfig,
+    #[cfg(feature = "local-http")]
+    http_auth: Option<HttpAuthConfig>,
+    #[cfg(feature = "local-tls")]
+    tls: Option<TlsListenerConfig>,
+    ipc_addr: Option<IpcAddr>,
+    inbound_limit: InboundLimitConfig,
     client_config: ServerAddr,
     balancer: PingBalancer,
     #[cfg(target_os = "macos")]
@@ -53,7 +72,15 @@ impl SocksBuilder {
             udp_expiry_duration: None,
             udp_capacity: None,
             udp_bind_addr: None,
+            udp_prebind: false,
+            udp_external_addrs: HashMap::new(),
             socks5_auth: Socks5AuthConfig::default(),
+            #[cfg(feature = "local-http")]
+            http_auth: None,
+            #[cfg(feature = "local-tls")]
+            tls: None,
+            ipc_addr: None,
+            inbound_limit: InboundLimitConfig::default(),
             client_config,
             balancer,
             #[cfg(target_os = "macos")]
@@ -86,11 +113,51 @@ impl SocksBuilder {
         self.udp_bind_addr = Some(a);
     }
 
+    /// Create UDP association as soon as a SOCKS5 `UDP ASSOCIATE` request arrives, instead of on its first packet
+    ///
+    /// Only works if client fills its UDP address in the request
+    pub fn set_udp_prebind(&mut self, prebind: bool) {
+        self.udp_prebind = prebind;
+    }
+
+    /// Reply external addresses of local addresses that clients connected to for SOCKS5 `UDP ASSOCIATE` requests
+    ///
+    /// For multi-homed hosts, clients on each interface get a reachable address of the UDP relay
+    pub fn set_udp_external_addrs(&mut self, addrs: HashMap<IpAddr, IpAddr>) {
+        self.udp_external_addrs = addrs;
+    }
+
     /// Set SOCKS5 Username/Password Authentication configuration
     pub fn set_socks5_auth(&mut self, p: Socks5AuthConfig) {
         self.socks5_auth = p;
     }
 
+    /// Set HTTP Proxy-Authorization configuration of HTTP clients on the same port
+    ///
+    /// Users of SOCKS5 authentication are used if not set
+    #[cfg(feature = "local-http")]
+    pub fn set_http_auth(&mut self, auth: HttpAuthConfig) {
+        self.http_auth = Some(auth);
+    }
+
+    /// Accept TCP clients with TLS, UDP relay is not affected
+    #[cfg(feature = "local-tls")]
+    pub fn set_tls(&mut self, tls: TlsListenerConfig) {
+        self.tls = Some(tls);
+    }
+
+    /// Accept clients on a Unix domain socket or Windows named pipe, instead of the TCP `client_config`
+    ///
+    /// UDP relay should be disabled with `Mode::TcpOnly`, it has no address for clients
+    pub fn set_ipc_addr(&mut self, addr: IpcAddr) {
+        self.ipc_addr = Some(addr);
+    }
+
+    /// Limit concurrent and new TCP connections of clients
+    pub fn set_inbound_limit(&mut self, limit: InboundLimitConfig) {
+        self.inbound_limit = limit;
+    }
+
     /// macOS launchd activate socket
     #[cfg(target_os = "macos")]
     pub fn set_launchd_tcp_socket_name(&mut self, n: String) {
@@ -106,6 +173,13 @@ impl SocksBuilder {
     pub async fn build(self) -> io::Result<Socks> {
         let udp_bind_addr = self.udp_bind_addr.unwrap_or_else(|| self.client_config.clone());
 
+        let (udp_prebind_tx, udp_prebind_rx) = if self.udp_prebind && self.mode.enable_udp() && self.mode.enable_tcp() {
+            let (tx, rx) = mpsc::channel(UDP_PREBIND_CHANNEL_SIZE);
+            (Some(tx), Some(rx))
+        } else {
+            (None, None)
+        };
+
         let mut udp_server = None;
         if self.mode.enable_udp() {
             #[allow(unused_mut)]
@@ -117,6 +191,10 @@ impl SocksBuilder {
                 self.balancer.clone(),
             );
 
+            if let Some(rx) = udp_prebind_rx {
+                builder.set_prebind_receiver(rx);
+            }
+
             #[cfg(target_os = "macos")]
             if let Some(s) = self.launchd_udp_socket_name {
                 builder.set_launchd_socket_name(s);
@@ -138,6 +216,26 @@ impl SocksBuilder {
                 self.socks5_auth,
             );
 
+            if let Some(tx) = udp_prebind_tx {
+                builder.set_udp_prebind_sender(tx);
+            }
+            builder.set_udp_external_addrs(self.udp_external_addrs);
+
+            #[cfg(feature = "local-http")]
+            if let Some(auth) = self.http_auth {
+                builder.set_http_auth(auth);
+            }
+
+            #[cfg(feature = "local-tls")]
+            if let Some(tls) = self.tls {
+                builder.set_tls(tls);
+            }
+
+            if let Some(addr) = self.ipc_addr {
+                builder.set_ipc_addr(addr);
+            }
+            builder.set_inbound_limit(self.inbound_limit);
+
             #[cfg(target_os = "macos")]
             if let Some(s) = self.launchd_tcp_socket_name {
                 builder.set_launchd_socket_name(s);
@@ -1,14 +1,39 @@
-use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 
-use log::{error, info};
+use log::{debug, error, info};
 use shadowsocks::{config::Mode, net::TcpListener as ShadowTcpListener, ServerAddr};
-use tokio::{net::TcpStream, time};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::TcpStream,
+    sync::mpsc,
+    time,
+};
+#[cfg(feature = "local-tls")]
+use tokio_rustls::TlsAcceptor;
 
 #[cfg(feature = "local-http")]
-use crate::local::http::HttpConnectionHandler;
-use crate::local::{
-    context::ServiceContext, loadbalancing::PingBalancer, net::tcp::listener::create_standard_tcp_listener,
-    socks::config::Socks5AuthConfig,
+use crate::local::http::{HttpAuthConfig, HttpConnectionHandler};
+#[cfg(feature = "local-tls")]
+use crate::local::net::tls::TlsListenerConfig;
+use crate::{
+    config::InboundLimitConfig,
+    local::{
+        context::ServiceContext,
+        loadbalancing::PingBalancer,
+        net::{
+            ipc::{IpcAddr, IpcListener, IPC_PEER_ADDR},
+            limit::InboundLimiter,
+            tcp::listener::create_standard_tcp_listener,
+            LocalStream,
+        },
+        socks::config::Socks5AuthConfig,
+    },
 };
 
 #[cfg(feature = "local-socks4")]
@@ -22,6 +47,14 @@ pub struct SocksTcpServerBuilder {
     balancer: PingBalancer,
     mode: Mode,
     socks5_auth: Arc<Socks5AuthConfig>,
+    udp_prebind_tx: Option<mpsc::Sender<SocketAddr>>,
+    udp_external_addrs: HashMap<IpAddr, IpAddr>,
+    #[cfg(feature = "local-http")]
+    http_auth: Option<HttpAuthConfig>,
+    #[cfg(feature = "local-tls")]
+    tls: Option<TlsListenerConfig>,
+    ipc_addr: Option<IpcAddr>,
+    inbound_limit: InboundLimitConfig,
     #[cfg(target_os = "macos")]
     launchd_socket_name: Option<String>,
 }
@@ -42,18 +75,73 @@ impl SocksTcpServerBuilder {
             balancer,
             mode,
             socks5_auth: Arc::new(socks5_auth),
+            udp_prebind_tx: None,
+            udp_external_addrs: HashMap::new(),
+            #[cfg(feature = "local-http")]
+            http_auth: None,
+            #[cfg(feature = "local-tls")]
+            tls: None,
+            ipc_addr: None,
+            inbound_limit: InboundLimitConfig::default(),
             #[cfg(target_os = "macos")]
             launchd_socket_name: None,
         }
     }
 
+    /// Send clients' addresses of `UDP ASSOCIATE` requests to the UDP server, for creating associations in advance
+    pub(crate) fn set_udp_prebind_sender(&mut self, tx: mpsc::Sender<SocketAddr>) {
+        self.udp_prebind_tx = Some(tx);
+    }
+
+    /// Reply external addresses of local addresses that clients connected to for `UDP ASSOCIATE` requests
+    pub(crate) fn set_udp_external_addrs(&mut self, addrs: HashMap<IpAddr, IpAddr>) {
+        self.udp_external_addrs = addrs;
+    }
+
+    /// HTTP Proxy-Authorization configuration of HTTP clients on the same port
+    #[cfg(feature = "local-http")]
+    pub(crate) fn set_http_auth(&mut self, auth: HttpAuthConfig) {
+        self.http_auth = Some(auth);
+    }
+
+    /// Accept clients with TLS
+    #[cfg(feature = "local-tls")]
+    pub(crate) fn set_tls(&mut self, tls: TlsListenerConfig) {
+        self.tls = Some(tls);
+    }
+
+    /// Listen on a Unix domain socket or Windows named pipe, instead of `client_config`
+    pub(crate) fn set_ipc_addr(&mut self, addr: IpcAddr) {
+        self.ipc_addr = Some(addr);
+    }
+
+    /// Limit concurrent and new connections of clients
+    pub(crate) fn set_inbound_limit(&mut self, limit: InboundLimitConfig) {
+        self.inbound_limit = limit;
+    }
+
     /// macOS launchd activate socket
     #[cfg(target_os = "macos")]
     pub fn set_launchd_socket_name(&mut self, n: String) {
         self.launchd_socket_name = Some(n);
     }
 
-    pub async fn build(self) -> io::Result<SocksTcpServer> {
+    pub async fn build(mut self) -> io::Result<SocksTcpServer> {
+        #[cfg(feature = "local-tls")]
+        let tls_acceptor = match self.tls {
+            Some(ref tls) => Some(tls.load_acceptor()?),
+            None => None,
+        };
+
+        if let Some(addr) = self.ipc_addr.take() {
+            let listener = SocksListener::Ipc(IpcListener::bind(addr)?);
+            return Ok(self.build_with_listener(
+                listener,
+                #[cfg(feature = "local-tls")]
+                tls_acceptor,
+            ));
+        }
+
         cfg_if::cfg_if! {
             if #[cfg(target_os = "macos")] {
                 let listener = if let Some(launchd_socket_name) = self.launchd_socket_name {
@@ -71,113 +159,266 @@ impl SocksTcpServerBuilder {
             }
         }
 
-        Ok(SocksTcpServer {
+        Ok(self.build_with_listener(
+            SocksListener::Tcp(listener),
+            #[cfg(feature = "local-tls")]
+            tls_acceptor,
+        ))
+    }
+
+    fn build_with_listener(
+        self,
+        listener: SocksListener,
+        #[cfg(feature = "local-tls")] tls_acceptor: Option<TlsAcceptor>,
+    ) -> SocksTcpServer {
+        SocksTcpServer {
             context: self.context,
             listener,
             udp_bind_addr: self.udp_bind_addr,
             balancer: self.balancer,
             mode: self.mode,
             socks5_auth: self.socks5_auth,
-        })
+            udp_prebind_tx: self.udp_prebind_tx,
+            udp_external_addrs: Arc::new(self.udp_external_addrs),
+            #[cfg(feature = "local-http")]
+            http_auth: self.http_auth,
+            #[cfg(feature = "local-tls")]
+            tls_acceptor,
+            inbound_limiter: if self.inbound_limit.is_empty() {
+                None
+            } else {
+                Some(Arc::new(InboundLimiter::new(self.inbound_limit)))
+            },
+        }
     }
 }
 
+enum SocksListener {
+    Tcp(ShadowTcpListener),
+    Ipc(IpcListener),
+}
+
 /// SOCKS TCP server instance
 pub struct SocksTcpServer {
     context: Arc<ServiceContext>,
-    listener: ShadowTcpListener,
+    listener: SocksListener,
     udp_bind_addr: ServerAddr,
     balancer: PingBalancer,
     mode: Mode,
     socks5_auth: Arc<Socks5AuthConfig>,
+    udp_prebind_tx: Option<mpsc::Sender<SocketAddr>>,
+    udp_external_addrs: Arc<HashMap<IpAddr, IpAddr>>,
+    #[cfg(feature = "local-http")]
+    http_auth: Option<HttpAuthConfig>,
+    #[cfg(feature = "local-tls")]
+    tls_acceptor: Option<TlsAcceptor>,
+    inbound_limiter: Option<Arc<InboundLimiter>>,
 }
 
 impl SocksTcpServer {
     /// Get TCP server local addr
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.listener.local_addr()
+        match self.listener {
+            SocksListener::Tcp(ref listener) => listener.local_addr(),
+            SocksListener::Ipc(ref listener) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("listening on {}", listener.addr()),
+            )),
+        }
     }
 
     /// Start TCP accept loop
     pub async fn run(self) -> io::Result<()> {
-        info!("shadowsocks socks TCP listening on {}", self.listener.local_addr()?);
+        match self.listener {
+            SocksListener::Tcp(ref listener) => {
+                info!("shadowsocks socks TCP listening on {}", listener.local_addr()?)
+            }
+            SocksListener::Ipc(ref listener) => info!("shadowsocks socks listening on {}", listener.addr()),
+        }
 
         // If UDP is enabled, SOCK5 UDP_ASSOCIATE command will let client to send requests to this address
         let udp_bind_addr = Arc::new(self.udp_bind_addr);
         #[cfg(feature = "local-http")]
-        let http_handler = HttpConnectionHandler::new(self.context.clone(), self.balancer.clone());
-
-        loop {
-            let (stream, peer_addr) = match self.listener.accept().await {
-                Ok(s) => s,
-                Err(err) => {
-                    error!("accept failed with error: {}", err);
-                    time::sleep(Duration::from_secs(1)).await;
-                    continue;
-                }
+        let http_handler = {
+            let mut handler = HttpConnectionHandler::new(self.context.clone(), self.balancer.clone());
+            // HTTP requests on the SOCKS port are authenticated by the same users, unless configured separately
+            let auth = match self.http_auth {
+                Some(auth) => auth,
+                None => HttpAuthConfig::from_socks5_auth(&self.socks5_auth),
             };
+            handler.set_auth(auth);
+            handler.set_mode(self.mode);
+            handler
+        };
 
-            let handler = SocksTcpHandler {
-                context: self.context.clone(),
-                udp_bind_addr: udp_bind_addr.clone(),
-                stream,
-                balancer: self.balancer.clone(),
-                peer_addr,
-                mode: self.mode,
-                socks5_auth: self.socks5_auth.clone(),
-                #[cfg(feature = "local-http")]
-                http_handler: http_handler.clone(),
-            };
+        let handler = SocksTcpHandler {
+            context: self.context,
+            udp_bind_addr,
+            balancer: self.balancer,
+            peer_addr: IPC_PEER_ADDR,
+            mode: self.mode,
+            socks5_auth: self.socks5_auth,
+            udp_prebind_tx: self.udp_prebind_tx,
+            udp_external_addrs: self.udp_external_addrs,
+            #[cfg(feature = "local-http")]
+            http_handler,
+            #[cfg(feature = "local-tls")]
+            tls_acceptor: self.tls_acceptor,
+        };
 
-            tokio::spawn(async move {
-                if let Err(err) = handler.handle_tcp_client().await {
-                    error!("socks5 tcp client handler error: {}", err);
-                }
-            });
+        match self.listener {
+            SocksListener::Tcp(listener) => loop {
+                let (stream, peer_addr) = match listener.accept().await {
+                    Ok(s) => s,
+                    Err(err) => {
+                        error!("accept failed with error: {}", err);
+                        time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                let permit = match self.inbound_limiter {
+                    None => None,
+                    Some(ref limiter) => match limiter.acquire(&peer_addr) {
+                        Ok(permit) => Some(permit),
+                        Err(reason) => {
+                            debug!("socks rejected {}, {}", peer_addr, reason);
+                            continue;
+                        }
+                    },
+                };
+
+                let mut handler = handler.clone();
+                handler.peer_addr = peer_addr;
+
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    if let Err(err) = handler.handle_tcp_client(stream).await {
+                        error!("socks5 tcp client handler error: {}", err);
+                    }
+                });
+            },
+            SocksListener::Ipc(mut listener) => loop {
+                let stream = match listener.accept().await {
+                    Ok(s) => s,
+                    Err(err) => {
+                        error!("accept failed with error: {}", err);
+                        time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                let permit = match self.inbound_limiter {
+                    None => None,
+                    Some(ref limiter) => match limiter.acquire(&IPC_PEER_ADDR) {
+                        Ok(permit) => Some(permit),
+                        Err(reason) => {
+                            debug!("socks rejected client on {}, {}", listener.addr(), reason);
+                            continue;
+                        }
+                    },
+                };
+
+                let handler = handler.clone();
+
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    if let Err(err) = handler.handle_buffered_client(stream).await {
+                        error!("socks5 tcp client handler error: {}", err);
+                    }
+                });
+            },
         }
     }
 }
 
+#[derive(Clone)]
 struct SocksTcpHandler {
     context: Arc<ServiceContext>,
     udp_bind_addr: Arc<ServerAddr>,
-    stream: TcpStream,
     balancer: PingBalancer,
     peer_addr: SocketAddr,
     mode: Mode,
     socks5_auth: Arc<Socks5AuthConfig>,
+    udp_prebind_tx: Option<mpsc::Sender<SocketAddr>>,
+    udp_external_addrs: Arc<HashMap<IpAddr, IpAddr>>,
     #[cfg(feature = "local-http")]
     http_handler: HttpConnectionHandler,
+    #[cfg(feature = "local-tls")]
+    tls_acceptor: Option<TlsAcceptor>,
 }
 
 impl SocksTcpHandler {
+    async fn handle_tcp_client(self, stream: TcpStream) -> io::Result<()> {
+        #[cfg(feature = "local-tls")]
+        if self.tls_acceptor.is_some() {
+            return self.handle_buffered_client(stream).await;
+        }
+
+        #[cfg(any(feature = "local-socks4", feature = "local-http"))]
+        let version = {
+            let mut version_buffer = [0u8; 1];
+            let n = stream.peek(&mut version_buffer).await?;
+            if n == 0 {
+                None
+            } else {
+                Some(version_buffer[0])
+            }
+        };
+        #[cfg(not(any(feature = "local-socks4", feature = "local-http")))]
+        let version = Some(0x05);
+
+        self.dispatch(stream, version).await
+    }
+
+    /// Clients of TLS or IPC, which can't be peeked, so the first byte is kept in buffer
+    async fn handle_buffered_client<S: LocalStream>(self, stream: S) -> io::Result<()> {
+        #[cfg(feature = "local-tls")]
+        if let Some(ref tls_acceptor) = self.tls_acceptor {
+            let stream = match tls_acceptor.accept(stream).await {
+                Ok(s) => s,
+                Err(err) => {
+                    debug!("socks TLS handshake with {} failed, error: {}", self.peer_addr, err);
+                    return Ok(());
+                }
+            };
+
+            let mut stream = BufReader::new(stream);
+            let version = stream.fill_buf().await?.first().copied();
+            return self.dispatch(stream, version).await;
+        }
+
+        let mut stream = BufReader::new(stream);
+        let version = stream.fill_buf().await?.first().copied();
+        self.dispatch(stream, version).await
+    }
+
     #[cfg(not(any(feature = "local-socks4", feature = "local-http")))]
-    async fn handle_tcp_client(self) -> io::Result<()> {
+    async fn dispatch<S: LocalStream>(self, stream: S, _version: Option<u8>) -> io::Result<()> {
         let handler = Socks5TcpHandler::new(
             self.context,
             self.udp_bind_addr,
             self.balancer,
             self.mode,
             self.socks5_auth,
+            self.udp_prebind_tx,
+            self.udp_external_addrs,
         );
-        handler.handle_socks5_client(self.stream, self.peer_addr).await
+        handler.handle_socks5_client(stream, self.peer_addr).await
     }
 
     #[cfg(any(feature = "local-socks4", feature = "local-http"))]
-    async fn handle_tcp_client(self) -> io::Result<()> {
-        use std::io::ErrorKind;
-
-        let mut version_buffer = [0u8; 1];
-        let n = self.stream.peek(&mut version_buffer).await?;
-        if n == 0 {
-            return Err(ErrorKind::UnexpectedEof.into());
-        }
+    async fn dispatch<S: LocalStream>(self, stream: S, version: Option<u8>) -> io::Result<()> {
+        let version = match version {
+            Some(v) => v,
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        };
 
-        match version_buffer[0] {
+        match version {
             #[cfg(feature = "local-socks4")]
             0x04 => {
-                let handler = Socks4TcpHandler::new(self.context, self.balancer, self.mode);
-                handler.handle_socks4_client(self.stream, self.peer_addr).await
+                let handler = Socks4TcpHandler::new(self.context, self.balancer, self.mode, self.socks5_auth);
+                handler.handle_socks4_client(stream, self.peer_addr).await
             }
 
             0x05 => {
@@ -187,25 +428,27 @@ impl SocksTcpHandler {
                     self.balancer,
                     self.mode,
                     self.socks5_auth,
+                    self.udp_prebind_tx,
+                    self.udp_external_addrs,
                 );
-                handler.handle_socks5_client(self.stream, self.peer_addr).await
+                handler.handle_socks5_client(stream, self.peer_addr).await
             }
 
             #[cfg(feature = "local-http")]
             b'G' | b'g' | b'H' | b'h' | b'P' | b'p' | b'D' | b'd' | b'C' | b'c' | b'O' | b'o' | b'T' | b't' => {
                 // GET, HEAD, POST, PUT, DELETE, CONNECT, OPTIONS, TRACE, PATCH
-                match self.http_handler.serve_connection(self.stream, self.peer_addr).await {
+                match self.http_handler.serve_connection(stream, self.peer_addr).await {
                     Ok(..) => Ok(()),
                     Err(err) => {
                         error!("HTTP connection {} handler failed with error: {}", self.peer_addr, err);
-                        Err(io::Error::new(ErrorKind::Other, err))
+                        Err(io::Error::other(err))
                     }
                 }
             }
 
             version => {
                 error!("unsupported socks version {:x}", version);
-                let err = io::Error::new(ErrorKind::Other, "unsupported socks version");
+                let err = io::Error::other("unsupported socks version");
                 Err(err)
             }
         }
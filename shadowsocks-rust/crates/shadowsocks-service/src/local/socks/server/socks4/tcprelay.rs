@@ -8,15 +8,13 @@ use std::{
 
 use log::{debug, error, trace, warn};
 use shadowsocks::config::Mode;
-use tokio::{
-    io::{AsyncWriteExt, BufReader},
-    net::TcpStream,
-};
+use tokio::io::{AsyncWriteExt, BufReader};
 
 use crate::local::{
     context::ServiceContext,
     loadbalancing::PingBalancer,
-    net::AutoProxyClientStream,
+    net::{special_target::SpecialTargetError, AutoProxyClientStream, LocalStream},
+    socks::config::Socks5AuthConfig,
     utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
 };
 
@@ -28,18 +26,25 @@ pub struct Socks4TcpHandler {
     context: Arc<ServiceContext>,
     balancer: PingBalancer,
     mode: Mode,
+    auth: Arc<Socks5AuthConfig>,
 }
 
 impl Socks4TcpHandler {
-    pub fn new(context: Arc<ServiceContext>, balancer: PingBalancer, mode: Mode) -> Socks4TcpHandler {
+    pub fn new(
+        context: Arc<ServiceContext>,
+        balancer: PingBalancer,
+        mode: Mode,
+        auth: Arc<Socks5AuthConfig>,
+    ) -> Socks4TcpHandler {
         Socks4TcpHandler {
             context,
             balancer,
             mode,
+            auth,
         }
     }
 
-    pub async fn handle_socks4_client(self, stream: TcpStream, peer_addr: SocketAddr) -> io::Result<()> {
+    pub async fn handle_socks4_client<S: LocalStream>(self, stream: S, peer_addr: SocketAddr) -> io::Result<()> {
         // 1. Handshake
 
         // NOTE: Wraps it with BufReader for reading NULL terminated information in HandshakeRequest
@@ -58,6 +63,19 @@ impl Socks4TcpHandler {
 
         trace!("socks4 {:?} peer: {}", handshake_req, peer_addr);
 
+        // SOCKS4 has no password authentication, clients must not bypass it
+        if self.auth.auth_required() {
+            warn!(
+                "socks4 request from {} rejected, socks5 authentication is required",
+                peer_addr
+            );
+
+            let handshake_rsp = HandshakeResponse::new(ResultCode::RequestRejectedOrFailed);
+            handshake_rsp.write_to(&mut s).await?;
+
+            return Ok(());
+        }
+
         match handshake_req.cd {
             Command::Connect => {
                 debug!("CONNECT {}", handshake_req.dst);
@@ -75,9 +93,9 @@ impl Socks4TcpHandler {
         }
     }
 
-    async fn handle_socks4_connect(
+    async fn handle_socks4_connect<S: LocalStream>(
         self,
-        mut stream: BufReader<TcpStream>,
+        mut stream: BufReader<S>,
         peer_addr: SocketAddr,
         target_addr: Address,
     ) -> io::Result<()> {
@@ -91,11 +109,14 @@ impl Socks4TcpHandler {
         }
 
         let target_addr = target_addr.into();
+        let nodelay = self.context.target_nodelay(&target_addr);
+        let context = self.context.clone();
+
         let mut server_opt = None;
         let server_result = if self.balancer.is_empty() {
             AutoProxyClientStream::connect_bypassed(self.context, &target_addr).await
         } else {
-            let server = self.balancer.best_tcp_server();
+            let server = self.balancer.best_tcp_server_for_client(&peer_addr, &target_addr);
 
             let r = AutoProxyClientStream::connect_with_opts(
                 self.context,
@@ -120,6 +141,10 @@ impl Socks4TcpHandler {
                 remote
             }
             Err(err) => {
+                if SpecialTargetError::is_dropped(&err) {
+                    return Err(err);
+                }
+
                 let result_code = match err.kind() {
                     ErrorKind::ConnectionRefused => ResultCode::RequestRejectedCannotConnect,
                     ErrorKind::ConnectionAborted => ResultCode::RequestRejectedCannotConnect,
@@ -142,12 +167,16 @@ impl Socks4TcpHandler {
         // UNWRAP.
         let mut stream = stream.into_inner();
 
+        if let Some(nodelay) = nodelay {
+            stream.set_nodelay(nodelay)?;
+        }
+
         match server_opt {
             Some(server) => {
                 let svr_cfg = server.server_config();
-                establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, &target_addr).await
+                establish_tcp_tunnel(&context, svr_cfg, &mut stream, &mut remote, peer_addr, &target_addr).await
             }
-            None => establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, &target_addr).await,
+            None => establish_tcp_tunnel_bypassed(&context, &mut stream, &mut remote, peer_addr, &target_addr).await,
         }
     }
 }
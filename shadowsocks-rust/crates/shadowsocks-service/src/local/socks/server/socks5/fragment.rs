@@ -0,0 +1,270 @@
+//! Reassembly of fragmented SOCKS5 UDP datagrams
+//!
+//! RFC 1928, section 7. `FRAG` of each fragment is its position (1 ~ 127) in the sequence, with the high-order bit set
+//! on the last one. A sequence is abandoned if it isn't completed in time, or any of its fragments is lost.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+use log::debug;
+use shadowsocks::relay::{socks5::Address, udprelay::MAXIMUM_UDP_PAYLOAD_SIZE};
+
+/// Reassembly timer of a sequence, RFC 1928 requires no less than 5 seconds
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Maximum clients reassembling at the same time
+const MAX_REASSEMBLY_QUEUES: usize = 256;
+
+const FRAG_END_FLAG: u8 = 0x80;
+const FRAG_POSITION_MASK: u8 = 0x7F;
+
+struct ReassemblyQueue {
+    address: Address,
+    position: u8,
+    payload: BytesMut,
+    start: Instant,
+}
+
+/// Reassembly queues of clients of a SOCKS5 UDP server
+#[derive(Default)]
+pub struct FragmentReassembler {
+    queues: HashMap<SocketAddr, ReassemblyQueue>,
+}
+
+impl FragmentReassembler {
+    /// Abandon the incomplete sequence of `peer_addr`, when it sends a standalone datagram (`FRAG` is 0)
+    pub fn abandon(&mut self, peer_addr: &SocketAddr) {
+        if !self.queues.is_empty() && self.queues.remove(peer_addr).is_some() {
+            debug!("udp fragments from {} abandoned by a standalone datagram", peer_addr);
+        }
+    }
+
+    /// Queue a fragment from `peer_addr`, returns the target address and the whole datagram after the last one
+    pub fn push(&mut self, peer_addr: SocketAddr, frag: u8, address: Address, data: &[u8]) -> Option<(Address, Bytes)> {
+        let position = frag & FRAG_POSITION_MASK;
+        let is_end = frag & FRAG_END_FLAG != 0;
+
+        if position == 0 {
+            debug!("udp fragment from {} with invalid FRAG {:#x}", peer_addr, frag);
+            self.queues.remove(&peer_addr);
+            return None;
+        }
+
+        let now = Instant::now();
+        if let Some(queue) = self.queues.get(&peer_addr) {
+            if now.saturating_duration_since(queue.start) >= REASSEMBLY_TIMEOUT {
+                debug!("udp fragments from {} abandoned, reassembly timed out", peer_addr);
+                self.queues.remove(&peer_addr);
+            }
+        }
+
+        if position == 1 {
+            // The first fragment starts a new sequence, replacing the incomplete one
+            if is_end {
+                self.queues.remove(&peer_addr);
+                return Some((address, Bytes::copy_from_slice(data)));
+            }
+            if self.queues.len() >= MAX_REASSEMBLY_QUEUES && !self.queues.contains_key(&peer_addr) {
+                debug!(
+                    "udp fragments from {} dropped, too many clients are reassembling",
+                    peer_addr
+                );
+                return None;
+            }
+
+            self.queues.insert(
+                peer_addr,
+                ReassemblyQueue {
+                    address,
+                    position,
+                    payload: BytesMut::from(data),
+                    start: now,
+                },
+            );
+            return None;
+        }
+
+        let queue = match self.queues.get_mut(&peer_addr) {
+            Some(q) => q,
+            None => {
+                debug!(
+                    "udp fragment {} from {} dropped, the first fragment is missing",
+                    position, peer_addr
+                );
+                return None;
+            }
+        };
+
+        if position != queue.position + 1 {
+            debug!(
+                "udp fragments from {} abandoned, expecting FRAG {} but received {}",
+                peer_addr,
+                queue.position + 1,
+                position
+            );
+            self.queues.remove(&peer_addr);
+            return None;
+        }
+        if address != queue.address {
+            debug!(
+                "udp fragments from {} abandoned, target changed from {} to {}",
+                peer_addr, queue.address, address
+            );
+            self.queues.remove(&peer_addr);
+            return None;
+        }
+        if queue.payload.len() + data.len() > MAXIMUM_UDP_PAYLOAD_SIZE {
+            debug!("udp fragments from {} abandoned, datagram is too large", peer_addr);
+            self.queues.remove(&peer_addr);
+            return None;
+        }
+
+        queue.position = position;
+        queue.payload.extend_from_slice(data);
+
+        if is_end {
+            let queue = self.queues.remove(&peer_addr).expect("reassembly queue");
+            return Some((queue.address, queue.payload.freeze()));
+        }
+        None
+    }
+
+    /// Remove sequences that are not completed in time
+    pub fn cleanup_expired(&mut self) {
+        let now = Instant::now();
+        self.queues
+            .retain(|_, queue| now.saturating_duration_since(queue.start) < REASSEMBLY_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn target() -> Address {
+        Address::SocketAddress(SocketAddr::from(([1, 1, 1, 1], 53)))
+    }
+
+    #[test]
+    fn test_fragment_reassemble() {
+        let mut reassembler = FragmentReassembler::default();
+        assert!(reassembler.push(peer(1), 1, target(), b"hello").is_none());
+        assert!(reassembler.push(peer(1), 2, target(), b", ").is_none());
+        let (address, data) = reassembler
+            .push(peer(1), 3 | FRAG_END_FLAG, target(), b"world")
+            .unwrap();
+        assert_eq!(address, target());
+        assert_eq!(&data[..], b"hello, world");
+        assert!(reassembler.queues.is_empty());
+    }
+
+    #[test]
+    fn test_fragment_single() {
+        let mut reassembler = FragmentReassembler::default();
+        let (_, data) = reassembler
+            .push(peer(1), 1 | FRAG_END_FLAG, target(), b"whole")
+            .unwrap();
+        assert_eq!(&data[..], b"whole");
+        assert!(reassembler.queues.is_empty());
+    }
+
+    #[test]
+    fn test_fragment_invalid_position() {
+        let mut reassembler = FragmentReassembler::default();
+        assert!(reassembler.push(peer(1), 1, target(), b"a").is_none());
+        // FRAG 0x80 has position 0, which is invalid and abandons the sequence
+        assert!(reassembler.push(peer(1), FRAG_END_FLAG, target(), b"b").is_none());
+        assert!(reassembler.queues.is_empty());
+    }
+
+    #[test]
+    fn test_fragment_missing_first() {
+        let mut reassembler = FragmentReassembler::default();
+        assert!(reassembler.push(peer(1), 2 | FRAG_END_FLAG, target(), b"b").is_none());
+        assert!(reassembler.queues.is_empty());
+    }
+
+    #[test]
+    fn test_fragment_out_of_order() {
+        let mut reassembler = FragmentReassembler::default();
+        assert!(reassembler.push(peer(1), 1, target(), b"a").is_none());
+        assert!(reassembler.push(peer(1), 3, target(), b"c").is_none());
+        assert!(reassembler.queues.is_empty());
+        // Later fragments of the abandoned sequence are dropped
+        assert!(reassembler.push(peer(1), 4 | FRAG_END_FLAG, target(), b"d").is_none());
+    }
+
+    #[test]
+    fn test_fragment_target_changed() {
+        let mut reassembler = FragmentReassembler::default();
+        let other = Address::DomainNameAddress("example.com".to_owned(), 53);
+        assert!(reassembler.push(peer(1), 1, target(), b"a").is_none());
+        assert!(reassembler.push(peer(1), 2 | FRAG_END_FLAG, other, b"b").is_none());
+        assert!(reassembler.queues.is_empty());
+    }
+
+    #[test]
+    fn test_fragment_restart() {
+        let mut reassembler = FragmentReassembler::default();
+        assert!(reassembler.push(peer(1), 1, target(), b"old").is_none());
+        assert!(reassembler.push(peer(1), 1, target(), b"new").is_none());
+        let (_, data) = reassembler.push(peer(1), 2 | FRAG_END_FLAG, target(), b"!").unwrap();
+        assert_eq!(&data[..], b"new!");
+    }
+
+    #[test]
+    fn test_fragment_abandon() {
+        let mut reassembler = FragmentReassembler::default();
+        assert!(reassembler.push(peer(1), 1, target(), b"a").is_none());
+        assert!(reassembler.push(peer(2), 1, target(), b"a").is_none());
+        reassembler.abandon(&peer(1));
+        assert!(reassembler.push(peer(1), 2 | FRAG_END_FLAG, target(), b"b").is_none());
+        assert!(reassembler.push(peer(2), 2 | FRAG_END_FLAG, target(), b"b").is_some());
+    }
+
+    #[test]
+    fn test_fragment_too_large() {
+        let mut reassembler = FragmentReassembler::default();
+        let data = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        assert!(reassembler.push(peer(1), 1, target(), &data).is_none());
+        assert!(reassembler.push(peer(1), 2 | FRAG_END_FLAG, target(), b"x").is_none());
+        assert!(reassembler.queues.is_empty());
+    }
+
+    #[test]
+    fn test_fragment_timeout() {
+        let mut reassembler = FragmentReassembler::default();
+        assert!(reassembler.push(peer(1), 1, target(), b"a").is_none());
+        assert!(reassembler.push(peer(2), 1, target(), b"a").is_none());
+        reassembler.queues.get_mut(&peer(1)).unwrap().start -= REASSEMBLY_TIMEOUT;
+
+        // The timed out sequence is not continued
+        assert!(reassembler.push(peer(1), 2 | FRAG_END_FLAG, target(), b"b").is_none());
+
+        reassembler.queues.get_mut(&peer(2)).unwrap().start -= REASSEMBLY_TIMEOUT;
+        reassembler.cleanup_expired();
+        assert!(reassembler.queues.is_empty());
+    }
+
+    #[test]
+    fn test_fragment_queue_limit() {
+        let mut reassembler = FragmentReassembler::default();
+        for port in 0..MAX_REASSEMBLY_QUEUES as u16 {
+            assert!(reassembler.push(peer(port), 1, target(), b"a").is_none());
+        }
+        let port = MAX_REASSEMBLY_QUEUES as u16;
+        assert!(reassembler.push(peer(port), 1, target(), b"a").is_none());
+        assert!(!reassembler.queues.contains_key(&peer(port)));
+
+        // Existing clients could still restart their sequences
+        assert!(reassembler.push(peer(0), 1, target(), b"b").is_none());
+        assert_eq!(reassembler.queues.len(), MAX_REASSEMBLY_QUEUES);
+    }
+}
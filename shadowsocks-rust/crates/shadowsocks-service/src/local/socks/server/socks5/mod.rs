@@ -5,5 +5,6 @@ pub use self::{
     udprelay::{Socks5UdpServer, Socks5UdpServerBuilder},
 };
 
+mod fragment;
 mod tcprelay;
 mod udprelay;
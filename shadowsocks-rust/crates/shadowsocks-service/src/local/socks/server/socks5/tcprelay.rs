@@ -1,8 +1,9 @@
 //! SOCKS5 TCP Server
 
 use std::{
+    collections::HashMap,
     io::{self, ErrorKind},
-    net::{Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     str,
     sync::Arc,
 };
@@ -16,17 +17,23 @@ use shadowsocks::{
     },
     ServerAddr,
 };
-use tokio::net::TcpStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
 
 use crate::{
     local::{
         context::ServiceContext,
-        loadbalancing::PingBalancer,
-        net::AutoProxyClientStream,
-        socks::config::Socks5AuthConfig,
+        loadbalancing::{PingBalancer, ServerIdent},
+        net::{special_target::SpecialTargetError, AutoProxyClientStream, LocalStream},
+        socks::config::{Socks5AuthConfig, Socks5RouteHint, Socks5UserPolicy},
         utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
     },
-    net::utils::ignore_until_end,
+    net::{
+        tcp_bind::make_tcp_bind_addr,
+        utils::{ignore_until_end, to_ipv4_mapped},
+    },
 };
 
 pub struct Socks5TcpHandler {
@@ -35,6 +42,8 @@ pub struct Socks5TcpHandler {
     balancer: PingBalancer,
     mode: Mode,
     auth: Arc<Socks5AuthConfig>,
+    udp_prebind_tx: Option<mpsc::Sender<SocketAddr>>,
+    udp_external_addrs: Arc<HashMap<IpAddr, IpAddr>>,
 }
 
 impl Socks5TcpHandler {
@@ -44,6 +53,8 @@ impl Socks5TcpHandler {
         balancer: PingBalancer,
         mode: Mode,
         auth: Arc<Socks5AuthConfig>,
+        udp_prebind_tx: Option<mpsc::Sender<SocketAddr>>,
+        udp_external_addrs: Arc<HashMap<IpAddr, IpAddr>>,
     ) -> Socks5TcpHandler {
         Socks5TcpHandler {
             context,
@@ -51,10 +62,17 @@ impl Socks5TcpHandler {
             balancer,
             mode,
             auth,
+            udp_prebind_tx,
+            udp_external_addrs,
         }
     }
 
-    async fn check_auth(&self, stream: &mut TcpStream, handshake_req: &HandshakeRequest) -> io::Result<()> {
+    /// Authenticate the client, returns policies of the authenticated user
+    async fn check_auth<S: LocalStream>(
+        &self,
+        stream: &mut S,
+        handshake_req: &HandshakeRequest,
+    ) -> io::Result<Option<Arc<Socks5UserPolicy>>> {
         use std::io::Error;
 
         let allow_none = !self.auth.auth_required();
@@ -76,7 +94,7 @@ impl Socks5TcpHandler {
                         trace!("reply handshake {:?}", resp);
                         resp.write_to(stream).await?;
 
-                        return Ok(());
+                        return Ok(None);
                     }
                 }
                 _ => {
@@ -90,13 +108,12 @@ impl Socks5TcpHandler {
 
         trace!("reply handshake {:?}", resp);
 
-        Err(Error::new(
-            ErrorKind::Other,
+        Err(Error::other(
             "currently shadowsocks-rust does not support authentication",
         ))
     }
 
-    async fn check_auth_password(&self, stream: &mut TcpStream) -> io::Result<()> {
+    async fn check_auth_password<S: LocalStream>(&self, stream: &mut S) -> io::Result<Option<Arc<Socks5UserPolicy>>> {
         use std::io::Error;
 
         const PASSWORD_AUTH_STATUS_FAILURE: u8 = 255;
@@ -109,10 +126,9 @@ impl Socks5TcpHandler {
                 let rsp = PasswdAuthResponse::new(err.as_reply().as_u8());
                 let _ = rsp.write_to(stream).await;
 
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    format!("Username/Password Authentication Initial request failed: {err}"),
-                ));
+                return Err(Error::other(format!(
+                    "Username/Password Authentication Initial request failed: {err}"
+                )));
             }
         };
 
@@ -122,8 +138,7 @@ impl Socks5TcpHandler {
                 let rsp = PasswdAuthResponse::new(PASSWORD_AUTH_STATUS_FAILURE);
                 let _ = rsp.write_to(stream).await;
 
-                return Err(Error::new(
-                    ErrorKind::Other,
+                return Err(Error::other(
                     "Username/Password Authentication Initial request uname contains invalid characters",
                 ));
             }
@@ -135,14 +150,30 @@ impl Socks5TcpHandler {
                 let rsp = PasswdAuthResponse::new(PASSWORD_AUTH_STATUS_FAILURE);
                 let _ = rsp.write_to(stream).await;
 
-                return Err(Error::new(
-                    ErrorKind::Other,
+                return Err(Error::other(
                     "Username/Password Authentication Initial request passwd contains invalid characters",
                 ));
             }
         };
 
-        if self.auth.passwd.check_user(user_name, password) {
+        // Users named with `@` are matched exactly before their routing hints
+        let (auth_user_name, route_hint) = if self.auth.passwd.check_user(user_name, password) {
+            (Some(user_name), None)
+        } else if self.auth.username_routing {
+            // Without users, credentials are only used for routing hints
+            let auth_required = self.auth.auth_required();
+            match Socks5RouteHint::split_user_name(user_name) {
+                Some((user, hint)) if !auth_required || self.auth.passwd.check_user(user, password) => {
+                    (Some(user), Some(hint))
+                }
+                None if !auth_required => (Some(user_name), None),
+                _ => (None, None),
+            }
+        } else {
+            (None, None)
+        };
+
+        if let Some(auth_user_name) = auth_user_name {
             trace!(
                 "socks5 authenticated with Username/Password method, user: {}, password: {}",
                 user_name,
@@ -152,7 +183,26 @@ impl Socks5TcpHandler {
             let rsp = PasswdAuthResponse::new(0);
             rsp.write_to(stream).await?;
 
-            Ok(())
+            let policy = self.auth.passwd.user_policy(auth_user_name).cloned();
+            let hint = match route_hint {
+                Some(hint) => hint,
+                None => return Ok(policy),
+            };
+
+            let user_policy = policy.as_deref().cloned().unwrap_or_default();
+            match user_policy.with_route_hint(&hint) {
+                Some(p) => {
+                    trace!("socks5 user {} routed by hint {:?}", auth_user_name, hint);
+                    Ok(Some(Arc::new(p)))
+                }
+                None => {
+                    debug!(
+                        "socks5 user {} is restricted to a server group, ignored routing hint {:?}",
+                        auth_user_name, hint
+                    );
+                    Ok(policy)
+                }
+            }
         } else {
             let rsp = PasswdAuthResponse::new(PASSWORD_AUTH_STATUS_FAILURE);
             rsp.write_to(stream).await?;
@@ -162,14 +212,13 @@ impl Socks5TcpHandler {
                 user_name, password
             );
 
-            Err(Error::new(
-                ErrorKind::Other,
-                format!("Username/Password Authentication failed, user: {user_name}, password: {password}"),
-            ))
+            Err(Error::other(format!(
+                "Username/Password Authentication failed, user: {user_name}, password: {password}"
+            )))
         }
     }
 
-    pub async fn handle_socks5_client(self, mut stream: TcpStream, peer_addr: SocketAddr) -> io::Result<()> {
+    pub async fn handle_socks5_client<S: LocalStream>(self, mut stream: S, peer_addr: SocketAddr) -> io::Result<()> {
         // 1. Handshake
 
         let handshake_req = match HandshakeRequest::read_from(&mut stream).await {
@@ -185,7 +234,7 @@ impl Socks5TcpHandler {
         };
 
         trace!("socks5 {:?}", handshake_req);
-        self.check_auth(&mut stream, &handshake_req).await?;
+        let policy = self.check_auth(&mut stream, &handshake_req).await?;
 
         // 2. Fetch headers
         let header = match TcpRequestHeader::read_from(&mut stream).await {
@@ -207,28 +256,40 @@ impl Socks5TcpHandler {
             Command::TcpConnect => {
                 debug!("CONNECT {}", addr);
 
-                self.handle_tcp_connect(stream, peer_addr, addr).await
+                self.handle_tcp_connect(stream, peer_addr, addr, policy).await
             }
             Command::UdpAssociate => {
                 debug!("UDP ASSOCIATE from {}", addr);
 
-                self.handle_udp_associate(stream, addr).await
+                self.handle_udp_associate(stream, peer_addr, addr).await
             }
             Command::TcpBind => {
-                warn!("BIND is not supported");
-                let rh = TcpResponseHeader::new(socks5::Reply::CommandNotSupported, addr);
-                rh.write_to(&mut stream).await?;
+                debug!("BIND {}", addr);
 
-                Ok(())
+                self.handle_tcp_bind(stream, peer_addr, addr, policy).await
             }
         }
     }
 
-    async fn handle_tcp_connect(
+    /// Choose a server for connections of the user, only servers in its `server_group` are allowed
+    fn best_tcp_server(
+        &self,
+        policy: Option<&Socks5UserPolicy>,
+        peer_addr: &SocketAddr,
+        target_addr: &Address,
+    ) -> Option<Arc<ServerIdent>> {
+        match policy.and_then(|p| p.server_group.as_deref()) {
+            Some(group) => self.balancer.best_tcp_server_in_group(group, peer_addr, target_addr),
+            None => Some(self.balancer.best_tcp_server_for_client(peer_addr, target_addr)),
+        }
+    }
+
+    async fn handle_tcp_connect<S: LocalStream>(
         self,
-        mut stream: TcpStream,
+        mut stream: S,
         peer_addr: SocketAddr,
         target_addr: Address,
+        policy: Option<Arc<Socks5UserPolicy>>,
     ) -> io::Result<()> {
         if !self.mode.enable_tcp() {
             warn!("TCP CONNECT is disabled");
@@ -239,22 +300,49 @@ impl Socks5TcpHandler {
             return Ok(());
         }
 
+        let acl = policy.as_ref().and_then(|p| p.acl.as_deref());
+        if let Some(acl) = acl {
+            if acl
+                .check_outbound_blocked(self.context.context_ref(), &target_addr)
+                .await
+            {
+                warn!(
+                    "socks5 CONNECT {} blocked by acl of the user, peer: {}",
+                    target_addr, peer_addr
+                );
+
+                let dummy_address = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
+                let header = TcpResponseHeader::new(Reply::ConnectionNotAllowed, Address::SocketAddress(dummy_address));
+                header.write_to(&mut stream).await?;
+
+                return Ok(());
+            }
+        }
+
+        let nodelay = self.context.target_nodelay(&target_addr);
+        let context = self.context.clone();
+
         let mut server_opt = None;
-        let remote_result = if self.balancer.is_empty() {
+        let bypass = policy.as_ref().is_some_and(|p| p.bypass);
+        let remote_result = if self.balancer.is_empty() || bypass {
             AutoProxyClientStream::connect_bypassed(self.context.clone(), &target_addr).await
         } else {
-            let server = self.balancer.best_tcp_server();
-
-            let r = AutoProxyClientStream::connect_with_opts(
-                self.context,
-                &server,
-                &target_addr,
-                server.connect_opts_ref(),
-            )
-            .await;
-            server_opt = Some(server);
-
-            r
+            match self.best_tcp_server(policy.as_deref(), &peer_addr, &target_addr) {
+                Some(server) => {
+                    let r = AutoProxyClientStream::connect_with_acl_opts(
+                        self.context,
+                        &server,
+                        &target_addr,
+                        acl,
+                        server.connect_opts_ref(),
+                    )
+                    .await;
+                    server_opt = Some(server);
+
+                    r
+                }
+                None => Err(io::Error::other("no server in group of the user is available")),
+            }
         };
 
         let mut remote = match remote_result {
@@ -269,9 +357,14 @@ impl Socks5TcpHandler {
                 remote
             }
             Err(err) => {
+                if SpecialTargetError::is_dropped(&err) {
+                    return Err(err);
+                }
+
                 let reply = match err.kind() {
                     ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
                     ErrorKind::ConnectionAborted => Reply::HostUnreachable,
+                    ErrorKind::PermissionDenied => Reply::ConnectionNotAllowed,
                     _ => Reply::NetworkUnreachable,
                 };
 
@@ -283,16 +376,180 @@ impl Socks5TcpHandler {
             }
         };
 
-        match server_opt {
-            Some(server) => {
-                let svr_cfg = server.server_config();
-                establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, &target_addr).await
+        if let Some(nodelay) = nodelay {
+            stream.set_nodelay(nodelay)?;
+        }
+
+        match policy.as_ref().and_then(|p| p.rate_limiter.as_ref()) {
+            Some(limiter) => {
+                let mut stream = limiter.limit(stream);
+                establish_tunnel(
+                    &context,
+                    server_opt.as_deref(),
+                    &mut stream,
+                    &mut remote,
+                    peer_addr,
+                    &target_addr,
+                )
+                .await
+            }
+            None => {
+                establish_tunnel(
+                    &context,
+                    server_opt.as_deref(),
+                    &mut stream,
+                    &mut remote,
+                    peer_addr,
+                    &target_addr,
+                )
+                .await
             }
-            None => establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, &target_addr).await,
         }
     }
 
-    async fn handle_udp_associate(self, mut stream: TcpStream, client_addr: Address) -> io::Result<()> {
+    async fn handle_tcp_bind<S: LocalStream>(
+        self,
+        mut stream: S,
+        peer_addr: SocketAddr,
+        target_addr: Address,
+        policy: Option<Arc<Socks5UserPolicy>>,
+    ) -> io::Result<()> {
+        // Ports are reserved on servers, bypassed targets are not supported
+        if !self.mode.enable_tcp() || self.balancer.is_empty() {
+            warn!("socks5 BIND requires a shadowsocks server");
+
+            let rh = TcpResponseHeader::new(socks5::Reply::CommandNotSupported, target_addr);
+            rh.write_to(&mut stream).await?;
+
+            return Ok(());
+        }
+
+        let server = match self.best_tcp_server(policy.as_deref(), &peer_addr, &target_addr) {
+            Some(s) => s,
+            None => {
+                warn!(
+                    "socks5 BIND {}, no server in group of the user is available",
+                    target_addr
+                );
+
+                let dummy_address = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
+                let header = TcpResponseHeader::new(Reply::NetworkUnreachable, Address::SocketAddress(dummy_address));
+                header.write_to(&mut stream).await?;
+
+                return Ok(());
+            }
+        };
+        let svr_cfg = server.server_config();
+
+        let remote_result = async {
+            let mut remote = AutoProxyClientStream::connect_proxied_with_opts(
+                self.context.clone(),
+                &server,
+                make_tcp_bind_addr(&target_addr)?,
+                server.connect_opts_ref(),
+            )
+            .await?;
+
+            // Server replies nothing until it receives the request
+            let _ = remote.write(&[]).await?;
+            let bind_addr = Address::read_from(&mut remote).await?;
+
+            Ok::<_, io::Error>((remote, bind_addr))
+        }
+        .await;
+
+        let (mut remote, bind_addr) = match remote_result {
+            Ok(r) => r,
+            Err(err) => {
+                error!(
+                    "socks5 BIND {} through {} failed, tcp_bind may not be enabled on server, error: {}",
+                    target_addr,
+                    svr_cfg.addr(),
+                    err
+                );
+
+                let dummy_address = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
+                let header = TcpResponseHeader::new(Reply::GeneralFailure, Address::SocketAddress(dummy_address));
+                header.write_to(&mut stream).await?;
+
+                return Err(err);
+            }
+        };
+
+        // Server is listening on all addresses, the peer could reach it by the address we connected to
+        let bind_addr = match bind_addr {
+            Address::SocketAddress(sa) if sa.ip().is_unspecified() => match *svr_cfg.addr() {
+                ServerAddr::SocketAddr(svr_addr) => Address::SocketAddress(SocketAddr::new(svr_addr.ip(), sa.port())),
+                ServerAddr::DomainName(ref dname, _) => Address::DomainNameAddress(dname.clone(), sa.port()),
+            },
+            addr => addr,
+        };
+
+        // 1st reply, the address that peer should connect to
+        let header = TcpResponseHeader::new(Reply::Succeeded, bind_addr.clone());
+        header.write_to(&mut stream).await?;
+
+        debug!(
+            "socks5 BIND {} listening on {} through {}",
+            target_addr,
+            bind_addr,
+            svr_cfg.addr()
+        );
+
+        let remote_peer_addr = tokio::select! {
+            r = Address::read_from(&mut remote) => match r {
+                Ok(a) => a,
+                // Server closes it if no peer connected in time
+                Err(err) => {
+                    let header = TcpResponseHeader::new(Reply::TtlExpired, bind_addr);
+                    header.write_to(&mut stream).await?;
+
+                    return Err(err.into());
+                }
+            },
+            _ = ignore_until_end(&mut stream) => {
+                trace!("socks5 BIND on {} closed by client before peer connected", bind_addr);
+                return Ok(());
+            }
+        };
+
+        // 2nd reply, the address of connected peer
+        let header = TcpResponseHeader::new(Reply::Succeeded, remote_peer_addr.clone());
+        header.write_to(&mut stream).await?;
+
+        match policy.as_ref().and_then(|p| p.rate_limiter.as_ref()) {
+            Some(limiter) => {
+                let mut stream = limiter.limit(stream);
+                establish_tcp_tunnel(
+                    &self.context,
+                    svr_cfg,
+                    &mut stream,
+                    &mut remote,
+                    peer_addr,
+                    &remote_peer_addr,
+                )
+                .await
+            }
+            None => {
+                establish_tcp_tunnel(
+                    &self.context,
+                    svr_cfg,
+                    &mut stream,
+                    &mut remote,
+                    peer_addr,
+                    &remote_peer_addr,
+                )
+                .await
+            }
+        }
+    }
+
+    async fn handle_udp_associate<S: LocalStream>(
+        self,
+        mut stream: S,
+        peer_addr: SocketAddr,
+        client_addr: Address,
+    ) -> io::Result<()> {
         if !self.mode.enable_udp() {
             warn!("socks5 udp is disabled");
 
@@ -304,7 +561,25 @@ impl Socks5TcpHandler {
 
         // shadowsocks accepts both TCP and UDP from the same address
 
-        let rh = TcpResponseHeader::new(socks5::Reply::Succeeded, self.udp_bind_addr.as_ref().into());
+        // Client's UDP address is known only if it is filled in the request.
+        // Unspecified IP means the same IP of the TCP connection.
+        if let (Some(tx), Address::SocketAddress(client_udp_addr)) = (&self.udp_prebind_tx, &client_addr) {
+            if client_udp_addr.port() != 0 {
+                let mut client_udp_addr = *client_udp_addr;
+                if client_udp_addr.ip().is_unspecified() {
+                    client_udp_addr.set_ip(peer_addr.ip());
+                }
+
+                if tx.try_send(client_udp_addr).is_err() {
+                    debug!(
+                        "socks5 udp prebind for {} failed, channel full or closed",
+                        client_udp_addr
+                    );
+                }
+            }
+        }
+
+        let rh = TcpResponseHeader::new(socks5::Reply::Succeeded, self.udp_reply_addr(&stream));
         rh.write_to(&mut stream).await?;
 
         // Hold connection until EOF.
@@ -312,4 +587,45 @@ impl Socks5TcpHandler {
 
         Ok(())
     }
+
+    /// Address of the UDP relay replied to `UDP ASSOCIATE`, the external address of the local address
+    /// that the client connected to if it is configured
+    fn udp_reply_addr<S: LocalStream>(&self, stream: &S) -> Address {
+        let mut addr: Address = self.udp_bind_addr.as_ref().into();
+        if self.udp_external_addrs.is_empty() {
+            return addr;
+        }
+
+        let local_ip = match stream.local_addr() {
+            Ok(a) => match a.ip() {
+                IpAddr::V6(ref v6) => to_ipv4_mapped(v6).map_or(a.ip(), IpAddr::V4),
+                ip => ip,
+            },
+            Err(..) => return addr,
+        };
+        if let Some(external_ip) = self.udp_external_addrs.get(&local_ip) {
+            addr = Address::SocketAddress(SocketAddr::new(*external_ip, addr.port()));
+        }
+        addr
+    }
+}
+
+async fn establish_tunnel<P>(
+    context: &ServiceContext,
+    server: Option<&ServerIdent>,
+    plain: &mut P,
+    remote: &mut AutoProxyClientStream,
+    peer_addr: SocketAddr,
+    target_addr: &Address,
+) -> io::Result<()>
+where
+    P: AsyncRead + AsyncWrite + Unpin,
+{
+    match server {
+        Some(server) => {
+            let svr_cfg = server.server_config();
+            establish_tcp_tunnel(context, svr_cfg, plain, remote, peer_addr, target_addr).await
+        }
+        None => establish_tcp_tunnel_bypassed(context, plain, remote, peer_addr, target_addr).await,
+    }
 }
@@ -10,6 +10,7 @@ use std::{
 use async_trait::async_trait;
 use byte_string::ByteStr;
 use bytes::{BufMut, BytesMut};
+use futures::future;
 use log::{debug, error, info, trace};
 use shadowsocks::{
     relay::{
@@ -18,8 +19,9 @@ use shadowsocks::{
     },
     ServerAddr,
 };
-use tokio::{net::UdpSocket, time};
+use tokio::{net::UdpSocket, sync::mpsc, time};
 
+use super::fragment::FragmentReassembler;
 use crate::{
     local::{
         context::ServiceContext,
@@ -35,6 +37,7 @@ pub struct Socks5UdpServerBuilder {
     time_to_live: Option<Duration>,
     capacity: Option<usize>,
     balancer: PingBalancer,
+    prebind_rx: Option<mpsc::Receiver<SocketAddr>>,
     #[cfg(target_os = "macos")]
     launchd_socket_name: Option<String>,
 }
@@ -53,11 +56,17 @@ impl Socks5UdpServerBuilder {
             time_to_live,
             capacity,
             balancer,
+            prebind_rx: None,
             #[cfg(target_os = "macos")]
             launchd_socket_name: None,
         }
     }
 
+    /// Receive clients' addresses from SOCKS5 `UDP ASSOCIATE` requests, for creating associations in advance
+    pub(crate) fn set_prebind_receiver(&mut self, rx: mpsc::Receiver<SocketAddr>) {
+        self.prebind_rx = Some(rx);
+    }
+
     /// macOS launchd activate socket
     #[cfg(target_os = "macos")]
     pub fn set_launchd_socket_name(&mut self, n: String) {
@@ -87,6 +96,7 @@ impl Socks5UdpServerBuilder {
             capacity: self.capacity,
             listener: Arc::new(socket),
             balancer: self.balancer,
+            prebind_rx: self.prebind_rx,
         })
     }
 }
@@ -134,6 +144,7 @@ pub struct Socks5UdpServer {
     capacity: Option<usize>,
     listener: Arc<UdpSocket>,
     balancer: PingBalancer,
+    prebind_rx: Option<mpsc::Receiver<SocketAddr>>,
 }
 
 impl Socks5UdpServer {
@@ -143,7 +154,7 @@ impl Socks5UdpServer {
     }
 
     /// Run server accept loop
-    pub async fn run(self) -> io::Result<()> {
+    pub async fn run(mut self) -> io::Result<()> {
         info!("shadowsocks socks5 UDP listening on {}", self.listener.local_addr()?);
 
         let (mut manager, cleanup_interval, mut keepalive_rx) = UdpAssociationManager::new(
@@ -158,12 +169,14 @@ impl Socks5UdpServer {
 
         let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
         let mut cleanup_timer = time::interval(cleanup_interval);
+        let mut reassembler = FragmentReassembler::default();
 
         loop {
             tokio::select! {
                 _ = cleanup_timer.tick() => {
                     // cleanup expired associations. iter() will remove expired elements
                     manager.cleanup_expired().await;
+                    reassembler.cleanup_expired();
                 }
 
                 peer_addr_opt = keepalive_rx.recv() => {
@@ -171,6 +184,13 @@ impl Socks5UdpServer {
                     manager.keep_alive(&peer_addr).await;
                 }
 
+                peer_addr_opt = receive_prebind_opt(&mut self.prebind_rx) => {
+                    match peer_addr_opt {
+                        Some(peer_addr) => manager.prebind(peer_addr).await,
+                        None => self.prebind_rx = None,
+                    }
+                }
+
                 recv_result = self.listener.recv_from(&mut buffer) => {
                     let (n, peer_addr) = match recv_result {
                         Ok(s) => s,
@@ -193,31 +213,56 @@ impl Socks5UdpServer {
                         }
                     };
 
-                    if header.frag != 0 {
-                        error!("received UDP associate with frag != 0, which is not supported by shadowsocks");
-                        continue;
-                    }
-
                     let pos = cur.position() as usize;
                     let payload = &data[pos..];
 
+                    let reassembled;
+                    let (target_addr, payload) = if header.frag == 0 {
+                        reassembler.abandon(&peer_addr);
+                        (header.address, payload)
+                    } else {
+                        trace!(
+                            "UDP ASSOCIATE {} -> {}, fragment {:#x}, {} bytes",
+                            peer_addr,
+                            header.address,
+                            header.frag,
+                            payload.len()
+                        );
+
+                        match reassembler.push(peer_addr, header.frag, header.address, payload) {
+                            Some((addr, datagram)) => {
+                                reassembled = datagram;
+                                (addr, &reassembled[..])
+                            }
+                            None => continue,
+                        }
+                    };
+
                     trace!(
                         "UDP ASSOCIATE {} -> {}, {} bytes",
                         peer_addr,
-                        header.address,
+                        target_addr,
                         payload.len()
                     );
 
-                    if let Err(err) = manager.send_to(peer_addr, header.address, payload).await {
+                    if let Err(err) = manager.send_to(peer_addr, target_addr, payload).await {
                         debug!(
                             "udp packet from {} relay {} bytes failed, error: {}",
                             peer_addr,
-                            data.len(),
+                            payload.len(),
                             err
                         );
                     }
                 }
             }
         }
+
+        #[inline]
+        async fn receive_prebind_opt(rx: &mut Option<mpsc::Receiver<SocketAddr>>) -> Option<SocketAddr> {
+            match *rx {
+                None => future::pending().await,
+                Some(ref mut rx) => rx.recv().await,
+            }
+        }
     }
 }
@@ -0,0 +1,166 @@
+//! Proxy settings of network services, managed with `networksetup`
+
+use std::{io, process::Command};
+
+use log::{debug, error};
+
+use super::{ProxyAddr, SystemProxyTargets};
+
+#[derive(Debug, Clone, Copy)]
+enum ProxyKind {
+    Web,
+    SecureWeb,
+    Socks,
+}
+
+impl ProxyKind {
+    fn get_command(self) -> &'static str {
+        match self {
+            ProxyKind::Web => "-getwebproxy",
+            ProxyKind::SecureWeb => "-getsecurewebproxy",
+            ProxyKind::Socks => "-getsocksfirewallproxy",
+        }
+    }
+
+    fn set_command(self) -> &'static str {
+        match self {
+            ProxyKind::Web => "-setwebproxy",
+            ProxyKind::SecureWeb => "-setsecurewebproxy",
+            ProxyKind::Socks => "-setsocksfirewallproxy",
+        }
+    }
+
+    fn set_state_command(self) -> &'static str {
+        match self {
+            ProxyKind::Web => "-setwebproxystate",
+            ProxyKind::SecureWeb => "-setsecurewebproxystate",
+            ProxyKind::Socks => "-setsocksfirewallproxystate",
+        }
+    }
+}
+
+/// A proxy setting of a network service before it was changed
+struct SavedProxy {
+    service: String,
+    kind: ProxyKind,
+    enabled: bool,
+    server: String,
+    port: String,
+}
+
+pub struct SavedSettings {
+    proxies: Vec<SavedProxy>,
+}
+
+fn networksetup(args: &[&str]) -> io::Result<String> {
+    debug!("networksetup {}", args.join(" "));
+
+    let output = Command::new("networksetup").args(args).output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        error!(
+            "networksetup {} exited with {}, {}",
+            args.join(" "),
+            output.status,
+            stderr.trim()
+        );
+        return Err(io::Error::other(format!("networksetup exited with {}", output.status)));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Enabled network services
+fn list_services() -> io::Result<Vec<String>> {
+    let output = networksetup(&["-listallnetworkservices"])?;
+
+    // The first line is a notice of disabled services, which are marked with '*'
+    Ok(output
+        .lines()
+        .skip(1)
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('*'))
+        .map(ToOwned::to_owned)
+        .collect())
+}
+
+fn get_proxy(service: &str, kind: ProxyKind) -> io::Result<SavedProxy> {
+    let output = networksetup(&[kind.get_command(), service])?;
+
+    let mut saved = SavedProxy {
+        service: service.to_owned(),
+        kind,
+        enabled: false,
+        server: String::new(),
+        port: String::new(),
+    };
+
+    // Enabled: Yes
+    // Server: 127.0.0.1
+    // Port: 1080
+    // Authenticated Proxy Enabled: 0
+    for line in output.lines() {
+        if let Some((key, value)) = line.split_once(':') {
+            let value = value.trim();
+            match key.trim() {
+                "Enabled" => saved.enabled = value == "Yes",
+                "Server" => saved.server = value.to_owned(),
+                "Port" => saved.port = value.to_owned(),
+                _ => {}
+            }
+        }
+    }
+
+    Ok(saved)
+}
+
+fn set_proxy(service: &str, kind: ProxyKind, addr: &ProxyAddr) -> io::Result<()> {
+    let port = addr.port.to_string();
+    networksetup(&[kind.set_command(), service, &addr.host, &port]).map(|_| ())
+}
+
+fn restore_proxy(saved: &SavedProxy) -> io::Result<()> {
+    // Setting server and port turns the proxy on
+    if !saved.server.is_empty() && saved.port != "0" && !saved.port.is_empty() {
+        networksetup(&[saved.kind.set_command(), &saved.service, &saved.server, &saved.port])?;
+    }
+    let state = if saved.enabled { "on" } else { "off" };
+    networksetup(&[saved.kind.set_state_command(), &saved.service, state]).map(|_| ())
+}
+
+pub fn apply(targets: &SystemProxyTargets) -> io::Result<SavedSettings> {
+    let mut kinds = Vec::new();
+    if let Some(ref http) = targets.http {
+        kinds.push((ProxyKind::Web, http));
+        kinds.push((ProxyKind::SecureWeb, http));
+    }
+    if let Some(ref socks) = targets.socks {
+        kinds.push((ProxyKind::Socks, socks));
+    }
+
+    let mut saved = SavedSettings { proxies: Vec::new() };
+    for service in list_services()? {
+        for &(kind, addr) in &kinds {
+            let result = get_proxy(&service, kind).and_then(|proxy| {
+                saved.proxies.push(proxy);
+                set_proxy(&service, kind, addr)
+            });
+            if let Err(err) = result {
+                restore(&saved);
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(saved)
+}
+
+pub fn restore(saved: &SavedSettings) {
+    for proxy in saved.proxies.iter().rev() {
+        if let Err(err) = restore_proxy(proxy) {
+            error!(
+                "failed to restore {:?} proxy of network service {}, error: {}",
+                proxy.kind, proxy.service, err
+            );
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! HTTP / SOCKS proxy settings of the system
+//!
+//! Settings are pointed to listeners of sslocal when it starts, and the previous ones are restored when it stops,
+//! like most GUI clients do.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use log::info;
+use shadowsocks::ServerAddr;
+
+#[cfg(target_os = "macos")]
+use self::macos as sys;
+#[cfg(windows)]
+use self::windows as sys;
+
+#[cfg(target_os = "macos")]
+mod macos;
+#[cfg(windows)]
+mod windows;
+
+/// Address of a proxy in system settings
+#[derive(Debug, Clone)]
+pub struct ProxyAddr {
+    pub host: String,
+    pub port: u16,
+}
+
+impl ProxyAddr {
+    fn new(addr: &ServerAddr) -> ProxyAddr {
+        match *addr {
+            ServerAddr::SocketAddr(ref sa) => {
+                // Listening on all interfaces, connect to it with loopback
+                let ip = match sa.ip() {
+                    IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                    ip => ip,
+                };
+                ProxyAddr {
+                    host: ip.to_string(),
+                    port: sa.port(),
+                }
+            }
+            ServerAddr::DomainName(ref dname, port) => ProxyAddr {
+                host: dname.clone(),
+                port,
+            },
+        }
+    }
+}
+
+/// Proxies that should be set in system settings
+#[derive(Debug, Clone, Default)]
+pub struct SystemProxyTargets {
+    pub http: Option<ProxyAddr>,
+    pub socks: Option<ProxyAddr>,
+}
+
+impl SystemProxyTargets {
+    /// Set HTTP and HTTPS proxies to `addr`, the first one wins
+    pub fn set_http(&mut self, addr: &ServerAddr) {
+        if self.http.is_none() {
+            self.http = Some(ProxyAddr::new(addr));
+        }
+    }
+
+    /// Set SOCKS proxy to `addr`, the first one wins
+    pub fn set_socks(&mut self, addr: &ServerAddr) {
+        if self.socks.is_none() {
+            self.socks = Some(ProxyAddr::new(addr));
+        }
+    }
+
+    /// Nothing to set
+    pub fn is_empty(&self) -> bool {
+        self.http.is_none() && self.socks.is_none()
+    }
+}
+
+/// Proxy settings of the system, restored to the previous ones when dropped
+pub struct SystemProxy {
+    saved: sys::SavedSettings,
+}
+
+impl SystemProxy {
+    /// Apply `targets` to system settings
+    ///
+    /// Settings that were changed are restored if it fails.
+    pub fn set(targets: SystemProxyTargets) -> io::Result<SystemProxy> {
+        let saved = sys::apply(&targets)?;
+        info!(
+            "system proxy set, http: {:?}, socks: {:?}",
+            targets.http.as_ref().map(|a| format!("{}:{}", a.host, a.port)),
+            targets.socks.as_ref().map(|a| format!("{}:{}", a.host, a.port))
+        );
+        Ok(SystemProxy { saved })
+    }
+}
+
+impl Drop for SystemProxy {
+    fn drop(&mut self) {
+        sys::restore(&self.saved);
+        info!("system proxy restored");
+    }
+}
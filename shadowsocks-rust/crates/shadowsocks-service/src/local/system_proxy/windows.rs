@@ -0,0 +1,141 @@
+//! WinINET proxy settings of the current user, managed with `reg`
+
+use std::{io, process::Command, ptr};
+
+use log::{debug, error};
+use windows_sys::Win32::Networking::WinInet::{
+    InternetSetOptionW, INTERNET_OPTION_REFRESH, INTERNET_OPTION_SETTINGS_CHANGED,
+};
+
+use super::{ProxyAddr, SystemProxyTargets};
+
+const INTERNET_SETTINGS_KEY: &str = r"HKCU\Software\Microsoft\Windows\CurrentVersion\Internet Settings";
+
+/// Addresses that are connected directly
+const PROXY_OVERRIDE: &str = "<local>;localhost;127.*;10.*;172.16.*;172.17.*;172.18.*;172.19.*;172.20.*;172.21.*;\
+                              172.22.*;172.23.*;172.24.*;172.25.*;172.26.*;172.27.*;172.28.*;172.29.*;172.30.*;\
+                              172.31.*;192.168.*";
+
+/// Values of `INTERNET_SETTINGS_KEY` before they were changed, `None` if they didn't exist
+pub struct SavedSettings {
+    proxy_enable: Option<String>,
+    proxy_server: Option<String>,
+    proxy_override: Option<String>,
+}
+
+fn reg(args: &[&str]) -> io::Result<String> {
+    debug!("reg {}", args.join(" "));
+
+    let output = Command::new("reg").args(args).output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        error!(
+            "reg {} exited with {}, {}",
+            args.join(" "),
+            output.status,
+            stderr.trim()
+        );
+        return Err(io::Error::other(format!("reg exited with {}", output.status)));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Data of value `name`, `None` if it doesn't exist
+fn query_value(name: &str) -> Option<String> {
+    let output = Command::new("reg")
+        .args(["query", INTERNET_SETTINGS_KEY, "/v", name])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    //     ProxyServer    REG_SZ    127.0.0.1:1080
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for line in stdout.lines() {
+        let mut parts = line.trim().splitn(3, "    ");
+        if parts.next() == Some(name) {
+            let _ty = parts.next()?;
+            return Some(parts.next().unwrap_or_default().trim().to_owned());
+        }
+    }
+    None
+}
+
+fn set_value(name: &str, ty: &str, data: &str) -> io::Result<()> {
+    reg(&["add", INTERNET_SETTINGS_KEY, "/v", name, "/t", ty, "/d", data, "/f"]).map(|_| ())
+}
+
+fn restore_value(name: &str, ty: &str, data: Option<&str>) -> io::Result<()> {
+    match data {
+        Some(data) => {
+            // REG_DWORD data are queried in hex, like 0x1
+            let data = match data.strip_prefix("0x") {
+                Some(hex) if ty == "REG_DWORD" => u32::from_str_radix(hex, 16).unwrap_or(0).to_string(),
+                _ => data.to_owned(),
+            };
+            set_value(name, ty, &data)
+        }
+        None => reg(&["delete", INTERNET_SETTINGS_KEY, "/v", name, "/f"]).map(|_| ()),
+    }
+}
+
+/// Notify WinINET applications that settings have changed
+fn refresh_settings() {
+    unsafe {
+        InternetSetOptionW(ptr::null(), INTERNET_OPTION_SETTINGS_CHANGED, ptr::null(), 0);
+        InternetSetOptionW(ptr::null(), INTERNET_OPTION_REFRESH, ptr::null(), 0);
+    }
+}
+
+/// `scheme=host:port` in `ProxyServer`, IPv6 addresses are bracketed
+fn proxy_server_entry(scheme: &str, addr: &ProxyAddr) -> String {
+    if addr.host.contains(':') {
+        format!("{}=[{}]:{}", scheme, addr.host, addr.port)
+    } else {
+        format!("{}={}:{}", scheme, addr.host, addr.port)
+    }
+}
+
+pub fn apply(targets: &SystemProxyTargets) -> io::Result<SavedSettings> {
+    let saved = SavedSettings {
+        proxy_enable: query_value("ProxyEnable"),
+        proxy_server: query_value("ProxyServer"),
+        proxy_override: query_value("ProxyOverride"),
+    };
+
+    let mut proxy_server = Vec::new();
+    if let Some(ref http) = targets.http {
+        proxy_server.push(proxy_server_entry("http", http));
+        proxy_server.push(proxy_server_entry("https", http));
+    }
+    if let Some(ref socks) = targets.socks {
+        proxy_server.push(proxy_server_entry("socks", socks));
+    }
+
+    let result = set_value("ProxyServer", "REG_SZ", &proxy_server.join(";"))
+        .and_then(|_| set_value("ProxyOverride", "REG_SZ", PROXY_OVERRIDE))
+        .and_then(|_| set_value("ProxyEnable", "REG_DWORD", "1"));
+    if let Err(err) = result {
+        restore(&saved);
+        return Err(err);
+    }
+
+    refresh_settings();
+    Ok(saved)
+}
+
+pub fn restore(saved: &SavedSettings) {
+    let values = [
+        ("ProxyEnable", "REG_DWORD", saved.proxy_enable.as_deref()),
+        ("ProxyServer", "REG_SZ", saved.proxy_server.as_deref()),
+        ("ProxyOverride", "REG_SZ", saved.proxy_override.as_deref()),
+    ];
+    for (name, ty, data) in values {
+        if let Err(err) = restore_value(name, ty, data) {
+            error!("failed to restore {} of internet settings, error: {}", name, err);
+        }
+    }
+
+    refresh_settings();
+}
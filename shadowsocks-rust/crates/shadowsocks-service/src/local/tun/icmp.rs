@@ -0,0 +1,180 @@
+//! ICMP echo requests (ping) intercepted by tun
+//!
+//! ICMP couldn't be relayed by shadowsocks, echo requests are answered locally. With `TunIcmpEchoPolicy::Server`,
+//! replies of proxied destinations are delayed by the latency of the best server, and never sent if it is down,
+//! so `ping` reflects the state of the tunnel instead of the tun device itself.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::{BufMut, BytesMut};
+use etherparse::PacketBuilder;
+use log::trace;
+use shadowsocks::relay::socks5::Address;
+use smoltcp::wire::{Icmpv4Message, Icmpv4Packet, Icmpv6Message, Icmpv6Packet};
+use tokio::{sync::mpsc, time};
+
+use crate::{
+    config::TunIcmpEchoPolicy,
+    local::{context::ServiceContext, loadbalancing::PingBalancer},
+};
+
+/// An echo request, which is replied with the same identifier, sequence number and data
+struct EchoRequest<'a> {
+    ident: u16,
+    seq_no: u16,
+    data: &'a [u8],
+}
+
+pub struct IcmpTun {
+    context: Arc<ServiceContext>,
+    balancer: PingBalancer,
+    policy: TunIcmpEchoPolicy,
+    tun_tx: mpsc::Sender<BytesMut>,
+    tun_rx: mpsc::Receiver<BytesMut>,
+}
+
+impl IcmpTun {
+    pub fn new(context: Arc<ServiceContext>, balancer: PingBalancer, policy: TunIcmpEchoPolicy) -> IcmpTun {
+        let (tun_tx, tun_rx) = mpsc::channel(64);
+        IcmpTun {
+            context,
+            balancer,
+            policy,
+            tun_tx,
+            tun_rx,
+        }
+    }
+
+    /// Handle an ICMP `payload` from `src_addr` to `dst_addr`
+    ///
+    /// Returns `false` if it is not handled, and should be answered by the TCP stack like other ICMP packets.
+    pub async fn handle_packet(&mut self, src_addr: IpAddr, dst_addr: IpAddr, payload: &[u8]) -> bool {
+        if self.policy == TunIcmpEchoPolicy::Reply {
+            return false;
+        }
+
+        let request = match parse_echo_request(dst_addr, payload) {
+            Some(r) => r,
+            None => return false,
+        };
+
+        if self.policy == TunIcmpEchoPolicy::Drop {
+            trace!("[TUN] ICMP echo {} -> {} dropped", src_addr, dst_addr);
+            return true;
+        }
+
+        if self.balancer.is_empty()
+            || self
+                .context
+                .check_target_bypassed(&Address::SocketAddress(SocketAddr::new(dst_addr, 0)))
+                .await
+        {
+            return false;
+        }
+
+        let server = self.balancer.best_tcp_server();
+        let score = server.tcp_score();
+        if score.is_down() {
+            trace!(
+                "[TUN] ICMP echo {} -> {} dropped, server {} is down",
+                src_addr,
+                dst_addr,
+                server.server_config().addr()
+            );
+            return true;
+        }
+
+        let packet = match make_echo_reply(dst_addr, src_addr, &request) {
+            Some(p) => p,
+            None => return true,
+        };
+
+        let latency = Duration::from_millis(score.latency() as u64);
+        trace!(
+            "[TUN] ICMP echo {} -> {} replied in {:?} of server {}",
+            src_addr,
+            dst_addr,
+            latency,
+            server.server_config().addr()
+        );
+
+        let tun_tx = self.tun_tx.clone();
+        tokio::spawn(async move {
+            time::sleep(latency).await;
+            let _ = tun_tx.send(packet).await;
+        });
+
+        true
+    }
+
+    pub async fn recv_packet(&mut self) -> BytesMut {
+        match self.tun_rx.recv().await {
+            Some(b) => b,
+            None => unreachable!("channel closed unexpectedly"),
+        }
+    }
+}
+
+fn parse_echo_request(dst_addr: IpAddr, payload: &[u8]) -> Option<EchoRequest<'_>> {
+    match dst_addr {
+        IpAddr::V4(..) => {
+            let packet = Icmpv4Packet::new_checked(payload).ok()?;
+            if packet.msg_type() != Icmpv4Message::EchoRequest {
+                return None;
+            }
+            Some(EchoRequest {
+                ident: packet.echo_ident(),
+                seq_no: packet.echo_seq_no(),
+                data: packet.data(),
+            })
+        }
+        IpAddr::V6(..) => {
+            let packet = Icmpv6Packet::new_checked(payload).ok()?;
+            if packet.msg_type() != Icmpv6Message::EchoRequest {
+                return None;
+            }
+            Some(EchoRequest {
+                ident: packet.echo_ident(),
+                seq_no: packet.echo_seq_no(),
+                data: packet.payload(),
+            })
+        }
+    }
+}
+
+fn make_echo_reply(src_addr: IpAddr, dst_addr: IpAddr, request: &EchoRequest<'_>) -> Option<BytesMut> {
+    // ICMPv4 and ICMPv6 headers are built by steps of different types
+    let packet = match (src_addr, dst_addr) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let builder =
+                PacketBuilder::ipv4(src.octets(), dst.octets(), 64).icmpv4_echo_reply(request.ident, request.seq_no);
+
+            let packet = BytesMut::with_capacity(builder.size(request.data.len()));
+            let mut packet_writer = packet.writer();
+            builder
+                .write(&mut packet_writer, request.data)
+                .expect("PacketBuilder::write");
+
+            packet_writer.into_inner()
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let builder =
+                PacketBuilder::ipv6(src.octets(), dst.octets(), 64).icmpv6_echo_reply(request.ident, request.seq_no);
+
+            let packet = BytesMut::with_capacity(builder.size(request.data.len()));
+            let mut packet_writer = packet.writer();
+            builder
+                .write(&mut packet_writer, request.data)
+                .expect("PacketBuilder::write");
+
+            packet_writer.into_inner()
+        }
+        _ => return None,
+    };
+
+    Some(packet)
+}
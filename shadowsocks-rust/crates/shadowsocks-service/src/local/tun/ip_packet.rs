@@ -33,6 +33,14 @@ impl<T: AsRef<[u8]> + Copy> IpPacket<T> {
         }
     }
 
+    /// Traffic Class (IPv6) or Type of Service (IPv4), DSCP is its higher 6 bits
+    pub fn traffic_class(&self) -> u8 {
+        match *self {
+            IpPacket::Ipv4(ref packet) => (packet.dscp() << 2) | packet.ecn(),
+            IpPacket::Ipv6(ref packet) => packet.traffic_class(),
+        }
+    }
+
     pub fn protocol(&self) -> IpProtocol {
         match *self {
             IpPacket::Ipv4(ref packet) => packet.next_header(),
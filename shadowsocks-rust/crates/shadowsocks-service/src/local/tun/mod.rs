@@ -3,7 +3,7 @@
 #[cfg(unix)]
 use std::os::unix::io::RawFd;
 use std::{
-    io::{self, ErrorKind},
+    io::{self},
     net::{IpAddr, SocketAddr},
     sync::Arc,
     time::Duration,
@@ -14,6 +14,8 @@ use cfg_if::cfg_if;
 use ipnet::IpNet;
 use log::{debug, error, info, trace, warn};
 use shadowsocks::config::Mode;
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+use shadowsocks::ServerAddr;
 use smoltcp::wire::{IpProtocol, TcpPacket, UdpPacket};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
@@ -39,23 +41,68 @@ cfg_if! {
     }
 }
 
-use crate::local::{context::ServiceContext, loadbalancing::PingBalancer};
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+use crate::config::TunAutoRouteConfig;
+#[cfg(windows)]
+use crate::config::TunIpv6Config;
+#[cfg(feature = "local-fake-dns")]
+use crate::local::fake_dns::manager::FakeDnsManager;
+use crate::{
+    config::{TunDnsHijackAction, TunDnsHijackRule, TunDscpConfig, TunIcmpEchoPolicy},
+    local::{context::ServiceContext, loadbalancing::PingBalancer},
+};
 
-use self::{ip_packet::IpPacket, tcp::TcpTun, udp::UdpTun};
+#[cfg(target_os = "linux")]
+use self::offload::OffloadTunDevice;
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+use self::route::TunRoutes;
+use self::{icmp::IcmpTun, ip_packet::IpPacket, tcp::TcpTun, udp::UdpTun};
 
+mod icmp;
 mod ip_packet;
+#[cfg(target_os = "linux")]
+mod offload;
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+mod route;
 mod tcp;
 mod udp;
 mod virt_device;
+#[cfg(windows)]
+mod windows;
+
+/// Most TCP packets sent back that are coalesced in one batch
+#[cfg(target_os = "linux")]
+const MAX_COALESCE_PACKETS: usize = 64;
+
+/// Interval of checking the upstream IPv6 network, whose prefixes may be changed by the ISP
+const IPV6_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
 
 /// Tun service builder
 pub struct TunBuilder {
     context: Arc<ServiceContext>,
     balancer: PingBalancer,
     tun_config: TunConfiguration,
+    name: Option<String>,
+    address: Option<IpNet>,
+    destination: Option<IpNet>,
+    mtu: Option<u16>,
+    #[cfg(unix)]
+    raw_fd: Option<RawFd>,
+    #[cfg(target_os = "linux")]
+    offload: bool,
     udp_expiry_duration: Option<Duration>,
     udp_capacity: Option<usize>,
     mode: Mode,
+    dscp: Option<Arc<TunDscpConfig>>,
+    sniff_timeout: Option<Duration>,
+    dns_hijack: Vec<TunDnsHijackRule>,
+    icmp_echo: TunIcmpEchoPolicy,
+    #[cfg(windows)]
+    ipv6: Option<TunIpv6Config>,
+    #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+    auto_route: Option<(TunAutoRouteConfig, Vec<ServerAddr>)>,
+    #[cfg(feature = "local-fake-dns")]
+    fake_dns: Option<Arc<FakeDnsManager>>,
 }
 
 /// TunConfiguration contains a HANDLE, which is a *mut c_void on Windows.
@@ -68,27 +115,57 @@ impl TunBuilder {
             context,
             balancer,
             tun_config: TunConfiguration::default(),
+            name: None,
+            address: None,
+            destination: None,
+            mtu: None,
+            #[cfg(unix)]
+            raw_fd: None,
+            #[cfg(target_os = "linux")]
+            offload: false,
             udp_expiry_duration: None,
             udp_capacity: None,
             mode: Mode::TcpOnly,
+            dscp: None,
+            sniff_timeout: None,
+            dns_hijack: Vec::new(),
+            icmp_echo: TunIcmpEchoPolicy::default(),
+            #[cfg(windows)]
+            ipv6: None,
+            #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+            auto_route: None,
+            #[cfg(feature = "local-fake-dns")]
+            fake_dns: None,
         }
     }
 
     pub fn address(&mut self, addr: IpNet) {
-        self.tun_config.address(addr.addr()).netmask(addr.netmask());
+        self.address = Some(addr);
     }
 
     pub fn destination(&mut self, addr: IpNet) {
-        self.tun_config.destination(addr.addr());
+        self.destination = Some(addr);
     }
 
     pub fn name(&mut self, name: &str) {
-        self.tun_config.tun_name(name);
+        self.name = Some(name.to_owned());
+    }
+
+    pub fn mtu(&mut self, mtu: u16) {
+        self.mtu = Some(mtu);
     }
 
     #[cfg(unix)]
     pub fn file_descriptor(&mut self, fd: RawFd) {
-        self.tun_config.raw_fd(fd);
+        self.raw_fd = Some(fd);
+    }
+
+    /// Create the device with checksum and TCP segmentation offloads, reading and writing TCP in GSO packets
+    ///
+    /// Devices of `file_descriptor` are opened by others without offloads, it is ignored for them.
+    #[cfg(target_os = "linux")]
+    pub fn offload(&mut self, offload: bool) {
+        self.offload = offload;
     }
 
     pub fn udp_expiry_duration(&mut self, udp_expiry_duration: Duration) {
@@ -103,56 +180,264 @@ impl TunBuilder {
         self.mode = mode;
     }
 
+    /// Reflect DSCP of intercepted packets onto outbound connections
+    pub fn dscp(&mut self, dscp: TunDscpConfig) {
+        self.dscp = Some(Arc::new(dscp));
+    }
+
+    /// Sniff TLS SNI or HTTP `Host` of TCP connections for ACL domain rules, waiting at most `timeout` for them
+    pub fn sniff_timeout(&mut self, timeout: Duration) {
+        self.sniff_timeout = Some(timeout);
+    }
+
+    /// Rules of DNS traffic, the first rule matching the destination decides
+    ///
+    /// Without matching rules, UDP queries to port 53 are hijacked if `fake_dns` is set, or passed otherwise
+    pub fn dns_hijack(&mut self, rules: Vec<TunDnsHijackRule>) {
+        self.dns_hijack = rules;
+    }
+
+    /// Handling of ICMP echo requests
+    pub fn icmp_echo(&mut self, policy: TunIcmpEchoPolicy) {
+        self.icmp_echo = policy;
+    }
+
+    /// IPv6 address, routes and DNS servers of the wintun interface
+    #[cfg(windows)]
+    pub fn ipv6(&mut self, config: TunIpv6Config) {
+        self.ipv6 = Some(config);
+    }
+
+    /// Route IPv4 traffic to the device when it is up, except `servers` and bypassed networks, until it stops
+    #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+    pub fn auto_route(&mut self, config: TunAutoRouteConfig, servers: Vec<ServerAddr>) {
+        self.auto_route = Some((config, servers));
+    }
+
+    /// Hijack DNS queries, answering them with fake addresses of `manager`
+    ///
+    /// Connections to fake addresses are mapped back to domain names, `manager` has to be added to the context.
+    #[cfg(feature = "local-fake-dns")]
+    pub fn fake_dns(&mut self, manager: Arc<FakeDnsManager>) {
+        self.fake_dns = Some(manager);
+    }
+
     /// Build Tun server
     pub async fn build(mut self) -> io::Result<Tun> {
-        self.tun_config.layer(Layer::L3).up();
+        #[cfg(target_os = "linux")]
+        let device = if self.offload && self.raw_fd.is_none() {
+            let address = match self.address {
+                Some(a) => a,
+                None => return Err(io::Error::other("tun offload requires interface address")),
+            };
+            let name = self.name.clone();
+            let destination = self.destination.map(|d| d.addr());
+            let mtu = self.mtu.map_or(offload::DEFAULT_MTU, u32::from);
+
+            match tokio::task::spawn_blocking(move || {
+                OffloadTunDevice::create(name.as_deref(), address, destination, mtu)
+            })
+            .await
+            {
+                Ok(r) => TunDevice::Offload(r?),
+                Err(err) => return Err(io::Error::other(err)),
+            }
+        } else {
+            TunDevice::Plain(self.create_device()?)
+        };
+        #[cfg(not(target_os = "linux"))]
+        let device = TunDevice::Plain(self.create_device()?);
 
-        // XXX: tun2 set IFF_NO_PI by default.
-        //
-        // #[cfg(target_os = "linux")]
-        // self.tun_config.platform_config(|tun_config| {
-        //     // IFF_NO_PI preventing excessive buffer reallocating
-        //     tun_config.packet_information(false);
-        // });
+        #[cfg(windows)]
+        if let Some(ipv6) = self.ipv6 {
+            let name = device.tun_name()?;
 
-        let device = match create_as_async(&self.tun_config) {
-            Ok(d) => d,
-            Err(TunError::Io(err)) => return Err(err),
-            Err(err) => return Err(io::Error::new(ErrorKind::Other, err)),
+            match tokio::task::spawn_blocking(move || windows::configure_ipv6(&name, &ipv6)).await {
+                Ok(r) => r?,
+                Err(err) => return Err(io::Error::other(err)),
+            }
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+        let routes = match self.auto_route {
+            None => None,
+            Some((config, servers)) => {
+                let name = device.tun_name()?;
+
+                // Addresses of servers are resolved once, connections to them must not be routed to tun
+                let mut server_ips = Vec::with_capacity(servers.len());
+                for server in servers {
+                    match server {
+                        ServerAddr::SocketAddr(sa) => server_ips.push(sa.ip()),
+                        ServerAddr::DomainName(dname, port) => {
+                            let addrs = self.context.context_ref().dns_resolve(&dname, port).await?;
+                            server_ips.extend(addrs.map(|sa| sa.ip()));
+                        }
+                    }
+                }
+
+                match tokio::task::spawn_blocking(move || TunRoutes::install(&name, &config, &server_ips)).await {
+                    Ok(r) => Some(r?),
+                    Err(err) => return Err(io::Error::other(err)),
+                }
+            }
         };
 
-        let (udp, udp_cleanup_interval, udp_keepalive_rx) = UdpTun::new(
+        let (mut udp, udp_cleanup_interval, udp_keepalive_rx) = UdpTun::new(
             self.context.clone(),
             self.balancer.clone(),
             self.udp_expiry_duration,
             self.udp_capacity,
+            self.dscp.clone(),
         );
+        let dns_hijack: Arc<[TunDnsHijackRule]> = self.dns_hijack.into();
+        udp.set_dns_hijack(dns_hijack.clone());
+        #[cfg(feature = "local-fake-dns")]
+        if let Some(manager) = self.fake_dns {
+            udp.set_fake_dns(manager);
+        }
 
-        let tcp = TcpTun::new(
-            self.context,
-            self.balancer,
-            device.as_ref().mtu().unwrap_or(1500) as u32,
-        );
+        let icmp = IcmpTun::new(self.context.clone(), self.balancer.clone(), self.icmp_echo);
+
+        #[cfg(all(feature = "local-fake-dns", any(target_os = "linux", target_os = "macos")))]
+        let context = self.context.clone();
+        let tcp = TcpTun::new(self.context, self.balancer, device.mtu(), self.dscp, self.sniff_timeout);
 
         Ok(Tun {
             device,
             tcp,
             udp,
+            icmp,
             udp_cleanup_interval,
             udp_keepalive_rx,
             mode: self.mode,
+            dns_hijack,
+            #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+            routes,
+            #[cfg(all(feature = "local-fake-dns", any(target_os = "linux", target_os = "macos")))]
+            context,
         })
     }
+
+    fn create_device(&mut self) -> io::Result<AsyncDevice> {
+        if let Some(ref name) = self.name {
+            self.tun_config.tun_name(name);
+        }
+        if let Some(address) = self.address {
+            self.tun_config.address(address.addr()).netmask(address.netmask());
+        }
+        if let Some(destination) = self.destination {
+            self.tun_config.destination(destination.addr());
+        }
+        if let Some(mtu) = self.mtu {
+            self.tun_config.mtu(mtu);
+        }
+        #[cfg(unix)]
+        if let Some(fd) = self.raw_fd {
+            // Passed by embedders or parent processes, fails early if it is not opened
+            if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+                let err = io::Error::last_os_error();
+                error!("tun file descriptor {} is not opened, error: {}", fd, err);
+                return Err(err);
+            }
+            self.tun_config.raw_fd(fd);
+        }
+
+        self.tun_config.layer(Layer::L3).up();
+
+        // XXX: tun2 set IFF_NO_PI by default.
+        //
+        // #[cfg(target_os = "linux")]
+        // self.tun_config.platform_config(|tun_config| {
+        //     // IFF_NO_PI preventing excessive buffer reallocating
+        //     tun_config.packet_information(false);
+        // });
+
+        match create_as_async(&self.tun_config) {
+            Ok(d) => Ok(d),
+            Err(TunError::Io(err)) => Err(err),
+            Err(err) => Err(io::Error::other(err)),
+        }
+    }
+}
+
+/// Device of tun service
+enum TunDevice {
+    Plain(AsyncDevice),
+    #[cfg(target_os = "linux")]
+    Offload(OffloadTunDevice),
+}
+
+impl TunDevice {
+    fn tun_name(&self) -> io::Result<String> {
+        match *self {
+            TunDevice::Plain(ref device) => device.as_ref().tun_name().map_err(io::Error::other),
+            #[cfg(target_os = "linux")]
+            TunDevice::Offload(ref device) => Ok(device.name().to_owned()),
+        }
+    }
+
+    fn network(&self) -> io::Result<IpNet> {
+        match *self {
+            TunDevice::Plain(ref device) => {
+                let address = device.as_ref().address().map_err(io::Error::other)?;
+                let netmask = device.as_ref().netmask().map_err(io::Error::other)?;
+                IpNet::with_netmask(address, netmask).map_err(io::Error::other)
+            }
+            #[cfg(target_os = "linux")]
+            TunDevice::Offload(ref device) => Ok(device.address()),
+        }
+    }
+
+    fn mtu(&self) -> u32 {
+        match *self {
+            TunDevice::Plain(ref device) => device.as_ref().mtu().unwrap_or(1500) as u32,
+            #[cfg(target_os = "linux")]
+            TunDevice::Offload(ref device) => device.mtu(),
+        }
+    }
+
+    /// Size of buffers reading frames from the device
+    fn frame_buffer_size(&self) -> usize {
+        match *self {
+            TunDevice::Plain(..) => 65536,
+            #[cfg(target_os = "linux")]
+            TunDevice::Offload(..) => offload::MAX_FRAME_SIZE,
+        }
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            TunDevice::Plain(ref mut device) => device.read(buf).await,
+            #[cfg(target_os = "linux")]
+            TunDevice::Offload(ref device) => device.read(buf).await,
+        }
+    }
+
+    async fn write(&mut self, packet: &[u8]) -> io::Result<usize> {
+        match *self {
+            TunDevice::Plain(ref mut device) => device.write(packet).await,
+            #[cfg(target_os = "linux")]
+            TunDevice::Offload(ref device) => device.write(&Default::default(), packet).await,
+        }
+    }
 }
 
 /// Tun service
 pub struct Tun {
-    device: AsyncDevice,
+    // Routes are removed before the device is closed
+    #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+    routes: Option<TunRoutes>,
+    #[cfg(all(feature = "local-fake-dns", any(target_os = "linux", target_os = "macos")))]
+    context: Arc<ServiceContext>,
+    device: TunDevice,
     tcp: TcpTun,
     udp: UdpTun,
+    icmp: IcmpTun,
     udp_cleanup_interval: Duration,
     udp_keepalive_rx: mpsc::Receiver<SocketAddr>,
     mode: Mode,
+    dns_hijack: Arc<[TunDnsHijackRule]>,
 }
 
 impl Tun {
@@ -160,49 +445,34 @@ impl Tun {
     pub async fn run(mut self) -> io::Result<()> {
         info!(
             "shadowsocks tun device {}, mode {}",
-            self.device
-                .as_ref()
-                .tun_name()
-                .or_else(|r| Ok::<_, ()>(r.to_string()))
-                .unwrap(),
+            self.device.tun_name().unwrap_or_else(|err| err.to_string()),
             self.mode,
         );
 
-        let address = match self.device.as_ref().address() {
-            Ok(a) => a,
-            Err(err) => {
-                error!("[TUN] failed to get device address, error: {}", err);
-                return Err(io::Error::new(io::ErrorKind::Other, err));
-            }
-        };
-
-        let netmask = match self.device.as_ref().netmask() {
+        let address_net = match self.device.network() {
             Ok(n) => n,
             Err(err) => {
-                error!("[TUN] failed to get device netmask, error: {}", err);
-                return Err(io::Error::new(io::ErrorKind::Other, err));
-            }
-        };
-
-        let address_net = match IpNet::with_netmask(address, netmask) {
-            Ok(n) => n,
-            Err(err) => {
-                error!("[TUN] invalid address {}, netmask {}, error: {}", address, netmask, err);
-                return Err(io::Error::new(io::ErrorKind::Other, err));
+                error!("[TUN] failed to get device network, error: {}", err);
+                return Err(err);
             }
         };
 
         trace!(
             "[TUN] tun device network: {} (address: {}, netmask: {})",
             address_net,
-            address,
-            netmask
+            address_net.addr(),
+            address_net.netmask()
         );
 
         let address_broadcast = address_net.broadcast();
 
-        let mut packet_buffer = vec![0u8; 65536].into_boxed_slice();
+        let mut packet_buffer = vec![0u8; self.device.frame_buffer_size()].into_boxed_slice();
+        #[cfg(target_os = "linux")]
+        let mut segments = Vec::new();
         let mut udp_cleanup_timer = time::interval(self.udp_cleanup_interval);
+        // Ticks immediately, which installs IPv6 routes of the current upstream network
+        let mut ipv6_refresh_timer = time::interval(IPV6_REFRESH_INTERVAL);
+        let routes_ipv6 = self.routes_ipv6();
 
         loop {
             tokio::select! {
@@ -210,6 +480,24 @@ impl Tun {
                 n = self.device.read(&mut packet_buffer) => {
                     let n = n?;
 
+                    // GSO packets are split into segments
+                    #[cfg(target_os = "linux")]
+                    if let TunDevice::Offload(..) = self.device {
+                        segments.clear();
+                        if let Err(err) = offload::split_frame(&mut packet_buffer[..n], &mut segments) {
+                            error!("[TUN] invalid frame, error: {}, {:?}", err, ByteStr::new(&packet_buffer[..n]));
+                            continue;
+                        }
+                        for segment in &segments {
+                            trace!("[TUN] received IP packet {:?}", ByteStr::new(segment));
+
+                            if let Err(err) = self.handle_tun_frame(&address_broadcast, segment).await {
+                                error!("[TUN] handle IP frame failed, error: {}", err);
+                            }
+                        }
+                        continue;
+                    }
+
                     let packet = &mut packet_buffer[..n];
                     trace!("[TUN] received IP packet {:?}", ByteStr::new(packet));
 
@@ -239,14 +527,62 @@ impl Tun {
                     self.udp.cleanup_expired().await;
                 }
 
+                // Upstream IPv6 prefixes and default gateway
+                _ = ipv6_refresh_timer.tick(), if routes_ipv6 => {
+                    self.refresh_ipv6().await;
+                }
+
                 // UDP keep-alive associations
                 peer_addr_opt = self.udp_keepalive_rx.recv() => {
                     let peer_addr = peer_addr_opt.expect("UDP keep-alive channel closed unexpectly");
                     self.udp.keep_alive(&peer_addr).await;
                 }
 
+                // ICMP echo replies
+                packet = self.icmp.recv_packet() => {
+                    match self.device.write(&packet).await {
+                        Ok(n) => {
+                            if n < packet.len() {
+                                warn!("[TUN] sent IP packet (ICMP), but truncated. sent {} < {}, {:?}", n, packet.len(), ByteStr::new(&packet));
+                            } else {
+                                trace!("[TUN] sent IP packet (ICMP) {:?}", ByteStr::new(&packet));
+                            }
+                        }
+                        Err(err) => {
+                            error!("[TUN] failed to set packet information, error: {}, {:?}", err, ByteStr::new(&packet));
+                        }
+                    }
+                }
+
                 // TCP channel sent back
                 packet = self.tcp.recv_packet() => {
+                    // Segments of the same flow are coalesced into GSO packets
+                    #[cfg(target_os = "linux")]
+                    if let TunDevice::Offload(ref device) = self.device {
+                        let mut packets = vec![packet];
+                        while packets.len() < MAX_COALESCE_PACKETS {
+                            match self.tcp.try_recv_packet() {
+                                Some(p) => packets.push(p),
+                                None => break,
+                            }
+                        }
+                        for (hdr, packet) in offload::coalesce_tcp(packets) {
+                            match device.write(&hdr, &packet).await {
+                                Ok(n) => {
+                                    if n < packet.len() {
+                                        warn!("[TUN] sent IP packet (TCP), but truncated. sent {} < {}, {:?}", n, packet.len(), ByteStr::new(&packet));
+                                    } else {
+                                        trace!("[TUN] sent IP packet (TCP) {:?}", ByteStr::new(&packet));
+                                    }
+                                }
+                                Err(err) => {
+                                    error!("[TUN] failed to set packet information, error: {}, {:?}", err, ByteStr::new(&packet));
+                                }
+                            }
+                        }
+                        continue;
+                    }
+
                     match self.device.write(&packet).await {
                         Ok(n) => {
                             if n < packet.len() {
@@ -264,6 +600,57 @@ impl Tun {
         }
     }
 
+    fn routes_ipv6(&self) -> bool {
+        cfg_if! {
+            if #[cfg(any(target_os = "linux", target_os = "macos"))] {
+                self.routes.as_ref().is_some_and(TunRoutes::routes_ipv6)
+            } else {
+                false
+            }
+        }
+    }
+
+    /// Follow changes of the upstream IPv6 network, and exclude the new prefixes from fake DNS addresses
+    async fn refresh_ipv6(&mut self) {
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        {
+            let mut routes = match self.routes.take() {
+                Some(routes) => routes,
+                None => return,
+            };
+
+            let result = match tokio::task::spawn_blocking(move || {
+                let result = routes.refresh_ipv6();
+                (routes, result)
+            })
+            .await
+            {
+                Ok((routes, result)) => {
+                    self.routes = Some(routes);
+                    result
+                }
+                Err(err) => {
+                    error!("[TUN] refresh IPv6 routes panicked, error: {}", err);
+                    return;
+                }
+            };
+
+            match result {
+                Ok(None) => {}
+                #[cfg(feature = "local-fake-dns")]
+                Ok(Some(prefixes)) => {
+                    let prefixes = prefixes.into_iter().map(IpNet::V6).collect();
+                    self.context.set_fake_dns_excluded_networks(prefixes).await;
+                }
+                #[cfg(not(feature = "local-fake-dns"))]
+                Ok(Some(..)) => {}
+                Err(err) => {
+                    error!("[TUN] failed to refresh IPv6 routes, error: {}", err);
+                }
+            }
+        }
+    }
+
     async fn handle_tun_frame(&mut self, device_broadcast_addr: &IpAddr, frame: &[u8]) -> smoltcp::wire::Result<()> {
         let packet = match IpPacket::new_checked(frame)? {
             Some(packet) => packet,
@@ -335,8 +722,23 @@ impl Tun {
                     tcp_packet
                 );
 
+                // DNS over TCP is never hijacked, but could be blocked
+                if self
+                    .dns_hijack
+                    .iter()
+                    .find(|rule| rule.matches(&dst_addr))
+                    .is_some_and(|rule| rule.action == TunDnsHijackAction::Block)
+                {
+                    trace!("[TUN] TCP {} -> {} blocked by DNS hijack rules", src_addr, dst_addr);
+                    return Ok(());
+                }
+
                 // TCP first handshake packet.
-                if let Err(err) = self.tcp.handle_packet(src_addr, dst_addr, &tcp_packet).await {
+                if let Err(err) = self
+                    .tcp
+                    .handle_packet(src_addr, dst_addr, packet.traffic_class(), &tcp_packet)
+                    .await
+                {
                     error!(
                         "handle TCP packet failed, error: {}, {} <-> {}, packet: {:?}",
                         err, src_addr, dst_addr, tcp_packet
@@ -381,11 +783,23 @@ impl Tun {
                     udp_packet
                 );
 
-                if let Err(err) = self.udp.handle_packet(src_addr, dst_addr, payload).await {
+                if let Err(err) = self
+                    .udp
+                    .handle_packet(src_addr, dst_addr, packet.traffic_class(), payload)
+                    .await
+                {
                     error!("handle UDP packet failed, err: {}, packet: {:?}", err, udp_packet);
                 }
             }
             IpProtocol::Icmp | IpProtocol::Icmpv6 => {
+                if self
+                    .icmp
+                    .handle_packet(src_ip_addr, dst_ip_addr, packet.payload())
+                    .await
+                {
+                    return Ok(());
+                }
+
                 // ICMP is handled by TCP's Interface.
                 // smoltcp's interface will always send replies to EchoRequest
                 self.tcp.drive_interface_state(frame).await;
@@ -0,0 +1,795 @@
+//! Linux tun device with virtio-net header offloads
+//!
+//! With `IFF_VNET_HDR` and `TUNSETOFFLOAD`, the kernel passes TCP streams to the device as GSO packets of up to 64KiB,
+//! and accepts them in the other direction, so one `read` or `write` carries dozens of segments. GSO packets read from
+//! the device are split into segments for the TCP stack, and segments of the same flow are coalesced before written.
+
+use std::{
+    ffi::CStr,
+    fs::{File, OpenOptions},
+    io::{self, IoSlice, Read, Write},
+    net::IpAddr,
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+};
+
+use ipnet::IpNet;
+use log::trace;
+use nix::{ioctl_write_int, ioctl_write_ptr};
+use tokio::io::unix::AsyncFd;
+
+use super::route::run;
+
+ioctl_write_ptr!(tun_set_iff, b'T', 202, libc::c_int);
+ioctl_write_int!(tun_set_offload, b'T', 208);
+ioctl_write_ptr!(tun_set_vnet_hdr_sz, b'T', 216, libc::c_int);
+
+// linux/if_tun.h
+const IFF_TUN: libc::c_short = 0x0001;
+const IFF_NO_PI: libc::c_short = 0x1000;
+const IFF_VNET_HDR: libc::c_short = 0x4000;
+const TUN_F_CSUM: libc::c_uint = 0x01;
+const TUN_F_TSO4: libc::c_uint = 0x02;
+const TUN_F_TSO6: libc::c_uint = 0x04;
+
+// linux/virtio_net.h
+pub const VIRTIO_NET_HDR_LEN: usize = 10;
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+/// Largest frame read from the device, a GSO packet with its header
+pub const MAX_FRAME_SIZE: usize = VIRTIO_NET_HDR_LEN + 65535;
+
+/// MTU of the device if it is not configured, segments of GSO packets are not larger than it
+pub const DEFAULT_MTU: u32 = 1500;
+
+const IPV6_HEADER_LEN: usize = 40;
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_PSH: u8 = 0x08;
+const TCP_FLAG_ACK: u8 = 0x10;
+const TCP_FLAG_CWR: u8 = 0x80;
+const TCP_CHECKSUM_OFFSET: usize = 16;
+
+#[repr(C)]
+struct IfReqFlags {
+    name: [libc::c_char; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+/// `struct virtio_net_hdr`, in native byte order of the legacy interface
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VirtioNetHdr {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+}
+
+impl VirtioNetHdr {
+    fn decode(buf: &[u8]) -> VirtioNetHdr {
+        VirtioNetHdr {
+            flags: buf[0],
+            gso_type: buf[1],
+            hdr_len: u16::from_ne_bytes([buf[2], buf[3]]),
+            gso_size: u16::from_ne_bytes([buf[4], buf[5]]),
+            csum_start: u16::from_ne_bytes([buf[6], buf[7]]),
+            csum_offset: u16::from_ne_bytes([buf[8], buf[9]]),
+        }
+    }
+
+    fn encode(&self) -> [u8; VIRTIO_NET_HDR_LEN] {
+        let mut buf = [0u8; VIRTIO_NET_HDR_LEN];
+        buf[0] = self.flags;
+        buf[1] = self.gso_type;
+        buf[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+        buf[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+        buf[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+        buf[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+        buf
+    }
+}
+
+/// Tun device created with `IFF_VNET_HDR`, offloading checksums and TCP segmentation
+pub struct OffloadTunDevice {
+    io: AsyncFd<File>,
+    name: String,
+    address: IpNet,
+    mtu: u32,
+}
+
+impl OffloadTunDevice {
+    /// Create a device named `name` (or `tun%d`) with `address` and `mtu`, and bring it up
+    ///
+    /// Addresses are configured with `ip`, it should be called in a blocking context.
+    pub fn create(
+        name: Option<&str>,
+        address: IpNet,
+        destination: Option<IpAddr>,
+        mtu: u32,
+    ) -> io::Result<OffloadTunDevice> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/net/tun")?;
+
+        let mut req = IfReqFlags {
+            name: [0; libc::IFNAMSIZ],
+            flags: IFF_TUN | IFF_NO_PI | IFF_VNET_HDR,
+            _pad: [0; 22],
+        };
+        if let Some(name) = name {
+            if name.len() >= libc::IFNAMSIZ {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "tun name too long"));
+            }
+            for (dst, src) in req.name.iter_mut().zip(name.bytes()) {
+                *dst = src as libc::c_char;
+            }
+        }
+
+        let fd = file.as_raw_fd();
+        let vnet_hdr_sz = VIRTIO_NET_HDR_LEN as libc::c_int;
+        unsafe {
+            tun_set_iff(fd, &req as *const IfReqFlags as *const libc::c_int)?;
+            tun_set_vnet_hdr_sz(fd, &vnet_hdr_sz)?;
+            tun_set_offload(
+                fd,
+                (TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6) as nix::sys::ioctl::ioctl_param_type,
+            )?;
+        }
+
+        // Name of the device is filled by the kernel
+        let name = unsafe { CStr::from_ptr(req.name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+
+        let address_arg = address.to_string();
+        let mut args = vec!["address", "add", address_arg.as_str()];
+        let destination_arg = destination.map(|d| d.to_string());
+        if let Some(ref destination) = destination_arg {
+            args.extend(["peer", destination.as_str()]);
+        }
+        args.extend(["dev", name.as_str()]);
+        run("ip", &args)?;
+
+        let mtu_arg = mtu.to_string();
+        run("ip", &["link", "set", "dev", &name, "mtu", &mtu_arg, "up"])?;
+
+        Ok(OffloadTunDevice {
+            io: AsyncFd::new(file)?,
+            name,
+            address,
+            mtu,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn address(&self) -> IpNet {
+        self.address
+    }
+
+    pub fn mtu(&self) -> u32 {
+        self.mtu
+    }
+
+    /// Read a frame, a virtio-net header followed by an IP packet
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.io.readable().await?;
+            match guard.try_io(|inner| inner.get_ref().read(buf)) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Write `packet` with virtio-net header `hdr`, returns the length of `packet` written
+    pub async fn write(&self, hdr: &VirtioNetHdr, packet: &[u8]) -> io::Result<usize> {
+        let hdr = hdr.encode();
+        let bufs = [IoSlice::new(&hdr), IoSlice::new(packet)];
+        loop {
+            let mut guard = self.io.writable().await?;
+            match guard.try_io(|inner| inner.get_ref().write_vectored(&bufs)) {
+                Ok(result) => return result.map(|n| n.saturating_sub(VIRTIO_NET_HDR_LEN)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+fn invalid_frame(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Sum of 16-bit words of `data` added to `sum`, not folded
+fn checksum_add(mut sum: u64, data: &[u8]) -> u64 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u64;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u64) << 8;
+    }
+    sum
+}
+
+fn checksum_fold(mut sum: u64) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Sum of the TCP pseudo header of `packet` with TCP length `tcp_len`, not folded
+fn pseudo_header_sum(packet: &[u8], tcp_len: usize) -> u64 {
+    let sum = if packet[0] >> 4 == 4 {
+        checksum_add(0, &packet[12..20])
+    } else {
+        checksum_add(0, &packet[8..40])
+    };
+    sum + libc::IPPROTO_TCP as u64 + tcp_len as u64
+}
+
+/// Length of the IP header of a TCP packet, `None` if it is not TCP or has IPv6 extension headers
+fn tcp_ip_header_len(packet: &[u8]) -> Option<usize> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 && packet[9] as i32 == libc::IPPROTO_TCP && packet[0] & 0x0f >= 5 => {
+            Some((packet[0] & 0x0f) as usize * 4)
+        }
+        6 if packet.len() >= IPV6_HEADER_LEN && packet[6] as i32 == libc::IPPROTO_TCP => Some(IPV6_HEADER_LEN),
+        _ => None,
+    }
+}
+
+/// Length of the IP and TCP headers of a TCP packet, `None` if the TCP data offset is invalid or exceeds `packet`
+fn tcp_header_len(packet: &[u8], ip_header_len: usize) -> Option<usize> {
+    let data_offset = *packet.get(ip_header_len + 12)? >> 4;
+    if data_offset < 5 {
+        return None;
+    }
+    let header_len = ip_header_len + data_offset as usize * 4;
+    if header_len > packet.len() {
+        return None;
+    }
+    Some(header_len)
+}
+
+/// Set lengths of the IP header to `packet.len()`, and the checksum of IPv4 header
+fn set_ip_length(packet: &mut [u8], ip_header_len: usize) {
+    let len = packet.len();
+    if packet[0] >> 4 == 4 {
+        packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        packet[10..12].copy_from_slice(&[0, 0]);
+        let checksum = !checksum_fold(checksum_add(0, &packet[..ip_header_len]));
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    } else {
+        packet[4..6].copy_from_slice(&((len - IPV6_HEADER_LEN) as u16).to_be_bytes());
+    }
+}
+
+/// Split a frame read from the device into IP packets, with complete checksums
+pub fn split_frame(frame: &mut [u8], packets: &mut Vec<Vec<u8>>) -> io::Result<()> {
+    if frame.len() < VIRTIO_NET_HDR_LEN {
+        return Err(invalid_frame("frame shorter than virtio-net header"));
+    }
+
+    let hdr = VirtioNetHdr::decode(&frame[..VIRTIO_NET_HDR_LEN]);
+    let packet = &mut frame[VIRTIO_NET_HDR_LEN..];
+
+    match hdr.gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+        VIRTIO_NET_HDR_GSO_NONE => {
+            if hdr.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+                // The checksum field holds the sum of the pseudo header
+                let csum_start = hdr.csum_start as usize;
+                let csum_at = csum_start + hdr.csum_offset as usize;
+                if csum_at + 2 > packet.len() {
+                    return Err(invalid_frame("checksum offset out of packet"));
+                }
+                let initial = u16::from_be_bytes([packet[csum_at], packet[csum_at + 1]]);
+                packet[csum_at..csum_at + 2].copy_from_slice(&[0, 0]);
+                let checksum = !checksum_fold(checksum_add(initial as u64, &packet[csum_start..]));
+                packet[csum_at..csum_at + 2].copy_from_slice(&checksum.to_be_bytes());
+            }
+            packets.push(packet.to_vec());
+            Ok(())
+        }
+        VIRTIO_NET_HDR_GSO_TCPV4 | VIRTIO_NET_HDR_GSO_TCPV6 => split_tcp(packet, hdr.gso_size as usize, packets),
+        _ => Err(invalid_frame("unsupported GSO type")),
+    }
+}
+
+/// Split a TCP GSO packet into segments carrying `gso_size` bytes of payload
+fn split_tcp(packet: &[u8], gso_size: usize, packets: &mut Vec<Vec<u8>>) -> io::Result<()> {
+    let ip_header_len = tcp_ip_header_len(packet).ok_or_else(|| invalid_frame("GSO packet is not TCP"))?;
+    if packet.len() < ip_header_len + 20 || gso_size == 0 {
+        return Err(invalid_frame("truncated TCP GSO packet"));
+    }
+    let header_len =
+        tcp_header_len(packet, ip_header_len).ok_or_else(|| invalid_frame("invalid TCP data offset in GSO packet"))?;
+
+    let is_ipv4 = packet[0] >> 4 == 4;
+    let ip_id = u16::from_be_bytes([packet[4], packet[5]]);
+    let seq = u32::from_be_bytes(packet[ip_header_len + 4..ip_header_len + 8].try_into().unwrap());
+    let flags = packet[ip_header_len + 13];
+
+    let payload = &packet[header_len..];
+    let count = payload.len().div_ceil(gso_size);
+    trace!(
+        "[TUN] split TCP GSO packet of {} bytes into {} segments",
+        packet.len(),
+        count
+    );
+
+    for (index, chunk) in payload.chunks(gso_size).enumerate() {
+        let mut segment = Vec::with_capacity(header_len + chunk.len());
+        segment.extend_from_slice(&packet[..header_len]);
+        segment.extend_from_slice(chunk);
+
+        if is_ipv4 {
+            segment[4..6].copy_from_slice(&ip_id.wrapping_add(index as u16).to_be_bytes());
+        }
+        set_ip_length(&mut segment, ip_header_len);
+
+        let tcp = &mut segment[ip_header_len..];
+        let segment_seq = seq.wrapping_add((index * gso_size) as u32);
+        tcp[4..8].copy_from_slice(&segment_seq.to_be_bytes());
+        let mut segment_flags = flags;
+        if index + 1 < count {
+            segment_flags &= !(TCP_FLAG_FIN | TCP_FLAG_PSH);
+        }
+        if index > 0 {
+            segment_flags &= !TCP_FLAG_CWR;
+        }
+        tcp[13] = segment_flags;
+
+        tcp[TCP_CHECKSUM_OFFSET..TCP_CHECKSUM_OFFSET + 2].copy_from_slice(&[0, 0]);
+        let sum = pseudo_header_sum(&segment, segment.len() - ip_header_len);
+        let checksum = !checksum_fold(checksum_add(sum, &segment[ip_header_len..]));
+        segment[ip_header_len + TCP_CHECKSUM_OFFSET..ip_header_len + TCP_CHECKSUM_OFFSET + 2]
+            .copy_from_slice(&checksum.to_be_bytes());
+
+        packets.push(segment);
+    }
+
+    Ok(())
+}
+
+/// A GSO packet being coalesced
+struct Coalescing {
+    packet: Vec<u8>,
+    ip_header_len: usize,
+    header_len: usize,
+    gso_size: usize,
+    segments: usize,
+}
+
+impl Coalescing {
+    fn new(packet: Vec<u8>) -> Coalescing {
+        // Packets with invalid headers are passed as is
+        let (ip_header_len, header_len) = match tcp_ip_header_len(&packet) {
+            Some(ip_header_len) if packet.len() >= ip_header_len + 20 => match tcp_header_len(&packet, ip_header_len) {
+                Some(header_len) => (ip_header_len, header_len),
+                None => (0, 0),
+            },
+            _ => (0, 0),
+        };
+        Coalescing {
+            gso_size: packet.len() - header_len,
+            packet,
+            ip_header_len,
+            header_len,
+            segments: 1,
+        }
+    }
+
+    /// Append `next` if it is the following segment of the same flow, with the same headers except sequence numbers
+    fn try_append(&mut self, next: &[u8]) -> bool {
+        let header_len = self.header_len;
+        let ip_header_len = self.ip_header_len;
+
+        // Only IPv4 without options, or IPv6 without extension headers, and only pure ACK segments with payloads
+        if header_len == 0 || (ip_header_len != 20 && ip_header_len != IPV6_HEADER_LEN) || self.gso_size == 0 {
+            return false;
+        }
+        if next.len() <= header_len || tcp_ip_header_len(next) != Some(ip_header_len) {
+            return false;
+        }
+        let next_payload_len = next.len() - header_len;
+        if next_payload_len > self.gso_size || self.packet.len() + next_payload_len > 65535 {
+            return false;
+        }
+        // Only the last segment could be smaller
+        if (self.packet.len() - header_len) % self.gso_size != 0 {
+            return false;
+        }
+
+        let (cur, nxt) = (&self.packet[..header_len], &next[..header_len]);
+        let cur_flags = cur[ip_header_len + 13];
+        let next_flags = nxt[ip_header_len + 13];
+        if cur_flags != TCP_FLAG_ACK || next_flags & !TCP_FLAG_PSH != TCP_FLAG_ACK {
+            return false;
+        }
+
+        // Addresses, ports, TCP header length, acknowledgement number, window and options
+        let same_headers = if ip_header_len == 20 {
+            cur[0] == nxt[0] && cur[1] == nxt[1] && cur[6..10] == nxt[6..10] && cur[12..20] == nxt[12..20]
+        } else {
+            cur[0..4] == nxt[0..4] && cur[6..40] == nxt[6..40]
+        };
+        let (cur_tcp, next_tcp) = (&cur[ip_header_len..], &nxt[ip_header_len..]);
+        if !same_headers
+            || cur_tcp[0..4] != next_tcp[0..4]
+            || cur_tcp[8..13] != next_tcp[8..13]
+            || cur_tcp[14..16] != next_tcp[14..16]
+            || cur_tcp[20..] != next_tcp[20..]
+        {
+            return false;
+        }
+
+        let cur_seq = u32::from_be_bytes(cur_tcp[4..8].try_into().unwrap());
+        let next_seq = u32::from_be_bytes(next_tcp[4..8].try_into().unwrap());
+        if cur_seq.wrapping_add((self.packet.len() - header_len) as u32) != next_seq {
+            return false;
+        }
+
+        self.packet.extend_from_slice(&next[header_len..]);
+        self.packet[ip_header_len + 13] |= next_flags & TCP_FLAG_PSH;
+        self.segments += 1;
+        true
+    }
+
+    fn finish(mut self) -> (VirtioNetHdr, Vec<u8>) {
+        if self.segments == 1 {
+            return (VirtioNetHdr::default(), self.packet);
+        }
+
+        let ip_header_len = self.ip_header_len;
+        set_ip_length(&mut self.packet, ip_header_len);
+
+        // Checksum is completed by the kernel, the field holds the sum of the pseudo header
+        let sum = pseudo_header_sum(&self.packet, self.packet.len() - ip_header_len);
+        let csum_at = ip_header_len + TCP_CHECKSUM_OFFSET;
+        self.packet[csum_at..csum_at + 2].copy_from_slice(&checksum_fold(sum).to_be_bytes());
+
+        let hdr = VirtioNetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: if self.packet[0] >> 4 == 4 {
+                VIRTIO_NET_HDR_GSO_TCPV4
+            } else {
+                VIRTIO_NET_HDR_GSO_TCPV6
+            },
+            hdr_len: self.header_len as u16,
+            gso_size: self.gso_size as u16,
+            csum_start: ip_header_len as u16,
+            csum_offset: TCP_CHECKSUM_OFFSET as u16,
+        };
+        trace!(
+            "[TUN] coalesced {} TCP segments into GSO packet of {} bytes",
+            self.segments,
+            self.packet.len()
+        );
+        (hdr, self.packet)
+    }
+}
+
+/// Coalesce consecutive TCP segments of the same flow in `packets` into GSO packets
+pub fn coalesce_tcp(packets: Vec<Vec<u8>>) -> Vec<(VirtioNetHdr, Vec<u8>)> {
+    let mut frames = Vec::with_capacity(packets.len());
+    let mut current: Option<Coalescing> = None;
+
+    for packet in packets {
+        if let Some(ref mut coalescing) = current {
+            if coalescing.try_append(&packet) {
+                continue;
+            }
+        }
+        if let Some(coalescing) = current.replace(Coalescing::new(packet)) {
+            frames.push(coalescing.finish());
+        }
+    }
+    if let Some(coalescing) = current {
+        frames.push(coalescing.finish());
+    }
+
+    frames
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// TCP packet from 10.0.0.1:1234 to 10.0.0.2:443 (or fd00::1 to fd00::2), with complete checksums
+    fn tcp_packet(ipv6: bool, id: u16, seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = if ipv6 {
+            let mut header = vec![0u8; IPV6_HEADER_LEN];
+            header[0] = 0x60;
+            header[6] = libc::IPPROTO_TCP as u8;
+            header[7] = 64;
+            header[8..10].copy_from_slice(&[0xfd, 0x00]);
+            header[23] = 1;
+            header[24..26].copy_from_slice(&[0xfd, 0x00]);
+            header[39] = 2;
+            header
+        } else {
+            let mut header = vec![0u8; 20];
+            header[0] = 0x45;
+            header[4..6].copy_from_slice(&id.to_be_bytes());
+            header[8] = 64;
+            header[9] = libc::IPPROTO_TCP as u8;
+            header[12..16].copy_from_slice(&[10, 0, 0, 1]);
+            header[16..20].copy_from_slice(&[10, 0, 0, 2]);
+            header
+        };
+        let ip_header_len = packet.len();
+
+        let mut tcp = [0u8; 20];
+        tcp[0..2].copy_from_slice(&1234u16.to_be_bytes());
+        tcp[2..4].copy_from_slice(&443u16.to_be_bytes());
+        tcp[4..8].copy_from_slice(&seq.to_be_bytes());
+        tcp[8..12].copy_from_slice(&1u32.to_be_bytes());
+        tcp[12] = 0x50;
+        tcp[13] = flags;
+        tcp[14..16].copy_from_slice(&65535u16.to_be_bytes());
+        packet.extend_from_slice(&tcp);
+        packet.extend_from_slice(payload);
+
+        set_ip_length(&mut packet, ip_header_len);
+        let sum = pseudo_header_sum(&packet, packet.len() - ip_header_len);
+        let checksum = !checksum_fold(checksum_add(sum, &packet[ip_header_len..]));
+        packet[ip_header_len + TCP_CHECKSUM_OFFSET..ip_header_len + TCP_CHECKSUM_OFFSET + 2]
+            .copy_from_slice(&checksum.to_be_bytes());
+        packet
+    }
+
+    fn frame(hdr: &VirtioNetHdr, packet: &[u8]) -> Vec<u8> {
+        let mut frame = hdr.encode().to_vec();
+        frame.extend_from_slice(packet);
+        frame
+    }
+
+    fn gso_hdr(ipv6: bool, gso_size: u16) -> VirtioNetHdr {
+        VirtioNetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: if ipv6 {
+                VIRTIO_NET_HDR_GSO_TCPV6
+            } else {
+                VIRTIO_NET_HDR_GSO_TCPV4
+            },
+            hdr_len: if ipv6 { 60 } else { 40 },
+            gso_size,
+            csum_start: if ipv6 { 40 } else { 20 },
+            csum_offset: TCP_CHECKSUM_OFFSET as u16,
+        }
+    }
+
+    fn assert_checksums(packet: &[u8]) {
+        let ip_header_len = tcp_ip_header_len(packet).unwrap();
+        if ip_header_len == 20 {
+            assert_eq!(
+                checksum_fold(checksum_add(0, &packet[..20])),
+                0xffff,
+                "IPv4 header checksum"
+            );
+        }
+        let sum = pseudo_header_sum(packet, packet.len() - ip_header_len);
+        assert_eq!(
+            checksum_fold(checksum_add(sum, &packet[ip_header_len..])),
+            0xffff,
+            "TCP checksum"
+        );
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    fn split(frame: &mut [u8]) -> io::Result<Vec<Vec<u8>>> {
+        let mut packets = Vec::new();
+        split_frame(frame, &mut packets)?;
+        Ok(packets)
+    }
+
+    #[test]
+    fn test_split_tcp_gso() {
+        for ipv6 in [false, true] {
+            let data = payload(2500);
+            let packet = tcp_packet(ipv6, 100, 1000, TCP_FLAG_ACK | TCP_FLAG_PSH | TCP_FLAG_FIN, &data);
+            let segments = split(&mut frame(&gso_hdr(ipv6, 1000), &packet)).unwrap();
+            assert_eq!(segments.len(), 3);
+
+            let ip_header_len = if ipv6 { IPV6_HEADER_LEN } else { 20 };
+            for (index, segment) in segments.iter().enumerate() {
+                let expected = &data[index * 1000..((index + 1) * 1000).min(data.len())];
+                let expected_flags = if index == 2 {
+                    TCP_FLAG_ACK | TCP_FLAG_PSH | TCP_FLAG_FIN
+                } else {
+                    TCP_FLAG_ACK
+                };
+                assert_eq!(
+                    *segment,
+                    tcp_packet(
+                        ipv6,
+                        100 + index as u16,
+                        1000 + index as u32 * 1000,
+                        expected_flags,
+                        expected
+                    )
+                );
+                assert_eq!(&segment[ip_header_len + 20..], expected);
+                assert_checksums(segment);
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_tcp_single_segment() {
+        let packet = tcp_packet(false, 1, 1, TCP_FLAG_ACK, &payload(1000));
+        let segments = split(&mut frame(&gso_hdr(false, 1000), &packet)).unwrap();
+        assert_eq!(segments, vec![packet]);
+    }
+
+    #[test]
+    fn test_split_tcp_invalid_data_offset() {
+        for data_offset in [0u8, 4] {
+            let mut packet = tcp_packet(false, 1, 1, TCP_FLAG_ACK, &payload(100));
+            packet[20 + 12] = data_offset << 4;
+            let err = split(&mut frame(&gso_hdr(false, 50), &packet)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        // TCP header of 60 bytes exceeds the packet
+        let mut packet = tcp_packet(false, 1, 1, TCP_FLAG_ACK, &payload(10));
+        packet[20 + 12] = 0xf0;
+        let err = split(&mut frame(&gso_hdr(false, 50), &packet)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_split_tcp_malformed() {
+        let packet = tcp_packet(false, 1, 1, TCP_FLAG_ACK, &payload(100));
+
+        // Truncated TCP header
+        let err = split(&mut frame(&gso_hdr(false, 50), &packet[..30])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // Zero gso_size
+        let err = split(&mut frame(&gso_hdr(false, 0), &packet)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // IHL less than 5
+        let mut bad = packet.clone();
+        bad[0] = 0x44;
+        let err = split(&mut frame(&gso_hdr(false, 50), &bad)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // Not TCP
+        let mut bad = packet.clone();
+        bad[9] = libc::IPPROTO_UDP as u8;
+        let err = split(&mut frame(&gso_hdr(false, 50), &bad)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // Unsupported GSO type
+        let mut hdr = gso_hdr(false, 50);
+        hdr.gso_type = 3;
+        let err = split(&mut frame(&hdr, &packet)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // Shorter than virtio-net header
+        let err = split(&mut [0u8; VIRTIO_NET_HDR_LEN - 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_split_checksum_completion() {
+        let packet = tcp_packet(false, 1, 1, TCP_FLAG_ACK, &payload(101));
+
+        // The checksum field holds the sum of the pseudo header only
+        let mut partial = packet.clone();
+        let sum = checksum_fold(pseudo_header_sum(&partial, partial.len() - 20));
+        partial[20 + TCP_CHECKSUM_OFFSET..20 + TCP_CHECKSUM_OFFSET + 2].copy_from_slice(&sum.to_be_bytes());
+        let hdr = VirtioNetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            csum_start: 20,
+            csum_offset: TCP_CHECKSUM_OFFSET as u16,
+            ..Default::default()
+        };
+        assert_eq!(split(&mut frame(&hdr, &partial)).unwrap(), vec![packet.clone()]);
+
+        let hdr = VirtioNetHdr {
+            csum_offset: packet.len() as u16,
+            ..hdr
+        };
+        let err = split(&mut frame(&hdr, &packet)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Without offloads
+        assert_eq!(
+            split(&mut frame(&VirtioNetHdr::default(), &packet)).unwrap(),
+            vec![packet]
+        );
+    }
+
+    #[test]
+    fn test_coalesce_tcp() {
+        for ipv6 in [false, true] {
+            let segments = vec![
+                tcp_packet(ipv6, 10, 1, TCP_FLAG_ACK, &payload(1000)),
+                tcp_packet(ipv6, 11, 1001, TCP_FLAG_ACK, &payload(1000)),
+                tcp_packet(ipv6, 12, 2001, TCP_FLAG_ACK | TCP_FLAG_PSH, &payload(500)),
+            ];
+            let frames = coalesce_tcp(segments.clone());
+            assert_eq!(frames.len(), 1);
+
+            let (hdr, packet) = &frames[0];
+            let header_len = if ipv6 { 60 } else { 40 };
+            assert_eq!(hdr.gso_size, 1000);
+            assert_eq!(hdr.hdr_len as usize, header_len);
+            assert_eq!(packet.len(), header_len + 2500);
+
+            // Segmented by the kernel in the same way
+            assert_eq!(split(&mut frame(hdr, packet)).unwrap(), segments);
+        }
+    }
+
+    #[test]
+    fn test_coalesce_tcp_boundaries() {
+        // A gap of sequence numbers
+        let frames = coalesce_tcp(vec![
+            tcp_packet(false, 1, 1, TCP_FLAG_ACK, &payload(100)),
+            tcp_packet(false, 2, 201, TCP_FLAG_ACK, &payload(100)),
+        ]);
+        assert_eq!(frames.len(), 2);
+
+        // Segments after a smaller one
+        let frames = coalesce_tcp(vec![
+            tcp_packet(false, 1, 1, TCP_FLAG_ACK, &payload(100)),
+            tcp_packet(false, 2, 101, TCP_FLAG_ACK, &payload(50)),
+            tcp_packet(false, 3, 151, TCP_FLAG_ACK, &payload(50)),
+        ]);
+        assert_eq!(frames.len(), 2);
+
+        // Segments larger than the first one
+        let frames = coalesce_tcp(vec![
+            tcp_packet(false, 1, 1, TCP_FLAG_ACK, &payload(50)),
+            tcp_packet(false, 2, 51, TCP_FLAG_ACK, &payload(100)),
+        ]);
+        assert_eq!(frames.len(), 2);
+
+        // Not pure ACK segments
+        let frames = coalesce_tcp(vec![
+            tcp_packet(false, 1, 1, TCP_FLAG_ACK | TCP_FLAG_FIN, &payload(100)),
+            tcp_packet(false, 2, 101, TCP_FLAG_ACK, &payload(100)),
+        ]);
+        assert_eq!(frames.len(), 2);
+
+        // Up to 64KiB
+        let segments: Vec<Vec<u8>> = (0..50)
+            .map(|i| tcp_packet(false, i, 1 + i as u32 * 1400, TCP_FLAG_ACK, &payload(1400)))
+            .collect();
+        let frames = coalesce_tcp(segments);
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|(_, packet)| packet.len() <= 65535));
+    }
+
+    #[test]
+    fn test_coalesce_tcp_passthrough() {
+        let single = tcp_packet(false, 1, 1, TCP_FLAG_ACK, &payload(100));
+        let mut invalid = tcp_packet(false, 2, 101, TCP_FLAG_ACK, &payload(100));
+        invalid[20 + 12] = 0x40;
+        let short = vec![0x45, 0x00];
+
+        let frames = coalesce_tcp(vec![single.clone(), invalid.clone(), invalid.clone(), short.clone()]);
+        let packets: Vec<Vec<u8>> = frames
+            .into_iter()
+            .map(|(hdr, packet)| {
+                assert_eq!(hdr.gso_type, VIRTIO_NET_HDR_GSO_NONE);
+                assert_eq!(hdr.flags, 0);
+                packet
+            })
+            .collect();
+        assert_eq!(packets, vec![single, invalid.clone(), invalid, short]);
+    }
+}
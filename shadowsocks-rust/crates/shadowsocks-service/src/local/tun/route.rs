@@ -0,0 +1,814 @@
+//! Automatic routes of tun devices
+//!
+//! The default route is overridden by `0.0.0.0/1` and `128.0.0.0/1` to the tun device, so the previous default
+//! route is kept untouched and restored by removing them. Servers and bypassed networks are routed through the
+//! previous default gateway, otherwise connections to servers would loop back into the tun device.
+//!
+//! On Linux, with marks or UIDs bypassing tun, the overrides are installed in a separated routing table instead,
+//! which is looked up by policy routing rules after rules of the bypassed packets.
+//!
+//! IPv6 is routed by `::/1` and `8000::/1` likewise on Linux and macOS. The IPv6 default gateway and prefixes of the
+//! upstream network change with prefix delegation of residential networks, so they are followed by
+//! [`TunRoutes::refresh_ipv6`] instead of being read once.
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::net::Ipv6Addr;
+use std::{
+    io,
+    net::IpAddr,
+    process::{Command, Stdio},
+};
+
+use ipnet::IpNet;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use ipnet::Ipv6Net;
+use log::{debug, info, warn};
+
+use crate::config::TunAutoRouteConfig;
+
+/// Halves of `0.0.0.0/0`, which are preferred to the default route for their longer prefixes
+const DEFAULT_ROUTE_OVERRIDES: [&str; 2] = ["0.0.0.0/1", "128.0.0.0/1"];
+
+/// Halves of `::/0`
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+const DEFAULT_ROUTE_OVERRIDES_V6: [&str; 2] = ["::/1", "8000::/1"];
+
+/// Priority of the first policy routing rule
+#[cfg(target_os = "linux")]
+const RULE_PRIORITY: u32 = 9000;
+
+pub(super) fn run(program: &str, args: &[&str]) -> io::Result<String> {
+    debug!("[TUN] {} {}", program, args.join(" "));
+
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!(
+            "{} {} exited with {}, {}",
+            program,
+            args.join(" "),
+            output.status,
+            stderr.trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Next hop of the default route before tun is up
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Gateway {
+    /// Address of the gateway, `None` for point-to-point interfaces
+    addr: Option<IpAddr>,
+    /// Outbound interface, an interface name on Linux and macOS, or the interface address on Windows
+    interface: String,
+}
+
+#[derive(Debug, Clone)]
+enum InstalledRoute {
+    Tun(IpNet),
+    Gateway(IpNet),
+    /// Route to tun in a routing table of policy routing
+    #[cfg(target_os = "linux")]
+    TableTun(IpNet, u32),
+    /// Address family (`-4` or `-6`), selector and action of a policy routing rule
+    #[cfg(target_os = "linux")]
+    Rule(&'static str, Vec<String>),
+    /// nftables table marking bypassed packets
+    #[cfg(target_os = "linux")]
+    Nftables(String),
+}
+
+/// IPv6 routes following the upstream network
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[derive(Debug, Default)]
+struct Ipv6Routes {
+    /// Current IPv6 default gateway, `None` if there is no IPv6 upstream
+    gateway: Option<Gateway>,
+    /// IPv6 servers and bypassed networks, routed through `gateway`
+    bypass: Vec<IpNet>,
+    /// Current global prefixes of the upstream interface
+    prefixes: Vec<Ipv6Net>,
+    /// Interface identifier of the tun address
+    address_suffix: Option<Ipv6Addr>,
+    /// Current tun address in the first upstream prefix
+    address: Option<Ipv6Addr>,
+}
+
+/// Installed routes, removed when dropped
+pub struct TunRoutes {
+    tun_name: String,
+    gateway: Gateway,
+    routes: Vec<InstalledRoute>,
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    ipv6: Option<Ipv6Routes>,
+}
+
+impl TunRoutes {
+    /// Route IPv4 (and IPv6 if enabled) traffic to `tun_name`, except `servers` and bypassed networks of `config`
+    pub fn install(tun_name: &str, config: &TunAutoRouteConfig, servers: &[IpAddr]) -> io::Result<TunRoutes> {
+        let gateway = default_gateway()?;
+        debug!("[TUN] default gateway {:?}", gateway);
+
+        let mut routes = TunRoutes {
+            tun_name: tun_name.to_owned(),
+            gateway,
+            routes: Vec::new(),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            ipv6: if config.ipv6 {
+                Some(Ipv6Routes {
+                    address_suffix: config.ipv6_address_suffix,
+                    ..Default::default()
+                })
+            } else {
+                None
+            },
+        };
+
+        let bypass = servers
+            .iter()
+            .map(|addr| IpNet::from(*addr))
+            .chain(config.bypass.iter().copied());
+        for net in bypass {
+            if let IpNet::V6(..) = net {
+                // Routed through the IPv6 gateway found by refresh_ipv6
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                if let Some(ref mut ipv6) = routes.ipv6 {
+                    if !ipv6.bypass.contains(&net) {
+                        ipv6.bypass.push(net);
+                    }
+                    continue;
+                }
+
+                debug!("[TUN] {} is not routed to tun, skipped", net);
+                continue;
+            }
+            if routes
+                .routes
+                .iter()
+                .any(|r| matches!(*r, InstalledRoute::Gateway(n) if n == net))
+            {
+                continue;
+            }
+
+            add_gateway_route(&net, &routes.gateway)?;
+            routes.routes.push(InstalledRoute::Gateway(net));
+        }
+
+        #[cfg(target_os = "linux")]
+        if config.policy_routing() {
+            routes.install_policy_routing(config)?;
+        } else {
+            routes.install_overrides()?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        routes.install_overrides()?;
+
+        info!(
+            "shadowsocks tun {} routes IPv4 traffic, previous default gateway {}",
+            tun_name,
+            match routes.gateway.addr {
+                Some(addr) => addr.to_string(),
+                None => routes.gateway.interface.clone(),
+            }
+        );
+
+        Ok(routes)
+    }
+
+    /// Networks routed to tun instead of the default route
+    fn default_route_overrides(&self) -> Vec<IpNet> {
+        let overrides = DEFAULT_ROUTE_OVERRIDES.iter();
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        let overrides = overrides.chain(if self.ipv6.is_some() {
+            DEFAULT_ROUTE_OVERRIDES_V6.iter()
+        } else {
+            [].iter()
+        });
+        overrides
+            .map(|net| net.parse::<IpNet>().expect("default route override"))
+            .collect()
+    }
+
+    /// Address families of policy routing rules
+    #[cfg(target_os = "linux")]
+    fn rule_families(&self) -> &'static [&'static str] {
+        if self.ipv6.is_some() {
+            &["-4", "-6"]
+        } else {
+            &["-4"]
+        }
+    }
+
+    fn install_overrides(&mut self) -> io::Result<()> {
+        for net in self.default_route_overrides() {
+            add_tun_route(&net, &self.tun_name)?;
+            self.routes.push(InstalledRoute::Tun(net));
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn install_policy_routing(&mut self, config: &TunAutoRouteConfig) -> io::Result<()> {
+        let table = config.route_table.to_string();
+
+        for net in self.default_route_overrides() {
+            run(
+                "ip",
+                &[
+                    "route",
+                    "replace",
+                    &net.to_string(),
+                    "dev",
+                    &self.tun_name,
+                    "table",
+                    &table,
+                ],
+            )?;
+            self.routes.push(InstalledRoute::TableTun(net, config.route_table));
+        }
+
+        if let Some(fwmark) = config.fwmark {
+            self.add_rule(&["fwmark", &fwmark.to_string(), "lookup", "main"], RULE_PRIORITY)?;
+
+            if !config.bypass_cgroups.is_empty() {
+                self.install_cgroup_marks(config, fwmark)?;
+            }
+        }
+        for &(start, end) in &config.bypass_uids {
+            self.add_rule(
+                &["uidrange", &format!("{start}-{end}"), "lookup", "main"],
+                RULE_PRIORITY + 1,
+            )?;
+        }
+        // Routes of main table are preferred except the default route, like routes of the local network
+        self.add_rule(&["lookup", "main", "suppress_prefixlength", "0"], RULE_PRIORITY + 2)?;
+        self.add_rule(&["lookup", &table], RULE_PRIORITY + 3)?;
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn add_rule(&mut self, selector: &[&str], priority: u32) -> io::Result<()> {
+        let mut rule = selector.iter().map(|s| (*s).to_owned()).collect::<Vec<_>>();
+        rule.push("pref".to_owned());
+        rule.push(priority.to_string());
+
+        for &family in self.rule_families() {
+            // Rules left by a crashed process
+            while delete_rule(family, &rule).is_ok() {}
+
+            let args = [family, "rule", "add"]
+                .into_iter()
+                .chain(rule.iter().map(String::as_str))
+                .collect::<Vec<_>>();
+            run("ip", &args)?;
+            self.routes.push(InstalledRoute::Rule(family, rule.clone()));
+        }
+        Ok(())
+    }
+
+    /// IPv6 traffic is routed to tun, and [`TunRoutes::refresh_ipv6`] has to be called periodically
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub fn routes_ipv6(&self) -> bool {
+        self.ipv6.is_some()
+    }
+
+    /// Follow the IPv6 default gateway and global prefixes of the upstream interface
+    ///
+    /// Routes of IPv6 servers and bypassed networks are moved to the new gateway, and the tun address is moved to the
+    /// new prefix. Returns the new prefixes if they changed.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub fn refresh_ipv6(&mut self) -> io::Result<Option<Vec<Ipv6Net>>> {
+        let ipv6 = match self.ipv6 {
+            Some(ref mut ipv6) => ipv6,
+            None => return Ok(None),
+        };
+
+        // No IPv6 default route if the upstream has no IPv6 connectivity (yet)
+        let gateway = match default_gateway6() {
+            Ok(gateway) => Some(gateway),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+
+        if gateway != ipv6.gateway {
+            if let Some(ref gateway) = ipv6.gateway {
+                for net in &ipv6.bypass {
+                    if let Err(err) = delete_gateway_route(net, gateway) {
+                        warn!("[TUN] failed to remove route {} via {:?}, error: {}", net, gateway, err);
+                    }
+                }
+            }
+
+            info!("shadowsocks tun {} IPv6 default gateway {:?}", self.tun_name, gateway);
+            ipv6.gateway = gateway;
+
+            if let Some(ref gateway) = ipv6.gateway {
+                for net in &ipv6.bypass {
+                    // Retried when the gateway changes again, other routes are still useful
+                    if let Err(err) = add_gateway_route(net, gateway) {
+                        warn!("[TUN] failed to add route {} via {:?}, error: {}", net, gateway, err);
+                    }
+                }
+            }
+        }
+
+        let prefixes = match ipv6.gateway {
+            Some(ref gateway) => upstream_prefixes(&gateway.interface)?,
+            None => Vec::new(),
+        };
+        if prefixes == ipv6.prefixes {
+            return Ok(None);
+        }
+
+        info!(
+            "shadowsocks tun {} upstream IPv6 prefixes {:?}",
+            self.tun_name, prefixes
+        );
+
+        if let Some(suffix) = ipv6.address_suffix {
+            let address = prefixes.first().map(|prefix| prefix_address(prefix, suffix));
+            if address != ipv6.address {
+                if let Some(address) = ipv6.address.take() {
+                    if let Err(err) = delete_tun_address(&address, &self.tun_name) {
+                        warn!("[TUN] failed to remove address {}, error: {}", address, err);
+                    }
+                }
+                if let Some(address) = address {
+                    add_tun_address(&address, &self.tun_name)?;
+                    ipv6.address = Some(address);
+                }
+            }
+        }
+
+        ipv6.prefixes.clone_from(&prefixes);
+        Ok(Some(prefixes))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn install_cgroup_marks(&mut self, config: &TunAutoRouteConfig, fwmark: u32) -> io::Result<()> {
+        let name = format!("shadowsocks_tun_{}", config.route_table);
+
+        // Table left by a crashed process
+        let _ = run("nft", &["delete", "table", "inet", &name]);
+
+        run("nft", &["add", "table", "inet", &name])?;
+        self.routes.push(InstalledRoute::Nftables(name.clone()));
+        run(
+            "nft",
+            &[
+                "add",
+                "chain",
+                "inet",
+                &name,
+                "output",
+                "{ type route hook output priority mangle; policy accept; }",
+            ],
+        )?;
+
+        for cgroup in &config.bypass_cgroups {
+            let level = cgroup.split('/').count().to_string();
+            let path = format!("\"{cgroup}\"");
+            let mark = fwmark.to_string();
+            run(
+                "nft",
+                &[
+                    "add", "rule", "inet", &name, "output", "socket", "cgroupv2", "level", &level, &path, "meta",
+                    "mark", "set", &mark,
+                ],
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for TunRoutes {
+    fn drop(&mut self) {
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        if let Some(ipv6) = self.ipv6.take() {
+            if let Some(address) = ipv6.address {
+                if let Err(err) = delete_tun_address(&address, &self.tun_name) {
+                    warn!("[TUN] failed to remove address {}, error: {}", address, err);
+                }
+            }
+            if let Some(ref gateway) = ipv6.gateway {
+                for net in &ipv6.bypass {
+                    if let Err(err) = delete_gateway_route(net, gateway) {
+                        warn!("[TUN] failed to remove route {} via {:?}, error: {}", net, gateway, err);
+                    }
+                }
+            }
+        }
+
+        while let Some(route) = self.routes.pop() {
+            let result = match route {
+                InstalledRoute::Tun(ref net) => delete_tun_route(net, &self.tun_name),
+                InstalledRoute::Gateway(ref net) => delete_gateway_route(net, &self.gateway),
+                #[cfg(target_os = "linux")]
+                InstalledRoute::TableTun(ref net, table) => run(
+                    "ip",
+                    &[
+                        "route",
+                        "del",
+                        &net.to_string(),
+                        "dev",
+                        &self.tun_name,
+                        "table",
+                        &table.to_string(),
+                    ],
+                )
+                .map(|_| ()),
+                #[cfg(target_os = "linux")]
+                InstalledRoute::Rule(family, ref rule) => delete_rule(family, rule),
+                #[cfg(target_os = "linux")]
+                InstalledRoute::Nftables(ref name) => run("nft", &["delete", "table", "inet", name]).map(|_| ()),
+            };
+            if let Err(err) = result {
+                warn!("[TUN] failed to remove route {:?}, error: {}", route, err);
+            }
+        }
+        info!("shadowsocks tun {} removed routes", self.tun_name);
+    }
+}
+
+/// Address of the global prefix `prefix` with the interface identifier `suffix`
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn prefix_address(prefix: &Ipv6Net, suffix: Ipv6Addr) -> Ipv6Addr {
+    let mask = u128::from(prefix.netmask());
+    Ipv6Addr::from((u128::from(prefix.network()) & mask) | (u128::from(suffix) & !mask))
+}
+
+#[cfg(target_os = "linux")]
+fn default_gateway() -> io::Result<Gateway> {
+    default_route("-4", "no IPv4 default route")
+}
+
+#[cfg(target_os = "linux")]
+fn default_gateway6() -> io::Result<Gateway> {
+    // default via fe80::1 dev eth0 proto ra metric 100 expires 1798sec pref medium
+    default_route("-6", "no IPv6 default route")
+}
+
+#[cfg(target_os = "linux")]
+fn default_route(family: &str, not_found: &'static str) -> io::Result<Gateway> {
+    // default via 192.168.1.1 dev eth0 proto dhcp metric 100
+    let output = run("ip", &[family, "route", "show", "default"])?;
+
+    for line in output.lines() {
+        let mut addr = None;
+        let mut interface = None;
+
+        let mut tokens = line.split_whitespace();
+        while let Some(token) = tokens.next() {
+            match token {
+                "via" => addr = tokens.next().and_then(|a| a.parse::<IpAddr>().ok()),
+                "dev" => interface = tokens.next().map(ToOwned::to_owned),
+                _ => {}
+            }
+        }
+
+        if let Some(interface) = interface {
+            return Ok(Gateway { addr, interface });
+        }
+    }
+
+    Err(io::Error::new(io::ErrorKind::NotFound, not_found))
+}
+
+#[cfg(target_os = "linux")]
+fn upstream_prefixes(interface: &str) -> io::Result<Vec<Ipv6Net>> {
+    //     inet6 2001:db8:1:2::1234/64 scope global dynamic mngtmpaddr noprefixroute
+    //        valid_lft 86313sec preferred_lft 14313sec
+    let output = run("ip", &["-6", "addr", "show", "dev", interface, "scope", "global"])?;
+
+    let mut prefixes = Vec::new();
+    for line in output.lines() {
+        let mut tokens = line.split_whitespace();
+        if tokens.next() != Some("inet6") {
+            continue;
+        }
+        // Deprecated addresses are left by the previous prefix
+        if line.split_whitespace().any(|t| t == "deprecated") {
+            continue;
+        }
+        if let Some(net) = tokens.next().and_then(|n| n.parse::<Ipv6Net>().ok()) {
+            prefixes.push(net.trunc());
+        }
+    }
+    prefixes.sort();
+    prefixes.dedup();
+    Ok(prefixes)
+}
+
+#[cfg(target_os = "linux")]
+fn add_tun_address(addr: &Ipv6Addr, tun_name: &str) -> io::Result<()> {
+    run(
+        "ip",
+        &["-6", "addr", "replace", &format!("{addr}/128"), "dev", tun_name],
+    )
+    .map(|_| ())
+}
+
+#[cfg(target_os = "linux")]
+fn delete_tun_address(addr: &Ipv6Addr, tun_name: &str) -> io::Result<()> {
+    run("ip", &["-6", "addr", "del", &format!("{addr}/128"), "dev", tun_name]).map(|_| ())
+}
+
+#[cfg(target_os = "linux")]
+fn add_gateway_route(net: &IpNet, gateway: &Gateway) -> io::Result<()> {
+    let net = net.to_string();
+    match gateway.addr {
+        Some(addr) => {
+            let addr = addr.to_string();
+            run(
+                "ip",
+                &["route", "replace", &net, "via", &addr, "dev", &gateway.interface],
+            )
+        }
+        None => run("ip", &["route", "replace", &net, "dev", &gateway.interface]),
+    }
+    .map(|_| ())
+}
+
+#[cfg(target_os = "linux")]
+fn delete_gateway_route(net: &IpNet, gateway: &Gateway) -> io::Result<()> {
+    run("ip", &["route", "del", &net.to_string(), "dev", &gateway.interface]).map(|_| ())
+}
+
+#[cfg(target_os = "linux")]
+fn delete_rule(family: &str, rule: &[String]) -> io::Result<()> {
+    let args = [family, "rule", "del"]
+        .into_iter()
+        .chain(rule.iter().map(String::as_str))
+        .collect::<Vec<_>>();
+    run("ip", &args).map(|_| ())
+}
+
+#[cfg(target_os = "linux")]
+fn add_tun_route(net: &IpNet, tun_name: &str) -> io::Result<()> {
+    run("ip", &["route", "replace", &net.to_string(), "dev", tun_name]).map(|_| ())
+}
+
+#[cfg(target_os = "linux")]
+fn delete_tun_route(net: &IpNet, tun_name: &str) -> io::Result<()> {
+    run("ip", &["route", "del", &net.to_string(), "dev", tun_name]).map(|_| ())
+}
+
+#[cfg(target_os = "macos")]
+fn default_gateway() -> io::Result<Gateway> {
+    default_route(&["-n", "get", "default"], "no IPv4 default route")
+}
+
+#[cfg(target_os = "macos")]
+fn default_gateway6() -> io::Result<Gateway> {
+    default_route(&["-n", "get", "-inet6", "default"], "no IPv6 default route")
+}
+
+#[cfg(target_os = "macos")]
+fn default_route(args: &[&str], not_found: &'static str) -> io::Result<Gateway> {
+    //    route to: default
+    // destination: default
+    //     gateway: 192.168.1.1
+    //   interface: en0
+    let output = match run("route", args) {
+        Ok(output) => output,
+        // route: writing to routing socket: not in table
+        Err(..) => return Err(io::Error::new(io::ErrorKind::NotFound, not_found)),
+    };
+
+    let mut addr = None;
+    let mut interface = None;
+    for line in output.lines() {
+        match line.trim().split_once(':') {
+            // IPv6 link-local gateways are scoped, like fe80::1%en0
+            Some(("gateway", value)) => addr = value.trim().split('%').next().and_then(|a| a.parse::<IpAddr>().ok()),
+            Some(("interface", value)) => interface = Some(value.trim().to_owned()),
+            _ => {}
+        }
+    }
+
+    match interface {
+        Some(interface) => Ok(Gateway { addr, interface }),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, not_found)),
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn upstream_prefixes(interface: &str) -> io::Result<Vec<Ipv6Net>> {
+    // 	inet6 fe80::1c2b:3d4e:5f60:7182%en0 prefixlen 64 secured scopeid 0x4
+    // 	inet6 2001:db8:1:2::1234 prefixlen 64 autoconf secured
+    let output = run("ifconfig", &[interface])?;
+
+    let mut prefixes = Vec::new();
+    for line in output.lines() {
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        if tokens.len() < 4 || tokens[0] != "inet6" || tokens[2] != "prefixlen" {
+            continue;
+        }
+        // Link-local addresses are scoped, and deprecated addresses are left by the previous prefix
+        if tokens[1].contains('%') || tokens.iter().any(|t| *t == "deprecated" || *t == "detached") {
+            continue;
+        }
+        let addr = match tokens[1].parse::<Ipv6Addr>() {
+            Ok(addr) => addr,
+            Err(..) => continue,
+        };
+        if let Some(net) = tokens[3]
+            .parse::<u8>()
+            .ok()
+            .and_then(|len| Ipv6Net::new(addr, len).ok())
+        {
+            prefixes.push(net.trunc());
+        }
+    }
+    prefixes.sort();
+    prefixes.dedup();
+    Ok(prefixes)
+}
+
+#[cfg(target_os = "macos")]
+fn add_tun_address(addr: &Ipv6Addr, tun_name: &str) -> io::Result<()> {
+    run(
+        "ifconfig",
+        &[tun_name, "inet6", &addr.to_string(), "prefixlen", "128", "alias"],
+    )
+    .map(|_| ())
+}
+
+#[cfg(target_os = "macos")]
+fn delete_tun_address(addr: &Ipv6Addr, tun_name: &str) -> io::Result<()> {
+    run("ifconfig", &[tun_name, "inet6", &addr.to_string(), "-alias"]).map(|_| ())
+}
+
+/// Address family argument of `route`
+#[cfg(target_os = "macos")]
+fn route_family(net: &IpNet) -> &'static str {
+    match *net {
+        IpNet::V4(..) => "-inet",
+        IpNet::V6(..) => "-inet6",
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn add_gateway_route(net: &IpNet, gateway: &Gateway) -> io::Result<()> {
+    let family = route_family(net);
+    let net = net.to_string();
+    match gateway.addr {
+        // Link-local gateways must be scoped to the interface
+        Some(IpAddr::V6(addr)) if addr.segments()[0] & 0xffc0 == 0xfe80 => {
+            let addr = format!("{}%{}", addr, gateway.interface);
+            run("route", &["-n", "add", family, "-net", &net, &addr])
+        }
+        Some(addr) => run("route", &["-n", "add", family, "-net", &net, &addr.to_string()]),
+        None => run(
+            "route",
+            &["-n", "add", family, "-net", &net, "-interface", &gateway.interface],
+        ),
+    }
+    .map(|_| ())
+}
+
+#[cfg(target_os = "macos")]
+fn delete_gateway_route(net: &IpNet, _gateway: &Gateway) -> io::Result<()> {
+    run("route", &["-n", "delete", route_family(net), "-net", &net.to_string()]).map(|_| ())
+}
+
+#[cfg(target_os = "macos")]
+fn add_tun_route(net: &IpNet, tun_name: &str) -> io::Result<()> {
+    run(
+        "route",
+        &[
+            "-n",
+            "add",
+            route_family(net),
+            "-net",
+            &net.to_string(),
+            "-interface",
+            tun_name,
+        ],
+    )
+    .map(|_| ())
+}
+
+#[cfg(target_os = "macos")]
+fn delete_tun_route(net: &IpNet, tun_name: &str) -> io::Result<()> {
+    run(
+        "route",
+        &[
+            "-n",
+            "delete",
+            route_family(net),
+            "-net",
+            &net.to_string(),
+            "-interface",
+            tun_name,
+        ],
+    )
+    .map(|_| ())
+}
+
+#[cfg(windows)]
+fn default_gateway() -> io::Result<Gateway> {
+    // Network Destination        Netmask          Gateway       Interface  Metric
+    //           0.0.0.0          0.0.0.0      192.168.1.1   192.168.1.100     25
+    let output = run("route", &["print", "-4", "0.0.0.0"])?;
+
+    let mut best: Option<(u32, Gateway)> = None;
+    for line in output.lines() {
+        let columns = line.split_whitespace().collect::<Vec<_>>();
+        if columns.len() != 5 || columns[0] != "0.0.0.0" || columns[1] != "0.0.0.0" {
+            continue;
+        }
+
+        let interface = match columns[3].parse::<IpAddr>() {
+            Ok(i) => i,
+            Err(..) => continue,
+        };
+        let metric = columns[4].parse::<u32>().unwrap_or(u32::MAX);
+        // On-link routes are reached with the interface address as the gateway
+        let addr = columns[2].parse::<IpAddr>().unwrap_or(interface);
+
+        if best.as_ref().map_or(true, |(m, _)| metric < *m) {
+            best = Some((
+                metric,
+                Gateway {
+                    addr: Some(addr),
+                    interface: interface.to_string(),
+                },
+            ));
+        }
+    }
+
+    match best {
+        Some((_, gateway)) => Ok(gateway),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, "no IPv4 default route")),
+    }
+}
+
+#[cfg(windows)]
+fn add_gateway_route(net: &IpNet, gateway: &Gateway) -> io::Result<()> {
+    let addr = gateway
+        .addr
+        .map(|a| a.to_string())
+        .unwrap_or_else(|| gateway.interface.clone());
+    run(
+        "route",
+        &[
+            "add",
+            &net.network().to_string(),
+            "mask",
+            &net.netmask().to_string(),
+            &addr,
+        ],
+    )
+    .map(|_| ())
+}
+
+#[cfg(windows)]
+fn delete_gateway_route(net: &IpNet, _gateway: &Gateway) -> io::Result<()> {
+    run(
+        "route",
+        &["delete", &net.network().to_string(), "mask", &net.netmask().to_string()],
+    )
+    .map(|_| ())
+}
+
+#[cfg(windows)]
+fn add_tun_route(net: &IpNet, tun_name: &str) -> io::Result<()> {
+    run(
+        "netsh",
+        &[
+            "interface",
+            "ipv4",
+            "add",
+            "route",
+            &format!("prefix={net}"),
+            &format!("interface={tun_name}"),
+            "store=active",
+        ],
+    )
+    .map(|_| ())
+}
+
+#[cfg(windows)]
+fn delete_tun_route(net: &IpNet, tun_name: &str) -> io::Result<()> {
+    run(
+        "netsh",
+        &[
+            "interface",
+            "ipv4",
+            "delete",
+            "route",
+            &format!("prefix={net}"),
+            &format!("interface={tun_name}"),
+            "store=active",
+        ],
+    )
+    .map(|_| ())
+}
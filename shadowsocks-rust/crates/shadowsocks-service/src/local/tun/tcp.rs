@@ -1,7 +1,7 @@
 use std::{
     collections::HashMap,
     future::Future,
-    io::{self, ErrorKind},
+    io::{self},
     mem,
     net::{IpAddr, SocketAddr},
     pin::Pin,
@@ -31,10 +31,11 @@ use tokio::{
 };
 
 use crate::{
+    config::TunDscpConfig,
     local::{
         context::ServiceContext,
         loadbalancing::PingBalancer,
-        net::AutoProxyClientStream,
+        net::{rewind::RewindStream, sniff::sniff_host, AutoProxyClientStream},
         utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
     },
     net::utils::to_ipv4_mapped,
@@ -169,7 +170,7 @@ impl AsyncRead for TcpConnection {
             return Poll::Pending;
         }
 
-        let recv_buf = unsafe { mem::transmute::<_, &mut [u8]>(buf.unfilled_mut()) };
+        let recv_buf = unsafe { mem::transmute::<&mut [mem::MaybeUninit<u8>], &mut [u8]>(buf.unfilled_mut()) };
         let n = control.recv_buffer.dequeue_slice(recv_buf);
         buf.advance(n);
 
@@ -246,6 +247,8 @@ pub struct TcpTun {
     iface_rx: mpsc::UnboundedReceiver<Vec<u8>>,
     iface_tx: mpsc::UnboundedSender<Vec<u8>>,
     iface_tx_avail: Arc<AtomicBool>,
+    dscp: Option<Arc<TunDscpConfig>>,
+    sniff_timeout: Option<Duration>,
 }
 
 impl Drop for TcpTun {
@@ -257,7 +260,13 @@ impl Drop for TcpTun {
 }
 
 impl TcpTun {
-    pub fn new(context: Arc<ServiceContext>, balancer: PingBalancer, mtu: u32) -> TcpTun {
+    pub fn new(
+        context: Arc<ServiceContext>,
+        balancer: PingBalancer,
+        mtu: u32,
+        dscp: Option<Arc<TunDscpConfig>>,
+        sniff_timeout: Option<Duration>,
+    ) -> TcpTun {
         let mut capabilities = DeviceCapabilities::default();
         capabilities.medium = Medium::Ip;
         capabilities.max_transmission_unit = mtu as usize;
@@ -490,6 +499,8 @@ impl TcpTun {
             iface_rx,
             iface_tx,
             iface_tx_avail,
+            dscp,
+            sniff_timeout,
         }
     }
 
@@ -497,6 +508,7 @@ impl TcpTun {
         &mut self,
         src_addr: SocketAddr,
         dst_addr: SocketAddr,
+        traffic_class: u8,
         tcp_packet: &TcpPacket<&[u8]>,
     ) -> io::Result<()> {
         // TCP first handshake packet, create a new Connection
@@ -517,7 +529,7 @@ impl TcpTun {
             // socket.set_ack_delay(None);
 
             if let Err(err) = socket.listen(dst_addr) {
-                return Err(io::Error::new(ErrorKind::Other, format!("listen error: {:?}", err)));
+                return Err(io::Error::other(format!("listen error: {:?}", err)));
             }
 
             debug!("created TCP connection for {} <-> {}", src_addr, dst_addr);
@@ -529,12 +541,22 @@ impl TcpTun {
                 &accept_opts.tcp,
             );
 
+            // DSCP of the SYN packet is applied to the whole connection
+            let tos = self
+                .dscp
+                .as_ref()
+                .and_then(|d| d.outbound_dscp(traffic_class >> 2))
+                .map(|dscp| dscp << 2);
+
             // establish a tunnel
             let context = self.context.clone();
             let balancer = self.balancer.clone();
+            let sniff_timeout = self.sniff_timeout;
             tokio::spawn(async move {
                 let connection = connection.await;
-                if let Err(err) = handle_redir_client(context, balancer, connection, src_addr, dst_addr).await {
+                if let Err(err) =
+                    handle_redir_client(context, balancer, connection, src_addr, dst_addr, tos, sniff_timeout).await
+                {
                     error!("TCP tunnel failure, {} <-> {}, error: {}", src_addr, dst_addr, err);
                 }
             });
@@ -559,29 +581,85 @@ impl TcpTun {
             None => unreachable!("channel closed unexpectedly"),
         }
     }
+
+    /// Packets sent back that are ready, without waiting
+    #[cfg(target_os = "linux")]
+    pub fn try_recv_packet(&mut self) -> Option<Vec<u8>> {
+        self.iface_rx.try_recv().ok()
+    }
 }
 
 /// Established Client Transparent Proxy
 ///
 /// This method must be called after handshaking with client (for example, socks5 handshaking)
-async fn establish_client_tcp_redir<'a>(
+async fn establish_client_tcp_redir(
     context: Arc<ServiceContext>,
     balancer: PingBalancer,
     mut stream: TcpConnection,
     peer_addr: SocketAddr,
     addr: &Address,
+    tos: Option<u8>,
+    sniff_timeout: Option<Duration>,
 ) -> io::Result<()> {
     if balancer.is_empty() {
-        let mut remote = AutoProxyClientStream::connect_bypassed(context, addr).await?;
-        return establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, addr).await;
+        let mut remote = AutoProxyClientStream::connect_bypassed(context.clone(), addr).await?;
+        set_remote_tos(&remote, tos, peer_addr, addr);
+        return establish_tcp_tunnel_bypassed(&context, &mut stream, &mut remote, peer_addr, addr).await;
     }
 
-    let server = balancer.best_tcp_server();
+    let server = balancer.best_tcp_server_for_client(&peer_addr, addr);
     let svr_cfg = server.server_config();
 
-    let mut remote =
-        AutoProxyClientStream::connect_with_opts(context, &server, addr, server.connect_opts_ref()).await?;
-    establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, addr).await
+    // Process rules take precedence over rules of targets, there is no need to sniff
+    let process_bypassed = context.check_process_bypassed(peer_addr).await;
+
+    let (prefix, host) = match sniff_timeout {
+        Some(timeout) if process_bypassed.is_none() => sniff_host(&mut stream, timeout).await?,
+        _ => (Vec::new(), None),
+    };
+    let mut stream = RewindStream::new(prefix, stream);
+
+    let mut remote = match (process_bypassed, host) {
+        (Some(bypassed), _) => {
+            trace!("TCP {} -> {} bypassed: {} by process rules", peer_addr, addr, bypassed);
+            AutoProxyClientStream::connect_decided_with_opts(
+                context.clone(),
+                &server,
+                addr,
+                bypassed,
+                server.connect_opts_ref(),
+            )
+            .await?
+        }
+        (None, Some(ref host)) => {
+            trace!("TCP {} -> {} sniffed host {}", peer_addr, addr, host);
+            AutoProxyClientStream::connect_sniffed_with_opts(
+                context.clone(),
+                &server,
+                addr,
+                host,
+                server.connect_opts_ref(),
+            )
+            .await?
+        }
+        (None, None) => {
+            AutoProxyClientStream::connect_with_opts(context.clone(), &server, addr, server.connect_opts_ref()).await?
+        }
+    };
+    set_remote_tos(&remote, tos, peer_addr, addr);
+    establish_tcp_tunnel(&context, svr_cfg, &mut stream, &mut remote, peer_addr, addr).await
+}
+
+fn set_remote_tos(remote: &AutoProxyClientStream, tos: Option<u8>, peer_addr: SocketAddr, addr: &Address) {
+    if let Some(tos) = tos {
+        match remote.set_tos(tos) {
+            Ok(..) => trace!("TCP {} -> {} outbound TOS set to {:#04x}", peer_addr, addr, tos),
+            Err(err) => debug!(
+                "TCP {} -> {} failed to set outbound TOS {:#04x}, error: {}",
+                peer_addr, addr, tos, err
+            ),
+        }
+    }
 }
 
 async fn handle_redir_client(
@@ -590,6 +668,8 @@ async fn handle_redir_client(
     s: TcpConnection,
     peer_addr: SocketAddr,
     mut daddr: SocketAddr,
+    tos: Option<u8>,
+    sniff_timeout: Option<Duration>,
 ) -> io::Result<()> {
     // Get forward address from socket
     //
@@ -599,6 +679,12 @@ async fn handle_redir_client(
             daddr = SocketAddr::new(IpAddr::from(v4), a.port());
         }
     }
-    let target_addr = Address::from(daddr);
-    establish_client_tcp_redir(context, balancer, s, peer_addr, &target_addr).await
+    #[cfg_attr(not(feature = "local-fake-dns"), allow(unused_mut))]
+    let mut target_addr = Address::from(daddr);
+    // Fake addresses are mapped back to domain names before choosing servers and checking ACL
+    #[cfg(feature = "local-fake-dns")]
+    if let Some(mapped_addr) = context.try_map_fake_address(&target_addr).await {
+        target_addr = mapped_addr;
+    }
+    establish_client_tcp_redir(context, balancer, s, peer_addr, &target_addr, tos, sniff_timeout).await
 }
@@ -8,11 +8,22 @@ use std::{
 use async_trait::async_trait;
 use bytes::{BufMut, BytesMut};
 use etherparse::PacketBuilder;
+#[cfg(feature = "local-fake-dns")]
+use hickory_resolver::proto::op::{response_code::ResponseCode, Message};
 use log::debug;
+#[cfg(feature = "local-fake-dns")]
+use log::error;
+#[cfg(feature = "local-fake-dns")]
+use lru_time_cache::LruCache;
 use shadowsocks::relay::socks5::Address;
+#[cfg(feature = "local-fake-dns")]
+use spin::Mutex as SpinMutex;
 use tokio::sync::mpsc;
 
+#[cfg(feature = "local-fake-dns")]
+use crate::local::fake_dns::{manager::FakeDnsManager, processor::handle_dns_request};
 use crate::{
+    config::{TunDnsHijackAction, TunDnsHijackRule, TunDscpConfig},
     local::{
         context::ServiceContext,
         loadbalancing::PingBalancer,
@@ -24,6 +35,12 @@ use crate::{
 pub struct UdpTun {
     tun_rx: mpsc::Receiver<BytesMut>,
     manager: UdpAssociationManager<UdpTunInboundWriter>,
+    dscp: Option<Arc<TunDscpConfig>>,
+    dns_hijack: Arc<[TunDnsHijackRule]>,
+    #[cfg(feature = "local-fake-dns")]
+    writer: UdpTunInboundWriter,
+    #[cfg(feature = "local-fake-dns")]
+    fake_dns: Option<Arc<FakeDnsManager>>,
 }
 
 impl UdpTun {
@@ -32,27 +49,129 @@ impl UdpTun {
         balancer: PingBalancer,
         time_to_live: Option<Duration>,
         capacity: Option<usize>,
+        dscp: Option<Arc<TunDscpConfig>>,
     ) -> (UdpTun, Duration, mpsc::Receiver<SocketAddr>) {
         let (tun_tx, tun_rx) = mpsc::channel(64);
-        let (manager, cleanup_interval, keepalive_rx) = UdpAssociationManager::new(
-            context,
-            UdpTunInboundWriter::new(tun_tx),
-            time_to_live,
-            capacity,
-            balancer,
-        );
-
-        (UdpTun { tun_rx, manager }, cleanup_interval, keepalive_rx)
+        let writer = UdpTunInboundWriter::new(tun_tx, time_to_live.unwrap_or(crate::DEFAULT_UDP_EXPIRY_DURATION));
+        let (manager, cleanup_interval, keepalive_rx) =
+            UdpAssociationManager::new(context, writer.clone(), time_to_live, capacity, balancer);
+
+        (
+            UdpTun {
+                tun_rx,
+                manager,
+                dscp,
+                dns_hijack: Arc::new([]),
+                #[cfg(feature = "local-fake-dns")]
+                writer,
+                #[cfg(feature = "local-fake-dns")]
+                fake_dns: None,
+            },
+            cleanup_interval,
+            keepalive_rx,
+        )
+    }
+
+    /// Answer DNS queries with fake addresses of `manager`, instead of relaying them
+    #[cfg(feature = "local-fake-dns")]
+    pub fn set_fake_dns(&mut self, manager: Arc<FakeDnsManager>) {
+        self.fake_dns = Some(manager);
+    }
+
+    /// Rules of DNS queries, the first rule matching the destination decides
+    pub fn set_dns_hijack(&mut self, rules: Arc<[TunDnsHijackRule]>) {
+        self.dns_hijack = rules;
+    }
+
+    fn dns_hijack_action(&self, dst_addr: &SocketAddr) -> TunDnsHijackAction {
+        if let Some(rule) = self.dns_hijack.iter().find(|rule| rule.matches(dst_addr)) {
+            return rule.action;
+        }
+
+        // DNS queries to any server are hijacked by default
+        #[cfg(feature = "local-fake-dns")]
+        if self.fake_dns.is_some() && dst_addr.port() == 53 {
+            return TunDnsHijackAction::Hijack;
+        }
+
+        TunDnsHijackAction::Pass
+    }
+
+    #[cfg(feature = "local-fake-dns")]
+    async fn handle_fake_dns_query(
+        &self,
+        manager: &FakeDnsManager,
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let req_message = match Message::from_vec(payload) {
+            Ok(m) => m,
+            Err(err) => {
+                debug!("UDP {} -> {} invalid DNS query, error: {}", src_addr, dst_addr, err);
+                return Ok(());
+            }
+        };
+
+        let rsp_message = match handle_dns_request(&req_message, manager).await {
+            Ok(m) => m,
+            Err(err) => {
+                error!("failed to handle DNS request, error: {}", err);
+                Message::error_msg(req_message.id(), req_message.op_code(), ResponseCode::ServFail)
+            }
+        };
+
+        let rsp_buffer = rsp_message.to_vec()?;
+        self.writer
+            .write_packet(src_addr, &Address::from(dst_addr), &rsp_buffer)
+            .await
     }
 
     pub async fn handle_packet(
         &mut self,
         src_addr: SocketAddr,
         dst_addr: SocketAddr,
+        traffic_class: u8,
         payload: &[u8],
     ) -> io::Result<()> {
         debug!("UDP {} -> {} payload.size: {} bytes", src_addr, dst_addr, payload.len());
-        if let Err(err) = self.manager.send_to(src_addr, dst_addr.into(), payload).await {
+
+        match self.dns_hijack_action(&dst_addr) {
+            TunDnsHijackAction::Block => {
+                debug!("UDP {} -> {} blocked by DNS hijack rules", src_addr, dst_addr);
+                return Ok(());
+            }
+            #[cfg(feature = "local-fake-dns")]
+            TunDnsHijackAction::Hijack => {
+                if let Some(ref manager) = self.fake_dns {
+                    return self.handle_fake_dns_query(manager, src_addr, dst_addr, payload).await;
+                }
+            }
+            _ => {}
+        }
+
+        #[cfg(feature = "local-fake-dns")]
+        if let Some(ref manager) = self.fake_dns {
+            // Responses of targets behind fake addresses are sent back from the fake addresses
+            if !self.writer.is_fake_target(&src_addr, &dst_addr) {
+                if let Ok(Some(..)) = manager.map_ip_domain(dst_addr.ip()).await {
+                    self.writer.set_fake_target(src_addr, dst_addr);
+                }
+            }
+        }
+
+        // DSCP of the first packet is applied to the whole association
+        let tos = self
+            .dscp
+            .as_ref()
+            .and_then(|d| d.outbound_dscp(traffic_class >> 2))
+            .map(|dscp| dscp << 2);
+
+        if let Err(err) = self
+            .manager
+            .send_to_with_tos(src_addr, dst_addr.into(), payload, tos)
+            .await
+        {
             debug!(
                 "UDP {} -> {} payload.size: {} bytes failed, error: {}",
                 src_addr,
@@ -85,17 +204,33 @@ impl UdpTun {
 #[derive(Clone)]
 struct UdpTunInboundWriter {
     tun_tx: mpsc::Sender<BytesMut>,
+    #[cfg(feature = "local-fake-dns")]
+    fake_targets: Arc<SpinMutex<LruCache<SocketAddr, SocketAddr>>>,
 }
 
 impl UdpTunInboundWriter {
-    fn new(tun_tx: mpsc::Sender<BytesMut>) -> UdpTunInboundWriter {
-        UdpTunInboundWriter { tun_tx }
+    #[cfg_attr(not(feature = "local-fake-dns"), allow(unused_variables))]
+    fn new(tun_tx: mpsc::Sender<BytesMut>, time_to_live: Duration) -> UdpTunInboundWriter {
+        UdpTunInboundWriter {
+            tun_tx,
+            #[cfg(feature = "local-fake-dns")]
+            fake_targets: Arc::new(SpinMutex::new(LruCache::with_expiry_duration(time_to_live))),
+        }
     }
-}
 
-#[async_trait]
-impl UdpInboundWrite for UdpTunInboundWriter {
-    async fn send_to(&self, peer_addr: SocketAddr, remote_addr: &Address, data: &[u8]) -> io::Result<()> {
+    #[cfg(feature = "local-fake-dns")]
+    fn is_fake_target(&self, peer_addr: &SocketAddr, target_addr: &SocketAddr) -> bool {
+        self.fake_targets.lock().get(peer_addr) == Some(target_addr)
+    }
+
+    /// Packets from `peer_addr` are sent to fake address `target_addr`
+    #[cfg(feature = "local-fake-dns")]
+    fn set_fake_target(&self, peer_addr: SocketAddr, target_addr: SocketAddr) {
+        self.fake_targets.lock().insert(peer_addr, target_addr);
+    }
+
+    /// Write a UDP packet from `remote_addr` to `peer_addr` into tun
+    async fn write_packet(&self, peer_addr: SocketAddr, remote_addr: &Address, data: &[u8]) -> io::Result<()> {
         let addr = match *remote_addr {
             Address::SocketAddress(sa) => {
                 // Try to convert IPv4 mapped IPv6 address if server is running on dual-stack mode
@@ -161,3 +296,15 @@ impl UdpInboundWrite for UdpTunInboundWriter {
         Ok(())
     }
 }
+
+#[async_trait]
+impl UdpInboundWrite for UdpTunInboundWriter {
+    async fn send_to(&self, peer_addr: SocketAddr, remote_addr: &Address, data: &[u8]) -> io::Result<()> {
+        #[cfg(feature = "local-fake-dns")]
+        let fake_addr = self.fake_targets.lock().get(&peer_addr).map(|a| Address::from(*a));
+        #[cfg(feature = "local-fake-dns")]
+        let remote_addr = fake_addr.as_ref().unwrap_or(remote_addr);
+
+        self.write_packet(peer_addr, remote_addr, data).await
+    }
+}
@@ -72,7 +72,7 @@ impl Device for VirtTunDevice {
     }
 
     fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
-        return Some(VirtTxToken(self));
+        Some(VirtTxToken(self))
     }
 
     fn capabilities(&self) -> DeviceCapabilities {
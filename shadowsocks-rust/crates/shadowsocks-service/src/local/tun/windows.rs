@@ -0,0 +1,68 @@
+//! IPv6 configuration of wintun interfaces
+//!
+//! tun2 only configures the IPv4 address of wintun interfaces, the rest is configured with `netsh`.
+
+use std::{io, process::Command};
+
+use log::{debug, error};
+
+use crate::config::TunIpv6Config;
+
+fn netsh(args: &[String]) -> io::Result<()> {
+    debug!("[TUN] netsh {}", args.join(" "));
+
+    let output = Command::new("netsh").args(args).output()?;
+    if !output.status.success() {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        error!(
+            "[TUN] netsh {} exited with {}, {}",
+            args.join(" "),
+            output.status,
+            stdout.trim()
+        );
+        return Err(io::Error::other(format!("netsh exited with {}", output.status)));
+    }
+    Ok(())
+}
+
+/// Add the IPv6 address, routes and DNS servers to interface `name`
+pub fn configure_ipv6(name: &str, config: &TunIpv6Config) -> io::Result<()> {
+    let interface = format!("interface={name}");
+
+    netsh(&[
+        "interface".to_owned(),
+        "ipv6".to_owned(),
+        "add".to_owned(),
+        "address".to_owned(),
+        interface.clone(),
+        format!("address={}", config.address),
+        "store=active".to_owned(),
+    ])?;
+
+    for route in &config.routes {
+        netsh(&[
+            "interface".to_owned(),
+            "ipv6".to_owned(),
+            "add".to_owned(),
+            "route".to_owned(),
+            format!("prefix={route}"),
+            interface.clone(),
+            "store=active".to_owned(),
+        ])?;
+    }
+
+    for (index, dns) in config.dns.iter().enumerate() {
+        netsh(&[
+            "interface".to_owned(),
+            "ipv6".to_owned(),
+            "add".to_owned(),
+            "dnsservers".to_owned(),
+            format!("name={name}"),
+            format!("address={dns}"),
+            format!("index={}", index + 1),
+            "validate=no".to_owned(),
+        ])?;
+    }
+
+    Ok(())
+}
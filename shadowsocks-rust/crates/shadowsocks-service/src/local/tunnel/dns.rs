@@ -0,0 +1,144 @@
+//! DNS fast path of UDP tunnel
+//!
+//! Tunneling a stub resolver to a remote DNS server is the common use of UDP tunnel. Queries of a client are
+//! relayed concurrently through the same association, and each query is tracked by its ID. A query that is not
+//! answered in time is answered with `SERVFAIL`, so the stub resolver could retry or fail fast
+//! instead of waiting for its own (usually much longer) timeout.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use spin::Mutex as SpinMutex;
+
+/// Queries that are tracked at the same time, the others are relayed without tracking
+const MAX_PENDING_QUERIES: usize = 4096;
+
+const DNS_HEADER_LEN: usize = 12;
+const DNS_RCODE_SERVFAIL: u8 = 2;
+
+struct PendingQuery {
+    deadline: Instant,
+    servfail: Vec<u8>,
+}
+
+/// In-flight DNS queries of a UDP tunnel
+pub struct DnsQueryTracker {
+    timeout: Duration,
+    pending: SpinMutex<HashMap<(SocketAddr, u16), PendingQuery>>,
+}
+
+impl DnsQueryTracker {
+    /// Create with per-query `timeout`
+    pub fn new(timeout: Duration) -> DnsQueryTracker {
+        DnsQueryTracker {
+            timeout,
+            pending: SpinMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Interval of checking expired queries
+    pub fn check_interval(&self) -> Duration {
+        (self.timeout / 4).max(Duration::from_millis(10))
+    }
+
+    /// Track a packet sent from `peer_addr`, ignored if it is not a DNS query
+    pub fn query_sent(&self, peer_addr: SocketAddr, data: &[u8]) {
+        let id = match dns_message_id(data, false) {
+            Some(id) => id,
+            None => return,
+        };
+        let servfail = match servfail_response(data) {
+            Some(r) => r,
+            None => return,
+        };
+
+        let mut pending = self.pending.lock();
+        if pending.len() >= MAX_PENDING_QUERIES {
+            return;
+        }
+        pending.insert(
+            (peer_addr, id),
+            PendingQuery {
+                deadline: Instant::now() + self.timeout,
+                servfail,
+            },
+        );
+    }
+
+    /// Stop tracking the query answered by a packet sent to `peer_addr`
+    pub fn response_received(&self, peer_addr: SocketAddr, data: &[u8]) {
+        if let Some(id) = dns_message_id(data, true) {
+            self.pending.lock().remove(&(peer_addr, id));
+        }
+    }
+
+    /// Remove expired queries, returns the `SERVFAIL` responses that should be sent to their clients
+    pub fn take_expired(&self) -> Vec<(SocketAddr, Vec<u8>)> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+
+        let mut pending = self.pending.lock();
+        pending.retain(|(peer_addr, ..), query| {
+            if query.deadline > now {
+                return true;
+            }
+            expired.push((*peer_addr, std::mem::take(&mut query.servfail)));
+            false
+        });
+
+        expired
+    }
+}
+
+/// ID of a DNS message, if it is a query (or a response if `response` is set)
+fn dns_message_id(data: &[u8], response: bool) -> Option<u16> {
+    if data.len() < DNS_HEADER_LEN {
+        return None;
+    }
+    let is_response = data[2] & 0x80 != 0;
+    if is_response != response {
+        return None;
+    }
+    Some(u16::from_be_bytes([data[0], data[1]]))
+}
+
+/// Build a `SERVFAIL` response of `query`, with its header and the first question
+fn servfail_response(query: &[u8]) -> Option<Vec<u8>> {
+    let qdcount = u16::from_be_bytes([query[4], query[5]]);
+
+    let mut end = DNS_HEADER_LEN;
+    if qdcount > 0 {
+        // QNAME, a sequence of labels ends with a zero length label or a compression pointer
+        loop {
+            let len = *query.get(end)? as usize;
+            if len == 0 {
+                end += 1;
+                break;
+            }
+            if len & 0xC0 == 0xC0 {
+                end += 2;
+                break;
+            }
+            end += 1 + len;
+        }
+        // QTYPE and QCLASS
+        end += 4;
+        if end > query.len() {
+            return None;
+        }
+    }
+
+    let mut response = query[..end].to_vec();
+    // QR, keeps OPCODE and RD
+    response[2] = 0x80 | (query[2] & 0x79);
+    // RA and RCODE
+    response[3] = 0x80 | DNS_RCODE_SERVFAIL;
+    // QDCOUNT, ANCOUNT, NSCOUNT, ARCOUNT
+    response[4..6].copy_from_slice(&u16::from(qdcount > 0).to_be_bytes());
+    response[6..12].fill(0);
+
+    Some(response)
+}
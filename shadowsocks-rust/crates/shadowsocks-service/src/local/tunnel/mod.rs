@@ -1,7 +1,12 @@
 //! Shadowsocks Local Tunnel Server
 
-pub use self::server::{Tunnel, TunnelBuilder};
+pub use self::{
+    reverse::{ReverseTunnel, ReverseTunnelBuilder},
+    server::{Tunnel, TunnelBuilder},
+};
 
+mod dns;
+pub mod reverse;
 pub mod server;
 mod tcprelay;
 mod udprelay;
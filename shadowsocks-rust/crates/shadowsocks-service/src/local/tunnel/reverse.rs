@@ -0,0 +1,209 @@
+//! Reverse Tunnel, exposing a service of the local network on a port of the server
+//!
+//! The server has to enable `reverse_tunnel`. Inbound connections of the server's port are relayed back to
+//! `forward_addr` through shadowsocks connections.
+
+use std::{io, sync::Arc, time::Duration};
+
+use log::{debug, error, info, trace};
+use shadowsocks::relay::socks5::Address;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time,
+};
+
+use crate::{
+    local::{
+        context::ServiceContext,
+        loadbalancing::{PingBalancer, ServerIdent},
+        net::AutoProxyClientStream,
+        utils::establish_tcp_tunnel,
+    },
+    net::reverse_tunnel::{
+        make_reverse_tunnel_accept_addr, make_reverse_tunnel_addr, REVERSE_TUNNEL_HEARTBEAT_INTERVAL,
+    },
+};
+
+/// Control connections without any heartbeat in this long are considered broken
+const CONTROL_READ_TIMEOUT: Duration = Duration::from_secs(REVERSE_TUNNEL_HEARTBEAT_INTERVAL.as_secs() * 3);
+
+/// Maximum interval of reconnecting control connections
+const MAX_RECONNECT_INTERVAL: Duration = Duration::from_secs(32);
+
+pub struct ReverseTunnelBuilder {
+    context: Arc<ServiceContext>,
+    forward_addr: Address,
+    reverse_port: u16,
+    balancer: PingBalancer,
+}
+
+impl ReverseTunnelBuilder {
+    /// Create a new Reverse Tunnel exposing `forward_addr` with context
+    pub fn with_context(
+        context: Arc<ServiceContext>,
+        forward_addr: Address,
+        balancer: PingBalancer,
+    ) -> ReverseTunnelBuilder {
+        ReverseTunnelBuilder {
+            context,
+            forward_addr,
+            reverse_port: 0,
+            balancer,
+        }
+    }
+
+    /// Port of the server to listen on, any port is chosen by the server if it is 0
+    pub fn set_reverse_port(&mut self, port: u16) {
+        self.reverse_port = port;
+    }
+
+    /// Build Reverse Tunnel
+    pub fn build(self) -> ReverseTunnel {
+        ReverseTunnel {
+            context: self.context,
+            forward_addr: Arc::new(self.forward_addr),
+            reverse_port: self.reverse_port,
+            balancer: self.balancer,
+        }
+    }
+}
+
+/// Reverse Tunnel instance
+pub struct ReverseTunnel {
+    context: Arc<ServiceContext>,
+    forward_addr: Arc<Address>,
+    reverse_port: u16,
+    balancer: PingBalancer,
+}
+
+impl ReverseTunnel {
+    /// Address of the exposed service
+    pub fn forward_addr(&self) -> &Address {
+        &self.forward_addr
+    }
+
+    /// Keep the control connection to the best server, reconnect if it is broken
+    pub async fn run(self) -> io::Result<()> {
+        let mut reconnect_interval = Duration::from_secs(1);
+
+        loop {
+            let server = self.balancer.best_tcp_server();
+            if let Err(err) = self.serve_control(&server, &mut reconnect_interval).await {
+                error!(
+                    "reverse tunnel {} through server {} failed, error: {}, retry in {:?}",
+                    self.forward_addr,
+                    server.server_config().addr(),
+                    err,
+                    reconnect_interval
+                );
+            }
+            time::sleep(reconnect_interval).await;
+            reconnect_interval = (reconnect_interval * 2).min(MAX_RECONNECT_INTERVAL);
+        }
+    }
+
+    /// Serve until the control connection is broken, `reconnect_interval` is reset once the server is listening
+    async fn serve_control(&self, server: &Arc<ServerIdent>, reconnect_interval: &mut Duration) -> io::Result<()> {
+        let svr_cfg = server.server_config();
+
+        let mut control = AutoProxyClientStream::connect_proxied_with_opts(
+            self.context.clone(),
+            server,
+            make_reverse_tunnel_addr(self.reverse_port),
+            server.connect_opts_ref(),
+        )
+        .await?;
+
+        // Server replies nothing until it receives the request
+        let _ = control.write(&[]).await?;
+        let listen_addr = match time::timeout(CONTROL_READ_TIMEOUT, Address::read_from(&mut control)).await {
+            Ok(r) => r?,
+            Err(..) => return Err(io::ErrorKind::TimedOut.into()),
+        };
+
+        info!(
+            "shadowsocks reverse tunnel {} listening on {} of server {}",
+            self.forward_addr,
+            listen_addr,
+            svr_cfg.addr()
+        );
+        *reconnect_interval = Duration::from_secs(1);
+
+        let mut token = [0u8; 8];
+        loop {
+            match time::timeout(CONTROL_READ_TIMEOUT, control.read_exact(&mut token)).await {
+                Ok(Ok(..)) => {}
+                Ok(Err(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    debug!(
+                        "reverse tunnel {} control connection closed by server {}",
+                        self.forward_addr,
+                        svr_cfg.addr()
+                    );
+                    return Err(err);
+                }
+                Ok(Err(err)) => return Err(err),
+                Err(..) => return Err(io::ErrorKind::TimedOut.into()),
+            }
+
+            let token = u64::from_be_bytes(token);
+            if token == 0 {
+                trace!("reverse tunnel {} heartbeat from {}", self.forward_addr, svr_cfg.addr());
+                continue;
+            }
+
+            let context = self.context.clone();
+            let server = server.clone();
+            let forward_addr = self.forward_addr.clone();
+            tokio::spawn(async move {
+                if let Err(err) = serve_inbound(context, &server, &forward_addr, token).await {
+                    debug!("reverse tunnel {} inbound aborted with error: {}", forward_addr, err);
+                }
+            });
+        }
+    }
+}
+
+async fn serve_inbound(
+    context: Arc<ServiceContext>,
+    server: &ServerIdent,
+    forward_addr: &Address,
+    token: u64,
+) -> io::Result<()> {
+    let svr_cfg = server.server_config();
+
+    let mut remote = AutoProxyClientStream::connect_proxied_with_opts(
+        context.clone(),
+        server,
+        make_reverse_tunnel_accept_addr(),
+        server.connect_opts_ref(),
+    )
+    .await?;
+    remote.write_all(&token.to_be_bytes()).await?;
+
+    let peer_addr = match Address::read_from(&mut remote).await? {
+        Address::SocketAddress(sa) => sa,
+        Address::DomainNameAddress(..) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "reverse tunnel inbound address is not a socket address",
+            ));
+        }
+    };
+
+    // Operator configured the exposed service, which is not checked by ACL or special targets
+    let mut stream = AutoProxyClientStream::connect_bypassed_with_opts(
+        context.clone(),
+        forward_addr.clone(),
+        context.connect_opts_ref(),
+    )
+    .await?;
+
+    trace!(
+        "reverse tunnel {} <-> {} through server {}",
+        peer_addr,
+        forward_addr,
+        svr_cfg.addr()
+    );
+
+    establish_tcp_tunnel(&context, svr_cfg, &mut stream, &mut remote, peer_addr, forward_addr).await
+}
@@ -5,7 +5,10 @@ use std::{io, sync::Arc, time::Duration};
 use futures::{future, FutureExt};
 use shadowsocks::{config::Mode, relay::socks5::Address, ServerAddr};
 
-use crate::local::{context::ServiceContext, loadbalancing::PingBalancer};
+use crate::{
+    config::{InboundLimitConfig, TunnelForwardConfig},
+    local::{context::ServiceContext, loadbalancing::PingBalancer, net::limit::InboundLimiter},
+};
 
 use super::{
     tcprelay::{TunnelTcpServer, TunnelTcpServerBuilder},
@@ -20,6 +23,10 @@ pub struct TunnelBuilder {
     udp_capacity: Option<usize>,
     client_addr: ServerAddr,
     udp_addr: Option<ServerAddr>,
+    udp_dns_query_timeout: Option<Duration>,
+    inbound_limit: InboundLimitConfig,
+    sniff_timeout: Option<Duration>,
+    forwards: Vec<TunnelForwardConfig>,
     balancer: PingBalancer,
     #[cfg(target_os = "macos")]
     launchd_tcp_socket_name: Option<String>,
@@ -49,6 +56,10 @@ impl TunnelBuilder {
             udp_capacity: None,
             client_addr,
             udp_addr: None,
+            udp_dns_query_timeout: None,
+            inbound_limit: InboundLimitConfig::default(),
+            sniff_timeout: None,
+            forwards: Vec::new(),
             balancer,
             #[cfg(target_os = "macos")]
             launchd_tcp_socket_name: None,
@@ -77,6 +88,28 @@ impl TunnelBuilder {
         self.udp_addr = Some(addr);
     }
 
+    /// Treat UDP packets as DNS messages, answer `SERVFAIL` for queries that are not answered in `timeout`
+    pub fn set_udp_dns_query_timeout(&mut self, timeout: Duration) {
+        self.udp_dns_query_timeout = Some(timeout);
+    }
+
+    /// Limit concurrent and new TCP connections of clients
+    pub fn set_inbound_limit(&mut self, limit: InboundLimitConfig) {
+        self.inbound_limit = limit;
+    }
+
+    /// Sniff TLS SNI or HTTP `Host` of TCP clients for ACL domain rules, if forwarding to an IP address
+    pub fn set_sniff_timeout(&mut self, timeout: Duration) {
+        self.sniff_timeout = Some(timeout);
+    }
+
+    /// Serve another `local -> remote` mapping, UDP of which is bound to its listen address
+    ///
+    /// `udp_bind_addr`, `udp_dns_query_timeout` and launchd sockets only apply to `client_addr -> forward_addr`.
+    pub fn add_forward(&mut self, forward: TunnelForwardConfig) {
+        self.forwards.push(forward);
+    }
+
     /// macOS launchd activate socket
     #[cfg(target_os = "macos")]
     pub fn set_launchd_tcp_socket_name(&mut self, n: String) {
@@ -90,78 +123,136 @@ impl TunnelBuilder {
     }
 
     pub async fn build(self) -> io::Result<Tunnel> {
-        let mut tcp_server = None;
+        // Connections of all mappings are limited together
+        let inbound_limiter = if self.inbound_limit.is_empty() {
+            None
+        } else {
+            Some(Arc::new(InboundLimiter::new(self.inbound_limit.clone())))
+        };
+
+        let mut tcp_servers = Vec::new();
+        let mut udp_servers = Vec::new();
+
         if self.mode.enable_tcp() {
             #[allow(unused_mut)]
-            let mut builder = TunnelTcpServerBuilder::new(
-                self.context.clone(),
+            let mut builder = self.tcp_server_builder(
                 self.client_addr.clone(),
-                self.balancer.clone(),
                 self.forward_addr.clone(),
+                inbound_limiter.clone(),
             );
 
             #[cfg(target_os = "macos")]
-            if let Some(s) = self.launchd_tcp_socket_name {
-                builder.set_launchd_socket_name(s);
+            if let Some(ref s) = self.launchd_tcp_socket_name {
+                builder.set_launchd_socket_name(s.clone());
             }
 
-            let server = builder.build().await?;
-            tcp_server = Some(server);
+            tcp_servers.push(builder.build().await?);
         }
 
-        let mut udp_server = None;
         if self.mode.enable_udp() {
-            let udp_addr = self.udp_addr.unwrap_or(self.client_addr);
+            let udp_addr = self.udp_addr.clone().unwrap_or_else(|| self.client_addr.clone());
 
-            #[allow(unused_mut)]
-            let mut builder = TunnelUdpServerBuilder::new(
-                self.context.clone(),
-                udp_addr,
-                self.udp_expiry_duration,
-                self.udp_capacity,
-                self.balancer,
-                self.forward_addr,
-            );
+            let mut builder = self.udp_server_builder(udp_addr, self.forward_addr.clone());
+
+            if let Some(timeout) = self.udp_dns_query_timeout {
+                builder.set_dns_query_timeout(timeout);
+            }
 
             #[cfg(target_os = "macos")]
-            if let Some(s) = self.launchd_udp_socket_name {
-                builder.set_launchd_socket_name(s);
+            if let Some(ref s) = self.launchd_udp_socket_name {
+                builder.set_launchd_socket_name(s.clone());
+            }
+
+            udp_servers.push(builder.build().await?);
+        }
+
+        for forward in &self.forwards {
+            let mode = forward.mode.unwrap_or(self.mode);
+            if mode.enable_tcp() {
+                let builder = self.tcp_server_builder(
+                    forward.addr.clone(),
+                    forward.forward_addr.clone(),
+                    inbound_limiter.clone(),
+                );
+                tcp_servers.push(builder.build().await?);
+            }
+            if mode.enable_udp() {
+                let builder = self.udp_server_builder(forward.addr.clone(), forward.forward_addr.clone());
+                udp_servers.push(builder.build().await?);
             }
+        }
+
+        Ok(Tunnel {
+            tcp_servers,
+            udp_servers,
+        })
+    }
 
-            let server = builder.build().await?;
-            udp_server = Some(server);
+    fn tcp_server_builder(
+        &self,
+        client_addr: ServerAddr,
+        forward_addr: Address,
+        inbound_limiter: Option<Arc<InboundLimiter>>,
+    ) -> TunnelTcpServerBuilder {
+        let mut builder =
+            TunnelTcpServerBuilder::new(self.context.clone(), client_addr, self.balancer.clone(), forward_addr);
+        if let Some(limiter) = inbound_limiter {
+            builder.set_inbound_limiter(limiter);
+        }
+        if let Some(timeout) = self.sniff_timeout {
+            builder.set_sniff_timeout(timeout);
         }
+        builder
+    }
 
-        Ok(Tunnel { tcp_server, udp_server })
+    fn udp_server_builder(&self, udp_addr: ServerAddr, forward_addr: Address) -> TunnelUdpServerBuilder {
+        TunnelUdpServerBuilder::new(
+            self.context.clone(),
+            udp_addr,
+            self.udp_expiry_duration,
+            self.udp_capacity,
+            self.balancer.clone(),
+            forward_addr,
+        )
     }
 }
 
 /// Tunnel Server
 pub struct Tunnel {
-    tcp_server: Option<TunnelTcpServer>,
-    udp_server: Option<TunnelUdpServer>,
+    tcp_servers: Vec<TunnelTcpServer>,
+    udp_servers: Vec<TunnelUdpServer>,
 }
 
 impl Tunnel {
-    /// TCP server instance
+    /// TCP server instance of the first mapping
     pub fn tcp_server(&self) -> Option<&TunnelTcpServer> {
-        self.tcp_server.as_ref()
+        self.tcp_servers.first()
     }
 
-    /// UDP server instance
+    /// UDP server instance of the first mapping
     pub fn udp_server(&self) -> Option<&TunnelUdpServer> {
-        self.udp_server.as_ref()
+        self.udp_servers.first()
+    }
+
+    /// TCP server instances of all mappings
+    pub fn tcp_servers(&self) -> &[TunnelTcpServer] {
+        &self.tcp_servers
+    }
+
+    /// UDP server instances of all mappings
+    pub fn udp_servers(&self) -> &[TunnelUdpServer] {
+        &self.udp_servers
     }
 
     /// Start serving
     pub async fn run(self) -> io::Result<()> {
         let mut vfut = Vec::new();
 
-        if let Some(tcp_server) = self.tcp_server {
+        for tcp_server in self.tcp_servers {
             vfut.push(tcp_server.run().boxed());
         }
 
-        if let Some(udp_server) = self.udp_server {
+        for udp_server in self.udp_servers {
             vfut.push(udp_server.run().boxed());
         }
 
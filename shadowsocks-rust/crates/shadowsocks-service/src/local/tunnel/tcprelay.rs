@@ -2,14 +2,17 @@
 
 use std::{io, net::SocketAddr, sync::Arc, time::Duration};
 
-use log::{error, info, trace};
+use log::{debug, error, info, trace};
 use shadowsocks::{net::TcpListener as ShadowTcpListener, relay::socks5::Address, ServerAddr};
 use tokio::{net::TcpStream, time};
 
 use crate::local::{
     context::ServiceContext,
     loadbalancing::PingBalancer,
-    net::{tcp::listener::create_standard_tcp_listener, AutoProxyClientStream},
+    net::{
+        limit::InboundLimiter, rewind::RewindStream, sniff::sniff_host, tcp::listener::create_standard_tcp_listener,
+        AutoProxyClientStream,
+    },
     utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
 };
 
@@ -18,6 +21,8 @@ pub struct TunnelTcpServerBuilder {
     client_config: ServerAddr,
     balancer: PingBalancer,
     forward_addr: Address,
+    inbound_limiter: Option<Arc<InboundLimiter>>,
+    sniff_timeout: Option<Duration>,
     #[cfg(target_os = "macos")]
     launchd_socket_name: Option<String>,
 }
@@ -34,11 +39,23 @@ impl TunnelTcpServerBuilder {
             client_config,
             balancer,
             forward_addr,
+            inbound_limiter: None,
+            sniff_timeout: None,
             #[cfg(target_os = "macos")]
             launchd_socket_name: None,
         }
     }
 
+    /// Limit concurrent and new connections of clients, which could be shared with other servers
+    pub(crate) fn set_inbound_limiter(&mut self, limiter: Arc<InboundLimiter>) {
+        self.inbound_limiter = Some(limiter);
+    }
+
+    /// Sniff TLS SNI or HTTP `Host` of clients for ACL domain rules, waiting at most `timeout` for them
+    pub(crate) fn set_sniff_timeout(&mut self, timeout: Duration) {
+        self.sniff_timeout = Some(timeout);
+    }
+
     /// macOS launchd activate socket
     #[cfg(target_os = "macos")]
     pub fn set_launchd_socket_name(&mut self, n: String) {
@@ -63,11 +80,19 @@ impl TunnelTcpServerBuilder {
             }
         }
 
+        // Domain names are only sniffed if the target is an IP address
+        let sniff_timeout = match self.forward_addr {
+            Address::SocketAddress(..) => self.sniff_timeout,
+            Address::DomainNameAddress(..) => None,
+        };
+
         Ok(TunnelTcpServer {
             context: self.context,
             listener,
             balancer: self.balancer,
             forward_addr: self.forward_addr,
+            inbound_limiter: self.inbound_limiter,
+            sniff_timeout,
         })
     }
 }
@@ -78,6 +103,8 @@ pub struct TunnelTcpServer {
     listener: ShadowTcpListener,
     balancer: PingBalancer,
     forward_addr: Address,
+    inbound_limiter: Option<Arc<InboundLimiter>>,
+    sniff_timeout: Option<Duration>,
 }
 
 impl TunnelTcpServer {
@@ -101,13 +128,25 @@ impl TunnelTcpServer {
                 }
             };
 
-            tokio::spawn(handle_tcp_client(
-                self.context.clone(),
-                stream,
-                self.balancer.clone(),
-                peer_addr,
-                forward_addr.clone(),
-            ));
+            let permit = match self.inbound_limiter {
+                None => None,
+                Some(ref limiter) => match limiter.acquire(&peer_addr) {
+                    Ok(permit) => Some(permit),
+                    Err(reason) => {
+                        debug!("tcp tunnel rejected {}, {}", peer_addr, reason);
+                        continue;
+                    }
+                },
+            };
+
+            let context = self.context.clone();
+            let balancer = self.balancer.clone();
+            let forward_addr = forward_addr.clone();
+            let sniff_timeout = self.sniff_timeout;
+            tokio::spawn(async move {
+                let _permit = permit;
+                handle_tcp_client(context, stream, balancer, peer_addr, forward_addr, sniff_timeout).await
+            });
         }
     }
 }
@@ -118,17 +157,22 @@ async fn handle_tcp_client(
     balancer: PingBalancer,
     peer_addr: SocketAddr,
     forward_addr: Arc<Address>,
+    sniff_timeout: Option<Duration>,
 ) -> io::Result<()> {
     let forward_addr: &Address = &forward_addr;
 
+    if let Some(nodelay) = context.target_nodelay(forward_addr) {
+        stream.set_nodelay(nodelay)?;
+    }
+
     if balancer.is_empty() {
         trace!("establishing tcp tunnel {} <-> {} direct", peer_addr, forward_addr);
 
-        let mut remote = AutoProxyClientStream::connect_bypassed(context, forward_addr).await?;
-        return establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, forward_addr).await;
+        let mut remote = AutoProxyClientStream::connect_bypassed(context.clone(), forward_addr).await?;
+        return establish_tcp_tunnel_bypassed(&context, &mut stream, &mut remote, peer_addr, forward_addr).await;
     }
 
-    let server = balancer.best_tcp_server();
+    let server = balancer.best_tcp_server_for_client(&peer_addr, forward_addr);
     let svr_cfg = server.server_config();
     trace!(
         "establishing tcp tunnel {} <-> {} through sever {} (outbound: {})",
@@ -138,8 +182,33 @@ async fn handle_tcp_client(
         svr_cfg.addr(),
     );
 
-    let mut remote =
-        AutoProxyClientStream::connect_proxied_with_opts(context, &server, forward_addr, server.connect_opts_ref())
-            .await?;
-    establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, forward_addr).await
+    let (prefix, host) = match sniff_timeout {
+        Some(timeout) => sniff_host(&mut stream, timeout).await?,
+        None => (Vec::new(), None),
+    };
+    let mut stream = RewindStream::new(prefix, stream);
+
+    let mut remote = match host {
+        Some(ref host) => {
+            trace!("tcp tunnel {} -> {} sniffed host {}", peer_addr, forward_addr, host);
+            AutoProxyClientStream::connect_sniffed_with_opts(
+                context.clone(),
+                &server,
+                forward_addr,
+                host,
+                server.connect_opts_ref(),
+            )
+            .await?
+        }
+        None => {
+            AutoProxyClientStream::connect_proxied_with_opts(
+                context.clone(),
+                &server,
+                forward_addr,
+                server.connect_opts_ref(),
+            )
+            .await?
+        }
+    };
+    establish_tcp_tunnel(&context, svr_cfg, &mut stream, &mut remote, peer_addr, forward_addr).await
 }
@@ -10,6 +10,7 @@ use shadowsocks::{
 };
 use tokio::{net::UdpSocket, time};
 
+use super::dns::DnsQueryTracker;
 use crate::local::{
     context::ServiceContext,
     loadbalancing::PingBalancer,
@@ -23,6 +24,7 @@ pub struct TunnelUdpServerBuilder {
     capacity: Option<usize>,
     balancer: PingBalancer,
     forward_addr: Address,
+    dns_query_timeout: Option<Duration>,
     #[cfg(target_os = "macos")]
     launchd_socket_name: Option<String>,
 }
@@ -43,11 +45,17 @@ impl TunnelUdpServerBuilder {
             capacity,
             balancer,
             forward_addr,
+            dns_query_timeout: None,
             #[cfg(target_os = "macos")]
             launchd_socket_name: None,
         }
     }
 
+    /// Treat packets as DNS messages, answer `SERVFAIL` for queries that are not answered in `timeout`
+    pub fn set_dns_query_timeout(&mut self, timeout: Duration) {
+        self.dns_query_timeout = Some(timeout);
+    }
+
     /// macOS launchd activate socket
     #[cfg(target_os = "macos")]
     pub fn set_launchd_socket_name(&mut self, n: String) {
@@ -78,6 +86,7 @@ impl TunnelUdpServerBuilder {
             listener: Arc::new(socket),
             balancer: self.balancer,
             forward_addr: self.forward_addr,
+            dns_queries: self.dns_query_timeout.map(|t| Arc::new(DnsQueryTracker::new(t))),
         })
     }
 }
@@ -85,11 +94,15 @@ impl TunnelUdpServerBuilder {
 #[derive(Clone)]
 struct TunnelUdpInboundWriter {
     inbound: Arc<UdpSocket>,
+    dns_queries: Option<Arc<DnsQueryTracker>>,
 }
 
 #[async_trait]
 impl UdpInboundWrite for TunnelUdpInboundWriter {
     async fn send_to(&self, peer_addr: SocketAddr, _remote_addr: &Address, data: &[u8]) -> io::Result<()> {
+        if let Some(ref dns_queries) = self.dns_queries {
+            dns_queries.response_received(peer_addr, data);
+        }
         self.inbound.send_to(data, peer_addr).await.map(|_| ())
     }
 }
@@ -101,6 +114,7 @@ pub struct TunnelUdpServer {
     listener: Arc<UdpSocket>,
     balancer: PingBalancer,
     forward_addr: Address,
+    dns_queries: Option<Arc<DnsQueryTracker>>,
 }
 
 impl TunnelUdpServer {
@@ -117,6 +131,7 @@ impl TunnelUdpServer {
             self.context.clone(),
             TunnelUdpInboundWriter {
                 inbound: self.listener.clone(),
+                dns_queries: self.dns_queries.clone(),
             },
             self.time_to_live,
             self.capacity,
@@ -125,6 +140,10 @@ impl TunnelUdpServer {
 
         let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
         let mut cleanup_timer = time::interval(cleanup_interval);
+        let mut dns_timer = time::interval(match self.dns_queries {
+            Some(ref dns_queries) => dns_queries.check_interval(),
+            None => cleanup_interval,
+        });
 
         loop {
             tokio::select! {
@@ -133,6 +152,14 @@ impl TunnelUdpServer {
                     manager.cleanup_expired().await;
                 }
 
+                _ = dns_timer.tick(), if self.dns_queries.is_some() => {
+                    let dns_queries = self.dns_queries.as_ref().unwrap();
+                    for (peer_addr, response) in dns_queries.take_expired() {
+                        debug!("udp tunnel dns query from {} timed out, responding SERVFAIL", peer_addr);
+                        let _ = self.listener.send_to(&response, peer_addr).await;
+                    }
+                }
+
                 peer_addr_opt = keepalive_rx.recv() => {
                     let peer_addr = peer_addr_opt.expect("keep-alive channel closed unexpectly");
                     manager.keep_alive(&peer_addr).await;
@@ -160,6 +187,9 @@ impl TunnelUdpServer {
                     }
 
                     let data = &buffer[..n];
+                    if let Some(ref dns_queries) = self.dns_queries {
+                        dns_queries.query_sent(peer_addr, data);
+                    }
                     if let Err(err) = manager.send_to(peer_addr, self.forward_addr.clone(), data)
                         .await
                     {
@@ -1,20 +1,71 @@
 //! Shadowsocks Local Utilities
 
-use std::{io, net::SocketAddr, time::Duration};
+use std::{
+    future, io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
 
+use futures::FutureExt;
 use log::{debug, trace};
 use shadowsocks::{
     config::ServerConfig,
-    relay::{socks5::Address, tcprelay::utils::copy_encrypted_bidirectional},
+    relay::{
+        socks5::Address,
+        tcprelay::utils::{alloc_plain_read_buffer, copy_encrypted_bidirectional},
+    },
 };
 use tokio::{
-    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::Notify,
     time,
 };
 
-use crate::local::net::AutoProxyIo;
+use crate::local::{context::ServiceContext, net::AutoProxyIo};
+
+/// Counters of first packets of proxied TCP tunnels, which carry target addresses with data sent by clients
+#[derive(Debug, Default)]
+pub struct FirstPacketStat {
+    packets: AtomicU64,
+    coalesced_reads: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl FirstPacketStat {
+    /// Create an empty statistic
+    pub fn new() -> FirstPacketStat {
+        FirstPacketStat::default()
+    }
+
+    /// Count a first packet of `bytes` data, collected from `reads` reads of the client
+    fn incr(&self, reads: u64, bytes: u64) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.coalesced_reads
+            .fetch_add(reads.saturating_sub(1), Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// First packets sent with data
+    pub fn packets(&self) -> u64 {
+        self.packets.load(Ordering::Relaxed)
+    }
+
+    /// Reads of clients coalesced into first packets besides the first read, each saves a packet to the server
+    pub fn coalesced_reads(&self) -> u64 {
+        self.coalesced_reads.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of data sent in first packets
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
 
 pub(crate) async fn establish_tcp_tunnel<P, S>(
+    context: &ServiceContext,
     svr_cfg: &ServerConfig,
     plain: &mut P,
     shadow: &mut S,
@@ -34,24 +85,54 @@ where
             svr_cfg.addr(),
         );
     } else {
-        return establish_tcp_tunnel_bypassed(plain, shadow, peer_addr, target_addr).await;
+        return establish_tcp_tunnel_bypassed(context, plain, shadow, peer_addr, target_addr).await;
     }
 
+    let watcher = TunnelWatcher::new(context);
+    let plain = &mut watcher.watch(plain);
+
     // https://github.com/shadowsocks/shadowsocks-rust/issues/232
     //
     // Protocols like FTP, clients will wait for servers to send Welcome Message without sending anything.
     //
     // Wait at most 500ms, and then sends handshake packet to remote servers.
+    //
+    // Target address is sent with the first packet together, so everything that the client has already sent is
+    // collected into the first packet, saving round trips of small packets at the very beginning of connections.
     {
-        let mut buffer = [0u8; 8192];
+        let start = Instant::now();
+        let mut buffer = alloc_plain_read_buffer(svr_cfg.method());
         match time::timeout(Duration::from_millis(500), plain.read(&mut buffer)).await {
             Ok(Ok(0)) => {
                 // EOF. Just terminate right here.
                 return Ok(());
             }
-            Ok(Ok(n)) => {
+            Ok(Ok(mut n)) => {
+                // Collect the rest of data that is already available, without waiting.
+                let mut reads = 1;
+                while n < buffer.len() {
+                    match plain.read(&mut buffer[n..]).now_or_never() {
+                        Some(Ok(0)) | None => break,
+                        Some(Ok(m)) => {
+                            n += m;
+                            reads += 1;
+                        }
+                        Some(Err(err)) => return Err(err),
+                    }
+                }
+
                 // Send the first packet.
                 shadow.write_all(&buffer[..n]).await?;
+                context.first_packet_stat().incr(reads, n as u64);
+
+                trace!(
+                    "tcp tunnel {} -> {} (proxied) sent handshake with {} bytes of {} reads in {:?}",
+                    peer_addr,
+                    target_addr,
+                    n,
+                    reads,
+                    start.elapsed()
+                );
             }
             Ok(Err(err)) => return Err(err),
             Err(..) => {
@@ -67,7 +148,14 @@ where
         }
     }
 
-    match copy_encrypted_bidirectional(svr_cfg.method(), shadow, plain).await {
+    let result = tokio::select! {
+        r = copy_encrypted_bidirectional(svr_cfg.method(), shadow, plain) => r,
+        reason = watcher.timed_out() => {
+            trace!("tcp tunnel {} <-> {} (proxied) closed by {}", peer_addr, target_addr, reason);
+            return Ok(());
+        }
+    };
+    match result {
         Ok((wn, rn)) => {
             trace!(
                 "tcp tunnel {} <-> {} (proxied) closed, L2R {} bytes, R2L {} bytes",
@@ -91,6 +179,7 @@ where
 }
 
 pub(crate) async fn establish_tcp_tunnel_bypassed<P, S>(
+    context: &ServiceContext,
     plain: &mut P,
     shadow: &mut S,
     peer_addr: SocketAddr,
@@ -102,7 +191,17 @@ where
 {
     debug!("established tcp tunnel {} <-> {} bypassed", peer_addr, target_addr);
 
-    match copy_bidirectional(plain, shadow).await {
+    let watcher = TunnelWatcher::new(context);
+    let mut plain = watcher.watch(plain);
+
+    let result = tokio::select! {
+        r = copy_bidirectional(&mut plain, shadow) => r,
+        reason = watcher.timed_out() => {
+            trace!("tcp tunnel {} <-> {} (bypassed) closed by {}", peer_addr, target_addr, reason);
+            return Ok(());
+        }
+    };
+    match result {
         Ok((rn, wn)) => {
             trace!(
                 "tcp tunnel {} <-> {} (bypassed) closed, L2R {} bytes, R2L {} bytes",
@@ -124,3 +223,152 @@ where
 
     Ok(())
 }
+
+/// Activities of a TCP tunnel, which is closed after `tcp_idle_timeout` or `tcp_half_close_timeout` of the local server
+struct TunnelWatcher {
+    idle_timeout: Option<Duration>,
+    half_close_timeout: Option<Duration>,
+    start: Instant,
+    // Milliseconds since `start`
+    last_active: AtomicU64,
+    // Milliseconds since `start` plus 1, 0 if both directions are open
+    half_closed: AtomicU64,
+    half_closed_notify: Notify,
+}
+
+impl TunnelWatcher {
+    fn new(context: &ServiceContext) -> TunnelWatcher {
+        TunnelWatcher {
+            idle_timeout: context.tcp_idle_timeout(),
+            half_close_timeout: context.tcp_half_close_timeout(),
+            start: Instant::now(),
+            last_active: AtomicU64::new(0),
+            half_closed: AtomicU64::new(0),
+            half_closed_notify: Notify::new(),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.idle_timeout.is_some() || self.half_close_timeout.is_some()
+    }
+
+    /// Record activities of `stream`, the plain side of the tunnel, which are in both directions
+    fn watch<S>(&self, stream: S) -> WatchedStream<'_, S> {
+        WatchedStream {
+            stream,
+            watcher: if self.is_enabled() { Some(self) } else { None },
+        }
+    }
+
+    fn elapsed_millis(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn active(&self) {
+        self.last_active.store(self.elapsed_millis(), Ordering::Relaxed);
+    }
+
+    fn half_close(&self) {
+        let closed_at = self.elapsed_millis() + 1;
+        if self
+            .half_closed
+            .compare_exchange(0, closed_at, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.half_closed_notify.notify_one();
+        }
+    }
+
+    /// Resolves with the reason after any of the timeouts is reached, never resolves if they are not set
+    async fn timed_out(&self) -> &'static str {
+        if !self.is_enabled() {
+            return future::pending().await;
+        }
+
+        loop {
+            let mut deadline = None;
+            let mut reason = "";
+            if let Some(timeout) = self.idle_timeout {
+                let last_active = Duration::from_millis(self.last_active.load(Ordering::Relaxed));
+                deadline = Some(self.start + last_active + timeout);
+                reason = "tcp_idle_timeout";
+            }
+            if let Some(timeout) = self.half_close_timeout {
+                let half_closed = self.half_closed.load(Ordering::Relaxed);
+                if half_closed > 0 {
+                    let d = self.start + Duration::from_millis(half_closed - 1) + timeout;
+                    if deadline.map_or(true, |deadline| d < deadline) {
+                        deadline = Some(d);
+                        reason = "tcp_half_close_timeout";
+                    }
+                }
+            }
+
+            match deadline {
+                Some(deadline) if deadline <= Instant::now() => return reason,
+                Some(deadline) => {
+                    tokio::select! {
+                        _ = time::sleep_until(deadline.into()) => {}
+                        _ = self.half_closed_notify.notified() => {}
+                    }
+                }
+                None => self.half_closed_notify.notified().await,
+            }
+        }
+    }
+}
+
+/// Plain stream of a tunnel, recording its activities to the watcher
+struct WatchedStream<'a, S> {
+    stream: S,
+    watcher: Option<&'a TunnelWatcher>,
+}
+
+impl<S> AsyncRead for WatchedStream<'_, S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.stream).poll_read(cx, buf);
+        if let (Some(watcher), Poll::Ready(Ok(()))) = (this.watcher, &result) {
+            if buf.filled().len() > filled {
+                watcher.active();
+            } else if buf.remaining() > 0 {
+                // EOF, client closed its sending direction
+                watcher.half_close();
+            }
+        }
+        result
+    }
+}
+
+impl<S> AsyncWrite for WatchedStream<'_, S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.stream).poll_write(cx, buf);
+        if let (Some(watcher), Poll::Ready(Ok(n))) = (this.watcher, &result) {
+            if *n > 0 {
+                watcher.active();
+            }
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // Remote closed its sending direction
+        if let Some(watcher) = this.watcher {
+            watcher.half_close();
+        }
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
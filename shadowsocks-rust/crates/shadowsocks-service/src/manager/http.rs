@@ -0,0 +1,267 @@
+//! HTTP REST API of manager
+//!
+//! Serves the same commands of the manager protocol in JSON, for web panels:
+//!
+//! ```plain
+//! GET    /servers         `list`
+//! POST   /servers         `add`, with the same JSON parameter in body
+//! DELETE /servers/<port>  `remove`
+//! GET    /ping            `ping`, traffic of servers
+//! GET    /load            `load`
+//! GET    /users           `users`
+//! GET    /quota           `quota`
+//! POST   /quota/reset     `reset_quota`, with the same JSON parameter in body
+//! ```
+//!
+//! Requests are authenticated with `Authorization: Bearer <token>`. Errors are responded in `{"error": "<reason>"}`.
+
+use std::{
+    convert::Infallible,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{self, HeaderValue},
+    server::conn::http1,
+    service, Method, Request, Response, StatusCode,
+};
+use ipnet::IpNet;
+use log::{debug, error, info, trace, warn};
+use serde::Serialize;
+use shadowsocks::manager::protocol::{AddRequest, RemoveRequest, ResetQuotaRequest};
+use tokio::{net::TcpListener, time};
+
+use crate::{
+    config::ManagerHttpConfig,
+    net::{tokio_rt::TokioIo, utils::constant_time_eq},
+};
+
+use super::server::Manager;
+
+/// Maximum size of a request body
+const MANAGER_HTTP_MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Manager's HTTP REST API server
+pub struct ManagerHttpServer {
+    listener: TcpListener,
+    token: String,
+    allowed_ips: Vec<IpNet>,
+}
+
+impl ManagerHttpServer {
+    /// Bind the listener
+    pub async fn bind(config: ManagerHttpConfig) -> io::Result<ManagerHttpServer> {
+        let listener = TcpListener::bind(config.listen_addr).await?;
+        Ok(ManagerHttpServer {
+            listener,
+            token: config.token,
+            allowed_ips: config.allowed_ips,
+        })
+    }
+
+    /// Start serving requests of `manager`
+    pub async fn run(&self, manager: Arc<Manager>) -> io::Result<()> {
+        info!(
+            "shadowsocks manager http listening on http://{}",
+            self.listener.local_addr()?
+        );
+
+        let service = Arc::new(ManagerHttpService {
+            manager,
+            token: self.token.clone(),
+        });
+
+        loop {
+            let (stream, peer_addr) = match self.listener.accept().await {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("manager http accept failed with error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            if !self.is_allowed(peer_addr.ip()) {
+                warn!("manager http rejected {}, not in allowed_ips", peer_addr);
+                continue;
+            }
+
+            trace!("manager http accepted client {}", peer_addr);
+
+            let service = service.clone();
+            tokio::spawn(async move {
+                let result = http1::Builder::new()
+                    .serve_connection(
+                        TokioIo::new(stream),
+                        service::service_fn(move |req| {
+                            let service = service.clone();
+                            async move { Ok::<_, Infallible>(service.serve_request(req, peer_addr).await) }
+                        }),
+                    )
+                    .await;
+
+                if let Err(err) = result {
+                    debug!("manager http connection {} failed, error: {}", peer_addr, err);
+                }
+            });
+        }
+    }
+
+    fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.allowed_ips.is_empty() {
+            return true;
+        }
+
+        // IPv4 clients of dual-stack listeners
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        self.allowed_ips.iter().any(|net| net.contains(&ip))
+    }
+}
+
+struct ManagerHttpService {
+    manager: Arc<Manager>,
+    token: String,
+}
+
+impl ManagerHttpService {
+    async fn serve_request(&self, req: Request<Incoming>, peer_addr: SocketAddr) -> Response<Full<Bytes>> {
+        if !self.check_token(&req) {
+            warn!("manager http rejected {}, invalid token", peer_addr);
+
+            let mut rsp = make_error_response(StatusCode::UNAUTHORIZED, "unauthorized");
+            rsp.headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            return rsp;
+        }
+
+        trace!(
+            "manager http received {} {} from {}",
+            req.method(),
+            req.uri(),
+            peer_addr
+        );
+
+        let path = req.uri().path().trim_end_matches('/').to_owned();
+        match (req.method().clone(), path.as_str()) {
+            (Method::GET, "/servers") => make_response(StatusCode::OK, &self.manager.handle_list().await.servers),
+            (Method::POST, "/servers") => {
+                let body = match read_body(req).await {
+                    Some(b) => b,
+                    None => return make_error_response(StatusCode::PAYLOAD_TOO_LARGE, "request is too large"),
+                };
+                let add_req = match serde_json::from_slice::<AddRequest>(&body) {
+                    Ok(r) => r,
+                    Err(err) => return make_error_response(StatusCode::BAD_REQUEST, &err.to_string()),
+                };
+
+                match self.manager.handle_add(&add_req).await {
+                    Ok(..) => make_empty_response(StatusCode::NO_CONTENT),
+                    Err(err) => {
+                        error!("add server_port: {} failed, error: {}", add_req.server_port, err);
+                        make_error_response(StatusCode::BAD_REQUEST, &err.to_string())
+                    }
+                }
+            }
+            (Method::DELETE, path) if path.starts_with("/servers/") => {
+                let server_port = match path["/servers/".len()..].parse::<u16>() {
+                    Ok(p) => p,
+                    Err(..) => return make_error_response(StatusCode::BAD_REQUEST, "invalid server port"),
+                };
+                self.manager.handle_remove(&RemoveRequest { server_port }).await;
+                make_empty_response(StatusCode::NO_CONTENT)
+            }
+            (Method::GET, "/ping") => make_response(StatusCode::OK, &self.manager.handle_ping().await),
+            (Method::GET, "/load") => make_response(StatusCode::OK, &self.manager.handle_load().await),
+            (Method::GET, "/users") => make_response(StatusCode::OK, &self.manager.handle_users().await),
+            (Method::GET, "/quota") => make_response(StatusCode::OK, &self.manager.handle_quota().await),
+            (Method::POST, "/quota/reset") => {
+                let body = match read_body(req).await {
+                    Some(b) => b,
+                    None => return make_error_response(StatusCode::PAYLOAD_TOO_LARGE, "request is too large"),
+                };
+                let reset_req = match serde_json::from_slice::<ResetQuotaRequest>(&body) {
+                    Ok(r) => r,
+                    Err(err) => return make_error_response(StatusCode::BAD_REQUEST, &err.to_string()),
+                };
+
+                let rsp = self.manager.handle_reset_quota(&reset_req).await;
+                if rsp.0 == "ok" {
+                    make_empty_response(StatusCode::NO_CONTENT)
+                } else {
+                    make_error_response(StatusCode::NOT_FOUND, &rsp.0)
+                }
+            }
+            (_, "/servers" | "/ping" | "/load" | "/users" | "/quota" | "/quota/reset") => {
+                make_error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+            }
+            (_, path) if path.starts_with("/servers/") => {
+                make_error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+            }
+            _ => make_error_response(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+
+    fn check_token(&self, req: &Request<Incoming>) -> bool {
+        let token = match req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+        {
+            Some(t) => t.trim(),
+            None => return false,
+        };
+
+        constant_time_eq(token.as_bytes(), self.token.as_bytes())
+    }
+}
+
+/// Read the request body, `None` if it is too large
+async fn read_body(req: Request<Incoming>) -> Option<Bytes> {
+    match Limited::new(req.into_body(), MANAGER_HTTP_MAX_BODY_SIZE)
+        .collect()
+        .await
+    {
+        Ok(b) => Some(b.to_bytes()),
+        Err(..) => None,
+    }
+}
+
+fn make_response<T: Serialize>(status: StatusCode, body: &T) -> Response<Full<Bytes>> {
+    let body = match serde_json::to_vec(body) {
+        Ok(b) => b,
+        Err(err) => {
+            error!("manager http serialize response failed, error: {}", err);
+            return make_empty_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let mut rsp = Response::new(Full::new(Bytes::from(body)));
+    *rsp.status_mut() = status;
+    rsp.headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    rsp
+}
+
+fn make_error_response(status: StatusCode, error: &str) -> Response<Full<Bytes>> {
+    #[derive(Serialize)]
+    struct ErrorBody<'a> {
+        error: &'a str,
+    }
+
+    make_response(status, &ErrorBody { error })
+}
+
+fn make_empty_response(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut rsp = Response::new(Full::new(Bytes::new()));
+    *rsp.status_mut() = status;
+    rsp
+}
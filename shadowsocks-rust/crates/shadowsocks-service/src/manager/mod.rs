@@ -13,8 +13,10 @@ use crate::{
     server::SERVER_DEFAULT_KEEPALIVE_TIMEOUT,
 };
 
-pub use self::server::{Manager, ManagerBuilder};
+pub use self::server::{Manager, ManagerBuilder, ServerLimits};
 
+#[cfg(feature = "manager-http")]
+mod http;
 pub mod server;
 
 /// Starts a manager server
@@ -39,6 +41,8 @@ pub async fn run(config: Config) -> io::Result<()> {
 
         #[cfg(target_os = "android")]
         vpn_protect_path: config.outbound_vpn_protect_path,
+        #[cfg(target_os = "android")]
+        vpn_socket_protect: config.outbound_vpn_socket_protect,
 
         bind_local_addr: config.outbound_bind_addr,
         bind_interface: config.outbound_bind_interface,
@@ -72,6 +76,9 @@ pub async fn run(config: Config) -> io::Result<()> {
         manager_builder.set_dns_resolver(Arc::new(resolver));
     }
     manager_builder.set_ipv6_first(config.ipv6_first);
+    if let Some(family) = config.outbound_addr_family {
+        manager_builder.set_addr_family(family);
+    }
 
     manager_builder.set_connect_opts(connect_opts);
     manager_builder.set_accept_opts(accept_opts);
@@ -91,7 +98,14 @@ pub async fn run(config: Config) -> io::Result<()> {
     let manager = manager_builder.build().await?;
 
     for svr_inst in config.server {
-        manager.add_server(svr_inst.config).await;
+        let limits = ServerLimits {
+            max_connections: svr_inst.max_connections,
+            udp_max_associations: None,
+            rate_limit: svr_inst.rate_limit,
+            user_rate_limits: svr_inst.user_rate_limits,
+            quota: svr_inst.quota,
+        };
+        manager.add_server_with_limits(svr_inst.config, limits).await;
     }
 
     manager.run().await
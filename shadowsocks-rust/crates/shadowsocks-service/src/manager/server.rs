@@ -13,11 +13,12 @@ use shadowsocks::{
     manager::{
         datagram::ManagerSocketAddr,
         protocol::{
-            self, AddRequest, AddResponse, ErrorResponse, ListResponse, ManagerRequest, PingResponse, RemoveRequest,
-            RemoveResponse, ServerUserConfig, StatRequest,
+            self, AddRequest, AddResponse, ErrorResponse, ListResponse, LoadResponse, ManagerRequest, PingResponse,
+            QuotaResponse, QuotaUsage, RemoveRequest, RemoveResponse, ResetQuotaRequest, ResetQuotaResponse,
+            ServerLoad, ServerQuota, ServerUserConfig, StatRequest, UserFlow, UsersResponse,
         },
     },
-    net::{AcceptOpts, ConnectOpts},
+    net::{AcceptOpts, AddrFamily, ConnectOpts},
     plugin::PluginConfig,
     ManagerListener, ServerAddr,
 };
@@ -25,14 +26,36 @@ use tokio::{sync::Mutex, task::JoinHandle};
 
 use crate::{
     acl::AccessControl,
-    config::{ManagerConfig, ManagerServerHost, ManagerServerMode, SecurityConfig},
-    net::FlowStat,
-    server::ServerBuilder,
+    config::{ManagerConfig, ManagerServerHost, ManagerServerMode, QuotaConfig, RateLimitConfig, SecurityConfig},
+    net::{FlowStat, UserFlowStat},
+    server::{LoadStat, ServerBuilder, TrafficQuota},
 };
 
+#[cfg(feature = "manager-http")]
+use super::http::ManagerHttpServer;
+
+/// Resource limits of one managed server, isolating its load from the other servers
+#[derive(Debug, Clone, Default)]
+pub struct ServerLimits {
+    /// Maximum concurrent TCP connections, unlimited if `None`
+    pub max_connections: Option<usize>,
+    /// Maximum UDP associations, overrides the manager's `udp_max_associations`
+    pub udp_max_associations: Option<usize>,
+    /// Bandwidth limit shared by all clients, unlimited if `None`
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Bandwidth limits of users
+    pub user_rate_limits: HashMap<String, RateLimitConfig>,
+    /// Traffic quotas of the server and its users
+    pub quota: QuotaConfig,
+}
+
 enum ServerInstanceMode {
     Builtin {
         flow_stat: Arc<FlowStat>,
+        user_flow_stat: Arc<UserFlowStat>,
+        load_stat: Arc<LoadStat>,
+        traffic_quota: Option<Arc<TrafficQuota>>,
+        user_traffic_quotas: HashMap<String, Arc<TrafficQuota>>,
         abortable: JoinHandle<io::Result<()>>,
     },
 
@@ -43,6 +66,7 @@ enum ServerInstanceMode {
 struct ServerInstance {
     mode: ServerInstanceMode,
     svr_cfg: ServerConfig,
+    limits: ServerLimits,
 }
 
 impl Drop for ServerInstance {
@@ -62,6 +86,68 @@ impl ServerInstance {
             ServerInstanceMode::Standalone { flow_stat } => flow_stat,
         }
     }
+
+    fn load_stat(&self) -> Option<ServerLoad> {
+        match self.mode {
+            ServerInstanceMode::Builtin { ref load_stat, .. } => Some(ServerLoad {
+                tcp_connections: load_stat.tcp_connections(),
+                max_connections: self.limits.max_connections,
+                tcp_rejected: load_stat.tcp_rejected() as u64,
+                udp_associations: load_stat.udp_associations(),
+                udp_max_associations: self.limits.udp_max_associations,
+                udp_evicted: load_stat.udp_evicted() as u64,
+            }),
+            #[cfg(unix)]
+            ServerInstanceMode::Standalone { .. } => None,
+        }
+    }
+
+    fn user_flow_stat(&self) -> Option<HashMap<String, UserFlow>> {
+        match self.mode {
+            ServerInstanceMode::Builtin { ref user_flow_stat, .. } => Some(
+                user_flow_stat
+                    .users()
+                    .into_iter()
+                    .map(|(name, stat)| {
+                        let flow = UserFlow {
+                            tx: stat.tx(),
+                            rx: stat.rx(),
+                        };
+                        (name, flow)
+                    })
+                    .collect(),
+            ),
+            #[cfg(unix)]
+            ServerInstanceMode::Standalone { .. } => None,
+        }
+    }
+
+    fn quota(&self) -> Option<ServerQuota> {
+        fn quota_usage(quota: &TrafficQuota) -> QuotaUsage {
+            let used = quota.used();
+            QuotaUsage {
+                quota: quota.quota(),
+                used,
+                exceeded: used >= quota.quota(),
+            }
+        }
+
+        match self.mode {
+            ServerInstanceMode::Builtin {
+                ref traffic_quota,
+                ref user_traffic_quotas,
+                ..
+            } => Some(ServerQuota {
+                server: traffic_quota.as_deref().map(quota_usage),
+                users: user_traffic_quotas
+                    .iter()
+                    .map(|(name, quota)| (name.clone(), quota_usage(quota)))
+                    .collect(),
+            }),
+            #[cfg(unix)]
+            ServerInstanceMode::Standalone { .. } => None,
+        }
+    }
 }
 
 /// Manager server builder
@@ -74,6 +160,7 @@ pub struct ManagerBuilder {
     udp_capacity: Option<usize>,
     acl: Option<Arc<AccessControl>>,
     ipv6_first: bool,
+    addr_family: Option<AddrFamily>,
     security: SecurityConfig,
 }
 
@@ -94,6 +181,7 @@ impl ManagerBuilder {
             udp_capacity: None,
             acl: None,
             ipv6_first: false,
+            addr_family: None,
             security: SecurityConfig::default(),
         }
     }
@@ -139,6 +227,11 @@ impl ManagerBuilder {
         self.ipv6_first = ipv6_first;
     }
 
+    /// Only connect addresses of `family`, the other family is disabled
+    pub fn set_addr_family(&mut self, family: AddrFamily) {
+        self.addr_family = Some(family);
+    }
+
     /// Set security config
     pub fn set_security_config(&mut self, security: SecurityConfig) {
         self.security = security;
@@ -147,6 +240,11 @@ impl ManagerBuilder {
     /// Build the manager server instance
     pub async fn build(self) -> io::Result<Manager> {
         let listener = ManagerListener::bind(&self.context, &self.svr_cfg.addr).await?;
+        #[cfg(feature = "manager-http")]
+        let http_server = match self.svr_cfg.http {
+            Some(ref config) => Some(ManagerHttpServer::bind(config.clone()).await?),
+            None => None,
+        };
         Ok(Manager {
             context: self.context,
             servers: Mutex::new(HashMap::new()),
@@ -157,8 +255,11 @@ impl ManagerBuilder {
             udp_capacity: self.udp_capacity,
             acl: self.acl,
             ipv6_first: self.ipv6_first,
+            addr_family: self.addr_family,
             security: self.security,
             listener,
+            #[cfg(feature = "manager-http")]
+            http_server,
         })
     }
 }
@@ -174,8 +275,11 @@ pub struct Manager {
     udp_capacity: Option<usize>,
     acl: Option<Arc<AccessControl>>,
     ipv6_first: bool,
+    addr_family: Option<AddrFamily>,
     security: SecurityConfig,
     listener: ManagerListener,
+    #[cfg(feature = "manager-http")]
+    http_server: Option<ManagerHttpServer>,
 }
 
 impl Manager {
@@ -190,10 +294,24 @@ impl Manager {
     }
 
     /// Start serving
-    pub async fn run(mut self) -> io::Result<()> {
+    pub async fn run(self) -> io::Result<()> {
         let local_addr = self.listener.local_addr()?;
         info!("shadowsocks manager server listening on {}", local_addr);
 
+        let manager = Arc::new(self);
+
+        #[cfg(feature = "manager-http")]
+        if let Some(ref http_server) = manager.http_server {
+            return tokio::select! {
+                r = manager.serve_requests() => r,
+                r = http_server.run(manager.clone()) => r,
+            };
+        }
+
+        manager.serve_requests().await
+    }
+
+    async fn serve_requests(&self) -> io::Result<()> {
         loop {
             let (req, peer_addr) = match self.listener.recv_from().await {
                 Ok(r) => r,
@@ -228,6 +346,22 @@ impl Manager {
                     let rsp = self.handle_ping().await;
                     let _ = self.listener.send_to(&rsp, &peer_addr).await;
                 }
+                ManagerRequest::Load(..) => {
+                    let rsp = self.handle_load().await;
+                    let _ = self.listener.send_to(&rsp, &peer_addr).await;
+                }
+                ManagerRequest::Users(..) => {
+                    let rsp = self.handle_users().await;
+                    let _ = self.listener.send_to(&rsp, &peer_addr).await;
+                }
+                ManagerRequest::Quota(..) => {
+                    let rsp = self.handle_quota().await;
+                    let _ = self.listener.send_to(&rsp, &peer_addr).await;
+                }
+                ManagerRequest::ResetQuota(ref req) => {
+                    let rsp = self.handle_reset_quota(req).await;
+                    let _ = self.listener.send_to(&rsp, &peer_addr).await;
+                }
                 ManagerRequest::Stat(ref stat) => self.handle_stat(stat).await,
             }
         }
@@ -235,14 +369,23 @@ impl Manager {
 
     /// Add a server programatically
     pub async fn add_server(&self, svr_cfg: ServerConfig) {
+        self.add_server_with_limits(svr_cfg, ServerLimits::default()).await
+    }
+
+    /// Add a server with its own resource limits programatically
+    pub async fn add_server_with_limits(&self, svr_cfg: ServerConfig, mut limits: ServerLimits) {
+        if limits.udp_max_associations.is_none() {
+            limits.udp_max_associations = self.udp_capacity;
+        }
+
         match self.svr_cfg.server_mode {
-            ManagerServerMode::Builtin => self.add_server_builtin(svr_cfg).await,
+            ManagerServerMode::Builtin => self.add_server_builtin(svr_cfg, limits).await,
             #[cfg(unix)]
-            ManagerServerMode::Standalone => self.add_server_standalone(svr_cfg).await,
+            ManagerServerMode::Standalone => self.add_server_standalone(svr_cfg, limits).await,
         }
     }
 
-    async fn add_server_builtin(&self, svr_cfg: ServerConfig) {
+    async fn add_server_builtin(&self, svr_cfg: ServerConfig, limits: ServerLimits) {
         // Each server should use a separate Context, but shares
         //
         // * AccessControlList
@@ -257,10 +400,26 @@ impl Manager {
             server_builder.set_udp_expiry_duration(d);
         }
 
-        if let Some(c) = self.udp_capacity {
+        if let Some(c) = limits.udp_max_associations {
             server_builder.set_udp_capacity(c);
         }
 
+        if let Some(c) = limits.max_connections {
+            server_builder.set_tcp_max_connections(c);
+        }
+
+        if let Some(limit) = limits.rate_limit {
+            server_builder.set_rate_limit(limit);
+        }
+
+        for (name, limit) in limits.user_rate_limits.iter() {
+            server_builder.set_user_rate_limit(name.clone(), *limit);
+        }
+
+        if !limits.quota.is_empty() {
+            server_builder.set_quota(&limits.quota);
+        }
+
         if let Some(ref acl) = self.acl {
             server_builder.set_acl(acl.clone());
         }
@@ -269,6 +428,10 @@ impl Manager {
             server_builder.set_ipv6_first(self.ipv6_first);
         }
 
+        if let Some(family) = self.addr_family {
+            server_builder.set_addr_family(family);
+        }
+
         server_builder.set_security_config(&self.security);
 
         let server_port = server_builder.server_config().addr().port();
@@ -284,6 +447,10 @@ impl Manager {
         }
 
         let flow_stat = server_builder.flow_stat();
+        let user_flow_stat = server_builder.user_flow_stat();
+        let load_stat = server_builder.load_stat();
+        let traffic_quota = server_builder.traffic_quota();
+        let user_traffic_quotas = server_builder.user_traffic_quotas();
         let server = match server_builder.build().await {
             Ok(s) => s,
             Err(err) => {
@@ -297,8 +464,16 @@ impl Manager {
         servers.insert(
             server_port,
             ServerInstance {
-                mode: ServerInstanceMode::Builtin { flow_stat, abortable },
+                mode: ServerInstanceMode::Builtin {
+                    flow_stat,
+                    user_flow_stat,
+                    load_stat,
+                    traffic_quota,
+                    user_traffic_quotas,
+                    abortable,
+                },
                 svr_cfg,
+                limits,
             },
         );
     }
@@ -354,7 +529,7 @@ impl Manager {
     }
 
     #[cfg(unix)]
-    async fn add_server_standalone(&self, svr_cfg: ServerConfig) {
+    async fn add_server_standalone(&self, svr_cfg: ServerConfig, limits: ServerLimits) {
         use std::{
             fs::{self, OpenOptions},
             io::Write,
@@ -388,10 +563,31 @@ impl Manager {
             outbound_fwmark: None,
             outbound_bind_addr: None,
             outbound_bind_interface: None,
+            uplink: None,
+            check_url: None,
+            check_dns: None,
+            check_interval: None,
+            check_timeout: None,
+            check_fail_threshold: None,
+            check_recover_threshold: None,
+            tier: 1,
+            group: None,
+            no_delay: None,
+            max_connections: limits.max_connections,
+            max_connections_per_ip: None,
+            udp_max_associations_per_ip: None,
+            rate_limit: limits.rate_limit,
+            user_rate_limits: limits.user_rate_limits.clone(),
+            user_acls: HashMap::new(),
+            quota: limits.quota.clone(),
+            tcp_bind: false,
+            reverse_tunnel: false,
+            port_range: None,
         };
 
         let mut config = Config::new(ConfigType::Server);
         config.server.push(server_instance);
+        config.udp_max_associations = limits.udp_max_associations;
 
         trace!("created standalone server with config {:?}", config);
 
@@ -433,8 +629,8 @@ impl Manager {
             .arg("--manager-addr")
             .arg(&manager_addr);
 
-        if let Some(ref acl) = self.acl {
-            child_command.arg("--acl").arg(acl.file_path().to_str().expect("acl"));
+        if let Some(acl_path) = self.acl.as_ref().and_then(|acl| acl.file_path()) {
+            child_command.arg("--acl").arg(acl_path.to_str().expect("acl"));
         }
 
         let child_result = child_command.kill_on_drop(false).spawn();
@@ -453,11 +649,12 @@ impl Manager {
             ServerInstance {
                 mode: ServerInstanceMode::Standalone { flow_stat: 0 },
                 svr_cfg,
+                limits,
             },
         );
     }
 
-    async fn handle_add(&self, req: &AddRequest) -> io::Result<AddResponse> {
+    pub(super) async fn handle_add(&self, req: &AddRequest) -> io::Result<AddResponse> {
         let addr = match self.svr_cfg.server_host {
             ManagerServerHost::Domain(ref dname) => ServerAddr::DomainName(dname.clone(), req.server_port),
             ManagerServerHost::Ip(ip) => ServerAddr::SocketAddr(SocketAddr::new(ip, req.server_port)),
@@ -516,10 +713,33 @@ impl Manager {
 
         svr_cfg.set_mode(mode.unwrap_or(self.svr_cfg.mode));
 
+        let mut user_rate_limits = HashMap::new();
+        let mut user_quotas = HashMap::new();
         if let Some(ref users) = req.users {
             let mut user_manager = ServerUserManager::new();
 
             for user in users.iter() {
+                if user.upload_kbps == Some(0) || user.download_kbps == Some(0) {
+                    return Ok(AddResponse(
+                        "users[].upload_kbps and users[].download_kbps must be > 0".to_owned(),
+                    ));
+                }
+                if user.upload_kbps.is_some() || user.download_kbps.is_some() {
+                    user_rate_limits.insert(
+                        user.name.clone(),
+                        RateLimitConfig {
+                            upload_kbps: user.upload_kbps,
+                            download_kbps: user.download_kbps,
+                        },
+                    );
+                }
+                if user.quota == Some(0) {
+                    return Ok(AddResponse("users[].quota must be > 0".to_owned()));
+                }
+                if let Some(quota) = user.quota {
+                    user_quotas.insert(user.name.clone(), quota);
+                }
+
                 let user = match ServerUser::with_encoded_key(&user.name, &user.password) {
                     Ok(u) => u,
                     Err(..) => {
@@ -528,10 +748,7 @@ impl Manager {
                             user.password
                         );
 
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            "users[].password must be encoded with base64",
-                        ));
+                        return Err(io::Error::other("users[].password must be encoded with base64"));
                     }
                 };
 
@@ -541,12 +758,50 @@ impl Manager {
             svr_cfg.set_user_manager(user_manager);
         }
 
-        self.add_server(svr_cfg).await;
+        if req.max_connections == Some(0) || req.udp_max_associations == Some(0) {
+            return Ok(AddResponse(
+                "max_connections and udp_max_associations must be > 0".to_owned(),
+            ));
+        }
+
+        if req.upload_kbps == Some(0) || req.download_kbps == Some(0) {
+            return Ok(AddResponse("upload_kbps and download_kbps must be > 0".to_owned()));
+        }
+
+        if req.quota == Some(0) {
+            return Ok(AddResponse("quota must be > 0".to_owned()));
+        }
+
+        if req.quota_reset_day.is_some_and(|d| !(1..=28).contains(&d)) {
+            return Ok(AddResponse("quota_reset_day must be in 1 - 28".to_owned()));
+        }
+
+        let rate_limit = if req.upload_kbps.is_some() || req.download_kbps.is_some() {
+            Some(RateLimitConfig {
+                upload_kbps: req.upload_kbps,
+                download_kbps: req.download_kbps,
+            })
+        } else {
+            None
+        };
+
+        let limits = ServerLimits {
+            max_connections: req.max_connections,
+            udp_max_associations: req.udp_max_associations,
+            rate_limit,
+            user_rate_limits,
+            quota: QuotaConfig {
+                quota: req.quota,
+                user_quotas,
+                reset_day: req.quota_reset_day,
+            },
+        };
+        self.add_server_with_limits(svr_cfg, limits).await;
 
         Ok(AddResponse("ok".to_owned()))
     }
 
-    async fn handle_remove(&self, req: &RemoveRequest) -> RemoveResponse {
+    pub(super) async fn handle_remove(&self, req: &RemoveRequest) -> RemoveResponse {
         let mut servers = self.servers.lock().await;
         servers.remove(&req.server_port);
 
@@ -558,7 +813,7 @@ impl Manager {
         RemoveResponse("ok".to_owned())
     }
 
-    async fn handle_list(&self) -> ListResponse {
+    pub(super) async fn handle_list(&self) -> ListResponse {
         let instances = self.servers.lock().await;
 
         let mut servers = Vec::new();
@@ -571,9 +826,13 @@ impl Manager {
                 let mut vu = Vec::with_capacity(user_manager.user_count());
 
                 for user in user_manager.users_iter() {
+                    let limit = server.limits.user_rate_limits.get(user.name());
                     vu.push(ServerUserConfig {
                         name: user.name().to_owned(),
                         password: user.encoded_key(),
+                        upload_kbps: limit.and_then(|l| l.upload_kbps),
+                        download_kbps: limit.and_then(|l| l.download_kbps),
+                        quota: server.limits.quota.user_quotas.get(user.name()).copied(),
                     });
                 }
 
@@ -590,6 +849,12 @@ impl Manager {
                 plugin_mode: None,
                 mode: None,
                 users,
+                max_connections: server.limits.max_connections,
+                udp_max_associations: server.limits.udp_max_associations,
+                upload_kbps: server.limits.rate_limit.and_then(|l| l.upload_kbps),
+                download_kbps: server.limits.rate_limit.and_then(|l| l.download_kbps),
+                quota: server.limits.quota.quota,
+                quota_reset_day: server.limits.quota.reset_day,
             };
             servers.push(sc);
         }
@@ -597,7 +862,7 @@ impl Manager {
         ListResponse { servers }
     }
 
-    async fn handle_ping(&self) -> PingResponse {
+    pub(super) async fn handle_ping(&self) -> PingResponse {
         let instances = self.servers.lock().await;
 
         let mut stat = HashMap::new();
@@ -608,6 +873,89 @@ impl Manager {
         PingResponse { stat }
     }
 
+    pub(super) async fn handle_load(&self) -> LoadResponse {
+        let instances = self.servers.lock().await;
+
+        let mut load = HashMap::new();
+        for (port, server) in instances.iter() {
+            // Standalone servers are running in other processes
+            if let Some(l) = server.load_stat() {
+                load.insert(*port, l);
+            }
+        }
+
+        LoadResponse { load }
+    }
+
+    pub(super) async fn handle_users(&self) -> UsersResponse {
+        let instances = self.servers.lock().await;
+
+        let mut users = HashMap::new();
+        for (port, server) in instances.iter() {
+            // Only servers with `users` (AEAD-2022 EIH), and standalone servers are running in other processes
+            if server.svr_cfg.user_manager().is_none() {
+                continue;
+            }
+            if let Some(u) = server.user_flow_stat() {
+                users.insert(*port, u);
+            }
+        }
+
+        UsersResponse { users }
+    }
+
+    pub(super) async fn handle_quota(&self) -> QuotaResponse {
+        let instances = self.servers.lock().await;
+
+        let mut quota = HashMap::new();
+        for (port, server) in instances.iter() {
+            if server.limits.quota.is_empty() {
+                continue;
+            }
+            // Standalone servers are running in other processes
+            if let Some(q) = server.quota() {
+                quota.insert(*port, q);
+            }
+        }
+
+        QuotaResponse { quota }
+    }
+
+    pub(super) async fn handle_reset_quota(&self, req: &ResetQuotaRequest) -> ResetQuotaResponse {
+        let instances = self.servers.lock().await;
+
+        let (traffic_quota, user_traffic_quotas) = match instances.get(&req.server_port).map(|s| &s.mode) {
+            Some(ServerInstanceMode::Builtin {
+                traffic_quota,
+                user_traffic_quotas,
+                ..
+            }) => (traffic_quota, user_traffic_quotas),
+            _ => return ResetQuotaResponse(format!("no builtin server on port {}", req.server_port)),
+        };
+
+        match req.user {
+            Some(ref name) => match user_traffic_quotas.get(name) {
+                Some(quota) => quota.reset(),
+                None => return ResetQuotaResponse(format!("user \"{name}\" has no quota")),
+            },
+            None => {
+                if let Some(quota) = traffic_quota {
+                    quota.reset();
+                }
+                for quota in user_traffic_quotas.values() {
+                    quota.reset();
+                }
+            }
+        }
+
+        info!(
+            "reset quota of server_port: {}, user: {}",
+            req.server_port,
+            req.user.as_deref().unwrap_or("*")
+        );
+        ResetQuotaResponse("ok".to_owned())
+    }
+
     #[cfg(not(unix))]
     async fn handle_stat(&self, _: &StatRequest) {}
 
@@ -676,10 +1024,18 @@ impl Manager {
                             }
 
                             let svr_cfg = config.server[0].config.clone();
+                            let limits = ServerLimits {
+                                max_connections: config.server[0].max_connections,
+                                udp_max_associations: config.udp_max_associations,
+                                rate_limit: config.server[0].rate_limit,
+                                user_rate_limits: config.server[0].user_rate_limits.clone(),
+                                quota: config.server[0].quota.clone(),
+                            };
 
                             vac.insert(ServerInstance {
                                 mode: ServerInstanceMode::Standalone { flow_stat: *flow },
                                 svr_cfg,
+                                limits,
                             });
                         }
                     }
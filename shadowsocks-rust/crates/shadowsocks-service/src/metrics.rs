@@ -0,0 +1,182 @@
+//! Prometheus metrics exporter
+//!
+//! Serves `GET /metrics` in the Prometheus text exposition format, for monitoring with standard tooling.
+//! Metrics are collected from statistics of the service when they are scraped.
+
+use std::{
+    convert::Infallible,
+    fmt::{Display, Write},
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use http_body_util::Full;
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{self, HeaderValue},
+    server::conn::http1,
+    service, Method, Request, Response, StatusCode,
+};
+use log::{debug, error, info, trace};
+use tokio::{net::TcpListener, time};
+
+use crate::net::tokio_rt::TokioIo;
+
+/// Path of the metrics endpoint
+const METRICS_PATH: &str = "/metrics";
+
+/// Content type of the text exposition format
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Type of a metric family
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricType {
+    /// Value that only increases
+    Counter,
+    /// Value that goes up and down
+    Gauge,
+}
+
+impl MetricType {
+    fn as_str(self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+        }
+    }
+}
+
+/// Writer of metrics in the text exposition format
+#[derive(Debug, Default)]
+pub struct MetricsWriter {
+    buffer: String,
+    family: String,
+}
+
+impl MetricsWriter {
+    /// Create an empty writer
+    pub fn new() -> MetricsWriter {
+        MetricsWriter::default()
+    }
+
+    /// Start a metric family, samples written after it belong to it
+    pub fn family(&mut self, name: &str, kind: MetricType, help: &str) {
+        let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+        let _ = writeln!(self.buffer, "# HELP {name} {help}");
+        let _ = writeln!(self.buffer, "# TYPE {name} {}", kind.as_str());
+        name.clone_into(&mut self.family);
+    }
+
+    /// Write a sample of the current family
+    pub fn sample<V: Display>(&mut self, labels: &[(&str, &str)], value: V) {
+        self.buffer.push_str(&self.family);
+        if !labels.is_empty() {
+            self.buffer.push('{');
+            for (i, (name, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.buffer.push(',');
+                }
+                let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+                let _ = write!(self.buffer, "{name}=\"{value}\"");
+            }
+            self.buffer.push('}');
+        }
+        let _ = writeln!(self.buffer, " {value}");
+    }
+
+    /// Consume and get the written metrics
+    pub fn into_string(self) -> String {
+        self.buffer
+    }
+}
+
+/// Source of metrics, collected on every scrape
+#[async_trait]
+pub trait MetricsCollector: Send + Sync {
+    /// Write all metrics into `writer`
+    async fn collect(&self, writer: &mut MetricsWriter);
+}
+
+/// Prometheus metrics exporter
+pub struct MetricsServer {
+    listener: TcpListener,
+    collector: Arc<dyn MetricsCollector>,
+}
+
+impl MetricsServer {
+    /// Bind the listener, serving metrics of `collector`
+    pub async fn bind(addr: SocketAddr, collector: Arc<dyn MetricsCollector>) -> io::Result<MetricsServer> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(MetricsServer { listener, collector })
+    }
+
+    /// Get server local address
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Start serving
+    pub async fn run(self) -> io::Result<()> {
+        info!(
+            "shadowsocks metrics listening on http://{}{}",
+            self.listener.local_addr()?,
+            METRICS_PATH
+        );
+
+        loop {
+            let (stream, peer_addr) = match self.listener.accept().await {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("metrics accept failed with error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            trace!("metrics accepted client {}", peer_addr);
+
+            let collector = self.collector.clone();
+            tokio::spawn(async move {
+                let result = http1::Builder::new()
+                    .serve_connection(
+                        TokioIo::new(stream),
+                        service::service_fn(move |req| {
+                            let collector = collector.clone();
+                            async move { Ok::<_, Infallible>(serve_request(collector.as_ref(), req).await) }
+                        }),
+                    )
+                    .await;
+
+                if let Err(err) = result {
+                    debug!("metrics connection {} failed, error: {}", peer_addr, err);
+                }
+            });
+        }
+    }
+}
+
+async fn serve_request(collector: &dyn MetricsCollector, req: Request<Incoming>) -> Response<Full<Bytes>> {
+    if req.uri().path() != METRICS_PATH {
+        return make_response(StatusCode::NOT_FOUND, Bytes::new());
+    }
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return make_response(StatusCode::METHOD_NOT_ALLOWED, Bytes::new());
+    }
+
+    let mut writer = MetricsWriter::new();
+    collector.collect(&mut writer).await;
+
+    let mut rsp = make_response(StatusCode::OK, writer.into_string());
+    rsp.headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(METRICS_CONTENT_TYPE));
+    rsp
+}
+
+fn make_response<B: Into<Bytes>>(status: StatusCode, body: B) -> Response<Full<Bytes>> {
+    let mut rsp = Response::new(Full::new(body.into()));
+    *rsp.status_mut() = status;
+    rsp
+}
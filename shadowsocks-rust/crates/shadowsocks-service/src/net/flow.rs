@@ -1,6 +1,11 @@
 //! Server flow statistic
 
-use std::sync::atomic::Ordering;
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, Arc},
+};
+
+use spin::Mutex as SpinMutex;
 
 #[cfg(target_has_atomic = "64")]
 type FlowCounter = std::sync::atomic::AtomicU64;
@@ -8,9 +13,11 @@ type FlowCounter = std::sync::atomic::AtomicU64;
 type FlowCounter = std::sync::atomic::AtomicU32;
 
 /// Connection flow statistic
+#[derive(Debug)]
 pub struct FlowStat {
     tx: FlowCounter,
     rx: FlowCounter,
+    parent: Option<Arc<FlowStat>>,
 }
 
 impl Default for FlowStat {
@@ -18,6 +25,7 @@ impl Default for FlowStat {
         FlowStat {
             tx: FlowCounter::new(0),
             rx: FlowCounter::new(0),
+            parent: None,
         }
     }
 }
@@ -28,6 +36,14 @@ impl FlowStat {
         FlowStat::default()
     }
 
+    /// Create an empty flow statistic, which also counts into `parent`
+    pub fn with_parent(parent: Arc<FlowStat>) -> FlowStat {
+        FlowStat {
+            parent: Some(parent),
+            ..FlowStat::default()
+        }
+    }
+
     /// Transmitted bytes count
     pub fn tx(&self) -> u64 {
         self.tx.load(Ordering::Relaxed) as _
@@ -36,6 +52,9 @@ impl FlowStat {
     /// Increase transmitted bytes
     pub fn incr_tx(&self, n: u64) {
         self.tx.fetch_add(n as _, Ordering::AcqRel);
+        if let Some(ref parent) = self.parent {
+            parent.incr_tx(n);
+        }
     }
 
     /// Received bytes count
@@ -46,5 +65,43 @@ impl FlowStat {
     /// Increase received bytes
     pub fn incr_rx(&self, n: u64) {
         self.rx.fetch_add(n as _, Ordering::AcqRel);
+        if let Some(ref parent) = self.parent {
+            parent.incr_rx(n);
+        }
+    }
+}
+
+/// Flow statistics of users of a multi-user (AEAD-2022 EIH) server
+#[derive(Debug)]
+pub struct UserFlowStat {
+    parent: Arc<FlowStat>,
+    users: SpinMutex<HashMap<String, Arc<FlowStat>>>,
+}
+
+impl UserFlowStat {
+    /// Create empty flow statistics of users, which also count into `parent`
+    pub fn new(parent: Arc<FlowStat>) -> UserFlowStat {
+        UserFlowStat {
+            parent,
+            users: SpinMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get flow statistic of user `name`, created on its first use
+    pub fn user(&self, name: &str) -> Arc<FlowStat> {
+        let mut users = self.users.lock();
+        if let Some(stat) = users.get(name) {
+            return stat.clone();
+        }
+
+        let stat = Arc::new(FlowStat::with_parent(self.parent.clone()));
+        users.insert(name.to_owned(), stat.clone());
+        stat
+    }
+
+    /// Flow statistics of users that have been used
+    pub fn users(&self) -> Vec<(String, Arc<FlowStat>)> {
+        let users = self.users.lock();
+        users.iter().map(|(name, stat)| (name.clone(), stat.clone())).collect()
     }
 }
@@ -1,6 +1,10 @@
 //! Shadowsocks Service Network Utilities
 
-pub use self::{flow::FlowStat, mon_socket::MonProxySocket, mon_stream::MonProxyStream};
+pub use self::{
+    flow::{FlowStat, UserFlowStat},
+    mon_socket::MonProxySocket,
+    mon_stream::MonProxyStream,
+};
 
 pub mod flow;
 #[cfg(target_os = "macos")]
@@ -8,6 +12,12 @@ pub mod launch_activate_socket;
 pub mod mon_socket;
 pub mod mon_stream;
 pub mod packet_window;
+pub mod rate_limit;
+pub mod reverse_tunnel;
+pub mod tcp_bind;
+#[cfg(any(feature = "local-http", feature = "manager-http", feature = "metrics"))]
+#[cfg_attr(not(feature = "local-http"), allow(dead_code))]
+pub(crate) mod tokio_rt;
 pub mod utils;
 
 /// Packet size for all UDP associations' send queue
@@ -8,18 +8,49 @@ use shadowsocks::{
 };
 use tokio::net::ToSocketAddrs;
 
-use super::flow::FlowStat;
+use super::flow::{FlowStat, UserFlowStat};
 
 /// Monitored `ProxySocket`
 pub struct MonProxySocket {
     socket: ProxySocket,
     flow_stat: Arc<FlowStat>,
+    user_flow_stat: Option<Arc<UserFlowStat>>,
 }
 
 impl MonProxySocket {
     /// Create a new socket with flow monitor
     pub fn from_socket(socket: ProxySocket, flow_stat: Arc<FlowStat>) -> MonProxySocket {
-        MonProxySocket { socket, flow_stat }
+        MonProxySocket {
+            socket,
+            flow_stat,
+            user_flow_stat: None,
+        }
+    }
+
+    /// Count flows of packets with authenticated users into `user_flow_stat`
+    pub fn set_user_flow_stat(&mut self, user_flow_stat: Arc<UserFlowStat>) {
+        self.user_flow_stat = Some(user_flow_stat);
+    }
+
+    fn control_flow_stat(&self, control: Option<&UdpSocketControlData>) -> Option<Arc<FlowStat>> {
+        match (&self.user_flow_stat, control.and_then(|c| c.user.as_ref())) {
+            (Some(user_flow_stat), Some(user)) => Some(user_flow_stat.user(user.name())),
+            _ => None,
+        }
+    }
+
+    fn incr_tx_with_ctrl(&self, control: Option<&UdpSocketControlData>, n: u64) {
+        match self.control_flow_stat(control) {
+            Some(flow_stat) => flow_stat.incr_tx(n),
+            None => self.flow_stat.incr_tx(n),
+        }
+    }
+
+    fn incr_rx_with_ctrl(&self, control: Option<&UdpSocketControlData>, n: u64) {
+        match self.control_flow_stat(control) {
+            Some(flow_stat) => flow_stat.incr_rx(n),
+            None => self.flow_stat.incr_rx(n),
+        }
     }
 
     /// Send a UDP packet to addr through proxy
@@ -40,7 +71,7 @@ impl MonProxySocket {
         payload: &[u8],
     ) -> io::Result<()> {
         let n = self.socket.send_with_ctrl(addr, control, payload).await?;
-        self.flow_stat.incr_tx(n as u64);
+        self.incr_tx_with_ctrl(Some(control), n as u64);
 
         Ok(())
     }
@@ -64,7 +95,7 @@ impl MonProxySocket {
         payload: &[u8],
     ) -> io::Result<()> {
         let n = self.socket.send_to_with_ctrl(target, addr, control, payload).await?;
-        self.flow_stat.incr_tx(n as u64);
+        self.incr_tx_with_ctrl(Some(control), n as u64);
 
         Ok(())
     }
@@ -93,7 +124,7 @@ impl MonProxySocket {
         recv_buf: &mut [u8],
     ) -> io::Result<(usize, Address, Option<UdpSocketControlData>)> {
         let (n, addr, recv_n, control) = self.socket.recv_with_ctrl(recv_buf).await?;
-        self.flow_stat.incr_rx(recv_n as u64);
+        self.incr_rx_with_ctrl(control.as_ref(), recv_n as u64);
 
         Ok((n, addr, control))
     }
@@ -122,7 +153,7 @@ impl MonProxySocket {
         recv_buf: &mut [u8],
     ) -> io::Result<(usize, SocketAddr, Address, Option<UdpSocketControlData>)> {
         let (n, peer_addr, addr, recv_n, control) = self.socket.recv_from_with_ctrl(recv_buf).await?;
-        self.flow_stat.incr_rx(recv_n as u64);
+        self.incr_rx_with_ctrl(control.as_ref(), recv_n as u64);
 
         Ok((n, peer_addr, addr, control))
     }
@@ -26,6 +26,12 @@ impl<S> MonProxyStream<S> {
         MonProxyStream { stream, flow_stat }
     }
 
+    /// Count flows into `flow_stat` from now on, such as the user's statistic after it is authenticated
+    #[inline]
+    pub fn set_flow_stat(&mut self, flow_stat: Arc<FlowStat>) {
+        self.flow_stat = flow_stat;
+    }
+
     #[inline]
     pub fn get_ref(&self) -> &S {
         &self.stream
@@ -0,0 +1,260 @@
+//! Bandwidth limits with token buckets
+//!
+//! `RateLimiter` is a hard limit of bandwidth shared by a set of streams and associations, e.g. all connections of a
+//! SOCKS5 user, a server port, or a user of a multi-user server. Streams wait for tokens, UDP packets are dropped if
+//! there are no tokens left.
+
+use std::{
+    future::Future,
+    io::{self, IoSlice},
+    pin::Pin,
+    sync::Arc,
+    task::{self, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::ready;
+use spin::Mutex as SpinMutex;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{self, Sleep},
+};
+
+/// Tokens could be saved up to this long of the bandwidth
+const BUCKET_MAX_BURST: Duration = Duration::from_millis(100);
+
+/// Tokens could be owed up to this long of the bandwidth
+const BUCKET_MAX_DEBT: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+struct TokenBucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Bandwidth of one direction
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    /// Bytes per second
+    rate: f64,
+    state: SpinMutex<TokenBucketState>,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(kbps: u64) -> TokenBucket {
+        let rate = kbps as f64 * 1000.0 / 8.0;
+        TokenBucket {
+            rate,
+            state: SpinMutex::new(TokenBucketState {
+                tokens: rate * BUCKET_MAX_BURST.as_secs_f64(),
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    fn refill(&self, state: &mut TokenBucketState) {
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(state.last_refill);
+        state.tokens =
+            (state.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate * BUCKET_MAX_BURST.as_secs_f64());
+        state.last_refill = now;
+    }
+
+    /// Time to wait until tokens are paid back, `None` if there are tokens left
+    pub(crate) fn delay(&self) -> Option<Duration> {
+        let mut state = self.state.lock();
+        self.refill(&mut state);
+        if state.tokens > 0.0 {
+            None
+        } else {
+            Some(Duration::from_secs_f64(-state.tokens / self.rate).max(Duration::from_millis(1)))
+        }
+    }
+
+    pub(crate) fn consume(&self, n: usize) {
+        let mut state = self.state.lock();
+        self.refill(&mut state);
+        state.tokens = (state.tokens - n as f64).max(-self.rate * BUCKET_MAX_DEBT.as_secs_f64());
+    }
+}
+
+/// Waits until all `buckets` have tokens left
+pub(crate) fn poll_buckets_ready<'a, I>(
+    buckets: I,
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    cx: &mut task::Context<'_>,
+) -> Poll<()>
+where
+    I: Iterator<Item = &'a TokenBucket> + Clone,
+{
+    loop {
+        if let Some(ref mut d) = *delay {
+            ready!(d.as_mut().poll(cx));
+            *delay = None;
+        }
+
+        match buckets.clone().filter_map(TokenBucket::delay).max() {
+            None => return Poll::Ready(()),
+            Some(d) => *delay = Some(Box::pin(time::sleep(d))),
+        }
+    }
+}
+
+/// Bandwidth limit shared by streams
+#[derive(Debug)]
+pub struct RateLimiter {
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    /// Create with bandwidth in kbit/s, direction is not limited if `None`
+    pub fn new(upload_kbps: Option<u64>, download_kbps: Option<u64>) -> RateLimiter {
+        RateLimiter {
+            upload: upload_kbps.map(TokenBucket::new),
+            download: download_kbps.map(TokenBucket::new),
+        }
+    }
+
+    /// Limit `stream` of the client. Data read from it is uploaded, data written to it is downloaded
+    pub fn limit<S>(self: &Arc<Self>, stream: S) -> RateLimitedStream<S> {
+        let mut stream = RateLimitedStream::new(stream);
+        stream.add_limiter(self.clone());
+        stream
+    }
+
+    /// Check if data could be uploaded now
+    pub fn is_upload_ready(&self) -> bool {
+        self.upload.as_ref().map_or(true, |b| b.delay().is_none())
+    }
+
+    /// Check if data could be downloaded now
+    pub fn is_download_ready(&self) -> bool {
+        self.download.as_ref().map_or(true, |b| b.delay().is_none())
+    }
+
+    /// Data uploaded from the client
+    pub fn uploaded(&self, n: usize) {
+        if let Some(ref bucket) = self.upload {
+            bucket.consume(n);
+        }
+    }
+
+    /// Data downloaded to the client
+    pub fn downloaded(&self, n: usize) {
+        if let Some(ref bucket) = self.download {
+            bucket.consume(n);
+        }
+    }
+}
+
+/// A client stream limited by a set of `RateLimiter`s, data waits for all of them
+pub struct RateLimitedStream<S> {
+    stream: S,
+    limiters: Vec<Arc<RateLimiter>>,
+    upload_delay: Option<Pin<Box<Sleep>>>,
+    download_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> RateLimitedStream<S> {
+    /// Create a stream without limits
+    pub fn new(stream: S) -> RateLimitedStream<S> {
+        RateLimitedStream {
+            stream,
+            limiters: Vec::new(),
+            upload_delay: None,
+            download_delay: None,
+        }
+    }
+
+    /// Limit the stream by `limiter` too
+    pub fn add_limiter(&mut self, limiter: Arc<RateLimiter>) {
+        self.limiters.push(limiter);
+    }
+
+    /// Get reference of the inner stream
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Get mutable reference of the inner stream
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Consume and get the inner stream
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    fn poll_upload_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<()> {
+        let buckets = self.limiters.iter().filter_map(|l| l.upload.as_ref());
+        poll_buckets_ready(buckets, &mut self.upload_delay, cx)
+    }
+
+    fn poll_download_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<()> {
+        let buckets = self.limiters.iter().filter_map(|l| l.download.as_ref());
+        poll_buckets_ready(buckets, &mut self.download_delay, cx)
+    }
+}
+
+impl<S> AsyncRead for RateLimitedStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_upload_ready(cx));
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+        let n = buf.filled().len() - filled;
+        for limiter in &this.limiters {
+            limiter.uploaded(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for RateLimitedStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_download_ready(cx));
+
+        let n = ready!(Pin::new(&mut this.stream).poll_write(cx, buf))?;
+        for limiter in &this.limiters {
+            limiter.downloaded(n);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_download_ready(cx));
+
+        let n = ready!(Pin::new(&mut this.stream).poll_write_vectored(cx, bufs))?;
+        for limiter in &this.limiters {
+            limiter.downloaded(n);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
@@ -0,0 +1,45 @@
+//! Reverse tunnels through shadowsocks servers
+//!
+//! Local servers open a control connection to the reserved target `REVERSE_TUNNEL_DOMAIN`, whose port is the port
+//! to listen on the server (0 for any port). Servers that allow it (`reverse_tunnel`) reply the address they are
+//! listening on, and then an 8-byte token for each inbound connection. Token 0 is a heartbeat.
+//!
+//! For each token, local servers open a data connection to `REVERSE_TUNNEL_ACCEPT_DOMAIN` and send the token first.
+//! Servers reply the address of the inbound connection, and the stream relays it from then on. The listener is
+//! closed with the control connection.
+
+use std::time::Duration;
+
+use shadowsocks::relay::Address;
+
+/// Reserved target of control connections, `.invalid` never resolves
+pub const REVERSE_TUNNEL_DOMAIN: &str = "reverse.shadowsocks.invalid";
+
+/// Reserved target of data connections
+pub const REVERSE_TUNNEL_ACCEPT_DOMAIN: &str = "accept.reverse.shadowsocks.invalid";
+
+/// Interval of heartbeats sent by servers on idle control connections
+pub const REVERSE_TUNNEL_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Maximum time for inbound connections to be claimed by data connections
+pub const REVERSE_TUNNEL_ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Target of a control connection, listening on `port` of the server
+pub fn make_reverse_tunnel_addr(port: u16) -> Address {
+    Address::DomainNameAddress(REVERSE_TUNNEL_DOMAIN.to_owned(), port)
+}
+
+/// Target of a data connection
+pub fn make_reverse_tunnel_accept_addr() -> Address {
+    Address::DomainNameAddress(REVERSE_TUNNEL_ACCEPT_DOMAIN.to_owned(), 0)
+}
+
+/// Check if target `addr` is a control connection
+pub fn is_reverse_tunnel_addr(addr: &Address) -> bool {
+    matches!(*addr, Address::DomainNameAddress(ref dname, _) if dname.eq_ignore_ascii_case(REVERSE_TUNNEL_DOMAIN))
+}
+
+/// Check if target `addr` is a data connection
+pub fn is_reverse_tunnel_accept_addr(addr: &Address) -> bool {
+    matches!(*addr, Address::DomainNameAddress(ref dname, _) if dname.eq_ignore_ascii_case(REVERSE_TUNNEL_ACCEPT_DOMAIN))
+}
@@ -0,0 +1,236 @@
+//! SOCKS5 BIND through shadowsocks servers
+//!
+//! Local servers request a BIND by connecting to a target under the reserved domain `TCP_BIND_DOMAIN`. The labels
+//! before it are DST.ADDR of the BIND request (`:` of IPv6 addresses replaced by `-`), and the port is DST.PORT.
+//! Servers that allow it (`tcp_bind`) listen on a new port, and reply 2 addresses at the beginning of the response
+//! stream:
+//!
+//! 1. The address it is listening on
+//! 2. The address of the accepted inbound connection
+//!
+//! Inbound connections not from DST.ADDR and DST.PORT are closed, as RFC 1928 requires. An unspecified DST.ADDR or
+//! a zero DST.PORT matches any. After that, the stream relays the inbound connection. Servers that don't allow it
+//! close the connection.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use log::debug;
+use shadowsocks::relay::Address;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Reserved target of BIND requests, `.invalid` never resolves
+pub const TCP_BIND_DOMAIN: &str = "bind.shadowsocks.invalid";
+
+/// Maximum time to wait for the inbound connection of a BIND
+pub const TCP_BIND_ACCEPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Target of a BIND request, `expected` is the address that the peer is expected to connect from
+pub fn make_tcp_bind_addr(expected: &Address) -> io::Result<Address> {
+    let host = match *expected {
+        Address::SocketAddress(sa) if sa.ip().is_unspecified() => None,
+        Address::SocketAddress(sa) => Some(sa.ip().to_string().replace(':', "-")),
+        Address::DomainNameAddress(ref dname, _) => Some(dname.trim_end_matches('.').to_owned()),
+    };
+
+    let dname = match host {
+        None => TCP_BIND_DOMAIN.to_owned(),
+        Some(host) => format!("{host}.{TCP_BIND_DOMAIN}"),
+    };
+    if dname.len() > u8::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("BIND address {expected} is too long"),
+        ));
+    }
+
+    Ok(Address::DomainNameAddress(dname, expected.port()))
+}
+
+/// Check if target `addr` is a BIND request
+pub fn is_tcp_bind_addr(addr: &Address) -> bool {
+    matches!(*addr, Address::DomainNameAddress(ref dname, _) if tcp_bind_host(dname).is_some())
+}
+
+/// DST.ADDR in a BIND request domain, empty if it is not specified
+fn tcp_bind_host(dname: &str) -> Option<&str> {
+    if dname.eq_ignore_ascii_case(TCP_BIND_DOMAIN) {
+        return Some("");
+    }
+
+    let split = dname.len().checked_sub(TCP_BIND_DOMAIN.len() + 1)?;
+    if !dname.is_char_boundary(split) {
+        return None;
+    }
+    let (host, suffix) = dname.split_at(split);
+    if !suffix.starts_with('.') || !suffix[1..].eq_ignore_ascii_case(TCP_BIND_DOMAIN) || host.is_empty() {
+        return None;
+    }
+    Some(host)
+}
+
+/// Address that the peer of BIND request `addr` is expected to connect from
+///
+/// `None` for addresses that are not BIND requests. IP addresses are left unspecified if the request doesn't have
+/// one.
+pub fn tcp_bind_expected_peer(addr: &Address) -> Option<Address> {
+    let (dname, port) = match *addr {
+        Address::DomainNameAddress(ref dname, port) => (dname, port),
+        Address::SocketAddress(..) => return None,
+    };
+    let host = tcp_bind_host(dname)?;
+
+    if host.is_empty() {
+        return Some(Address::SocketAddress(SocketAddr::new(
+            Ipv4Addr::UNSPECIFIED.into(),
+            port,
+        )));
+    }
+    if let Ok(ip) = host.replace('-', ":").parse::<IpAddr>() {
+        return Some(Address::SocketAddress(SocketAddr::new(ip, port)));
+    }
+    Some(Address::DomainNameAddress(host.to_owned(), port))
+}
+
+/// Check if `peer` connected from one of `ips` (any if unspecified) and `port` (any if 0)
+pub fn is_expected_tcp_bind_peer(ips: &[IpAddr], port: u16, peer: SocketAddr) -> bool {
+    if port != 0 && peer.port() != port {
+        return false;
+    }
+
+    let peer_ip = to_canonical_ip(peer.ip());
+    ips.iter()
+        .any(|ip| ip.is_unspecified() || to_canonical_ip(*ip) == peer_ip)
+}
+
+fn to_canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        IpAddr::V4(..) => ip,
+    }
+}
+
+/// Accept the first inbound connection from one of `ips` and `port`, others are closed
+pub async fn accept_tcp_bind_peer(
+    listener: &TcpListener,
+    ips: &[IpAddr],
+    port: u16,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        if is_expected_tcp_bind_peer(ips, port, peer_addr) {
+            return Ok((stream, peer_addr));
+        }
+
+        debug!(
+            "BIND on {} rejected unexpected peer {}",
+            listener.local_addr()?,
+            peer_addr
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv6Addr;
+
+    use tokio::{io::AsyncReadExt, net::TcpSocket};
+
+    use super::*;
+
+    #[test]
+    fn test_tcp_bind_addr_ipv4() {
+        let expected = Address::SocketAddress(SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).into(), 2121));
+        let addr = make_tcp_bind_addr(&expected).unwrap();
+        assert!(is_tcp_bind_addr(&addr));
+        assert_eq!(tcp_bind_expected_peer(&addr), Some(expected));
+    }
+
+    #[test]
+    fn test_tcp_bind_addr_ipv6() {
+        let expected = Address::SocketAddress(SocketAddr::new("2001:db8::1".parse::<Ipv6Addr>().unwrap().into(), 0));
+        let addr = make_tcp_bind_addr(&expected).unwrap();
+        assert_eq!(
+            addr,
+            Address::DomainNameAddress("2001-db8--1.bind.shadowsocks.invalid".to_owned(), 0)
+        );
+        assert_eq!(tcp_bind_expected_peer(&addr), Some(expected));
+    }
+
+    #[test]
+    fn test_tcp_bind_addr_unspecified_and_domain() {
+        let expected = Address::SocketAddress(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 20));
+        let addr = make_tcp_bind_addr(&expected).unwrap();
+        assert_eq!(addr, Address::DomainNameAddress(TCP_BIND_DOMAIN.to_owned(), 20));
+        assert_eq!(tcp_bind_expected_peer(&addr), Some(expected));
+
+        let expected = Address::DomainNameAddress("ftp.example.com".to_owned(), 20);
+        let addr = make_tcp_bind_addr(&expected).unwrap();
+        assert_eq!(tcp_bind_expected_peer(&addr), Some(expected));
+
+        let expected = Address::DomainNameAddress("a".repeat(240), 20);
+        assert!(make_tcp_bind_addr(&expected).is_err());
+    }
+
+    #[test]
+    fn test_not_tcp_bind_addr() {
+        for dname in ["example.com", "xbind.shadowsocks.invalid", ".bind.shadowsocks.invalid"] {
+            let addr = Address::DomainNameAddress(dname.to_owned(), 80);
+            assert!(!is_tcp_bind_addr(&addr), "{dname}");
+            assert_eq!(tcp_bind_expected_peer(&addr), None);
+        }
+        let addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 80));
+        assert!(!is_tcp_bind_addr(&addr));
+    }
+
+    #[test]
+    fn test_expected_tcp_bind_peer() {
+        let ip = IpAddr::from(Ipv4Addr::new(192, 0, 2, 1));
+        let peer = SocketAddr::new(ip, 2020);
+
+        assert!(is_expected_tcp_bind_peer(&[ip], 2020, peer));
+        assert!(is_expected_tcp_bind_peer(&[ip], 0, peer));
+        assert!(is_expected_tcp_bind_peer(&[Ipv4Addr::UNSPECIFIED.into()], 2020, peer));
+        assert!(!is_expected_tcp_bind_peer(&[], 0, peer));
+        assert!(!is_expected_tcp_bind_peer(&[ip], 2021, peer));
+        assert!(!is_expected_tcp_bind_peer(
+            &[Ipv4Addr::new(192, 0, 2, 2).into()],
+            0,
+            peer
+        ));
+
+        // Dual-stack listeners accept IPv4 peers with mapped addresses
+        let mapped = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped().into(), 2020);
+        assert!(is_expected_tcp_bind_peer(&[ip], 2020, mapped));
+    }
+
+    #[tokio::test]
+    async fn test_accept_tcp_bind_peer_rejects_unexpected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+
+        let expected = TcpSocket::new_v4().unwrap();
+        expected.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let expected_addr = expected.local_addr().unwrap();
+
+        // Connects first from another port
+        let mut unexpected = TcpStream::connect(listen_addr).await.unwrap();
+        let expected = expected.connect(listen_addr).await.unwrap();
+
+        let (_, peer_addr) = accept_tcp_bind_peer(&listener, &[expected_addr.ip()], expected_addr.port())
+            .await
+            .unwrap();
+        assert_eq!(peer_addr, expected_addr);
+        assert_eq!(peer_addr, expected.local_addr().unwrap());
+
+        // Closed by the listener
+        let mut buf = [0u8; 1];
+        assert_eq!(unexpected.read(&mut buf).await.unwrap(), 0);
+    }
+}
@@ -24,6 +24,14 @@ where
     Ok(())
 }
 
+/// Compares in constant time, not leaking secrets like passwords and tokens by timing
+///
+/// Only the length of `a` and `b` may be leaked.
+#[cfg(any(feature = "local-http", feature = "manager-http"))]
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 /// Helper function for converting IPv4 mapped IPv6 address
 ///
 /// This is the same as `Ipv6Addr::to_ipv4_mapped`, but it is still unstable in the current libstd
@@ -1,16 +1,22 @@
 //! Shadowsocks Local Server Context
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
 use shadowsocks::{
     config::ServerType,
     context::{Context, SharedContext},
     dns_resolver::DnsResolver,
-    net::ConnectOpts,
+    net::{AddrFamily, ConnectOpts},
     relay::Address,
 };
 
-use crate::{acl::AccessControl, config::SecurityConfig, net::FlowStat};
+use crate::{
+    acl::AccessControl,
+    config::SecurityConfig,
+    net::{rate_limit::RateLimiter, FlowStat, UserFlowStat},
+};
+
+use super::{load::LoadStat, peer_limit::PeerLimit, quota::TrafficQuota, reverse_tunnel::ReverseTunnelInbounds};
 
 /// Server Service Context
 #[derive(Clone)]
@@ -23,15 +29,56 @@ pub struct ServiceContext {
 
     // Flow statistic report
     flow_stat: Arc<FlowStat>,
+
+    // Flow statistic report of users, counted into `flow_stat` too
+    user_flow_stat: Arc<UserFlowStat>,
+
+    // Saturation statistic report
+    load_stat: Arc<LoadStat>,
+
+    // Bandwidth limits of the server and its users
+    rate_limiter: Option<Arc<RateLimiter>>,
+    user_rate_limiters: HashMap<String, Arc<RateLimiter>>,
+
+    // Traffic quotas of the server and its users
+    traffic_quota: Option<Arc<TrafficQuota>>,
+    user_traffic_quotas: HashMap<String, Arc<TrafficQuota>>,
+
+    // ACLs of users, checked besides `acl` for their outbound addresses
+    user_acls: HashMap<String, Arc<AccessControl>>,
+
+    // Concurrent TCP connections and UDP associations of each client IP
+    tcp_peer_limit: Option<Arc<PeerLimit>>,
+    udp_peer_limit: Option<Arc<PeerLimit>>,
+
+    // Allow SOCKS5 BIND
+    tcp_bind: bool,
+
+    // Allow reverse tunnels, and their inbound connections waiting to be claimed
+    reverse_tunnel: bool,
+    reverse_tunnel_inbounds: Arc<ReverseTunnelInbounds>,
 }
 
 impl Default for ServiceContext {
     fn default() -> Self {
+        let flow_stat = Arc::new(FlowStat::new());
         ServiceContext {
             context: Context::new_shared(ServerType::Server),
             connect_opts: ConnectOpts::default(),
             acl: None,
-            flow_stat: Arc::new(FlowStat::new()),
+            user_flow_stat: Arc::new(UserFlowStat::new(flow_stat.clone())),
+            flow_stat,
+            load_stat: Arc::new(LoadStat::new()),
+            rate_limiter: None,
+            user_rate_limiters: HashMap::new(),
+            traffic_quota: None,
+            user_traffic_quotas: HashMap::new(),
+            user_acls: HashMap::new(),
+            tcp_peer_limit: None,
+            udp_peer_limit: None,
+            tcp_bind: false,
+            reverse_tunnel: false,
+            reverse_tunnel_inbounds: Arc::new(ReverseTunnelInbounds::default()),
         }
     }
 }
@@ -72,6 +119,11 @@ impl ServiceContext {
         self.acl.as_deref()
     }
 
+    /// Set Access Control List of user `name`
+    pub fn set_user_acl(&mut self, name: String, acl: Arc<AccessControl>) {
+        self.user_acls.insert(name, acl);
+    }
+
     /// Get cloned flow statistic
     pub fn flow_stat(&self) -> Arc<FlowStat> {
         self.flow_stat.clone()
@@ -82,6 +134,98 @@ impl ServiceContext {
         self.flow_stat.as_ref()
     }
 
+    /// Get cloned flow statistic of users
+    pub fn user_flow_stat(&self) -> Arc<UserFlowStat> {
+        self.user_flow_stat.clone()
+    }
+
+    /// Get flow statistic of users reference
+    pub fn user_flow_stat_ref(&self) -> &UserFlowStat {
+        self.user_flow_stat.as_ref()
+    }
+
+    /// Get cloned load statistic
+    pub fn load_stat(&self) -> Arc<LoadStat> {
+        self.load_stat.clone()
+    }
+
+    /// Get load statistic reference
+    pub fn load_stat_ref(&self) -> &LoadStat {
+        self.load_stat.as_ref()
+    }
+
+    /// Set bandwidth limit shared by all clients
+    pub fn set_rate_limiter(&mut self, limiter: Arc<RateLimiter>) {
+        self.rate_limiter = Some(limiter);
+    }
+
+    /// Get bandwidth limit shared by all clients
+    pub fn rate_limiter(&self) -> Option<&Arc<RateLimiter>> {
+        self.rate_limiter.as_ref()
+    }
+
+    /// Set bandwidth limit shared by all connections of user `name`
+    pub fn set_user_rate_limiter(&mut self, name: String, limiter: Arc<RateLimiter>) {
+        self.user_rate_limiters.insert(name, limiter);
+    }
+
+    /// Get bandwidth limit of user `name`
+    pub fn user_rate_limiter(&self, name: &str) -> Option<&Arc<RateLimiter>> {
+        self.user_rate_limiters.get(name)
+    }
+
+    /// Set traffic quota shared by all clients
+    pub fn set_traffic_quota(&mut self, quota: Arc<TrafficQuota>) {
+        self.traffic_quota = Some(quota);
+    }
+
+    /// Get traffic quota shared by all clients
+    pub fn traffic_quota(&self) -> Option<&Arc<TrafficQuota>> {
+        self.traffic_quota.as_ref()
+    }
+
+    /// Set traffic quota of user `name`
+    pub fn set_user_traffic_quota(&mut self, name: String, quota: Arc<TrafficQuota>) {
+        self.user_traffic_quotas.insert(name, quota);
+    }
+
+    /// Get traffic quotas of users
+    pub fn user_traffic_quotas(&self) -> &HashMap<String, Arc<TrafficQuota>> {
+        &self.user_traffic_quotas
+    }
+
+    /// Check if new sessions of the server, or of user `user_name`, should be rejected by traffic quotas
+    pub fn check_quota_exceeded(&self, user_name: Option<&str>) -> bool {
+        if self.traffic_quota.as_ref().is_some_and(|q| q.is_exceeded()) {
+            return true;
+        }
+
+        match user_name.and_then(|name| self.user_traffic_quotas.get(name)) {
+            None => false,
+            Some(quota) => quota.is_exceeded(),
+        }
+    }
+
+    /// Set limit of concurrent TCP connections of each client IP
+    pub fn set_tcp_peer_limit(&mut self, limit: Arc<PeerLimit>) {
+        self.tcp_peer_limit = Some(limit);
+    }
+
+    /// Get limit of concurrent TCP connections of each client IP
+    pub fn tcp_peer_limit(&self) -> Option<&Arc<PeerLimit>> {
+        self.tcp_peer_limit.as_ref()
+    }
+
+    /// Set limit of UDP associations of each client IP
+    pub fn set_udp_peer_limit(&mut self, limit: Arc<PeerLimit>) {
+        self.udp_peer_limit = Some(limit);
+    }
+
+    /// Get limit of UDP associations of each client IP
+    pub fn udp_peer_limit(&self) -> Option<&Arc<PeerLimit>> {
+        self.udp_peer_limit.as_ref()
+    }
+
     /// Set customized DNS resolver
     pub fn set_dns_resolver(&mut self, resolver: Arc<DnsResolver>) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set DNS resolver on a shared context");
@@ -93,11 +237,17 @@ impl ServiceContext {
         self.context.dns_resolver()
     }
 
-    /// Check if target should be bypassed
-    pub async fn check_outbound_blocked(&self, addr: &Address) -> bool {
-        match self.acl {
+    /// Check if target should be blocked, by the server's ACL and the ACL of user `user_name`
+    pub async fn check_outbound_blocked(&self, addr: &Address, user_name: Option<&str>) -> bool {
+        if let Some(ref acl) = self.acl {
+            if acl.check_outbound_blocked(&self.context, addr).await {
+                return true;
+            }
+        }
+
+        match user_name.and_then(|name| self.user_acls.get(name)) {
             None => false,
-            Some(ref acl) => acl.check_outbound_blocked(&self.context, addr).await,
+            Some(acl) => acl.check_outbound_blocked(&self.context, addr).await,
         }
     }
 
@@ -115,6 +265,37 @@ impl ServiceContext {
         context.set_ipv6_first(ipv6_first);
     }
 
+    /// Only connect addresses of `family`, the other family is disabled
+    pub fn set_addr_family(&mut self, family: AddrFamily) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set addr_family on a shared context");
+        context.set_addr_family(family);
+    }
+
+    /// Allow clients to BIND listening ports on this server
+    pub fn set_tcp_bind(&mut self, tcp_bind: bool) {
+        self.tcp_bind = tcp_bind;
+    }
+
+    /// Check if clients are allowed to BIND listening ports on this server
+    pub fn tcp_bind(&self) -> bool {
+        self.tcp_bind
+    }
+
+    /// Allow clients to listen on ports of this server with reverse tunnels
+    pub fn set_reverse_tunnel(&mut self, reverse_tunnel: bool) {
+        self.reverse_tunnel = reverse_tunnel;
+    }
+
+    /// Check if clients are allowed to listen on ports of this server with reverse tunnels
+    pub fn reverse_tunnel(&self) -> bool {
+        self.reverse_tunnel
+    }
+
+    /// Inbound connections of reverse tunnels, waiting to be claimed by clients
+    pub(crate) fn reverse_tunnel_inbounds(&self) -> &ReverseTunnelInbounds {
+        &self.reverse_tunnel_inbounds
+    }
+
     /// Set security config
     pub fn set_security_config(&mut self, security: &SecurityConfig) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set security on a shared context");
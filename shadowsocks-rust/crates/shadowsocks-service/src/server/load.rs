@@ -0,0 +1,123 @@
+//! Server load statistic
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Saturation statistic of a server
+#[derive(Debug, Default)]
+pub struct LoadStat {
+    tcp_connections: AtomicUsize,
+    tcp_accepted: AtomicUsize,
+    tcp_rejected: AtomicUsize,
+    tcp_handshake_failures: AtomicUsize,
+    tcp_connect_failures: AtomicUsize,
+    udp_associations: AtomicUsize,
+    udp_associated: AtomicUsize,
+    udp_evicted: AtomicUsize,
+    udp_recv_failures: AtomicUsize,
+}
+
+impl LoadStat {
+    /// Create an empty load statistic
+    pub fn new() -> LoadStat {
+        LoadStat::default()
+    }
+
+    /// Active TCP connections
+    pub fn tcp_connections(&self) -> usize {
+        self.tcp_connections.load(Ordering::Relaxed)
+    }
+
+    /// TCP connections accepted since the server started
+    pub fn tcp_accepted(&self) -> usize {
+        self.tcp_accepted.load(Ordering::Relaxed)
+    }
+
+    /// TCP connections rejected by the connection limits
+    pub fn tcp_rejected(&self) -> usize {
+        self.tcp_rejected.load(Ordering::Relaxed)
+    }
+
+    /// TCP connections closed by invalid handshakes
+    pub fn tcp_handshake_failures(&self) -> usize {
+        self.tcp_handshake_failures.load(Ordering::Relaxed)
+    }
+
+    /// TCP connections closed by failing to connect their targets
+    pub fn tcp_connect_failures(&self) -> usize {
+        self.tcp_connect_failures.load(Ordering::Relaxed)
+    }
+
+    /// Active UDP associations
+    pub fn udp_associations(&self) -> usize {
+        self.udp_associations.load(Ordering::Relaxed)
+    }
+
+    /// UDP associations created since the server started
+    pub fn udp_associated(&self) -> usize {
+        self.udp_associated.load(Ordering::Relaxed)
+    }
+
+    /// UDP associations evicted by the association table's capacity or the limit of each client IP
+    pub fn udp_evicted(&self) -> usize {
+        self.udp_evicted.load(Ordering::Relaxed)
+    }
+
+    /// UDP packets that could not be received, including packets failed to decrypt
+    pub fn udp_recv_failures(&self) -> usize {
+        self.udp_recv_failures.load(Ordering::Relaxed)
+    }
+
+    /// Count an active TCP connection until the returned guard is dropped
+    pub(crate) fn tcp_connection(self: &Arc<Self>) -> TcpConnectionGuard {
+        self.tcp_connections.fetch_add(1, Ordering::Relaxed);
+        self.tcp_accepted.fetch_add(1, Ordering::Relaxed);
+        TcpConnectionGuard { stat: self.clone() }
+    }
+
+    pub(crate) fn incr_tcp_rejected(&self) {
+        self.tcp_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn incr_tcp_handshake_failures(&self) {
+        self.tcp_handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn incr_tcp_connect_failures(&self) {
+        self.tcp_connect_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Update size of an association table from `old` to `new`, servers listening on many ports have many tables
+    pub(crate) fn update_udp_associations(&self, old: usize, new: usize) {
+        if new >= old {
+            self.udp_associations.fetch_add(new - old, Ordering::Relaxed);
+        } else {
+            self.udp_associations.fetch_sub(old - new, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn incr_udp_associated(&self) {
+        self.udp_associated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn incr_udp_evicted(&self) {
+        self.udp_evicted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn incr_udp_recv_failures(&self) {
+        self.udp_recv_failures.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// An active TCP connection of `LoadStat`
+pub(crate) struct TcpConnectionGuard {
+    stat: Arc<LoadStat>,
+}
+
+impl Drop for TcpConnectionGuard {
+    fn drop(&mut self) {
+        self.stat.tcp_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,153 @@
+//! Prometheus metrics of servers
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::{
+    metrics::{MetricType, MetricsCollector, MetricsWriter},
+    net::{FlowStat, UserFlowStat},
+};
+
+use super::{load::LoadStat, server::Server};
+
+type LoadMetric = (&'static str, MetricType, &'static str, fn(&LoadStat) -> usize);
+
+const LOAD_METRICS: [LoadMetric; 9] = [
+    (
+        "shadowsocks_server_tcp_connections",
+        MetricType::Gauge,
+        "Active TCP connections",
+        LoadStat::tcp_connections,
+    ),
+    (
+        "shadowsocks_server_tcp_connections_total",
+        MetricType::Counter,
+        "TCP connections accepted",
+        LoadStat::tcp_accepted,
+    ),
+    (
+        "shadowsocks_server_tcp_rejected_total",
+        MetricType::Counter,
+        "TCP connections rejected by max_connections or max_connections_per_ip",
+        LoadStat::tcp_rejected,
+    ),
+    (
+        "shadowsocks_server_tcp_handshake_errors_total",
+        MetricType::Counter,
+        "TCP connections closed by invalid handshakes",
+        LoadStat::tcp_handshake_failures,
+    ),
+    (
+        "shadowsocks_server_tcp_connect_errors_total",
+        MetricType::Counter,
+        "TCP connections closed by failing to connect their targets",
+        LoadStat::tcp_connect_failures,
+    ),
+    (
+        "shadowsocks_server_udp_associations",
+        MetricType::Gauge,
+        "Active UDP associations",
+        LoadStat::udp_associations,
+    ),
+    (
+        "shadowsocks_server_udp_associations_total",
+        MetricType::Counter,
+        "UDP associations created",
+        LoadStat::udp_associated,
+    ),
+    (
+        "shadowsocks_server_udp_evicted_total",
+        MetricType::Counter,
+        "UDP associations evicted by udp_max_associations or udp_max_associations_per_ip",
+        LoadStat::udp_evicted,
+    ),
+    (
+        "shadowsocks_server_udp_recv_errors_total",
+        MetricType::Counter,
+        "UDP packets failed to receive or decrypt",
+        LoadStat::udp_recv_failures,
+    ),
+];
+
+struct ServerStats {
+    addr: String,
+    flow_stat: Arc<FlowStat>,
+    user_flow_stat: Arc<UserFlowStat>,
+    load_stat: Arc<LoadStat>,
+}
+
+/// Metrics of server instances, labeled by their addresses
+#[derive(Default)]
+pub struct ServerMetrics {
+    servers: Vec<ServerStats>,
+}
+
+impl ServerMetrics {
+    /// Create metrics without servers
+    pub fn new() -> ServerMetrics {
+        ServerMetrics::default()
+    }
+
+    /// Collect metrics of `server`
+    pub fn add_server(&mut self, server: &Server) {
+        self.servers.push(ServerStats {
+            addr: server.server_config().addr().to_string(),
+            flow_stat: server.flow_stat(),
+            user_flow_stat: server.user_flow_stat(),
+            load_stat: server.load_stat(),
+        });
+    }
+}
+
+#[async_trait]
+impl MetricsCollector for ServerMetrics {
+    async fn collect(&self, writer: &mut MetricsWriter) {
+        writer.family(
+            "shadowsocks_server_tx_bytes_total",
+            MetricType::Counter,
+            "Bytes sent to clients",
+        );
+        for server in &self.servers {
+            writer.sample(&[("server", &server.addr)], server.flow_stat.tx());
+        }
+
+        writer.family(
+            "shadowsocks_server_rx_bytes_total",
+            MetricType::Counter,
+            "Bytes received from clients",
+        );
+        for server in &self.servers {
+            writer.sample(&[("server", &server.addr)], server.flow_stat.rx());
+        }
+
+        writer.family(
+            "shadowsocks_server_user_tx_bytes_total",
+            MetricType::Counter,
+            "Bytes sent to clients of users of multi-user servers",
+        );
+        for server in &self.servers {
+            for (user, stat) in server.user_flow_stat.users() {
+                writer.sample(&[("server", &server.addr), ("user", &user)], stat.tx());
+            }
+        }
+
+        writer.family(
+            "shadowsocks_server_user_rx_bytes_total",
+            MetricType::Counter,
+            "Bytes received from clients of users of multi-user servers",
+        );
+        for server in &self.servers {
+            for (user, stat) in server.user_flow_stat.users() {
+                writer.sample(&[("server", &server.addr), ("user", &user)], stat.rx());
+            }
+        }
+
+        for (name, kind, help, value) in LOAD_METRICS {
+            writer.family(name, kind, help);
+            for server in &self.servers {
+                writer.sample(&[("server", &server.addr)], value(&server.load_stat));
+            }
+        }
+    }
+}
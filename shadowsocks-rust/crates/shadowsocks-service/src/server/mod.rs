@@ -2,7 +2,7 @@
 
 use std::{
     future::Future,
-    io::{self, ErrorKind},
+    io::{self},
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
@@ -19,13 +19,26 @@ use crate::{
     dns::build_dns_resolver,
 };
 
+#[cfg(feature = "metrics")]
+use crate::metrics::MetricsServer;
+
+#[cfg(feature = "metrics")]
+pub use self::metrics::ServerMetrics;
 pub use self::{
+    load::LoadStat,
+    quota::TrafficQuota,
     server::{Server, ServerBuilder},
     tcprelay::TcpServer,
     udprelay::UdpServer,
 };
 
 pub mod context;
+mod load;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod peer_limit;
+mod quota;
+mod reverse_tunnel;
 #[allow(clippy::module_inception)]
 pub mod server;
 mod tcprelay;
@@ -70,6 +83,8 @@ pub async fn run(config: Config) -> io::Result<()> {
 
         #[cfg(target_os = "android")]
         vpn_protect_path: config.outbound_vpn_protect_path,
+        #[cfg(target_os = "android")]
+        vpn_socket_protect: config.outbound_vpn_socket_protect,
 
         bind_local_addr: config.outbound_bind_addr,
         bind_interface: config.outbound_bind_interface,
@@ -130,6 +145,36 @@ pub async fn run(config: Config) -> io::Result<()> {
         if let Some(c) = config.udp_max_associations {
             server_builder.set_udp_capacity(c);
         }
+        if let Some(c) = inst.max_connections {
+            server_builder.set_tcp_max_connections(c);
+        }
+        if let Some(c) = inst.max_connections_per_ip {
+            server_builder.set_tcp_max_connections_per_ip(c);
+        }
+        if let Some(c) = inst.udp_max_associations_per_ip {
+            server_builder.set_udp_max_associations_per_ip(c);
+        }
+        if let Some(port_range) = inst.port_range {
+            server_builder.set_port_range(port_range);
+        }
+        if let Some(limit) = inst.rate_limit {
+            server_builder.set_rate_limit(limit);
+        }
+        for (name, limit) in inst.user_rate_limits {
+            server_builder.set_user_rate_limit(name, limit);
+        }
+        for (name, acl) in inst.user_acls {
+            server_builder.set_user_acl(name, Arc::new(acl));
+        }
+        if !inst.quota.is_empty() {
+            server_builder.set_quota(&inst.quota);
+        }
+        if inst.tcp_bind {
+            server_builder.set_tcp_bind(true);
+        }
+        if inst.reverse_tunnel {
+            server_builder.set_reverse_tunnel(true);
+        }
         if let Some(d) = config.udp_timeout {
             server_builder.set_udp_expiry_duration(d);
         }
@@ -150,19 +195,33 @@ pub async fn run(config: Config) -> io::Result<()> {
             server_builder.set_ipv6_first(config.ipv6_first);
         }
 
+        if let Some(family) = config.outbound_addr_family {
+            server_builder.set_addr_family(family);
+        }
+
         server_builder.set_security_config(&config.security);
 
         let server = server_builder.build().await?;
         servers.push(server);
     }
 
-    if servers.len() == 1 {
+    let mut vfut = Vec::with_capacity(servers.len() + 1);
+
+    #[cfg(feature = "metrics")]
+    if let Some(metrics_addr) = config.metrics_addr {
+        let mut metrics = ServerMetrics::new();
+        for server in &servers {
+            metrics.add_server(server);
+        }
+        let metrics_server = MetricsServer::bind(metrics_addr, Arc::new(metrics)).await?;
+        vfut.push(ServerHandle(tokio::spawn(metrics_server.run())));
+    }
+
+    if servers.len() == 1 && vfut.is_empty() {
         let server = servers.pop().unwrap();
         return server.run().await;
     }
 
-    let mut vfut = Vec::with_capacity(servers.len());
-
     for server in servers {
         vfut.push(ServerHandle(tokio::spawn(async move { server.run().await })));
     }
@@ -187,7 +246,7 @@ impl Future for ServerHandle {
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         match ready!(Pin::new(&mut self.0).poll(cx)) {
             Ok(res) => res.into(),
-            Err(err) => Err(io::Error::new(ErrorKind::Other, err)).into(),
+            Err(err) => Err(io::Error::other(err)).into(),
         }
     }
 }
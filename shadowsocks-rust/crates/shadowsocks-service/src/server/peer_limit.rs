@@ -0,0 +1,83 @@
+//! Limits of concurrent sessions of each client IP
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use spin::Mutex as SpinMutex;
+
+use crate::net::utils::to_ipv4_mapped;
+
+/// Limit of concurrent sessions (TCP connections or UDP associations) of each client IP
+#[derive(Debug)]
+pub struct PeerLimit {
+    max: usize,
+    peers: SpinMutex<HashMap<IpAddr, usize>>,
+}
+
+impl PeerLimit {
+    /// Create with maximum sessions of each client IP
+    pub fn new(max: usize) -> PeerLimit {
+        PeerLimit {
+            max,
+            peers: SpinMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Maximum sessions of each client IP
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Admit a new session of `peer_addr`, it is counted until the permit is dropped
+    ///
+    /// Returns `None` if the client IP has already reached the limit
+    pub fn try_acquire(self: &Arc<Self>, peer_addr: &SocketAddr) -> Option<PeerPermit> {
+        let ip = peer_ip(peer_addr);
+
+        let mut peers = self.peers.lock();
+        let sessions = peers.entry(ip).or_insert(0);
+        if *sessions >= self.max {
+            return None;
+        }
+        *sessions += 1;
+
+        Some(PeerPermit {
+            limit: self.clone(),
+            ip,
+        })
+    }
+
+    fn release(&self, ip: &IpAddr) {
+        let mut peers = self.peers.lock();
+        if let Some(sessions) = peers.get_mut(ip) {
+            *sessions = sessions.saturating_sub(1);
+            if *sessions == 0 {
+                peers.remove(ip);
+            }
+        }
+    }
+}
+
+/// An admitted session, which is counted until it is dropped
+#[derive(Debug)]
+pub struct PeerPermit {
+    limit: Arc<PeerLimit>,
+    ip: IpAddr,
+}
+
+impl Drop for PeerPermit {
+    fn drop(&mut self) {
+        self.limit.release(&self.ip);
+    }
+}
+
+/// IPv4 clients of dual-stack listeners are counted by their IPv4 addresses
+pub fn peer_ip(peer_addr: &SocketAddr) -> IpAddr {
+    match peer_addr.ip() {
+        IpAddr::V6(ref v6) => to_ipv4_mapped(v6).map_or(IpAddr::V6(*v6), IpAddr::V4),
+        ip => ip,
+    }
+}
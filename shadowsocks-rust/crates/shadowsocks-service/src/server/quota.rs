@@ -0,0 +1,192 @@
+//! Traffic quota of servers and users
+
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use spin::Mutex as SpinMutex;
+
+use crate::net::FlowStat;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug)]
+struct TrafficQuotaState {
+    /// Traffic of `flow_stat` when this period started
+    baseline: u64,
+    /// UNIX timestamp of the next automatic reset
+    next_reset: Option<u64>,
+}
+
+/// Traffic quota, bytes sent and received counted by a `FlowStat`
+#[derive(Debug)]
+pub struct TrafficQuota {
+    flow_stat: Arc<FlowStat>,
+    quota: u64,
+    reset_day: Option<u8>,
+    state: SpinMutex<TrafficQuotaState>,
+}
+
+impl TrafficQuota {
+    /// Create a quota of `quota` bytes, reset on `reset_day` of every month (UTC)
+    pub fn new(flow_stat: Arc<FlowStat>, quota: u64, reset_day: Option<u8>) -> TrafficQuota {
+        let baseline = flow_stat.tx() + flow_stat.rx();
+        TrafficQuota {
+            flow_stat,
+            quota,
+            reset_day,
+            state: SpinMutex::new(TrafficQuotaState {
+                baseline,
+                next_reset: reset_day.map(|day| next_reset_time(unix_now(), day)),
+            }),
+        }
+    }
+
+    /// Bytes allowed in a period
+    pub fn quota(&self) -> u64 {
+        self.quota
+    }
+
+    /// Bytes sent and received in this period
+    pub fn used(&self) -> u64 {
+        let total = self.flow_stat.tx() + self.flow_stat.rx();
+
+        let mut state = self.state.lock();
+        if let (Some(next_reset), Some(day)) = (state.next_reset, self.reset_day) {
+            let now = unix_now();
+            if now >= next_reset {
+                state.baseline = total;
+                state.next_reset = Some(next_reset_time(now, day));
+            }
+        }
+        total.saturating_sub(state.baseline)
+    }
+
+    /// Check if the quota is used up
+    pub fn is_exceeded(&self) -> bool {
+        self.used() >= self.quota
+    }
+
+    /// Start a new period now
+    pub fn reset(&self) {
+        let total = self.flow_stat.tx() + self.flow_stat.rx();
+        self.state.lock().baseline = total;
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// UNIX timestamp of the next `day` of month after `now`
+fn next_reset_time(now: u64, day: u8) -> u64 {
+    let day = u32::from(day);
+    let (year, month, today) = civil_from_days((now / SECONDS_PER_DAY) as i64);
+    let (year, month) = if today < day {
+        (year, month)
+    } else if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    days_from_civil(year, month, day) as u64 * SECONDS_PER_DAY
+}
+
+// Conversions between days since 1970-01-01 and proleptic Gregorian dates
+// http://howardhinnant.github.io/date_algorithms.html
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    (y, m, d)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = i64::from(if month > 2 { month - 3 } else { month + 9 });
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19722), (2023, 12, 31));
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        // 2100 is not a leap year
+        assert_eq!(civil_from_days(47540), (2100, 2, 28));
+        assert_eq!(civil_from_days(47541), (2100, 3, 1));
+    }
+
+    #[test]
+    fn test_days_from_civil_round_trip() {
+        for days in -800_000..800_000 {
+            let (year, month, day) = civil_from_days(days);
+            assert!((1..=12).contains(&month) && (1..=31).contains(&day), "{days}");
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn test_next_reset_time() {
+        let at = |days: u64| days * SECONDS_PER_DAY;
+
+        // 2024-01-31 01:00 to 2024-02-01
+        assert_eq!(next_reset_time(at(19753) + 3600, 1), at(19754));
+        // 2024-01-14 to 2024-01-15
+        assert_eq!(next_reset_time(at(19736), 15), at(19737));
+        // On the day of reset, to the next month, 2024-01-15 to 2024-02-15
+        assert_eq!(next_reset_time(at(19737), 15), at(19768));
+        assert_eq!(next_reset_time(at(19737) + SECONDS_PER_DAY - 1, 15), at(19768));
+        // 2023-12-20 to 2024-01-01
+        assert_eq!(next_reset_time(at(19711), 1), at(19723));
+        // 2024-02-29 to 2024-03-28
+        assert_eq!(next_reset_time(at(19782), 28), at(19810));
+    }
+
+    #[test]
+    fn test_quota_used_and_reset() {
+        let flow_stat = Arc::new(FlowStat::new());
+        flow_stat.incr_tx(100);
+
+        let quota = TrafficQuota::new(flow_stat.clone(), 1000, Some(1));
+        assert_eq!(quota.used(), 0);
+
+        flow_stat.incr_tx(600);
+        flow_stat.incr_rx(399);
+        assert_eq!(quota.used(), 999);
+        assert!(!quota.is_exceeded());
+        flow_stat.incr_rx(1);
+        assert!(quota.is_exceeded());
+
+        quota.reset();
+        assert_eq!(quota.used(), 0);
+
+        // Reset automatically when the next reset time is reached
+        flow_stat.incr_tx(10);
+        quota.state.lock().next_reset = Some(0);
+        assert_eq!(quota.used(), 0);
+        let next_reset = quota.state.lock().next_reset.unwrap();
+        assert!(next_reset > unix_now());
+    }
+}
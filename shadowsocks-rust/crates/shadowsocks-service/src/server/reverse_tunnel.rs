@@ -0,0 +1,78 @@
+//! Inbound connections of reverse tunnels, waiting to be claimed by data connections of clients
+
+use std::{collections::HashMap, net::SocketAddr, time::Instant};
+
+use spin::Mutex as SpinMutex;
+use tokio::net::TcpStream;
+
+use crate::net::reverse_tunnel::REVERSE_TUNNEL_ACCEPT_TIMEOUT;
+
+/// Maximum inbound connections waiting to be claimed, of all reverse tunnels of a server
+pub const MAX_PENDING_INBOUNDS: usize = 1024;
+
+struct PendingInbound {
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    accepted: Instant,
+}
+
+/// Inbound connections accepted by listeners of reverse tunnels, keyed by random tokens
+#[derive(Default)]
+pub struct ReverseTunnelInbounds {
+    inbounds: SpinMutex<HashMap<u64, PendingInbound>>,
+}
+
+impl ReverseTunnelInbounds {
+    /// Keep an inbound connection until it is claimed with the returned token
+    ///
+    /// Tokens are unguessable, so data connections couldn't claim inbound connections of others' tunnels.
+    /// `None` if there are already `MAX_PENDING_INBOUNDS` connections waiting, and `stream` is closed.
+    pub fn insert(&self, stream: TcpStream, peer_addr: SocketAddr) -> Option<u64> {
+        let now = Instant::now();
+        let mut inbounds = self.inbounds.lock();
+
+        if inbounds.len() >= MAX_PENDING_INBOUNDS {
+            Self::retain_unexpired(&mut inbounds, now);
+            if inbounds.len() >= MAX_PENDING_INBOUNDS {
+                return None;
+            }
+        }
+
+        loop {
+            let token = rand::random::<u64>();
+            // 0 is heartbeat
+            if token == 0 || inbounds.contains_key(&token) {
+                continue;
+            }
+
+            inbounds.insert(
+                token,
+                PendingInbound {
+                    stream,
+                    peer_addr,
+                    accepted: now,
+                },
+            );
+            return Some(token);
+        }
+    }
+
+    /// Claim the inbound connection of `token`
+    pub fn take(&self, token: u64) -> Option<(TcpStream, SocketAddr)> {
+        let inbound = self.inbounds.lock().remove(&token)?;
+        if inbound.accepted.elapsed() >= REVERSE_TUNNEL_ACCEPT_TIMEOUT {
+            return None;
+        }
+        Some((inbound.stream, inbound.peer_addr))
+    }
+
+    /// Close inbound connections that are not claimed in time
+    pub fn cleanup_expired(&self) {
+        let mut inbounds = self.inbounds.lock();
+        Self::retain_unexpired(&mut inbounds, Instant::now());
+    }
+
+    fn retain_unexpired(inbounds: &mut HashMap<u64, PendingInbound>, now: Instant) {
+        inbounds.retain(|_, inbound| now.saturating_duration_since(inbound.accepted) < REVERSE_TUNNEL_ACCEPT_TIMEOUT);
+    }
+}
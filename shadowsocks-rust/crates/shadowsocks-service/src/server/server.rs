@@ -2,7 +2,9 @@
 
 use std::{
     collections::HashMap,
-    io::{self, ErrorKind},
+    io::{self},
+    net::SocketAddr,
+    ops::RangeInclusive,
     sync::Arc,
     time::Duration,
 };
@@ -10,17 +12,24 @@ use std::{
 use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
 use log::{error, trace};
 use shadowsocks::{
-    config::{ManagerAddr, ServerConfig},
+    config::{ManagerAddr, ServerAddr, ServerConfig},
     dns_resolver::DnsResolver,
-    net::{AcceptOpts, ConnectOpts},
+    net::{AcceptOpts, AddrFamily, ConnectOpts},
     plugin::{Plugin, PluginMode},
     ManagerClient,
 };
-use tokio::time;
+use tokio::{sync::Semaphore, time};
 
-use crate::{acl::AccessControl, config::SecurityConfig, net::FlowStat};
+use crate::{
+    acl::AccessControl,
+    config::{QuotaConfig, RateLimitConfig, SecurityConfig},
+    net::{rate_limit::RateLimiter, reverse_tunnel::REVERSE_TUNNEL_ACCEPT_TIMEOUT, FlowStat, UserFlowStat},
+};
 
-use super::{context::ServiceContext, tcprelay::TcpServer, udprelay::UdpServer};
+use super::{
+    context::ServiceContext, load::LoadStat, peer_limit::PeerLimit, quota::TrafficQuota, tcprelay::TcpServer,
+    udprelay::UdpServer,
+};
 
 /// Shadowsocks Server Builder
 pub struct ServerBuilder {
@@ -28,6 +37,8 @@ pub struct ServerBuilder {
     svr_cfg: ServerConfig,
     udp_expiry_duration: Option<Duration>,
     udp_capacity: Option<usize>,
+    tcp_max_connections: Option<usize>,
+    port_range: Option<RangeInclusive<u16>>,
     manager_addr: Option<ManagerAddr>,
     accept_opts: AcceptOpts,
 }
@@ -45,6 +56,8 @@ impl ServerBuilder {
             svr_cfg,
             udp_expiry_duration: None,
             udp_capacity: None,
+            tcp_max_connections: None,
+            port_range: None,
             manager_addr: None,
             accept_opts: AcceptOpts::default(),
         }
@@ -60,6 +73,26 @@ impl ServerBuilder {
         self.context.flow_stat_ref()
     }
 
+    /// Get flow statistic of users
+    pub fn user_flow_stat(&self) -> Arc<UserFlowStat> {
+        self.context.user_flow_stat()
+    }
+
+    /// Get load statistic
+    pub fn load_stat(&self) -> Arc<LoadStat> {
+        self.context.load_stat()
+    }
+
+    /// Get traffic quota shared by all clients
+    pub fn traffic_quota(&self) -> Option<Arc<TrafficQuota>> {
+        self.context.traffic_quota().cloned()
+    }
+
+    /// Get traffic quotas of users
+    pub fn user_traffic_quotas(&self) -> HashMap<String, Arc<TrafficQuota>> {
+        self.context.user_traffic_quotas().clone()
+    }
+
     /// Set `ConnectOpts`
     pub fn set_connect_opts(&mut self, opts: ConnectOpts) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set ConnectOpts on a shared context");
@@ -76,6 +109,77 @@ impl ServerBuilder {
         self.udp_capacity = Some(c);
     }
 
+    /// Set maximum concurrent TCP connections to be served in one server
+    ///
+    /// New connections are closed immediately when it is reached
+    pub fn set_tcp_max_connections(&mut self, c: usize) {
+        self.tcp_max_connections = Some(c);
+    }
+
+    /// Set maximum concurrent TCP connections of each client IP
+    ///
+    /// New connections of the client IP are closed immediately when it is reached
+    pub fn set_tcp_max_connections_per_ip(&mut self, c: usize) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set peer limit on a shared context");
+        context.set_tcp_peer_limit(Arc::new(PeerLimit::new(c)));
+    }
+
+    /// Set maximum UDP associations of each client IP
+    ///
+    /// The least recently active association of the client IP is evicted for a new one when it is reached
+    pub fn set_udp_max_associations_per_ip(&mut self, c: usize) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set peer limit on a shared context");
+        context.set_udp_peer_limit(Arc::new(PeerLimit::new(c)));
+    }
+
+    /// Listen on all ports in `port_range` besides the server's port, sharing its statistics and limits
+    ///
+    /// Except the UDP association capacity (`set_udp_capacity`), each port keeps its own association table of that
+    /// capacity. Not supported with plugins, which only listen on the server's port
+    pub fn set_port_range(&mut self, port_range: RangeInclusive<u16>) {
+        self.port_range = Some(port_range);
+    }
+
+    /// Set bandwidth limit shared by all clients of this server
+    pub fn set_rate_limit(&mut self, limit: RateLimitConfig) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set rate limit on a shared context");
+        context.set_rate_limiter(Arc::new(RateLimiter::new(limit.upload_kbps, limit.download_kbps)));
+    }
+
+    /// Set bandwidth limit shared by all connections of user `name`
+    pub fn set_user_rate_limit(&mut self, name: String, limit: RateLimitConfig) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set rate limit on a shared context");
+        context.set_user_rate_limiter(name, Arc::new(RateLimiter::new(limit.upload_kbps, limit.download_kbps)));
+    }
+
+    /// Set traffic quotas of this server and its users
+    pub fn set_quota(&mut self, config: &QuotaConfig) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set quota on a shared context");
+
+        if let Some(quota) = config.quota {
+            let quota = TrafficQuota::new(context.flow_stat(), quota, config.reset_day);
+            context.set_traffic_quota(Arc::new(quota));
+        }
+
+        for (name, quota) in config.user_quotas.iter() {
+            let flow_stat = context.user_flow_stat_ref().user(name);
+            let quota = TrafficQuota::new(flow_stat, *quota, config.reset_day);
+            context.set_user_traffic_quota(name.clone(), Arc::new(quota));
+        }
+    }
+
+    /// Allow clients to BIND listening ports on this server
+    pub fn set_tcp_bind(&mut self, tcp_bind: bool) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set tcp_bind on a shared context");
+        context.set_tcp_bind(tcp_bind);
+    }
+
+    /// Allow clients to listen on ports of this server with reverse tunnels
+    pub fn set_reverse_tunnel(&mut self, reverse_tunnel: bool) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set reverse_tunnel on a shared context");
+        context.set_reverse_tunnel(reverse_tunnel);
+    }
+
     /// Set manager's address to report `stat`
     pub fn set_manager_addr(&mut self, manager_addr: ManagerAddr) {
         self.manager_addr = Some(manager_addr);
@@ -98,6 +202,12 @@ impl ServerBuilder {
         context.set_acl(acl);
     }
 
+    /// Set access control list of user `name`, checked besides the server's for outbound addresses
+    pub fn set_user_acl(&mut self, name: String, acl: Arc<AccessControl>) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set ACL on a shared context");
+        context.set_user_acl(name, acl);
+    }
+
     /// Set `AcceptOpts` for accepting new connections
     pub fn set_accept_opts(&mut self, opts: AcceptOpts) {
         self.accept_opts = opts;
@@ -109,6 +219,12 @@ impl ServerBuilder {
         context.set_ipv6_first(ipv6_first);
     }
 
+    /// Only connect addresses of `family`, the other family is disabled
+    pub fn set_addr_family(&mut self, family: AddrFamily) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set addr_family on a shared context");
+        context.set_addr_family(family);
+    }
+
     /// Set security config
     pub fn set_security_config(&mut self, security: &SecurityConfig) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set security on a shared context");
@@ -129,9 +245,17 @@ impl ServerBuilder {
             plugin = Some(plugin_process);
         }
 
+        let connection_limit = self.tcp_max_connections.map(|c| Arc::new(Semaphore::new(c)));
+
         let mut tcp_server = None;
         if self.svr_cfg.mode().enable_tcp() {
-            let server = TcpServer::new(self.context.clone(), self.svr_cfg.clone(), self.accept_opts.clone()).await?;
+            let server = TcpServer::new(
+                self.context.clone(),
+                self.svr_cfg.clone(),
+                connection_limit.clone(),
+                self.accept_opts.clone(),
+            )
+            .await?;
             tcp_server = Some(server);
         }
 
@@ -148,11 +272,50 @@ impl ServerBuilder {
             udp_server = Some(server);
         }
 
+        let mut port_range_tcp_servers = Vec::new();
+        let mut port_range_udp_servers = Vec::new();
+        for port in self.port_range.clone().into_iter().flatten() {
+            if port == self.svr_cfg.addr().port() {
+                continue;
+            }
+
+            let mut svr_cfg = self.svr_cfg.clone();
+            svr_cfg.set_addr(match *self.svr_cfg.addr() {
+                ServerAddr::SocketAddr(ref addr) => ServerAddr::SocketAddr(SocketAddr::new(addr.ip(), port)),
+                ServerAddr::DomainName(ref domain, ..) => ServerAddr::DomainName(domain.clone(), port),
+            });
+
+            if svr_cfg.mode().enable_tcp() {
+                let server = TcpServer::new(
+                    self.context.clone(),
+                    svr_cfg.clone(),
+                    connection_limit.clone(),
+                    self.accept_opts.clone(),
+                )
+                .await?;
+                port_range_tcp_servers.push(server);
+            }
+
+            if svr_cfg.mode().enable_udp() {
+                let server = UdpServer::new(
+                    self.context.clone(),
+                    svr_cfg,
+                    self.udp_expiry_duration,
+                    self.udp_capacity,
+                    self.accept_opts.clone(),
+                )
+                .await?;
+                port_range_udp_servers.push(server);
+            }
+        }
+
         Ok(Server {
             context: self.context,
             svr_cfg: self.svr_cfg,
             tcp_server,
             udp_server,
+            port_range_tcp_servers,
+            port_range_udp_servers,
             manager_addr: self.manager_addr,
             plugin,
         })
@@ -165,6 +328,8 @@ pub struct Server {
     svr_cfg: ServerConfig,
     tcp_server: Option<TcpServer>,
     udp_server: Option<UdpServer>,
+    port_range_tcp_servers: Vec<TcpServer>,
+    port_range_udp_servers: Vec<UdpServer>,
     manager_addr: Option<ManagerAddr>,
     plugin: Option<Plugin>,
 }
@@ -175,6 +340,21 @@ impl Server {
         &self.svr_cfg
     }
 
+    /// Get flow statistic
+    pub fn flow_stat(&self) -> Arc<FlowStat> {
+        self.context.flow_stat()
+    }
+
+    /// Get flow statistic of users
+    pub fn user_flow_stat(&self) -> Arc<UserFlowStat> {
+        self.context.user_flow_stat()
+    }
+
+    /// Get load statistic
+    pub fn load_stat(&self) -> Arc<LoadStat> {
+        self.context.load_stat()
+    }
+
     /// Get TCP server instance
     pub fn tcp_server(&self) -> Option<&TcpServer> {
         self.tcp_server.as_ref()
@@ -185,6 +365,16 @@ impl Server {
         self.udp_server.as_ref()
     }
 
+    /// Get TCP server instances of the other ports in the port range
+    pub fn port_range_tcp_servers(&self) -> &[TcpServer] {
+        &self.port_range_tcp_servers
+    }
+
+    /// Get UDP server instances of the other ports in the port range
+    pub fn port_range_udp_servers(&self) -> &[UdpServer] {
+        &self.port_range_udp_servers
+    }
+
     /// Start serving
     pub async fn run(self) -> io::Result<()> {
         let vfut = FuturesUnordered::new();
@@ -215,6 +405,30 @@ impl Server {
             vfut.push(udp_server.run().boxed())
         }
 
+        for tcp_server in self.port_range_tcp_servers {
+            vfut.push(tcp_server.run().boxed());
+        }
+
+        for udp_server in self.port_range_udp_servers {
+            vfut.push(udp_server.run().boxed());
+        }
+
+        if self.context.reverse_tunnel() {
+            // Inbound connections of reverse tunnels are closed if they are not claimed in time,
+            // even if no more connections are accepted
+            let context = self.context.clone();
+            vfut.push(
+                async move {
+                    let mut interval = time::interval(REVERSE_TUNNEL_ACCEPT_TIMEOUT);
+                    loop {
+                        interval.tick().await;
+                        context.reverse_tunnel_inbounds().cleanup_expired();
+                    }
+                }
+                .boxed(),
+            );
+        }
+
         if let Some(manager_addr) = self.manager_addr {
             let manager_fut = async move {
                 loop {
@@ -261,7 +475,7 @@ impl Server {
             error!("servers exited with error: {}", err);
         }
 
-        let err = io::Error::new(ErrorKind::Other, "server exited unexpectedly");
+        let err = io::Error::other("server exited unexpectedly");
         Err(err)
     }
 }
@@ -3,7 +3,7 @@
 use std::{
     future::Future,
     io::{self, ErrorKind},
-    net::SocketAddr,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::Arc,
     time::Duration,
 };
@@ -12,16 +12,26 @@ use log::{debug, error, info, trace, warn};
 use shadowsocks::{
     crypto::CipherKind,
     net::{AcceptOpts, TcpStream as OutboundTcpStream},
-    relay::tcprelay::{utils::copy_encrypted_bidirectional, ProxyServerStream},
+    relay::{
+        tcprelay::{utils::copy_encrypted_bidirectional, ProxyServerStream},
+        Address,
+    },
     ProxyListener, ServerConfig,
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream as TokioTcpStream,
+    net::{TcpListener, TcpStream as TokioTcpStream},
+    sync::Semaphore,
     time,
 };
 
-use crate::net::{utils::ignore_until_end, MonProxyStream};
+use crate::net::{
+    rate_limit::RateLimitedStream,
+    reverse_tunnel::{is_reverse_tunnel_accept_addr, is_reverse_tunnel_addr, REVERSE_TUNNEL_HEARTBEAT_INTERVAL},
+    tcp_bind::{accept_tcp_bind_peer, tcp_bind_expected_peer, TCP_BIND_ACCEPT_TIMEOUT},
+    utils::ignore_until_end,
+    MonProxyStream,
+};
 
 use super::context::ServiceContext;
 
@@ -30,12 +40,14 @@ pub struct TcpServer {
     context: Arc<ServiceContext>,
     svr_cfg: ServerConfig,
     listener: ProxyListener,
+    connection_limit: Option<Arc<Semaphore>>,
 }
 
 impl TcpServer {
     pub(crate) async fn new(
         context: Arc<ServiceContext>,
         svr_cfg: ServerConfig,
+        connection_limit: Option<Arc<Semaphore>>,
         accept_opts: AcceptOpts,
     ) -> io::Result<TcpServer> {
         let listener = ProxyListener::bind_with_opts(context.context(), &svr_cfg, accept_opts).await?;
@@ -43,6 +55,7 @@ impl TcpServer {
             context,
             svr_cfg,
             listener,
+            connection_limit,
         })
     }
 
@@ -66,10 +79,17 @@ impl TcpServer {
 
         loop {
             let flow_stat = self.context.flow_stat();
+            let rate_limiter = self.context.rate_limiter().cloned();
 
             let (local_stream, peer_addr) = match self
                 .listener
-                .accept_map(|s| MonProxyStream::from_stream(s, flow_stat))
+                .accept_map(|s| {
+                    let mut s = RateLimitedStream::new(MonProxyStream::from_stream(s, flow_stat));
+                    if let Some(limiter) = rate_limiter {
+                        s.add_limiter(limiter);
+                    }
+                    s
+                })
                 .await
             {
                 Ok(s) => s,
@@ -85,6 +105,48 @@ impl TcpServer {
                 continue;
             }
 
+            if self.context.check_quota_exceeded(None) {
+                debug!(
+                    "tcp server {} exceeded its quota, rejected {}",
+                    self.svr_cfg.addr(),
+                    peer_addr
+                );
+                continue;
+            }
+
+            let permit = match self.connection_limit {
+                None => None,
+                Some(ref limit) => match limit.clone().try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(..) => {
+                        debug!(
+                            "tcp server {} reached max_connections, rejected {}",
+                            self.svr_cfg.addr(),
+                            peer_addr
+                        );
+                        self.context.load_stat_ref().incr_tcp_rejected();
+                        continue;
+                    }
+                },
+            };
+            let peer_permit = match self.context.tcp_peer_limit() {
+                None => None,
+                Some(limit) => match limit.try_acquire(&peer_addr) {
+                    Some(permit) => Some(permit),
+                    None => {
+                        debug!(
+                            "tcp server {} reached max_connections_per_ip ({}), rejected {}",
+                            self.svr_cfg.addr(),
+                            limit.max(),
+                            peer_addr
+                        );
+                        self.context.load_stat_ref().incr_tcp_rejected();
+                        continue;
+                    }
+                },
+            };
+            let connection = self.context.load_stat().tcp_connection();
+
             let client = TcpServerClient {
                 context: self.context.clone(),
                 method: self.svr_cfg.method(),
@@ -94,6 +156,10 @@ impl TcpServer {
             };
 
             tokio::spawn(async move {
+                let _permit = permit;
+                let _peer_permit = peer_permit;
+                let _connection = connection;
+
                 if let Err(err) = client.serve().await {
                     debug!("tcp server stream aborted with error: {}", err);
                 }
@@ -120,7 +186,7 @@ struct TcpServerClient {
     context: Arc<ServiceContext>,
     method: CipherKind,
     peer_addr: SocketAddr,
-    stream: ProxyServerStream<MonProxyStream<TokioTcpStream>>,
+    stream: ProxyServerStream<RateLimitedStream<MonProxyStream<TokioTcpStream>>>,
     timeout: Option<Duration>,
 }
 
@@ -155,13 +221,14 @@ impl TcpServerClient {
                 //
                 // Keep connection open. Except AEAD-2022
                 warn!("tcp handshake failed. peer: {}, {}", self.peer_addr, err);
+                self.context.load_stat_ref().incr_tcp_handshake_failures();
 
                 #[cfg(feature = "aead-cipher-2022")]
                 if self.method.is_aead_2022() {
                     // Set SO_LINGER(0) for misbehave clients, which will eventually receive RST. (ECONNRESET)
                     // This will also prevent the socket entering TIME_WAIT state.
 
-                    let stream = self.stream.into_inner().into_inner();
+                    let stream = self.stream.into_inner().into_inner().into_inner();
                     let _ = stream.set_linger(Some(Duration::ZERO));
 
                     return Ok(());
@@ -193,7 +260,41 @@ impl TcpServerClient {
             target_addr
         );
 
-        if self.context.check_outbound_blocked(&target_addr).await {
+        if let Some(user) = self.stream.user() {
+            if self.context.check_quota_exceeded(Some(user.name())) {
+                debug!(
+                    "tcp client {} user {} exceeded its quota, rejected",
+                    self.peer_addr,
+                    user.name()
+                );
+                return Ok(());
+            }
+
+            let flow_stat = self.context.user_flow_stat_ref().user(user.name());
+            let rate_limiter = self.context.user_rate_limiter(user.name()).cloned();
+
+            let stream = self.stream.get_mut();
+            stream.get_mut().set_flow_stat(flow_stat);
+            if let Some(limiter) = rate_limiter {
+                stream.add_limiter(limiter);
+            }
+        }
+
+        if let Some(expected_peer) = tcp_bind_expected_peer(&target_addr) {
+            return self.serve_bind(expected_peer).await;
+        }
+        if is_reverse_tunnel_addr(&target_addr) {
+            return self.serve_reverse_tunnel(target_addr).await;
+        }
+        if is_reverse_tunnel_accept_addr(&target_addr) {
+            return self.serve_reverse_tunnel_accept().await;
+        }
+
+        if self
+            .context
+            .check_outbound_blocked(&target_addr, self.stream.user().map(|u| u.name()))
+            .await
+        {
             error!(
                 "tcp client {} outbound {} blocked by ACL rules",
                 self.peer_addr, target_addr
@@ -217,6 +318,7 @@ impl TcpServerClient {
                     "tcp tunnel {} -> {} connect failed, error: {}",
                     self.peer_addr, target_addr, err
                 );
+                self.context.load_stat_ref().incr_tcp_connect_failures();
                 return Err(err);
             }
         };
@@ -280,4 +382,250 @@ impl TcpServerClient {
 
         Ok(())
     }
+
+    /// Listen on the address that client connected to, which should be reachable from the peer too
+    fn inbound_bind_ip(&self) -> io::Result<IpAddr> {
+        let local_addr = self.stream.get_ref().get_ref().get_ref().local_addr()?;
+        Ok(match self.context.connect_opts_ref().bind_local_addr {
+            Some(ip) => ip,
+            // Connected from a plugin
+            None if local_addr.ip().is_loopback() => match local_addr {
+                SocketAddr::V4(..) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                SocketAddr::V6(..) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            },
+            None => local_addr.ip(),
+        })
+    }
+
+    async fn serve_bind(mut self, expected_peer: Address) -> io::Result<()> {
+        if !self.context.tcp_bind() {
+            error!("tcp client {} BIND rejected, tcp_bind is not enabled", self.peer_addr);
+            return Ok(());
+        }
+
+        // Peers are only accepted from DST.ADDR and DST.PORT of the BIND request
+        let (expected_ips, expected_port) = match expected_peer {
+            Address::SocketAddress(sa) => (vec![sa.ip()], sa.port()),
+            Address::DomainNameAddress(dname, port) => match self.context.context().dns_resolve(&dname, port).await {
+                Ok(addrs) => (addrs.map(|a| a.ip()).collect::<Vec<_>>(), port),
+                Err(err) => {
+                    error!(
+                        "tcp client {} BIND peer {}:{} resolve failed, error: {}",
+                        self.peer_addr, dname, port, err
+                    );
+                    return Ok(());
+                }
+            },
+        };
+
+        let bind_ip = self.inbound_bind_ip()?;
+        let listener = TcpListener::bind(SocketAddr::new(bind_ip, 0)).await?;
+        let bind_addr = listener.local_addr()?;
+
+        debug!(
+            "tcp client {} BIND listening on {}, expecting peer {:?} port {}",
+            self.peer_addr, bind_addr, expected_ips, expected_port
+        );
+
+        timeout_fut(
+            self.timeout,
+            Address::SocketAddress(bind_addr).write_to(&mut self.stream),
+        )
+        .await?;
+
+        // Clients shouldn't send anything before the peer is connected
+        let mut buffer = [0u8; 1];
+        let (mut peer_stream, peer_addr) = tokio::select! {
+            r = time::timeout(TCP_BIND_ACCEPT_TIMEOUT, accept_tcp_bind_peer(&listener, &expected_ips, expected_port)) => match r {
+                Ok(Ok(s)) => s,
+                Ok(Err(err)) => return Err(err),
+                Err(..) => {
+                    debug!("tcp client {} BIND on {} timed out", self.peer_addr, bind_addr);
+                    return Ok(());
+                }
+            },
+            _ = self.stream.read(&mut buffer) => {
+                trace!("tcp client {} BIND on {} closed before peer connected", self.peer_addr, bind_addr);
+                return Ok(());
+            }
+        };
+        drop(listener);
+
+        let peer_addr = Address::SocketAddress(peer_addr);
+        if self
+            .context
+            .check_outbound_blocked(&peer_addr, self.stream.user().map(|u| u.name()))
+            .await
+        {
+            error!(
+                "tcp client {} BIND peer {} blocked by ACL rules",
+                self.peer_addr, peer_addr
+            );
+            return Ok(());
+        }
+
+        timeout_fut(self.timeout, peer_addr.write_to(&mut self.stream)).await?;
+
+        debug!(
+            "established tcp BIND tunnel {} <-> {} on {}",
+            self.peer_addr, peer_addr, bind_addr
+        );
+
+        match copy_encrypted_bidirectional(self.method, &mut self.stream, &mut peer_stream).await {
+            Ok((rn, wn)) => {
+                trace!(
+                    "tcp BIND tunnel {} <-> {} closed, L2R {} bytes, R2L {} bytes",
+                    self.peer_addr,
+                    peer_addr,
+                    rn,
+                    wn
+                );
+            }
+            Err(err) => {
+                trace!(
+                    "tcp BIND tunnel {} <-> {} closed with error: {}",
+                    self.peer_addr,
+                    peer_addr,
+                    err
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn serve_reverse_tunnel(mut self, target_addr: Address) -> io::Result<()> {
+        if !self.context.reverse_tunnel() {
+            error!(
+                "tcp client {} reverse tunnel rejected, reverse_tunnel is not enabled",
+                self.peer_addr
+            );
+            return Ok(());
+        }
+
+        let bind_ip = self.inbound_bind_ip()?;
+        let listener = match TcpListener::bind(SocketAddr::new(bind_ip, target_addr.port())).await {
+            Ok(l) => l,
+            Err(err) => {
+                error!(
+                    "tcp client {} reverse tunnel failed to listen on port {}, error: {}",
+                    self.peer_addr,
+                    target_addr.port(),
+                    err
+                );
+                return Err(err);
+            }
+        };
+        let bind_addr = listener.local_addr()?;
+
+        timeout_fut(
+            self.timeout,
+            Address::SocketAddress(bind_addr).write_to(&mut self.stream),
+        )
+        .await?;
+
+        info!(
+            "tcp client {} reverse tunnel listening on {}",
+            self.peer_addr, bind_addr
+        );
+
+        let mut heartbeat = time::interval_at(
+            time::Instant::now() + REVERSE_TUNNEL_HEARTBEAT_INTERVAL,
+            REVERSE_TUNNEL_HEARTBEAT_INTERVAL,
+        );
+
+        // Clients shouldn't send anything on the control connection
+        let mut buffer = [0u8; 1];
+        loop {
+            let token = tokio::select! {
+                r = listener.accept() => {
+                    let (stream, peer_addr) = match r {
+                        Ok(s) => s,
+                        Err(err) => {
+                            error!("reverse tunnel on {} accept failed with error: {}", bind_addr, err);
+                            time::sleep(Duration::from_secs(1)).await;
+                            continue;
+                        }
+                    };
+
+                    if self.context.check_client_blocked(&peer_addr) {
+                        warn!("reverse tunnel on {} access denied from {} by ACL rules", bind_addr, peer_addr);
+                        continue;
+                    }
+
+                    trace!("reverse tunnel on {} accepted {}", bind_addr, peer_addr);
+                    match self.context.reverse_tunnel_inbounds().insert(stream, peer_addr) {
+                        Some(token) => token,
+                        None => {
+                            warn!(
+                                "reverse tunnel on {} closed {}, too many inbound connections are waiting to be claimed",
+                                bind_addr, peer_addr
+                            );
+                            continue;
+                        }
+                    }
+                }
+                _ = heartbeat.tick() => 0,
+                _ = self.stream.read(&mut buffer) => {
+                    debug!("tcp client {} reverse tunnel on {} closed", self.peer_addr, bind_addr);
+                    return Ok(());
+                }
+            };
+
+            timeout_fut(self.timeout, self.stream.write_all(&token.to_be_bytes())).await?;
+            heartbeat.reset();
+        }
+    }
+
+    async fn serve_reverse_tunnel_accept(mut self) -> io::Result<()> {
+        if !self.context.reverse_tunnel() {
+            error!(
+                "tcp client {} reverse tunnel rejected, reverse_tunnel is not enabled",
+                self.peer_addr
+            );
+            return Ok(());
+        }
+
+        let mut token = [0u8; 8];
+        timeout_fut(self.timeout, self.stream.read_exact(&mut token)).await?;
+        let token = u64::from_be_bytes(token);
+
+        let (mut inbound_stream, inbound_addr) = match self.context.reverse_tunnel_inbounds().take(token) {
+            Some(s) => s,
+            None => {
+                debug!(
+                    "tcp client {} reverse tunnel claimed an expired or unknown inbound connection",
+                    self.peer_addr
+                );
+                return Ok(());
+            }
+        };
+
+        let inbound_addr = Address::SocketAddress(inbound_addr);
+        timeout_fut(self.timeout, inbound_addr.write_to(&mut self.stream)).await?;
+
+        debug!("established tcp reverse tunnel {} <-> {}", self.peer_addr, inbound_addr);
+
+        match copy_encrypted_bidirectional(self.method, &mut self.stream, &mut inbound_stream).await {
+            Ok((rn, wn)) => {
+                trace!(
+                    "tcp reverse tunnel {} <-> {} closed, L2R {} bytes, R2L {} bytes",
+                    self.peer_addr,
+                    inbound_addr,
+                    rn,
+                    wn
+                );
+            }
+            Err(err) => {
+                trace!(
+                    "tcp reverse tunnel {} <-> {} closed with error: {}",
+                    self.peer_addr,
+                    inbound_addr,
+                    err
+                );
+            }
+        }
+
+        Ok(())
+    }
 }
@@ -2,10 +2,10 @@
 
 use std::{
     cell::RefCell,
-    io::{self, ErrorKind},
+    io::{self},
     net::SocketAddr,
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
@@ -33,7 +33,10 @@ use crate::net::{
     UDP_ASSOCIATION_SEND_CHANNEL_SIZE,
 };
 
-use super::context::ServiceContext;
+use super::{
+    context::ServiceContext,
+    peer_limit::{peer_ip, PeerPermit},
+};
 
 #[derive(Debug, Clone, Copy)]
 enum NatKey {
@@ -53,6 +56,14 @@ enum NatMap {
 }
 
 impl NatMap {
+    fn len(&self) -> usize {
+        match *self {
+            NatMap::Association(ref m) => m.len(),
+            #[cfg(feature = "aead-cipher-2022")]
+            NatMap::Session(ref m) => m.len(),
+        }
+    }
+
     fn cleanup_expired(&mut self) {
         match *self {
             NatMap::Association(ref mut m) => {
@@ -66,29 +77,78 @@ impl NatMap {
     }
 
     fn keep_alive(&mut self, key: &NatKey) {
-        match (self, key) {
-            (NatMap::Association(ref mut m), NatKey::PeerAddr(ref peer_addr)) => {
-                m.get(peer_addr);
-            }
+        let assoc = match (self, key) {
+            (NatMap::Association(ref mut m), NatKey::PeerAddr(ref peer_addr)) => m.get_mut(peer_addr),
             #[cfg(feature = "aead-cipher-2022")]
-            (NatMap::Session(ref mut m), NatKey::SessionId(ref session_id)) => {
-                m.get(session_id);
-            }
+            (NatMap::Session(ref mut m), NatKey::SessionId(ref session_id)) => m.get_mut(session_id),
             #[allow(unreachable_patterns)]
             _ => unreachable!("NatMap & NatKey mismatch"),
+        };
+        if let Some(assoc) = assoc {
+            assoc.last_active = Instant::now();
         }
     }
 }
 
+/// Admit a new association of `peer_addr` by `udp_max_associations_per_ip`
+///
+/// The least recently active association of the client IP in `assoc_map` is evicted if the limit is reached
+fn acquire_peer_permit<K>(
+    context: &ServiceContext,
+    assoc_map: &mut LruCache<K, UdpAssociation>,
+    peer_addr: &SocketAddr,
+) -> io::Result<Option<PeerPermit>>
+where
+    K: Ord + Clone,
+{
+    let limit = match context.udp_peer_limit() {
+        None => return Ok(None),
+        Some(limit) => limit,
+    };
+
+    if let Some(permit) = limit.try_acquire(peer_addr) {
+        return Ok(Some(permit));
+    }
+
+    let ip = peer_ip(peer_addr);
+    let least_active = assoc_map
+        .peek_iter()
+        .filter(|(_, assoc)| peer_ip(&assoc.peer_addr) == ip)
+        .min_by_key(|(_, assoc)| assoc.last_active)
+        .map(|(key, _)| key.clone());
+
+    // Associations of the client IP may all be kept by the other ports of the server
+    let key = match least_active {
+        Some(key) => key,
+        None => return Err(io::Error::other("reached udp_max_associations_per_ip")),
+    };
+    if let Some(assoc) = assoc_map.remove(&key) {
+        debug!(
+            "udp association for {} evicted, {} reached udp_max_associations_per_ip ({})",
+            assoc.peer_addr,
+            ip,
+            limit.max()
+        );
+        context.load_stat_ref().incr_udp_evicted();
+    }
+
+    match limit.try_acquire(peer_addr) {
+        Some(permit) => Ok(Some(permit)),
+        None => Err(io::Error::other("reached udp_max_associations_per_ip")),
+    }
+}
+
 /// UDP server instance
 pub struct UdpServer {
     context: Arc<ServiceContext>,
     assoc_map: NatMap,
+    capacity: Option<usize>,
     keepalive_tx: mpsc::Sender<NatKey>,
     keepalive_rx: mpsc::Receiver<NatKey>,
     time_to_live: Duration,
     listener: Arc<MonProxySocket>,
     svr_cfg: ServerConfig,
+    reported_associations: usize,
 }
 
 impl UdpServer {
@@ -124,17 +184,22 @@ impl UdpServer {
         let (keepalive_tx, keepalive_rx) = mpsc::channel(UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE);
 
         let socket = ProxySocket::bind_with_opts(context.context(), &svr_cfg, accept_opts).await?;
-        let socket = MonProxySocket::from_socket(socket, context.flow_stat());
+        let mut socket = MonProxySocket::from_socket(socket, context.flow_stat());
+        if svr_cfg.user_manager().is_some() {
+            socket.set_user_flow_stat(context.user_flow_stat());
+        }
         let listener = Arc::new(socket);
 
         Ok(UdpServer {
             context,
             assoc_map,
+            capacity,
             keepalive_tx,
             keepalive_rx,
             time_to_live,
             listener,
             svr_cfg,
+            reported_associations: 0,
         })
     }
 
@@ -234,6 +299,7 @@ impl UdpServer {
                 _ = cleanup_timer.tick() => {
                     // cleanup expired associations. iter() will remove expired elements
                     self.assoc_map.cleanup_expired();
+                    self.report_associations();
                 }
 
                 peer_addr_opt = self.keepalive_rx.recv() => {
@@ -283,6 +349,7 @@ impl UdpServer {
             Ok(s) => s,
             Err(err) => {
                 error!("udp server recv packet failed. {}", err);
+                context.load_stat_ref().incr_udp_recv_failures();
                 return None;
             }
         };
@@ -306,7 +373,16 @@ impl UdpServer {
             return None;
         }
 
-        if context.check_outbound_blocked(&target_addr).await {
+        let user = control.as_ref().and_then(|c| c.user.as_ref());
+        if context.check_quota_exceeded(user.map(|u| u.name())) {
+            trace!("udp client {} outbound {} rejected by quota", peer_addr, target_addr);
+            return None;
+        }
+
+        if context
+            .check_outbound_blocked(&target_addr, user.map(|u| u.name()))
+            .await
+        {
             warn!("udp client {} outbound {} blocked by ACL rules", peer_addr, target_addr);
             return None;
         }
@@ -324,44 +400,54 @@ impl UdpServer {
     ) -> io::Result<()> {
         match self.assoc_map {
             NatMap::Association(ref mut m) => {
-                if let Some(assoc) = m.get(&peer_addr) {
+                if let Some(assoc) = m.get_mut(&peer_addr) {
+                    assoc.last_active = Instant::now();
                     return assoc.try_send((peer_addr, target_addr, data, control));
                 }
 
+                let peer_permit = acquire_peer_permit(&self.context, m, &peer_addr)?;
                 let assoc = UdpAssociation::new_association(
                     self.context.clone(),
                     listener.clone(),
                     peer_addr,
                     self.keepalive_tx.clone(),
+                    peer_permit,
                 );
 
                 debug!("created udp association for {}", peer_addr);
 
                 assoc.try_send((peer_addr, target_addr, data, control))?;
+                if self.capacity.is_some_and(|c| m.len() >= c) {
+                    self.context.load_stat_ref().incr_udp_evicted();
+                }
                 m.insert(peer_addr, assoc);
+                self.context.load_stat_ref().incr_udp_associated();
             }
             #[cfg(feature = "aead-cipher-2022")]
             NatMap::Session(ref mut m) => {
                 let xcontrol = match control {
                     None => {
                         error!("control is required for session based NAT, from {}", peer_addr);
-                        return Err(io::Error::new(ErrorKind::Other, "control data missing in packet"));
+                        return Err(io::Error::other("control data missing in packet"));
                     }
                     Some(ref c) => c,
                 };
 
                 let client_session_id = xcontrol.client_session_id;
 
-                if let Some(assoc) = m.get(&client_session_id) {
+                if let Some(assoc) = m.get_mut(&client_session_id) {
+                    assoc.last_active = Instant::now();
                     return assoc.try_send((peer_addr, target_addr, data, control));
                 }
 
+                let peer_permit = acquire_peer_permit(&self.context, m, &peer_addr)?;
                 let assoc = UdpAssociation::new_session(
                     self.context.clone(),
                     listener.clone(),
                     peer_addr,
                     self.keepalive_tx.clone(),
                     client_session_id,
+                    peer_permit,
                 );
 
                 debug!(
@@ -370,12 +456,26 @@ impl UdpServer {
                 );
 
                 assoc.try_send((peer_addr, target_addr, data, control))?;
+                if self.capacity.is_some_and(|c| m.len() >= c) {
+                    self.context.load_stat_ref().incr_udp_evicted();
+                }
                 m.insert(client_session_id, assoc);
+                self.context.load_stat_ref().incr_udp_associated();
             }
         }
 
+        self.report_associations();
         Ok(())
     }
+
+    /// Report size of the association table into the load statistic
+    fn report_associations(&mut self) {
+        let n = self.assoc_map.len();
+        self.context
+            .load_stat_ref()
+            .update_udp_associations(self.reported_associations, n);
+        self.reported_associations = n;
+    }
 }
 
 type UdpAssociationSendMessage = (SocketAddr, Address, Bytes, Option<UdpSocketControlData>);
@@ -383,6 +483,9 @@ type UdpAssociationSendMessage = (SocketAddr, Address, Bytes, Option<UdpSocketCo
 struct UdpAssociation {
     assoc_handle: JoinHandle<()>,
     sender: mpsc::Sender<UdpAssociationSendMessage>,
+    peer_addr: SocketAddr,
+    last_active: Instant,
+    _peer_permit: Option<PeerPermit>,
 }
 
 impl Drop for UdpAssociation {
@@ -397,9 +500,16 @@ impl UdpAssociation {
         inbound: Arc<MonProxySocket>,
         peer_addr: SocketAddr,
         keepalive_tx: mpsc::Sender<NatKey>,
+        peer_permit: Option<PeerPermit>,
     ) -> UdpAssociation {
         let (assoc_handle, sender) = UdpAssociationContext::create(context, inbound, peer_addr, keepalive_tx, None);
-        UdpAssociation { assoc_handle, sender }
+        UdpAssociation {
+            assoc_handle,
+            sender,
+            peer_addr,
+            last_active: Instant::now(),
+            _peer_permit: peer_permit,
+        }
     }
 
     #[cfg(feature = "aead-cipher-2022")]
@@ -409,15 +519,22 @@ impl UdpAssociation {
         peer_addr: SocketAddr,
         keepalive_tx: mpsc::Sender<NatKey>,
         client_session_id: u64,
+        peer_permit: Option<PeerPermit>,
     ) -> UdpAssociation {
         let (assoc_handle, sender) =
             UdpAssociationContext::create(context, inbound, peer_addr, keepalive_tx, Some(client_session_id));
-        UdpAssociation { assoc_handle, sender }
+        UdpAssociation {
+            assoc_handle,
+            sender,
+            peer_addr,
+            last_active: Instant::now(),
+            _peer_permit: peer_permit,
+        }
     }
 
     fn try_send(&self, data: UdpAssociationSendMessage) -> io::Result<()> {
         if self.sender.try_send(data).is_err() {
-            let err = io::Error::new(ErrorKind::Other, "udp relay channel full");
+            let err = io::Error::other("udp relay channel full");
             return Err(err);
         }
         Ok(())
@@ -611,7 +728,8 @@ impl UdpAssociationContext {
             control,
         );
 
-        if self.context.check_outbound_blocked(target_addr).await {
+        let user_name = control.as_ref().and_then(|c| c.user.as_ref()).map(|u| u.name());
+        if self.context.check_outbound_blocked(target_addr, user_name).await {
             error!(
                 "udp client {} outbound {} blocked by ACL rules",
                 self.peer_addr, target_addr
@@ -638,6 +756,16 @@ impl UdpAssociationContext {
             session_context.client_user.clone_from(&control.user);
         }
 
+        if !self.check_rate_limit(data.len(), true) {
+            trace!(
+                "udp relay {} -> {} with {} bytes dropped by rate limit",
+                self.peer_addr,
+                target_addr,
+                data.len()
+            );
+            return;
+        }
+
         if let Err(err) = self.dispatch_received_outbound_packet(target_addr, data).await {
             error!(
                 "udp relay {} -> {} with {} bytes, error: {}",
@@ -649,6 +777,36 @@ impl UdpAssociationContext {
         }
     }
 
+    /// Check bandwidth limits of the server and the client's user, packets are dropped if any of them is exceeded
+    fn check_rate_limit(&self, n: usize, upload: bool) -> bool {
+        let user_limiter = self
+            .client_session
+            .as_ref()
+            .and_then(|s| s.client_user.as_ref())
+            .and_then(|u| self.context.user_rate_limiter(u.name()));
+        let limiters = [self.context.rate_limiter(), user_limiter];
+
+        let ready = limiters.iter().flatten().all(|l| {
+            if upload {
+                l.is_upload_ready()
+            } else {
+                l.is_download_ready()
+            }
+        });
+        if !ready {
+            return false;
+        }
+
+        for limiter in limiters.iter().flatten() {
+            if upload {
+                limiter.uploaded(n);
+            } else {
+                limiter.downloaded(n);
+            }
+        }
+        true
+    }
+
     async fn dispatch_received_outbound_packet(&mut self, target_addr: &Address, data: &[u8]) -> io::Result<()> {
         match *target_addr {
             Address::SocketAddress(sa) => self.send_received_outbound_packet(sa, data).await,
@@ -743,6 +901,16 @@ impl UdpAssociationContext {
             }
         }
 
+        if !self.check_rate_limit(data.len(), false) {
+            trace!(
+                "udp relay {} <- {} with {} bytes dropped by rate limit",
+                self.peer_addr,
+                addr,
+                data.len()
+            );
+            return;
+        }
+
         match self.client_session {
             None => {
                 // Naive route, send data directly back to client without session
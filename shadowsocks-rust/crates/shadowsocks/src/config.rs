@@ -308,6 +308,7 @@ pub enum ServerSource {
     Configuration, //< Created from configuration
     CommandLine,   //< Created from command line
     OnlineConfig,  //< Created from online configuration (SIP008)
+    Api,           //< Created at runtime by API or control commands
 }
 
 /// Configuration for a server
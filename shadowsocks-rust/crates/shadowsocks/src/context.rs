@@ -9,6 +9,7 @@ use crate::{
     config::{ReplayAttackPolicy, ServerType},
     crypto::{v1::random_iv_or_salt, CipherKind},
     dns_resolver::DnsResolver,
+    net::AddrFamily,
     security::replay::ReplayProtector,
 };
 
@@ -25,6 +26,8 @@ pub struct Context {
 
     // Connect IPv6 address first
     ipv6_first: bool,
+    // Only use addresses of this family
+    addr_family: Option<AddrFamily>,
 }
 
 /// `Context` for sharing between services
@@ -38,6 +41,7 @@ impl Context {
             replay_policy: ReplayAttackPolicy::Default,
             dns_resolver: Arc::new(DnsResolver::system_resolver()),
             ipv6_first: false,
+            addr_family: None,
         }
     }
 
@@ -100,7 +104,7 @@ impl Context {
             }
             ReplayAttackPolicy::Reject => {
                 if self.replay_protector.check_nonce_and_set(method, nonce) {
-                    let err = io::Error::new(io::ErrorKind::Other, "detected repeated nonce (iv/salt)");
+                    let err = io::Error::other("detected repeated nonce (iv/salt)");
                     Err(err)
                 } else {
                     Ok(())
@@ -122,9 +126,30 @@ impl Context {
     }
 
     /// Resolves DNS address to `SocketAddr`s
+    ///
+    /// Addresses of the other family are dropped if `addr_family` is set
     #[allow(clippy::needless_lifetimes)]
     pub async fn dns_resolve<'a>(&self, addr: &'a str, port: u16) -> io::Result<impl Iterator<Item = SocketAddr> + 'a> {
-        self.dns_resolver.resolve(addr, port).await
+        let addrs = self.dns_resolver.resolve(addr, port).await?;
+
+        let family = match self.addr_family {
+            Some(f) => f,
+            None => return Ok(addrs.collect::<Vec<_>>().into_iter()),
+        };
+
+        let addrs = addrs.filter(|a| AddrFamily::from(a) == family).collect::<Vec<_>>();
+        if addrs.is_empty() {
+            return Err(io::Error::other(format!(
+                "{}:{} has no {} address",
+                addr,
+                port,
+                match family {
+                    AddrFamily::Ipv4 => "IPv4",
+                    AddrFamily::Ipv6 => "IPv6",
+                }
+            )));
+        }
+        Ok(addrs.into_iter())
     }
 
     /// Try to connect IPv6 addresses first if hostname could be resolved to both IPv4 and IPv6
@@ -137,6 +162,16 @@ impl Context {
         self.ipv6_first
     }
 
+    /// Only connect addresses of `family`, the other family is disabled
+    pub fn set_addr_family(&mut self, family: AddrFamily) {
+        self.addr_family = Some(family);
+    }
+
+    /// The only family of addresses to connect, if the other family is disabled
+    pub fn addr_family(&self) -> Option<AddrFamily> {
+        self.addr_family
+    }
+
     /// Set policy against replay attack
     pub fn set_replay_attack_policy(&mut self, replay_policy: ReplayAttackPolicy) {
         self.replay_policy = replay_policy;
@@ -68,160 +68,93 @@ macro_rules! lookup_then {
     }};
 }
 
+/// Helper macro for resolving host and then connecting the addresses, racing them with Happy Eyeballs (RFC 8305)
+///
+/// Addresses are interleaved by family, starting with the preferred one. A new attempt starts every
+/// `CONNECTION_ATTEMPT_DELAY`, or right after the previous one failed. The first established connection wins,
+/// and the other attempts are dropped.
 #[macro_export]
 macro_rules! lookup_then_connect {
     ($context:expr, $addr:expr, $port:expr, |$resolved_addr:ident| $body:block) => {{
-        use futures::future::{self, Either};
+        use futures::stream::{FuturesUnordered, StreamExt};
         use log::trace;
-        use std::{net::SocketAddr, time::Duration};
+        use std::{collections::VecDeque, net::SocketAddr, time::Duration};
         use tokio::time;
 
+        // RFC 8305, section 5, recommended value of Connection Attempt Delay
+        const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
         let ipv6_first = $context.ipv6_first();
 
-        let mut v4_addrs = Vec::new();
-        let mut v6_addrs = Vec::new();
+        let mut v4_addrs = VecDeque::new();
+        let mut v6_addrs = VecDeque::new();
 
         for addr in $context.dns_resolve($addr, $port).await? {
             match addr {
-                SocketAddr::V4(..) => v4_addrs.push(addr),
-                SocketAddr::V6(..) => v6_addrs.push(addr),
+                SocketAddr::V4(..) => v4_addrs.push_back(addr),
+                SocketAddr::V6(..) => v6_addrs.push_back(addr),
             }
         }
 
-        let has_v4 = !v4_addrs.is_empty();
-        let has_v6 = !v6_addrs.is_empty();
+        assert!(!v4_addrs.is_empty() || !v6_addrs.is_empty(), "resolved empty address");
 
-        assert!(has_v4 || has_v6, "resolved empty address");
+        let (mut preferred, mut other) = if ipv6_first {
+            (v6_addrs, v4_addrs)
+        } else {
+            (v4_addrs, v6_addrs)
+        };
 
-        // Happy Eyeballs, RFC6555, RFC8305
-        //
-        // RFC6555 gives an example that Chrome and Firefox uses 300ms
-        const FIXED_DELAY: Duration = Duration::from_millis(300);
-
-        // Connects every addresses synchronously.
-        // TODO: Try another address after FIXED_DELAY if one of the IPs is unreachable.
-        //
-        // This would require `future::select_ok`, which will require futures to be `Unpin`
-        // (boxed future, excessive memory allocation).
-
-        let connect_v4 = async {
-            // use futures::FutureExt;
-            //
-            // let mut vfut = Vec::new();
-            //
-            // let mut delay = Duration::from_millis(0);
-            //
-            // for $resolved_addr in v4_addrs {
-            //     vfut.push(
-            //         async move {
-            //             if delay != Duration::from_millis(0) {
-            //                 time::sleep(delay).await;
-            //             }
-            //
-            //             trace!("trying connect {}:{} {}", $addr, $port, $resolved_addr);
-            //
-            //             match $body {
-            //                 Ok(r) => Ok(($resolved_addr, r)),
-            //                 Err(err) => Err(err),
-            //             }
-            //         }
-            //         .boxed(),
-            //     );
-            //
-            //     delay += FIXED_DELAY;
-            // }
-            //
-            // match future::select_ok(vfut).await {
-            //     Ok((r, _)) => Ok(r),
-            //     Err(err) => Err(err),
-            // }
-
-            let mut result = None;
-
-            for $resolved_addr in v4_addrs {
-                trace!("trying connect {}:{} {}", $addr, $port, $resolved_addr);
-
-                match $body {
-                    Ok(r) => {
-                        trace!("connected {}:{} {}", $addr, $port, $resolved_addr);
-                        result = Some(Ok(($resolved_addr, r)));
-                        break;
-                    }
-                    Err(err) => {
-                        result = Some(Err(err));
-                    }
-                }
-            }
+        // RFC 8305, section 4, interleave families with First Address Family Count 1
+        let mut addrs = Vec::with_capacity(preferred.len() + other.len());
+        while !preferred.is_empty() || !other.is_empty() {
+            addrs.extend(preferred.pop_front());
+            addrs.extend(other.pop_front());
+        }
+        let mut addrs = addrs.into_iter();
 
-            result.expect("impossible")
+        let connect = |$resolved_addr: SocketAddr| async move {
+            trace!("trying connect {}:{} {}", $addr, $port, $resolved_addr);
+            ($resolved_addr, $body)
         };
 
-        let connect_v6 = async {
-            let mut result = None;
+        let mut attempts = FuturesUnordered::new();
+        attempts.extend(addrs.next().map(connect));
 
-            for $resolved_addr in v6_addrs {
-                trace!("trying connect {}:{} {}", $addr, $port, $resolved_addr);
-
-                match $body {
-                    Ok(r) => {
-                        trace!("connected {}:{} {}", $addr, $port, $resolved_addr);
-                        result = Some(Ok(($resolved_addr, r)));
-                        break;
-                    }
-                    Err(err) => {
-                        result = Some(Err(err));
+        let mut last_err = None;
+        loop {
+            let attempted = if addrs.as_slice().is_empty() {
+                attempts.next().await
+            } else {
+                match time::timeout(CONNECTION_ATTEMPT_DELAY, attempts.next()).await {
+                    Ok(r) => r,
+                    Err(..) => {
+                        // Previous attempts are still in progress, start the next one
+                        attempts.extend(addrs.next().map(connect));
+                        continue;
                     }
                 }
-            }
-
-            result.expect("impossible")
-        };
+            };
 
-        if has_v4 && !has_v6 {
-            connect_v4.await
-        } else if !has_v4 && has_v6 {
-            connect_v6.await
-        } else {
-            if ipv6_first {
-                let v4_fut = async move {
-                    time::sleep(FIXED_DELAY).await;
-                    connect_v4.await
-                };
-                let v6_fut = connect_v6;
-
-                tokio::pin!(v4_fut);
-                tokio::pin!(v6_fut);
-
-                match future::select(v4_fut, v6_fut).await {
-                    Either::Left((v4_res, v6_fut)) => match v4_res {
-                        Ok(res) => Ok(res),
-                        Err(_v4_err) => v6_fut.await,
-                    },
-                    Either::Right((v6_res, v4_fut)) => match v6_res {
-                        Ok(res) => Ok(res),
-                        Err(_v6_err) => v4_fut.await,
-                    },
+            match attempted {
+                Some(($resolved_addr, Ok(r))) => {
+                    trace!("connected {}:{} {}", $addr, $port, $resolved_addr);
+                    break Ok(($resolved_addr, r));
                 }
-            } else {
-                let v6_fut = async move {
-                    time::sleep(FIXED_DELAY).await;
-                    connect_v6.await
-                };
-                let v4_fut = connect_v4;
-
-                tokio::pin!(v4_fut);
-                tokio::pin!(v6_fut);
-
-                match future::select(v4_fut, v6_fut).await {
-                    Either::Left((v4_res, v6_fut)) => match v4_res {
-                        Ok(res) => Ok(res),
-                        Err(_v4_err) => v6_fut.await,
-                    },
-                    Either::Right((v6_res, v4_fut)) => match v6_res {
-                        Ok(res) => Ok(res),
-                        Err(_v6_err) => v4_fut.await,
-                    },
+                Some(($resolved_addr, Err(err))) => {
+                    trace!(
+                        "failed to connect {}:{} {}, error: {}",
+                        $addr,
+                        $port,
+                        $resolved_addr,
+                        err
+                    );
+                    last_err = Some(err);
+                    attempts.extend(addrs.next().map(connect));
                 }
+                None => match addrs.next() {
+                    Some(addr) => attempts.push(connect(addr)),
+                    None => break Err(last_err.expect("resolved empty address")),
+                },
             }
         }
     }};
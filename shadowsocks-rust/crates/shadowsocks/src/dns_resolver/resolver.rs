@@ -4,7 +4,7 @@
 use std::sync::Arc;
 use std::{
     fmt::{self, Debug},
-    io::{self, Error, ErrorKind},
+    io::{self, Error},
     net::SocketAddr,
     time::Instant,
 };
@@ -368,7 +368,7 @@ impl DnsResolver {
             DnsResolver::System => match lookup_host((addr, port)).await {
                 Ok(v) => Ok(EitherResolved::Tokio(v)),
                 Err(err) => {
-                    let err = Error::new(ErrorKind::Other, format!("dns resolve {addr}:{port} error: {err}"));
+                    let err = Error::other(format!("dns resolve {addr}:{port} error: {err}"));
                     Err(err)
                 }
             },
@@ -378,7 +378,7 @@ impl DnsResolver {
                     lookup_result.into_iter().map(move |ip| SocketAddr::new(ip, port)),
                 )),
                 Err(err) => {
-                    let err = Error::new(ErrorKind::Other, format!("dns resolve {addr}:{port} error: {err}"));
+                    let err = Error::other(format!("dns resolve {addr}:{port} error: {err}"));
                     Err(err)
                 }
             },
@@ -388,14 +388,14 @@ impl DnsResolver {
                     lookup_result.into_iter().map(move |ip| SocketAddr::new(ip, port)),
                 )),
                 Err(err) => {
-                    let err = Error::new(ErrorKind::Other, format!("dns resolve {addr}:{port} error: {err}"));
+                    let err = Error::other(format!("dns resolve {addr}:{port} error: {err}"));
                     Err(err)
                 }
             },
             DnsResolver::Custom(ref resolver) => match resolver.resolve(addr, port).await {
                 Ok(v) => Ok(EitherResolved::Custom(v.into_iter())),
                 Err(err) => {
-                    let err = Error::new(ErrorKind::Other, format!("dns resolve {addr}:{port} error: {err}"));
+                    let err = Error::other(format!("dns resolve {addr}:{port} error: {err}"));
                     Err(err)
                 }
             },
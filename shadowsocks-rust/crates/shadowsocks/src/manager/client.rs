@@ -8,8 +8,9 @@ use super::{
     datagram::ManagerDatagram,
     error::Error,
     protocol::{
-        AddRequest, AddResponse, ListRequest, ListResponse, ManagerProtocol, PingRequest, PingResponse, RemoveRequest,
-        RemoveResponse, StatRequest,
+        AddRequest, AddResponse, ListRequest, ListResponse, LoadRequest, LoadResponse, ManagerProtocol, PingRequest,
+        PingResponse, QuotaRequest, QuotaResponse, RemoveRequest, RemoveResponse, ResetQuotaRequest,
+        ResetQuotaResponse, StatRequest, UsersRequest, UsersResponse,
     },
 };
 
@@ -34,6 +35,14 @@ impl ManagerClient {
 
     impl_command!(ping, PingRequest, PingResponse);
 
+    impl_command!(load, LoadRequest, LoadResponse);
+
+    impl_command!(users, UsersRequest, UsersResponse);
+
+    impl_command!(quota, QuotaRequest, QuotaResponse);
+
+    impl_command!(reset_quota, ResetQuotaRequest, ResetQuotaResponse);
+
     impl_command!(remove, RemoveRequest, RemoveResponse);
 
     /// Create a `ManagerDatagram` for sending data to manager
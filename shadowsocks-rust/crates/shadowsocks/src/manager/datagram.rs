@@ -120,14 +120,14 @@ impl ManagerDatagram {
     }
 
     /// Receives data from the socket.
-    pub async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, ManagerSocketAddr)> {
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, ManagerSocketAddr)> {
         match *self {
-            ManagerDatagram::UdpDatagram(ref mut udp) => {
+            ManagerDatagram::UdpDatagram(ref udp) => {
                 let (s, addr) = udp.recv_from(buf).await?;
                 Ok((s, ManagerSocketAddr::SocketAddr(addr)))
             }
             #[cfg(unix)]
-            ManagerDatagram::UnixDatagram(ref mut unix) => {
+            ManagerDatagram::UnixDatagram(ref unix) => {
                 let (s, addr) = unix.recv_from(buf).await?;
                 Ok((s, ManagerSocketAddr::UnixSocketAddr(addr)))
             }
@@ -144,9 +144,9 @@ impl ManagerDatagram {
     }
 
     /// Sends data to the socket to the specified address.
-    pub async fn send_to(&mut self, buf: &[u8], target: &ManagerSocketAddr) -> io::Result<usize> {
+    pub async fn send_to(&self, buf: &[u8], target: &ManagerSocketAddr) -> io::Result<usize> {
         match *self {
-            ManagerDatagram::UdpDatagram(ref mut udp) => match *target {
+            ManagerDatagram::UdpDatagram(ref udp) => match *target {
                 ManagerSocketAddr::SocketAddr(ref saddr) => udp.send_to(buf, saddr).await,
                 #[cfg(unix)]
                 ManagerSocketAddr::UnixSocketAddr(..) => {
@@ -155,7 +155,7 @@ impl ManagerDatagram {
                 }
             },
             #[cfg(unix)]
-            ManagerDatagram::UnixDatagram(ref mut unix) => match *target {
+            ManagerDatagram::UnixDatagram(ref unix) => match *target {
                 ManagerSocketAddr::UnixSocketAddr(ref saddr) => match saddr.as_pathname() {
                     Some(paddr) => unix.send_to(buf, paddr).await,
                     None => {
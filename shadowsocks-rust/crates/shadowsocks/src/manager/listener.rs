@@ -25,13 +25,13 @@ impl ManagerListener {
             .map(|socket| ManagerListener { socket })
     }
 
-    pub async fn recv_from(&mut self) -> Result<(ManagerRequest, ManagerSocketAddr), Error> {
+    pub async fn recv_from(&self) -> Result<(ManagerRequest, ManagerSocketAddr), Error> {
         let mut buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
         let (n, peer_addr) = self.socket.recv_from(&mut buf).await?;
         Ok((ManagerRequest::from_bytes(&buf[..n])?, peer_addr))
     }
 
-    pub async fn send_to<P: ManagerProtocol>(&mut self, data: &P, target: &ManagerSocketAddr) -> Result<(), Error> {
+    pub async fn send_to<P: ManagerProtocol>(&self, data: &P, target: &ManagerSocketAddr) -> Result<(), Error> {
         let buf = data.to_bytes()?;
         let n = self.socket.send_to(&buf, target).await?;
         if n != buf.len() {
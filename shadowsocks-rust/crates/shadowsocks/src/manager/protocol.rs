@@ -2,7 +2,7 @@
 
 use std::{
     collections::HashMap,
-    io::{self, ErrorKind},
+    io::{self},
     str,
     string::ToString,
 };
@@ -21,6 +21,12 @@ pub trait ManagerProtocol: Sized {
 pub struct ServerUserConfig {
     pub name: String,
     pub password: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload_kbps: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_kbps: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<u64>,
 }
 
 /// Server's configuration
@@ -42,6 +48,18 @@ pub struct ServerConfig {
     pub mode: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub users: Option<Vec<ServerUserConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub udp_max_associations: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload_kbps: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_kbps: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota_reset_day: Option<u8>,
 }
 
 /// `add` request
@@ -231,6 +249,223 @@ impl ManagerProtocol for PingResponse {
     }
 }
 
+/// `load` request
+#[derive(Debug, Clone)]
+pub struct LoadRequest;
+
+impl ManagerProtocol for LoadRequest {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        let cmd = str::from_utf8(buf)?;
+        if cmd != "load" {
+            return Err(Error::UnrecognizedCommand(cmd.to_owned()));
+        }
+
+        Ok(LoadRequest)
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        Ok(b"load\n".to_vec())
+    }
+}
+
+/// Saturation of a server
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ServerLoad {
+    /// Active TCP connections
+    pub tcp_connections: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    /// TCP connections rejected because `max_connections` was reached
+    pub tcp_rejected: u64,
+    /// Active UDP associations
+    pub udp_associations: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub udp_max_associations: Option<usize>,
+    /// UDP associations evicted because `udp_max_associations` was reached
+    pub udp_evicted: u64,
+}
+
+/// `load` response
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(transparent)]
+pub struct LoadResponse {
+    pub load: HashMap<u16, ServerLoad>,
+}
+
+impl ManagerProtocol for LoadResponse {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        let req = serde_json::from_slice(buf)?;
+        Ok(req)
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = serde_json::to_vec(self)?;
+        buf.push(b'\n');
+        Ok(buf)
+    }
+}
+
+/// `users` request
+#[derive(Debug, Clone)]
+pub struct UsersRequest;
+
+impl ManagerProtocol for UsersRequest {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        let cmd = str::from_utf8(buf)?;
+        if cmd != "users" {
+            return Err(Error::UnrecognizedCommand(cmd.to_owned()));
+        }
+
+        Ok(UsersRequest)
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        Ok(b"users\n".to_vec())
+    }
+}
+
+/// Traffic of a user of a multi-user server
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UserFlow {
+    /// Bytes sent to the user
+    pub tx: u64,
+    /// Bytes received from the user
+    pub rx: u64,
+}
+
+/// `users` response
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(transparent)]
+pub struct UsersResponse {
+    /// Traffic of users by their names, of each server port
+    pub users: HashMap<u16, HashMap<String, UserFlow>>,
+}
+
+impl ManagerProtocol for UsersResponse {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        let req = serde_json::from_slice(buf)?;
+        Ok(req)
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = serde_json::to_vec(self)?;
+        buf.push(b'\n');
+        Ok(buf)
+    }
+}
+
+/// `quota` request
+#[derive(Debug, Clone)]
+pub struct QuotaRequest;
+
+impl ManagerProtocol for QuotaRequest {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        let cmd = str::from_utf8(buf)?;
+        if cmd != "quota" {
+            return Err(Error::UnrecognizedCommand(cmd.to_owned()));
+        }
+
+        Ok(QuotaRequest)
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        Ok(b"quota\n".to_vec())
+    }
+}
+
+/// Traffic quota of a server or a user
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct QuotaUsage {
+    /// Bytes allowed in a period
+    pub quota: u64,
+    /// Bytes sent and received in this period
+    pub used: u64,
+    /// New sessions are rejected until the quota is reset
+    pub exceeded: bool,
+}
+
+/// Traffic quotas of a server port
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ServerQuota {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server: Option<QuotaUsage>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub users: HashMap<String, QuotaUsage>,
+}
+
+/// `quota` response
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(transparent)]
+pub struct QuotaResponse {
+    /// Quotas of each server port with quotas
+    pub quota: HashMap<u16, ServerQuota>,
+}
+
+impl ManagerProtocol for QuotaResponse {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        let req = serde_json::from_slice(buf)?;
+        Ok(req)
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = serde_json::to_vec(self)?;
+        buf.push(b'\n');
+        Ok(buf)
+    }
+}
+
+/// `reset_quota` request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResetQuotaRequest {
+    pub server_port: u16,
+    /// Reset the quota of this user, or the server's quota and all its users' if `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+impl ManagerProtocol for ResetQuotaRequest {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        let mut nsplit = buf.splitn(2, |b| *b == b':');
+
+        let cmd = nsplit.next().expect("first element shouldn't be None");
+        let cmd = str::from_utf8(cmd)?.trim();
+        if cmd != "reset_quota" {
+            return Err(Error::UnrecognizedCommand(cmd.to_owned()));
+        }
+
+        match nsplit.next() {
+            None => Err(Error::MissingParameter),
+            Some(param) => {
+                let req = serde_json::from_slice(param)?;
+                Ok(req)
+            }
+        }
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = b"reset_quota: ".to_vec();
+        serde_json::to_writer(&mut buf, self)?;
+        buf.push(b'\n');
+        Ok(buf)
+    }
+}
+
+/// `reset_quota` response
+#[derive(Debug, Clone)]
+pub struct ResetQuotaResponse(pub String);
+
+impl ManagerProtocol for ResetQuotaResponse {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        Ok(ResetQuotaResponse(str::from_utf8(buf)?.trim().to_owned()))
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut v = self.0.as_bytes().to_owned();
+        v.push(b'\n');
+        Ok(v)
+    }
+}
+
 /// `stat` request
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(transparent)]
@@ -282,12 +517,17 @@ impl<E: ToString> ManagerProtocol for ErrorResponse<E> {
 }
 
 /// Collections of Manager's request
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 pub enum ManagerRequest {
     Add(AddRequest),
     Remove(RemoveRequest),
     List(ListRequest),
     Ping(PingRequest),
+    Load(LoadRequest),
+    Users(UsersRequest),
+    Quota(QuotaRequest),
+    ResetQuota(ResetQuotaRequest),
     Stat(StatRequest),
 }
 
@@ -299,6 +539,10 @@ impl ManagerRequest {
             ManagerRequest::Remove(..) => "remove",
             ManagerRequest::List(..) => "list",
             ManagerRequest::Ping(..) => "ping",
+            ManagerRequest::Load(..) => "load",
+            ManagerRequest::Users(..) => "users",
+            ManagerRequest::Quota(..) => "quota",
+            ManagerRequest::ResetQuota(..) => "reset_quota",
             ManagerRequest::Stat(..) => "stat",
         }
     }
@@ -311,6 +555,10 @@ impl ManagerProtocol for ManagerRequest {
             ManagerRequest::Remove(ref req) => req.to_bytes(),
             ManagerRequest::List(ref req) => req.to_bytes(),
             ManagerRequest::Ping(ref req) => req.to_bytes(),
+            ManagerRequest::Load(ref req) => req.to_bytes(),
+            ManagerRequest::Users(ref req) => req.to_bytes(),
+            ManagerRequest::Quota(ref req) => req.to_bytes(),
+            ManagerRequest::ResetQuota(ref req) => req.to_bytes(),
             ManagerRequest::Stat(ref req) => req.to_bytes(),
         }
     }
@@ -346,6 +594,31 @@ impl ManagerProtocol for ManagerRequest {
                 }
                 Ok(ManagerRequest::Ping(PingRequest))
             }
+            "load" => {
+                if nsplit.next().is_some() {
+                    return Err(Error::RedundantParameter);
+                }
+                Ok(ManagerRequest::Load(LoadRequest))
+            }
+            "users" => {
+                if nsplit.next().is_some() {
+                    return Err(Error::RedundantParameter);
+                }
+                Ok(ManagerRequest::Users(UsersRequest))
+            }
+            "quota" => {
+                if nsplit.next().is_some() {
+                    return Err(Error::RedundantParameter);
+                }
+                Ok(ManagerRequest::Quota(QuotaRequest))
+            }
+            "reset_quota" => match nsplit.next() {
+                None => Err(Error::MissingParameter),
+                Some(param) => {
+                    let req = serde_json::from_slice(param)?;
+                    Ok(ManagerRequest::ResetQuota(req))
+                }
+            },
             "stat" => match nsplit.next() {
                 None => Err(Error::MissingParameter),
                 Some(param) => {
@@ -375,6 +648,6 @@ pub enum Error {
 
 impl From<Error> for io::Error {
     fn from(err: Error) -> io::Error {
-        io::Error::new(ErrorKind::Other, err.to_string())
+        io::Error::other(err.to_string())
     }
 }
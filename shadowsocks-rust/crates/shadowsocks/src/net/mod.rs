@@ -2,6 +2,8 @@
 
 use std::net::SocketAddr;
 
+#[cfg(target_os = "android")]
+pub use self::option::SocketProtect;
 #[cfg(unix)]
 pub use self::sys::uds::{UnixListener, UnixStream};
 pub use self::{
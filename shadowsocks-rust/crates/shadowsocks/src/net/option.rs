@@ -1,5 +1,7 @@
 //! Options for connecting to remote server
 
+#[cfg(target_os = "android")]
+use std::{fmt, io, os::unix::io::RawFd, sync::Arc};
 use std::{net::IpAddr, time::Duration};
 
 /// Options for connecting to TCP remote server
@@ -28,6 +30,11 @@ pub struct TcpSocketOpts {
     /// - macOS (iOS, watchOS, ...) with Client Support only.
     /// - Linux (>5.19)
     pub mptcp: bool,
+
+    /// `TCP_MAXSEG` set before connecting, clamps MSS advertised in SYN
+    ///
+    /// Currently only supported on Unix
+    pub mss: Option<u32>,
 }
 
 /// Options for UDP server
@@ -57,6 +64,12 @@ pub struct ConnectOpts {
     #[cfg(target_os = "android")]
     pub vpn_protect_path: Option<std::path::PathBuf>,
 
+    /// Protects outbound sockets in process, for embedding in an Android `VpnService`
+    ///
+    /// Called with every outbound socket before it is connected, after `vpn_protect_path`
+    #[cfg(target_os = "android")]
+    pub vpn_socket_protect: Option<Arc<dyn SocketProtect>>,
+
     /// Outbound socket binds to this IP address, mostly for choosing network interfaces
     ///
     /// It only affects sockets that trying to connect to addresses with the same family
@@ -84,3 +97,27 @@ pub struct AcceptOpts {
     /// Enable IPV6_V6ONLY option for socket
     pub ipv6_only: bool,
 }
+
+/// Excludes a socket from the VPN, like [`VpnService.protect()`](https://developer.android.com/reference/android/net/VpnService#protect(int))
+#[cfg(target_os = "android")]
+pub trait SocketProtect: Send + Sync {
+    /// Protect socket `fd`, failing the socket if it returns an error
+    fn protect(&self, fd: RawFd) -> io::Result<()>;
+}
+
+#[cfg(target_os = "android")]
+impl<F> SocketProtect for F
+where
+    F: Fn(RawFd) -> io::Result<()> + Send + Sync,
+{
+    fn protect(&self, fd: RawFd) -> io::Result<()> {
+        self(fd)
+    }
+}
+
+#[cfg(target_os = "android")]
+impl fmt::Debug for dyn SocketProtect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SocketProtect")
+    }
+}
@@ -45,6 +45,12 @@ fn set_common_sockopt_for_connect(addr: SocketAddr, socket: &TcpSocket, opts: &C
         socket.set_recv_buffer_size(buf_size)?;
     }
 
+    // Set `TCP_MAXSEG`
+    #[cfg(unix)]
+    if let Some(mss) = opts.tcp.mss {
+        socket2::SockRef::from(socket).set_mss(mss)?;
+    }
+
     Ok(())
 }
 
@@ -129,6 +135,65 @@ fn socket_bind_dual_stack_inner(socket: &Socket, addr: &SocketAddr, ipv6_only: b
     Ok(())
 }
 
+/// Set `IP_TOS` (IPv4) or `IPV6_TCLASS` (IPv6) of the socket, which marks DSCP and ECN of outgoing packets
+#[cfg(unix)]
+pub fn socket_set_tos<S>(socket: &S, tos: u8) -> io::Result<()>
+where
+    S: std::os::unix::io::AsRawFd,
+{
+    use std::os::unix::prelude::{FromRawFd, IntoRawFd};
+
+    let fd = socket.as_raw_fd();
+
+    let sock = unsafe { Socket::from_raw_fd(fd) };
+    let result = socket_set_tos_inner(&sock, tos);
+    let _ = sock.into_raw_fd();
+
+    result
+}
+
+/// Set `IP_TOS` (IPv4) or `IPV6_TCLASS` (IPv6) of the socket, which marks DSCP and ECN of outgoing packets
+#[cfg(windows)]
+pub fn socket_set_tos<S>(socket: &S, tos: u8) -> io::Result<()>
+where
+    S: std::os::windows::io::AsRawSocket,
+{
+    use std::os::windows::prelude::{FromRawSocket, IntoRawSocket};
+
+    let handle = socket.as_raw_socket();
+
+    let sock = unsafe { Socket::from_raw_socket(handle) };
+    let result = socket_set_tos_inner(&sock, tos);
+    sock.into_raw_socket();
+
+    result
+}
+
+fn socket_set_tos_inner(socket: &Socket, tos: u8) -> io::Result<()> {
+    match socket.local_addr()?.as_socket() {
+        Some(SocketAddr::V4(..)) => socket.set_tos(u32::from(tos)),
+        Some(SocketAddr::V6(..)) => {
+            // Dual-stack sockets send IPv4 packets to IPv4-mapped-IPv6 addresses, which follow `IP_TOS`
+            let _ = socket.set_tos(u32::from(tos));
+
+            cfg_if! {
+                if #[cfg(any(
+                    target_os = "linux",
+                    target_os = "android",
+                    target_os = "macos",
+                    target_os = "ios",
+                    target_os = "freebsd"
+                ))] {
+                    socket.set_tclass_v6(u32::from(tos))
+                } else {
+                    Err(io::Error::new(ErrorKind::Unsupported, "IPV6_TCLASS is not supported"))
+                }
+            }
+        }
+        None => Err(io::Error::new(ErrorKind::InvalidInput, "socket is not an IP socket")),
+    }
+}
+
 /// IP Stack Capabilities
 #[derive(Debug, Clone, Copy, Default)]
 pub struct IpStackCapabilities {
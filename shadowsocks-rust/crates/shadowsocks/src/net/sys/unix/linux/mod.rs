@@ -67,6 +67,10 @@ impl TcpStream {
                     Err(..) => return Err(io::Error::new(ErrorKind::TimedOut, "protect() timeout")),
                 }
             }
+
+            if let Some(ref protect) = opts.vpn_socket_protect {
+                protect.protect(socket.as_raw_fd())?;
+            }
         }
 
         // Set SO_MARK for mark-based routing on Linux (since 2.6.25)
@@ -324,6 +328,10 @@ pub async fn bind_outbound_udp_socket(bind_addr: &SocketAddr, config: &ConnectOp
                 Err(..) => return Err(io::Error::new(ErrorKind::TimedOut, "protect() timeout")),
             }
         }
+
+        if let Some(ref protect) = config.vpn_socket_protect {
+            protect.protect(socket.as_raw_fd())?;
+        }
     }
 
     // Set SO_MARK for mark-based routing on Linux (since 2.6.25)
@@ -25,7 +25,7 @@ use super::{
     is_dual_stack_addr,
     sys::{
         create_inbound_tcp_socket, set_common_sockopt_after_accept, set_tcp_fastopen, socket_bind_dual_stack,
-        TcpStream as SysTcpStream,
+        socket_set_tos, TcpStream as SysTcpStream,
     },
     AcceptOpts, ConnectOpts,
 };
@@ -98,6 +98,11 @@ impl TcpStream {
     pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
         self.0.set_nodelay(nodelay)
     }
+
+    /// Sets DSCP and ECN (`IP_TOS` or `IPV6_TCLASS`) of packets sent on this socket.
+    pub fn set_tos(&self, tos: u8) -> io::Result<()> {
+        socket_set_tos(self, tos)
+    }
 }
 
 impl AsyncRead for TcpStream {
@@ -38,7 +38,7 @@ use tokio::{io::ReadBuf, net::ToSocketAddrs};
 use crate::{context::Context, relay::socks5::Address, ServerAddr};
 
 use super::{
-    sys::{bind_outbound_udp_socket, create_inbound_udp_socket, create_outbound_udp_socket},
+    sys::{bind_outbound_udp_socket, create_inbound_udp_socket, create_outbound_udp_socket, socket_set_tos},
     AcceptOpts, AddrFamily, ConnectOpts,
 };
 
@@ -78,10 +78,7 @@ pub struct BatchRecvMessage<'a> {
 
 #[inline]
 fn make_mtu_error(packet_size: usize, mtu: usize) -> io::Error {
-    io::Error::new(
-        io::ErrorKind::Other,
-        format!("UDP packet {} > MTU {}", packet_size, mtu),
-    )
+    io::Error::other(format!("UDP packet {} > MTU {}", packet_size, mtu))
 }
 
 /// Wrappers for outbound `UdpSocket`
@@ -190,6 +187,11 @@ impl UdpSocket {
         })
     }
 
+    /// Sets DSCP and ECN (`IP_TOS` or `IPV6_TCLASS`) of packets sent on this socket.
+    pub fn set_tos(&self, tos: u8) -> io::Result<()> {
+        socket_set_tos(&self.socket, tos)
+    }
+
     /// Wrapper of `UdpSocket::poll_send`
     pub fn poll_send(&self, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
         // Check MTU
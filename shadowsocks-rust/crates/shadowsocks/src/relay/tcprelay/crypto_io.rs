@@ -15,7 +15,7 @@ use log::trace;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 use crate::{
-    config::ServerUserManager,
+    config::{ServerUser, ServerUserManager},
     context::Context,
     crypto::{CipherCategory, CipherKind},
 };
@@ -174,6 +174,18 @@ impl DecryptedReader {
         }
     }
 
+    /// Get authenticated user (AEAD2022)
+    pub fn user(&self) -> Option<&Arc<ServerUser>> {
+        match *self {
+            #[cfg(feature = "stream-cipher")]
+            DecryptedReader::Stream(..) => None,
+            DecryptedReader::Aead(..) => None,
+            DecryptedReader::None => None,
+            #[cfg(feature = "aead-cipher-2022")]
+            DecryptedReader::Aead2022(ref reader) => reader.user(),
+        }
+    }
+
     pub fn handshaked(&self) -> bool {
         match *self {
             #[cfg(feature = "stream-cipher")]
@@ -430,6 +442,12 @@ impl<S> CryptoStream<S> {
         self.dec.request_nonce()
     }
 
+    /// Authenticated user by EIH (server stream of AEAD2022)
+    #[inline]
+    pub fn user(&self) -> Option<&Arc<ServerUser>> {
+        self.dec.user()
+    }
+
     /// Set request nonce (for server stream of AEAD2022)
     #[inline]
     pub fn set_request_nonce(&mut self, request_nonce: &[u8]) {
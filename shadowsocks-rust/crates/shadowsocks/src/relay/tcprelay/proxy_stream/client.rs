@@ -26,13 +26,16 @@ use crate::{
     net::{ConnectOpts, TcpStream as OutboundTcpStream},
     relay::{
         socks5::Address,
-        tcprelay::crypto_io::{CryptoRead, CryptoStream, CryptoWrite, StreamType},
+        tcprelay::{
+            crypto_io::{CryptoRead, CryptoStream, CryptoWrite, StreamType},
+            utils::plain_read_buffer_size,
+        },
     },
 };
 
 enum ProxyClientStreamWriteState {
     Connect(Address),
-    Connecting(BytesMut),
+    Connecting(BytesMut, usize),
     Connected,
 }
 
@@ -231,11 +234,7 @@ where
                         let sent_nonce = this.stream.sent_nonce();
                         let sent_nonce = if sent_nonce.is_empty() { None } else { Some(sent_nonce) };
                         if sent_nonce != this.stream.received_request_nonce() {
-                            return Err(io::Error::new(
-                                ErrorKind::Other,
-                                "received TCP response header with unmatched salt",
-                            ))
-                            .into();
+                            return Err(io::Error::other("received TCP response header with unmatched salt")).into();
                         }
 
                         *(this.reader_state) = ProxyClientStreamReadState::Established;
@@ -248,6 +247,21 @@ where
     }
 }
 
+/// Maximum length of payload that could be sent in the first packet with the target address
+#[inline]
+fn first_packet_max_payload(method: CipherKind, addr: &Address) -> usize {
+    #[allow(unused_mut)]
+    let mut header_length = addr.serialized_len();
+
+    #[cfg(feature = "aead-cipher-2022")]
+    if method.is_aead_2022() {
+        // Padding is only added to the first packet without payload
+        header_length += 2;
+    }
+
+    plain_read_buffer_size(method) - header_length
+}
+
 #[inline]
 fn make_first_packet_buffer(method: CipherKind, addr: &Address, buf: &[u8]) -> BytesMut {
     // Target Address should be sent with the first packet together,
@@ -301,16 +315,19 @@ where
         loop {
             match this.writer_state {
                 ProxyClientStreamWriteState::Connect(ref addr) => {
-                    let buffer = make_first_packet_buffer(this.stream.method(), addr, buf);
+                    // The whole first packet has to be encrypted in one chunk, otherwise the exceeded part will be lost.
+                    let first_len = buf.len().min(first_packet_max_payload(this.stream.method(), addr));
+                    let buffer = make_first_packet_buffer(this.stream.method(), addr, &buf[..first_len]);
 
                     // Save the concatenated buffer before it is written successfully.
                     // APIs require buffer to be kept alive before Poll::Ready
                     //
                     // Proactor APIs like IOCP on Windows, pointers of buffers have to be kept alive
                     // before IO completion.
-                    *(this.writer_state) = ProxyClientStreamWriteState::Connecting(buffer);
+                    *(this.writer_state) = ProxyClientStreamWriteState::Connecting(buffer, first_len);
                 }
-                ProxyClientStreamWriteState::Connecting(ref buffer) => {
+                ProxyClientStreamWriteState::Connecting(ref buffer, ref first_len) => {
+                    let first_len = *first_len;
                     let n = ready!(this.stream.poll_write_encrypted(cx, buffer))?;
 
                     // In general, poll_write_encrypted should perform like write_all.
@@ -326,7 +343,7 @@ where
                     //
                     // For protocols that requires *Server Hello* message, like FTP, clients won't send anything to the server until server sends handshake messages.
                     // This could be achieved by calling poll_write with an empty input buffer.
-                    return Ok(buf.len()).into();
+                    return Ok(first_len).into();
                 }
                 ProxyClientStreamWriteState::Connected => {
                     return this.stream.poll_write_encrypted(cx, buf).map_err(Into::into);
@@ -13,7 +13,7 @@ use pin_project::pin_project;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 use crate::{
-    config::ServerUserManager,
+    config::{ServerUser, ServerUserManager},
     context::SharedContext,
     crypto::CipherKind,
     relay::{
@@ -90,6 +90,11 @@ impl<S> ProxyServerStream<S> {
     pub fn into_inner(self) -> S {
         self.stream.into_inner()
     }
+
+    /// Get the user authenticated by EIH (AEAD-2022), available after handshake
+    pub fn user(&self) -> Option<&Arc<ServerUser>> {
+        self.stream.user()
+    }
 }
 
 impl<S> ProxyServerStream<S>
@@ -101,7 +106,7 @@ where
     /// This method should be called only once after accepted.
     pub async fn handshake(&mut self) -> io::Result<Address> {
         if self.has_handshaked {
-            return Err(io::Error::new(io::ErrorKind::Other, "stream is already handshaked"));
+            return Err(io::Error::other("stream is already handshaked"));
         }
 
         self.has_handshaked = true;
@@ -121,10 +126,7 @@ where
                 if chunk_count == 1 && chunk_remaining == 0 {
                     // Header is the end of the data chunk, so no payload is in the first chunk, and padding == 0.
                     // REJECT insecure clients.
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "no payload in first data chunk, and padding is 0",
-                    ));
+                    return Err(io::Error::other("no payload in first data chunk, and padding is 0"));
                 } else if chunk_count > 1 {
                     warn!(
                         "tcp header is separated in {} chunks, client is not following the AEAD-2022 spec",
@@ -144,7 +146,7 @@ where
     #[inline]
     fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
         if !self.has_handshaked {
-            return Err(io::Error::new(io::ErrorKind::Other, "stream is not handshaked yet")).into();
+            return Err(io::Error::other("stream is not handshaked yet")).into();
         }
 
         let this = self.project();
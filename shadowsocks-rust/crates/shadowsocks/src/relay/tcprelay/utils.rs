@@ -153,7 +153,8 @@ where
     .await
 }
 
-fn plain_read_buffer_size(method: CipherKind) -> usize {
+/// Maximum size of plain data that could be encrypted in one chunk
+pub(crate) fn plain_read_buffer_size(method: CipherKind) -> usize {
     match method.category() {
         CipherCategory::Aead => super::aead::MAX_PACKET_SIZE,
         #[cfg(feature = "stream-cipher")]
@@ -57,7 +57,7 @@ impl From<ProxySocketError> for io::Error {
     fn from(e: ProxySocketError) -> io::Error {
         match e {
             ProxySocketError::IoError(e) => e,
-            _ => io::Error::new(ErrorKind::Other, e),
+            _ => io::Error::other(e),
         }
     }
 }
@@ -81,9 +81,7 @@ pub struct ProxySocket {
 impl ProxySocket {
     /// Create a client to communicate with Shadowsocks' UDP server (outbound)
     pub async fn connect(context: SharedContext, svr_cfg: &ServerConfig) -> ProxySocketResult<ProxySocket> {
-        ProxySocket::connect_with_opts(context, svr_cfg, &DEFAULT_CONNECT_OPTS)
-            .await
-            .map_err(Into::into)
+        ProxySocket::connect_with_opts(context, svr_cfg, &DEFAULT_CONNECT_OPTS).await
     }
 
     /// Create a client to communicate with Shadowsocks' UDP server (outbound)
@@ -147,9 +145,7 @@ impl ProxySocket {
 
     /// Create a `ProxySocket` binding to a specific address (inbound)
     pub async fn bind(context: SharedContext, svr_cfg: &ServerConfig) -> ProxySocketResult<ProxySocket> {
-        ProxySocket::bind_with_opts(context, svr_cfg, AcceptOpts::default())
-            .await
-            .map_err(Into::into)
+        ProxySocket::bind_with_opts(context, svr_cfg, AcceptOpts::default()).await
     }
 
     /// Create a `ProxySocket` binding to a specific address (inbound)
@@ -213,9 +209,7 @@ impl ProxySocket {
     /// Send a UDP packet to addr through proxy
     #[inline]
     pub async fn send(&self, addr: &Address, payload: &[u8]) -> ProxySocketResult<usize> {
-        self.send_with_ctrl(addr, &DEFAULT_SOCKET_CONTROL, payload)
-            .await
-            .map_err(Into::into)
+        self.send_with_ctrl(addr, &DEFAULT_SOCKET_CONTROL, payload).await
     }
 
     /// Send a UDP packet to addr through proxy with `ControlData`
@@ -371,7 +365,6 @@ impl ProxySocket {
     ) -> ProxySocketResult<usize> {
         self.send_to_with_ctrl(target, addr, &DEFAULT_SOCKET_CONTROL, payload)
             .await
-            .map_err(Into::into)
     }
 
     /// Send a UDP packet to target through proxy `target`
@@ -585,6 +578,11 @@ impl ProxySocket {
         self.socket.local_addr()
     }
 
+    /// Set DSCP and ECN (`IP_TOS` or `IPV6_TCLASS`) of packets sent to the server
+    pub fn set_tos(&self, tos: u8) -> io::Result<()> {
+        self.socket.set_tos(tos)
+    }
+
     /// Set `send` timeout, `None` will clear timeout
     pub fn set_send_timeout(&mut self, t: Option<Duration>) {
         self.send_timeout = t;
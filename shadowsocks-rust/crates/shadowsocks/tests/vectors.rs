@@ -0,0 +1,94 @@
+//! Byte-level test vectors of the AEAD protocol
+//!
+//! Generated independently from this implementation, with fixed salts (`0x10, 0x11, ...`)
+//! and password `test-vector-password`.
+
+use std::net::SocketAddr;
+
+use tokio::{io::AsyncReadExt, io::AsyncWriteExt, net::TcpStream};
+
+use shadowsocks::{
+    config::{ServerConfig, ServerType},
+    context::Context,
+    crypto::CipherKind,
+    relay::{socks5::Address, udprelay::crypto_io::decrypt_client_payload},
+    ProxyListener,
+};
+
+const PASSWORD: &str = "test-vector-password";
+const KEY: &str = "7b0fe7cdb335d65dca509459e2cdd6980696805c5c8992e38dad5d826e06c891";
+
+// salt | AEAD(127.0.0.1:8080 | "hello")
+const UDP_AES_256_GCM: &str = "101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f\
+                               2a461b4ed571d902318e438647022e84cca65815e1d0abac5acb6ccc";
+const UDP_CHACHA20_POLY1305: &str = "101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f\
+                                     4b8f25ba168486efab90489f7d09abe0c164d7fa8281669b2efbdd12";
+
+// salt | [len | example.com:443 "hello"] | [len | " world"]
+const TCP_AES_256_GCM: &str = "101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f\
+                               2b2d30906ca4d115ce9d0c86d8baa06fdfee31f35e20a44fef2d19986a21c04447cfb63023edfbe70dc534c4\
+                               6383693d192bf71dad06ff76343ab0dd9bcac921d3ae05e0465879f16f5437b322ffe43466640e6fea13e842\
+                               93a1835a593e";
+const TCP_CHACHA20_POLY1305: &str = "101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f\
+                                     4ae4b86f306d412f1bd40dca37fe4b778e67e4003d05f64cd8681e87f369367caa1f2055a1808735fbe0\
+                                     619792af57705d60aec3edb5cb6b7cfdfbcd65f7c4d1b222ffbcc09ea655512d77292e122acf860ae3\
+                                     61171495b0ef0ceeefeda5";
+
+fn decode_hex(s: &str) -> Vec<u8> {
+    let s = s.split_whitespace().collect::<String>();
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn udp_vector(method: CipherKind, vector: &str) {
+    let svr_cfg = ServerConfig::new("127.0.0.1:0".parse::<SocketAddr>().unwrap(), PASSWORD, method);
+    assert_eq!(svr_cfg.key(), decode_hex(KEY));
+
+    let context = Context::new(ServerType::Server);
+    let mut packet = decode_hex(vector);
+    let (n, addr, ..) = decrypt_client_payload(&context, method, svr_cfg.key(), &mut packet, None).unwrap();
+
+    assert_eq!(addr, Address::SocketAddress("127.0.0.1:8080".parse().unwrap()));
+    assert_eq!(&packet[..n], b"hello");
+}
+
+async fn tcp_vector(method: CipherKind, vector: &str) {
+    let svr_cfg = ServerConfig::new("127.0.0.1:0".parse::<SocketAddr>().unwrap(), PASSWORD, method);
+    let context = Context::new_shared(ServerType::Server);
+
+    let listener = ProxyListener::bind(context, &svr_cfg).await.unwrap();
+    let listen_addr = listener.local_addr().unwrap();
+
+    let mut client = TcpStream::connect(listen_addr).await.unwrap();
+    client.write_all(&decode_hex(vector)).await.unwrap();
+
+    let (mut stream, ..) = listener.accept().await.unwrap();
+    let addr = stream.handshake().await.unwrap();
+    assert_eq!(addr, Address::DomainNameAddress("example.com".to_owned(), 443));
+
+    let mut payload = [0u8; 11];
+    stream.read_exact(&mut payload).await.unwrap();
+    assert_eq!(&payload, b"hello world");
+}
+
+#[test]
+fn udp_vector_aes_256_gcm() {
+    udp_vector(CipherKind::AES_256_GCM, UDP_AES_256_GCM);
+}
+
+#[test]
+fn udp_vector_chacha20_poly1305() {
+    udp_vector(CipherKind::CHACHA20_POLY1305, UDP_CHACHA20_POLY1305);
+}
+
+#[tokio::test]
+async fn tcp_vector_aes_256_gcm() {
+    tcp_vector(CipherKind::AES_256_GCM, TCP_AES_256_GCM).await;
+}
+
+#[tokio::test]
+async fn tcp_vector_chacha20_poly1305() {
+    tcp_vector(CipherKind::CHACHA20_POLY1305, TCP_CHACHA20_POLY1305).await;
+}
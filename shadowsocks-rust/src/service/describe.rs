@@ -0,0 +1,555 @@
+//! Self-description of the built binary
+//!
+//! Describes local instance types, ciphers, transports and configuration options that are available in this build
+//! in JSON, so GUI frontends could render configuration forms without hardcoding knowledge of each release.
+
+use std::process::ExitCode;
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use shadowsocks_service::{config::ProtocolType, shadowsocks::crypto::available_ciphers};
+
+/// Cargo features that could be enabled in the build
+const FEATURES: &[(&str, bool)] = &[
+    ("local", cfg!(feature = "local")),
+    ("server", cfg!(feature = "server")),
+    ("manager", cfg!(feature = "manager")),
+    ("hickory-dns", cfg!(feature = "hickory-dns")),
+    ("dns-over-tls", cfg!(feature = "dns-over-tls")),
+    ("dns-over-https", cfg!(feature = "dns-over-https")),
+    ("dns-over-h3", cfg!(feature = "dns-over-h3")),
+    ("local-dns", cfg!(feature = "local-dns")),
+    ("local-flow-stat", cfg!(feature = "local-flow-stat")),
+    ("local-http", cfg!(feature = "local-http")),
+    ("local-http-native-tls", cfg!(feature = "local-http-native-tls")),
+    ("local-http-rustls", cfg!(feature = "local-http-rustls")),
+    ("local-tls", cfg!(feature = "local-tls")),
+    ("local-redir", cfg!(feature = "local-redir")),
+    ("local-tunnel", cfg!(feature = "local-tunnel")),
+    ("local-socks4", cfg!(feature = "local-socks4")),
+    ("local-tun", cfg!(feature = "local-tun")),
+    ("local-fake-dns", cfg!(feature = "local-fake-dns")),
+    ("local-history", cfg!(feature = "local-history")),
+    ("local-file-watch", cfg!(feature = "local-file-watch")),
+    ("local-online-config", cfg!(feature = "local-online-config")),
+    ("multi-threaded", cfg!(feature = "multi-threaded")),
+    ("stream-cipher", cfg!(feature = "stream-cipher")),
+    ("aead-cipher-extra", cfg!(feature = "aead-cipher-extra")),
+    ("aead-cipher-2022", cfg!(feature = "aead-cipher-2022")),
+    ("aead-cipher-2022-extra", cfg!(feature = "aead-cipher-2022-extra")),
+    (
+        "security-replay-attack-detect",
+        cfg!(feature = "security-replay-attack-detect"),
+    ),
+];
+
+/// TCP Fast Open is supported on this platform
+pub const TCP_FAST_OPEN_SUPPORTED: bool = cfg!(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "windows"
+));
+
+/// Cargo features enabled in the build
+pub fn enabled_features() -> Vec<&'static str> {
+    FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// Description of the built binary
+#[derive(Debug, Serialize)]
+pub struct Description {
+    pub version: &'static str,
+    pub build_time: &'static str,
+    pub features: Vec<&'static str>,
+    /// Protocols of instances in `locals`, empty if `local` is not enabled
+    pub local_protocols: Vec<&'static str>,
+    pub ciphers: Vec<&'static str>,
+    pub transports: TransportDescription,
+    pub options: Vec<OptionDescription>,
+}
+
+/// Transports supported by the build and platform
+#[derive(Debug, Serialize)]
+pub struct TransportDescription {
+    /// Values of `mode`
+    pub modes: Vec<&'static str>,
+    pub tcp_fast_open: bool,
+    pub mptcp: bool,
+    /// SIP003 plugins, and SIP003u plugins for UDP
+    pub plugin: bool,
+    /// Values of `tcp_redir` and `udp_redir` in `locals`, empty if `local-redir` is not enabled
+    pub tcp_redir: Vec<&'static str>,
+    pub udp_redir: Vec<&'static str>,
+}
+
+/// A configuration option
+#[derive(Debug, Serialize)]
+pub struct OptionDescription {
+    /// Path of the key, `[]` stands for elements of an array, e.g. `locals[].protocol`
+    pub name: &'static str,
+    /// JSON type of the value: `string`, `integer`, `number`, `boolean`, `object` or `array`
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// Instance types that use the option: `local`, `server` or `manager`
+    pub scope: &'static [&'static str],
+    /// Default value, `null` if it has no default, or the default depends on the platform
+    pub default: Value,
+    /// Possible values of an enumerated option
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<&'static str>,
+    pub description: &'static str,
+}
+
+const ALL: &[&str] = &["local", "server", "manager"];
+const LOCAL: &[&str] = &["local"];
+const SERVER: &[&str] = &["server", "manager"];
+
+impl OptionDescription {
+    fn new(
+        name: &'static str,
+        kind: &'static str,
+        scope: &'static [&'static str],
+        description: &'static str,
+    ) -> OptionDescription {
+        OptionDescription {
+            name,
+            kind,
+            scope,
+            default: Value::Null,
+            values: Vec::new(),
+            description,
+        }
+    }
+
+    fn default(mut self, default: Value) -> OptionDescription {
+        self.default = default;
+        self
+    }
+
+    fn values(mut self, values: Vec<&'static str>) -> OptionDescription {
+        self.values = values;
+        self
+    }
+}
+
+/// Describe the built binary
+pub fn describe() -> Description {
+    let local_protocols = if cfg!(feature = "local") {
+        ProtocolType::available_protocols().to_vec()
+    } else {
+        Vec::new()
+    };
+
+    #[allow(unused_mut)]
+    let mut transports = TransportDescription {
+        modes: vec!["tcp_only", "tcp_and_udp", "udp_only"],
+        tcp_fast_open: TCP_FAST_OPEN_SUPPORTED,
+        mptcp: cfg!(any(target_os = "linux", target_os = "android")),
+        plugin: true,
+        tcp_redir: Vec::new(),
+        udp_redir: Vec::new(),
+    };
+    #[cfg(feature = "local-redir")]
+    {
+        use shadowsocks_service::config::RedirType;
+
+        transports.tcp_redir = RedirType::tcp_available_types().to_vec();
+        transports.udp_redir = RedirType::udp_available_types().to_vec();
+    }
+
+    Description {
+        version: crate::VERSION,
+        build_time: crate::BUILD_TIME,
+        features: enabled_features(),
+        local_protocols,
+        ciphers: available_ciphers().to_vec(),
+        transports,
+        options: describe_options(),
+    }
+}
+
+fn describe_options() -> Vec<OptionDescription> {
+    let ciphers = available_ciphers().to_vec();
+    let modes = vec!["tcp_only", "tcp_and_udp", "udp_only"];
+
+    let mut options = vec![
+        OptionDescription::new("server", "string", ALL, "Address of the server"),
+        OptionDescription::new("server_port", "integer", ALL, "Port of the server"),
+        OptionDescription::new("password", "string", ALL, "Password of the server"),
+        OptionDescription::new("method", "string", ALL, "Encryption method").values(ciphers),
+        OptionDescription::new("plugin", "string", ALL, "SIP003 plugin program"),
+        OptionDescription::new("plugin_opts", "string", ALL, "Options passed to the plugin"),
+        OptionDescription::new("plugin_mode", "string", ALL, "Relays that go through the plugin")
+            .default(json!("tcp_only"))
+            .values(modes.clone()),
+        OptionDescription::new("mode", "string", ALL, "Relays that are enabled")
+            .default(json!("tcp_only"))
+            .values(modes),
+        OptionDescription::new("timeout", "integer", ALL, "Idle timeout of TCP connections in seconds"),
+        OptionDescription::new(
+            "udp_timeout",
+            "integer",
+            ALL,
+            "Idle timeout of UDP associations in seconds",
+        )
+        .default(json!(300)),
+        OptionDescription::new(
+            "udp_max_associations",
+            "integer",
+            ALL,
+            "Maximum UDP associations kept at the same time",
+        ),
+        OptionDescription::new(
+            "servers",
+            "array",
+            ALL,
+            "Servers, each one has the same keys as the basic format",
+        ),
+        OptionDescription::new(
+            "servers[].tcp_weight",
+            "number",
+            LOCAL,
+            "Weight of the server in choosing for TCP, in [0, 1]",
+        )
+        .default(json!(1.0)),
+        OptionDescription::new(
+            "servers[].udp_weight",
+            "number",
+            LOCAL,
+            "Weight of the server in choosing for UDP, in [0, 1]",
+        )
+        .default(json!(1.0)),
+        OptionDescription::new(
+            "servers[].tier",
+            "integer",
+            LOCAL,
+            "Failover tier of the server, 1 is the highest",
+        )
+        .default(json!(1)),
+        OptionDescription::new(
+            "servers[].group",
+            "string",
+            LOCAL,
+            "Group of the server for balancer's routes",
+        ),
+        OptionDescription::new(
+            "servers[].max_connections",
+            "integer",
+            ALL,
+            "Maximum concurrent sessions through the server",
+        ),
+        OptionDescription::new(
+            "servers[].tcp_bind",
+            "boolean",
+            SERVER,
+            "Allow clients to BIND a listening port on the server",
+        )
+        .default(json!(false)),
+        OptionDescription::new(
+            "servers[].reverse_tunnel",
+            "boolean",
+            SERVER,
+            "Allow clients to listen on ports of the server with reverse tunnels",
+        )
+        .default(json!(false)),
+        OptionDescription::new(
+            "servers[].uplink",
+            "string",
+            LOCAL,
+            "Uplink of connections to the server",
+        ),
+        OptionDescription::new("no_delay", "boolean", ALL, "Set TCP_NODELAY").default(json!(false)),
+        OptionDescription::new("fast_open", "boolean", ALL, "Enable TCP Fast Open").default(json!(false)),
+        OptionDescription::new("mptcp", "boolean", ALL, "Enable Multipath-TCP").default(json!(false)),
+        OptionDescription::new(
+            "keep_alive",
+            "integer",
+            ALL,
+            "TCP keep-alive idle and interval in seconds",
+        ),
+        OptionDescription::new("nofile", "integer", ALL, "Limit of file descriptors on *NIX systems"),
+        OptionDescription::new("ipv6_first", "boolean", ALL, "Connect IPv6 addresses first").default(json!(false)),
+        OptionDescription::new("ipv6_only", "boolean", ALL, "Set IPV6_V6ONLY on listeners").default(json!(false)),
+        OptionDescription::new(
+            "outbound_addr_family",
+            "string",
+            ALL,
+            "Only connect addresses of this family, \"ipv4\" or \"ipv6\"",
+        ),
+        OptionDescription::new("dns", "string", ALL, "DNS resolver, system's configuration if not set"),
+        OptionDescription::new(
+            "outbound_bind_interface",
+            "string",
+            ALL,
+            "Interface of outbound sockets",
+        ),
+        OptionDescription::new("outbound_bind_addr", "string", ALL, "Address of outbound sockets"),
+        OptionDescription::new("acl", "string", ALL, "Path of ACL file"),
+        OptionDescription::new(
+            "security.replay_attack.policy",
+            "string",
+            ALL,
+            "Handling of replay attacks",
+        )
+        .default(json!("default"))
+        .values(vec!["default", "ignore", "detect", "reject"]),
+        OptionDescription::new("log.level", "integer", ALL, "Log verbosity, same as -v").default(json!(0)),
+        OptionDescription::new("runtime.mode", "string", ALL, "Async runtime")
+            .default(json!(if cfg!(feature = "multi-threaded") {
+                "multi_thread"
+            } else {
+                "single_thread"
+            }))
+            .values(vec!["single_thread", "multi_thread"]),
+        OptionDescription::new(
+            "runtime.worker_count",
+            "integer",
+            ALL,
+            "Worker threads of multi-thread runtime",
+        ),
+    ];
+
+    if cfg!(any(target_os = "linux", target_os = "android")) {
+        options.push(OptionDescription::new(
+            "outbound_fwmark",
+            "integer",
+            ALL,
+            "SO_MARK of outbound sockets",
+        ));
+    }
+
+    if cfg!(feature = "local") {
+        options.extend([
+            OptionDescription::new(
+                "local_address",
+                "string",
+                LOCAL,
+                "Listen address of the local instance, loopback address if not set",
+            ),
+            OptionDescription::new("local_port", "integer", LOCAL, "Listen port of the local instance"),
+            OptionDescription::new("protocol", "string", LOCAL, "Protocol of the local instance")
+                .default(json!("socks"))
+                .values(ProtocolType::available_protocols().to_vec()),
+            OptionDescription::new(
+                "locals",
+                "array",
+                LOCAL,
+                "Local instances, each one has local_address, local_port, protocol and its own options",
+            ),
+            OptionDescription::new(
+                "balancer.max_server_rtt",
+                "integer",
+                LOCAL,
+                "Timeout of each server check in seconds",
+            )
+            .default(json!(5)),
+            OptionDescription::new(
+                "balancer.check_interval",
+                "integer",
+                LOCAL,
+                "Interval between server checks in seconds",
+            )
+            .default(json!(10)),
+            OptionDescription::new("balancer.strategy", "string", LOCAL, "Strategy of choosing servers")
+                .default(json!("best_score"))
+                .values(vec![
+                    "best_score",
+                    "least_connections",
+                    "consistent_hash",
+                    "source_hash",
+                ]),
+            OptionDescription::new(
+                "balancer.state_file",
+                "string",
+                LOCAL,
+                "File that server probe history is saved into",
+            ),
+            OptionDescription::new(
+                "special_target_policy",
+                "string",
+                LOCAL,
+                "Handling of unspecified, loopback, multicast and broadcast targets",
+            )
+            .default(json!("acl"))
+            .values(vec!["acl", "reject", "proxy", "drop"]),
+            OptionDescription::new(
+                "uplinks",
+                "array",
+                LOCAL,
+                "Uplinks that outbound sockets are balanced across",
+            ),
+            OptionDescription::new("qos", "object", LOCAL, "Bandwidth scheduling of proxied TCP flows"),
+            OptionDescription::new("mirror", "object", LOCAL, "Mirror plaintext traffic for debugging"),
+            OptionDescription::new(
+                "local_control_address",
+                "string",
+                LOCAL,
+                "Local control socket for managing servers at runtime",
+            ),
+        ]);
+    }
+
+    if cfg!(feature = "local-http") {
+        options.extend([
+            OptionDescription::new(
+                "local_control_http.listen_address",
+                "string",
+                LOCAL,
+                "Listen address of the control API over HTTP(S)",
+            ),
+            OptionDescription::new(
+                "local_control_http.token",
+                "string",
+                LOCAL,
+                "Bearer token of the control API",
+            ),
+            OptionDescription::new(
+                "local_control_http.allowed_ips",
+                "array",
+                LOCAL,
+                "IPs or networks of clients that are allowed to connect",
+            ),
+            OptionDescription::new(
+                "local_control_http.tls_cert",
+                "string",
+                LOCAL,
+                "TLS certificate chain in PEM, requires local-http-rustls",
+            ),
+            OptionDescription::new("local_control_http.tls_key", "string", LOCAL, "TLS private key in PEM"),
+        ]);
+    }
+
+    if cfg!(feature = "local-online-config") {
+        options.extend([
+            OptionDescription::new(
+                "online_config.config_url",
+                "string",
+                LOCAL,
+                "URL of SIP008 online configuration",
+            ),
+            OptionDescription::new(
+                "online_config.update_interval",
+                "integer",
+                LOCAL,
+                "Interval between updates in seconds",
+            )
+            .default(json!(3600)),
+        ]);
+    }
+
+    if cfg!(feature = "local") {
+        options.extend([
+            OptionDescription::new(
+                "ha.listen_address",
+                "string",
+                LOCAL,
+                "Address that receives heartbeats of the paired instance",
+            ),
+            OptionDescription::new(
+                "ha.peer_address",
+                "string",
+                LOCAL,
+                "Heartbeat address of the paired instance",
+            ),
+            OptionDescription::new(
+                "ha.priority",
+                "integer",
+                LOCAL,
+                "Instance with higher priority stays active if both are active",
+            )
+            .default(json!(100)),
+            OptionDescription::new("ha.heartbeat_interval", "integer", LOCAL, "Seconds between heartbeats")
+                .default(json!(1)),
+            OptionDescription::new(
+                "ha.failover_timeout",
+                "integer",
+                LOCAL,
+                "Seconds without heartbeats before the standby takes over",
+            )
+            .default(json!(3)),
+            OptionDescription::new(
+                "ha.notify_active",
+                "string",
+                LOCAL,
+                "Command to run when becoming active",
+            ),
+            OptionDescription::new(
+                "ha.notify_standby",
+                "string",
+                LOCAL,
+                "Command to run when becoming standby",
+            ),
+        ]);
+    }
+
+    if cfg!(feature = "local-history") {
+        options.extend([
+            OptionDescription::new("history.path", "string", LOCAL, "SQLite database of completed sessions"),
+            OptionDescription::new(
+                "history.retention_days",
+                "integer",
+                LOCAL,
+                "Records older than this are deleted",
+            ),
+            OptionDescription::new("history.max_records", "integer", LOCAL, "Only keep the latest records"),
+        ]);
+    }
+
+    if cfg!(feature = "local-file-watch") {
+        options.push(OptionDescription::new(
+            "file_watch.debounce_ms",
+            "integer",
+            LOCAL,
+            "Apply changes of watched files after they stay unchanged for this duration",
+        ));
+    }
+
+    if cfg!(feature = "manager") {
+        options.extend([
+            OptionDescription::new("manager_address", "string", SERVER, "Address of the manager"),
+            OptionDescription::new("manager_port", "integer", SERVER, "Port of the manager"),
+        ]);
+    }
+
+    options
+}
+
+/// Defines command line options
+pub fn define_command_line_options(app: Command) -> Command {
+    app.arg(
+        Arg::new("PRETTY")
+            .long("pretty")
+            .action(ArgAction::SetTrue)
+            .help("Pretty print the description"),
+    )
+}
+
+/// Program entrance `main`
+pub fn main(matches: &ArgMatches) -> ExitCode {
+    let description = describe();
+    let result = if matches.get_flag("PRETTY") {
+        serde_json::to_string_pretty(&description)
+    } else {
+        serde_json::to_string(&description)
+    };
+
+    match result {
+        Ok(s) => {
+            println!("{s}");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("failed to serialize description, error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! Query history of completed sessions
+//!
+//! Reads the SQLite database that `sslocal` records sessions into, configured by `history.path`.
+
+use std::{
+    path::PathBuf,
+    process::ExitCode,
+    time::{Duration, SystemTime},
+};
+
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use shadowsocks_service::local::history::{query_history, HistoryQuery, SessionRecord};
+
+/// Defines command line options
+pub fn define_command_line_options(app: Command) -> Command {
+    app.arg(
+        Arg::new("DATABASE")
+            .short('d')
+            .long("database")
+            .num_args(1)
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(PathBuf))
+            .required(true)
+            .help("Session history database, `history.path` in sslocal's configuration"),
+    )
+    .arg(
+        Arg::new("DOMAIN")
+            .long("domain")
+            .num_args(1)
+            .action(ArgAction::Set)
+            .help("Sessions to this domain name (or IP address), including its subdomains"),
+    )
+    .arg(
+        Arg::new("SERVER")
+            .long("server")
+            .num_args(1)
+            .action(ArgAction::Set)
+            .help("Sessions through this server, e.g. 1.2.3.4:8388"),
+    )
+    .arg(
+        Arg::new("SINCE")
+            .long("since")
+            .num_args(1)
+            .action(ArgAction::Set)
+            .value_parser(parse_time)
+            .help("Sessions started at or after this time, RFC 3339 (2024-01-02T15:04:05Z) or ago (30m, 12h, 7d)"),
+    )
+    .arg(
+        Arg::new("UNTIL")
+            .long("until")
+            .num_args(1)
+            .action(ArgAction::Set)
+            .value_parser(parse_time)
+            .help("Sessions started before this time, in the same format of --since"),
+    )
+    .arg(
+        Arg::new("LIMIT")
+            .long("limit")
+            .num_args(1)
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(usize))
+            .default_value("100")
+            .help("Maximum sessions to show, latest first. 0 for no limit"),
+    )
+    .arg(
+        Arg::new("JSON")
+            .long("json")
+            .action(ArgAction::SetTrue)
+            .help("Print sessions in JSON"),
+    )
+}
+
+fn parse_time(s: &str) -> Result<SystemTime, String> {
+    if let Ok(t) = OffsetDateTime::parse(s, &Rfc3339) {
+        return Ok(t.into());
+    }
+
+    let (n, unit) = s.split_at(s.len() - s.chars().last().map_or(0, char::len_utf8));
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("invalid time {s}, expecting RFC 3339 or 30m, 12h, 7d")),
+    };
+    let n = n
+        .parse::<u64>()
+        .map_err(|_| format!("invalid time {s}, expecting RFC 3339 or 30m, 12h, 7d"))?;
+
+    Ok(SystemTime::now() - Duration::from_secs(n.saturating_mul(secs)))
+}
+
+fn format_time(unix_millis: u64) -> String {
+    OffsetDateTime::from_unix_timestamp_nanos(i128::from(unix_millis) * 1_000_000)
+        .ok()
+        .and_then(|t| t.format(&Rfc3339).ok())
+        .unwrap_or_else(|| unix_millis.to_string())
+}
+
+fn print_record(record: &SessionRecord) {
+    let target = if record.host.contains(':') {
+        format!("[{}]:{}", record.host, record.port)
+    } else {
+        format!("{}:{}", record.host, record.port)
+    };
+
+    println!(
+        "{} {} {} via {} {:.1}s up {} down {}",
+        format_time(record.start_time),
+        record.protocol,
+        target,
+        record.server.as_deref().unwrap_or("direct"),
+        record.duration as f64 / 1000.0,
+        record.uploaded,
+        record.downloaded
+    );
+}
+
+/// Program entrance `main`
+pub fn main(matches: &ArgMatches) -> ExitCode {
+    let path = matches.get_one::<PathBuf>("DATABASE").expect("database");
+
+    let query = HistoryQuery {
+        domain: matches.get_one::<String>("DOMAIN").cloned(),
+        server: matches.get_one::<String>("SERVER").cloned(),
+        since: matches.get_one::<SystemTime>("SINCE").copied(),
+        until: matches.get_one::<SystemTime>("UNTIL").copied(),
+        limit: matches.get_one::<usize>("LIMIT").copied().filter(|l| *l > 0),
+    };
+
+    let records = match query_history(path, &query) {
+        Ok(r) => r,
+        Err(err) => {
+            eprintln!("failed to query session history in {}, error: {}", path.display(), err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if matches.get_flag("JSON") {
+        match serde_json::to_string(&records) {
+            Ok(s) => println!("{s}"),
+            Err(err) => {
+                eprintln!("failed to serialize sessions, error: {err}");
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        for record in &records {
+            print_record(record);
+        }
+    }
+
+    ExitCode::SUCCESS
+}
@@ -3,7 +3,7 @@
 use std::{
     future::Future,
     net::IpAddr,
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::ExitCode,
     sync::Arc,
     time::{Duration, Instant},
@@ -120,7 +120,7 @@ pub fn define_command_line_options(mut app: Command) -> Command {
             .long("protocol")
             .num_args(1)
             .action(ArgAction::Set)
-            .value_parser(PossibleValuesParser::new(ProtocolType::available_protocols()))
+            .value_parser(vparser::parse_protocol)
             .help("Protocol for communicating with clients (SOCKS5 by default)"),
     )
     .arg(
@@ -214,6 +214,7 @@ pub fn define_command_line_options(mut app: Command) -> Command {
     )
     .arg(Arg::new("DNS").long("dns").num_args(1).action(ArgAction::Set).help("DNS nameservers, formatted like [(tcp|udp)://]host[:port][,host[:port]]..., or unix:///path/to/dns, or predefined keys like \"google\", \"cloudflare\""))
     .arg(Arg::new("DNS_CACHE_SIZE").long("dns-cache-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(usize)).help("DNS cache size in number of records. Works when trust-dns DNS backend is enabled."))
+    .arg(Arg::new("VERIFY_SERVERS").long("verify-servers").action(ArgAction::SetTrue).help("Verify servers' method and password with one request on startup"))
     .arg(Arg::new("TCP_NO_DELAY").long("tcp-no-delay").alias("no-delay").action(ArgAction::SetTrue).help("Set TCP_NODELAY option for sockets"))
     .arg(Arg::new("TCP_FAST_OPEN").long("tcp-fast-open").alias("fast-open").action(ArgAction::SetTrue).help("Enable TCP Fast Open (TFO)"))
     .arg(Arg::new("TCP_KEEP_ALIVE").long("tcp-keep-alive").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u64)).help("Set TCP keep alive timeout seconds"))
@@ -233,6 +234,18 @@ pub fn define_command_line_options(mut app: Command) -> Command {
             .help("Resolve hostname to IPv6 address first"),
     );
 
+    #[cfg(feature = "metrics")]
+    {
+        app = app.arg(
+            Arg::new("METRICS_ADDR")
+                .long("metrics-addr")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_parser(vparser::parse_socket_addr)
+                .help("Serve Prometheus metrics on IP:PORT, GET /metrics"),
+        );
+    }
+
     #[cfg(feature = "logging")]
     {
         app = app
@@ -267,10 +280,18 @@ pub fn define_command_line_options(mut app: Command) -> Command {
                 .long("forward-addr")
                 .num_args(1)
                 .action(ArgAction::Set)
-                .requires("LOCAL_ADDR")
                 .value_parser(vparser::parse_address)
-                .required_if_eq("PROTOCOL", "tunnel")
-                .help("Forwarding data directly to this address (for tunnel)"),
+                .required_if_eq_any([("PROTOCOL", "tunnel"), ("PROTOCOL", "reverse-tunnel")])
+                .help("Forwarding data directly to this address (for tunnel), or exposing it on the server (for reverse-tunnel)"),
+        )
+        .arg(
+            Arg::new("REVERSE_PORT")
+                .long("reverse-port")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u16))
+                .requires("FORWARD_ADDR")
+                .help("Port of the server that reverse-tunnel listens on, chosen by the server if not set"),
         );
     }
 
@@ -436,19 +457,37 @@ pub fn define_command_line_options(mut app: Command) -> Command {
                     .action(ArgAction::Set)
                     .value_parser(vparser::parse_ipnet)
                     .help("Tun interface destination address (network)"),
+            )
+            .arg(
+                Arg::new("TUN_INTERFACE_MTU")
+                    .long("tun-interface-mtu")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_parser(clap::value_parser!(u16).range(576..))
+                    .help("Tun interface MTU, 1500 if not specify"),
             );
 
         #[cfg(unix)]
         {
-            app = app.arg(
-                Arg::new("TUN_DEVICE_FD_FROM_PATH")
-                    .long("tun-device-fd-from-path")
-                    .num_args(1)
-                    .action(ArgAction::Set)
-                    .value_parser(clap::value_parser!(PathBuf))
-                    .value_hint(ValueHint::AnyPath)
-                    .help("Tun device file descriptor will be transferred from this unix domain socket path"),
-            );
+            app = app
+                .arg(
+                    Arg::new("TUN_DEVICE_FD_FROM_PATH")
+                        .long("tun-device-fd-from-path")
+                        .num_args(1)
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .value_hint(ValueHint::AnyPath)
+                        .help("Tun device file descriptor will be transferred from this unix domain socket path"),
+                )
+                .arg(
+                    Arg::new("TUN_DEVICE_FD")
+                        .long("tun-fd")
+                        .num_args(1)
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(i32).range(0..))
+                        .conflicts_with("TUN_DEVICE_FD_FROM_PATH")
+                        .help("File descriptor of an opened tun device, inherited from the parent process"),
+                );
         }
     }
 
@@ -702,8 +741,12 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
                 Some("socks") => ProtocolType::Socks,
                 #[cfg(feature = "local-http")]
                 Some("http") => ProtocolType::Http,
+                #[cfg(feature = "local-http")]
+                Some("mixed") => ProtocolType::Mixed,
                 #[cfg(feature = "local-tunnel")]
                 Some("tunnel") => ProtocolType::Tunnel,
+                #[cfg(feature = "local-tunnel")]
+                Some("reverse-tunnel") => ProtocolType::ReverseTunnel,
                 #[cfg(feature = "local-redir")]
                 Some("redir") => ProtocolType::Redir,
                 #[cfg(feature = "local-dns")]
@@ -719,9 +762,14 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
                 Some(local_addr) => local_config.addr = Some(local_addr),
                 None => {
                     #[cfg(feature = "local-tun")]
-                    if protocol == ProtocolType::Tun {
-                        // `tun` protocol doesn't need --local-addr
-                    } else {
+                    let no_local_addr = protocol == ProtocolType::Tun;
+                    #[cfg(not(feature = "local-tun"))]
+                    let no_local_addr = false;
+                    // `reverse-tunnel` protocol doesn't listen locally
+                    #[cfg(feature = "local-tunnel")]
+                    let no_local_addr = no_local_addr || protocol == ProtocolType::ReverseTunnel;
+
+                    if !no_local_addr {
                         panic!("`local-addr` is required for protocol {}", protocol.as_str());
                     }
                 }
@@ -735,6 +783,10 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
             if let Some(addr) = matches.get_one::<Address>("FORWARD_ADDR").cloned() {
                 local_config.forward_addr = Some(addr);
             }
+            #[cfg(feature = "local-tunnel")]
+            if let Some(port) = matches.get_one::<u16>("REVERSE_PORT") {
+                local_config.reverse_port = Some(*port);
+            }
 
             #[cfg(feature = "local-redir")]
             {
@@ -797,11 +849,18 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
                 if let Some(tun_name) = matches.get_one::<String>("TUN_INTERFACE_NAME").cloned() {
                     local_config.tun_interface_name = Some(tun_name);
                 }
+                if let Some(tun_mtu) = matches.get_one::<u16>("TUN_INTERFACE_MTU").cloned() {
+                    local_config.tun_interface_mtu = Some(tun_mtu);
+                }
 
                 #[cfg(unix)]
                 if let Some(fd_path) = matches.get_one::<PathBuf>("TUN_DEVICE_FD_FROM_PATH").cloned() {
                     local_config.tun_device_fd_from_path = Some(fd_path);
                 }
+                #[cfg(unix)]
+                if let Some(fd) = matches.get_one::<i32>("TUN_DEVICE_FD").cloned() {
+                    local_config.tun_device_fd = Some(fd);
+                }
             }
 
             #[cfg(feature = "local-fake-dns")]
@@ -833,6 +892,10 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
             config.local.push(LocalInstanceConfig::with_local_config(local_config));
         }
 
+        if matches.get_flag("VERIFY_SERVERS") {
+            config.balancer.verify_servers = true;
+        }
+
         if matches.get_flag("TCP_NO_DELAY") {
             config.no_delay = true;
         }
@@ -874,7 +937,13 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
         }
 
         if let Some(acl_file) = matches.get_one::<String>("ACL") {
-            let acl = match AccessControl::load_from_file(acl_file) {
+            // Rule sources in configuration are merged with the ACL file from command line
+            let rule_sources = config
+                .acl
+                .as_ref()
+                .map(|acl| acl.rule_sources().clone())
+                .unwrap_or_default();
+            let acl = match AccessControl::load(Some(Path::new(acl_file)), &rule_sources) {
                 Ok(acl) => acl,
                 Err(err) => {
                     eprintln!("loading ACL \"{acl_file}\", {err}");
@@ -904,6 +973,11 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
             config.udp_max_associations = Some(*udp_max_assoc);
         }
 
+        #[cfg(feature = "metrics")]
+        if let Some(metrics_addr) = matches.get_one::<std::net::SocketAddr>("METRICS_ADDR") {
+            config.metrics_addr = Some(*metrics_addr);
+        }
+
         if let Some(bs) = matches.get_one::<u32>("INBOUND_SEND_BUFFER_SIZE") {
             config.inbound_send_buffer_size = Some(*bs);
         }
@@ -984,20 +1058,29 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
     let main_fut = async move {
         let config_path = config.config_path.clone();
 
-        let instance = Server::new(config).await.expect("create local");
+        let (server, reload_task) = if config.ha.is_some() {
+            // Listeners are re-created when the instance becomes active, reloading servers is not supported
+            (
+                shadowsocks_service::local::run(config).boxed(),
+                future::pending().boxed(),
+            )
+        } else {
+            let instance = Server::new(config).await.expect("create local");
 
-        let reload_task = match config_path {
-            Some(config_path) => ServerReloader {
-                config_path: config_path.clone(),
-                balancer: instance.server_balancer().clone(),
-            }
-            .launch_reload_server_task()
-            .boxed(),
-            None => future::pending().boxed(),
+            let reload_task = match config_path {
+                Some(config_path) => ServerReloader {
+                    config_path: config_path.clone(),
+                    balancer: instance.server_balancer().clone(),
+                }
+                .launch_reload_server_task()
+                .boxed(),
+                None => future::pending().boxed(),
+            };
+
+            (instance.run().boxed(), reload_task)
         };
 
         let abort_signal = monitor::create_signal_monitor();
-        let server = instance.run();
 
         let reload_task = reload_task.fuse();
         let abort_signal = abort_signal.fuse();
@@ -1,6 +1,12 @@
 //! Server Manager launchers
 
-use std::{future::Future, net::IpAddr, path::PathBuf, process::ExitCode, time::Duration};
+use std::{
+    future::Future,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
 
 use clap::{builder::PossibleValuesParser, Arg, ArgAction, ArgGroup, ArgMatches, Command, ValueHint};
 use futures::future::{self, Either};
@@ -421,7 +427,13 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
         }
 
         if let Some(acl_file) = matches.get_one::<String>("ACL") {
-            let acl = match AccessControl::load_from_file(acl_file) {
+            // Rule sources in configuration are merged with the ACL file from command line
+            let rule_sources = config
+                .acl
+                .as_ref()
+                .map(|acl| acl.rule_sources().clone())
+                .unwrap_or_default();
+            let acl = match AccessControl::load(Some(Path::new(acl_file)), &rule_sources) {
                 Ok(acl) => acl,
                 Err(err) => {
                     eprintln!("loading ACL \"{acl_file}\", {err}");
@@ -1,9 +1,14 @@
 //! Service launchers
 
+pub mod describe;
 pub mod genkey;
+#[cfg(feature = "local-history")]
+pub mod history;
 #[cfg(feature = "local")]
 pub mod local;
 #[cfg(feature = "manager")]
 pub mod manager;
 #[cfg(feature = "server")]
 pub mod server;
+pub mod support_bundle;
+pub mod verify;
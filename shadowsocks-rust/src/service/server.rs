@@ -1,6 +1,12 @@
 //! Server launchers
 
-use std::{future::Future, net::IpAddr, path::PathBuf, process::ExitCode, time::Duration};
+use std::{
+    future::Future,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
 
 use clap::{builder::PossibleValuesParser, Arg, ArgAction, ArgGroup, ArgMatches, Command, ValueHint};
 use futures::future::{self, Either};
@@ -159,6 +165,18 @@ pub fn define_command_line_options(mut app: Command) -> Command {
                 .help("Resolve hostname to IPv6 address first"),
         );
 
+    #[cfg(feature = "metrics")]
+    {
+        app = app.arg(
+            Arg::new("METRICS_ADDR")
+                .long("metrics-addr")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_parser(vparser::parse_socket_addr)
+                .help("Serve Prometheus metrics on IP:PORT, GET /metrics"),
+        );
+    }
+
     #[cfg(feature = "logging")]
     {
         app = app
@@ -440,7 +458,13 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
         }
 
         if let Some(acl_file) = matches.get_one::<String>("ACL") {
-            let acl = match AccessControl::load_from_file(acl_file) {
+            // Rule sources in configuration are merged with the ACL file from command line
+            let rule_sources = config
+                .acl
+                .as_ref()
+                .map(|acl| acl.rule_sources().clone())
+                .unwrap_or_default();
+            let acl = match AccessControl::load(Some(Path::new(acl_file)), &rule_sources) {
                 Ok(acl) => acl,
                 Err(err) => {
                     eprintln!("loading ACL \"{acl_file}\", {err}");
@@ -470,6 +494,11 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
             config.udp_max_associations = Some(*udp_max_assoc);
         }
 
+        #[cfg(feature = "metrics")]
+        if let Some(metrics_addr) = matches.get_one::<std::net::SocketAddr>("METRICS_ADDR") {
+            config.metrics_addr = Some(*metrics_addr);
+        }
+
         if let Some(bs) = matches.get_one::<u32>("INBOUND_SEND_BUFFER_SIZE") {
             config.inbound_send_buffer_size = Some(*bs);
         }
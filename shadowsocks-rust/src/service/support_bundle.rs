@@ -0,0 +1,347 @@
+//! Support bundle generator
+//!
+//! Collects sanitized configuration, version, recent logs, status of a running `sslocal` and platform capabilities
+//! into a single tar archive, which could be attached to bug reports.
+
+use std::{
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::{Arg, ArgAction, ArgMatches, Command, ValueHint};
+use serde_json::Value;
+use tokio::{runtime::Builder, time};
+
+use shadowsocks_service::shadowsocks::{
+    config::ServerType, context::Context, manager::datagram::ManagerDatagram, ManagerAddr,
+};
+
+use crate::{config::get_default_config_path, vparser};
+
+use super::describe;
+
+/// Bytes read from the end of each log file
+const LOG_TAIL_SIZE: u64 = 256 * 1024;
+/// Timeout of each request to the local control socket
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(3);
+/// Keys in configuration that may contain secrets
+const SECRET_KEYS: &[&str] = &["password", "key", "plugin_opts", "plugin_args", "config_url"];
+const REDACTED: &str = "<redacted>";
+
+/// Defines command line options
+pub fn define_command_line_options(mut app: Command) -> Command {
+    app = app
+        .arg(
+            Arg::new("CONFIG")
+                .short('c')
+                .long("config")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(PathBuf))
+                .value_hint(ValueHint::FilePath)
+                .help("Shadowsocks configuration file, secrets will be redacted"),
+        )
+        .arg(
+            Arg::new("LOG_FILE")
+                .long("log-file")
+                .num_args(1)
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(PathBuf))
+                .value_hint(ValueHint::FilePath)
+                .help(
+                    "Log file to be included, only the latest 256KiB is collected. Could be specified multiple times",
+                ),
+        )
+        .arg(
+            Arg::new("CONTROL_ADDR")
+                .long("control-address")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_parser(vparser::parse_manager_addr)
+                .help("Local control socket of a running sslocal, default is `local_control_address` in configuration"),
+        )
+        .arg(
+            Arg::new("OUTPUT")
+                .short('o')
+                .long("output")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(PathBuf))
+                .value_hint(ValueHint::FilePath)
+                .help("Output archive path, default is ss-support-bundle-<timestamp>.tar"),
+        );
+
+    app
+}
+
+/// Program entrance `main`
+pub fn main(matches: &ArgMatches) -> ExitCode {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let mut bundle = SupportBundle::new(timestamp);
+
+    bundle.add_file("version.txt", version_info().into_bytes());
+    bundle.add_file("platform.txt", platform_info().into_bytes());
+
+    let config_path = matches
+        .get_one::<PathBuf>("CONFIG")
+        .cloned()
+        .or_else(|| get_default_config_path("local.json"));
+
+    let mut control_addr = matches.get_one::<ManagerAddr>("CONTROL_ADDR").cloned();
+
+    match config_path {
+        Some(path) => match load_sanitized_config(&path) {
+            Ok(config) => {
+                if control_addr.is_none() {
+                    control_addr = config
+                        .get("local_control_address")
+                        .and_then(Value::as_str)
+                        .and_then(|s| s.parse::<ManagerAddr>().ok());
+                }
+                let content = serde_json::to_string_pretty(&config).expect("serialize config");
+                bundle.add_file("config.json", content.into_bytes());
+            }
+            Err(err) => bundle.add_error(format!("config {}: {}", path.display(), err)),
+        },
+        None => bundle.add_error("config: no configuration file"),
+    }
+
+    if let Some(log_files) = matches.get_many::<PathBuf>("LOG_FILE") {
+        for path in log_files {
+            match read_tail(path, LOG_TAIL_SIZE) {
+                Ok(content) => {
+                    let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+                    bundle.add_file(&format!("logs/{name}"), content);
+                }
+                Err(err) => bundle.add_error(format!("log {}: {}", path.display(), err)),
+            }
+        }
+    }
+
+    match control_addr {
+        Some(addr) => {
+            let runtime = Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("create tokio Runtime");
+
+            for (cmd, name) in [("list", "status.txt"), ("stats", "balancer.json")] {
+                match runtime.block_on(control_request(&addr, cmd)) {
+                    Ok(rsp) => bundle.add_file(name, rsp.into_bytes()),
+                    Err(err) => bundle.add_error(format!("control {addr} {cmd}: {err}")),
+                }
+            }
+        }
+        None => bundle.add_error("status: no local control socket"),
+    }
+
+    let output = matches
+        .get_one::<PathBuf>("OUTPUT")
+        .cloned()
+        .unwrap_or_else(|| PathBuf::from(format!("ss-support-bundle-{timestamp}.tar")));
+
+    match fs::write(&output, bundle.finish()) {
+        Ok(..) => {
+            println!("support bundle written to {}", output.display());
+            println!("please review it before attaching to bug reports");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("failed to write {}, error: {}", output.display(), err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Files of the bundle, in a tar archive
+struct SupportBundle {
+    archive: Vec<u8>,
+    mtime: u64,
+    errors: String,
+}
+
+impl SupportBundle {
+    fn new(mtime: u64) -> SupportBundle {
+        SupportBundle {
+            archive: Vec::new(),
+            mtime,
+            errors: String::new(),
+        }
+    }
+
+    /// Record things that failed to be collected, which are written into `errors.txt`
+    fn add_error<S: AsRef<str>>(&mut self, err: S) {
+        let _ = writeln!(self.errors, "{}", err.as_ref());
+    }
+
+    /// Append a file in ustar format
+    fn add_file(&mut self, name: &str, data: Vec<u8>) {
+        let name = format!("ss-support-bundle/{name}");
+        let name = name.as_bytes();
+
+        let mut header = [0u8; 512];
+        header[..name.len().min(100)].copy_from_slice(&name[..name.len().min(100)]);
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[108..116].copy_from_slice(b"0000000\0");
+        header[116..124].copy_from_slice(b"0000000\0");
+        header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+        header[136..148].copy_from_slice(format!("{:011o}\0", self.mtime).as_bytes());
+        header[148..156].copy_from_slice(b"        ");
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+
+        let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+        header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+
+        self.archive.extend_from_slice(&header);
+        self.archive.extend_from_slice(&data);
+        let padding = (512 - data.len() % 512) % 512;
+        self.archive.resize(self.archive.len() + padding, 0);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if !self.errors.is_empty() {
+            let errors = std::mem::take(&mut self.errors);
+            self.add_file("errors.txt", errors.into_bytes());
+        }
+        // End of archive, two empty blocks
+        self.archive.resize(self.archive.len() + 1024, 0);
+        self.archive
+    }
+}
+
+fn version_info() -> String {
+    let mut info = String::new();
+    let _ = writeln!(info, "version: {}", crate::VERSION);
+    let _ = writeln!(info, "build time: {}", crate::BUILD_TIME);
+
+    let _ = writeln!(info, "features: {}", describe::enabled_features().join(", "));
+
+    info
+}
+
+fn platform_info() -> String {
+    let mut info = String::new();
+    let _ = writeln!(
+        info,
+        "platform: {} {} ({})",
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        std::env::consts::FAMILY
+    );
+
+    let ipv6 = std::net::UdpSocket::bind("[::1]:0").is_ok();
+    let _ = writeln!(info, "ipv6: {ipv6}");
+
+    let _ = writeln!(info, "tcp fast open: {}", describe::TCP_FAST_OPEN_SUPPORTED);
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        for (name, path) in [
+            ("net.ipv4.tcp_fastopen", "/proc/sys/net/ipv4/tcp_fastopen"),
+            ("net.mptcp.enabled", "/proc/sys/net/mptcp/enabled"),
+        ] {
+            let value = fs::read_to_string(path).unwrap_or_else(|err| format!("unavailable, {err}"));
+            let _ = writeln!(info, "{}: {}", name, value.trim());
+        }
+    }
+
+    #[cfg(feature = "local-redir")]
+    {
+        use shadowsocks_service::config::RedirType;
+
+        let _ = writeln!(info, "tcp redir: {}", RedirType::tcp_available_types().join(", "));
+        let _ = writeln!(info, "udp redir: {}", RedirType::udp_available_types().join(", "));
+    }
+
+    let _ = writeln!(info, "tun: {}", cfg!(feature = "local-tun"));
+
+    info
+}
+
+fn load_sanitized_config(path: &Path) -> io::Result<Value> {
+    let content = fs::read_to_string(path)?;
+    let mut config = json5::from_str::<Value>(&content).map_err(io::Error::other)?;
+    redact_secrets(&mut config);
+    Ok(config)
+}
+
+fn redact_secrets(value: &mut Value) {
+    match *value {
+        Value::Object(ref mut map) => {
+            for (key, value) in map.iter_mut() {
+                if SECRET_KEYS.contains(&key.as_str()) {
+                    *value = Value::String(REDACTED.to_owned());
+                } else {
+                    redact_secrets(value);
+                }
+            }
+        }
+        Value::Array(ref mut array) => array.iter_mut().for_each(redact_secrets),
+        // SIP002 URLs have passwords inside
+        Value::String(ref mut s) if s.starts_with("ss://") => *s = REDACTED.to_owned(),
+        _ => {}
+    }
+}
+
+fn read_tail(path: &Path, size: u64) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if len > size {
+        file.seek(SeekFrom::Start(len - size))?;
+    }
+
+    let mut content = Vec::with_capacity(len.min(size) as usize);
+    file.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// Send `cmd` to the local control socket and wait for its response
+async fn control_request(addr: &ManagerAddr, cmd: &str) -> io::Result<String> {
+    let context = Context::new(ServerType::Local);
+
+    // Control socket only responds to named peers
+    let bind_addr = match *addr {
+        #[cfg(unix)]
+        ManagerAddr::UnixSocketAddr(..) => ManagerAddr::UnixSocketAddr(std::env::temp_dir().join(format!(
+            "ss-support-bundle-{}-{}.sock",
+            std::process::id(),
+            cmd
+        ))),
+        ManagerAddr::SocketAddr(SocketAddr::V6(..)) => ManagerAddr::SocketAddr("[::]:0".parse().unwrap()),
+        _ => ManagerAddr::SocketAddr("0.0.0.0:0".parse().unwrap()),
+    };
+
+    let mut socket = ManagerDatagram::bind(&context, &bind_addr).await?;
+
+    let result = async {
+        socket.send_to_manager(cmd.as_bytes(), &context, addr).await?;
+
+        let mut buffer = vec![0u8; 65536];
+        match time::timeout(CONTROL_TIMEOUT, socket.recv(&mut buffer)).await {
+            Ok(Ok(n)) => Ok(String::from_utf8_lossy(&buffer[..n]).into_owned()),
+            Ok(Err(err)) => Err(err),
+            Err(..) => Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+    .await;
+
+    #[cfg(unix)]
+    {
+        if let ManagerAddr::UnixSocketAddr(ref path) = bind_addr {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    result
+}
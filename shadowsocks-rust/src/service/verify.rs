@@ -0,0 +1,612 @@
+//! Protocol conformance checks against a remote shadowsocks server
+//!
+//! Runs a battery of requests, including malformed ones, through the server and prints a compatibility report.
+//! It helps determining whether a third-party server implementation is at fault.
+
+use std::{fmt, future::Future, io, net::SocketAddr, process::ExitCode, time::Duration};
+
+use base64::Engine as _;
+use clap::{Arg, ArgAction, ArgMatches, Command, ValueHint};
+use rand::{distributions::Alphanumeric, Rng, RngCore};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{lookup_host, TcpStream, UdpSocket},
+    runtime::Builder,
+    time,
+};
+
+use shadowsocks_service::shadowsocks::{
+    config::{ServerAddr, ServerConfig, ServerType},
+    context::{Context, SharedContext},
+    crypto::{CipherCategory, CipherKind},
+    relay::{socks5::Address, udprelay::ProxySocket},
+    ProxyClientStream,
+};
+
+use crate::vparser;
+
+const DEFAULT_TCP_TARGET: &str = "detectportal.firefox.com:80";
+const DEFAULT_DNS_SERVER: &str = "8.8.8.8:53";
+const DEFAULT_TIMEOUT_SEC: u64 = 5;
+
+/// Larger than the maximum chunk size of all AEAD protocols, so the request must be split into chunks
+const MULTI_CHUNK_BODY_SIZE: usize = 0x10000 + 1024;
+
+/// Defines command line options
+pub fn define_command_line_options(mut app: Command) -> Command {
+    app = app
+        .arg(
+            Arg::new("SERVER_URL")
+                .long("server-url")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .required(true)
+                .value_hint(ValueHint::Url)
+                .value_parser(vparser::parse_server_url)
+                .help("Server to be checked, in SIP002 (https://shadowsocks.org/doc/sip002.html) format"),
+        )
+        .arg(
+            Arg::new("TCP_TARGET")
+                .long("tcp-target")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_parser(vparser::parse_address)
+                .help("HTTP server requested through the server, default is detectportal.firefox.com:80"),
+        )
+        .arg(
+            Arg::new("DNS_SERVER")
+                .long("dns-server")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_parser(vparser::parse_address)
+                .help("DNS server queried through the server, default is 8.8.8.8:53"),
+        )
+        .arg(
+            Arg::new("TIMEOUT")
+                .long("timeout")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u64))
+                .help("Timeout seconds of each check, default is 5"),
+        )
+        .arg(
+            Arg::new("NO_UDP")
+                .long("no-udp")
+                .action(ArgAction::SetTrue)
+                .help("Skip UDP checks, for servers that don't relay UDP"),
+        );
+
+    app
+}
+
+/// Program entrance `main`
+pub fn main(matches: &ArgMatches) -> ExitCode {
+    let svr_cfg = matches
+        .get_one::<ServerConfig>("SERVER_URL")
+        .cloned()
+        .expect("`server-url` is required");
+
+    if svr_cfg.plugin().is_some() {
+        eprintln!("servers with SIP003 plugins are not supported");
+        return ExitCode::FAILURE;
+    }
+
+    let verifier = Verifier {
+        context: Context::new_shared(ServerType::Local),
+        svr_cfg,
+        tcp_target: matches
+            .get_one::<Address>("TCP_TARGET")
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_TCP_TARGET.parse().expect("tcp target")),
+        dns_server: matches
+            .get_one::<Address>("DNS_SERVER")
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_DNS_SERVER.parse().expect("dns server")),
+        timeout: Duration::from_secs(
+            matches
+                .get_one::<u64>("TIMEOUT")
+                .cloned()
+                .unwrap_or(DEFAULT_TIMEOUT_SEC),
+        ),
+        check_udp: !matches.get_flag("NO_UDP"),
+    };
+
+    let runtime = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("create tokio Runtime");
+    let reports = runtime.block_on(verifier.run());
+
+    println!(
+        "shadowsocks server {} ({}) conformance report:",
+        verifier.svr_cfg.addr(),
+        verifier.svr_cfg.method()
+    );
+
+    let mut counts = [0usize; 4];
+    for report in &reports {
+        counts[report.status as usize] += 1;
+        println!("  [{}] {:<18} {}", report.status, report.name, report.detail);
+    }
+
+    println!(
+        "{} passed, {} warnings, {} failed, {} skipped",
+        counts[CheckStatus::Pass as usize],
+        counts[CheckStatus::Warn as usize],
+        counts[CheckStatus::Fail as usize],
+        counts[CheckStatus::Skip as usize],
+    );
+
+    if counts[CheckStatus::Fail as usize] > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CheckStatus {
+    Pass = 0,
+    Warn = 1,
+    Fail = 2,
+    Skip = 3,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CheckStatus::Pass => f.write_str("PASS"),
+            CheckStatus::Warn => f.write_str("WARN"),
+            CheckStatus::Fail => f.write_str("FAIL"),
+            CheckStatus::Skip => f.write_str("SKIP"),
+        }
+    }
+}
+
+struct CheckReport {
+    name: &'static str,
+    status: CheckStatus,
+    detail: String,
+}
+
+impl CheckReport {
+    fn new<S: Into<String>>(name: &'static str, status: CheckStatus, detail: S) -> CheckReport {
+        CheckReport {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Result of waiting for a response of a (malformed) request
+enum Response {
+    Data(usize),
+    Closed,
+    Error(io::Error),
+    Timeout,
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Response::Data(n) => write!(f, "responded {n} bytes"),
+            Response::Closed => f.write_str("closed connection"),
+            Response::Error(ref err) => write!(f, "failed with {err}"),
+            Response::Timeout => f.write_str("didn't respond"),
+        }
+    }
+}
+
+struct Verifier {
+    context: SharedContext,
+    svr_cfg: ServerConfig,
+    tcp_target: Address,
+    dns_server: Address,
+    timeout: Duration,
+    check_udp: bool,
+}
+
+impl Verifier {
+    async fn run(&self) -> Vec<CheckReport> {
+        let mut reports = vec![
+            self.check_tcp_relay().await,
+            self.check_tcp_multi_chunk().await,
+            self.check_tcp_wrong_key().await,
+            self.check_tcp_replay().await,
+        ];
+
+        if self.check_udp {
+            reports.push(self.check_udp_relay().await);
+            reports.push(self.check_udp_wrong_key().await);
+            reports.push(self.check_udp_replay().await);
+        }
+
+        reports
+    }
+
+    fn method(&self) -> CipherKind {
+        self.svr_cfg.method()
+    }
+
+    #[cfg(feature = "aead-cipher-2022")]
+    fn is_aead_2022(&self) -> bool {
+        self.method().is_aead_2022()
+    }
+
+    #[cfg(not(feature = "aead-cipher-2022"))]
+    fn is_aead_2022(&self) -> bool {
+        false
+    }
+
+    /// Server config with the same method and address, but a random key
+    fn wrong_key_config(&self) -> ServerConfig {
+        let mut rng = rand::thread_rng();
+
+        let password = if self.is_aead_2022() {
+            let mut key = vec![0u8; self.method().key_len()];
+            rng.fill_bytes(&mut key);
+            base64::engine::general_purpose::STANDARD.encode(&key)
+        } else {
+            (&mut rng).sample_iter(Alphanumeric).take(16).map(char::from).collect()
+        };
+
+        ServerConfig::new(self.svr_cfg.addr().clone(), password, self.method())
+    }
+
+    fn http_request(&self, body_size: usize) -> Vec<u8> {
+        let host = match self.tcp_target {
+            Address::SocketAddress(ref saddr) => saddr.ip().to_string(),
+            Address::DomainNameAddress(ref dname, ..) => dname.clone(),
+        };
+
+        let mut request = if body_size == 0 {
+            format!("GET / HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\nAccept: */*\r\n\r\n").into_bytes()
+        } else {
+            format!(
+                "POST / HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\nAccept: */*\r\nContent-Length: {body_size}\r\n\r\n"
+            )
+            .into_bytes()
+        };
+        request.resize(request.len() + body_size, b'x');
+        request
+    }
+
+    async fn resolve(&self, addr: &ServerAddr) -> io::Result<SocketAddr> {
+        match *addr {
+            ServerAddr::SocketAddr(saddr) => Ok(saddr),
+            ServerAddr::DomainName(ref dname, port) => match lookup_host((dname.as_str(), port)).await?.next() {
+                Some(saddr) => Ok(saddr),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "server address resolved to nothing",
+                )),
+            },
+        }
+    }
+
+    async fn with_timeout<F, T>(&self, fut: F) -> io::Result<T>
+    where
+        F: Future<Output = io::Result<T>>,
+    {
+        match time::timeout(self.timeout, fut).await {
+            Ok(r) => r,
+            Err(..) => Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+
+    /// Send an HTTP request through `svr_cfg` and read the status line of response
+    async fn http_through(&self, svr_cfg: &ServerConfig, request: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream = ProxyClientStream::connect(self.context.clone(), svr_cfg, self.tcp_target.clone()).await?;
+        stream.write_all(request).await?;
+        stream.flush().await?;
+
+        let mut status_line = Vec::new();
+        let mut buf = [0u8; 1];
+        while !status_line.ends_with(b"\r\n") {
+            if stream.read(&mut buf).await? == 0 {
+                break;
+            }
+            status_line.push(buf[0]);
+        }
+        Ok(status_line)
+    }
+
+    /// Send raw `data` to server and wait for the first response
+    async fn tcp_raw_response(&self, data: &[u8]) -> Response {
+        let res = async {
+            let server_addr = self.resolve(self.svr_cfg.tcp_external_addr()).await?;
+            let mut stream = TcpStream::connect(server_addr).await?;
+            stream.write_all(data).await?;
+
+            let mut buf = [0u8; 1024];
+            stream.read(&mut buf).await
+        };
+
+        match time::timeout(self.timeout, res).await {
+            Ok(Ok(0)) => Response::Closed,
+            Ok(Ok(n)) => Response::Data(n),
+            Ok(Err(err)) => Response::Error(err),
+            Err(..) => Response::Timeout,
+        }
+    }
+
+    async fn udp_raw_response(&self, socket: &UdpSocket, packet: &[u8]) -> Response {
+        let res = async {
+            socket.send(packet).await?;
+
+            let mut buf = vec![0u8; 65536];
+            socket.recv(&mut buf).await
+        };
+
+        match time::timeout(self.timeout, res).await {
+            Ok(Ok(n)) => Response::Data(n),
+            Ok(Err(err)) => Response::Error(err),
+            Err(..) => Response::Timeout,
+        }
+    }
+
+    fn dns_query(id: u16) -> Vec<u8> {
+        // A query of "example.com", IN A
+        let mut query = Vec::with_capacity(29);
+        query.extend_from_slice(&id.to_be_bytes());
+        query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        query.extend_from_slice(b"\x07example\x03com\x00");
+        query.extend_from_slice(&[0x00, 0x01, 0x00, 0x01]);
+        query
+    }
+
+    async fn check_tcp_relay(&self) -> CheckReport {
+        const NAME: &str = "tcp-relay";
+
+        let request = self.http_request(0);
+        match self.with_timeout(self.http_through(&self.svr_cfg, &request)).await {
+            Ok(status_line) if status_line.starts_with(b"HTTP/") => {
+                let status_line = String::from_utf8_lossy(&status_line);
+                let detail = if self.is_aead_2022() {
+                    format!("{:?}, response header verified", status_line.trim_end())
+                } else {
+                    format!("{:?}", status_line.trim_end())
+                };
+                CheckReport::new(NAME, CheckStatus::Pass, detail)
+            }
+            Ok(status_line) => CheckReport::new(
+                NAME,
+                CheckStatus::Fail,
+                format!("unexpected response {:?}", String::from_utf8_lossy(&status_line)),
+            ),
+            Err(err) => CheckReport::new(NAME, CheckStatus::Fail, format!("request failed, {err}")),
+        }
+    }
+
+    async fn check_tcp_multi_chunk(&self) -> CheckReport {
+        const NAME: &str = "tcp-multi-chunk";
+
+        if self.method().category() == CipherCategory::None {
+            return CheckReport::new(NAME, CheckStatus::Skip, "method has no chunks");
+        }
+
+        let request = self.http_request(MULTI_CHUNK_BODY_SIZE);
+        match self.with_timeout(self.http_through(&self.svr_cfg, &request)).await {
+            Ok(status_line) if status_line.starts_with(b"HTTP/") => CheckReport::new(
+                NAME,
+                CheckStatus::Pass,
+                format!("{} bytes request relayed in multiple chunks", request.len()),
+            ),
+            Ok(..) => CheckReport::new(
+                NAME,
+                CheckStatus::Warn,
+                "no response for a large request, server may have failed reading multiple chunks, or target rejected it",
+            ),
+            Err(err) => CheckReport::new(NAME, CheckStatus::Fail, format!("request failed, {err}")),
+        }
+    }
+
+    async fn check_tcp_wrong_key(&self) -> CheckReport {
+        const NAME: &str = "tcp-wrong-key";
+
+        if self.method().category() == CipherCategory::None {
+            return CheckReport::new(NAME, CheckStatus::Skip, "method has no key");
+        }
+
+        let svr_cfg = self.wrong_key_config();
+        let request = self.http_request(0);
+        match self.with_timeout(self.http_through(&svr_cfg, &request)).await {
+            Ok(status_line) if !status_line.is_empty() => CheckReport::new(
+                NAME,
+                CheckStatus::Fail,
+                "server relayed a request encrypted with a wrong key",
+            ),
+            Ok(..) => CheckReport::new(NAME, CheckStatus::Pass, "rejected, closed connection"),
+            Err(err) => CheckReport::new(NAME, CheckStatus::Pass, format!("rejected, {err}")),
+        }
+    }
+
+    async fn check_tcp_replay(&self) -> CheckReport {
+        const NAME: &str = "tcp-replay";
+
+        if self.method().category() == CipherCategory::None {
+            return CheckReport::new(NAME, CheckStatus::Skip, "method has no salt");
+        }
+
+        // Encrypt the request once, and send exactly the same bytes twice
+        let request = self.http_request(0);
+        let encrypted = async {
+            let (client, mut server) = tokio::io::duplex(request.len() + 1024);
+
+            let mut stream =
+                ProxyClientStream::from_stream(self.context.clone(), client, &self.svr_cfg, self.tcp_target.clone());
+            stream.write_all(&request).await?;
+            stream.flush().await?;
+            drop(stream);
+
+            let mut encrypted = Vec::new();
+            server.read_to_end(&mut encrypted).await?;
+            Ok::<_, io::Error>(encrypted)
+        };
+        let encrypted = match encrypted.await {
+            Ok(e) => e,
+            Err(err) => return CheckReport::new(NAME, CheckStatus::Fail, format!("failed to encrypt request, {err}")),
+        };
+
+        match self.tcp_raw_response(&encrypted).await {
+            Response::Data(..) => {}
+            response => {
+                return CheckReport::new(
+                    NAME,
+                    CheckStatus::Fail,
+                    format!("server {response} for the original request"),
+                )
+            }
+        }
+
+        match self.tcp_raw_response(&encrypted).await {
+            Response::Data(n) => {
+                let status = if self.is_aead_2022() {
+                    CheckStatus::Fail
+                } else {
+                    // Replay protection is optional in the older protocols
+                    CheckStatus::Warn
+                };
+                CheckReport::new(
+                    NAME,
+                    status,
+                    format!("server responded {n} bytes for a replayed request"),
+                )
+            }
+            response => CheckReport::new(
+                NAME,
+                CheckStatus::Pass,
+                format!("replayed request rejected, server {response}"),
+            ),
+        }
+    }
+
+    async fn check_udp_relay(&self) -> CheckReport {
+        const NAME: &str = "udp-relay";
+
+        let res = async {
+            let socket = ProxySocket::connect(self.context.clone(), &self.svr_cfg).await?;
+
+            let id = rand::random::<u16>();
+            socket.send(&self.dns_server, &Verifier::dns_query(id)).await?;
+
+            let mut buf = vec![0u8; 65536];
+            let (n, addr, ..) = socket.recv(&mut buf).await?;
+            Ok::<_, io::Error>((id, buf[..n].to_vec(), addr))
+        };
+
+        match self.with_timeout(res).await {
+            Ok((id, response, addr)) if response.len() >= 2 && response[..2] == id.to_be_bytes() => {
+                CheckReport::new(NAME, CheckStatus::Pass, format!("DNS response from {addr}"))
+            }
+            Ok(..) => CheckReport::new(NAME, CheckStatus::Fail, "response is not the DNS response of the query"),
+            Err(err) => CheckReport::new(NAME, CheckStatus::Fail, format!("query failed, {err}")),
+        }
+    }
+
+    async fn check_udp_wrong_key(&self) -> CheckReport {
+        const NAME: &str = "udp-wrong-key";
+
+        if self.method().category() == CipherCategory::None {
+            return CheckReport::new(NAME, CheckStatus::Skip, "method has no key");
+        }
+
+        let svr_cfg = self.wrong_key_config();
+        let res = async {
+            let socket = ProxySocket::connect(self.context.clone(), &svr_cfg).await?;
+            socket.send(&self.dns_server, &Verifier::dns_query(0)).await?;
+
+            let mut buf = vec![0u8; 65536];
+            socket.recv(&mut buf).await?;
+            Ok::<_, io::Error>(())
+        };
+
+        match time::timeout(self.timeout, res).await {
+            Err(..) => CheckReport::new(NAME, CheckStatus::Pass, "dropped silently"),
+            Ok(Err(err)) if err.kind() != io::ErrorKind::Other => {
+                CheckReport::new(NAME, CheckStatus::Warn, format!("socket failed, {err}"))
+            }
+            // Packets that failed to decrypt are also responses
+            Ok(..) => CheckReport::new(
+                NAME,
+                CheckStatus::Fail,
+                "server responded to a packet encrypted with a wrong key",
+            ),
+        }
+    }
+
+    #[cfg(feature = "aead-cipher-2022")]
+    async fn check_udp_replay(&self) -> CheckReport {
+        use bytes::BytesMut;
+        use shadowsocks_service::shadowsocks::relay::udprelay::{
+            crypto_io::encrypt_client_payload, options::UdpSocketControlData,
+        };
+
+        const NAME: &str = "udp-replay";
+
+        if !self.is_aead_2022() {
+            return CheckReport::new(NAME, CheckStatus::Skip, "packet ID is only defined in AEAD-2022");
+        }
+
+        let mut control = UdpSocketControlData::default();
+        control.client_session_id = rand::random::<u64>();
+        control.packet_id = 1;
+
+        let mut packet = BytesMut::new();
+        encrypt_client_payload(
+            &self.context,
+            self.method(),
+            self.svr_cfg.key(),
+            &self.dns_server,
+            &control,
+            self.svr_cfg.identity_keys(),
+            &Verifier::dns_query(rand::random::<u16>()),
+            &mut packet,
+        );
+
+        let socket = async {
+            let server_addr = self.resolve(self.svr_cfg.udp_external_addr()).await?;
+            let socket = UdpSocket::bind(if server_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+            socket.connect(server_addr).await?;
+            Ok::<_, io::Error>(socket)
+        };
+        let socket = match socket.await {
+            Ok(s) => s,
+            Err(err) => return CheckReport::new(NAME, CheckStatus::Fail, format!("failed to connect server, {err}")),
+        };
+
+        match self.udp_raw_response(&socket, &packet).await {
+            Response::Data(..) => {}
+            response => {
+                return CheckReport::new(
+                    NAME,
+                    CheckStatus::Fail,
+                    format!("server {response} for the original packet"),
+                )
+            }
+        }
+
+        match self.udp_raw_response(&socket, &packet).await {
+            Response::Data(n) => CheckReport::new(
+                NAME,
+                CheckStatus::Fail,
+                format!("server responded {n} bytes for a packet with duplicated packet ID"),
+            ),
+            response => CheckReport::new(
+                NAME,
+                CheckStatus::Pass,
+                format!("replayed packet dropped, server {response}"),
+            ),
+        }
+    }
+
+    #[cfg(not(feature = "aead-cipher-2022"))]
+    async fn check_udp_replay(&self) -> CheckReport {
+        CheckReport::new(
+            "udp-replay",
+            CheckStatus::Skip,
+            "packet ID is only defined in AEAD-2022",
+        )
+    }
+}
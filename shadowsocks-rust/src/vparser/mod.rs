@@ -11,7 +11,7 @@ use shadowsocks_service::config::RedirType;
 #[cfg(feature = "local-dns")]
 use shadowsocks_service::local::dns::NameServerAddr;
 use shadowsocks_service::{
-    config::{ManagerServerHost, ManagerServerMode},
+    config::{ManagerServerHost, ManagerServerMode, ProtocolType},
     shadowsocks::{crypto::CipherKind, relay::socks5::Address, ManagerAddr, ServerAddr, ServerConfig},
 };
 
@@ -56,6 +56,21 @@ pub fn parse_server_url(v: &str) -> Result<ServerConfig, String> {
     }
 }
 
+pub fn parse_protocol(v: &str) -> Result<String, String> {
+    if ProtocolType::available_protocols().contains(&v) {
+        return Ok(v.to_owned());
+    }
+    match ProtocolType::missing_feature(v) {
+        Some(feature) => Err(format!(
+            "requires feature \"{feature}\", which is not enabled in this build"
+        )),
+        None => Err(format!(
+            "should be one of {}",
+            ProtocolType::available_protocols().join(", ")
+        )),
+    }
+}
+
 #[cfg(any(feature = "local-tun", feature = "local-fake-dns"))]
 pub fn parse_ipnet(v: &str) -> Result<IpNet, String> {
     match v.parse::<IpNet>() {
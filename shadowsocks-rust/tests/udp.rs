@@ -1,4 +1,4 @@
-#![cfg_attr(clippy, allow(blacklisted_name))]
+#![allow(clippy::disallowed_names)]
 #![cfg(all(feature = "local", feature = "server"))]
 
 use std::net::SocketAddr;